@@ -0,0 +1,196 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Endpoints of the client-server API for appservices that are not in Ruma
+//! yet.
+
+/// `POST /_matrix/client/*/appservice/{appserviceId}/ping`
+///
+/// Ask the homeserver to ping the appservice, see [MSC2659].
+///
+/// [MSC2659]: https://github.com/matrix-org/matrix-spec-proposals/pull/2659
+pub(crate) mod ping {
+    use std::time::Duration;
+
+    use ruma::{
+        api::{request, response, Metadata},
+        metadata, OwnedTransactionId,
+    };
+
+    /// The stable endpoint.
+    pub(crate) mod v1 {
+        use super::*;
+
+        // The stable endpoint is declared as the only unstable one, so it is
+        // used whatever versions the homeserver advertises. Callers fall back
+        // to the unstable endpoint if it is unrecognized.
+        const METADATA: Metadata = metadata! {
+            method: POST,
+            rate_limited: false,
+            authentication: AccessToken,
+            history: {
+                unstable => "/_matrix/client/v1/appservice/:appservice_id/ping",
+            }
+        };
+
+        /// Request type for the `ping` endpoint.
+        #[request(error = ruma::api::client::Error)]
+        pub struct Request {
+            /// The ID of the appservice in its registration.
+            #[ruma_api(path)]
+            pub appservice_id: String,
+
+            /// A transaction ID that the homeserver sends in the ping to the
+            /// appservice.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub transaction_id: Option<OwnedTransactionId>,
+        }
+
+        /// Response type for the `ping` endpoint.
+        #[response(error = ruma::api::client::Error)]
+        pub struct Response {
+            /// How long it took the homeserver to reach the appservice.
+            #[serde(with = "ruma::serde::duration::ms", rename = "duration_ms")]
+            pub duration: Duration,
+        }
+
+        impl Request {
+            /// Creates a new `Request` with the given appservice ID and
+            /// transaction ID.
+            pub fn new(appservice_id: String, transaction_id: OwnedTransactionId) -> Self {
+                Self { appservice_id, transaction_id: Some(transaction_id) }
+            }
+        }
+    }
+
+    /// The endpoint of MSC2659, for homeservers that don't support the stable
+    /// one yet.
+    pub(crate) mod unstable {
+        use super::*;
+
+        const METADATA: Metadata = metadata! {
+            method: POST,
+            rate_limited: false,
+            authentication: AccessToken,
+            history: {
+                unstable => "/_matrix/client/unstable/fi.mau.msc2659/appservice/:appservice_id/ping",
+            }
+        };
+
+        /// Request type for the unstable `ping` endpoint.
+        #[request(error = ruma::api::client::Error)]
+        pub struct Request {
+            /// The ID of the appservice in its registration.
+            #[ruma_api(path)]
+            pub appservice_id: String,
+
+            /// A transaction ID that the homeserver sends in the ping to the
+            /// appservice.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub transaction_id: Option<OwnedTransactionId>,
+        }
+
+        /// Response type for the unstable `ping` endpoint.
+        #[response(error = ruma::api::client::Error)]
+        pub struct Response {
+            /// How long it took the homeserver to reach the appservice.
+            #[serde(with = "ruma::serde::duration::ms", rename = "duration_ms")]
+            pub duration: Duration,
+        }
+
+        impl Request {
+            /// Creates a new `Request` with the given appservice ID and
+            /// transaction ID.
+            pub fn new(appservice_id: String, transaction_id: OwnedTransactionId) -> Self {
+                Self { appservice_id, transaction_id: Some(transaction_id) }
+            }
+        }
+    }
+}
+
+/// `POST /_matrix/client/unstable/org.matrix.msc2716/rooms/{roomId}/batch_send`
+///
+/// Import a batch of historical events in a room, see [MSC2716].
+///
+/// [MSC2716]: https://github.com/matrix-org/matrix-spec-proposals/pull/2716
+pub(crate) mod batch_send {
+    /// The endpoint of MSC2716.
+    pub(crate) mod unstable {
+        use ruma::{
+            api::{request, response, Metadata},
+            events::{AnyStateEvent, AnyTimelineEvent},
+            metadata,
+            serde::Raw,
+            OwnedEventId, OwnedRoomId,
+        };
+
+        const METADATA: Metadata = metadata! {
+            method: POST,
+            rate_limited: false,
+            authentication: AccessToken,
+            history: {
+                unstable => "/_matrix/client/unstable/org.matrix.msc2716/rooms/:room_id/batch_send",
+            }
+        };
+
+        /// Request type for the `batch_send` endpoint.
+        #[request(error = ruma::api::client::Error)]
+        pub struct Request {
+            /// The room to import the events in.
+            #[ruma_api(path)]
+            pub room_id: OwnedRoomId,
+
+            /// The event before which the events are imported.
+            #[ruma_api(query)]
+            pub prev_event_id: OwnedEventId,
+
+            /// The ID of the batch the events are connected to, returned by
+            /// the previous batch.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub batch_id: Option<String>,
+
+            /// The state events that are valid at the start of the batch.
+            pub state_events_at_start: Vec<Raw<AnyStateEvent>>,
+
+            /// The events to import, from the oldest to the newest.
+            pub events: Vec<Raw<AnyTimelineEvent>>,
+        }
+
+        /// Response type for the `batch_send` endpoint.
+        #[response(error = ruma::api::client::Error)]
+        pub struct Response {
+            /// The ID of the batch the next batch must be connected to.
+            pub next_batch_id: String,
+
+            /// The insertion event that was created at the start of the
+            /// history, if this is the first batch.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub base_insertion_event_id: Option<OwnedEventId>,
+        }
+
+        impl Request {
+            /// Creates a new `Request` with the given room ID, previous event
+            /// ID and events.
+            pub fn new(
+                room_id: OwnedRoomId,
+                prev_event_id: OwnedEventId,
+                state_events_at_start: Vec<Raw<AnyStateEvent>>,
+                events: Vec<Raw<AnyTimelineEvent>>,
+            ) -> Self {
+                Self { room_id, prev_event_id, batch_id: None, state_events_at_start, events }
+            }
+        }
+    }
+}
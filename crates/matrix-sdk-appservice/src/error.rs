@@ -32,6 +32,9 @@ pub enum Error {
     #[error("no client for localpart found")]
     NoClientForLocalpart,
 
+    #[error("a device ID is required to masquerade as the device of a user")]
+    MissingDeviceId,
+
     #[error("could not convert host:port to socket addr")]
     HostPortToSocketAddrs,
 
@@ -79,6 +82,12 @@ pub enum Error {
 
     #[error("hyper error: {0}")]
     Hyper(#[from] hyper::Error),
+
+    #[error("task join error: {0}")]
+    Join(#[from] tokio::task::JoinError),
+
+    #[error("the homeserver failed to ping the appservice: {errcode}: {message}")]
+    Ping { errcode: String, message: String },
 }
 
 impl Error {
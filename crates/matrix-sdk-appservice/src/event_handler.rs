@@ -12,13 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
 
 use tokio::sync::Mutex;
 
 use crate::{
-    ruma::api::appservice::query::{
-        query_room_alias::v1 as query_room, query_user_id::v1 as query_user,
+    ruma::{
+        api::appservice::{
+            query::{query_room_alias::v1 as query_room, query_user_id::v1 as query_user},
+            thirdparty::{
+                get_location_for_protocol::v1 as query_location_for_protocol,
+                get_location_for_room_alias::v1 as query_location_for_room_alias,
+                get_protocol::v1 as query_protocol,
+                get_user_for_protocol::v1 as query_user_for_protocol,
+                get_user_for_user_id::v1 as query_user_for_user_id,
+            },
+        },
+        thirdparty::{Location, Protocol, User},
     },
     AppService,
 };
@@ -27,23 +37,36 @@ pub(crate) type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 pub(crate) type AppserviceFn<A, R> =
     Box<dyn FnMut(AppService, A) -> BoxFuture<'static, R> + Send + Sync + 'static>;
 
+type Handler<A, R> = Arc<Mutex<Option<AppserviceFn<A, R>>>>;
+
 #[derive(Default, Clone)]
 pub struct EventHandler {
-    pub users: Arc<Mutex<Option<AppserviceFn<query_user::Request, bool>>>>,
-    pub rooms: Arc<Mutex<Option<AppserviceFn<query_room::Request, bool>>>>,
+    pub users: Handler<query_user::Request, bool>,
+    pub rooms: Handler<query_room::Request, bool>,
+    pub protocol: Handler<query_protocol::Request, Option<Protocol>>,
+    pub location_for_protocol: Handler<query_location_for_protocol::Request, Vec<Location>>,
+    pub location_for_room_alias: Handler<query_location_for_room_alias::Request, Vec<Location>>,
+    pub user_for_protocol: Handler<query_user_for_protocol::Request, Vec<User>>,
+    pub user_for_user_id: Handler<query_user_for_user_id::Request, Vec<User>>,
 }
 
-impl std::fmt::Debug for EventHandler {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn field<A, R>(debug: &mut fmt::DebugStruct<'_, '_>, name: &str, handler: &Handler<A, R>) {
+            match handler.try_lock() {
+                Ok(lock) => debug.field(name, &lock.is_some()),
+                Err(_) => debug.field(name, &format_args!("<locked>")),
+            };
+        }
+
         let mut debug = f.debug_struct("EventHandler");
-        match self.users.try_lock() {
-            Ok(lock) => debug.field("users", &lock.is_some()),
-            Err(_) => debug.field("users", &format_args!("<locked>")),
-        };
-        match self.rooms.try_lock() {
-            Ok(lock) => debug.field("rooms", &lock.is_some()),
-            Err(_) => debug.field("rooms", &format_args!("<locked>")),
-        };
+        field(&mut debug, "users", &self.users);
+        field(&mut debug, "rooms", &self.rooms);
+        field(&mut debug, "protocol", &self.protocol);
+        field(&mut debug, "location_for_protocol", &self.location_for_protocol);
+        field(&mut debug, "location_for_room_alias", &self.location_for_room_alias);
+        field(&mut debug, "user_for_protocol", &self.user_for_protocol);
+        field(&mut debug, "user_for_user_id", &self.user_for_user_id);
         debug.finish()
     }
 }
@@ -0,0 +1,76 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Health of the link between the homeserver and the appservice.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::Result;
+
+/// The health of the link between the homeserver and the appservice.
+///
+/// It is updated by the transactions received from the homeserver and by
+/// [`AppService::ping()`][crate::AppService::ping].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Health {
+    /// When the last transaction was handled successfully.
+    pub last_transaction: Option<SystemTime>,
+    /// When the last successful ping was sent.
+    pub last_ping: Option<SystemTime>,
+    /// How long it took the homeserver to reach the appservice during the
+    /// last successful ping.
+    pub ping_latency: Option<Duration>,
+    /// The error of the last ping, if it failed.
+    ///
+    /// This is reset by the next successful ping.
+    pub ping_error: Option<String>,
+}
+
+/// Shared handle to the [`Health`] of an appservice.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HealthTracker {
+    inner: Arc<RwLock<Health>>,
+}
+
+impl HealthTracker {
+    /// Get a copy of the current health.
+    pub(crate) fn get(&self) -> Health {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Record that a transaction was handled successfully.
+    pub(crate) fn record_transaction(&self) {
+        self.inner.write().unwrap().last_transaction = Some(SystemTime::now());
+    }
+
+    /// Record the result of a ping.
+    pub(crate) fn record_ping(&self, result: &Result<Duration>) {
+        let mut health = self.inner.write().unwrap();
+
+        match result {
+            Ok(latency) => {
+                health.last_ping = Some(SystemTime::now());
+                health.ping_latency = Some(*latency);
+                health.ping_error = None;
+            }
+            Err(error) => {
+                health.ping_error = Some(error.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Import of the history of a remote channel with [MSC2716].
+//!
+//! [MSC2716]: https://github.com/matrix-org/matrix-spec-proposals/pull/2716
+
+use std::collections::BTreeSet;
+
+use futures_util::{pin_mut, Stream, StreamExt};
+use ruma::{
+    api::client::state::send_state_event,
+    events::{AnyStateEvent, AnyStateEventContent, AnyTimelineEvent, MessageLikeEventContent},
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::{api::batch_send, AppService, Result};
+
+const HISTORY_KEY: &[u8] = b"appservice.history.";
+
+/// A message of the remote network to import in a room.
+#[derive(Debug, Clone)]
+pub struct HistoryMessage {
+    /// The ID of the message on the remote network.
+    ///
+    /// It is used to resume an import that was interrupted.
+    pub id: String,
+    /// The virtual user that sent the message.
+    pub sender: OwnedUserId,
+    /// When the message was sent on the remote network.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+    /// The type of the event.
+    pub event_type: String,
+    /// The content of the event.
+    pub content: Value,
+}
+
+impl HistoryMessage {
+    /// Create a new `HistoryMessage` with the given event content.
+    pub fn new(
+        id: impl Into<String>,
+        sender: OwnedUserId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+        content: &impl MessageLikeEventContent,
+    ) -> Result<Self> {
+        Ok(Self {
+            id: id.into(),
+            sender,
+            timestamp,
+            event_type: content.event_type().to_string(),
+            content: serde_json::to_value(content)?,
+        })
+    }
+
+    fn to_event(&self) -> Result<Raw<AnyTimelineEvent>> {
+        let event = json!({
+            "type": self.event_type,
+            "sender": self.sender,
+            "origin_server_ts": self.timestamp,
+            "content": self.content,
+        });
+
+        Ok(Raw::new(&event)?.cast())
+    }
+}
+
+/// The summary of an import.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ImportSummary {
+    /// The number of messages that were imported, including by previous runs
+    /// of the same import.
+    pub imported: usize,
+    /// The ID of the insertion event that the homeserver created at the
+    /// anchor of the import, if a batch was imported.
+    pub base_insertion_event_id: Option<OwnedEventId>,
+}
+
+/// The progress of an import, persisted after every batch.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ImportState {
+    /// The ID of the batch the next batch must be connected to.
+    next_batch_id: Option<String>,
+    /// The remote ID of the oldest message that was imported.
+    last_imported_id: Option<String>,
+    base_insertion_event_id: Option<OwnedEventId>,
+    imported: usize,
+    marker_sent: bool,
+}
+
+/// Importer of the history of a remote channel in a room, with [MSC2716].
+///
+/// The messages are imported by batches before an existing event of the
+/// room, the anchor. Since every batch is inserted before the previous one,
+/// the messages must be given from the newest to the oldest, which is usually
+/// the order in which remote networks paginate their history.
+///
+/// The homeserver connects the batches with insertion and batch events, and
+/// the progress of the import is persisted in the store of the client of the
+/// `sender_localpart` after every batch, so an interrupted import resumes
+/// after the last imported message when it is run again with the same
+/// messages. Once every message is imported, a marker event is sent in the
+/// room, so other homeservers fetch the imported history.
+///
+/// The messages are delivered at least once: if the import is interrupted
+/// after a batch was sent, but before the progress was persisted, that batch
+/// is sent again when the import resumes.
+///
+/// The room version must support MSC2716, and the homeserver must allow the
+/// appservice to use it.
+///
+/// [MSC2716]: https://github.com/matrix-org/matrix-spec-proposals/pull/2716
+#[derive(Debug, Clone)]
+pub struct HistoryImporter {
+    appservice: AppService,
+    room_id: OwnedRoomId,
+    prev_event_id: OwnedEventId,
+    batch_size: usize,
+}
+
+impl HistoryImporter {
+    /// Create a new history importer.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room to import the messages in.
+    /// * `prev_event_id` - The event of the room before which the messages
+    ///   are imported.
+    pub fn new(appservice: AppService, room_id: OwnedRoomId, prev_event_id: OwnedEventId) -> Self {
+        Self { appservice, room_id, prev_event_id, batch_size: 100 }
+    }
+
+    /// Set the maximum number of messages per batch.
+    ///
+    /// Defaults to 100.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Import the given messages, from the newest to the oldest.
+    ///
+    /// If a previous run of this import was interrupted, the messages are
+    /// skipped until the last one that was imported.
+    ///
+    /// # Delivery
+    ///
+    /// The homeserver doesn't deduplicate the batches, and the progress can
+    /// only be persisted once a batch was accepted. If this future is dropped,
+    /// or the store fails, between these two steps, the last batch is sent
+    /// again by the next run, so its messages appear twice in the room.
+    pub async fn import(
+        &self,
+        messages: impl Stream<Item = HistoryMessage>,
+    ) -> Result<ImportSummary> {
+        pin_mut!(messages);
+
+        let client = self.appservice.user(None).await?;
+        let key =
+            [HISTORY_KEY, self.room_id.as_bytes(), b".", self.prev_event_id.as_bytes()].concat();
+
+        let mut state: ImportState = match client.store().get_custom_value(&key).await? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => ImportState::default(),
+        };
+
+        let mut skipping = state.last_imported_id.is_some();
+        let mut batch = Vec::new();
+
+        while let Some(message) = messages.next().await {
+            if skipping {
+                skipping = state.last_imported_id.as_ref() != Some(&message.id);
+                continue;
+            }
+
+            batch.push(message);
+
+            if batch.len() == self.batch_size {
+                // The batch is sent again if the progress isn't persisted.
+                self.send_batch(&mut state, &batch).await?;
+                client.store().set_custom_value(&key, serde_json::to_vec(&state)?).await?;
+                batch.clear();
+            }
+        }
+
+        if skipping {
+            warn!(
+                room_id = %self.room_id,
+                "The last imported message wasn't found, nothing was imported",
+            );
+        }
+
+        if !batch.is_empty() {
+            self.send_batch(&mut state, &batch).await?;
+            client.store().set_custom_value(&key, serde_json::to_vec(&state)?).await?;
+        }
+
+        if let (Some(base_insertion_event_id), false) =
+            (&state.base_insertion_event_id, state.marker_sent)
+        {
+            self.send_marker(base_insertion_event_id).await?;
+            state.marker_sent = true;
+            client.store().set_custom_value(&key, serde_json::to_vec(&state)?).await?;
+        }
+
+        Ok(ImportSummary {
+            imported: state.imported,
+            base_insertion_event_id: state.base_insertion_event_id,
+        })
+    }
+
+    /// Send a batch of messages, ordered from the newest to the oldest.
+    async fn send_batch(&self, state: &mut ImportState, batch: &[HistoryMessage]) -> Result<()> {
+        let Some(oldest) = batch.last() else { return Ok(()) };
+
+        // The senders must be joined to the room at the start of the batch.
+        let senders: BTreeSet<&UserId> = batch.iter().map(|m| &*m.sender).collect();
+        let state_events_at_start = senders
+            .into_iter()
+            .map(|sender| -> Result<Raw<AnyStateEvent>> {
+                let event = json!({
+                    "type": "m.room.member",
+                    "sender": sender,
+                    "state_key": sender,
+                    "origin_server_ts": oldest.timestamp,
+                    "content": { "membership": "join" },
+                });
+                Ok(Raw::new(&event)?.cast())
+            })
+            .collect::<Result<_>>()?;
+        let events = batch.iter().rev().map(HistoryMessage::to_event).collect::<Result<_>>()?;
+
+        let mut request = batch_send::unstable::Request::new(
+            self.room_id.clone(),
+            self.prev_event_id.clone(),
+            state_events_at_start,
+            events,
+        );
+        request.batch_id = state.next_batch_id.clone();
+
+        let client = self.appservice.user(None).await?;
+        let response = client.send(request, None).await?;
+        debug!(room_id = %self.room_id, count = batch.len(), "Imported batch of history");
+
+        state.next_batch_id = Some(response.next_batch_id);
+        state.last_imported_id = Some(oldest.id.clone());
+        state.imported += batch.len();
+        if state.base_insertion_event_id.is_none() {
+            state.base_insertion_event_id = response.base_insertion_event_id;
+        }
+
+        Ok(())
+    }
+
+    /// Send the marker event that points to the base insertion event of the
+    /// import.
+    async fn send_marker(&self, base_insertion_event_id: &OwnedEventId) -> Result<()> {
+        let client = self.appservice.user(None).await?;
+        let content = json!({ "org.matrix.msc2716.marker.insertion": base_insertion_event_id });
+        let request = send_state_event::v3::Request::new_raw(
+            self.room_id.clone(),
+            "org.matrix.msc2716.marker".into(),
+            base_insertion_event_id.to_string(),
+            Raw::new(&content)?.cast::<AnyStateEventContent>(),
+        );
+
+        client.send(request, None).await?;
+        Ok(())
+    }
+}
@@ -22,7 +22,7 @@
 //! - [x] receive and validate requests from the homeserver correctly
 //! - [x] allow calling the homeserver with proper user identity assertion
 //! - [x] have consistent room state by leveraging matrix-sdk's state store
-//! - [ ] provide E2EE support by leveraging matrix-sdk's crypto store
+//! - [x] provide E2EE support by leveraging matrix-sdk's crypto store
 //!
 //! # Status
 //!
@@ -36,6 +36,15 @@
 //! for the access tokens and because membership states for appservice users are
 //! determined based on the registered namespaces.
 //!
+//! # End-to-end encryption
+//!
+//! With the `e2e-encryption` feature, appservice users can take part in
+//! encrypted rooms without syncing, if the homeserver implements [MSC2409]
+//! and [MSC3202] and they are enabled in the registration. The to-device
+//! events, device list changes and one-time key counts of the transactions
+//! are handed to the crypto machine of the matching user, and a user built
+//! with [`UserBuilder::masquerade_device()`] uploads the keys of its device.
+//!
 //! # Quickstart
 //!
 //! ```no_run
@@ -77,11 +86,13 @@
 //! [Application Service]: https://matrix.org/docs/spec/application_service/r0.1.2
 //! [matrix-org/matrix-rust-sdk#228]: https://github.com/matrix-org/matrix-rust-sdk/issues/228
 //! [examples directory]: https://github.com/matrix-org/matrix-rust-sdk/tree/main/crates/matrix-sdk-appservice/examples
+//! [MSC2409]: https://github.com/matrix-org/matrix-spec-proposals/pull/2409
+//! [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
 
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, sync::Arc, time::Duration};
 
 use axum::body::HttpBody;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 pub use error::Error;
 use event_handler::AppserviceFn;
 pub use matrix_sdk;
@@ -93,27 +104,51 @@ use ruma::{
         appservice::{
             event::push_events,
             query::{query_room_alias::v1 as query_room, query_user_id::v1 as query_user},
+            thirdparty::{
+                get_location_for_protocol::v1 as query_location_for_protocol,
+                get_location_for_room_alias::v1 as query_location_for_room_alias,
+                get_protocol::v1 as query_protocol,
+                get_user_for_protocol::v1 as query_user_for_protocol,
+                get_user_for_user_id::v1 as query_user_for_user_id,
+            },
+        },
+        client::{
+            account::register,
+            error::{ErrorBody, ErrorKind},
+            sync::sync_events,
         },
-        client::{account::register, sync::sync_events},
     },
     assign,
     events::{room::member::MembershipState, AnyStateEvent, AnyTimelineEvent},
-    DeviceId, OwnedRoomId, OwnedServerName,
+    thirdparty::{Location, Protocol, User},
+    DeviceId, OwnedEventId, OwnedRoomId, OwnedServerName, RoomId, TransactionId,
 };
 use serde::Deserialize;
 use thiserror::Error;
-use tokio::task::JoinHandle;
+use tokio::{sync::Mutex, task::JoinHandle};
 use tracing::{debug, info, warn};
 
+mod api;
 mod error;
 pub mod event_handler;
+mod health;
+pub mod history;
+pub mod portal;
 pub mod registration;
+mod transaction;
 pub mod user;
+pub mod virtual_user;
 mod webserver;
 
+pub use health::Health;
+use health::HealthTracker;
+pub use history::HistoryImporter;
+pub use portal::PortalManager;
 pub use registration::AppServiceRegistration;
 use registration::NamespaceCache;
+use transaction::{EphemeralEvent, TransactionExtensions};
 pub use user::UserBuilder;
+pub use virtual_user::VirtualUserManager;
 pub use webserver::AppServiceRouter;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -121,6 +156,9 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 const USER_KEY: &[u8] = b"appservice.users.";
 const USER_MEMBER: &[u8] = b"appservice.users.membership.";
 
+/// How long to wait for the homeserver to ping the appservice.
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
 type Localpart = String;
 
 /// AppService
@@ -131,8 +169,13 @@ pub struct AppService {
     registration: Arc<AppServiceRegistration>,
     namespaces: Arc<NamespaceCache>,
     clients: Arc<DashMap<Localpart, Client>>,
+    /// The users that were logged in or built with a device ID, and receive
+    /// the end-to-end encryption data of the transactions.
+    crypto_users: Arc<DashSet<Localpart>>,
     event_handler: event_handler::EventHandler,
     default_request_config: Option<RequestConfig>,
+    health: HealthTracker,
+    transaction_lock: Arc<Mutex<()>>,
 }
 
 /// Builder for an AppService
@@ -206,8 +249,11 @@ impl AppServiceBuilder {
             registration,
             namespaces,
             clients,
+            crypto_users: Default::default(),
             event_handler,
             default_request_config,
+            health: HealthTracker::default(),
+            transaction_lock: Default::default(),
         };
         if let Some(client_builder) = self.client_builder {
             appservice
@@ -288,6 +334,38 @@ impl AppService {
         self.clients.clone()
     }
 
+    /// Create a new [`HistoryImporter`] for this appservice.
+    ///
+    /// See [`HistoryImporter::new()`] for the arguments.
+    pub fn history_importer(
+        &self,
+        room_id: OwnedRoomId,
+        prev_event_id: OwnedEventId,
+    ) -> HistoryImporter {
+        HistoryImporter::new(self.clone(), room_id, prev_event_id)
+    }
+
+    /// Create a new [`PortalManagerBuilder`] for this appservice.
+    ///
+    /// See [`PortalManagerBuilder::new()`] for the arguments.
+    ///
+    /// [`PortalManagerBuilder`]: portal::PortalManagerBuilder
+    /// [`PortalManagerBuilder::new()`]: portal::PortalManagerBuilder::new
+    pub fn portal_manager(
+        &self,
+        protocol: portal::BridgeInfo,
+        portal_source: impl portal::PortalSource + 'static,
+    ) -> portal::PortalManagerBuilder {
+        PortalManager::builder(self.clone(), protocol, portal_source)
+    }
+
+    /// Create a new [`VirtualUserManagerBuilder`] for this appservice.
+    ///
+    /// [`VirtualUserManagerBuilder`]: virtual_user::VirtualUserManagerBuilder
+    pub fn virtual_user_manager(&self) -> virtual_user::VirtualUserManagerBuilder {
+        VirtualUserManager::builder(self.clone())
+    }
+
     /// Register a responder for queries about the existence of a user with a
     /// given mxid.
     ///
@@ -330,6 +408,128 @@ impl AppService {
         *self.event_handler.rooms.lock().await = Some(handler);
     }
 
+    /// Register a responder for queries about the metadata of a third party
+    /// protocol.
+    ///
+    /// The responder returns `None` if the protocol is unknown.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/protocol/{protocol}](https://spec.matrix.org/v1.6/application-service-api/#get_matrixappv1thirdpartyprotocolprotocol).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::AppService;
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_protocol_query(Box::new(|_, req| {
+    ///     Box::pin(async move {
+    ///         println!("Got request for {}", req.protocol);
+    ///         None
+    ///     })
+    /// }));
+    /// # }
+    /// ```
+    pub async fn register_protocol_query(
+        &self,
+        handler: AppserviceFn<query_protocol::Request, Option<Protocol>>,
+    ) {
+        *self.event_handler.protocol.lock().await = Some(handler);
+    }
+
+    /// Register a responder for queries about the portal rooms of a third
+    /// party location.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/location/{protocol}](https://spec.matrix.org/v1.6/application-service-api/#get_matrixappv1thirdpartylocationprotocol).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::AppService;
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_location_for_protocol_query(Box::new(|_, req| {
+    ///     Box::pin(async move {
+    ///         println!("Got request for {} with {:?}", req.protocol, req.fields);
+    ///         Vec::new()
+    ///     })
+    /// }));
+    /// # }
+    /// ```
+    pub async fn register_location_for_protocol_query(
+        &self,
+        handler: AppserviceFn<query_location_for_protocol::Request, Vec<Location>>,
+    ) {
+        *self.event_handler.location_for_protocol.lock().await = Some(handler);
+    }
+
+    /// Register a responder for queries about the third party locations of a
+    /// portal room with the given alias.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/location](https://spec.matrix.org/v1.6/application-service-api/#get_matrixappv1thirdpartylocation).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::AppService;
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_location_for_room_alias_query(Box::new(|_, req| {
+    ///     Box::pin(async move {
+    ///         println!("Got request for {}", req.alias);
+    ///         Vec::new()
+    ///     })
+    /// }));
+    /// # }
+    /// ```
+    pub async fn register_location_for_room_alias_query(
+        &self,
+        handler: AppserviceFn<query_location_for_room_alias::Request, Vec<Location>>,
+    ) {
+        *self.event_handler.location_for_room_alias.lock().await = Some(handler);
+    }
+
+    /// Register a responder for queries about the Matrix users of a third
+    /// party user.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/user/{protocol}](https://spec.matrix.org/v1.6/application-service-api/#get_matrixappv1thirdpartyuserprotocol).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::AppService;
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_user_for_protocol_query(Box::new(|_, req| {
+    ///     Box::pin(async move {
+    ///         println!("Got request for {} with {:?}", req.protocol, req.fields);
+    ///         Vec::new()
+    ///     })
+    /// }));
+    /// # }
+    /// ```
+    pub async fn register_user_for_protocol_query(
+        &self,
+        handler: AppserviceFn<query_user_for_protocol::Request, Vec<User>>,
+    ) {
+        *self.event_handler.user_for_protocol.lock().await = Some(handler);
+    }
+
+    /// Register a responder for queries about the third party users of a
+    /// Matrix user.
+    ///
+    /// See [GET /_matrix/app/v1/thirdparty/user](https://spec.matrix.org/v1.6/application-service-api/#get_matrixappv1thirdpartyuser).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk_appservice::AppService;
+    /// # fn run(appservice: AppService) {
+    /// appservice.register_user_for_user_id_query(Box::new(|_, req| {
+    ///     Box::pin(async move {
+    ///         println!("Got request for {}", req.userid);
+    ///         Vec::new()
+    ///     })
+    /// }));
+    /// # }
+    /// ```
+    pub async fn register_user_for_user_id_query(
+        &self,
+        handler: AppserviceFn<query_user_for_user_id::Request, Vec<User>>,
+    ) {
+        *self.event_handler.user_for_user_id.lock().await = Some(handler);
+    }
+
     /// Register an appservice user by sending a [`register::v3::Request`] to
     /// the homeserver.
     ///
@@ -359,7 +559,7 @@ impl AppService {
     }
 
     /// Add the given localpart to the database of registered localparts.
-    async fn set_user_registered(&self, localpart: impl AsRef<str>) -> Result<()> {
+    pub(crate) async fn set_user_registered(&self, localpart: impl AsRef<str>) -> Result<()> {
         let client = self.user(None).await?;
         client
             .store()
@@ -372,7 +572,7 @@ impl AppService {
     }
 
     /// Get whether a localpart is listed in the database as registered.
-    async fn is_user_registered(&self, localpart: impl AsRef<str>) -> Result<bool> {
+    pub(crate) async fn is_user_registered(&self, localpart: impl AsRef<str>) -> Result<bool> {
         let client = self.user(None).await?;
         let key = [USER_KEY, localpart.as_ref().as_bytes()].concat();
         let store = client.store().get_custom_value(&key).await?;
@@ -393,6 +593,80 @@ impl AppService {
         self.registration.hs_token == hs_token.as_ref()
     }
 
+    /// Ask the homeserver to ping the appservice, to check that it can reach
+    /// it.
+    ///
+    /// Returns how long it took the homeserver to reach the appservice. The
+    /// request is sent by the client of the appservice user and fails if the
+    /// homeserver doesn't answer within 10 seconds. The result is also
+    /// recorded in the [`health()`][Self::health] of the appservice.
+    ///
+    /// This requires a homeserver that implements [MSC2659], and the
+    /// appservice to be [running][Self::run] or to serve its
+    /// [`service()`][Self::service].
+    ///
+    /// [MSC2659]: https://github.com/matrix-org/matrix-spec-proposals/pull/2659
+    pub async fn ping(&self) -> Result<Duration> {
+        let result = self.send_ping().await;
+        self.health.record_ping(&result);
+        result
+    }
+
+    async fn send_ping(&self) -> Result<Duration> {
+        let client = self.user(None).await?;
+        // A ping measures the latency, so it is not retried.
+        let config = RequestConfig::new().disable_retry().timeout(PING_TIMEOUT);
+        let appservice_id = &self.registration.id;
+        let transaction_id = TransactionId::new();
+
+        let request = api::ping::v1::Request::new(appservice_id.clone(), transaction_id.clone());
+        let result = match client.send(request, Some(config)).await {
+            // The homeserver doesn't support the stable endpoint yet.
+            Err(error) if error.client_api_error_kind() == Some(&ErrorKind::Unrecognized) => {
+                let request =
+                    api::ping::unstable::Request::new(appservice_id.clone(), transaction_id);
+                client.send(request, Some(config)).await.map(|response| response.duration)
+            }
+            result => result.map(|response| response.duration),
+        };
+
+        result.map_err(|error| match error.as_client_api_error().map(|error| &error.body) {
+            Some(ErrorBody::Standard { kind, message }) => {
+                Error::Ping { errcode: kind.to_string(), message: message.clone() }
+            }
+            _ => error.into(),
+        })
+    }
+
+    /// Get the current health of the link between the homeserver and the
+    /// appservice.
+    pub fn health(&self) -> Health {
+        self.health.get()
+    }
+
+    /// Spawn a task that [pings][Self::ping] the appservice through the
+    /// homeserver at the given interval, to keep its [`health()`][Self::health]
+    /// up to date.
+    ///
+    /// Failed pings are logged. The returned handle can be used to abort the
+    /// task.
+    pub fn start_health_monitor(&self, interval: Duration) -> JoinHandle<()> {
+        let appservice = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+
+                match appservice.ping().await {
+                    Ok(latency) => debug!(?latency, "The homeserver pinged the appservice"),
+                    Err(error) => warn!(%error, "The homeserver failed to ping the appservice"),
+                }
+            }
+        })
+    }
+
     /// Check if given `user_id` is in any of the [`AppServiceRegistration`]'s
     /// `users` namespaces.
     pub fn user_id_is_in_namespace(&self, user_id: impl AsRef<str>) -> bool {
@@ -400,6 +674,13 @@ impl AppService {
         self.namespaces.users.iter().any(|regex| regex.is_match(user_id))
     }
 
+    /// Check if given `room_alias` is in any of the
+    /// [`AppServiceRegistration`]'s `aliases` namespaces.
+    pub fn room_alias_is_in_namespace(&self, room_alias: impl AsRef<str>) -> bool {
+        let room_alias = room_alias.as_ref();
+        self.namespaces.aliases.iter().any(|regex| regex.is_match(room_alias))
+    }
+
     /// Returns a [`Service`][tower::Service] that processes appservice
     /// requests.
     pub fn service<B>(&self) -> AppServiceRouter<B>
@@ -414,8 +695,23 @@ impl AppService {
     /// Receive an incoming [transaction], pushing the contained events to
     /// active clients.
     ///
+    /// The to-device events and end-to-end encryption data in `extensions`
+    /// are only pushed to the client of the device they are meant for, if the
+    /// client was logged in or built with a device ID.
+    ///
+    /// Transactions are processed one at a time, in the order they are
+    /// received. An error is returned if the transaction couldn't be pushed to
+    /// one of the clients, so the homeserver sends it again later. Clients
+    /// that already processed it skip it then, see
+    /// [`Client::receive_transaction()`].
+    ///
     /// [transaction]: https://spec.matrix.org/v1.2/application-service-api/#put_matrixappv1transactionstxnid
-    async fn receive_transaction(&self, transaction: push_events::v1::Request) -> Result<()> {
+    async fn receive_transaction(
+        &self,
+        transaction: push_events::v1::Request,
+        extensions: TransactionExtensions,
+    ) -> Result<()> {
+        let _guard = self.transaction_lock.lock().await;
         let sender_localpart_client = self.user(None).await?;
 
         // Find membership events affecting members in our namespace, and update
@@ -447,10 +743,13 @@ impl AppService {
         // Spawn a task for each client that constructs and pushes a sync event
         let mut tasks: Vec<JoinHandle<_>> = Vec::new();
         let transaction = Arc::new(transaction);
+        let extensions = Arc::new(extensions);
         for user_client in self.clients.iter() {
             let client = sender_localpart_client.clone();
+            let has_crypto = self.crypto_users.contains(user_client.key());
             let user_client = user_client.clone();
             let transaction = transaction.clone();
+            let extensions = extensions.clone();
             let sender_localpart = self.registration.sender_localpart.clone();
 
             let task = tokio::spawn(async move {
@@ -461,6 +760,10 @@ impl AppService {
                 let user_localpart = user_id.localpart();
                 let mut response = sync_events::v3::Response::new(transaction.txn_id.to_string());
 
+                if let Some(device_id) = user_client.device_id().filter(|_| has_crypto) {
+                    extensions.fill_sync_response(user_id, device_id, &mut response);
+                }
+
                 // Clients expect events to be grouped per room, where the
                 // group also denotes what the client's membership of the given
                 // room is. We take all the events in the transaction and sort
@@ -473,14 +776,9 @@ impl AppService {
                         warn!("Transaction contained event with no ID");
                         continue;
                     };
-                    let key = &[USER_MEMBER, room_id.as_bytes(), b".", user_localpart.as_bytes()]
-                        .concat();
-                    let membership = match client.store().get_custom_value(key).await? {
-                        Some(value) => String::from_utf8(value).ok().map(MembershipState::from),
-                        // Assume the `sender_localpart` user is in every known room
-                        None if user_localpart == sender_localpart => Some(MembershipState::Join),
-                        None => None,
-                    };
+                    let membership =
+                        user_membership(&client, &room_id, user_localpart, &sender_localpart)
+                            .await?;
 
                     match membership {
                         Some(MembershipState::Join) => {
@@ -501,17 +799,56 @@ impl AppService {
                         None => debug!("Assuming {user_localpart} is not in {room_id}"),
                     }
                 }
+
+                // Ephemeral events of rooms are only pushed to the users that
+                // are in the room, while the presence of users is pushed to
+                // everyone.
+                for event in extensions.ephemeral_events() {
+                    match event {
+                        EphemeralEvent::Room(room_id, raw_event) => {
+                            let membership = user_membership(
+                                &client,
+                                &room_id,
+                                user_localpart,
+                                &sender_localpart,
+                            )
+                            .await?;
+
+                            if membership == Some(MembershipState::Join) {
+                                let room = response.rooms.join.entry(room_id).or_default();
+                                room.ephemeral.events.push(raw_event);
+                            }
+                        }
+                        EphemeralEvent::Presence(raw_event) => {
+                            response.presence.events.push(raw_event);
+                        }
+                    }
+                }
+
                 user_client.receive_transaction(&transaction.txn_id, response).await?;
                 Ok::<_, Error>(())
             });
 
             tasks.push(task);
         }
+        // Only acknowledge the transaction once every client processed it.
+        let mut result = Ok(());
         for task in tasks {
-            if let Err(e) = task.await {
-                warn!("Joining sync task failed: {e}");
+            let task_result = match task.await {
+                Ok(res) => res,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = task_result {
+                warn!("Pushing transaction to client failed: {e}");
+                if result.is_ok() {
+                    result = Err(e);
+                }
             }
         }
+        result?;
+
+        self.health.record_transaction();
+
         Ok(())
     }
 
@@ -538,6 +875,26 @@ impl AppService {
     }
 }
 
+/// Get the membership of the appservice user with the given localpart in the
+/// given room, as it was last seen in a transaction.
+///
+/// The `sender_localpart` user is assumed to be in every known room.
+async fn user_membership(
+    sender_localpart_client: &Client,
+    room_id: &RoomId,
+    user_localpart: &str,
+    sender_localpart: &str,
+) -> Result<Option<MembershipState>> {
+    let key = &[USER_MEMBER, room_id.as_bytes(), b".", user_localpart.as_bytes()].concat();
+    let membership = match sender_localpart_client.store().get_custom_value(key).await? {
+        Some(value) => String::from_utf8(value).ok().map(MembershipState::from),
+        None if user_localpart == sender_localpart => Some(MembershipState::Join),
+        None => None,
+    };
+
+    Ok(membership)
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -552,17 +909,27 @@ mod tests {
         ruma::{api::appservice::Registration, events::room::member::OriginalSyncRoomMemberEvent},
         Client, RoomMemberships,
     };
-    use matrix_sdk_test::{appservice::TransactionBuilder, async_test, TimelineTestEvent};
+    use matrix_sdk_test::{
+        appservice::TransactionBuilder, async_test, test_json, TimelineTestEvent,
+    };
     use ruma::{
-        api::{appservice::event::push_events, MatrixVersion},
-        events::AnyTimelineEvent,
-        room_id,
+        api::{
+            appservice::{event::push_events, Namespace},
+            MatrixVersion,
+        },
+        device_id,
+        events::{
+            dummy::ToDeviceDummyEvent, presence::PresenceEvent, typing::SyncTypingEvent,
+            AnyTimelineEvent,
+        },
+        room_alias_id, room_id,
         serde::Raw,
+        RoomAliasId,
     };
     use serde_json::json;
     use tower::{Service, ServiceExt};
     use wiremock::{
-        matchers::{body_json, header, method, path},
+        matchers::{body_json, body_partial_json, header, method, path, path_regex, query_param},
         Mock, MockServer, ResponseTemplate,
     };
 
@@ -651,6 +1018,167 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn test_put_transaction_updates_health() -> Result<()> {
+        let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
+
+        let mut transaction_builder = TransactionBuilder::new();
+        transaction_builder.add_timeline_event(TimelineTestEvent::Member);
+        let transaction = transaction_builder.build_transaction();
+
+        let appservice = appservice(None, None).await?;
+        assert!(appservice.health().last_transaction.is_none());
+
+        let response = appservice
+            .service()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(uri)
+                    .body(Body::from(transaction))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert!(appservice.health().last_transaction.is_some());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_put_transaction_failure_is_not_acknowledged() -> Result<()> {
+        let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
+
+        let transaction = json!({
+            "events": [{
+                "type": "m.room.message",
+                "event_id": "$event:localhost",
+                "room_id": "not a room id",
+                "sender": "@alice:localhost",
+                "origin_server_ts": 152037280,
+                "content": { "msgtype": "m.text", "body": "hello" }
+            }]
+        });
+
+        let appservice = appservice(None, None).await?;
+        let response = appservice
+            .service()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(uri)
+                    .body(Body::from(serde_json::to_vec(&transaction)?))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        // The homeserver must send the transaction again.
+        assert_eq!(response.status(), 500);
+        assert!(appservice.health().last_transaction.is_none());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_ping() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v1/appservice/appservice/ping"))
+            .and(header("authorization", "Bearer as_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "duration_ms": 123,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let latency = appservice.ping().await?;
+        assert_eq!(latency, Duration::from_millis(123));
+
+        let health = appservice.health();
+        assert!(health.last_ping.is_some());
+        assert_eq!(health.ping_latency, Some(latency));
+        assert!(health.ping_error.is_none());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_ping_failure() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/v1/appservice/appservice/ping"))
+            .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+                "errcode": "M_UNRECOGNIZED",
+                "error": "Unrecognized request",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/unstable/fi.mau.msc2659/appservice/appservice/ping"))
+            .respond_with(ResponseTemplate::new(502).set_body_json(json!({
+                "errcode": "M_CONNECTION_FAILED",
+                "error": "Connection refused",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let error = appservice.ping().await.unwrap_err();
+        assert!(matches!(error, Error::Ping { errcode, .. } if errcode == "M_CONNECTION_FAILED"));
+
+        let health = appservice.health();
+        assert!(health.last_ping.is_none());
+        assert!(health.ping_error.is_some());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_ping_handler() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+
+        let response = appservice
+            .service()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/_matrix/app/v1/ping")
+                    .header("authorization", "Bearer hs_token")
+                    .body(Body::from(r#"{"transaction_id":"mxtxn"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let response = appservice
+            .service()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/_matrix/app/v1/ping")
+                    .header("authorization", "Bearer as_token")
+                    .body(Body::from(r#"{"transaction_id":"mxtxn"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 401);
+
+        Ok(())
+    }
+
     #[async_test]
     async fn test_put_transaction_with_repeating_txn_id() -> Result<()> {
         let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
@@ -755,19 +1283,439 @@ mod tests {
         Ok(())
     }
 
+    fn registration_with_aliases() -> Result<Registration> {
+        let mut registration: Registration = serde_yaml::from_str(&registration_string())?;
+        registration.namespaces.aliases.push(Namespace::new(true, "#_appservice_.*".to_owned()));
+        Ok(registration)
+    }
+
     #[async_test]
-    async fn test_invalid_access_token() -> Result<()> {
-        let uri = "/_matrix/app/v1/transactions/1?access_token=invalid_token";
+    async fn test_room_alias_is_in_namespace() -> Result<()> {
+        let appservice = appservice(None, Some(registration_with_aliases()?)).await?;
 
-        let mut transaction_builder = TransactionBuilder::new();
-        let transaction =
-            transaction_builder.add_timeline_event(TimelineTestEvent::Member).build_transaction();
+        assert!(appservice.room_alias_is_in_namespace("#_appservice_chan:localhost"));
+        assert!(!appservice.room_alias_is_in_namespace("#chan:localhost"));
 
-        let appservice = appservice(None, None).await?;
+        Ok(())
+    }
 
-        let response = appservice
-            .service()
-            .oneshot(
+    #[async_test]
+    async fn test_portal_manager() -> Result<()> {
+        use portal::{BridgeInfo, Portal, PortalSource};
+
+        struct Portals;
+
+        #[matrix_sdk::async_trait]
+        impl PortalSource for Portals {
+            async fn portal(&self, room_alias: &RoomAliasId) -> Option<Portal> {
+                let channel = room_alias.alias().strip_prefix("_appservice_")?;
+                (channel != "unknown").then(|| Portal::new(BridgeInfo::new(channel)))
+            }
+        }
+
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), Some(registration_with_aliases()?)).await?;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/createRoom"))
+            .and(body_partial_json(json!({
+                "room_alias_name": "_appservice_chan",
+                "power_level_content_override": {
+                    "users": { "@_appservice:localhost": 100 }
+                },
+                "initial_state": [
+                    {
+                        "type": "m.room.join_rules",
+                        "content": { "join_rule": "public" }
+                    },
+                    {
+                        "type": "m.bridge",
+                        "state_key": "remote/chan",
+                        "content": {
+                            "bridgebot": "@_appservice:localhost",
+                            "protocol": { "id": "remote" },
+                            "channel": { "id": "chan" }
+                        }
+                    }
+                ]
+            })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "room_id": "!portal:localhost" })),
+            )
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/createRoom"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "room_id": "!portal2:localhost" })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = appservice.portal_manager(BridgeInfo::new("remote"), Portals).build();
+        manager.register_room_query().await;
+
+        let mut service = appservice.service();
+        let mut query = |alias: &str| {
+            let uri = format!("/_matrix/app/v1/rooms/{alias}?access_token=hs_token");
+            service.call(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let room_alias = room_alias_id!("#_appservice_chan:localhost");
+        assert_eq!(query("%23_appservice_chan:localhost").await.unwrap().status(), 200);
+        assert_eq!(query("%23_appservice_unknown:localhost").await.unwrap().status(), 404);
+        assert_eq!(query("%23chan:localhost").await.unwrap().status(), 404);
+
+        let room_id = manager.room_id(room_alias).await?;
+        assert_eq!(room_id.as_deref(), Some(room_id!("!portal:localhost")));
+
+        // The room is not created again outside of a query.
+        let room_id = manager.get_or_create_room(room_alias).await?;
+        assert_eq!(room_id.as_deref(), Some(room_id!("!portal:localhost")));
+
+        // The alias was deleted if it is queried again.
+        assert_eq!(query("%23_appservice_chan:localhost").await.unwrap().status(), 200);
+        let room_id = manager.room_id(room_alias).await?;
+        assert_eq!(room_id.as_deref(), Some(room_id!("!portal2:localhost")));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_portal_manager_room_in_use() -> Result<()> {
+        use portal::{BridgeInfo, Portal, PortalSource};
+
+        struct Portals;
+
+        #[matrix_sdk::async_trait]
+        impl PortalSource for Portals {
+            async fn portal(&self, _room_alias: &RoomAliasId) -> Option<Portal> {
+                Some(Portal::new(BridgeInfo::new("chan")))
+            }
+        }
+
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), Some(registration_with_aliases()?)).await?;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/createRoom"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "errcode": "M_ROOM_IN_USE",
+                "error": "Room alias already taken",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/client/r0/directory/room/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "room_id": "!portal:localhost",
+                "servers": ["localhost"],
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // The room created by a previous run is remembered.
+        let manager = appservice.portal_manager(BridgeInfo::new("remote"), Portals).build();
+        let room_alias = room_alias_id!("#_appservice_chan:localhost");
+        let room_id = manager.get_or_create_room(room_alias).await?;
+        assert_eq!(room_id.as_deref(), Some(room_id!("!portal:localhost")));
+
+        let room_id = manager.room_id(room_alias).await?;
+        assert_eq!(room_id.as_deref(), Some(room_id!("!portal:localhost")));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_history_importer() -> Result<()> {
+        use history::HistoryMessage;
+        use ruma::{
+            event_id, events::room::message::RoomMessageEventContent, user_id,
+            MilliSecondsSinceUnixEpoch, UInt,
+        };
+
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+        let batch_send_path = "/_matrix/client/unstable/org.matrix.msc2716/rooms/\
+                               !room:localhost/batch_send";
+
+        Mock::given(method("POST"))
+            .and(path(batch_send_path))
+            .and(query_param("prev_event_id", "$anchor:localhost"))
+            .and(query_param("batch_id", "batch1"))
+            .and(body_partial_json(json!({
+                "events": [{ "content": { "body": "1" } }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "state_event_ids": [],
+                "event_ids": ["$1:localhost"],
+                "next_batch_id": "batch2",
+                "insertion_event_id": "$insertion2:localhost",
+                "batch_event_id": "$batch2:localhost",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(batch_send_path))
+            .and(query_param("prev_event_id", "$anchor:localhost"))
+            .and(body_partial_json(json!({
+                "state_events_at_start": [{
+                    "type": "m.room.member",
+                    "state_key": "@_appservice_alice:localhost",
+                    "content": { "membership": "join" }
+                }],
+                "events": [
+                    { "content": { "body": "2" }, "origin_server_ts": 2 },
+                    { "content": { "body": "3" }, "origin_server_ts": 3 }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "state_event_ids": ["$member:localhost"],
+                "event_ids": ["$2:localhost", "$3:localhost"],
+                "next_batch_id": "batch1",
+                "insertion_event_id": "$insertion1:localhost",
+                "batch_event_id": "$batch1:localhost",
+                "base_insertion_event_id": "$base:localhost",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state/org.matrix.msc2716.marker/.*"))
+            .and(body_json(json!({
+                "org.matrix.msc2716.marker.insertion": "$base:localhost"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EVENT_ID))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let messages: Vec<_> = (1..=3u32)
+            .rev()
+            .map(|i| {
+                HistoryMessage::new(
+                    i.to_string(),
+                    user_id!("@_appservice_alice:localhost").to_owned(),
+                    MilliSecondsSinceUnixEpoch(UInt::from(i)),
+                    &RoomMessageEventContent::text_plain(i.to_string()),
+                )
+            })
+            .collect::<Result<_>>()?;
+
+        let importer = appservice
+            .history_importer(
+                room_id!("!room:localhost").to_owned(),
+                event_id!("$anchor:localhost").to_owned(),
+            )
+            .batch_size(2);
+        let summary = importer.import(futures_util::stream::iter(messages.clone())).await?;
+        assert_eq!(summary.imported, 3);
+        assert_eq!(summary.base_insertion_event_id.as_deref(), Some(event_id!("$base:localhost")));
+
+        // Everything was imported already, nothing is sent again.
+        let summary = importer.import(futures_util::stream::iter(messages)).await?;
+        assert_eq!(summary.imported, 3);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_get_protocol() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+        appservice
+            .register_protocol_query(Box::new(|_, req| {
+                Box::pin(async move {
+                    (req.protocol == "irc")
+                        .then(|| serde_json::from_value(test_json::thirdparty::PROTOCOL.clone()))
+                        .transpose()
+                        .unwrap()
+                })
+            }))
+            .await;
+
+        let uri = "/_matrix/app/v1/thirdparty/protocol/irc?access_token=hs_token";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let protocol: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(protocol["location_fields"], json!(["network", "channel"]));
+
+        let uri = "/_matrix/app/v1/thirdparty/protocol/gitter?access_token=hs_token";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_get_location_for_protocol() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+        appservice
+            .register_location_for_protocol_query(Box::new(|_, req| {
+                Box::pin(async move {
+                    assert_eq!(req.protocol, "irc");
+                    assert_eq!(req.fields.len(), 2, "The access token is not a field");
+
+                    if req.fields.get("channel").map(String::as_str) == Some("#matrix") {
+                        serde_json::from_value(test_json::thirdparty::LOCATIONS.clone()).unwrap()
+                    } else {
+                        Vec::new()
+                    }
+                })
+            }))
+            .await;
+
+        let uri = "/_matrix/app/v1/thirdparty/location/irc?access_token=hs_token\
+                   &network=freenode&channel=%23matrix";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let locations: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(locations, *test_json::thirdparty::LOCATIONS);
+
+        let uri = "/_matrix/app/v1/thirdparty/location/irc?access_token=hs_token\
+                   &network=freenode&channel=%23rust";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_get_location_for_room_alias() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+        appservice
+            .register_location_for_room_alias_query(Box::new(|_, req| {
+                Box::pin(async move {
+                    assert_eq!(req.alias, "#freenode_#matrix:matrix.org");
+                    serde_json::from_value(test_json::thirdparty::LOCATIONS.clone()).unwrap()
+                })
+            }))
+            .await;
+
+        let uri = "/_matrix/app/v1/thirdparty/location?access_token=hs_token\
+                   &alias=%23freenode_%23matrix:matrix.org";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let locations: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(locations, *test_json::thirdparty::LOCATIONS);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_get_user_for_protocol() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+        appservice
+            .register_user_for_protocol_query(Box::new(|_, req| {
+                Box::pin(async move {
+                    assert_eq!(req.protocol, "irc");
+                    assert_eq!(req.fields.get("nickname").map(String::as_str), Some("jim"));
+                    serde_json::from_value(test_json::thirdparty::USERS.clone()).unwrap()
+                })
+            }))
+            .await;
+
+        let uri = "/_matrix/app/v1/thirdparty/user/irc?access_token=hs_token\
+                   &network=freenode&nickname=jim";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let users: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(users, *test_json::thirdparty::USERS);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_get_user_for_user_id() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+
+        // Without a responder, there are no users.
+        let uri = "/_matrix/app/v1/thirdparty/user?access_token=hs_token\
+                   &userid=%40_appservice_jim:localhost";
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+
+        appservice
+            .register_user_for_user_id_query(Box::new(|_, req| {
+                Box::pin(async move {
+                    assert_eq!(req.userid, "@_appservice_jim:localhost");
+                    serde_json::from_value(test_json::thirdparty::USERS.clone()).unwrap()
+                })
+            }))
+            .await;
+
+        let response = appservice
+            .service()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let users: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(users, *test_json::thirdparty::USERS);
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_invalid_access_token() -> Result<()> {
+        let uri = "/_matrix/app/v1/transactions/1?access_token=invalid_token";
+
+        let mut transaction_builder = TransactionBuilder::new();
+        let transaction =
+            transaction_builder.add_timeline_event(TimelineTestEvent::Member).build_transaction();
+
+        let appservice = appservice(None, None).await?;
+
+        let response = appservice
+            .service()
+            .oneshot(
                 Request::builder()
                     .method(Method::PUT)
                     .uri(uri)
@@ -847,6 +1795,363 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn test_to_device_event_handler() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+
+        let bob = appservice
+            .user_builder("_appservice_bob")
+            .device_id(Some(device_id!("BOBDEVICE").to_owned()))
+            .build()
+            .await?;
+        let carl = appservice.user(Some("_appservice_carl")).await?;
+
+        #[allow(clippy::mutex_atomic)]
+        let on_bob_to_device = Arc::new(Mutex::new(false));
+        bob.add_event_handler({
+            let on_bob_to_device = on_bob_to_device.clone();
+            move |_ev: ToDeviceDummyEvent| {
+                *on_bob_to_device.lock().unwrap() = true;
+                future::ready(())
+            }
+        });
+
+        #[allow(clippy::mutex_atomic)]
+        let on_carl_to_device = Arc::new(Mutex::new(false));
+        carl.add_event_handler({
+            let on_carl_to_device = on_carl_to_device.clone();
+            move |_ev: ToDeviceDummyEvent| {
+                *on_carl_to_device.lock().unwrap() = true;
+                future::ready(())
+            }
+        });
+
+        let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
+
+        let mut transaction_builder = TransactionBuilder::new();
+        transaction_builder.add_to_device_event(json!({
+            "type": "m.dummy",
+            "sender": "@alice:localhost",
+            "to_user_id": "@_appservice_bob:localhost",
+            "to_device_id": "BOBDEVICE",
+            "content": {}
+        }));
+        let transaction = transaction_builder.build_transaction();
+
+        let response = appservice
+            .service()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(uri)
+                    .body(Body::from(transaction))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert!(*on_bob_to_device.lock().unwrap());
+        assert!(!*on_carl_to_device.lock().unwrap());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_masquerade_device() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/r0/account/whoami"))
+            .and(query_param("user_id", "@_appservice_bob:localhost"))
+            .and(query_param("org.matrix.msc3202.device_id", "BOBDEVICE"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@_appservice_bob:localhost",
+                "device_id": "BOBDEVICE",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client_builder = Client::builder()
+            .request_config(RequestConfig::default().disable_retry())
+            .server_versions([MatrixVersion::V1_0]);
+        let bob = appservice
+            .user_builder("_appservice_bob")
+            .device_id(Some(device_id!("BOBDEVICE").to_owned()))
+            .masquerade_device()
+            .client_builder(client_builder)
+            .build()
+            .await?;
+
+        bob.whoami().await?;
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_masquerade_device_requires_device_id() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+
+        let result = appservice.user_builder("_appservice_bob").masquerade_device().build().await;
+        assert!(matches!(result, Err(Error::MissingDeviceId)));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_to_device_event_without_device_id() -> Result<()> {
+        let appservice = appservice(None, None).await?;
+        let carl = appservice.user(Some("_appservice_carl")).await?;
+
+        #[allow(clippy::mutex_atomic)]
+        let on_carl_to_device = Arc::new(Mutex::new(false));
+        carl.add_event_handler({
+            let on_carl_to_device = on_carl_to_device.clone();
+            move |_ev: ToDeviceDummyEvent| {
+                *on_carl_to_device.lock().unwrap() = true;
+                future::ready(())
+            }
+        });
+
+        let uri = "/_matrix/app/v1/transactions/1?access_token=hs_token";
+
+        // Carl's device ID was generated, so it doesn't get the events sent to
+        // all the devices.
+        let mut transaction_builder = TransactionBuilder::new();
+        transaction_builder.add_to_device_event(json!({
+            "type": "m.dummy",
+            "sender": "@alice:localhost",
+            "to_user_id": "@_appservice_carl:localhost",
+            "to_device_id": "*",
+            "content": {}
+        }));
+        let transaction = transaction_builder.build_transaction();
+
+        let response = appservice
+            .service()
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri(uri)
+                    .body(Body::from(transaction))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert!(!*on_carl_to_device.lock().unwrap());
+
+        Ok(())
+    }
+
+    async fn mock_virtual_user_versions(server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::VERSIONS))
+            .mount(server)
+            .await;
+    }
+
+    #[async_test]
+    async fn test_virtual_user_manager_evicts_clients() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+        mock_virtual_user_versions(&server).await;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/register"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@_appservice_alice:localhost",
+            })))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let manager = appservice.virtual_user_manager().max_clients(2).build();
+        manager.user("_appservice_alice").await?;
+        manager.user("_appservice_bob").await?;
+        manager.user("_appservice_carl").await?;
+
+        let users = appservice.users();
+        assert!(!users.contains_key("_appservice_alice"));
+        assert!(users.contains_key("_appservice_bob"));
+        assert!(users.contains_key("_appservice_carl"));
+        assert!(users.contains_key("_appservice"));
+
+        // The client is created again, without registering the user again.
+        manager.user("_appservice_alice").await?;
+        assert!(users.contains_key("_appservice_alice"));
+        assert!(!users.contains_key("_appservice_bob"));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_virtual_user_manager_pins_crypto_clients() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+        mock_virtual_user_versions(&server).await;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/register"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@_appservice_alice:localhost",
+            })))
+            .mount(&server)
+            .await;
+
+        let alice = appservice
+            .user_builder("_appservice_alice")
+            .device_id(Some(device_id!("ALICEDEVICE").to_owned()))
+            .build()
+            .await?;
+
+        let manager = appservice.virtual_user_manager().max_clients(1).build();
+        let client = manager.user("_appservice_alice").await?;
+        assert_eq!(client.device_id(), alice.device_id());
+        manager.user("_appservice_bob").await?;
+        manager.user("_appservice_carl").await?;
+
+        let users = appservice.users();
+        assert!(users.contains_key("_appservice_alice"));
+        assert!(!users.contains_key("_appservice_bob"));
+        assert!(users.contains_key("_appservice_carl"));
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_virtual_user_manager_creates_client_once() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+        mock_virtual_user_versions(&server).await;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/register"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@_appservice_alice:localhost",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = appservice.virtual_user_manager().build();
+        let (first, second) =
+            tokio::join!(manager.user("_appservice_alice"), manager.user("_appservice_alice"));
+        assert_eq!(first?.user_id(), second?.user_id());
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_virtual_user_manager_register_users() -> Result<()> {
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/register"))
+            .and(body_json(json!({
+                "username": "_appservice_bob",
+                "type": "m.login.application_service"
+            })))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "errcode": "M_USER_IN_USE",
+                "error": "User ID already taken.",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/register"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@_appservice_alice:localhost",
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let manager = appservice
+            .virtual_user_manager()
+            .batch_size(2)
+            .batch_interval(Duration::from_millis(10))
+            .build();
+        let localparts = ["_appservice_alice", "_appservice_bob", "_appservice_carl"];
+        manager.register_users(localparts).await?;
+
+        // All the users are known to be registered now.
+        manager.register_users(localparts).await?;
+        for localpart in localparts {
+            assert!(appservice.is_user_registered(localpart).await?);
+        }
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_virtual_user_manager_profile_sync() -> Result<()> {
+        use virtual_user::{Avatar, ProfileSource, VirtualUserProfile};
+
+        struct Profiles;
+
+        #[matrix_sdk::async_trait]
+        impl ProfileSource for Profiles {
+            async fn profile(&self, localpart: &str) -> Option<VirtualUserProfile> {
+                Some(VirtualUserProfile {
+                    display_name: Some(localpart.trim_start_matches("_appservice_").to_owned()),
+                    avatar: Some(Avatar {
+                        content_type: "image/png".parse().unwrap(),
+                        data: b"avatar".to_vec(),
+                    }),
+                })
+            }
+        }
+
+        let server = MockServer::start().await;
+        let appservice = appservice(Some(server.uri()), None).await?;
+        mock_virtual_user_versions(&server).await;
+
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/r0/register"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "user_id": "@_appservice_alice:localhost",
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/r0/profile/.*/displayname"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(r"^/_matrix/client/r0/profile/.*/avatar_url"))
+            .and(body_json(json!({ "avatar_url": "mxc://localhost/avatar" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/media/r0/upload"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "content_uri": "mxc://localhost/avatar",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = appservice.virtual_user_manager().profile_source(Profiles).build();
+        manager.user("_appservice_alice").await?;
+        manager.user("_appservice_bob").await?;
+
+        // Nothing changed, so nothing is sent again.
+        manager.sync_profile("_appservice_alice").await?;
+
+        Ok(())
+    }
+
     #[async_test]
     async fn test_appservice_on_sub_path() -> Result<()> {
         let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
@@ -978,7 +2283,10 @@ mod tests {
         let alice = appservice.user(Some("_appservice_alice")).await?;
         let bob = appservice.user(Some("_appservice_bob")).await?;
         appservice
-            .receive_transaction(push_events::v1::Request::new("dontcare".into(), json))
+            .receive_transaction(
+                push_events::v1::Request::new("dontcare".into(), json),
+                Default::default(),
+            )
             .await?;
         let coolplace = room_id!("!coolplace:localhost");
         let boringplace = room_id!("!boringplace:localhost");
@@ -995,6 +2303,93 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn test_receive_transaction_ephemeral_events() -> Result<()> {
+        let events = vec![Raw::new(&json!({
+            "content": {
+                "avatar_url": null,
+                "displayname": "Alice",
+                "membership": "join"
+            },
+            "event_id": "$151800140517rfvjc:localhost",
+            "membership": "join",
+            "origin_server_ts": 151800140,
+            "sender": "@_appservice_alice:localhost",
+            "state_key": "@_appservice_alice:localhost",
+            "type": "m.room.member",
+            "room_id": "!coolplace:localhost",
+            "unsigned": {
+                "age": 2970366
+            }
+        }))?
+        .cast::<AnyTimelineEvent>()];
+        let extensions = serde_json::from_value(json!({
+            "de.sorunome.msc2409.ephemeral": [
+                {
+                    "type": "m.typing",
+                    "room_id": "!coolplace:localhost",
+                    "content": {
+                        "user_ids": ["@alice:localhost"]
+                    }
+                },
+                {
+                    "type": "m.presence",
+                    "sender": "@alice:localhost",
+                    "content": {
+                        "presence": "online"
+                    }
+                }
+            ]
+        }))?;
+
+        let appservice = appservice(None, None).await?;
+        let alice = appservice.user(Some("_appservice_alice")).await?;
+        let bob = appservice.user(Some("_appservice_bob")).await?;
+
+        #[allow(clippy::mutex_atomic)]
+        let on_alice_typing = Arc::new(Mutex::new(false));
+        alice.add_event_handler({
+            let on_alice_typing = on_alice_typing.clone();
+            move |_ev: SyncTypingEvent| {
+                *on_alice_typing.lock().unwrap() = true;
+                future::ready(())
+            }
+        });
+
+        #[allow(clippy::mutex_atomic)]
+        let on_bob_typing = Arc::new(Mutex::new(false));
+        bob.add_event_handler({
+            let on_bob_typing = on_bob_typing.clone();
+            move |_ev: SyncTypingEvent| {
+                *on_bob_typing.lock().unwrap() = true;
+                future::ready(())
+            }
+        });
+
+        #[allow(clippy::mutex_atomic)]
+        let on_bob_presence = Arc::new(Mutex::new(false));
+        bob.add_event_handler({
+            let on_bob_presence = on_bob_presence.clone();
+            move |_ev: PresenceEvent| {
+                *on_bob_presence.lock().unwrap() = true;
+                future::ready(())
+            }
+        });
+
+        appservice
+            .receive_transaction(
+                push_events::v1::Request::new("dontcare".into(), events),
+                extensions,
+            )
+            .await?;
+
+        assert!(*on_alice_typing.lock().unwrap(), "Alice should see typing in coolplace");
+        assert!(!*on_bob_typing.lock().unwrap(), "Bob should not see typing in coolplace");
+        assert!(*on_bob_presence.lock().unwrap(), "Bob should see the presence of Alice");
+
+        Ok(())
+    }
+
     mod registration {
         use ruma::api::appservice::Registration;
 
@@ -0,0 +1,422 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Portal rooms of a bridge.
+//!
+//! A portal is a Matrix room that is bridged to a channel of the remote
+//! network. Users usually open one by joining an alias in the namespace of the
+//! appservice, which makes the homeserver [query the appservice] about it. The
+//! [`PortalManager`] answers those queries by creating the room.
+//!
+//! [query the appservice]: https://spec.matrix.org/v1.6/application-service-api/#get_matrixappv1roomsroomalias
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use dashmap::DashMap;
+use matrix_sdk::async_trait;
+use ruma::{
+    api::client::{error::ErrorKind, room::create_room},
+    assign,
+    events::{
+        room::{
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            power_levels::RoomPowerLevelsEventContent,
+        },
+        AnyInitialStateEvent, InitialStateEvent,
+    },
+    int,
+    serde::Raw,
+    Int, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, UserId,
+};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{AppService, Error, Result};
+
+const PORTAL_KEY: &[u8] = b"appservice.portals.";
+
+/// A section of an `m.bridge` state event, as defined in [MSC2346].
+///
+/// [MSC2346]: https://github.com/matrix-org/matrix-spec-proposals/pull/2346
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BridgeInfo {
+    /// The identifier of the protocol, network or channel.
+    pub id: String,
+    /// The human-readable name of the protocol, network or channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub displayname: Option<String>,
+}
+
+impl BridgeInfo {
+    /// Create a new `BridgeInfo` with the given identifier.
+    pub fn new(id: impl Into<String>) -> Self {
+        Self { id: id.into(), displayname: None }
+    }
+}
+
+/// A remote channel that should be bridged to a portal room.
+#[derive(Debug, Clone)]
+pub struct Portal {
+    /// The name of the room.
+    pub name: Option<String>,
+    /// The topic of the room.
+    pub topic: Option<String>,
+    /// The network of the channel, if the protocol has several.
+    pub network: Option<BridgeInfo>,
+    /// The channel bridged to the room.
+    pub channel: BridgeInfo,
+}
+
+impl Portal {
+    /// Create a new `Portal` for the given channel.
+    pub fn new(channel: BridgeInfo) -> Self {
+        Self { name: None, topic: None, network: None, channel }
+    }
+}
+
+/// The source of the portals, provided by the bridge.
+#[async_trait]
+pub trait PortalSource: Send + Sync {
+    /// Get the portal that should be created for the given room alias.
+    ///
+    /// Returns `None` if the alias doesn't match a remote channel, in which
+    /// case no room is created.
+    async fn portal(&self, room_alias: &RoomAliasId) -> Option<Portal>;
+}
+
+/// Builder for a [`PortalManager`].
+pub struct PortalManagerBuilder {
+    appservice: AppService,
+    portal_source: Arc<dyn PortalSource>,
+    protocol: BridgeInfo,
+    join_rule: JoinRule,
+    power_levels: BTreeMap<OwnedUserId, Int>,
+    initial_state: Vec<Raw<AnyInitialStateEvent>>,
+}
+
+impl PortalManagerBuilder {
+    /// Create a new portal manager builder.
+    ///
+    /// # Arguments
+    ///
+    /// * `appservice` - The appservice whose `sender_localpart` user creates
+    ///   the rooms.
+    /// * `protocol` - The protocol of the remote network, used in the `m.bridge`
+    ///   state event of the rooms.
+    /// * `portal_source` - The source of the portals.
+    pub fn new(
+        appservice: AppService,
+        protocol: BridgeInfo,
+        portal_source: impl PortalSource + 'static,
+    ) -> Self {
+        Self {
+            appservice,
+            portal_source: Arc::new(portal_source),
+            protocol,
+            join_rule: JoinRule::Public,
+            power_levels: BTreeMap::new(),
+            initial_state: Vec::new(),
+        }
+    }
+
+    /// Set the join rule of the rooms.
+    ///
+    /// Defaults to [`JoinRule::Public`], so users can join the alias they
+    /// queried.
+    pub fn join_rule(mut self, join_rule: JoinRule) -> Self {
+        self.join_rule = join_rule;
+        self
+    }
+
+    /// Set the power level of a user in the rooms.
+    ///
+    /// The bridge bot, the user of the `sender_localpart`, always has a power
+    /// level of 100.
+    pub fn power_level(mut self, user_id: OwnedUserId, power_level: Int) -> Self {
+        self.power_levels.insert(user_id, power_level);
+        self
+    }
+
+    /// Add a state event to the initial state of the rooms.
+    pub fn initial_state(mut self, event: Raw<AnyInitialStateEvent>) -> Self {
+        self.initial_state.push(event);
+        self
+    }
+
+    /// Build the portal manager.
+    pub fn build(self) -> PortalManager {
+        PortalManager {
+            appservice: self.appservice,
+            portal_source: self.portal_source,
+            protocol: self.protocol,
+            join_rule: self.join_rule,
+            power_levels: self.power_levels,
+            initial_state: self.initial_state,
+            creation_locks: Default::default(),
+        }
+    }
+}
+
+impl fmt::Debug for PortalManagerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortalManagerBuilder")
+            .field("protocol", &self.protocol)
+            .field("join_rule", &self.join_rule)
+            .field("power_levels", &self.power_levels)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Manager of the portal rooms of a bridge.
+///
+/// Once [registered][Self::register_room_query], it answers the queries of
+/// the homeserver about room aliases in the namespace of the appservice by
+/// asking the [`PortalSource`] for the matching portal, and creating a room
+/// with that alias if there is one.
+///
+/// The rooms are created by the user of the `sender_localpart`, with the
+/// configured join rule and power levels, and an `m.bridge` state event as
+/// defined in [MSC2346]. The room of every alias is remembered in the store of
+/// that user, see [`room_id()`][Self::room_id].
+///
+/// The homeserver only queries the appservice about aliases that don't exist,
+/// so a query about an alias whose room is remembered means that the alias or
+/// the room was deleted, and a new room is created.
+///
+/// [MSC2346]: https://github.com/matrix-org/matrix-spec-proposals/pull/2346
+#[derive(Clone)]
+pub struct PortalManager {
+    appservice: AppService,
+    portal_source: Arc<dyn PortalSource>,
+    protocol: BridgeInfo,
+    join_rule: JoinRule,
+    power_levels: BTreeMap<OwnedUserId, Int>,
+    initial_state: Vec<Raw<AnyInitialStateEvent>>,
+    /// The locks held while the room of an alias is created, so concurrent
+    /// queries for the same alias create only one room.
+    creation_locks: Arc<DashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl PortalManager {
+    /// Create a new [`PortalManagerBuilder`].
+    pub fn builder(
+        appservice: AppService,
+        protocol: BridgeInfo,
+        portal_source: impl PortalSource + 'static,
+    ) -> PortalManagerBuilder {
+        PortalManagerBuilder::new(appservice, protocol, portal_source)
+    }
+
+    /// Answer the room alias queries of the homeserver with this manager.
+    ///
+    /// This replaces the handler set with
+    /// [`AppService::register_room_query()`].
+    pub async fn register_room_query(&self) {
+        let manager = self.clone();
+        self.appservice
+            .register_room_query(Box::new(move |_, req| {
+                let manager = manager.clone();
+                Box::pin(async move {
+                    match manager.answer_room_query(&req.room_alias).await {
+                        Ok(room_id) => room_id.is_some(),
+                        Err(error) => {
+                            warn!(room_alias = %req.room_alias, %error, "Creating portal failed");
+                            false
+                        }
+                    }
+                })
+            }))
+            .await;
+    }
+
+    /// Get the ID of the room that was created for the given alias.
+    pub async fn room_id(&self, room_alias: &RoomAliasId) -> Result<Option<OwnedRoomId>> {
+        let client = self.appservice.user(None).await?;
+        let key = [PORTAL_KEY, room_alias.as_bytes()].concat();
+
+        let room_id = match client.store().get_custom_value(&key).await? {
+            Some(value) => Some(RoomId::parse(std::str::from_utf8(&value)?)?),
+            None => None,
+        };
+
+        Ok(room_id)
+    }
+
+    /// Get the room of the given alias, creating it if the alias is in the
+    /// namespace of the appservice and the [`PortalSource`] has a portal for
+    /// it.
+    ///
+    /// If the alias is already used on the homeserver by a room that wasn't
+    /// remembered, for example because a previous run stopped after creating
+    /// it, that room is remembered and returned.
+    ///
+    /// Returns `None` if no room was created.
+    pub async fn get_or_create_room(
+        &self,
+        room_alias: &RoomAliasId,
+    ) -> Result<Option<OwnedRoomId>> {
+        self.get_or_create_room_inner(room_alias, None).await
+    }
+
+    /// Answer a query of the homeserver about the given alias.
+    ///
+    /// The room that is remembered for the alias when the query is received
+    /// is stale, so it is forgotten and a new room is created. A room created
+    /// by a concurrent query for the same alias is returned as-is.
+    async fn answer_room_query(&self, room_alias: &RoomAliasId) -> Result<Option<OwnedRoomId>> {
+        let stale_room_id = self.room_id(room_alias).await?;
+        self.get_or_create_room_inner(room_alias, stale_room_id.as_deref()).await
+    }
+
+    async fn get_or_create_room_inner(
+        &self,
+        room_alias: &RoomAliasId,
+        stale_room_id: Option<&RoomId>,
+    ) -> Result<Option<OwnedRoomId>> {
+        if !self.appservice.room_alias_is_in_namespace(room_alias) {
+            return Ok(None);
+        }
+
+        let lock = self.creation_locks.entry(room_alias.as_str().to_owned()).or_default().clone();
+        let result = self.get_or_create_room_locked(room_alias, stale_room_id, &lock).await;
+
+        // Only the map holds the lock once it is released by every caller.
+        drop(lock);
+        self.creation_locks.remove_if(room_alias.as_str(), |_, lock| Arc::strong_count(lock) == 1);
+
+        result
+    }
+
+    async fn get_or_create_room_locked(
+        &self,
+        room_alias: &RoomAliasId,
+        stale_room_id: Option<&RoomId>,
+        lock: &Mutex<()>,
+    ) -> Result<Option<OwnedRoomId>> {
+        let _guard = lock.lock().await;
+
+        let client = self.appservice.user(None).await?;
+        let key = [PORTAL_KEY, room_alias.as_bytes()].concat();
+
+        if let Some(room_id) = self.room_id(room_alias).await? {
+            if stale_room_id != Some(&*room_id) {
+                return Ok(Some(room_id));
+            }
+
+            debug!(%room_alias, %room_id, "Forgetting stale portal");
+            client.store().remove_custom_value(&key).await?;
+        }
+
+        let Some(portal) = self.portal_source.portal(room_alias).await else {
+            return Ok(None);
+        };
+
+        let room_id = match self.create_room(room_alias, portal).await {
+            Ok(room_id) => {
+                debug!(%room_alias, %room_id, "Created portal");
+                room_id
+            }
+            Err(Error::Matrix(error))
+                if matches!(error.client_api_error_kind(), Some(ErrorKind::RoomInUse)) =>
+            {
+                // The alias exists, so resolving it doesn't query the
+                // appservice again.
+                let room_id = client.resolve_room_alias(room_alias).await?.room_id;
+                debug!(%room_alias, %room_id, "Found existing portal");
+                room_id
+            }
+            Err(error) => return Err(error),
+        };
+
+        client.store().set_custom_value(&key, room_id.as_bytes().to_vec()).await?;
+
+        Ok(Some(room_id))
+    }
+
+    async fn create_room(&self, room_alias: &RoomAliasId, portal: Portal) -> Result<OwnedRoomId> {
+        let client = self.appservice.user(None).await?;
+        let bridge_bot = UserId::parse_with_server_name(
+            self.appservice.registration.sender_localpart.as_str(),
+            &self.appservice.server_name,
+        )?;
+
+        let mut power_levels = RoomPowerLevelsEventContent::new();
+        power_levels.users = self.power_levels.clone();
+        power_levels.users.insert(bridge_bot.clone(), int!(100));
+
+        let mut initial_state = self.initial_state.clone();
+        initial_state.push(
+            InitialStateEvent::new(RoomJoinRulesEventContent::new(self.join_rule.clone()))
+                .to_raw_any(),
+        );
+        initial_state.push(self.bridge_event(&bridge_bot, &portal)?);
+
+        let request = assign!(create_room::v3::Request::new(), {
+            room_alias_name: Some(room_alias.alias().to_owned()),
+            name: portal.name,
+            topic: portal.topic,
+            initial_state,
+            power_level_content_override: Some(Raw::new(&power_levels)?),
+        });
+
+        let room = client.create_room(request).await?;
+        Ok(room.room_id().to_owned())
+    }
+
+    /// Build the `m.bridge` state event of the given portal.
+    ///
+    /// Its state key is made of the IDs of the protocol, the network and the
+    /// channel, so it is unique per bridged channel.
+    fn bridge_event(
+        &self,
+        bridge_bot: &UserId,
+        portal: &Portal,
+    ) -> Result<Raw<AnyInitialStateEvent>> {
+        let state_key = [Some(&self.protocol), portal.network.as_ref(), Some(&portal.channel)]
+            .into_iter()
+            .flatten()
+            .map(|info| info.id.as_str())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut content = json!({
+            "bridgebot": bridge_bot,
+            "protocol": self.protocol,
+            "channel": portal.channel,
+        });
+        if let Some(network) = &portal.network {
+            content["network"] = serde_json::to_value(network)?;
+        }
+
+        let event = json!({
+            "type": "m.bridge",
+            "state_key": state_key,
+            "content": content,
+        });
+
+        Ok(Raw::from_json(serde_json::value::to_raw_value(&event)?))
+    }
+}
+
+impl fmt::Debug for PortalManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PortalManager")
+            .field("protocol", &self.protocol)
+            .field("join_rule", &self.join_rule)
+            .field("power_levels", &self.power_levels)
+            .finish_non_exhaustive()
+    }
+}
@@ -91,8 +91,7 @@ pub struct NamespaceCache {
     /// List of user regexes in our namespace
     pub(crate) users: Vec<Regex>,
     /// List of alias regexes in our namespace
-    #[allow(dead_code)]
-    aliases: Vec<Regex>,
+    pub(crate) aliases: Vec<Regex>,
     /// List of room id regexes in our namespace
     #[allow(dead_code)]
     rooms: Vec<Regex>,
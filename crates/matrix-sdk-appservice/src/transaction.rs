@@ -0,0 +1,252 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unstable fields of appservice transactions.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    api::client::sync::sync_events::{self, DeviceLists},
+    events::{
+        presence::PresenceEvent, AnyEphemeralRoomEvent, AnySyncEphemeralRoomEvent, AnyToDeviceEvent,
+    },
+    serde::Raw,
+    DeviceId, DeviceKeyAlgorithm, OwnedDeviceId, OwnedRoomId, OwnedUserId, UInt, UserId,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+type PerDevice<T> = BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, T>>;
+
+/// The fields of a transaction that are only defined in [MSC2409] and
+/// [MSC3202].
+///
+/// They allow the appservice to receive the ephemeral events, the to-device
+/// events and the end-to-end encryption data of its users, without running a
+/// sync for each of them.
+///
+/// [MSC2409]: https://github.com/matrix-org/matrix-spec-proposals/pull/2409
+/// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct TransactionExtensions {
+    /// The ephemeral events for the users of the appservice, like typing
+    /// notifications, receipts and presence.
+    #[serde(default)]
+    ephemeral: Vec<Raw<AnyEphemeralRoomEvent>>,
+
+    /// The name of the field above before MSC2409 is stable.
+    #[serde(rename = "de.sorunome.msc2409.ephemeral", default)]
+    unstable_ephemeral: Vec<Raw<AnyEphemeralRoomEvent>>,
+
+    /// The to-device events for the devices of the users of the appservice.
+    #[serde(rename = "de.sorunome.msc2409.to_device", default)]
+    to_device: Vec<Raw<AnyToDeviceEvent>>,
+
+    /// The users whose devices changed, or that don't share an encrypted room
+    /// with the users of the appservice anymore.
+    #[serde(rename = "org.matrix.msc3202.device_lists", default)]
+    device_lists: DeviceLists,
+
+    /// The number of unclaimed one-time keys of the devices of the users of
+    /// the appservice.
+    #[serde(rename = "org.matrix.msc3202.device_one_time_keys_count", default)]
+    device_one_time_keys_count: Option<PerDevice<BTreeMap<DeviceKeyAlgorithm, UInt>>>,
+
+    /// The name of the field above in earlier versions of MSC3202, that is
+    /// still sent by some homeservers.
+    #[serde(rename = "org.matrix.msc3202.device_one_time_key_counts", default)]
+    device_one_time_key_counts: Option<PerDevice<BTreeMap<DeviceKeyAlgorithm, UInt>>>,
+
+    /// The unused fallback key algorithms of the devices of the users of the
+    /// appservice.
+    #[serde(rename = "org.matrix.msc3202.device_unused_fallback_key_types", default)]
+    device_unused_fallback_key_types: PerDevice<Vec<DeviceKeyAlgorithm>>,
+}
+
+/// An ephemeral event of a transaction, according to where it must be pushed
+/// in a sync response.
+#[derive(Debug)]
+pub(crate) enum EphemeralEvent {
+    /// An event of a room, like a typing notification or a receipt.
+    Room(OwnedRoomId, Raw<AnySyncEphemeralRoomEvent>),
+    /// The presence of a user.
+    Presence(Raw<PresenceEvent>),
+}
+
+/// Helper type for extracting the kind of an ephemeral event.
+#[derive(Debug, Deserialize)]
+struct EphemeralEventInfo {
+    #[serde(rename = "type")]
+    event_type: String,
+    room_id: Option<OwnedRoomId>,
+}
+
+/// Helper type for extracting the recipient of a to-device event.
+#[derive(Debug, Deserialize)]
+struct ToDeviceRecipient {
+    to_user_id: OwnedUserId,
+    to_device_id: String,
+}
+
+impl TransactionExtensions {
+    /// The ephemeral events of the transaction.
+    ///
+    /// Events that are neither presence events nor have a room ID are
+    /// skipped.
+    pub(crate) fn ephemeral_events(&self) -> impl Iterator<Item = EphemeralEvent> + '_ {
+        self.ephemeral.iter().chain(&self.unstable_ephemeral).filter_map(|event| {
+            match event.deserialize_as::<EphemeralEventInfo>() {
+                Ok(info) if info.event_type == "m.presence" => {
+                    Some(EphemeralEvent::Presence(event.clone().cast()))
+                }
+                Ok(EphemeralEventInfo { room_id: Some(room_id), .. }) => {
+                    Some(EphemeralEvent::Room(room_id, event.clone().cast()))
+                }
+                Ok(_) => {
+                    warn!("Transaction contained ephemeral event with no room ID");
+                    None
+                }
+                Err(error) => {
+                    warn!(%error, "Transaction contained invalid ephemeral event");
+                    None
+                }
+            }
+        })
+    }
+
+    /// Add the parts of the transaction that are meant for the given device to
+    /// the sync response of its user.
+    pub(crate) fn fill_sync_response(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        response: &mut sync_events::v3::Response,
+    ) {
+        response.to_device.events = self
+            .to_device
+            .iter()
+            .filter(|event| match event.deserialize_as::<ToDeviceRecipient>() {
+                Ok(recipient) => {
+                    recipient.to_user_id == user_id
+                        && (recipient.to_device_id == "*"
+                            || recipient.to_device_id == device_id.as_str())
+                }
+                Err(error) => {
+                    warn!(%error, "Transaction contained to-device event with no recipient");
+                    false
+                }
+            })
+            .cloned()
+            .collect();
+
+        response.device_lists = self.device_lists.clone();
+
+        let one_time_keys_counts =
+            self.device_one_time_keys_count.as_ref().or(self.device_one_time_key_counts.as_ref());
+        if let Some(counts) = one_time_keys_counts.and_then(|c| c.get(user_id)?.get(device_id)) {
+            response.device_one_time_keys_count = counts.clone();
+        }
+
+        response.device_unused_fallback_key_types = self
+            .device_unused_fallback_key_types
+            .get(user_id)
+            .and_then(|devices| devices.get(device_id))
+            .cloned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        api::client::sync::sync_events, device_id, events::AnyToDeviceEvent, user_id,
+        DeviceKeyAlgorithm, UInt,
+    };
+    use serde_json::json;
+
+    use super::TransactionExtensions;
+
+    #[test]
+    fn test_fill_sync_response() {
+        let extensions: TransactionExtensions = serde_json::from_value(json!({
+            "events": [],
+            "de.sorunome.msc2409.to_device": [
+                {
+                    "type": "m.dummy",
+                    "sender": "@alice:localhost",
+                    "to_user_id": "@_appservice_bob:localhost",
+                    "to_device_id": "BOBDEVICE",
+                    "content": {}
+                },
+                {
+                    "type": "m.dummy",
+                    "sender": "@alice:localhost",
+                    "to_user_id": "@_appservice_carl:localhost",
+                    "to_device_id": "*",
+                    "content": {}
+                }
+            ],
+            "org.matrix.msc3202.device_lists": {
+                "changed": ["@alice:localhost"],
+                "left": []
+            },
+            "org.matrix.msc3202.device_one_time_keys_count": {
+                "@_appservice_bob:localhost": {
+                    "BOBDEVICE": { "signed_curve25519": 20 }
+                }
+            },
+            "org.matrix.msc3202.device_one_time_key_counts": {
+                "@_appservice_bob:localhost": {
+                    "BOBDEVICE": { "signed_curve25519": 10 }
+                }
+            },
+            "org.matrix.msc3202.device_unused_fallback_key_types": {
+                "@_appservice_bob:localhost": {
+                    "BOBDEVICE": ["signed_curve25519"]
+                }
+            }
+        }))
+        .unwrap();
+
+        let mut response = sync_events::v3::Response::new("txn".to_owned());
+        extensions.fill_sync_response(
+            user_id!("@_appservice_bob:localhost"),
+            device_id!("BOBDEVICE"),
+            &mut response,
+        );
+
+        assert_eq!(response.to_device.events.len(), 1);
+        let event = response.to_device.events[0].deserialize().unwrap();
+        assert!(matches!(event, AnyToDeviceEvent::Dummy(_)));
+        assert_eq!(response.device_lists.changed, vec![user_id!("@alice:localhost").to_owned()]);
+        assert_eq!(
+            response.device_one_time_keys_count.get(&DeviceKeyAlgorithm::SignedCurve25519),
+            Some(&UInt::from(20u32))
+        );
+        assert_eq!(
+            response.device_unused_fallback_key_types,
+            Some(vec![DeviceKeyAlgorithm::SignedCurve25519])
+        );
+
+        let mut response = sync_events::v3::Response::new("txn".to_owned());
+        extensions.fill_sync_response(
+            user_id!("@_appservice_carl:localhost"),
+            device_id!("CARLDEVICE"),
+            &mut response,
+        );
+
+        assert_eq!(response.to_device.events.len(), 1);
+        assert!(response.device_one_time_keys_count.is_empty());
+        assert!(response.device_unused_fallback_key_types.is_none());
+    }
+}
@@ -21,7 +21,7 @@ use ruma::{
 };
 use tracing::warn;
 
-use crate::{AppService, Result};
+use crate::{AppService, Error, Result};
 
 /// Builder for an appservice user
 #[derive(Debug)]
@@ -31,6 +31,7 @@ pub struct UserBuilder<'a> {
     device_id: Option<OwnedDeviceId>,
     client_builder: ClientBuilder,
     log_in: bool,
+    masquerade_device: bool,
     restored_session: Option<Session>,
 }
 
@@ -46,11 +47,16 @@ impl<'a> UserBuilder<'a> {
             device_id: None,
             client_builder: Client::builder(),
             log_in: false,
+            masquerade_device: false,
             restored_session: None,
         }
     }
 
     /// Set the device ID of the appservice user
+    ///
+    /// Users that are not logged in only take part in end-to-end encryption if
+    /// their device ID is set, otherwise a device ID that doesn't exist on the
+    /// homeserver is generated for them.
     pub fn device_id(mut self, device_id: Option<OwnedDeviceId>) -> Self {
         self.device_id = device_id;
         self
@@ -71,6 +77,23 @@ impl<'a> UserBuilder<'a> {
         self
     }
 
+    /// Make requests as the device of the appservice user
+    ///
+    /// This uses the device masquerading of [MSC3202], so the user can upload
+    /// the keys of its device without logging in. The device must exist on
+    /// the homeserver, for example by registering the user with
+    /// [`AppService::register_user()`] with the same device ID, and it must
+    /// be set with [`UserBuilder::device_id()`], otherwise
+    /// [`UserBuilder::build()`] fails.
+    ///
+    /// This has no effect if [`UserBuilder::login()`] is enabled.
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    pub fn masquerade_device(mut self) -> Self {
+        self.masquerade_device = true;
+        self
+    }
+
     /// Restore a persisted session
     ///
     /// This is primarily useful if you enable
@@ -84,12 +107,18 @@ impl<'a> UserBuilder<'a> {
     /// Build the appservice user
     ///
     /// # Errors
-    /// This function returns an error if an invalid localpart is provided.
+    /// This function returns an error if an invalid localpart is provided, or
+    /// if [`UserBuilder::masquerade_device()`] is enabled without a device ID.
     pub async fn build(self) -> Result<Client> {
         if let Some(client) = self.appservice.clients.get(self.localpart) {
             return Ok(client.clone());
         }
 
+        let masquerade_device = self.masquerade_device && !self.log_in;
+        if masquerade_device && self.device_id.is_none() && self.restored_session.is_none() {
+            return Err(Error::MissingDeviceId);
+        }
+
         let user_id = UserId::parse_with_server_name(self.localpart, &self.appservice.server_name)?;
         if !(self.appservice.user_id_is_in_namespace(&user_id)
             || self.localpart == self.appservice.registration.sender_localpart)
@@ -99,10 +128,19 @@ impl<'a> UserBuilder<'a> {
 
         let mut builder = self.client_builder;
 
+        // Without logging in, the requests are made with the access token of
+        // the appservice, which already identifies its own user but doesn't
+        // belong to a device.
         if !self.log_in && self.localpart != self.appservice.registration.sender_localpart {
             builder = builder.assert_identity();
         }
 
+        // The device is only given with the asserted identity, so it is also
+        // asserted for the user of the appservice.
+        if masquerade_device {
+            builder = builder.assert_identity().assert_device_identity();
+        }
+
         let client = builder
             .homeserver_url(self.appservice.homeserver_url.clone())
             .appservice_mode()
@@ -110,9 +148,14 @@ impl<'a> UserBuilder<'a> {
             .await
             .map_err(ClientBuildError::assert_valid_builder_args)?;
 
+        let log_in = self.log_in && self.localpart != self.appservice.registration.sender_localpart;
+
+        // Only the generated device IDs don't exist on the homeserver.
+        let has_crypto = log_in || self.device_id.is_some() || self.restored_session.is_some();
+
         let session = if let Some(session) = self.restored_session {
             session
-        } else if self.log_in && self.localpart != self.appservice.registration.sender_localpart {
+        } else if log_in {
             let login_info =
                 login::v3::LoginInfo::ApplicationService(login::v3::ApplicationService::new(
                     UserIdentifier::UserIdOrLocalpart(self.localpart.to_owned()),
@@ -144,6 +187,9 @@ impl<'a> UserBuilder<'a> {
 
         client.restore_session(session).await?;
 
+        if has_crypto {
+            self.appservice.crypto_users.insert(self.localpart.to_owned());
+        }
         self.appservice.clients.insert(self.localpart.to_owned(), client.clone());
 
         Ok(client)
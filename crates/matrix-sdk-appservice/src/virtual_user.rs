@@ -0,0 +1,497 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Management of the virtual users of a bridge.
+//!
+//! Bridges usually have one virtual user per user of the remote network. The
+//! [`VirtualUserManager`] registers them on the homeserver, keeps a bounded
+//! number of their clients in memory and keeps their profile in sync with the
+//! remote network.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use matrix_sdk::{async_trait, Client};
+use mime::Mime;
+use ruma::{api::client::error::ErrorKind, OwnedMxcUri};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{sync::Mutex as AsyncMutex, task::JoinHandle};
+use tracing::{debug, warn};
+
+use crate::{AppService, Error, Localpart, Result};
+
+const PROFILE_KEY: &[u8] = b"appservice.profiles.";
+const AVATAR_KEY: &[u8] = b"appservice.avatars.";
+
+/// How many times the registration of a user is retried when the homeserver
+/// rate-limits it.
+const MAX_RATE_LIMITED_ATTEMPTS: usize = 3;
+
+/// The profile of a virtual user on the remote network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VirtualUserProfile {
+    /// The display name of the user.
+    pub display_name: Option<String>,
+    /// The avatar of the user.
+    pub avatar: Option<Avatar>,
+}
+
+/// The avatar of a virtual user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Avatar {
+    /// The content type of the image.
+    pub content_type: Mime,
+    /// The image data.
+    pub data: Vec<u8>,
+}
+
+impl Avatar {
+    /// The SHA-256 hash of the image data, as a hexadecimal string.
+    fn hash(&self) -> String {
+        Sha256::digest(&self.data).iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// The source of the profiles of the virtual users, provided by the bridge.
+#[async_trait]
+pub trait ProfileSource: Send + Sync {
+    /// Get the profile of the virtual user with the given localpart.
+    ///
+    /// Returns `None` if the profile is unknown, in which case the profile of
+    /// the user on the homeserver is left untouched.
+    async fn profile(&self, localpart: &str) -> Option<VirtualUserProfile>;
+}
+
+/// The profile that was last set on the homeserver for a virtual user.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ProfileState {
+    display_name: Option<String>,
+    avatar_hash: Option<String>,
+}
+
+/// The localparts of the clients of the manager, ordered by last use.
+#[derive(Debug, Default)]
+struct LruOrder {
+    next_tick: u64,
+    ticks: HashMap<Localpart, u64>,
+    localparts: BTreeMap<u64, Localpart>,
+}
+
+impl LruOrder {
+    /// Mark the given localpart as the most recently used.
+    fn touch(&mut self, localpart: &str) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        if let Some(previous) = self.ticks.insert(localpart.to_owned(), tick) {
+            self.localparts.remove(&previous);
+        }
+        self.localparts.insert(tick, localpart.to_owned());
+    }
+
+    /// Remove the least recently used localparts until there are at most
+    /// `max` left, and return them.
+    fn evict(&mut self, max: usize) -> Vec<Localpart> {
+        let mut evicted = Vec::new();
+
+        while self.ticks.len() > max {
+            let Some(&tick) = self.localparts.keys().next() else { break };
+            if let Some(localpart) = self.localparts.remove(&tick) {
+                self.ticks.remove(&localpart);
+                evicted.push(localpart);
+            }
+        }
+
+        evicted
+    }
+}
+
+/// Builder for a [`VirtualUserManager`].
+pub struct VirtualUserManagerBuilder {
+    appservice: AppService,
+    max_clients: usize,
+    batch_size: usize,
+    batch_interval: Duration,
+    profile_source: Option<Arc<dyn ProfileSource>>,
+}
+
+impl VirtualUserManagerBuilder {
+    /// Create a new virtual user manager builder for the given appservice.
+    pub fn new(appservice: AppService) -> Self {
+        Self {
+            appservice,
+            max_clients: 1000,
+            batch_size: 10,
+            batch_interval: Duration::from_secs(1),
+            profile_source: None,
+        }
+    }
+
+    /// Set the maximum number of clients of virtual users kept in memory.
+    ///
+    /// Defaults to 1000.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    /// Set how many users are registered at the same time by
+    /// [`VirtualUserManager::register_users()`].
+    ///
+    /// Defaults to 10.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Set how long to wait between two batches of registrations.
+    ///
+    /// Defaults to 1 second.
+    pub fn batch_interval(mut self, batch_interval: Duration) -> Self {
+        self.batch_interval = batch_interval;
+        self
+    }
+
+    /// Set the source of the profiles of the virtual users.
+    ///
+    /// The profile of a user is synced when its client is created, and can be
+    /// synced again with [`VirtualUserManager::sync_profile()`].
+    pub fn profile_source(mut self, profile_source: impl ProfileSource + 'static) -> Self {
+        self.profile_source = Some(Arc::new(profile_source));
+        self
+    }
+
+    /// Build the virtual user manager.
+    pub fn build(self) -> VirtualUserManager {
+        VirtualUserManager {
+            appservice: self.appservice,
+            max_clients: self.max_clients,
+            batch_size: self.batch_size,
+            batch_interval: self.batch_interval,
+            profile_source: self.profile_source,
+            lru: Default::default(),
+            creation_locks: Default::default(),
+        }
+    }
+}
+
+impl fmt::Debug for VirtualUserManagerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtualUserManagerBuilder")
+            .field("max_clients", &self.max_clients)
+            .field("batch_size", &self.batch_size)
+            .field("batch_interval", &self.batch_interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Manager of the virtual users of a bridge.
+///
+/// The clients of the virtual users are created lazily by
+/// [`user()`][Self::user], registering the users first if needed, and only
+/// the most recently used ones are kept. The least recently used clients are
+/// dropped from the [`AppService::users()`] once there are more than the
+/// configured maximum, so they stop receiving the transactions of the
+/// appservice until they are used again. Event handlers should therefore be
+/// added to the client of the `sender_localpart`, which receives every event.
+///
+/// The clients that handle end-to-end encryption, because they were logged in
+/// or built with a device ID, are never dropped: their Olm machine would miss
+/// the room keys sent while they are not in memory, and could be created again
+/// with a new identity if their store is not persisted.
+///
+/// The users that are registered, the profiles that were set and the avatars
+/// that were uploaded are persisted in the store of the client of the
+/// `sender_localpart`, so they are not registered, set or uploaded again
+/// after a restart.
+#[derive(Clone)]
+pub struct VirtualUserManager {
+    appservice: AppService,
+    max_clients: usize,
+    batch_size: usize,
+    batch_interval: Duration,
+    profile_source: Option<Arc<dyn ProfileSource>>,
+    lru: Arc<Mutex<LruOrder>>,
+    /// The locks held while the client of a user is created, so concurrent
+    /// calls to [`user()`][Self::user] create it only once.
+    creation_locks: Arc<DashMap<Localpart, Arc<AsyncMutex<()>>>>,
+}
+
+impl VirtualUserManager {
+    /// Create a new [`VirtualUserManagerBuilder`].
+    pub fn builder(appservice: AppService) -> VirtualUserManagerBuilder {
+        VirtualUserManagerBuilder::new(appservice)
+    }
+
+    /// Get the client of the virtual user with the given localpart.
+    ///
+    /// The user is registered if it isn't already, and its profile is synced
+    /// if the client is created and a [`ProfileSource`] is set.
+    pub async fn user(&self, localpart: &str) -> Result<Client> {
+        let lock = self.creation_locks.entry(localpart.to_owned()).or_default().clone();
+        let result = self.user_locked(localpart, &lock).await;
+
+        // Only the map holds the lock once it is released by every caller.
+        drop(lock);
+        self.creation_locks.remove_if(localpart, |_, lock| Arc::strong_count(lock) == 1);
+
+        result
+    }
+
+    async fn user_locked(&self, localpart: &str, lock: &AsyncMutex<()>) -> Result<Client> {
+        let _guard = lock.lock().await;
+
+        let created = !self.appservice.clients.contains_key(localpart);
+        if created {
+            self.register_user(localpart).await?;
+        }
+
+        let client = self.appservice.user(Some(localpart)).await?;
+        self.touch(localpart);
+
+        if created {
+            if let Some(profile_source) = &self.profile_source {
+                if let Some(profile) = profile_source.profile(localpart).await {
+                    self.set_profile_with_client(&client, localpart, &profile).await?;
+                }
+            }
+        }
+
+        Ok(client)
+    }
+
+    /// Register the virtual users with the given localparts.
+    ///
+    /// The users that are already registered are skipped, and the other ones
+    /// are registered by batches, waiting between each batch to avoid being
+    /// rate-limited by the homeserver. When a registration is rate-limited
+    /// anyway, it is retried after the delay requested by the homeserver.
+    ///
+    /// All the batches are attempted, the first error is returned.
+    pub async fn register_users<I, S>(&self, localparts: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut unregistered = Vec::new();
+        for localpart in localparts {
+            let localpart = localpart.as_ref();
+            if !self.appservice.is_user_registered(localpart).await? {
+                unregistered.push(localpart.to_owned());
+            }
+        }
+
+        let mut result = Ok(());
+        for (i, batch) in unregistered.chunks(self.batch_size).enumerate() {
+            if i > 0 {
+                tokio::time::sleep(self.batch_interval).await;
+            }
+
+            let tasks: Vec<JoinHandle<_>> = batch
+                .iter()
+                .map(|localpart| {
+                    let manager = self.clone();
+                    let localpart = localpart.clone();
+                    tokio::spawn(async move { manager.register_user(&localpart).await })
+                })
+                .collect();
+
+            for task in tasks {
+                let task_result = match task.await {
+                    Ok(res) => res,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = task_result {
+                    warn!("Registering virtual user failed: {e}");
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Sync the profile of the virtual user with the given localpart from the
+    /// [`ProfileSource`].
+    ///
+    /// Does nothing if no profile source is set or if it doesn't know the
+    /// profile of the user.
+    pub async fn sync_profile(&self, localpart: &str) -> Result<()> {
+        let Some(profile_source) = &self.profile_source else {
+            return Ok(());
+        };
+        let Some(profile) = profile_source.profile(localpart).await else {
+            return Ok(());
+        };
+
+        self.set_profile(localpart, &profile).await
+    }
+
+    /// Set the profile of the virtual user with the given localpart.
+    ///
+    /// Only the parts of the profile that changed since it was last set are
+    /// sent to the homeserver, and an avatar is only uploaded once, even if
+    /// it is used by several users.
+    pub async fn set_profile(&self, localpart: &str, profile: &VirtualUserProfile) -> Result<()> {
+        let client = self.user(localpart).await?;
+        self.set_profile_with_client(&client, localpart, profile).await
+    }
+
+    async fn set_profile_with_client(
+        &self,
+        client: &Client,
+        localpart: &str,
+        profile: &VirtualUserProfile,
+    ) -> Result<()> {
+        let store_client = self.appservice.user(None).await?;
+        let store = store_client.store();
+        let key = [PROFILE_KEY, localpart.as_bytes()].concat();
+
+        let mut state: ProfileState = match store.get_custom_value(&key).await? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => ProfileState::default(),
+        };
+
+        if profile.display_name != state.display_name {
+            debug!(localpart, "Updating display name of virtual user");
+            client.account().set_display_name(profile.display_name.as_deref()).await?;
+            state.display_name = profile.display_name.clone();
+            store.set_custom_value(&key, serde_json::to_vec(&state)?).await?;
+        }
+
+        let avatar_hash = profile.avatar.as_ref().map(Avatar::hash);
+        if avatar_hash != state.avatar_hash {
+            debug!(localpart, "Updating avatar of virtual user");
+            let avatar_url = match (&profile.avatar, &avatar_hash) {
+                (Some(avatar), Some(hash)) => Some(self.upload_avatar(client, avatar, hash).await?),
+                _ => None,
+            };
+            client.account().set_avatar_url(avatar_url.as_deref()).await?;
+            state.avatar_hash = avatar_hash;
+            store.set_custom_value(&key, serde_json::to_vec(&state)?).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Upload the given avatar, unless an avatar with the same hash was
+    /// already uploaded.
+    async fn upload_avatar(
+        &self,
+        client: &Client,
+        avatar: &Avatar,
+        hash: &str,
+    ) -> Result<OwnedMxcUri> {
+        let store_client = self.appservice.user(None).await?;
+        let store = store_client.store();
+        let key = [AVATAR_KEY, hash.as_bytes()].concat();
+
+        if let Some(value) = store.get_custom_value(&key).await? {
+            if let Ok(url) = String::from_utf8(value) {
+                return Ok(url.into());
+            }
+        }
+
+        let response = client.media().upload(&avatar.content_type, avatar.data.clone()).await?;
+        store.set_custom_value(&key, response.content_uri.as_str().as_bytes().to_vec()).await?;
+
+        Ok(response.content_uri)
+    }
+
+    /// Register the given user if it isn't already, retrying if the
+    /// homeserver rate-limits the registration.
+    async fn register_user(&self, localpart: &str) -> Result<()> {
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let error = match self.appservice.register_user(localpart, None).await {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+
+            match error_kind(&error) {
+                Some(ErrorKind::UserInUse) => {
+                    // The user was registered by a previous run that didn't
+                    // persist it.
+                    self.appservice.set_user_registered(localpart).await?;
+                    return Ok(());
+                }
+                Some(ErrorKind::LimitExceeded { retry_after_ms })
+                    if attempts < MAX_RATE_LIMITED_ATTEMPTS =>
+                {
+                    let delay = retry_after_ms.unwrap_or(self.batch_interval);
+                    debug!(localpart, ?delay, "Registration of virtual user was rate-limited");
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return Err(error),
+            }
+        }
+    }
+
+    /// Mark the client of the given user as the most recently used, and drop
+    /// the least recently used clients if there are too many.
+    ///
+    /// The client of the `sender_localpart` and the clients that handle
+    /// end-to-end encryption are pinned, so they are not counted.
+    fn touch(&self, localpart: &str) {
+        let evicted = {
+            let mut lru = self.lru.lock().unwrap();
+            if !self.is_pinned(localpart) {
+                lru.touch(localpart);
+            }
+            lru.evict(self.max_clients)
+        };
+
+        for localpart in evicted {
+            // The client might have been replaced by a pinned one since it
+            // was last used.
+            if !self.is_pinned(&localpart) {
+                debug!(localpart, "Dropping client of virtual user");
+                self.appservice.clients.remove(&localpart);
+            }
+        }
+    }
+
+    fn is_pinned(&self, localpart: &str) -> bool {
+        localpart == self.appservice.registration.sender_localpart
+            || self.appservice.crypto_users.contains(localpart)
+    }
+}
+
+impl fmt::Debug for VirtualUserManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtualUserManager")
+            .field("max_clients", &self.max_clients)
+            .field("batch_size", &self.batch_size)
+            .field("batch_interval", &self.batch_interval)
+            .finish_non_exhaustive()
+    }
+}
+
+fn error_kind(error: &Error) -> Option<&ErrorKind> {
+    match error {
+        Error::Matrix(error) => error.client_api_error_kind(),
+        _ => None,
+    }
+}
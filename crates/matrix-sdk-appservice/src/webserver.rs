@@ -23,10 +23,10 @@ use std::{
 use axum::{
     async_trait,
     body::{Bytes, HttpBody},
-    extract::{FromRequest, FromRequestParts, Path},
+    extract::{rejection::PathRejection, FromRequest, FromRequestParts, Path},
     middleware::{self, Next},
     response::{ErrorResponse, IntoResponse, Response},
-    routing::{future::RouteFuture, get, put},
+    routing::{future::RouteFuture, get, post, put},
     BoxError, Extension, Json, Router, ServiceExt,
 };
 use http::StatusCode;
@@ -64,6 +64,16 @@ where
             .route("/_matrix/app/v1/users/:user_id", get(handlers::user))
             .route("/_matrix/app/v1/rooms/:room_id", get(handlers::room))
             .route("/_matrix/app/v1/transactions/:txn_id", put(handlers::transaction))
+            .route("/_matrix/app/v1/thirdparty/protocol/:protocol", get(handlers::protocol))
+            .route(
+                "/_matrix/app/v1/thirdparty/location/:protocol",
+                get(handlers::location_for_protocol),
+            )
+            .route("/_matrix/app/v1/thirdparty/location", get(handlers::location_for_room_alias))
+            .route("/_matrix/app/v1/thirdparty/user/:protocol", get(handlers::user_for_protocol))
+            .route("/_matrix/app/v1/thirdparty/user", get(handlers::user_for_user_id))
+            .route("/_matrix/app/v1/ping", post(handlers::ping))
+            .route("/_matrix/app/unstable/fi.mau.msc2659/ping", post(handlers::ping))
             .route("/users/:user_id", get(handlers::user))
             .route("/rooms/:room_id", get(handlers::room))
             .route("/transactions/:txn_id", put(handlers::transaction))
@@ -138,25 +148,53 @@ where
 {
     type Rejection = Response;
 
+    async fn from_request(
+        req: http::request::Request<B>,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let RawMatrixRequest(request, _) = RawMatrixRequest::from_request(req, state).await?;
+        Ok(Self(request))
+    }
+}
+
+/// A [`MatrixRequest`] that also keeps the body it was parsed from, to access
+/// the fields that are unknown to ruma.
+pub struct RawMatrixRequest<T>(T, Bytes);
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for RawMatrixRequest<T>
+where
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    T: IncomingRequest,
+{
+    type Rejection = Response;
+
     async fn from_request(
         req: http::request::Request<B>,
         state: &S,
     ) -> Result<Self, Self::Rejection> {
         let (mut parts, body) = req.into_parts();
-        let path_params = Path::<Vec<String>>::from_request_parts(&mut parts, state)
-            .await
-            .map_err(IntoResponse::into_response)?;
+        // Some routes, like the third party lookups by alias or user ID, don't
+        // have path parameters.
+        let path_params = match Path::<Vec<String>>::from_request_parts(&mut parts, state).await {
+            Ok(Path(path_params)) => path_params,
+            Err(PathRejection::MissingPathParams(_)) => Vec::new(),
+            Err(e) => return Err(e.into_response()),
+        };
         let bytes = Bytes::from_request(http::Request::new(body), state)
             .await
             .map_err(IntoResponse::into_response)?;
-        let http_request = http::Request::from_parts(parts, bytes);
+        let http_request = http::Request::from_parts(parts, bytes.clone());
 
         let request = T::try_from_http_request(http_request, &path_params).map_err(|_e| {
             // TODO: JSON error response
             StatusCode::BAD_REQUEST.into_response()
         })?;
 
-        Ok(Self(request))
+        Ok(Self(request, bytes))
     }
 }
 
@@ -166,11 +204,15 @@ mod handlers {
     use ruma::api::appservice::{
         event::push_events,
         query::{query_room_alias, query_user_id},
+        thirdparty::{
+            get_location_for_protocol, get_location_for_room_alias, get_protocol,
+            get_user_for_protocol, get_user_for_user_id,
+        },
     };
     use serde::Serialize;
 
-    use super::{ErrorMessage, MatrixRequest};
-    use crate::AppService;
+    use super::{ErrorMessage, MatrixRequest, RawMatrixRequest};
+    use crate::{transaction::TransactionExtensions, AppService};
 
     #[derive(Serialize)]
     struct EmptyObject {}
@@ -205,11 +247,108 @@ mod handlers {
         }
     }
 
+    pub async fn protocol(
+        Extension(appservice): Extension<AppService>,
+        MatrixRequest(request): MatrixRequest<get_protocol::v1::Request>,
+    ) -> impl IntoResponse {
+        let mut handler = appservice.event_handler.protocol.lock().await;
+        let Some(query_protocol) = handler.as_mut() else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        match query_protocol(appservice.clone(), request).await {
+            Some(protocol) => Ok(Json(protocol)),
+            None => Err(StatusCode::NOT_FOUND),
+        }
+    }
+
+    pub async fn location_for_protocol(
+        Extension(appservice): Extension<AppService>,
+        MatrixRequest(mut request): MatrixRequest<get_location_for_protocol::v1::Request>,
+    ) -> impl IntoResponse {
+        // The fields are the whole query string, which also contains the
+        // access token of the homeserver.
+        request.fields.remove("access_token");
+
+        let mut handler = appservice.event_handler.location_for_protocol.lock().await;
+        let Some(get_locations) = handler.as_mut() else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        non_empty(get_locations(appservice.clone(), request).await)
+    }
+
+    pub async fn location_for_room_alias(
+        Extension(appservice): Extension<AppService>,
+        MatrixRequest(request): MatrixRequest<get_location_for_room_alias::v1::Request>,
+    ) -> impl IntoResponse {
+        let mut handler = appservice.event_handler.location_for_room_alias.lock().await;
+        let Some(get_locations) = handler.as_mut() else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        non_empty(get_locations(appservice.clone(), request).await)
+    }
+
+    pub async fn user_for_protocol(
+        Extension(appservice): Extension<AppService>,
+        MatrixRequest(mut request): MatrixRequest<get_user_for_protocol::v1::Request>,
+    ) -> impl IntoResponse {
+        // The fields are the whole query string, which also contains the
+        // access token of the homeserver.
+        request.fields.remove("access_token");
+
+        let mut handler = appservice.event_handler.user_for_protocol.lock().await;
+        let Some(get_users) = handler.as_mut() else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        non_empty(get_users(appservice.clone(), request).await)
+    }
+
+    pub async fn user_for_user_id(
+        Extension(appservice): Extension<AppService>,
+        MatrixRequest(request): MatrixRequest<get_user_for_user_id::v1::Request>,
+    ) -> impl IntoResponse {
+        let mut handler = appservice.event_handler.user_for_user_id.lock().await;
+        let Some(get_users) = handler.as_mut() else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        non_empty(get_users(appservice.clone(), request).await)
+    }
+
+    /// The third party lookups respond with a 404 if there are no results.
+    fn non_empty<T: Serialize>(results: Vec<T>) -> Result<Json<Vec<T>>, StatusCode> {
+        if results.is_empty() {
+            Err(StatusCode::NOT_FOUND)
+        } else {
+            Ok(Json(results))
+        }
+    }
+
+    pub async fn ping() -> impl IntoResponse {
+        // The access token was validated by the middleware, which is all the
+        // homeserver needs to know to complete the ping.
+        Json(EmptyObject {})
+    }
+
     pub async fn transaction(
         appservice: Extension<AppService>,
-        MatrixRequest(request): MatrixRequest<push_events::v1::Request>,
+        RawMatrixRequest(request, body): RawMatrixRequest<push_events::v1::Request>,
     ) -> impl IntoResponse {
-        match appservice.receive_transaction(request).await {
+        let extensions = match serde_json::from_slice::<TransactionExtensions>(&body) {
+            Ok(extensions) => extensions,
+            Err(e) => {
+                let status_code = StatusCode::BAD_REQUEST;
+                return Err((
+                    status_code,
+                    Json(ErrorMessage { code: status_code.as_u16(), message: e.to_string() }),
+                ));
+            }
+        };
+
+        match appservice.receive_transaction(request, extensions).await {
             Ok(_) => Ok(Json(&EmptyObject {})),
             Err(e) => {
                 let status_code = StatusCode::INTERNAL_SERVER_ERROR;
@@ -240,11 +379,20 @@ async fn validate_access_token<B>(
     let appservice =
         req.extensions().get::<AppService>().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    // The access token is sent in the `Authorization` header since Matrix 1.4,
+    // but older homeservers only send it in the query string.
+    let header_token = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
     let query_string = req.uri().query().unwrap_or("");
-    match serde_html_form::from_str::<QueryParameters>(query_string) {
-        Ok(query) if query.access_token == appservice.registration.hs_token => {
-            Ok(next.run(req).await)
-        }
+    let query_token = serde_html_form::from_str::<QueryParameters>(query_string)
+        .ok()
+        .map(|query| query.access_token);
+
+    match header_token.or(query_token.as_deref()) {
+        Some(token) if token == appservice.registration.hs_token => Ok(next.run(req).await),
         _ => {
             let status_code = StatusCode::UNAUTHORIZED;
             let message =
@@ -17,7 +17,7 @@
 use std::ops::Deref;
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fmt,
+    fmt, mem,
     sync::Arc,
 };
 
@@ -42,14 +42,14 @@ use ruma::{
     events::{
         push_rules::{PushRulesEvent, PushRulesEventContent},
         room::{
-            member::{MembershipState, SyncRoomMemberEvent},
+            member::{MembershipState, RoomMemberEvent, SyncRoomMemberEvent},
             power_levels::{RoomPowerLevelsEvent, RoomPowerLevelsEventContent},
         },
-        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStateEvent, AnyStrippedStateEvent,
         AnySyncEphemeralRoomEvent, AnySyncStateEvent, AnySyncTimelineEvent,
         GlobalAccountDataEventType, StateEventType,
     },
-    push::{Action, PushConditionRoomCtx, Ruleset},
+    push::{Action, PredefinedOverrideRuleId, PushConditionRoomCtx, Ruleset},
     serde::Raw,
     MilliSecondsSinceUnixEpoch, OwnedUserId, RoomId, UInt, UserId,
 };
@@ -742,6 +742,7 @@ impl BaseClient {
 
             if new_info.timeline.limited {
                 room_info.mark_members_missing();
+                room.clear_historical_members();
             }
 
             let timeline = self
@@ -758,6 +759,8 @@ impl BaseClient {
                 )
                 .await?;
 
+            self.update_event_cache(&room_info, &timeline, &mut changes).await?;
+
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
 
@@ -823,6 +826,8 @@ impl BaseClient {
                 )
                 .await?;
 
+            self.update_event_cache(&room_info, &timeline, &mut changes).await?;
+
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
 
@@ -1009,6 +1014,80 @@ impl BaseClient {
         })
     }
 
+    /// Receive the member events that were sent along with some room events
+    /// because of [lazy-loading], e.g. the `state` of a `/messages` or
+    /// `/context` response.
+    ///
+    /// Contrary to the member events received in a sync response, these
+    /// describe the membership at some point in the room history, so they are
+    /// not persisted with the current state of the room. They are kept in
+    /// memory for the users the store doesn't know yet, and can be accessed
+    /// with [`Room::get_historical_member()`]. Nothing is kept if the full
+    /// member list of the room was already fetched.
+    ///
+    /// Returns the IDs of the users whose member event was kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id the events belong to.
+    ///
+    /// * `state` - The state events that were received, events that are not
+    ///   member events are ignored.
+    ///
+    /// [lazy-loading]: https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
+    #[instrument(skip_all, fields(?room_id))]
+    pub async fn receive_lazy_loaded_members(
+        &self,
+        room_id: &RoomId,
+        state: &[Raw<AnyStateEvent>],
+    ) -> Result<Vec<OwnedUserId>> {
+        let Some(room) = self.store.get_room(room_id) else {
+            return Ok(Vec::new());
+        };
+
+        if room.are_members_synced() {
+            return Ok(Vec::new());
+        }
+
+        let mut user_ids: Vec<OwnedUserId> = Vec::new();
+
+        for raw_event in state {
+            if !matches!(
+                raw_event.get_field::<StateEventType>("type"),
+                Ok(Some(StateEventType::RoomMember))
+            ) {
+                continue;
+            }
+
+            let member = match raw_event.deserialize_as::<RoomMemberEvent>() {
+                Ok(ev) => ev,
+                Err(e) => {
+                    let event_id: Option<String> = raw_event.get_field("event_id").ok().flatten();
+                    debug!(event_id, "Failed to deserialize member event: {e}");
+                    continue;
+                }
+            };
+
+            let user_id = member.state_key();
+            if user_ids.iter().any(|known| **known == *user_id)
+                || room.get_historical_member(user_id).is_some()
+                || self.store.get_member_event(room_id, user_id).await?.is_some()
+            {
+                continue;
+            }
+
+            let sync_member: SyncRoomMemberEvent = member.clone().into();
+            room.add_historical_member(user_id.to_owned(), sync_member.into());
+            user_ids.push(user_id.to_owned());
+        }
+
+        if !user_ids.is_empty() {
+            trace!(?user_ids, "Received lazy-loaded members");
+        }
+
+        Ok(user_ids)
+    }
+
     /// Receive a successful filter upload response, the filter id will be
     /// stored under the given name in the store.
     ///
@@ -1109,25 +1188,53 @@ impl BaseClient {
     /// Gets the push rules from `changes` if they have been updated, otherwise
     /// get them from the store. As a fallback, uses
     /// `Ruleset::server_default` if the user is logged in.
+    ///
+    /// The server-default rules for intentional mentions are added to the
+    /// push rules of servers that don't support them yet.
     pub async fn get_push_rules(&self, changes: &StateChanges) -> Result<Ruleset> {
-        if let Some(event) = changes
+        let mut ruleset = if let Some(event) = changes
             .account_data
             .get(&GlobalAccountDataEventType::PushRules)
             .and_then(|ev| ev.deserialize_as::<PushRulesEvent>().ok())
         {
-            Ok(event.content.global)
+            event.content.global
         } else if let Some(event) = self
             .store
             .get_account_data_event_static::<PushRulesEventContent>()
             .await?
             .and_then(|ev| ev.deserialize().ok())
         {
-            Ok(event.content.global)
+            event.content.global
         } else if let Some(session_meta) = self.store.session_meta() {
-            Ok(Ruleset::server_default(&session_meta.user_id))
+            return Ok(Ruleset::server_default(&session_meta.user_id));
         } else {
-            Ok(Ruleset::new())
-        }
+            return Ok(Ruleset::new());
+        };
+
+        self.add_missing_mention_rules(&mut ruleset);
+
+        Ok(ruleset)
+    }
+
+    /// Add the server-default push rules for intentional mentions ([MSC3952])
+    /// to the given ruleset, if they are missing.
+    ///
+    /// Without them, messages that only mention the user in their `m.mentions`
+    /// property are not highlighted, since their body is not matched against
+    /// the legacy mention rules.
+    ///
+    /// The added rules only exist locally, they can't be changed on the
+    /// server. Nothing is added if the client isn't logged in.
+    ///
+    /// Returns the IDs of the rules that were added.
+    ///
+    /// [MSC3952]: https://github.com/matrix-org/matrix-spec-proposals/pull/3952
+    pub fn add_missing_mention_rules(&self, ruleset: &mut Ruleset) -> Vec<String> {
+        let Some(session_meta) = self.store.session_meta() else {
+            return Vec::new();
+        };
+
+        add_missing_mention_rules(ruleset, &session_meta.user_id)
     }
 
     /// Get the push context for the given room.
@@ -1250,6 +1357,45 @@ impl Default for BaseClient {
     }
 }
 
+/// Add the server-default push rules for intentional mentions to the given
+/// ruleset, if they are missing.
+///
+/// See [`BaseClient::add_missing_mention_rules()`] for details.
+fn add_missing_mention_rules(ruleset: &mut Ruleset, user_id: &UserId) -> Vec<String> {
+    // The new rules must have a higher priority than the legacy rules that
+    // they replace.
+    let rules = [
+        (PredefinedOverrideRuleId::IsUserMention, PredefinedOverrideRuleId::ContainsDisplayName),
+        (PredefinedOverrideRuleId::IsRoomMention, PredefinedOverrideRuleId::RoomNotif),
+    ];
+    let mut server_default = None;
+    let mut added = Vec::new();
+
+    for (rule_id, legacy_rule_id) in rules {
+        if ruleset.override_.iter().any(|rule| rule.rule_id == rule_id.as_str()) {
+            continue;
+        }
+
+        let server_default = server_default.get_or_insert_with(|| Ruleset::server_default(user_id));
+        let Some(rule) =
+            server_default.override_.iter().find(|rule| rule.rule_id == rule_id.as_str())
+        else {
+            continue;
+        };
+
+        let mut override_: Vec<_> = mem::take(&mut ruleset.override_).into_iter().collect();
+        let position = override_
+            .iter()
+            .position(|rule| rule.rule_id == legacy_rule_id.as_str())
+            .unwrap_or(override_.len());
+        override_.insert(position, rule.clone());
+        ruleset.override_ = override_.into_iter().collect();
+        added.push(rule_id.as_str().to_owned());
+    }
+
+    added
+}
+
 #[cfg(test)]
 mod tests {
     use matrix_sdk_test::{
@@ -1258,7 +1404,10 @@ mod tests {
     };
     use ruma::{
         api::{client as api, IncomingResponse},
-        room_id, user_id,
+        push::{PredefinedOverrideRuleId, Ruleset},
+        room_id,
+        serde::Raw,
+        user_id,
     };
     use serde_json::json;
 
@@ -1412,4 +1561,96 @@ mod tests {
             DisplayName::Calculated("Kyra".to_owned())
         );
     }
+
+    #[async_test]
+    async fn lazy_loaded_members() {
+        let user_id = user_id!("@alice:example.org");
+        let room_id = room_id!("!test:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id.to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client.get_or_create_room(room_id, RoomState::Joined).await;
+
+        let member_event = |displayname: &str| {
+            Raw::new(&json!({
+                "content": {
+                    "displayname": displayname,
+                    "membership": "join",
+                },
+                "event_id": format!("${displayname}:example.org"),
+                "origin_server_ts": 1432135524678u64,
+                "room_id": room_id,
+                "sender": bob,
+                "state_key": bob,
+                "type": "m.room.member",
+            }))
+            .unwrap()
+            .cast()
+        };
+        let topic_event = Raw::new(&json!({
+            "content": {
+                "topic": "Lazy loading",
+            },
+            "event_id": "$topic:example.org",
+            "origin_server_ts": 1432135524678u64,
+            "room_id": room_id,
+            "sender": bob,
+            "state_key": "",
+            "type": "m.room.topic",
+        }))
+        .unwrap()
+        .cast();
+
+        let user_ids = client
+            .receive_lazy_loaded_members(room_id, &[topic_event, member_event("Bob")])
+            .await
+            .unwrap();
+        assert_eq!(user_ids, vec![bob.to_owned()]);
+
+        // Historical member events are not part of the current state.
+        let room = client.get_room(room_id).unwrap();
+        assert!(room.get_member(bob).await.unwrap().is_none());
+        let member = room.get_historical_member(bob).unwrap();
+        assert_eq!(member.as_original().unwrap().content.displayname.as_deref(), Some("Bob"));
+
+        // They don't override what we already know.
+        let user_ids =
+            client.receive_lazy_loaded_members(room_id, &[member_event("Old Bob")]).await.unwrap();
+        assert!(user_ids.is_empty());
+
+        let member = room.get_historical_member(bob).unwrap();
+        assert_eq!(member.as_original().unwrap().content.displayname.as_deref(), Some("Bob"));
+    }
+
+    #[test]
+    fn add_missing_mention_rules() {
+        let user_id = user_id!("@alice:example.org");
+        let is_user_mention = PredefinedOverrideRuleId::IsUserMention.as_str();
+        let is_room_mention = PredefinedOverrideRuleId::IsRoomMention.as_str();
+        let rule_ids = |ruleset: &Ruleset| {
+            ruleset.override_.iter().map(|r| r.rule_id.clone()).collect::<Vec<_>>()
+        };
+
+        // A ruleset from a server that doesn't support intentional mentions.
+        let mut ruleset = Ruleset::server_default(user_id);
+        let expected_rule_ids = rule_ids(&ruleset);
+        ruleset.override_.retain(|r| r.rule_id != is_user_mention && r.rule_id != is_room_mention);
+        assert_eq!(ruleset.override_.len(), expected_rule_ids.len() - 2);
+
+        let added = super::add_missing_mention_rules(&mut ruleset, user_id);
+        assert_eq!(added, [is_user_mention, is_room_mention]);
+        assert_eq!(rule_ids(&ruleset), expected_rule_ids);
+
+        // A ruleset that already has them is not modified.
+        let added = super::add_missing_mention_rules(&mut ruleset, user_id);
+        assert!(added.is_empty());
+        assert_eq!(rule_ids(&ruleset), expected_rule_ids);
+    }
 }
@@ -0,0 +1,576 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A persistent cache of the timeline events of rooms.
+//!
+//! The events of a room are stored in the state store in chunks of at most
+//! [`EVENT_CHUNK_CAPACITY`] events. The chunks of a room have contiguous IDs
+//! and are ordered by ID, so the chunk with the highest ID contains the most
+//! recent events. The [`prev_batch`](EventChunk::prev_batch) token of the
+//! oldest chunk is used to fetch the events that come before the cache from
+//! the server.
+//!
+//! New chunks are appended when events are received via sync and prepended
+//! when events are back-paginated. Since the cache must always be contiguous,
+//! it is cleared when a sync response has a gap in the timeline of a room.
+//!
+//! A room has at most [`MAX_EVENT_CHUNKS`] chunks. When a sync response adds
+//! more, the oldest chunks are removed, up to a chunk that has a `prev_batch`
+//! token.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{
+    canonical_json::redact, events::room::redaction::OriginalSyncRoomRedactionEvent, serde::Raw,
+    CanonicalJsonObject, OwnedEventId, RoomId, RoomVersionId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    deserialized_responses::SyncTimelineEvent,
+    error::Result,
+    store::{Result as StoreResult, StateChanges, StoreError},
+    sync::Timeline,
+    BaseClient, RoomInfo,
+};
+
+/// The maximum number of events in an [`EventChunk`].
+pub const EVENT_CHUNK_CAPACITY: usize = 50;
+
+/// The maximum number of chunks in the event cache of a room.
+pub const MAX_EVENT_CHUNKS: usize = 20;
+
+/// A chunk of contiguous events in the event cache of a room.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventChunk {
+    /// The ID of the chunk, unique in the room.
+    pub id: i64,
+    /// The token to fetch the events that come before this chunk with the
+    /// `/messages` API.
+    ///
+    /// On the oldest chunk of the room, it is `None` if the start of the
+    /// timeline of the room was reached. The other chunks only have it if they
+    /// start with the first event of a sync timeline.
+    pub prev_batch: Option<String>,
+    /// The events of the chunk, in chronological order.
+    pub events: Vec<SyncTimelineEvent>,
+}
+
+impl EventChunk {
+    /// Create a new empty chunk.
+    pub fn new(id: i64, prev_batch: Option<String>) -> Self {
+        Self { id, prev_batch, events: Vec::new() }
+    }
+
+    /// Whether this chunk contains the maximum number of events.
+    pub fn is_full(&self) -> bool {
+        self.events.len() >= EVENT_CHUNK_CAPACITY
+    }
+
+    /// Get the IDs of the events of this chunk.
+    pub fn event_ids(&self) -> impl Iterator<Item = OwnedEventId> + '_ {
+        self.events.iter().filter_map(|event| event.event_id())
+    }
+}
+
+/// The changes to the event cache of a room, see
+/// [`StateChanges::event_cache`].
+#[derive(Clone, Debug, Default)]
+pub struct EventCacheChanges {
+    /// Whether all the chunks that are currently stored for the room should be
+    /// removed before saving the new ones.
+    pub reset: bool,
+    /// The chunks that were created or updated, by ID.
+    pub chunks: BTreeMap<i64, EventChunk>,
+    /// The IDs of the oldest chunks that should be removed, because the room
+    /// has more than [`MAX_EVENT_CHUNKS`] chunks.
+    pub removed_chunks: BTreeSet<i64>,
+}
+
+impl BaseClient {
+    /// Add the events of a sync timeline to the event cache of the room.
+    ///
+    /// This also applies the redactions of the room found in `changes` to the
+    /// cached events, and removes the oldest chunks if the room has too many.
+    pub(crate) async fn update_event_cache(
+        &self,
+        room_info: &RoomInfo,
+        timeline: &Timeline,
+        changes: &mut StateChanges,
+    ) -> Result<()> {
+        let room_id = &room_info.room_id;
+        let mut cache_changes = changes.event_cache.remove(room_id).unwrap_or_default();
+
+        let last_chunk = if timeline.limited {
+            // There is a gap between the cached events and the new ones, we
+            // can only keep the new ones.
+            debug!(?room_id, "Timeline is limited, clearing the event cache");
+            cache_changes.reset = true;
+            cache_changes.chunks.clear();
+            cache_changes.removed_chunks.clear();
+            None
+        } else if let Some(chunk) = cache_changes.chunks.values().next_back() {
+            Some(chunk.clone())
+        } else {
+            match self.store.get_event_chunk_range(room_id).await? {
+                Some(range) => self.store.get_event_chunk(room_id, *range.end()).await?,
+                None => None,
+            }
+        };
+
+        // Look for the events that are already cached with a single query.
+        let cached_event_ids = if cache_changes.reset {
+            BTreeMap::new()
+        } else {
+            let event_ids: Vec<_> = timeline.events.iter().filter_map(|e| e.event_id()).collect();
+            self.store.get_event_chunk_ids(room_id, &event_ids).await?
+        };
+
+        let mut seen_event_ids = BTreeSet::new();
+        let mut new_events = Vec::new();
+
+        for event in &timeline.events {
+            let Some(event_id) = event.event_id() else { continue };
+
+            if !seen_event_ids.insert(event_id.clone())
+                || cached_event_ids.contains_key(&event_id)
+                || last_chunk.as_ref().map_or(false, |c| c.event_ids().any(|id| id == event_id))
+            {
+                debug!(?room_id, ?event_id, "Event is already in the event cache");
+                continue;
+            }
+
+            new_events.push(event.clone());
+        }
+
+        if !new_events.is_empty() {
+            // If the new events don't fit in the last chunk, they start a new
+            // one that keeps the token of the timeline, so the cache can be
+            // truncated before it.
+            let mut chunk = match last_chunk {
+                Some(chunk) if chunk.events.len() + new_events.len() > EVENT_CHUNK_CAPACITY => {
+                    EventChunk::new(chunk.id + 1, timeline.prev_batch.clone())
+                }
+                Some(chunk) => chunk,
+                None => EventChunk::new(0, timeline.prev_batch.clone()),
+            };
+
+            for event in new_events {
+                if chunk.is_full() {
+                    let id = chunk.id + 1;
+                    cache_changes.chunks.insert(chunk.id, chunk);
+                    chunk = EventChunk::new(id, None);
+                }
+
+                chunk.events.push(event);
+            }
+
+            cache_changes.chunks.insert(chunk.id, chunk);
+        }
+
+        self.redact_cached_events(room_info, &mut cache_changes, changes).await?;
+        self.remove_oldest_event_chunks(room_id, &mut cache_changes).await?;
+
+        if cache_changes.reset
+            || !cache_changes.chunks.is_empty()
+            || !cache_changes.removed_chunks.is_empty()
+        {
+            changes.event_cache.insert(room_id.to_owned(), cache_changes);
+        }
+
+        Ok(())
+    }
+
+    /// Apply the redactions of the room found in `changes` to the cached
+    /// events.
+    async fn redact_cached_events(
+        &self,
+        room_info: &RoomInfo,
+        cache_changes: &mut EventCacheChanges,
+        changes: &StateChanges,
+    ) -> Result<()> {
+        let room_id = &room_info.room_id;
+        let Some(redactions) = changes.redactions.get(room_id) else { return Ok(()) };
+
+        let room_version = room_info.room_version().cloned().unwrap_or_else(|| {
+            warn!(?room_id, "Unable to find the room version, assuming version 9");
+            RoomVersionId::V9
+        });
+
+        for (event_id, redaction) in redactions {
+            let chunk_id = match cache_changes
+                .chunks
+                .values()
+                .find(|chunk| chunk.event_ids().any(|id| id == *event_id))
+            {
+                Some(chunk) => chunk.id,
+                None if cache_changes.reset => continue,
+                None => {
+                    let Some(chunk) = self.load_event_chunk_for_event(room_id, event_id).await?
+                    else {
+                        continue;
+                    };
+                    let id = chunk.id;
+                    cache_changes.chunks.insert(id, chunk);
+                    id
+                }
+            };
+
+            let chunk = cache_changes.chunks.get_mut(&chunk_id).expect("chunk was just found");
+            for event in &mut chunk.events {
+                if event.event_id().as_ref() == Some(event_id) {
+                    redact_event(event, &room_version, redaction)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the oldest chunks of the room if it has more than
+    /// [`MAX_EVENT_CHUNKS`] chunks.
+    ///
+    /// The oldest chunk that is kept must have a `prev_batch` token, to be
+    /// able to fetch the removed events from the server again.
+    async fn remove_oldest_event_chunks(
+        &self,
+        room_id: &RoomId,
+        cache_changes: &mut EventCacheChanges,
+    ) -> Result<()> {
+        let Some(last_chunk_id) = cache_changes.chunks.keys().next_back().copied() else {
+            return Ok(());
+        };
+
+        let mut first_chunk_id = *cache_changes.chunks.keys().next().expect("chunks are not empty");
+        if !cache_changes.reset {
+            if let Some(range) = self.store.get_event_chunk_range(room_id).await? {
+                first_chunk_id = first_chunk_id.min(*range.start());
+            }
+        }
+        first_chunk_id = cache_changes
+            .removed_chunks
+            .iter()
+            .next_back()
+            .map_or(first_chunk_id, |id| first_chunk_id.max(id + 1));
+
+        let max_chunks = MAX_EVENT_CHUNKS as i64;
+        if last_chunk_id - first_chunk_id < max_chunks {
+            return Ok(());
+        }
+
+        for chunk_id in last_chunk_id - max_chunks + 1..=last_chunk_id {
+            let has_token = match cache_changes.chunks.get(&chunk_id) {
+                Some(chunk) => chunk.prev_batch.is_some(),
+                None => self
+                    .store
+                    .get_event_chunk(room_id, chunk_id)
+                    .await?
+                    .map_or(false, |chunk| chunk.prev_batch.is_some()),
+            };
+
+            if has_token {
+                debug!(?room_id, "Removing the event cache chunks before {chunk_id}");
+
+                for id in first_chunk_id..chunk_id {
+                    cache_changes.chunks.remove(&id);
+                    if !cache_changes.reset {
+                        cache_changes.removed_chunks.insert(id);
+                    }
+                }
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn load_event_chunk_for_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &OwnedEventId,
+    ) -> Result<Option<EventChunk>> {
+        match self.store.get_event_chunk_id(room_id, event_id).await? {
+            Some(chunk_id) => Ok(self.store.get_event_chunk(room_id, chunk_id).await?),
+            None => Ok(None),
+        }
+    }
+
+    /// Add events received from the `/messages` API in the backwards
+    /// direction to the event cache of the room.
+    ///
+    /// The events are only cached if they come right before the cached events,
+    /// i.e. if `from` is the `prev_batch` token of the oldest cached chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room the events belong to.
+    ///
+    /// * `from` - The token the events were fetched from.
+    ///
+    /// * `events` - The events, in reverse chronological order, like they are
+    ///   returned by the server.
+    ///
+    /// * `end` - The token to fetch the events that come before these ones, or
+    ///   `None` if the start of the timeline was reached.
+    pub async fn receive_back_paginated_events(
+        &self,
+        room_id: &RoomId,
+        from: &str,
+        events: &[SyncTimelineEvent],
+        end: Option<String>,
+    ) -> Result<()> {
+        // Hold the sync lock so the cache can't be cleared by a sync response
+        // while we extend it.
+        let _sync_lock = self.sync_lock().write().await;
+
+        let Some(range) = self.store.get_event_chunk_range(room_id).await? else {
+            return Ok(());
+        };
+        let Some(mut oldest_chunk) = self.store.get_event_chunk(room_id, *range.start()).await?
+        else {
+            return Ok(());
+        };
+
+        if oldest_chunk.prev_batch.as_deref() != Some(from) {
+            debug!(?room_id, "Back-paginated events are not contiguous with the event cache");
+            return Ok(());
+        }
+
+        let event_ids: Vec<_> = events.iter().filter_map(|e| e.event_id()).collect();
+        let cached_event_ids = self.store.get_event_chunk_ids(room_id, &event_ids).await?;
+
+        let mut seen_event_ids = BTreeSet::new();
+        let mut new_events = Vec::with_capacity(events.len());
+
+        for event in events {
+            let Some(event_id) = event.event_id() else { continue };
+
+            if seen_event_ids.insert(event_id.clone()) && !cached_event_ids.contains_key(&event_id)
+            {
+                new_events.push(event.clone());
+            }
+        }
+
+        let chunk_count = range.end() - range.start() + 1;
+        let new_chunk_count =
+            ((new_events.len() + EVENT_CHUNK_CAPACITY - 1) / EVENT_CHUNK_CAPACITY) as i64;
+        if chunk_count + new_chunk_count > MAX_EVENT_CHUNKS as i64 {
+            debug!(?room_id, "The event cache is full, not caching back-paginated events");
+            return Ok(());
+        }
+
+        let mut cache_changes = EventCacheChanges::default();
+
+        // The events are in reverse chronological order, so we fill the chunks
+        // from their end.
+        let mut chunk_id = oldest_chunk.id;
+        for chunk_events in new_events.chunks(EVENT_CHUNK_CAPACITY) {
+            chunk_id -= 1;
+
+            let mut chunk = EventChunk::new(chunk_id, None);
+            chunk.events = chunk_events.iter().rev().cloned().collect();
+            cache_changes.chunks.insert(chunk_id, chunk);
+        }
+
+        if let Some(chunk) = cache_changes.chunks.get_mut(&chunk_id) {
+            chunk.prev_batch = end;
+        } else {
+            oldest_chunk.prev_batch = end;
+        }
+
+        if chunk_id != oldest_chunk.id {
+            oldest_chunk.prev_batch = None;
+        }
+        cache_changes.chunks.insert(oldest_chunk.id, oldest_chunk);
+
+        let mut changes = StateChanges::default();
+        changes.event_cache.insert(room_id.to_owned(), cache_changes);
+        self.store.save_changes(&changes).await?;
+
+        Ok(())
+    }
+}
+
+/// Redact the given cached event.
+fn redact_event(
+    event: &mut SyncTimelineEvent,
+    room_version: &RoomVersionId,
+    redaction: &Raw<OriginalSyncRoomRedactionEvent>,
+) -> StoreResult<()> {
+    let redacted = redact(
+        event.event.deserialize_as::<CanonicalJsonObject>()?,
+        room_version,
+        Some(redaction.try_into()?),
+    )
+    .map_err(StoreError::Redaction)?;
+
+    event.event = Raw::new(&redacted)?.cast();
+    event.encryption_info = None;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_test::async_test;
+    use ruma::{event_id, room_id, serde::Raw, user_id};
+    use serde_json::json;
+
+    use super::{EVENT_CHUNK_CAPACITY, MAX_EVENT_CHUNKS};
+    use crate::{
+        deserialized_responses::SyncTimelineEvent, rooms::RoomState, store::StateChanges,
+        sync::Timeline, BaseClient, SessionMeta,
+    };
+
+    fn message(id: usize) -> SyncTimelineEvent {
+        SyncTimelineEvent::new(
+            Raw::new(&json!({
+                "content": { "body": format!("Message {id}"), "msgtype": "m.text" },
+                "event_id": format!("$message_{id}"),
+                "origin_server_ts": 152037280,
+                "sender": "@alice:localhost",
+                "type": "m.room.message",
+            }))
+            .unwrap()
+            .cast(),
+        )
+    }
+
+    async fn logged_in_client() -> BaseClient {
+        let client = BaseClient::new();
+        client
+            .set_session_meta(SessionMeta {
+                user_id: user_id!("@alice:localhost").to_owned(),
+                device_id: "FOOBAR".into(),
+            })
+            .await
+            .unwrap();
+        client
+    }
+
+    #[async_test]
+    async fn sync_and_back_paginated_events() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!test:localhost");
+        let room = client.get_or_create_room(room_id, RoomState::Joined).await;
+
+        // A first sync response with more events than a chunk can hold.
+        let mut timeline = Timeline::new(false, Some("prev_batch".to_owned()));
+        timeline.events = (10..70).map(message).collect();
+
+        let mut changes = StateChanges::default();
+        client.update_event_cache(&room.clone_info(), &timeline, &mut changes).await.unwrap();
+        client.store.save_changes(&changes).await.unwrap();
+
+        let range = client.store.get_event_chunk_range(room_id).await.unwrap().unwrap();
+        assert_eq!(range, 0..=1);
+        let chunk = client.store.get_event_chunk(room_id, 0).await.unwrap().unwrap();
+        assert_eq!(chunk.events.len(), EVENT_CHUNK_CAPACITY);
+        assert_eq!(chunk.prev_batch.as_deref(), Some("prev_batch"));
+        let chunk = client.store.get_event_chunk(room_id, 1).await.unwrap().unwrap();
+        assert_eq!(chunk.events.len(), 10);
+        assert_eq!(
+            client.store.get_event_chunk_id(room_id, event_id!("$message_69")).await.unwrap(),
+            Some(1)
+        );
+
+        // Duplicate events are ignored.
+        let mut timeline = Timeline::new(false, None);
+        timeline.events = (69..71).map(message).collect();
+
+        let mut changes = StateChanges::default();
+        client.update_event_cache(&room.clone_info(), &timeline, &mut changes).await.unwrap();
+        client.store.save_changes(&changes).await.unwrap();
+
+        let chunk = client.store.get_event_chunk(room_id, 1).await.unwrap().unwrap();
+        assert_eq!(chunk.events.len(), 11);
+
+        // Events that don't continue the cache are ignored.
+        let events: Vec<_> = (0..10).rev().map(message).collect();
+        client.receive_back_paginated_events(room_id, "other", &events, None).await.unwrap();
+        let range = client.store.get_event_chunk_range(room_id).await.unwrap().unwrap();
+        assert_eq!(range, 0..=1);
+
+        // Events that continue the cache are prepended.
+        client
+            .receive_back_paginated_events(room_id, "prev_batch", &events, Some("end".to_owned()))
+            .await
+            .unwrap();
+        let range = client.store.get_event_chunk_range(room_id).await.unwrap().unwrap();
+        assert_eq!(range, -1..=1);
+        let chunk = client.store.get_event_chunk(room_id, -1).await.unwrap().unwrap();
+        assert_eq!(chunk.prev_batch.as_deref(), Some("end"));
+        assert_eq!(chunk.events.first().unwrap().event_id().unwrap(), "$message_0");
+        assert_eq!(chunk.events.last().unwrap().event_id().unwrap(), "$message_9");
+        let chunk = client.store.get_event_chunk(room_id, 0).await.unwrap().unwrap();
+        assert_eq!(chunk.prev_batch, None);
+
+        // A limited timeline clears the cache.
+        let mut timeline = Timeline::new(true, Some("new_prev_batch".to_owned()));
+        timeline.events = vec![message(100)];
+
+        let mut changes = StateChanges::default();
+        client.update_event_cache(&room.clone_info(), &timeline, &mut changes).await.unwrap();
+        client.store.save_changes(&changes).await.unwrap();
+
+        let range = client.store.get_event_chunk_range(room_id).await.unwrap().unwrap();
+        assert_eq!(range, 0..=0);
+        let chunk = client.store.get_event_chunk(room_id, 0).await.unwrap().unwrap();
+        assert_eq!(chunk.prev_batch.as_deref(), Some("new_prev_batch"));
+        assert_eq!(chunk.events.len(), 1);
+        assert_eq!(
+            client.store.get_event_chunk_id(room_id, event_id!("$message_0")).await.unwrap(),
+            None
+        );
+    }
+
+    #[async_test]
+    async fn oldest_chunks_are_removed() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!test:localhost");
+        let room = client.get_or_create_room(room_id, RoomState::Joined).await;
+
+        // Each sync response fills a new chunk.
+        for i in 0..=MAX_EVENT_CHUNKS {
+            let start = i * EVENT_CHUNK_CAPACITY;
+            let mut timeline = Timeline::new(false, Some(format!("prev_batch_{i}")));
+            timeline.events = (start..start + EVENT_CHUNK_CAPACITY).map(message).collect();
+
+            let mut changes = StateChanges::default();
+            client.update_event_cache(&room.clone_info(), &timeline, &mut changes).await.unwrap();
+            client.store.save_changes(&changes).await.unwrap();
+        }
+
+        // The oldest chunk was removed.
+        let range = client.store.get_event_chunk_range(room_id).await.unwrap().unwrap();
+        assert_eq!(range, 1..=MAX_EVENT_CHUNKS as i64);
+        assert!(client.store.get_event_chunk(room_id, 0).await.unwrap().is_none());
+        assert!(client
+            .store
+            .get_event_chunk_id(room_id, event_id!("$message_0"))
+            .await
+            .unwrap()
+            .is_none());
+        let chunk = client.store.get_event_chunk(room_id, 1).await.unwrap().unwrap();
+        assert_eq!(chunk.prev_batch.as_deref(), Some("prev_batch_1"));
+
+        // Back-paginated events are not cached when the cache is full.
+        let events: Vec<_> = (0..10).rev().map(message).collect();
+        client.receive_back_paginated_events(room_id, "prev_batch_1", &events, None).await.unwrap();
+        let range = client.store.get_event_chunk_range(room_id).await.unwrap().unwrap();
+        assert_eq!(range, 1..=MAX_EVENT_CHUNKS as i64);
+    }
+}
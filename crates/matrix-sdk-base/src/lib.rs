@@ -28,6 +28,7 @@ mod client;
 pub mod debug;
 pub mod deserialized_responses;
 mod error;
+pub mod event_cache;
 pub mod media;
 mod rooms;
 mod session;
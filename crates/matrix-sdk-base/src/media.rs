@@ -1,5 +1,7 @@
 //! Common types for [media content](https://matrix.org/docs/spec/client_server/r0.6.1#id66).
 
+use std::time::Duration;
+
 use ruma::{
     api::client::media::get_content_thumbnail::v3::Method,
     events::{
@@ -12,7 +14,7 @@ use ruma::{
         },
         sticker::StickerEventContent,
     },
-    UInt,
+    MilliSecondsSinceUnixEpoch, UInt,
 };
 
 const UNIQUE_SEPARATOR: &str = "_";
@@ -88,6 +90,198 @@ impl UniqueKey for MediaRequest {
         format!("{}{UNIQUE_SEPARATOR}{}", self.source.unique_key(), self.format.unique_key())
     }
 }
+/// The policy of the media cache of a store.
+///
+/// The default policy limits the media cache to 400 MiB, doesn't cache media
+/// content over 20 MiB and removes media content that wasn't accessed for 60
+/// days.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaRetentionPolicy {
+    /// The maximum total size of the media cache, in bytes.
+    ///
+    /// When the cache grows over this size, the least recently accessed media
+    /// content is removed first.
+    pub max_cache_size: Option<usize>,
+
+    /// The maximum size of a single media content in the cache, in bytes.
+    ///
+    /// Media content over this size is not cached.
+    pub max_file_size: Option<usize>,
+
+    /// The duration after which media content that wasn't accessed is removed
+    /// from the cache.
+    pub last_access_expiry: Option<Duration>,
+}
+
+impl Default for MediaRetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_cache_size: Some(Self::DEFAULT_MAX_CACHE_SIZE),
+            max_file_size: Some(Self::DEFAULT_MAX_FILE_SIZE),
+            last_access_expiry: Some(Self::DEFAULT_LAST_ACCESS_EXPIRY),
+        }
+    }
+}
+
+impl MediaRetentionPolicy {
+    /// The default maximum total size of the media cache, 400 MiB.
+    pub const DEFAULT_MAX_CACHE_SIZE: usize = 400 * 1024 * 1024;
+
+    /// The default maximum size of a single media content in the cache, 20
+    /// MiB.
+    pub const DEFAULT_MAX_FILE_SIZE: usize = 20 * 1024 * 1024;
+
+    /// The default duration after which media content that wasn't accessed is
+    /// removed from the cache, 60 days.
+    pub const DEFAULT_LAST_ACCESS_EXPIRY: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+    /// Create a `MediaRetentionPolicy` with the default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a `MediaRetentionPolicy` without any limit.
+    pub fn empty() -> Self {
+        Self { max_cache_size: None, max_file_size: None, last_access_expiry: None }
+    }
+
+    /// Set the maximum total size of the media cache, in bytes.
+    pub fn with_max_cache_size(mut self, size: Option<usize>) -> Self {
+        self.max_cache_size = size;
+        self
+    }
+
+    /// Set the maximum size of a single media content in the cache, in bytes.
+    pub fn with_max_file_size(mut self, size: Option<usize>) -> Self {
+        self.max_file_size = size;
+        self
+    }
+
+    /// Set the duration after which media content that wasn't accessed is
+    /// removed from the cache.
+    pub fn with_last_access_expiry(mut self, expiry: Option<Duration>) -> Self {
+        self.last_access_expiry = expiry;
+        self
+    }
+
+    /// Whether media content of the given size is too big to be cached.
+    pub fn exceeds_max_file_size(&self, size: usize) -> bool {
+        self.max_file_size.map_or(false, |max_file_size| size > max_file_size)
+    }
+
+    /// Whether media content last accessed at the given time should be
+    /// removed from the cache at `now`.
+    pub fn has_expired(
+        &self,
+        last_access: MilliSecondsSinceUnixEpoch,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> bool {
+        self.last_access_expiry.map_or(false, |expiry| {
+            let elapsed = u64::from(now.get()).saturating_sub(last_access.get().into());
+            u128::from(elapsed) >= expiry.as_millis()
+        })
+    }
+
+    /// Select the media content to remove from a cache to respect this policy.
+    ///
+    /// This is meant to be used by store implementations.
+    ///
+    /// # Arguments
+    ///
+    /// * `entries` - The key, size and last access time of all the media
+    ///   content in the cache.
+    ///
+    /// * `now` - The current time.
+    pub fn select_for_removal<K>(
+        &self,
+        entries: impl IntoIterator<Item = (K, usize, MilliSecondsSinceUnixEpoch)>,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> Vec<K> {
+        let mut to_remove = Vec::new();
+        let mut to_keep = Vec::new();
+
+        for (key, size, last_access) in entries {
+            if self.exceeds_max_file_size(size) || self.has_expired(last_access, now) {
+                to_remove.push(key);
+            } else {
+                to_keep.push((key, size, last_access));
+            }
+        }
+
+        if let Some(max_cache_size) = self.max_cache_size {
+            to_remove.extend(select_for_trimming(to_keep, max_cache_size).0);
+        }
+
+        to_remove
+    }
+}
+
+/// The minimum duration between two updates of the last access time of media
+/// content in a store, one hour.
+///
+/// Stores only update the last access time of media content that is read if
+/// it is older than this, to avoid a write on every read.
+pub const MEDIA_LAST_ACCESS_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Whether the last access time of media content that is read at `now` should
+/// be updated in a store.
+///
+/// This is meant to be used by store implementations.
+pub fn should_update_media_last_access(
+    last_access: MilliSecondsSinceUnixEpoch,
+    now: MilliSecondsSinceUnixEpoch,
+) -> bool {
+    let elapsed = u64::from(now.get()).saturating_sub(last_access.get().into());
+    u128::from(elapsed) >= MEDIA_LAST_ACCESS_UPDATE_INTERVAL.as_millis()
+}
+
+/// Select the least recently accessed media content to remove from a cache for
+/// its total size to be at most `max_size`.
+///
+/// This is meant to be used by store implementations.
+///
+/// Returns the keys of the media content to remove and the total size of the
+/// media content that is left in the cache.
+///
+/// # Arguments
+///
+/// * `entries` - The key, size and last access time of all the media content
+///   in the cache.
+///
+/// * `max_size` - The maximum total size of the cache, in bytes.
+pub fn select_for_trimming<K>(
+    entries: impl IntoIterator<Item = (K, usize, MilliSecondsSinceUnixEpoch)>,
+    max_size: usize,
+) -> (Vec<K>, usize) {
+    let mut entries: Vec<_> = entries.into_iter().collect();
+    // Keep the most recently accessed content first.
+    entries.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+
+    let mut to_remove = Vec::new();
+    let mut total_size = 0usize;
+    for (key, size, _) in entries {
+        let new_total_size = total_size.saturating_add(size);
+
+        if !to_remove.is_empty() || new_total_size > max_size {
+            to_remove.push(key);
+        } else {
+            total_size = new_total_size;
+        }
+    }
+
+    (to_remove, total_size)
+}
+
+/// Statistics about the media cache of a store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MediaCacheStats {
+    /// The number of media content in the cache.
+    pub count: usize,
+
+    /// The total size of the media content in the cache, in bytes.
+    pub total_size: usize,
+}
+
 /// Trait for media event content.
 pub trait MediaEventContent {
     /// Get the source of the file for `Self`.
@@ -166,3 +360,57 @@ impl MediaEventContent for LocationMessageEventContent {
         self.info.as_ref()?.thumbnail_source.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ruma::{uint, MilliSecondsSinceUnixEpoch};
+
+    use super::{select_for_trimming, should_update_media_last_access, MediaRetentionPolicy};
+
+    fn ts(secs: u32) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(uint!(1_000) * secs.into())
+    }
+
+    #[test]
+    fn select_for_removal() {
+        let entries = [("a", 10, ts(10)), ("b", 30, ts(30)), ("c", 20, ts(20)), ("d", 5, ts(1))];
+        let now = ts(40);
+
+        // Without limits, everything is kept.
+        assert!(MediaRetentionPolicy::empty().select_for_removal(entries, now).is_empty());
+
+        // The default limits keep small and recently accessed content.
+        assert!(MediaRetentionPolicy::default().select_for_removal(entries, now).is_empty());
+
+        // Content that is too big is removed.
+        let policy = MediaRetentionPolicy::empty().with_max_file_size(Some(20));
+        assert_eq!(policy.select_for_removal(entries, now), ["b"]);
+
+        // Content that wasn't accessed recently is removed.
+        let policy =
+            MediaRetentionPolicy::empty().with_last_access_expiry(Some(Duration::from_secs(30)));
+        assert_eq!(policy.select_for_removal(entries, now), ["a", "d"]);
+
+        // The least recently accessed content is removed first.
+        let policy = MediaRetentionPolicy::empty().with_max_cache_size(Some(55));
+        assert_eq!(policy.select_for_removal(entries, now), ["a", "d"]);
+    }
+
+    #[test]
+    fn select_for_trimming_keeps_most_recent() {
+        let entries = [("a", 10, ts(10)), ("b", 30, ts(30)), ("c", 20, ts(20)), ("d", 5, ts(1))];
+
+        assert_eq!(select_for_trimming(entries, 100), (vec![], 65));
+        assert_eq!(select_for_trimming(entries, 55), (vec!["a", "d"], 50));
+        assert_eq!(select_for_trimming(entries, 0), (vec!["b", "c", "a", "d"], 0));
+    }
+
+    #[test]
+    fn last_access_update_is_throttled() {
+        assert!(!should_update_media_last_access(ts(10), ts(10)));
+        assert!(!should_update_media_last_access(ts(10), ts(60 * 60)));
+        assert!(should_update_media_last_access(ts(10), ts(10 + 60 * 60)));
+    }
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     sync::{Arc, RwLock as SyncRwLock},
 };
 
@@ -44,9 +44,12 @@ use crate::{
     deserialized_responses::MemberEvent,
     store::{DynStateStore, Result as StoreResult, StateStoreExt},
     sync::UnreadNotificationsCount,
-    MinimalStateEvent, RoomMemberships,
+    MinimalRoomMemberEvent, MinimalStateEvent, RoomMemberships,
 };
 
+/// The maximum number of historical members kept in memory per room.
+const MAX_HISTORICAL_MEMBERS: usize = 500;
+
 /// The underlying room data structure collecting state for joined, left and
 /// invited rooms.
 #[derive(Debug, Clone)]
@@ -55,6 +58,55 @@ pub struct Room {
     own_user_id: OwnedUserId,
     inner: Arc<SyncRwLock<RoomInfo>>,
     store: Arc<DynStateStore>,
+    /// Member events of users we don't know the current membership of, that
+    /// were received for events in the room history.
+    historical_members: Arc<SyncRwLock<HistoricalMembers>>,
+}
+
+/// The historical members of a room, the least recently used ones are
+/// dropped when there are more than [`MAX_HISTORICAL_MEMBERS`].
+#[derive(Debug, Default)]
+struct HistoricalMembers {
+    next_tick: u64,
+    members: BTreeMap<OwnedUserId, (u64, MinimalRoomMemberEvent)>,
+    user_ids: BTreeMap<u64, OwnedUserId>,
+}
+
+impl HistoricalMembers {
+    fn get(&mut self, user_id: &UserId) -> Option<MinimalRoomMemberEvent> {
+        let tick = self.next_tick;
+        let (previous, event) = self.members.get_mut(user_id)?;
+
+        self.next_tick += 1;
+        let user_id = self.user_ids.remove(previous).expect("the tick of a member is indexed");
+        self.user_ids.insert(tick, user_id);
+        *previous = tick;
+
+        Some(event.clone())
+    }
+
+    fn insert(&mut self, user_id: OwnedUserId, event: MinimalRoomMemberEvent) {
+        if self.members.contains_key(&user_id) {
+            return;
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.members.insert(user_id.clone(), (tick, event));
+        self.user_ids.insert(tick, user_id);
+
+        while self.members.len() > MAX_HISTORICAL_MEMBERS {
+            let Some(&oldest) = self.user_ids.keys().next() else { break };
+            if let Some(user_id) = self.user_ids.remove(&oldest) {
+                self.members.remove(&user_id);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.members.clear();
+        self.user_ids.clear();
+    }
 }
 
 /// The room summary containing member counts and members that should be used to
@@ -103,6 +155,7 @@ impl Room {
             room_id: room_info.room_id.clone(),
             store,
             inner: Arc::new(SyncRwLock::new(room_info)),
+            historical_members: Default::default(),
         }
     }
 
@@ -446,6 +499,33 @@ impl Room {
         *inner = summary;
     }
 
+    /// Get the member event of the given user at some point in the room
+    /// history, as received with lazy-loading.
+    ///
+    /// This is only available for users that [`Room::get_member()`] doesn't
+    /// know, and doesn't reflect the current state of the room. It is kept in
+    /// memory only, see
+    /// [`BaseClient::receive_lazy_loaded_members()`][crate::BaseClient::receive_lazy_loaded_members].
+    ///
+    /// Only the most recently used historical members are kept, and they are
+    /// forgotten when the timeline of the room is reset by a limited sync.
+    pub fn get_historical_member(&self, user_id: &UserId) -> Option<MinimalRoomMemberEvent> {
+        self.historical_members.write().unwrap().get(user_id)
+    }
+
+    pub(crate) fn add_historical_member(
+        &self,
+        user_id: OwnedUserId,
+        event: MinimalRoomMemberEvent,
+    ) {
+        self.historical_members.write().unwrap().insert(user_id, event);
+    }
+
+    /// Forget the historical members, e.g. because the timeline was reset.
+    pub(crate) fn clear_historical_members(&self) {
+        self.historical_members.write().unwrap().clear();
+    }
+
     /// Get the `RoomMember` with the given `user_id`.
     ///
     /// Returns `None` if the member was never part of this room, otherwise
@@ -1132,4 +1212,33 @@ mod test {
         room.inner.write().unwrap().update_summary(&summary);
         assert_eq!(room.display_name().await.unwrap(), DisplayName::EmptyWas("Matthew".to_owned()));
     }
+
+    #[test]
+    fn test_historical_members_are_evicted() {
+        let (_store, room) = make_room(RoomState::Joined);
+        let member = || {
+            MinimalStateEvent::Original(OriginalMinimalStateEvent {
+                content: RoomMemberEventContent::new(MembershipState::Join),
+                event_id: None,
+            })
+        };
+        let user_id = |i: usize| UserId::parse(format!("@user{i}:example.org")).unwrap();
+
+        for i in 0..MAX_HISTORICAL_MEMBERS {
+            room.add_historical_member(user_id(i), member());
+        }
+
+        // Using the first member makes the second one the least recently used.
+        assert!(room.get_historical_member(&user_id(0)).is_some());
+
+        let newcomer = user_id(MAX_HISTORICAL_MEMBERS);
+        room.add_historical_member(newcomer.clone(), member());
+
+        assert!(room.get_historical_member(&user_id(0)).is_some());
+        assert!(room.get_historical_member(&user_id(1)).is_none());
+        assert!(room.get_historical_member(&newcomer).is_some());
+
+        room.clear_historical_members();
+        assert!(room.get_historical_member(&user_id(0)).is_none());
+    }
 }
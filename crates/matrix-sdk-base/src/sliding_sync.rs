@@ -159,6 +159,7 @@ impl BaseClient {
 
                 if room_data.limited {
                     room_info.mark_members_missing();
+                    room.clear_historical_members();
                 }
 
                 let timeline = self
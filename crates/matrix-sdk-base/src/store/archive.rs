@@ -0,0 +1,469 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend-agnostic archives of the content of the stores.
+//!
+//! A [`StoreArchive`] can be exported from the stores of a [`StoreConfig`] and
+//! imported into the stores of another one, to migrate the data of a client
+//! from one store implementation to another.
+//!
+//! The archive contains the room infos of the state store and, with the
+//! `e2e-encryption` feature, the account, the private cross-signing identity,
+//! the Olm sessions, the inbound and outbound group sessions, the devices and
+//! identities of the tracked users, the room settings and the backup keys of
+//! the crypto store. The rest of the state is fetched again from the
+//! homeserver during the next sync.
+//!
+//! The serialized archive is always encrypted with a passphrase.
+
+#[cfg(feature = "e2e-encryption")]
+use std::{collections::BTreeMap, sync::Arc};
+
+#[cfg(feature = "e2e-encryption")]
+use matrix_sdk_crypto::{
+    olm::{
+        InboundGroupSession, OutboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+        PickledInboundGroupSession, PickledOutboundGroupSession, PickledSession,
+        PrivateCrossSigningIdentity, Session,
+    },
+    store::{
+        BackupKeys, Changes, CryptoStoreError, DeviceChanges, DynCryptoStore, IdentityChanges,
+        RecoveryKey, RoomSettings, TrackedUser,
+    },
+    ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities,
+};
+use matrix_sdk_store_encryption::StoreCipher;
+#[cfg(feature = "e2e-encryption")]
+use ruma::{OwnedRoomId, RoomId};
+use serde::{Deserialize, Serialize};
+
+use super::{DynStateStore, StateChanges, StoreConfig, StoreEncryptionError, StoreError};
+use crate::{RoomInfo, RoomState};
+
+/// The version of the format of the serialized archives.
+const ARCHIVE_VERSION: u8 = 1;
+
+/// An error that can happen when exporting or importing a [`StoreArchive`].
+#[derive(Debug, thiserror::Error)]
+pub enum StoreArchiveError {
+    /// An error happened in the state store.
+    #[error(transparent)]
+    StateStore(#[from] StoreError),
+
+    /// An error happened in the crypto store.
+    #[cfg(feature = "e2e-encryption")]
+    #[error(transparent)]
+    CryptoStore(#[from] CryptoStoreError),
+
+    /// The archive failed to be encrypted or decrypted.
+    ///
+    /// This happens notably if the wrong passphrase is used to decrypt an
+    /// archive.
+    #[error(transparent)]
+    Encryption(#[from] StoreEncryptionError),
+
+    /// The archive uses a format version that is not supported.
+    #[error("unsupported archive version {0}, the latest supported version is {ARCHIVE_VERSION}")]
+    UnsupportedVersion(u8),
+
+    /// The archive is truncated or malformed.
+    #[error("the archive is malformed")]
+    Malformed,
+}
+
+/// The content of the stores of a client, that can be moved between store
+/// implementations.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async {
+/// use matrix_sdk_base::store::{StoreArchive, StoreConfig};
+///
+/// # let old_config = StoreConfig::new();
+/// # let new_config = StoreConfig::new();
+/// let archive = StoreArchive::export(&old_config).await?;
+/// let bytes = archive.encrypt("passphrase")?;
+///
+/// // Later, or on another machine.
+/// let archive = StoreArchive::decrypt(&bytes, "passphrase")?;
+/// archive.import(&new_config).await?;
+/// # Ok::<(), matrix_sdk_base::store::StoreArchiveError>(()) };
+/// ```
+#[derive(Serialize, Deserialize)]
+pub struct StoreArchive {
+    room_infos: Vec<RoomInfo>,
+    #[cfg(feature = "e2e-encryption")]
+    crypto: Option<CryptoArchive>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl std::fmt::Debug for StoreArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("StoreArchive");
+        s.field("rooms", &self.room_infos.len());
+        #[cfg(feature = "e2e-encryption")]
+        s.field("crypto", &self.crypto);
+        s.finish()
+    }
+}
+
+impl StoreArchive {
+    /// Export the content of the stores of the given `StoreConfig`.
+    pub async fn export(config: &StoreConfig) -> Result<Self, StoreArchiveError> {
+        let mut room_infos = config.state_store.get_room_infos().await?;
+        room_infos.extend(config.state_store.get_stripped_room_infos().await?);
+
+        #[cfg(feature = "e2e-encryption")]
+        let crypto = CryptoArchive::export(
+            &*config.crypto_store,
+            room_infos.iter().map(|room_info| room_info.room_id()),
+        )
+        .await?;
+
+        Ok(Self {
+            room_infos,
+            #[cfg(feature = "e2e-encryption")]
+            crypto,
+        })
+    }
+
+    /// Import the content of this archive into the stores of the given
+    /// `StoreConfig`.
+    ///
+    /// The stores should be empty, the data that is already present in them
+    /// is overwritten.
+    pub async fn import(self, config: &StoreConfig) -> Result<(), StoreArchiveError> {
+        import_room_infos(&*config.state_store, self.room_infos).await?;
+
+        #[cfg(feature = "e2e-encryption")]
+        if let Some(crypto) = self.crypto {
+            crypto.import(&*config.crypto_store).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The number of rooms in this archive.
+    pub fn room_count(&self) -> usize {
+        self.room_infos.len()
+    }
+
+    /// Whether this archive contains the data of a crypto store.
+    #[cfg(feature = "e2e-encryption")]
+    pub fn has_crypto(&self) -> bool {
+        self.crypto.is_some()
+    }
+
+    /// Serialize this archive and encrypt it with the given passphrase.
+    ///
+    /// The result starts with the version of the format, followed by the store
+    /// cipher protected by the passphrase and the encrypted content.
+    pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>, StoreArchiveError> {
+        let cipher = StoreCipher::new()?;
+        #[cfg(not(test))]
+        let exported_cipher = cipher.export(passphrase)?;
+        #[cfg(test)]
+        let exported_cipher = cipher._insecure_export_fast_for_testing(passphrase)?;
+        let content = cipher.encrypt_value(self)?;
+
+        let cipher_len =
+            u32::try_from(exported_cipher.len()).map_err(|_| StoreArchiveError::Malformed)?;
+
+        let mut bytes = Vec::with_capacity(5 + exported_cipher.len() + content.len());
+        bytes.push(ARCHIVE_VERSION);
+        bytes.extend_from_slice(&cipher_len.to_be_bytes());
+        bytes.extend_from_slice(&exported_cipher);
+        bytes.extend_from_slice(&content);
+
+        Ok(bytes)
+    }
+
+    /// Decrypt an archive that was encrypted with
+    /// [`StoreArchive::encrypt()`].
+    pub fn decrypt(bytes: &[u8], passphrase: &str) -> Result<Self, StoreArchiveError> {
+        let (&version, rest) = bytes.split_first().ok_or(StoreArchiveError::Malformed)?;
+        if version != ARCHIVE_VERSION {
+            return Err(StoreArchiveError::UnsupportedVersion(version));
+        }
+
+        if rest.len() < 4 {
+            return Err(StoreArchiveError::Malformed);
+        }
+        let (cipher_len, rest) = rest.split_at(4);
+        let cipher_len = u32::from_be_bytes(cipher_len.try_into().unwrap()) as usize;
+
+        if rest.len() < cipher_len {
+            return Err(StoreArchiveError::Malformed);
+        }
+        let (exported_cipher, content) = rest.split_at(cipher_len);
+
+        let cipher = StoreCipher::import(passphrase, exported_cipher)?;
+        Ok(cipher.decrypt_value(content)?)
+    }
+}
+
+async fn import_room_infos(
+    store: &DynStateStore,
+    room_infos: Vec<RoomInfo>,
+) -> Result<(), StoreError> {
+    let mut changes = StateChanges::default();
+
+    for room_info in room_infos {
+        if room_info.state() == RoomState::Invited {
+            changes.add_stripped_room(room_info);
+        } else {
+            changes.add_room(room_info);
+        }
+    }
+
+    store.save_changes(&changes).await
+}
+
+/// The content of a crypto store.
+#[cfg(feature = "e2e-encryption")]
+#[derive(Serialize, Deserialize)]
+struct CryptoArchive {
+    account: PickledAccount,
+    private_identity: Option<PickledCrossSigningIdentity>,
+    sessions: Vec<PickledSession>,
+    inbound_group_sessions: Vec<PickledInboundGroupSession>,
+    #[serde(default)]
+    outbound_group_sessions: Vec<PickledOutboundGroupSession>,
+    devices: Vec<ReadOnlyDevice>,
+    identities: Vec<ReadOnlyUserIdentities>,
+    tracked_users: Vec<TrackedUser>,
+    #[serde(default)]
+    room_settings: BTreeMap<OwnedRoomId, RoomSettings>,
+    backup_version: Option<String>,
+    recovery_key: Option<RecoveryKey>,
+}
+
+#[cfg(all(feature = "e2e-encryption", not(tarpaulin_include)))]
+impl std::fmt::Debug for CryptoArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CryptoArchive")
+            .field("user_id", &self.account.user_id)
+            .field("device_id", &self.account.device_id)
+            .field("sessions", &self.sessions.len())
+            .field("inbound_group_sessions", &self.inbound_group_sessions.len())
+            .field("outbound_group_sessions", &self.outbound_group_sessions.len())
+            .field("devices", &self.devices.len())
+            .field("tracked_users", &self.tracked_users.len())
+            .field("room_settings", &self.room_settings.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "e2e-encryption")]
+impl CryptoArchive {
+    /// Export the content of the given crypto store.
+    ///
+    /// Returns `None` if the store doesn't contain an account.
+    ///
+    /// The outbound group sessions and the room settings are looked up for
+    /// the given rooms.
+    async fn export(
+        store: &DynCryptoStore,
+        room_ids: impl Iterator<Item = &RoomId>,
+    ) -> Result<Option<Self>, CryptoStoreError> {
+        let Some(account) = store.load_account().await? else {
+            return Ok(None);
+        };
+
+        let private_identity = match store.load_identity().await? {
+            Some(identity) => Some(identity.pickle().await),
+            None => None,
+        };
+
+        let tracked_users = store.load_tracked_users().await?;
+
+        let mut devices = Vec::new();
+        let mut identities = Vec::new();
+
+        for user in &tracked_users {
+            if let Some(identity) = store.get_user_identity(&user.user_id).await? {
+                identities.push(identity);
+            }
+
+            devices.extend(store.get_user_devices(&user.user_id).await?.into_values());
+        }
+
+        let mut sessions = Vec::new();
+        for session in store.get_all_sessions().await? {
+            sessions.push(session.pickle().await);
+        }
+
+        let mut inbound_group_sessions = Vec::new();
+        for session in store.get_inbound_group_sessions().await? {
+            inbound_group_sessions.push(session.pickle().await);
+        }
+
+        let mut outbound_group_sessions = Vec::new();
+        let mut room_settings = BTreeMap::new();
+
+        for room_id in room_ids {
+            if let Some(session) = store.get_outbound_group_session(room_id).await? {
+                outbound_group_sessions.push(session.pickle().await);
+            }
+
+            if let Some(settings) = store.get_room_settings(room_id).await? {
+                room_settings.insert(room_id.to_owned(), settings);
+            }
+        }
+
+        let BackupKeys { recovery_key, backup_version } = store.load_backup_keys().await?;
+
+        Ok(Some(Self {
+            account: account.pickle().await,
+            private_identity,
+            sessions,
+            inbound_group_sessions,
+            outbound_group_sessions,
+            devices,
+            identities,
+            tracked_users,
+            room_settings,
+            backup_version,
+            recovery_key,
+        }))
+    }
+
+    /// Import this content into the given crypto store.
+    async fn import(self, store: &DynCryptoStore) -> Result<(), CryptoStoreError> {
+        let account = ReadOnlyAccount::from_pickle(self.account)?;
+        let identity_keys = Arc::new(account.identity_keys());
+
+        let private_identity = match self.private_identity {
+            Some(pickle) => Some(
+                PrivateCrossSigningIdentity::from_pickle(pickle)
+                    .await
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?,
+            ),
+            None => None,
+        };
+
+        let sessions = self
+            .sessions
+            .into_iter()
+            .map(|pickle| {
+                Session::from_pickle(
+                    account.user_id().to_owned(),
+                    account.device_id().to_owned(),
+                    identity_keys.clone(),
+                    pickle,
+                )
+            })
+            .collect();
+
+        let inbound_group_sessions = self
+            .inbound_group_sessions
+            .into_iter()
+            .map(InboundGroupSession::from_pickle)
+            .collect::<Result<_, _>>()?;
+
+        let outbound_group_sessions = self
+            .outbound_group_sessions
+            .into_iter()
+            .map(|pickle| {
+                OutboundGroupSession::from_pickle(
+                    account.device_id().to_owned(),
+                    identity_keys.clone(),
+                    pickle,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+
+        // The account needs to be saved first, some stores use it to save the
+        // sessions.
+        store.save_account(account).await?;
+
+        store
+            .save_changes(Changes {
+                private_identity,
+                backup_version: self.backup_version,
+                recovery_key: self.recovery_key,
+                sessions,
+                inbound_group_sessions,
+                outbound_group_sessions,
+                room_settings: self.room_settings.into_iter().collect(),
+                identities: IdentityChanges { new: self.identities, ..Default::default() },
+                devices: DeviceChanges { new: self.devices, ..Default::default() },
+                ..Default::default()
+            })
+            .await?;
+
+        let tracked_users: Vec<_> =
+            self.tracked_users.iter().map(|user| (user.user_id.as_ref(), user.dirty)).collect();
+        store.save_tracked_users(&tracked_users).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use matrix_sdk_test::async_test;
+    use ruma::room_id;
+
+    use super::{StoreArchive, StoreArchiveError};
+    use crate::{
+        store::{MemoryStore, StateChanges, StateStore, StoreConfig},
+        RoomInfo, RoomState,
+    };
+
+    #[async_test]
+    async fn export_and_import() {
+        let joined_room_id = room_id!("!joined:localhost");
+        let invited_room_id = room_id!("!invited:localhost");
+
+        let old_store = MemoryStore::new();
+        let mut changes = StateChanges::default();
+        changes.add_room(RoomInfo::new(joined_room_id, RoomState::Joined));
+        changes.add_stripped_room(RoomInfo::new(invited_room_id, RoomState::Invited));
+        old_store.save_changes(&changes).await.unwrap();
+
+        // The memory crypto store doesn't keep the account, so the archive
+        // doesn't contain the crypto data. The crypto data is tested with
+        // persistent stores.
+        let old_config = StoreConfig::new().state_store(old_store);
+
+        let archive = StoreArchive::export(&old_config).await.unwrap();
+        assert_eq!(archive.room_count(), 2);
+        #[cfg(feature = "e2e-encryption")]
+        assert!(!archive.has_crypto());
+
+        let bytes = archive.encrypt("passphrase").unwrap();
+        assert_matches!(
+            StoreArchive::decrypt(&bytes, "wrong passphrase"),
+            Err(StoreArchiveError::Encryption(_))
+        );
+        assert_matches!(
+            StoreArchive::decrypt(&bytes[..3], "passphrase"),
+            Err(StoreArchiveError::Malformed)
+        );
+
+        let new_store = MemoryStore::new();
+        let new_config = StoreConfig::new().state_store(new_store.clone());
+        StoreArchive::decrypt(&bytes, "passphrase").unwrap().import(&new_config).await.unwrap();
+
+        let room_infos = new_store.get_room_infos().await.unwrap();
+        assert_eq!(room_infos.len(), 1);
+        assert_eq!(room_infos[0].room_id(), joined_room_id);
+        let stripped_room_infos = new_store.get_stripped_room_infos().await.unwrap();
+        assert_eq!(stripped_room_infos.len(), 1);
+        assert_eq!(stripped_room_infos[0].room_id(), invited_room_id);
+    }
+}
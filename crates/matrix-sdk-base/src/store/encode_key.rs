@@ -1,3 +1,23 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The encoding of the keys of the key-value store implementations.
+//!
+//! The components of a key are separated by [`ENCODE_SEPARATOR`]. When the
+//! store is encrypted, each component is hashed with the [`StoreCipher`] so
+//! the keys don't leak any data.
+
 use std::{borrow::Cow, ops::Deref};
 
 use matrix_sdk_store_encryption::StoreCipher;
@@ -12,9 +32,9 @@ use ruma::{
 
 /// Hold any data to be used as an encoding key
 /// without checking for the existence of `ENCODE_SEPARATOR` within
+#[derive(Debug)]
 pub struct EncodeUnchecked<'a>(&'a [u8]);
 
-#[cfg(feature = "state-store")]
 impl<'a> EncodeUnchecked<'a> {
     /// Wrap any `[u8]`
     pub fn from(bytes: &'a [u8]) -> Self {
@@ -28,16 +48,21 @@ impl<'a> EncodeKey for EncodeUnchecked<'a> {
     }
 }
 
+/// The byte that ends every component of an encoded key.
 pub const ENCODE_SEPARATOR: u8 = 0xff;
 
+/// A type that can be used as a key in a key-value store.
 pub trait EncodeKey {
-    fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
-        unimplemented!()
-    }
+    /// The raw bytes of this key, without separator.
+    fn encode_as_bytes(&self) -> Cow<'_, [u8]>;
 
+    /// Encode this key, each component followed by [`ENCODE_SEPARATOR`].
     fn encode(&self) -> Vec<u8> {
         [self.encode_as_bytes().deref(), &[ENCODE_SEPARATOR]].concat()
     }
+
+    /// Encode this key, each component hashed with the given store cipher and
+    /// followed by [`ENCODE_SEPARATOR`].
     fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
         let key = store_cipher.hash_key(table_name, &self.encode_as_bytes());
         [key.as_slice(), &[ENCODE_SEPARATOR]].concat()
@@ -160,6 +185,10 @@ where
     A: EncodeKey,
     B: EncodeKey,
 {
+    fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+        self.encode().into()
+    }
+
     fn encode(&self) -> Vec<u8> {
         [
             self.0.encode_as_bytes().deref(),
@@ -187,6 +216,10 @@ where
     B: EncodeKey,
     C: EncodeKey,
 {
+    fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+        self.encode().into()
+    }
+
     fn encode(&self) -> Vec<u8> {
         [
             self.0.encode_as_bytes().deref(),
@@ -219,6 +252,10 @@ where
     C: EncodeKey,
     D: EncodeKey,
 {
+    fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+        self.encode().into()
+    }
+
     fn encode(&self) -> Vec<u8> {
         [
             self.0.encode_as_bytes().deref(),
@@ -256,6 +293,10 @@ where
     D: EncodeKey,
     E: EncodeKey,
 {
+    fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+        self.encode().into()
+    }
+
     fn encode(&self) -> Vec<u8> {
         [
             self.0.encode_as_bytes().deref(),
@@ -288,3 +329,131 @@ where
         .concat()
     }
 }
+
+#[cfg(feature = "e2e-encryption")]
+mod crypto {
+    use std::borrow::Cow;
+
+    use matrix_sdk_crypto::{
+        olm::{InboundGroupSession, OutboundGroupSession, Session},
+        types::{events::room_key_request::SupportedKeyInfo, EventEncryptionAlgorithm},
+        ReadOnlyDevice, SecretInfo,
+    };
+    use matrix_sdk_store_encryption::StoreCipher;
+
+    use super::{EncodeKey, ENCODE_SEPARATOR};
+
+    impl EncodeKey for InboundGroupSession {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            self.encode().into()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            (self.room_id(), self.session_id()).encode()
+        }
+
+        fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
+            (self.room_id(), self.session_id()).encode_secure(table_name, store_cipher)
+        }
+    }
+
+    impl EncodeKey for OutboundGroupSession {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            self.encode().into()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            self.room_id().encode()
+        }
+        fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
+            self.room_id().encode_secure(table_name, store_cipher)
+        }
+    }
+
+    impl EncodeKey for Session {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            self.encode().into()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            let sender_key = self.sender_key().to_base64();
+            let session_id = self.session_id();
+
+            [sender_key.as_bytes(), &[ENCODE_SEPARATOR], session_id.as_bytes(), &[ENCODE_SEPARATOR]]
+                .concat()
+        }
+
+        fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
+            let sender_key =
+                store_cipher.hash_key(table_name, self.sender_key().to_base64().as_bytes());
+            let session_id = store_cipher.hash_key(table_name, self.session_id().as_bytes());
+
+            [sender_key.as_slice(), &[ENCODE_SEPARATOR], session_id.as_slice(), &[ENCODE_SEPARATOR]]
+                .concat()
+        }
+    }
+
+    impl EncodeKey for SecretInfo {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            self.encode().into()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            match self {
+                SecretInfo::KeyRequest(k) => k.encode(),
+                SecretInfo::SecretRequest(s) => s.encode(),
+            }
+        }
+        fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
+            match self {
+                SecretInfo::KeyRequest(k) => k.encode_secure(table_name, store_cipher),
+                SecretInfo::SecretRequest(s) => s.encode_secure(table_name, store_cipher),
+            }
+        }
+    }
+
+    impl EncodeKey for EventEncryptionAlgorithm {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            let s: &str = self.as_ref();
+            s.as_bytes().into()
+        }
+    }
+
+    impl EncodeKey for SupportedKeyInfo {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            self.encode().into()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            (self.room_id(), &self.algorithm(), self.session_id()).encode()
+        }
+        fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
+            let room_id = store_cipher.hash_key(table_name, self.room_id().as_bytes());
+            let algorithm = store_cipher.hash_key(table_name, self.algorithm().as_ref().as_bytes());
+            let session_id = store_cipher.hash_key(table_name, self.session_id().as_bytes());
+
+            [
+                room_id.as_slice(),
+                &[ENCODE_SEPARATOR],
+                algorithm.as_slice(),
+                &[ENCODE_SEPARATOR],
+                session_id.as_slice(),
+                &[ENCODE_SEPARATOR],
+            ]
+            .concat()
+        }
+    }
+
+    impl EncodeKey for ReadOnlyDevice {
+        fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
+            self.encode().into()
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            (self.user_id(), self.device_id()).encode()
+        }
+        fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
+            (self.user_id(), self.device_id()).encode_secure(table_name, store_cipher)
+        }
+    }
+}
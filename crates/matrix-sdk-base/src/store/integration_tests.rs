@@ -1,6 +1,9 @@
 //! Trait and macro of integration tests for StateStore implementations.
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
 
 use assert_matches::assert_matches;
 use async_trait::async_trait;
@@ -32,8 +35,9 @@ use serde_json::{json, value::Value as JsonValue};
 
 use super::DynStateStore;
 use crate::{
-    deserialized_responses::MemberEvent,
-    media::{MediaFormat, MediaRequest, MediaThumbnailSize},
+    deserialized_responses::{MemberEvent, SyncTimelineEvent},
+    event_cache::{EventCacheChanges, EventChunk},
+    media::{MediaCacheStats, MediaFormat, MediaRequest, MediaRetentionPolicy, MediaThumbnailSize},
     store::{Result, StateStoreExt},
     RoomInfo, RoomMemberships, RoomState, StateChanges, StateStoreDataKey, StateStoreDataValue,
 };
@@ -49,6 +53,10 @@ pub trait StateStoreIntegrationTests {
     async fn populate(&self) -> Result<()>;
     /// Test media content storage.
     async fn test_media_content(&self);
+    /// Test media cache cleaning.
+    async fn test_media_cache_cleaning(&self) -> Result<()>;
+    /// Test media cache trimming.
+    async fn test_media_cache_trimming(&self) -> Result<()>;
     /// Test room topic redaction.
     async fn test_topic_redaction(&self) -> Result<()>;
     /// Test populating the store.
@@ -73,6 +81,10 @@ pub trait StateStoreIntegrationTests {
     async fn test_stripped_non_stripped(&self) -> Result<()>;
     /// Test room removal.
     async fn test_room_removal(&self) -> Result<()>;
+    /// Test event cache saving.
+    async fn test_event_cache_saving(&self) -> Result<()>;
+    /// Test clearing the whole store.
+    async fn test_clear(&self) -> Result<()>;
 }
 
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -257,6 +269,72 @@ impl StateStoreIntegrationTests for DynStateStore {
         );
     }
 
+    async fn test_media_cache_cleaning(&self) -> Result<()> {
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(format!("mxc://localhost/{id}").into()),
+            format: MediaFormat::File,
+        };
+
+        assert_eq!(self.media_cache_stats().await?, MediaCacheStats::default());
+
+        self.add_media_content(&request("small"), vec![0; 10]).await?;
+        self.add_media_content(&request("medium"), vec![0; 20]).await?;
+        self.add_media_content(&request("big"), vec![0; 30]).await?;
+        assert_eq!(self.media_cache_stats().await?, MediaCacheStats { count: 3, total_size: 60 });
+
+        // The default policy keeps small and recently accessed content.
+        self.clean_media_cache(MediaRetentionPolicy::default()).await?;
+        assert_eq!(self.media_cache_stats().await?.count, 3);
+
+        // Content over the maximum file size is removed.
+        self.clean_media_cache(MediaRetentionPolicy::new().with_max_file_size(Some(25))).await?;
+        assert_eq!(self.media_cache_stats().await?, MediaCacheStats { count: 2, total_size: 30 });
+        assert!(self.get_media_content(&request("big")).await?.is_none());
+        assert!(self.get_media_content(&request("small")).await?.is_some());
+
+        // Content is removed until the cache fits in the maximum size.
+        self.clean_media_cache(MediaRetentionPolicy::new().with_max_cache_size(Some(25))).await?;
+        let stats = self.media_cache_stats().await?;
+        assert_eq!(stats.count, 1);
+        assert!(stats.total_size <= 25);
+
+        // Content that wasn't accessed since the expiry is removed.
+        let policy = MediaRetentionPolicy::new().with_last_access_expiry(Some(Duration::ZERO));
+        self.clean_media_cache(policy).await?;
+        assert_eq!(self.media_cache_stats().await?, MediaCacheStats::default());
+
+        Ok(())
+    }
+
+    async fn test_media_cache_trimming(&self) -> Result<()> {
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(format!("mxc://localhost/{id}").into()),
+            format: MediaFormat::File,
+        };
+
+        assert_eq!(self.trim_media_cache(100).await?, 0);
+
+        self.add_media_content(&request("small"), vec![0; 10]).await?;
+        self.add_media_content(&request("medium"), vec![0; 20]).await?;
+        self.add_media_content(&request("big"), vec![0; 30]).await?;
+
+        // Nothing is removed if the cache fits in the maximum size.
+        assert_eq!(self.trim_media_cache(100).await?, 60);
+        assert_eq!(self.media_cache_stats().await?.count, 3);
+
+        // Content is removed until the cache fits in the maximum size.
+        let total_size = self.trim_media_cache(25).await?;
+        let stats = self.media_cache_stats().await?;
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.total_size, total_size);
+        assert!(total_size <= 25);
+
+        assert_eq!(self.trim_media_cache(0).await?, 0);
+        assert_eq!(self.media_cache_stats().await?, MediaCacheStats::default());
+
+        Ok(())
+    }
+
     async fn test_topic_redaction(&self) -> Result<()> {
         let room_id = room_id();
         self.populate().await?;
@@ -860,6 +938,143 @@ impl StateStoreIntegrationTests for DynStateStore {
         assert!(self.get_stripped_room_infos().await?.is_empty(), "still stripped room info found");
         Ok(())
     }
+
+    async fn test_event_cache_saving(&self) -> Result<()> {
+        let room_id = room_id();
+        assert!(self.get_event_chunk_range(room_id).await?.is_none());
+
+        let mut first_chunk = EventChunk::new(0, Some("prev_batch".to_owned()));
+        first_chunk.events.extend((0..2).map(cached_message));
+        let mut second_chunk = EventChunk::new(1, None);
+        second_chunk.events.push(cached_message(2));
+
+        let mut changes = StateChanges::default();
+        changes.event_cache.insert(
+            room_id.to_owned(),
+            EventCacheChanges {
+                reset: false,
+                chunks: BTreeMap::from([(0, first_chunk), (1, second_chunk.clone())]),
+                removed_chunks: BTreeSet::new(),
+            },
+        );
+        self.save_changes(&changes).await?;
+
+        assert_eq!(self.get_event_chunk_range(room_id).await?, Some(0..=1));
+        let chunk = self.get_event_chunk(room_id, 0).await?.expect("first chunk not found");
+        assert_eq!(chunk.prev_batch.as_deref(), Some("prev_batch"));
+        assert_eq!(chunk.events.len(), 2);
+        assert!(self.get_event_chunk(room_id, 2).await?.is_none());
+        assert_eq!(self.get_event_chunk_id(room_id, event_id!("$message_1")).await?, Some(0));
+        assert_eq!(self.get_event_chunk_id(room_id, event_id!("$message_2")).await?, Some(1));
+
+        // Prepend an older chunk and update the newest one.
+        second_chunk.events.push(cached_message(3));
+        let mut older_chunk = EventChunk::new(-1, None);
+        older_chunk.events.push(cached_message(4));
+
+        let mut changes = StateChanges::default();
+        changes.event_cache.insert(
+            room_id.to_owned(),
+            EventCacheChanges {
+                reset: false,
+                chunks: BTreeMap::from([(-1, older_chunk), (1, second_chunk)]),
+                removed_chunks: BTreeSet::new(),
+            },
+        );
+        self.save_changes(&changes).await?;
+
+        assert_eq!(self.get_event_chunk_range(room_id).await?, Some(-1..=1));
+        assert_eq!(self.get_event_chunk(room_id, 1).await?.unwrap().events.len(), 2);
+        assert_eq!(self.get_event_chunk_id(room_id, event_id!("$message_3")).await?, Some(1));
+        assert_eq!(self.get_event_chunk_id(room_id, event_id!("$message_4")).await?, Some(-1));
+
+        // Look up several events at once.
+        let event_ids: [OwnedEventId; 3] =
+            [event_id!("$message_3"), event_id!("$message_4"), event_id!("$unknown")]
+                .map(ToOwned::to_owned);
+        assert_eq!(
+            self.get_event_chunk_ids(room_id, &event_ids).await?,
+            BTreeMap::from([(event_ids[0].clone(), 1), (event_ids[1].clone(), -1)])
+        );
+
+        // Remove the oldest chunk.
+        let mut changes = StateChanges::default();
+        changes.event_cache.insert(
+            room_id.to_owned(),
+            EventCacheChanges {
+                reset: false,
+                chunks: BTreeMap::new(),
+                removed_chunks: BTreeSet::from([-1]),
+            },
+        );
+        self.save_changes(&changes).await?;
+
+        assert_eq!(self.get_event_chunk_range(room_id).await?, Some(0..=1));
+        assert!(self.get_event_chunk(room_id, -1).await?.is_none());
+        assert!(self.get_event_chunk_id(room_id, event_id!("$message_4")).await?.is_none());
+        assert_eq!(self.get_event_chunk_id(room_id, event_id!("$message_3")).await?, Some(1));
+
+        // Reset the cache with a single new chunk.
+        let mut new_chunk = EventChunk::new(0, Some("new_prev_batch".to_owned()));
+        new_chunk.events.push(cached_message(5));
+
+        let mut changes = StateChanges::default();
+        changes.event_cache.insert(
+            room_id.to_owned(),
+            EventCacheChanges {
+                reset: true,
+                chunks: BTreeMap::from([(0, new_chunk)]),
+                removed_chunks: BTreeSet::new(),
+            },
+        );
+        self.save_changes(&changes).await?;
+
+        assert_eq!(self.get_event_chunk_range(room_id).await?, Some(0..=0));
+        let chunk = self.get_event_chunk(room_id, 0).await?.expect("new chunk not found");
+        assert_eq!(chunk.prev_batch.as_deref(), Some("new_prev_batch"));
+        assert_eq!(chunk.events.len(), 1);
+        assert!(self.get_event_chunk(room_id, -1).await?.is_none());
+        assert!(self.get_event_chunk_id(room_id, event_id!("$message_1")).await?.is_none());
+        assert_eq!(self.get_event_chunk_id(room_id, event_id!("$message_5")).await?, Some(0));
+
+        self.remove_room(room_id).await?;
+
+        assert!(self.get_event_chunk_range(room_id).await?.is_none());
+        assert!(self.get_event_chunk(room_id, 0).await?.is_none());
+        assert!(self.get_event_chunk_id(room_id, event_id!("$message_5")).await?.is_none());
+
+        Ok(())
+    }
+
+    async fn test_clear(&self) -> Result<()> {
+        let room_id = room_id();
+        let key = b"my_key";
+
+        self.populate().await?;
+        self.set_custom_value(key, vec![0, 1, 2, 3]).await?;
+
+        self.clear().await?;
+
+        assert!(self.get_room_infos().await?.is_empty(), "rooms are still there");
+        assert!(self.get_stripped_room_infos().await?.is_empty(), "invites are still there");
+        assert_matches!(self.get_kv_data(StateStoreDataKey::SyncToken).await, Ok(None));
+        assert!(self
+            .get_account_data_event(GlobalAccountDataEventType::PushRules)
+            .await?
+            .is_none());
+        assert!(self
+            .get_room_account_data_event(room_id, RoomAccountDataEventType::Tag)
+            .await?
+            .is_none());
+        assert!(self.get_state_event(room_id, StateEventType::RoomName, "").await?.is_none());
+        assert!(self.get_custom_value(key).await?.is_none(), "custom value is still there");
+
+        // The store can still be used after being cleared.
+        self.populate().await?;
+        assert_eq!(self.get_room_infos().await?.len(), 1);
+
+        Ok(())
+    }
 }
 
 /// Macro building to allow your StateStore implementation to run the entire
@@ -899,6 +1114,18 @@ macro_rules! statestore_integration_tests {
                 let store = get_store().await.unwrap().into_state_store();
                 store.test_media_content().await;
             }
+
+            #[async_test]
+            async fn test_media_cache_cleaning() -> StoreResult<()> {
+                let store = get_store().await?.into_state_store();
+                store.test_media_cache_cleaning().await
+            }
+
+            #[async_test]
+            async fn test_media_cache_trimming() -> StoreResult<()> {
+                let store = get_store().await?.into_state_store();
+                store.test_media_cache_trimming().await
+            }
         }
     };
     () => {
@@ -984,6 +1211,18 @@ macro_rules! statestore_integration_tests {
             let store = get_store().await?.into_state_store();
             store.test_room_removal().await
         }
+
+        #[async_test]
+        async fn test_event_cache_saving() -> StoreResult<()> {
+            let store = get_store().await?.into_state_store();
+            store.test_event_cache_saving().await
+        }
+
+        #[async_test]
+        async fn test_clear() -> StoreResult<()> {
+            let store = get_store().await?.into_state_store();
+            store.test_clear().await
+        }
     };
 }
 
@@ -1003,6 +1242,20 @@ fn stripped_room_id() -> &'static RoomId {
     room_id!("!stripped:localhost")
 }
 
+fn cached_message(id: usize) -> SyncTimelineEvent {
+    SyncTimelineEvent::new(
+        Raw::new(&json!({
+            "content": { "body": format!("Message {id}"), "msgtype": "m.text" },
+            "event_id": format!("$message_{id}"),
+            "origin_server_ts": 152037280,
+            "sender": "@example:localhost",
+            "type": "m.room.message",
+        }))
+        .unwrap()
+        .cast(),
+    )
+}
+
 fn first_receipt_event_id() -> &'static EventId {
     event_id!("$example")
 }
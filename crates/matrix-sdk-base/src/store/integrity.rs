@@ -0,0 +1,155 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types shared by the integrity checks of the store implementations.
+//!
+//! The store implementations provide a `verify_and_repair` method that checks
+//! the schema version of the store, that all the values can be decrypted and
+//! deserialized, and that the data is consistent. The problems are reported in
+//! an [`IntegrityReport`], and can be repaired by dropping the broken data or
+//! rebuilding the data that is derived from the rest of the store, instead of
+//! having to log out.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{OwnedUserId, RoomId};
+
+use super::StateStore;
+use crate::RoomMemberships;
+
+/// A problem found by the integrity check of a store.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IntegrityIssue {
+    /// The schema version of the store is not the one expected by this
+    /// version of the SDK.
+    ///
+    /// This can't be repaired, and the other checks are not run.
+    SchemaVersion {
+        /// The version found in the store, if any.
+        found: Option<usize>,
+        /// The version expected by this version of the SDK.
+        expected: usize,
+    },
+
+    /// Values can't be decrypted with the current store cipher, or can't be
+    /// deserialized.
+    ///
+    /// They are removed during the repair. If room infos are removed, the sync
+    /// token is removed too, so the next sync restores them.
+    CorruptedValues {
+        /// The table, tree or object store containing the values.
+        table: String,
+        /// The number of broken values.
+        count: usize,
+    },
+
+    /// Data is stored for rooms that don't have a room info.
+    ///
+    /// It is removed during the repair.
+    OrphanedRoomData {
+        /// The table, tree or object store containing the data.
+        table: String,
+        /// The number of rooms without a room info.
+        rooms: usize,
+    },
+
+    /// The display name maps of rooms don't match their members.
+    ///
+    /// They are rebuilt from the members during the repair.
+    InconsistentDisplayNames {
+        /// The number of rooms with broken display name maps.
+        rooms: usize,
+    },
+
+    /// Olm sessions are stored without an account.
+    ///
+    /// They can't be used without the account, and are removed during the
+    /// repair.
+    SessionsWithoutAccount {
+        /// The number of Olm sessions.
+        count: usize,
+    },
+}
+
+impl IntegrityIssue {
+    /// Whether this issue can be repaired by `verify_and_repair`.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(self, Self::SchemaVersion { .. })
+    }
+}
+
+/// The result of the integrity check of a store.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// The problems that were found.
+    pub issues: Vec<IntegrityIssue>,
+
+    /// Whether the repairable problems were repaired.
+    pub repaired: bool,
+}
+
+impl IntegrityReport {
+    /// Whether no problem was found.
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Whether the store can be used after the check, i.e. no problem was
+    /// found or all of them were repaired.
+    pub fn is_usable(&self) -> bool {
+        self.issues.iter().all(|issue| self.repaired && issue.is_repairable())
+    }
+}
+
+/// Compute the display name map of the given room from the members in the
+/// given store.
+///
+/// The map contains the users that share each display name among the joined
+/// and invited members, like the ones that are saved in
+/// [`StateChanges::ambiguity_maps`]. It can be used by the store
+/// implementations to check and rebuild the display name maps they contain.
+///
+/// [`StateChanges::ambiguity_maps`]: super::StateChanges::ambiguity_maps
+pub async fn compute_display_names<S>(
+    store: &S,
+    room_id: &RoomId,
+) -> Result<BTreeMap<String, BTreeSet<OwnedUserId>>, S::Error>
+where
+    S: StateStore + ?Sized,
+{
+    let mut display_names: BTreeMap<String, BTreeSet<OwnedUserId>> = BTreeMap::new();
+
+    for user_id in store.get_user_ids(room_id, RoomMemberships::ACTIVE).await? {
+        let display_name = if let Some(d) = store
+            .get_profile(room_id, &user_id)
+            .await?
+            .and_then(|p| p.into_original())
+            .and_then(|p| p.content.displayname)
+        {
+            Some(d)
+        } else {
+            store
+                .get_member_event(room_id, &user_id)
+                .await?
+                .and_then(|e| e.deserialize().ok())
+                .and_then(|e| e.original_content().and_then(|c| c.displayname.clone()))
+        };
+
+        let display_name = display_name.unwrap_or_else(|| user_id.localpart().to_owned());
+        display_names.entry(display_name).or_default().insert(user_id);
+    }
+
+    Ok(display_names)
+}
@@ -13,7 +13,8 @@
 // limitations under the License.
 
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
+    ops::RangeInclusive,
     sync::{Arc, RwLock},
 };
 
@@ -30,20 +31,29 @@ use ruma::{
         AnySyncStateEvent, GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType,
     },
     serde::Raw,
-    CanonicalJsonObject, EventId, MxcUri, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
-    RoomVersionId, UserId,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId, OwnedRoomId,
+    OwnedUserId, RoomId, RoomVersionId, UserId,
 };
 use tracing::{debug, warn};
 
-use super::{Result, RoomInfo, StateChanges, StateStore, StoreError};
+use super::{
+    integrity::compute_display_names, IntegrityIssue, IntegrityReport, Result, RoomInfo,
+    StateChanges, StateStore, StoreError,
+};
 use crate::{
-    deserialized_responses::RawMemberEvent, media::MediaRequest, MinimalRoomMemberEvent,
-    RoomMemberships, StateStoreDataKey, StateStoreDataValue,
+    deserialized_responses::RawMemberEvent,
+    event_cache::EventChunk,
+    media::{select_for_trimming, MediaCacheStats, MediaRequest, MediaRetentionPolicy, UniqueKey},
+    MinimalRoomMemberEvent, RoomMemberships, StateStoreDataKey, StateStoreDataValue,
 };
 
 /// In-Memory, non-persistent implementation of the `StateStore`
 ///
 /// Default if no other is configured at startup.
+///
+/// The media cache of this store is kept in memory, so its total size is
+/// limited to [`MemoryStore::DEFAULT_MAX_MEDIA_CACHE_SIZE`] by default,
+/// whatever the [`MediaRetentionPolicy`] of the client is.
 #[allow(clippy::type_complexity)]
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
@@ -77,9 +87,48 @@ pub struct MemoryStore {
             DashMap<(String, Option<String>), DashMap<OwnedEventId, DashMap<OwnedUserId, Receipt>>>,
         >,
     >,
+    event_chunks: Arc<DashMap<OwnedRoomId, BTreeMap<i64, EventChunk>>>,
+    media: Arc<DashMap<String, MemoryMedia>>,
+    max_media_cache_size: usize,
     custom: Arc<DashMap<Vec<u8>, Vec<u8>>>,
 }
 
+/// Report the rooms of the given map that don't have a room info, and remove
+/// them if `repair` is true.
+fn check_orphaned_rooms<V>(
+    table: &str,
+    map: &DashMap<OwnedRoomId, V>,
+    room_ids: &BTreeSet<OwnedRoomId>,
+    repair: bool,
+    issues: &mut Vec<IntegrityIssue>,
+) {
+    let orphans: Vec<OwnedRoomId> = map
+        .iter()
+        .map(|entry| entry.key().clone())
+        .filter(|room_id| !room_ids.contains(room_id))
+        .collect();
+
+    if orphans.is_empty() {
+        return;
+    }
+
+    issues.push(IntegrityIssue::OrphanedRoomData { table: table.to_owned(), rooms: orphans.len() });
+
+    if repair {
+        for room_id in orphans {
+            map.remove(&room_id);
+        }
+    }
+}
+
+/// A media file's content in the `MemoryStore`.
+#[derive(Debug)]
+struct MemoryMedia {
+    uri: String,
+    data: Vec<u8>,
+    last_access: MilliSecondsSinceUnixEpoch,
+}
+
 impl Default for MemoryStore {
     fn default() -> Self {
         Self::new()
@@ -87,6 +136,9 @@ impl Default for MemoryStore {
 }
 
 impl MemoryStore {
+    /// The default maximum total size of the media cache, 50 MiB.
+    pub const DEFAULT_MAX_MEDIA_CACHE_SIZE: usize = 50 * 1024 * 1024;
+
     #[allow(dead_code)]
     /// Create a new empty MemoryStore
     pub fn new() -> Self {
@@ -107,14 +159,99 @@ impl MemoryStore {
             presence: Default::default(),
             room_user_receipts: Default::default(),
             room_event_receipts: Default::default(),
-            #[cfg(feature = "memory-media-cache")]
-            media: Arc::new(tokio::sync::Mutex::new(LruCache::new(
-                100.try_into().expect("100 is a non-zero usize"),
-            ))),
+            event_chunks: Default::default(),
+            media: Default::default(),
+            max_media_cache_size: Self::DEFAULT_MAX_MEDIA_CACHE_SIZE,
             custom: DashMap::new().into(),
         }
     }
 
+    /// Set the maximum total size of the media cache, in bytes.
+    ///
+    /// When the cache grows over this size, the least recently accessed media
+    /// content is removed first.
+    pub fn with_max_media_cache_size(mut self, size: usize) -> Self {
+        self.max_media_cache_size = size;
+        self
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// The data of this store is never serialized, so this only checks that
+    /// the members, profiles and state events belong to rooms with a room
+    /// info, and that the display name maps match the members. See
+    /// [`IntegrityIssue`] for what the repair does for each problem.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        let mut issues = Vec::new();
+        let room_ids: BTreeSet<OwnedRoomId> = self
+            .room_info
+            .iter()
+            .chain(self.stripped_room_infos.iter())
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        check_orphaned_rooms("profiles", &self.profiles, &room_ids, repair, &mut issues);
+        check_orphaned_rooms("display_names", &self.display_names, &room_ids, repair, &mut issues);
+        check_orphaned_rooms("members", &self.members, &room_ids, repair, &mut issues);
+        check_orphaned_rooms("room_state", &self.room_state, &room_ids, repair, &mut issues);
+        check_orphaned_rooms(
+            "stripped_room_state",
+            &self.stripped_room_state,
+            &room_ids,
+            repair,
+            &mut issues,
+        );
+        check_orphaned_rooms(
+            "stripped_members",
+            &self.stripped_members,
+            &room_ids,
+            repair,
+            &mut issues,
+        );
+
+        // The display names can only be computed reliably once the orphaned
+        // data is gone.
+        if repair || issues.is_empty() {
+            let joined_room_ids: Vec<_> =
+                self.room_info.iter().map(|entry| entry.key().clone()).collect();
+            let mut inconsistent_rooms = 0;
+
+            for room_id in joined_room_ids {
+                let expected = compute_display_names(self, &room_id).await?;
+
+                // Empty sets are kept when the last user with a display name
+                // changes it.
+                let stored: BTreeMap<String, BTreeSet<OwnedUserId>> = self
+                    .display_names
+                    .get(&room_id)
+                    .map(|names| {
+                        names
+                            .iter()
+                            .filter(|entry| !entry.value().is_empty())
+                            .map(|entry| (entry.key().clone(), entry.value().clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                if stored == expected {
+                    continue;
+                }
+
+                inconsistent_rooms += 1;
+
+                if repair {
+                    self.display_names.insert(room_id, expected.into_iter().collect());
+                }
+            }
+
+            if inconsistent_rooms > 0 {
+                issues.push(IntegrityIssue::InconsistentDisplayNames { rooms: inconsistent_rooms });
+            }
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
     async fn get_kv_data(&self, key: StateStoreDataKey<'_>) -> Result<Option<StateStoreDataValue>> {
         match key {
             StateStoreDataKey::SyncToken => {
@@ -362,6 +499,20 @@ impl MemoryStore {
             }
         }
 
+        for (room_id, cache_changes) in &changes.event_cache {
+            let mut chunks = self.event_chunks.entry(room_id.clone()).or_default();
+
+            if cache_changes.reset {
+                chunks.clear();
+            }
+
+            for chunk_id in &cache_changes.removed_chunks {
+                chunks.remove(chunk_id);
+            }
+
+            chunks.extend(cache_changes.chunks.iter().map(|(id, chunk)| (*id, chunk.clone())));
+        }
+
         debug!("Saved changes in {:?}", now.elapsed());
 
         Ok(())
@@ -523,20 +674,113 @@ impl MemoryStore {
         Ok(self.custom.remove(key).map(|entry| entry.1))
     }
 
-    // The in-memory store doesn't cache media
-    async fn add_media_content(&self, _request: &MediaRequest, _data: Vec<u8>) -> Result<()> {
+    async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
+        self.media.insert(
+            request.unique_key(),
+            MemoryMedia {
+                uri: request.source.unique_key(),
+                data,
+                last_access: MilliSecondsSinceUnixEpoch::now(),
+            },
+        );
+        self.trim_media_cache(self.max_media_cache_size).await?;
         Ok(())
     }
-    async fn get_media_content(&self, _request: &MediaRequest) -> Result<Option<Vec<u8>>> {
-        Ok(None)
+
+    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        Ok(self.media.get_mut(&request.unique_key()).map(|mut media| {
+            media.last_access = MilliSecondsSinceUnixEpoch::now();
+            media.data.clone()
+        }))
     }
-    async fn remove_media_content(&self, _request: &MediaRequest) -> Result<()> {
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        self.media.remove(&request.unique_key());
         Ok(())
     }
-    async fn remove_media_content_for_uri(&self, _uri: &MxcUri) -> Result<()> {
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        self.media.retain(|_, media| media.uri != uri.as_str());
         Ok(())
     }
 
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<()> {
+        let entries: Vec<_> = self
+            .media
+            .iter()
+            .map(|media| (media.key().clone(), media.data.len(), media.last_access))
+            .collect();
+
+        for key in policy.select_for_removal(entries, MilliSecondsSinceUnixEpoch::now()) {
+            self.media.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize> {
+        let entries: Vec<_> = self
+            .media
+            .iter()
+            .map(|media| (media.key().clone(), media.data.len(), media.last_access))
+            .collect();
+
+        let (to_remove, total_size) =
+            select_for_trimming(entries, max_size.min(self.max_media_cache_size));
+        for key in to_remove {
+            self.media.remove(&key);
+        }
+
+        Ok(total_size)
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        Ok(MediaCacheStats {
+            count: self.media.len(),
+            total_size: self.media.iter().map(|media| media.data.len()).sum(),
+        })
+    }
+
+    async fn get_event_chunk_range(&self, room_id: &RoomId) -> Result<Option<RangeInclusive<i64>>> {
+        Ok(self.event_chunks.get(room_id).and_then(|chunks| {
+            let first = *chunks.keys().next()?;
+            let last = *chunks.keys().next_back()?;
+            Some(first..=last)
+        }))
+    }
+
+    async fn get_event_chunk(&self, room_id: &RoomId, chunk_id: i64) -> Result<Option<EventChunk>> {
+        Ok(self.event_chunks.get(room_id).and_then(|chunks| chunks.get(&chunk_id).cloned()))
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<i64>> {
+        Ok(self.event_chunks.get(room_id).and_then(|chunks| {
+            chunks
+                .values()
+                .find(|chunk| chunk.event_ids().any(|id| id == *event_id))
+                .map(|chunk| chunk.id)
+        }))
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>> {
+        let Some(chunks) = self.event_chunks.get(room_id) else { return Ok(BTreeMap::new()) };
+        let event_ids: BTreeSet<_> = event_ids.iter().collect();
+
+        Ok(chunks
+            .values()
+            .flat_map(|chunk| chunk.event_ids().map(|event_id| (event_id, chunk.id)))
+            .filter(|(event_id, _)| event_ids.contains(event_id))
+            .collect())
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         self.profiles.remove(room_id);
         self.display_names.remove(room_id);
@@ -549,6 +793,31 @@ impl MemoryStore {
         self.stripped_members.remove(room_id);
         self.room_user_receipts.remove(room_id);
         self.room_event_receipts.remove(room_id);
+        self.event_chunks.remove(room_id);
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.user_avatar_url.clear();
+        *self.sync_token.write().unwrap() = None;
+        self.filters.clear();
+        self.account_data.clear();
+        self.profiles.clear();
+        self.display_names.clear();
+        self.members.clear();
+        self.room_info.clear();
+        self.room_state.clear();
+        self.room_account_data.clear();
+        self.stripped_room_infos.clear();
+        self.stripped_room_state.clear();
+        self.stripped_members.clear();
+        self.presence.clear();
+        self.room_user_receipts.clear();
+        self.room_event_receipts.clear();
+        self.event_chunks.clear();
+        self.media.clear();
+        self.custom.clear();
 
         Ok(())
     }
@@ -719,18 +988,147 @@ impl StateStore for MemoryStore {
         self.remove_media_content_for_uri(uri).await
     }
 
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<()> {
+        self.clean_media_cache(policy).await
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize> {
+        self.trim_media_cache(max_size).await
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        self.media_cache_stats().await
+    }
+
+    async fn get_event_chunk_range(&self, room_id: &RoomId) -> Result<Option<RangeInclusive<i64>>> {
+        self.get_event_chunk_range(room_id).await
+    }
+
+    async fn get_event_chunk(&self, room_id: &RoomId, chunk_id: i64) -> Result<Option<EventChunk>> {
+        self.get_event_chunk(room_id, chunk_id).await
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<i64>> {
+        self.get_event_chunk_id(room_id, event_id).await
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>> {
+        self.get_event_chunk_ids(room_id, event_ids).await
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         self.remove_room(room_id).await
     }
+
+    async fn clear(&self) -> Result<()> {
+        self.clear().await
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeSet;
+
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        events::room::{member::MembershipState, MediaSource},
+        room_id, user_id,
+    };
+
     use super::{MemoryStore, Result, StateStore};
+    use crate::{
+        media::{MediaFormat, MediaRequest},
+        store::{IntegrityIssue, StateChanges},
+        RoomInfo, RoomState,
+    };
 
     async fn get_store() -> Result<impl StateStore> {
         Ok(MemoryStore::new())
     }
 
-    statestore_integration_tests!();
+    statestore_integration_tests!(with_media_tests);
+
+    #[async_test]
+    async fn verify_and_repair() {
+        let room_id = room_id!("!room:localhost");
+        let unknown_room_id = room_id!("!unknown:localhost");
+        let user_id = user_id!("@alice:localhost");
+
+        let store = MemoryStore::new();
+        let mut changes = StateChanges::default();
+        changes.add_room(RoomInfo::new(room_id, RoomState::Joined));
+        store.save_changes(&changes).await.unwrap();
+
+        // An empty set is kept when the last user with a display name changes
+        // it, it is not an issue.
+        store
+            .display_names
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert("bob".to_owned(), BTreeSet::new());
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+
+        // A member of a room without a room info, and a display name without a
+        // member.
+        store
+            .members
+            .entry(unknown_room_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned(), MembershipState::Join);
+        store
+            .display_names
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert("alice".to_owned(), BTreeSet::from([user_id.to_owned()]));
+
+        let report = store.verify_and_repair(false).await.unwrap();
+        assert!(!report.is_usable());
+        assert_eq!(
+            report.issues,
+            [IntegrityIssue::OrphanedRoomData { table: "members".to_owned(), rooms: 1 }]
+        );
+
+        let report = store.verify_and_repair(true).await.unwrap();
+        assert!(report.is_usable());
+        assert_eq!(
+            report.issues,
+            [
+                IntegrityIssue::OrphanedRoomData { table: "members".to_owned(), rooms: 1 },
+                IntegrityIssue::InconsistentDisplayNames { rooms: 1 },
+            ]
+        );
+
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+        assert!(store.get_users_with_display_name(room_id, "alice").await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn media_cache_max_size() {
+        let request = |id: &str| MediaRequest {
+            source: MediaSource::Plain(format!("mxc://localhost/{id}").into()),
+            format: MediaFormat::File,
+        };
+
+        let store = MemoryStore::new().with_max_media_cache_size(25);
+
+        store.add_media_content(&request("small"), vec![0; 10]).await.unwrap();
+        store.add_media_content(&request("medium"), vec![0; 10]).await.unwrap();
+        assert_eq!(store.media_cache_stats().await.unwrap().count, 2);
+
+        // The cache never grows over the maximum size of the store.
+        store.add_media_content(&request("big"), vec![0; 20]).await.unwrap();
+        let stats = store.media_cache_stats().await.unwrap();
+        assert!(stats.total_size <= 25);
+
+        // The maximum size of the store is used if it is smaller.
+        assert_eq!(store.trim_media_cache(100).await.unwrap(), stats.total_size);
+    }
 }
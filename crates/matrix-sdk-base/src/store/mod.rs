@@ -60,16 +60,22 @@ use tokio::sync::RwLock;
 pub type BoxStream<T> = Pin<Box<dyn futures_util::Stream<Item = T> + Send>>;
 
 use crate::{
+    event_cache::EventCacheChanges,
     rooms::{RoomInfo, RoomState},
     MinimalRoomMemberEvent, Room, Session, SessionMeta, SessionTokens,
 };
 
 pub(crate) mod ambiguity_map;
+mod archive;
+pub mod encode_key;
+pub mod integrity;
 mod memory_store;
 
 #[cfg(any(test, feature = "testing"))]
 pub use self::integration_tests::StateStoreIntegrationTests;
 pub use self::{
+    archive::{StoreArchive, StoreArchiveError},
+    integrity::{IntegrityIssue, IntegrityReport},
     memory_store::MemoryStore,
     traits::{
         DynStateStore, IntoStateStore, StateStore, StateStoreDataKey, StateStoreDataValue,
@@ -356,6 +362,9 @@ pub struct StateChanges {
     pub ambiguity_maps: BTreeMap<OwnedRoomId, BTreeMap<String, BTreeSet<OwnedUserId>>>,
     /// A map of `RoomId` to a vector of `Notification`s
     pub notifications: BTreeMap<OwnedRoomId, Vec<Notification>>,
+
+    /// A map of `RoomId` to the changes to the event cache of the room.
+    pub event_cache: BTreeMap<OwnedRoomId, EventCacheChanges>,
 }
 
 impl StateChanges {
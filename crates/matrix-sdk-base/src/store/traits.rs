@@ -12,7 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{borrow::Borrow, collections::BTreeSet, fmt, sync::Arc};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::RangeInclusive,
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use matrix_sdk_common::AsyncTraitDeps;
@@ -32,8 +38,10 @@ use ruma::{
 
 use super::{StateChanges, StoreError};
 use crate::{
-    deserialized_responses::RawMemberEvent, media::MediaRequest, MinimalRoomMemberEvent, RoomInfo,
-    RoomMemberships,
+    deserialized_responses::RawMemberEvent,
+    event_cache::EventChunk,
+    media::{MediaCacheStats, MediaRequest, MediaRetentionPolicy},
+    MinimalRoomMemberEvent, RoomInfo, RoomMemberships,
 };
 
 /// An abstract state store trait that can be used to implement different stores
@@ -313,12 +321,104 @@ pub trait StateStore: AsyncTraitDeps {
     /// * `uri` - The `MxcUri` of the media files.
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<(), Self::Error>;
 
+    /// Removes the media files' content that doesn't respect the given policy
+    /// from the media store.
+    ///
+    /// The stores keep track of the last time a media file's content was added
+    /// or accessed with [`StateStore::get_media_content`]. The last access
+    /// time is only updated if it is older than
+    /// [`MEDIA_LAST_ACCESS_UPDATE_INTERVAL`].
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The `MediaRetentionPolicy` to enforce.
+    ///
+    /// [`MEDIA_LAST_ACCESS_UPDATE_INTERVAL`]: crate::media::MEDIA_LAST_ACCESS_UPDATE_INTERVAL
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<(), Self::Error>;
+
+    /// Removes the least recently accessed media files' content from the media
+    /// store until their total size is at most `max_size`.
+    ///
+    /// Returns the total size of the media files' content left in the media
+    /// store.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_size` - The maximum total size of the media files' content, in
+    ///   bytes.
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize, Self::Error>;
+
+    /// Get statistics about the media files' content in the media store.
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats, Self::Error>;
+
+    /// Get the range of IDs of the chunks in the event cache of a room.
+    ///
+    /// Returns `None` if there are no cached events for this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    async fn get_event_chunk_range(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<RangeInclusive<i64>>, Self::Error>;
+
+    /// Get a chunk of the event cache of a room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    ///
+    /// * `chunk_id` - The id of the chunk.
+    async fn get_event_chunk(
+        &self,
+        room_id: &RoomId,
+        chunk_id: i64,
+    ) -> Result<Option<EventChunk>, Self::Error>;
+
+    /// Get the ID of the chunk of the event cache of a room that contains the
+    /// given event.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    ///
+    /// * `event_id` - The id of the event.
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<i64>, Self::Error>;
+
+    /// Get the IDs of the chunks of the event cache of a room that contain the
+    /// given events.
+    ///
+    /// The events that are not in the event cache are not in the returned map.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    ///
+    /// * `event_ids` - The ids of the events.
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>, Self::Error>;
+
     /// Removes a room and all elements associated from the state store.
     ///
     /// # Arguments
     ///
     /// * `room_id` - The `RoomId` of the room to delete.
     async fn remove_room(&self, room_id: &RoomId) -> Result<(), Self::Error>;
+
+    /// Remove all the data from the state store.
+    ///
+    /// This removes the rooms, the account data, the sync token, the media
+    /// cache, the custom values and the sliding sync caches. Only the data
+    /// needed to open the store again, like the store cipher, is kept.
+    async fn clear(&self) -> Result<(), Self::Error>;
 }
 
 #[repr(transparent)]
@@ -513,9 +613,56 @@ impl<T: StateStore> StateStore for EraseStateStoreError<T> {
         self.0.remove_media_content_for_uri(uri).await.map_err(Into::into)
     }
 
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<(), Self::Error> {
+        self.0.clean_media_cache(policy).await.map_err(Into::into)
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize, Self::Error> {
+        self.0.trim_media_cache(max_size).await.map_err(Into::into)
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats, Self::Error> {
+        self.0.media_cache_stats().await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk_range(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<RangeInclusive<i64>>, Self::Error> {
+        self.0.get_event_chunk_range(room_id).await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk(
+        &self,
+        room_id: &RoomId,
+        chunk_id: i64,
+    ) -> Result<Option<EventChunk>, Self::Error> {
+        self.0.get_event_chunk(room_id, chunk_id).await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<i64>, Self::Error> {
+        self.0.get_event_chunk_id(room_id, event_id).await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>, Self::Error> {
+        self.0.get_event_chunk_ids(room_id, event_ids).await.map_err(Into::into)
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<(), Self::Error> {
         self.0.remove_room(room_id).await.map_err(Into::into)
     }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.0.clear().await.map_err(Into::into)
+    }
 }
 
 /// Convenience functionality for state stores.
@@ -45,6 +45,15 @@ pub struct JoinHandle<T> {
     handle: RemoteHandle<Result<T, ()>>,
 }
 
+#[cfg(target_arch = "wasm32")]
+impl<T> JoinHandle<T> {
+    /// Drop this handle without cancelling the task, which keeps running in
+    /// the background.
+    pub fn forget(self) {
+        self.handle.forget()
+    }
+}
+
 #[cfg(target_arch = "wasm32")]
 impl<T: 'static> Future for JoinHandle<T> {
     type Output = Result<T, ()>;
@@ -42,16 +42,14 @@ type Aes256Ctr = ctr::Ctr128BE<Aes256>;
 /// Matrix attachment.
 pub struct AttachmentDecryptor<'a, R: Read> {
     inner: &'a mut R,
-    expected_hash: Vec<u8>,
-    sha: Sha256,
-    aes: Aes256Ctr,
+    decryptor: AttachmentChunkDecryptor,
 }
 
 impl<'a, R: 'a + Read + std::fmt::Debug> std::fmt::Debug for AttachmentDecryptor<'a, R> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AttachmentDecryptor")
             .field("inner", &self.inner)
-            .field("expected_hash", &self.expected_hash)
+            .field("expected_hash", &self.decryptor.expected_hash)
             .finish()
     }
 }
@@ -61,22 +59,124 @@ impl<'a, R: Read> Read for AttachmentDecryptor<'a, R> {
         let read_bytes = self.inner.read(buf)?;
 
         if read_bytes == 0 {
-            let hash = self.sha.finalize_reset();
-
-            if hash.as_slice() == self.expected_hash.as_slice() {
-                Ok(0)
-            } else {
-                Err(IoError::new(ErrorKind::Other, "Hash mismatch while decrypting"))
-            }
+            self.decryptor
+                .verify_hash()
+                .map(|_| 0)
+                .map_err(|_| IoError::new(ErrorKind::Other, "Hash mismatch while decrypting"))
         } else {
-            self.sha.update(&buf[0..read_bytes]);
-            self.aes.apply_keystream(&mut buf[0..read_bytes]);
+            self.decryptor.decrypt_chunk(&mut buf[0..read_bytes]);
 
             Ok(read_bytes)
         }
     }
 }
 
+/// A decryptor for Matrix attachments that receives the encrypted data in
+/// chunks, instead of reading it from a `Read` implementation.
+///
+/// This is useful when the encrypted data comes from an asynchronous source,
+/// like a stream of bytes received over the network.
+pub struct AttachmentChunkDecryptor {
+    expected_hash: Vec<u8>,
+    sha: Sha256,
+    aes: Aes256Ctr,
+}
+
+impl std::fmt::Debug for AttachmentChunkDecryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentChunkDecryptor")
+            .field("expected_hash", &self.expected_hash)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AttachmentChunkDecryptor {
+    /// Create a new decryptor for the attachment with the given encryption
+    /// info.
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The encryption info that is necessary to decrypt the
+    /// attachment.
+    ///
+    /// # Examples
+    /// ```
+    /// # use std::io::{Cursor, Read};
+    /// # use matrix_sdk_crypto::{AttachmentEncryptor, AttachmentChunkDecryptor};
+    /// let data = "Hello world".to_owned();
+    /// let mut cursor = Cursor::new(data.clone());
+    ///
+    /// let mut encryptor = AttachmentEncryptor::new(&mut cursor);
+    ///
+    /// let mut encrypted = Vec::new();
+    /// encryptor.read_to_end(&mut encrypted).unwrap();
+    /// let info = encryptor.finish();
+    ///
+    /// let mut decryptor = AttachmentChunkDecryptor::new(info).unwrap();
+    ///
+    /// for chunk in encrypted.chunks_mut(4) {
+    ///     decryptor.decrypt_chunk(chunk);
+    /// }
+    ///
+    /// decryptor.finish().unwrap();
+    ///
+    /// let decrypted = String::from_utf8(encrypted).unwrap();
+    /// assert_eq!(data, decrypted);
+    /// ```
+    pub fn new(info: MediaEncryptionInfo) -> Result<Self, DecryptorError> {
+        if info.version != VERSION {
+            return Err(DecryptorError::UnknownVersion);
+        }
+
+        let hash =
+            info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?.as_bytes().to_owned();
+        let mut key = info.key.k.into_inner();
+        let iv = info.iv.into_inner();
+
+        if key.len() != KEY_SIZE {
+            return Err(DecryptorError::KeyNonceLength);
+        }
+
+        let key_array = GenericArray::from_slice(&key);
+        let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
+
+        let sha = Sha256::default();
+
+        let aes = Aes256Ctr::new(key_array, &iv);
+        key.zeroize();
+
+        Ok(Self { expected_hash: hash, sha, aes })
+    }
+
+    /// Decrypt the given chunk of encrypted data in place.
+    ///
+    /// The chunks must be passed in the order in which they appear in the
+    /// encrypted attachment.
+    pub fn decrypt_chunk(&mut self, chunk: &mut [u8]) {
+        self.sha.update(&*chunk);
+        self.aes.apply_keystream(chunk);
+    }
+
+    /// Check that the hash of all the encrypted data that was decrypted
+    /// matches the hash of the encryption info.
+    ///
+    /// This must be called once all the chunks were decrypted, the decrypted
+    /// data must be discarded if this returns an error.
+    pub fn finish(mut self) -> Result<(), DecryptorError> {
+        self.verify_hash()
+    }
+
+    fn verify_hash(&mut self) -> Result<(), DecryptorError> {
+        let hash = self.sha.finalize_reset();
+
+        if hash.as_slice() == self.expected_hash.as_slice() {
+            Ok(())
+        } else {
+            Err(DecryptorError::HashMismatch)
+        }
+    }
+}
+
 /// Error type for attachment decryption.
 #[derive(Error, Debug)]
 pub enum DecryptorError {
@@ -94,6 +194,10 @@ pub enum DecryptorError {
     /// attachment encryption spec.
     #[error("Unknown version for the encrypted attachment.")]
     UnknownVersion,
+    /// The hash of the encrypted data doesn't match the hash of the
+    /// encryption info.
+    #[error("Hash mismatch while decrypting")]
+    HashMismatch,
 }
 
 impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
@@ -130,28 +234,9 @@ impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
         input: &'a mut R,
         info: MediaEncryptionInfo,
     ) -> Result<AttachmentDecryptor<'a, R>, DecryptorError> {
-        if info.version != VERSION {
-            return Err(DecryptorError::UnknownVersion);
-        }
-
-        let hash =
-            info.hashes.get("sha256").ok_or(DecryptorError::MissingHash)?.as_bytes().to_owned();
-        let mut key = info.key.k.into_inner();
-        let iv = info.iv.into_inner();
+        let decryptor = AttachmentChunkDecryptor::new(info)?;
 
-        if key.len() != KEY_SIZE {
-            return Err(DecryptorError::KeyNonceLength);
-        }
-
-        let key_array = GenericArray::from_slice(&key);
-        let iv = GenericArray::from_exact_iter(iv).ok_or(DecryptorError::KeyNonceLength)?;
-
-        let sha = Sha256::default();
-
-        let aes = Aes256Ctr::new(key_array, &iv);
-        key.zeroize();
-
-        Ok(AttachmentDecryptor { inner: input, expected_hash: hash, sha, aes })
+        Ok(AttachmentDecryptor { inner: input, decryptor })
     }
 }
 
@@ -159,11 +244,7 @@ impl<'a, R: Read + 'a> AttachmentDecryptor<'a, R> {
 pub struct AttachmentEncryptor<'a, R: Read + ?Sized> {
     finished: bool,
     inner: &'a mut R,
-    web_key: JsonWebKey,
-    iv: Base64,
-    hashes: BTreeMap<String, Base64>,
-    aes: Aes256Ctr,
-    sha: Sha256,
+    encryptor: AttachmentChunkEncryptor,
 }
 
 impl<'a, R: 'a + Read + std::fmt::Debug + ?Sized> std::fmt::Debug for AttachmentEncryptor<'a, R> {
@@ -180,17 +261,12 @@ impl<'a, R: Read + ?Sized + 'a> Read for AttachmentEncryptor<'a, R> {
         let read_bytes = self.inner.read(buf)?;
 
         if read_bytes == 0 {
-            let hash = self.sha.finalize_reset();
-            self.hashes
-                .entry("sha256".to_owned())
-                .or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
-            Ok(0)
+            self.finished = true;
         } else {
-            self.aes.apply_keystream(&mut buf[0..read_bytes]);
-            self.sha.update(&buf[0..read_bytes]);
-
-            Ok(read_bytes)
+            self.encryptor.encrypt_chunk(&mut buf[0..read_bytes]);
         }
+
+        Ok(read_bytes)
     }
 }
 
@@ -224,6 +300,70 @@ impl<'a, R: Read + ?Sized + 'a> AttachmentEncryptor<'a, R> {
     /// let key = encryptor.finish();
     /// ```
     pub fn new(reader: &'a mut R) -> Self {
+        AttachmentEncryptor { finished: false, inner: reader, encryptor: Default::default() }
+    }
+
+    /// Consume the encryptor and get the encryption key.
+    pub fn finish(self) -> MediaEncryptionInfo {
+        self.encryptor.finish()
+    }
+}
+
+/// An encryptor for Matrix attachments that receives the plain data in
+/// chunks, instead of reading it from a `Read` implementation.
+///
+/// This is useful when the data comes from an asynchronous source, like a file
+/// that is read in chunks to be streamed to the server.
+pub struct AttachmentChunkEncryptor {
+    web_key: JsonWebKey,
+    iv: Base64,
+    aes: Aes256Ctr,
+    sha: Sha256,
+}
+
+impl std::fmt::Debug for AttachmentChunkEncryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AttachmentChunkEncryptor").finish_non_exhaustive()
+    }
+}
+
+impl Default for AttachmentChunkEncryptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AttachmentChunkEncryptor {
+    /// Create a new encryptor with a fresh encryption key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if we can't generate enough random data to create a fresh
+    /// encryption key.
+    ///
+    /// # Examples
+    /// ```
+    /// # use std::io::{Cursor, Read};
+    /// # use matrix_sdk_crypto::{AttachmentChunkEncryptor, AttachmentDecryptor};
+    /// let data = "Hello world".to_owned();
+    ///
+    /// let mut encryptor = AttachmentChunkEncryptor::new();
+    ///
+    /// let mut encrypted = data.clone().into_bytes();
+    /// for chunk in encrypted.chunks_mut(4) {
+    ///     encryptor.encrypt_chunk(chunk);
+    /// }
+    /// let info = encryptor.finish();
+    ///
+    /// let mut cursor = Cursor::new(encrypted);
+    /// let mut decryptor = AttachmentDecryptor::new(&mut cursor, info).unwrap();
+    /// let mut decrypted_data = Vec::new();
+    /// decryptor.read_to_end(&mut decrypted_data).unwrap();
+    ///
+    /// let decrypted = String::from_utf8(decrypted_data).unwrap();
+    /// assert_eq!(data, decrypted);
+    /// ```
+    pub fn new() -> Self {
         let mut key = [0u8; KEY_SIZE];
         let mut iv = [0u8; IV_SIZE];
 
@@ -250,30 +390,27 @@ impl<'a, R: Read + ?Sized + 'a> AttachmentEncryptor<'a, R> {
         let aes = Aes256Ctr::new(key_array, &iv.into());
         key.zeroize();
 
-        AttachmentEncryptor {
-            finished: false,
-            inner: reader,
-            iv: encoded_iv,
-            web_key,
-            hashes: BTreeMap::new(),
-            aes,
-            sha: Sha256::default(),
-        }
+        Self { web_key, iv: encoded_iv, aes, sha: Sha256::default() }
+    }
+
+    /// Encrypt the given chunk of data in place.
+    ///
+    /// The chunks must be passed in the order in which they appear in the
+    /// attachment.
+    pub fn encrypt_chunk(&mut self, chunk: &mut [u8]) {
+        self.aes.apply_keystream(chunk);
+        self.sha.update(&*chunk);
     }
 
     /// Consume the encryptor and get the encryption key.
-    pub fn finish(mut self) -> MediaEncryptionInfo {
+    ///
+    /// This must be called once all the chunks were encrypted.
+    pub fn finish(self) -> MediaEncryptionInfo {
         let hash = self.sha.finalize();
-        self.hashes
-            .entry("sha256".to_owned())
-            .or_insert_with(|| Base64::new(hash.as_slice().to_owned()));
-
-        MediaEncryptionInfo {
-            version: VERSION.to_owned(),
-            hashes: self.hashes,
-            iv: self.iv,
-            key: self.web_key,
-        }
+        let hashes =
+            BTreeMap::from([("sha256".to_owned(), Base64::new(hash.as_slice().to_owned()))]);
+
+        MediaEncryptionInfo { version: VERSION.to_owned(), hashes, iv: self.iv, key: self.web_key }
     }
 }
 
@@ -304,7 +441,12 @@ mod tests {
 
     use serde_json::json;
 
-    use super::{AttachmentDecryptor, AttachmentEncryptor, MediaEncryptionInfo};
+    use assert_matches::assert_matches;
+
+    use super::{
+        AttachmentChunkDecryptor, AttachmentDecryptor, AttachmentEncryptor, DecryptorError,
+        MediaEncryptionInfo,
+    };
 
     const EXAMPLE_DATA: &[u8] = &[
         179, 154, 118, 127, 186, 127, 110, 33, 203, 33, 33, 134, 67, 100, 173, 46, 235, 27, 215,
@@ -378,4 +520,27 @@ mod tests {
 
         decryptor.read_to_end(&mut decrypted_data).unwrap_err();
     }
+
+    #[test]
+    fn chunk_decrypt() {
+        let mut data = EXAMPLE_DATA.to_vec();
+        let mut decryptor = AttachmentChunkDecryptor::new(example_key()).unwrap();
+
+        for chunk in data.chunks_mut(5) {
+            decryptor.decrypt_chunk(chunk);
+        }
+
+        decryptor.finish().unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), "It's a secret to everybody");
+    }
+
+    #[test]
+    fn chunk_decrypt_invalid_hash() {
+        let mut data = b"fake message".to_vec();
+        let mut decryptor = AttachmentChunkDecryptor::new(example_key()).unwrap();
+
+        decryptor.decrypt_chunk(&mut data);
+
+        assert_matches!(decryptor.finish(), Err(DecryptorError::HashMismatch));
+    }
 }
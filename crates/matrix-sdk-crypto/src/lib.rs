@@ -70,8 +70,9 @@ impl RoomKeyImportResult {
 
 pub use error::{EventError, MegolmError, OlmError, SessionCreationError, SignatureError};
 pub use file_encryption::{
-    decrypt_room_key_export, encrypt_room_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, KeyExportError, MediaEncryptionInfo,
+    decrypt_room_key_export, encrypt_room_key_export, AttachmentChunkDecryptor,
+    AttachmentChunkEncryptor, AttachmentDecryptor, AttachmentEncryptor, DecryptorError,
+    KeyExportError, MediaEncryptionInfo,
 };
 pub use gossiping::GossipRequest;
 pub use identities::{
@@ -90,6 +90,9 @@ use crate::{
     SignatureError, ToDeviceRequest,
 };
 
+/// The key of the generation of the crypto store in its custom values.
+const CRYPTO_STORE_GENERATION_KEY: &str = "crypto_store_generation";
+
 /// State machine implementation of the Olm/Megolm encryption protocol used for
 /// Matrix end to end encryption.
 #[derive(Clone)]
@@ -129,6 +132,9 @@ pub struct OlmMachineInner {
     /// A state machine that handles creating room key backups.
     #[cfg(feature = "backups_v1")]
     backup_machine: BackupMachine,
+    /// The generation of the crypto store that the caches of this machine
+    /// were loaded from, if it is known.
+    crypto_store_generation: Mutex<Option<u64>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -212,6 +218,7 @@ impl OlmMachine {
             identity_manager,
             #[cfg(feature = "backups_v1")]
             backup_machine,
+            crypto_store_generation: Mutex::new(None),
         });
 
         Self { inner }
@@ -309,6 +316,66 @@ impl OlmMachine {
         &self.inner.store
     }
 
+    /// Check whether another process modified the crypto store, and reload
+    /// the data that is cached in memory if it did.
+    ///
+    /// When several processes use the same crypto store, for example an app
+    /// and its notification extension, they must take a
+    /// [`CryptoStoreLock`] before using their `OlmMachine`, and call this
+    /// method once they hold the lock. The generation of the store is
+    /// incremented each time, so the other processes know that their caches
+    /// are outdated.
+    ///
+    /// Returns `true` if the caches were reloaded.
+    ///
+    /// [`CryptoStoreLock`]: crate::store::locks::CryptoStoreLock
+    pub async fn maintain_crypto_store_generation(&self) -> StoreResult<bool> {
+        let mut generation = self.inner.crypto_store_generation.lock().await;
+
+        let stored_generation = self
+            .inner
+            .store
+            .get_custom_value(CRYPTO_STORE_GENERATION_KEY)
+            .await?
+            .and_then(|bytes| Some(u64::from_le_bytes(bytes.try_into().ok()?)));
+
+        let outdated = generation.is_none() || *generation != stored_generation;
+
+        if outdated {
+            debug!(
+                generation = ?*generation,
+                ?stored_generation,
+                "The crypto store was modified by another process, reloading the caches"
+            );
+            self.reload_caches().await?;
+        }
+
+        let new_generation = stored_generation.map_or(0, |generation| generation.wrapping_add(1));
+        self.inner
+            .store
+            .set_custom_value(CRYPTO_STORE_GENERATION_KEY, new_generation.to_le_bytes().to_vec())
+            .await?;
+        *generation = Some(new_generation);
+
+        Ok(outdated)
+    }
+
+    /// Reload the data that is cached in memory from the store.
+    async fn reload_caches(&self) -> StoreResult<()> {
+        self.inner.store.clear_caches().await;
+        self.inner.group_session_manager.session_cache().clear();
+
+        if let Some(account) = self.inner.store.load_account().await? {
+            self.inner.account.replace_with(account).await;
+        }
+
+        if let Some(identity) = self.inner.store.load_identity().await? {
+            *self.inner.user_identity.lock().await = identity;
+        }
+
+        Ok(())
+    }
+
     /// The unique user id that owns this `OlmMachine` instance.
     pub fn user_id(&self) -> &UserId {
         &self.inner.user_id
@@ -797,6 +864,18 @@ impl OlmMachine {
         self.inner.group_session_manager.invalidate_group_session(room_id).await
     }
 
+    /// Remove all the end-to-end encryption data of the given room.
+    ///
+    /// This removes the inbound and outbound group sessions, the withheld
+    /// information and the settings of the room from the store. The room keys
+    /// of the room won't be available anymore, so events of the room can't be
+    /// decrypted afterwards, unless the keys are received again, for example
+    /// from a key backup.
+    pub async fn remove_room_data(&self, room_id: &RoomId) -> StoreResult<()> {
+        self.inner.group_session_manager.session_cache().remove(room_id);
+        self.inner.store.remove_room(room_id).await
+    }
+
     /// Get to-device requests to share a room key with users in a room.
     ///
     /// # Arguments
@@ -786,6 +786,17 @@ impl ReadOnlyAccount {
         })
     }
 
+    /// Replace the Olm account of this account, and of all its clones, with the
+    /// one of the given account.
+    ///
+    /// The given account must be a more recent version of this account that was
+    /// loaded from the store.
+    pub(crate) async fn replace_with(&self, other: ReadOnlyAccount) {
+        std::mem::swap(&mut *self.inner.lock().await, &mut *other.inner.lock().await);
+        self.shared.store(other.shared(), Ordering::SeqCst);
+        self.uploaded_signed_key_count.store(other.uploaded_key_count(), Ordering::SeqCst);
+    }
+
     /// Generate the unsigned `DeviceKeys` from this ReadOnlyAccount
     pub fn unsigned_device_keys(&self) -> DeviceKeys {
         let identity_keys = self.identity_keys();
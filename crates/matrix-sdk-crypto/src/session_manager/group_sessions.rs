@@ -56,6 +56,18 @@ impl GroupSessionCache {
         self.sessions.insert(session.room_id().to_owned(), session);
     }
 
+    /// Remove all the sessions from the cache, so they are loaded again from
+    /// the store.
+    pub(crate) fn clear(&self) {
+        self.sessions.clear();
+    }
+
+    /// Remove the session of the given room from the cache.
+    pub(crate) fn remove(&self, room_id: &RoomId) {
+        self.sessions.remove(room_id);
+        self.sessions_being_shared.retain(|_, session| session.room_id() != room_id);
+    }
+
     /// Either get a session for the given room from the cache or load it from
     /// the store.
     ///
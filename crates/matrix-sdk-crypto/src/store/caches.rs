@@ -67,10 +67,27 @@ impl SessionStore {
         self.entries.get(sender_key).map(|s| s.clone())
     }
 
+    /// Get all the sessions of the store.
+    pub async fn get_all(&self) -> Vec<Session> {
+        let entries: Vec<_> = self.entries.iter().map(|e| e.value().clone()).collect();
+        let mut sessions = Vec::new();
+
+        for entry in entries {
+            sessions.extend(entry.lock().await.iter().cloned());
+        }
+
+        sessions
+    }
+
     /// Add a list of sessions belonging to the sender key.
     pub fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
         self.entries.insert(sender_key.to_owned(), Arc::new(Mutex::new(sessions)));
     }
+
+    /// Remove all the sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -119,6 +136,16 @@ impl GroupSessionStore {
     pub fn get(&self, room_id: &RoomId, session_id: &str) -> Option<InboundGroupSession> {
         self.entries.get(room_id)?.get(session_id).cloned()
     }
+
+    /// Remove all the group sessions of the given room from the store.
+    pub fn remove_room(&self, room_id: &RoomId) {
+        self.entries.remove(room_id);
+    }
+
+    /// Remove all the group sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
 }
 
 /// In-memory store holding the devices of users.
@@ -167,6 +194,11 @@ impl DeviceStore {
             .map(|i| (i.key().to_owned(), i.value().clone()))
             .collect()
     }
+
+    /// Remove all the devices from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
 }
 
 /// A numeric type that can represent an infinite ordered sequence.
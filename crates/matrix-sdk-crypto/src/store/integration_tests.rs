@@ -768,6 +768,69 @@ macro_rules! cryptostore_integration_tests {
                 let loaded_2 = store.get_custom_value("B").await.unwrap();
                 assert_eq!(None, loaded_2);
             }
+
+            #[async_test]
+            async fn remove_room_and_clear() {
+                let (account, store) = get_loaded_store("remove_room_and_clear").await;
+
+                let room_1 = room_id!("!test_1:localhost");
+                let room_2 = room_id!("!test_2:localhost");
+                let (_, session_1) = account.create_group_session_pair_with_defaults(room_1).await;
+                let (_, session_2) = account.create_group_session_pair_with_defaults(room_2).await;
+                let settings = RoomSettings {
+                    algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
+                    only_allow_trusted_devices: true,
+                };
+
+                let changes = Changes {
+                    inbound_group_sessions: vec![session_1.clone(), session_2.clone()],
+                    room_settings: HashMap::from([
+                        (room_1.into(), settings.clone()),
+                        (room_2.into(), settings.clone()),
+                    ]),
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                store.remove_room(room_1).await.unwrap();
+
+                assert!(store
+                    .get_inbound_group_session(room_1, session_1.session_id())
+                    .await
+                    .unwrap()
+                    .is_none());
+                assert!(store.get_room_settings(room_1).await.unwrap().is_none());
+                assert!(store
+                    .get_inbound_group_session(room_2, session_2.session_id())
+                    .await
+                    .unwrap()
+                    .is_some());
+                assert_eq!(store.get_room_settings(room_2).await.unwrap(), Some(settings));
+
+                store.clear().await.unwrap();
+
+                assert!(store.load_account().await.unwrap().is_none());
+                assert!(store.get_inbound_group_sessions().await.unwrap().is_empty());
+                assert!(store.get_room_settings(room_2).await.unwrap().is_none());
+            }
+
+            #[async_test]
+            async fn leased_lock() {
+                let (_account, store) = get_loaded_store("leased_lock").await;
+
+                assert!(store.try_take_leased_lock(60_000, "key", "alice").await.unwrap());
+                // The holder can renew its lease.
+                assert!(store.try_take_leased_lock(60_000, "key", "alice").await.unwrap());
+                // Another holder can't take the lock before the lease expires.
+                assert!(!store.try_take_leased_lock(60_000, "key", "bob").await.unwrap());
+                // Other keys are independent.
+                assert!(store.try_take_leased_lock(60_000, "other", "bob").await.unwrap());
+
+                // A lease of zero milliseconds releases the lock.
+                assert!(store.try_take_leased_lock(0, "key", "alice").await.unwrap());
+                assert!(store.try_take_leased_lock(60_000, "key", "bob").await.unwrap());
+                assert!(!store.try_take_leased_lock(60_000, "key", "alice").await.unwrap());
+            }
         }
     };
 }
@@ -0,0 +1,339 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A lock on a [`CryptoStore`] that can be shared between several processes.
+//!
+//! The lock is a lease that is stored in the crypto store with
+//! [`CryptoStore::try_take_leased_lock()`]. While the lock is held, the lease
+//! is renewed periodically by a background task. When it isn't renewed, for
+//! example because the process holding it was killed, the lease expires and
+//! another process can take the lock.
+//!
+//! [`CryptoStore`]: super::CryptoStore
+//! [`CryptoStore::try_take_leased_lock()`]: super::CryptoStore::try_take_leased_lock
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use matrix_sdk_common::{executor::spawn, sleep::sleep};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{error, instrument, trace};
+
+use super::{CryptoStoreError, DynCryptoStore};
+
+/// Duration of a lease, in milliseconds.
+///
+/// The lease expires after this duration if it isn't renewed.
+pub const LEASE_DURATION_MS: u32 = 500;
+
+/// Period after which the lease is renewed, in milliseconds.
+///
+/// This must be much shorter than [`LEASE_DURATION_MS`], so the lease doesn't
+/// expire while a lock is held.
+pub const EXTEND_LEASE_EVERY_MS: u64 = 50;
+
+/// Initial delay before retrying to take a lock, in milliseconds.
+const INITIAL_BACKOFF_MS: u32 = 10;
+
+/// Maximal delay between two attempts to take a lock, in milliseconds.
+pub const MAX_BACKOFF_MS: u32 = 1000;
+
+/// An error that can happen while taking a [`CryptoStoreLock`].
+#[derive(Debug, Error)]
+pub enum LockStoreError {
+    /// Spinning on the lock took longer than the given maximal backoff.
+    #[error("the lock couldn't be taken before the timeout")]
+    LockTimeout,
+
+    /// The crypto store failed to take the lease.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// A lock on a crypto store, shared between processes that use the same
+/// database.
+///
+/// The lock is reentrant: it can be taken several times by the same
+/// `CryptoStoreLock` or its clones, and is released when all the guards are
+/// dropped.
+///
+/// If the lease can't be renewed in time, another process can take the lock
+/// while guards are still alive. This can be checked with
+/// [`CryptoStoreLockGuard::is_lost()`].
+#[derive(Clone, Debug)]
+pub struct CryptoStoreLock {
+    /// The store the lease is stored in.
+    store: Arc<DynCryptoStore>,
+
+    /// The key of the lease in the store.
+    lock_key: String,
+
+    /// The name of this holder, that must be unique for each process.
+    lock_holder: String,
+
+    /// The lease held by this process, if any.
+    ///
+    /// This also serializes the attempts to take the lock in this process.
+    lease: Arc<Mutex<Option<Arc<Lease>>>>,
+}
+
+/// A lease held by a [`CryptoStoreLock`], that is renewed by a background
+/// task.
+#[derive(Debug, Default)]
+struct Lease {
+    /// The number of guards of this lease that are alive.
+    num_holders: AtomicU32,
+
+    /// Whether the lease was taken by another process before it was released.
+    lost: AtomicBool,
+}
+
+impl CryptoStoreLock {
+    /// Create a new lock on the given crypto store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store that contains the lease.
+    ///
+    /// * `lock_key` - The key of the lease in the store. All the processes must
+    ///   use the same key to lock the same resource.
+    ///
+    /// * `lock_holder` - The name of this process, that must be different for
+    ///   each process, for example `"main"` and `"notification-extension"`.
+    pub fn new(store: Arc<DynCryptoStore>, lock_key: String, lock_holder: String) -> Self {
+        Self { store, lock_key, lock_holder, lease: Default::default() }
+    }
+
+    /// The name of the holder of this lock.
+    pub fn lock_holder(&self) -> &str {
+        &self.lock_holder
+    }
+
+    /// Try to take the lock once.
+    ///
+    /// Returns a guard that releases the lock when dropped, or `None` if
+    /// another process holds the lock.
+    #[instrument(skip(self), fields(key = %self.lock_key, holder = %self.lock_holder))]
+    pub async fn try_lock_once(&self) -> Result<Option<CryptoStoreLockGuard>, CryptoStoreError> {
+        let mut current_lease = self.lease.lock().await;
+
+        if let Some(lease) = &*current_lease {
+            // We already hold the lock, and the lease is being renewed.
+            trace!("Reentrant lock");
+            return Ok(Some(CryptoStoreLockGuard::new(lease.clone())));
+        }
+
+        let acquired = self
+            .store
+            .try_take_leased_lock(LEASE_DURATION_MS, &self.lock_key, &self.lock_holder)
+            .await?;
+
+        if !acquired {
+            trace!("Couldn't take the lock, it is held by another process");
+            return Ok(None);
+        }
+
+        trace!("Took the lock");
+        let lease = Arc::new(Lease::default());
+        let guard = CryptoStoreLockGuard::new(lease.clone());
+
+        *current_lease = Some(lease.clone());
+        spawn(self.clone().renew_lease(lease));
+
+        Ok(Some(guard))
+    }
+
+    /// Take the lock, waiting for it to be released by other processes.
+    ///
+    /// The delay between two attempts to take the lock is doubled each time.
+    ///
+    /// If `max_backoff` is set, this returns [`LockStoreError::LockTimeout`]
+    /// once the delay grows above `max_backoff` milliseconds. Otherwise, this
+    /// waits for the lock indefinitely, with a delay of at most
+    /// [`MAX_BACKOFF_MS`].
+    pub async fn spin_lock(
+        &self,
+        max_backoff: Option<u32>,
+    ) -> Result<CryptoStoreLockGuard, LockStoreError> {
+        let mut backoff = INITIAL_BACKOFF_MS;
+
+        loop {
+            if let Some(guard) = self.try_lock_once().await? {
+                return Ok(guard);
+            }
+
+            match max_backoff {
+                Some(max_backoff) if backoff > max_backoff => {
+                    return Err(LockStoreError::LockTimeout);
+                }
+                Some(_) => {}
+                None => backoff = backoff.min(MAX_BACKOFF_MS),
+            }
+
+            sleep(Duration::from_millis(backoff.into())).await;
+            backoff = backoff.saturating_mul(2);
+        }
+    }
+
+    /// Renew the given lease while the lock is held, and release it when all
+    /// the guards are dropped.
+    ///
+    /// If the lease was taken by another process, it is marked as lost and is
+    /// not renewed anymore.
+    async fn renew_lease(self, lease: Arc<Lease>) {
+        loop {
+            sleep(Duration::from_millis(EXTEND_LEASE_EVERY_MS)).await;
+
+            let mut current_lease = self.lease.lock().await;
+
+            // A lease of zero milliseconds releases the lock.
+            let release = lease.num_holders.load(Ordering::SeqCst) == 0;
+            let lease_duration_ms = if release { 0 } else { LEASE_DURATION_MS };
+
+            match self
+                .store
+                .try_take_leased_lock(lease_duration_ms, &self.lock_key, &self.lock_holder)
+                .await
+            {
+                Ok(true) => {}
+                Ok(false) if release => {
+                    // Nobody uses the lock anymore, so it doesn't matter.
+                    trace!(
+                        key = %self.lock_key,
+                        holder = %self.lock_holder,
+                        "The lease was taken by another process before it was released"
+                    );
+                }
+                Ok(false) => {
+                    error!(
+                        key = %self.lock_key,
+                        holder = %self.lock_holder,
+                        "The lease expired and was taken by another process"
+                    );
+
+                    lease.lost.store(true, Ordering::SeqCst);
+                    *current_lease = None;
+                    break;
+                }
+                Err(error) => {
+                    error!(
+                        key = %self.lock_key,
+                        holder = %self.lock_holder,
+                        ?error,
+                        "Failed to renew the lease"
+                    );
+                }
+            }
+
+            if release {
+                trace!(key = %self.lock_key, holder = %self.lock_holder, "Released the lock");
+                *current_lease = None;
+                break;
+            }
+        }
+    }
+}
+
+/// A guard of a [`CryptoStoreLock`].
+///
+/// The lock is released once all its guards are dropped.
+#[derive(Debug)]
+pub struct CryptoStoreLockGuard {
+    lease: Arc<Lease>,
+}
+
+impl CryptoStoreLockGuard {
+    fn new(lease: Arc<Lease>) -> Self {
+        lease.num_holders.fetch_add(1, Ordering::SeqCst);
+        Self { lease }
+    }
+
+    /// Whether the lock was taken by another process while this guard was
+    /// alive, because its lease couldn't be renewed in time.
+    ///
+    /// In that case, the data protected by the lock might have been modified
+    /// by the other process, so the work done while holding this guard should
+    /// be discarded, and the lock taken again.
+    pub fn is_lost(&self) -> bool {
+        self.lease.lost.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for CryptoStoreLockGuard {
+    fn drop(&mut self) {
+        self.lease.num_holders.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use matrix_sdk_common::sleep::sleep;
+    use matrix_sdk_test::async_test;
+
+    use super::{CryptoStoreLock, LockStoreError, EXTEND_LEASE_EVERY_MS, LEASE_DURATION_MS};
+    use crate::store::{CryptoStore, IntoCryptoStore, MemoryStore};
+
+    #[async_test]
+    async fn lock_is_exclusive_and_reentrant() {
+        let store = MemoryStore::new().into_crypto_store();
+        let main = CryptoStoreLock::new(Arc::clone(&store), "lock".to_owned(), "main".to_owned());
+        let other = CryptoStoreLock::new(store, "lock".to_owned(), "other".to_owned());
+
+        let guard = main.try_lock_once().await.unwrap().unwrap();
+        let reentrant_guard = main.try_lock_once().await.unwrap().unwrap();
+        assert!(other.try_lock_once().await.unwrap().is_none());
+
+        drop(guard);
+        assert!(other.try_lock_once().await.unwrap().is_none());
+
+        assert!(matches!(other.spin_lock(Some(20)).await, Err(LockStoreError::LockTimeout)));
+
+        // Once all the guards are dropped, the lease is released by the renewal
+        // task.
+        drop(reentrant_guard);
+        let _guard = other.spin_lock(Some(4 * EXTEND_LEASE_EVERY_MS as u32)).await.unwrap();
+        assert!(main.try_lock_once().await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn lost_lock_is_not_renewed() {
+        let store = MemoryStore::new().into_crypto_store();
+        let main = CryptoStoreLock::new(Arc::clone(&store), "lock".to_owned(), "main".to_owned());
+
+        let guard = main.try_lock_once().await.unwrap().unwrap();
+        assert!(!guard.is_lost());
+
+        // Another process takes the lock, as if the lease had expired.
+        assert!(store.try_take_leased_lock(0, "lock", "main").await.unwrap());
+        assert!(store.try_take_leased_lock(LEASE_DURATION_MS, "lock", "other").await.unwrap());
+
+        sleep(Duration::from_millis(2 * EXTEND_LEASE_EVERY_MS)).await;
+        assert!(guard.is_lost());
+
+        // The lock isn't reentrant anymore, and the lease of the other process
+        // was kept.
+        assert!(main.try_lock_once().await.unwrap().is_none());
+        drop(guard);
+        sleep(Duration::from_millis(2 * EXTEND_LEASE_EVERY_MS)).await;
+        assert!(!store.try_take_leased_lock(LEASE_DURATION_MS, "lock", "main").await.unwrap());
+    }
+}
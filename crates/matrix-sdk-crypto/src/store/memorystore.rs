@@ -12,10 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use dashmap::{DashMap, DashSet};
+use matrix_sdk_common::instant::Instant;
 use ruma::{
     DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, TransactionId,
     UserId,
@@ -56,6 +57,7 @@ pub struct MemoryStore {
     outgoing_key_requests: Arc<DashMap<OwnedTransactionId, GossipRequest>>,
     key_requests_by_info: Arc<DashMap<String, OwnedTransactionId>>,
     direct_withheld_info: Arc<DashMap<OwnedRoomId, DashMap<String, RoomKeyWithheldEvent>>>,
+    leases: Arc<DashMap<String, (String, Instant)>>,
 }
 
 impl Default for MemoryStore {
@@ -69,6 +71,7 @@ impl Default for MemoryStore {
             outgoing_key_requests: Default::default(),
             key_requests_by_info: Default::default(),
             direct_withheld_info: Default::default(),
+            leases: Default::default(),
         }
     }
 }
@@ -166,6 +169,10 @@ impl CryptoStore for MemoryStore {
         Ok(self.sessions.get(sender_key))
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        Ok(self.sessions.get_all().await)
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -311,6 +318,49 @@ impl CryptoStore for MemoryStore {
         warn!("Method not implemented");
         Ok(())
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        self.inbound_group_sessions.remove_room(room_id);
+        self.direct_withheld_info.remove(room_id);
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.sessions.clear();
+        self.inbound_group_sessions.clear();
+        self.olm_hashes.clear();
+        self.devices.clear();
+        self.identities.clear();
+        self.outgoing_key_requests.clear();
+        self.key_requests_by_info.clear();
+        self.direct_withheld_info.clear();
+
+        Ok(())
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool> {
+        let now = Instant::now();
+        let expiration = now + Duration::from_millis(lease_duration_ms.into());
+
+        let mut lease = self.leases.entry(key.to_owned()).or_insert((holder.to_owned(), now));
+        let (current_holder, current_expiration) = &mut *lease;
+
+        if current_holder == holder || *current_expiration <= now {
+            *current_holder = holder.to_owned();
+            *current_expiration = expiration;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn clear_caches(&self) {}
 }
 
 #[cfg(test)]
@@ -80,6 +80,7 @@ use crate::{
 
 pub mod caches;
 mod error;
+pub mod locks;
 mod memorystore;
 mod traits;
 
@@ -414,6 +415,16 @@ impl Store {
         self.inner.identity.lock().await.reset().await;
     }
 
+    /// Clear the values that are cached in memory by this store and by the
+    /// underlying [`CryptoStore`], so they are loaded again from the database.
+    pub(crate) async fn clear_caches(&self) {
+        self.inner.store.clear_caches().await;
+
+        let _lock = self.inner.tracked_user_loading_lock.lock().await;
+        self.inner.tracked_users_cache.clear();
+        self.inner.tracked_users_loaded.store(false, Ordering::SeqCst);
+    }
+
     /// PrivateCrossSigningIdentity associated with this store
     pub(crate) fn private_identity(&self) -> Arc<Mutex<PrivateCrossSigningIdentity>> {
         self.inner.identity.clone()
@@ -68,6 +68,9 @@ pub trait CryptoStore: AsyncTraitDeps {
         sender_key: &str,
     ) -> Result<Option<Arc<Mutex<Vec<Session>>>>, Self::Error>;
 
+    /// Get all the Olm sessions we have stored, whatever their sender key.
+    async fn get_all_sessions(&self) -> Result<Vec<Session>, Self::Error>;
+
     /// Get the inbound group session from our store.
     ///
     /// # Arguments
@@ -226,6 +229,62 @@ pub trait CryptoStore: AsyncTraitDeps {
     ///
     /// * `value` - The value to insert
     async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Remove the room keys, the withheld information and the settings of the
+    /// given room from the store.
+    ///
+    /// This is used when a room is forgotten, the messages of the room can't
+    /// be decrypted anymore afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    async fn remove_room(&self, room_id: &RoomId) -> Result<(), Self::Error>;
+
+    /// Remove all the data from the store, including the account.
+    ///
+    /// The data needed to open the store, like the store cipher, is kept so
+    /// the store can still be used afterwards.
+    async fn clear(&self) -> Result<(), Self::Error>;
+
+    /// Try to take a leased lock.
+    ///
+    /// This attempts to take a lock for the given lease duration.
+    ///
+    /// - If we already had the lease, this will extend the lease.
+    /// - If we didn't, but the previous lease has expired, we will acquire the
+    ///   lock.
+    /// - If there was no previous lease, we will acquire the lock.
+    /// - Otherwise, we don't get the lock.
+    ///
+    /// Returns whether taking the lock succeeded.
+    ///
+    /// Reading the current lease and writing the new one must be a single
+    /// atomic operation, for all the processes that share the store, for
+    /// example a compare-and-swap or a database transaction. Otherwise two
+    /// holders could both take the lock.
+    ///
+    /// # Arguments
+    ///
+    /// * `lease_duration_ms` - The duration of the lease, in milliseconds. A
+    ///   duration of zero releases the lock if we hold it.
+    ///
+    /// * `key` - The key of the lock.
+    ///
+    /// * `holder` - The unique name of the holder of the lock.
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool, Self::Error>;
+
+    /// Clear the values that are cached in memory by this store, so they are
+    /// loaded again from the database.
+    ///
+    /// This is needed when another process might have modified the data of
+    /// the store.
+    async fn clear_caches(&self);
 }
 
 #[repr(transparent)]
@@ -262,6 +321,10 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_sessions(sender_key).await.map_err(Into::into)
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        self.0.get_all_sessions().await.map_err(Into::into)
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -372,6 +435,27 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
     async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<(), Self::Error> {
         self.0.set_custom_value(key, value).await.map_err(Into::into)
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<(), Self::Error> {
+        self.0.remove_room(room_id).await.map_err(Into::into)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.0.clear().await.map_err(Into::into)
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool, Self::Error> {
+        self.0.try_take_leased_lock(lease_duration_ms, key, holder).await.map_err(Into::into)
+    }
+
+    async fn clear_caches(&self) {
+        self.0.clear_caches().await
+    }
 }
 
 /// A type-erased [`CryptoStore`].
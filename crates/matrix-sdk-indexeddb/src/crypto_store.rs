@@ -20,6 +20,7 @@ use std::{
 use async_trait::async_trait;
 use gloo_utils::format::JsValueSerdeExt;
 use indexed_db_futures::prelude::*;
+use matrix_sdk_base::store::{IntegrityIssue, IntegrityReport};
 use matrix_sdk_crypto::{
     olm::{
         IdentityKeys, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
@@ -34,13 +35,27 @@ use matrix_sdk_crypto::{
     TrackedUser,
 };
 use matrix_sdk_store_encryption::StoreCipher;
-use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId};
-use serde::{de::DeserializeOwned, Serialize};
+use ruma::{
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
 use tokio::sync::Mutex;
 use wasm_bindgen::JsValue;
 use web_sys::IdbKeyRange;
 
-use crate::safe_encode::SafeEncode;
+use crate::{
+    rotate::{
+        has_store_cipher, resume_rotation, rotate_store_cipher, CipherLocation, EncryptedDatabase,
+        ROTATION_MARKER,
+    },
+    safe_encode::{SafeEncode, KEY_SEPARATOR},
+};
+
+/// The version of the database schema.
+const CURRENT_DB_VERSION: u32 = 3;
 
 mod keys {
     // stores
@@ -67,8 +82,10 @@ mod keys {
 
     // keys
     pub const STORE_CIPHER: &str = "store_cipher";
+    pub const NEXT_STORE_CIPHER: &str = "next_store_cipher";
     pub const ACCOUNT: &str = "account";
     pub const PRIVATE_IDENTITY: &str = "private_identity";
+    pub const LEASE_LOCK_PREFIX: &str = "lease_lock:";
 
     // backup v1
     pub const BACKUP_KEYS: &str = "backup_keys";
@@ -108,6 +125,12 @@ pub enum IndexeddbCryptoStoreError {
     },
     #[error(transparent)]
     CryptoStoreError(#[from] CryptoStoreError),
+    #[error(transparent)]
+    Encryption(#[from] matrix_sdk_store_encryption::Error),
+    #[error("the store is used by other handles")]
+    InUse,
+    #[error("the store cipher is shared with the state store")]
+    SharedStoreCipher,
 }
 
 impl From<indexed_db_futures::web_sys::DomException> for IndexeddbCryptoStoreError {
@@ -147,7 +170,7 @@ impl IndexeddbCryptoStore {
         let name = format!("{prefix:0}::matrix-sdk-crypto");
 
         // Open my_db v1
-        let mut db_req: OpenDbRequest = IdbDatabase::open_u32(&name, 3)?;
+        let mut db_req: OpenDbRequest = IdbDatabase::open_u32(&name, CURRENT_DB_VERSION)?;
         db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
             // Even if the web-sys bindings expose the version as a f64, the IndexedDB API
             // works with an unsigned integer.
@@ -252,21 +275,7 @@ impl IndexeddbCryptoStore {
 
     /// Open a new `IndexeddbCryptoStore` with given name and passphrase
     pub async fn open_with_passphrase(prefix: &str, passphrase: &str) -> Result<Self> {
-        let name = format!("{prefix:0}::matrix-sdk-crypto-meta");
-
-        let mut db_req: OpenDbRequest = IdbDatabase::open_u32(&name, 1)?;
-        db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
-            let old_version = evt.old_version() as u32;
-            if old_version < 1 {
-                // migrating to version 1
-                let db = evt.db();
-
-                db.create_object_store("matrix-sdk-crypto")?;
-            }
-            Ok(())
-        }));
-
-        let db: IdbDatabase = db_req.into_future().await?;
+        let db = open_meta_db(&format!("{prefix:0}::matrix-sdk-crypto-meta")).await?;
 
         let tx: IdbTransaction<'_> =
             db.transaction_on_one_with_mode("matrix-sdk-crypto", IdbTransactionMode::Readonly)?;
@@ -303,11 +312,143 @@ impl IndexeddbCryptoStore {
             }
         };
 
+        let mut store =
+            IndexeddbCryptoStore::open_with_store_cipher(prefix, Some(store_cipher.into())).await?;
+
+        // Finish a rotation of the store cipher that was interrupted.
+        let store_cipher = resume_rotation::<IndexeddbCryptoStoreError>(
+            &cipher_location(&db),
+            &[store.encrypted_database()],
+            passphrase,
+        )
+        .await;
+
         // Must release the database access manually as it's not done when
         // dropping it.
         db.close();
 
-        IndexeddbCryptoStore::open_with_store_cipher(prefix, Some(store_cipher.into())).await
+        if let Some(store_cipher) = store_cipher? {
+            store.store_cipher = Some(store_cipher.into());
+        }
+
+        Ok(store)
+    }
+
+    /// Open the crypto store with the given prefix if it uses the store cipher
+    /// of the state store with the same name, to rotate its data with it.
+    pub(crate) async fn open_with_shared_store_cipher(prefix: &str) -> Result<Option<Self>> {
+        let db = open_meta_db(&format!("{prefix:0}::matrix-sdk-crypto-meta")).await?;
+        let has_store_cipher =
+            has_store_cipher::<IndexeddbCryptoStoreError>(&cipher_location(&db)).await;
+        db.close();
+
+        if has_store_cipher? {
+            Ok(None)
+        } else {
+            Ok(Some(IndexeddbCryptoStore::open_with_store_cipher(prefix, None).await?))
+        }
+    }
+
+    /// Change the passphrase that is used to encrypt the store cipher of this
+    /// store.
+    ///
+    /// The data of the store is not re-encrypted, only the store cipher is
+    /// exported again with the new passphrase.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        let db: IdbDatabase =
+            IdbDatabase::open_u32(&format!("{}-meta", self.name), 1)?.into_future().await?;
+
+        let tx: IdbTransaction<'_> =
+            db.transaction_on_one_with_mode("matrix-sdk-crypto", IdbTransactionMode::Readwrite)?;
+        let ob = tx.object_store("matrix-sdk-crypto")?;
+
+        let store_cipher: Vec<u8> = ob
+            .get(&JsValue::from_str(keys::STORE_CIPHER))?
+            .await?
+            .map(|k| k.into_serde())
+            .transpose()?
+            .ok_or(CryptoStoreError::UnpicklingError)?;
+
+        let cipher = StoreCipher::import(old_passphrase, &store_cipher)
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+        #[cfg(not(test))]
+        let export = cipher.export(new_passphrase);
+        #[cfg(test)]
+        let export = cipher._insecure_export_fast_for_testing(new_passphrase);
+
+        ob.put_key_val(
+            &JsValue::from_str(keys::STORE_CIPHER),
+            &JsValue::from_serde(&export.map_err(CryptoStoreError::backend)?)?,
+        )?;
+        tx.await.into_result()?;
+
+        // Must release the database access manually as it's not done when
+        // dropping it.
+        db.close();
+
+        Ok(())
+    }
+
+    /// Rotate the keys that are used to encrypt the data of this store.
+    ///
+    /// All the values of the store are re-encrypted with a new key and all the
+    /// hashed keys are hashed again with a new key, in a single transaction.
+    /// The new store cipher is encrypted with the given passphrase. If this is
+    /// interrupted, it is finished the next time the store is opened with
+    /// [`IndexeddbCryptoStore::open_with_passphrase()`].
+    ///
+    /// This only works for stores opened with
+    /// [`IndexeddbCryptoStore::open_with_passphrase()`]. The data of a store
+    /// that shares the store cipher of a state store is rotated with
+    /// [`IndexeddbStateStore::rotate_encryption_key()`].
+    ///
+    /// Returns the store using the new keys. This fails if the store cipher is
+    /// used by other handles, since they would keep using the old keys, so
+    /// this should be called before the store is given to a client.
+    ///
+    /// [`IndexeddbStateStore::rotate_encryption_key()`]: crate::IndexeddbStateStore::rotate_encryption_key
+    pub async fn rotate_encryption_key(mut self, passphrase: &str) -> Result<Self> {
+        let Some(store_cipher) = &self.store_cipher else {
+            return Err(CryptoStoreError::UnpicklingError.into());
+        };
+
+        if Arc::strong_count(store_cipher) > 1 {
+            return Err(IndexeddbCryptoStoreError::InUse);
+        }
+
+        let db = open_meta_db(&format!("{}-meta", self.name)).await?;
+        let store_cipher = rotate_store_cipher::<IndexeddbCryptoStoreError>(
+            &cipher_location(&db),
+            &[self.encrypted_database()],
+            passphrase,
+        )
+        .await;
+
+        // Must release the database access manually as it's not done when
+        // dropping it.
+        db.close();
+
+        let Some(store_cipher) = store_cipher? else {
+            return Err(IndexeddbCryptoStoreError::SharedStoreCipher);
+        };
+
+        self.store_cipher = Some(store_cipher.into());
+        Ok(self)
+    }
+
+    /// The description of the database of this store, to rotate its store
+    /// cipher.
+    pub(crate) fn encrypted_database(&self) -> EncryptedDatabase<'_> {
+        EncryptedDatabase {
+            db: &self.inner,
+            plain_key_stores: &[keys::CORE, keys::BACKUP_KEYS],
+            hashed_value_stores: &[keys::SECRET_REQUESTS_BY_INFO],
+            marker_store: keys::CORE,
+        }
     }
 
     /// Open a new `IndexeddbCryptoStore` with given name and no passphrase
@@ -315,6 +456,106 @@ impl IndexeddbCryptoStore {
         IndexeddbCryptoStore::open_with_store_cipher(name, None).await
     }
 
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks the schema version of the database, that the values can be
+    /// decrypted and deserialized, and that there are no Olm sessions without
+    /// an account. See [`IntegrityIssue`] for what the repair does for each
+    /// problem.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        let version = self.inner.version() as u32;
+
+        if version != CURRENT_DB_VERSION {
+            let issue = IntegrityIssue::SchemaVersion {
+                found: Some(version as usize),
+                expected: CURRENT_DB_VERSION as usize,
+            };
+            return Ok(IntegrityReport { issues: vec![issue], repaired: false });
+        }
+
+        // The other stores only contain markers or hashes.
+        let stores = [
+            keys::CORE,
+            keys::SESSION,
+            keys::INBOUND_GROUP_SESSIONS,
+            keys::OUTBOUND_GROUP_SESSIONS,
+            keys::DEVICES,
+            keys::IDENTITIES,
+            keys::OUTGOING_SECRET_REQUESTS,
+            keys::UNSENT_SECRET_REQUESTS,
+            keys::BACKUP_KEYS,
+            keys::DIRECT_WITHHELD_INFO,
+            keys::ROOM_SETTINGS,
+        ];
+
+        let mode =
+            if repair { IdbTransactionMode::Readwrite } else { IdbTransactionMode::Readonly };
+        let tx = self.inner.transaction_on_multi_with_mode(&stores, mode)?;
+        let mut issues = Vec::new();
+
+        for store_name in stores {
+            let store = tx.object_store(store_name)?;
+            let mut broken_keys = Vec::new();
+
+            if let Some(cursor) = store.open_cursor()?.await? {
+                while let Some(key) = cursor.key() {
+                    // The store cipher and the rotation marker are not
+                    // encrypted with the store cipher.
+                    let is_unencrypted = store_name == keys::CORE
+                        && key.as_string().map_or(false, |key| {
+                            key == keys::STORE_CIPHER
+                                || key == keys::NEXT_STORE_CIPHER
+                                || key == ROTATION_MARKER
+                        });
+
+                    if !is_unencrypted
+                        && self.deserialize_value::<IgnoredAny>(cursor.value()).is_err()
+                    {
+                        broken_keys.push(key);
+                    }
+
+                    cursor.continue_cursor()?.await?;
+                }
+            }
+
+            if broken_keys.is_empty() {
+                continue;
+            }
+
+            issues.push(IntegrityIssue::CorruptedValues {
+                table: store_name.to_owned(),
+                count: broken_keys.len(),
+            });
+
+            if repair {
+                for key in &broken_keys {
+                    store.delete(key)?;
+                }
+            }
+        }
+
+        let has_account =
+            tx.object_store(keys::CORE)?.get(&JsValue::from_str(keys::ACCOUNT))?.await?.is_some();
+        let sessions = tx.object_store(keys::SESSION)?;
+        let session_count = sessions.count()?.await? as usize;
+
+        if !has_account && session_count > 0 {
+            issues.push(IntegrityIssue::SessionsWithoutAccount { count: session_count });
+
+            if repair {
+                sessions.clear()?;
+            }
+        }
+
+        tx.await.into_result()?;
+
+        if repair {
+            self.session_cache.clear();
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
     fn serialize_value(&self, value: &impl Serialize) -> Result<JsValue, CryptoStoreError> {
         if let Some(key) = &self.store_cipher {
             let value = key.encrypt_value(value).map_err(CryptoStoreError::backend)?;
@@ -342,6 +583,33 @@ impl IndexeddbCryptoStore {
     }
 }
 
+/// Open the database that contains the store cipher of the crypto store.
+async fn open_meta_db(name: &str) -> Result<IdbDatabase> {
+    let mut db_req: OpenDbRequest = IdbDatabase::open_u32(name, 1)?;
+    db_req.set_on_upgrade_needed(Some(|evt: &IdbVersionChangeEvent| -> Result<(), JsValue> {
+        let old_version = evt.old_version() as u32;
+        if old_version < 1 {
+            // migrating to version 1
+            let db = evt.db();
+
+            db.create_object_store("matrix-sdk-crypto")?;
+        }
+        Ok(())
+    }));
+
+    Ok(db_req.into_future().await?)
+}
+
+/// Where the store cipher is saved in the given meta database.
+fn cipher_location(db: &IdbDatabase) -> CipherLocation<'_> {
+    CipherLocation {
+        db,
+        store: "matrix-sdk-crypto",
+        key: keys::STORE_CIPHER,
+        next_key: keys::NEXT_STORE_CIPHER,
+    }
+}
+
 // Small hack to have the following macro invocation act as the appropriate
 // trait impl block on wasm, but still be compiled on non-wasm as a regular
 // impl block otherwise.
@@ -754,6 +1022,26 @@ impl_crypto_store! {
         Ok(self.session_cache.get(sender_key))
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        self.inner
+            .transaction_on_one_with_mode(keys::SESSION, IdbTransactionMode::Readonly)?
+            .object_store(keys::SESSION)?
+            .get_all()?
+            .await?
+            .iter()
+            .map(|f| {
+                Ok(Session::from_pickle(
+                    account_info.user_id.clone(),
+                    account_info.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    self.deserialize_value(f)?,
+                ))
+            })
+            .collect()
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -1056,6 +1344,127 @@ impl_crypto_store! {
             .put_key_val(&JsValue::from_str(key), &self.serialize_value(&value)?)?;
         Ok(())
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[
+                keys::INBOUND_GROUP_SESSIONS,
+                keys::OUTBOUND_GROUP_SESSIONS,
+                keys::DIRECT_WITHHELD_INFO,
+                keys::ROOM_SETTINGS,
+            ],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let inbound = tx.object_store(keys::INBOUND_GROUP_SESSIONS)?;
+        let range = self.encode_to_range(keys::INBOUND_GROUP_SESSIONS, room_id)?;
+        for key in inbound.get_all_keys_with_key(&range)?.await?.iter() {
+            inbound.delete(&key)?;
+        }
+
+        tx.object_store(keys::OUTBOUND_GROUP_SESSIONS)?
+            .delete(&self.encode_key(keys::OUTBOUND_GROUP_SESSIONS, room_id))?;
+        tx.object_store(keys::ROOM_SETTINGS)?
+            .delete(&self.encode_key(keys::ROOM_SETTINGS, room_id))?;
+
+        // Withheld info is keyed by `(session_id, room_id)`, so we can only
+        // match the end of the keys.
+        let withheld = tx.object_store(keys::DIRECT_WITHHELD_INFO)?;
+        let room_suffix = self
+            .encode_key(keys::DIRECT_WITHHELD_INFO, room_id)
+            .as_string()
+            .map(|room_key| [KEY_SEPARATOR, &room_key].concat())
+            .unwrap_or_default();
+        for key in withheld.get_all_keys()?.await?.iter() {
+            if key.as_string().map_or(false, |key| key.ends_with(&room_suffix)) {
+                withheld.delete(&key)?;
+            }
+        }
+
+        tx.await.into_result()?;
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let stores = [
+            keys::SESSION,
+            keys::INBOUND_GROUP_SESSIONS,
+            keys::OUTBOUND_GROUP_SESSIONS,
+            keys::TRACKED_USERS,
+            keys::OLM_HASHES,
+            keys::DEVICES,
+            keys::IDENTITIES,
+            keys::OUTGOING_SECRET_REQUESTS,
+            keys::UNSENT_SECRET_REQUESTS,
+            keys::SECRET_REQUESTS_BY_INFO,
+            keys::BACKUP_KEYS,
+            keys::ROOM_SETTINGS,
+            keys::DIRECT_WITHHELD_INFO,
+        ];
+
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[&stores[..], &[keys::CORE]].concat(),
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        for store in stores {
+            tx.object_store(store)?.clear()?;
+        }
+
+        // Keep the store cipher, otherwise the database can't be opened
+        // anymore, and the leases, which are shared with other processes.
+        let core = tx.object_store(keys::CORE)?;
+        for key in core.get_all_keys()?.await?.iter() {
+            let keep = key.as_string().map_or(false, |key| {
+                key == keys::STORE_CIPHER || key.starts_with(keys::LEASE_LOCK_PREFIX)
+            });
+
+            if !keep {
+                core.delete(&key)?;
+            }
+        }
+
+        tx.await.into_result()?;
+
+        *self.account_info.write().unwrap() = None;
+        self.session_cache.clear();
+
+        Ok(())
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool> {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let expiration = now + u64::from(lease_duration_ms);
+        let key = JsValue::from_str(&format!("{}{key}", keys::LEASE_LOCK_PREFIX));
+
+        let tx =
+            self.inner.transaction_on_one_with_mode(keys::CORE, IdbTransactionMode::Readwrite)?;
+        let store = tx.object_store(keys::CORE)?;
+
+        if let Some(lease) = store.get(&key)?.await? {
+            let (current_holder, current_expiration): (String, u64) =
+                self.deserialize_value(lease)?;
+
+            if current_holder != holder && current_expiration > now {
+                return Ok(false);
+            }
+        }
+
+        store.put_key_val(&key, &self.serialize_value(&(holder, expiration))?)?;
+        tx.await.into_result()?;
+
+        Ok(true)
+    }
+
+    async fn clear_caches(&self) {
+        self.session_cache.clear();
+    }
 }
 
 impl Drop for IndexeddbCryptoStore {
@@ -1068,9 +1477,12 @@ impl Drop for IndexeddbCryptoStore {
 
 #[cfg(all(test, target_arch = "wasm32"))]
 mod tests {
+    use indexed_db_futures::prelude::*;
     use matrix_sdk_crypto::cryptostore_integration_tests;
+    use matrix_sdk_test::async_test;
+    use wasm_bindgen::JsValue;
 
-    use super::IndexeddbCryptoStore;
+    use super::{keys, IndexeddbCryptoStore};
 
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
@@ -1085,6 +1497,32 @@ mod tests {
         }
     }
     cryptostore_integration_tests!();
+
+    #[async_test]
+    async fn verify_and_repair() {
+        let store = get_store("verify_and_repair", Some("secret")).await;
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+
+        // A session that can't be decrypted, and that doesn't have an account.
+        let tx = store
+            .inner
+            .transaction_on_one_with_mode(keys::SESSION, IdbTransactionMode::Readwrite)
+            .unwrap();
+        tx.object_store(keys::SESSION)
+            .unwrap()
+            .put_key_val(&JsValue::from_str("session"), &JsValue::from_str("not encrypted"))
+            .unwrap();
+        tx.await.into_result().unwrap();
+
+        let report = store.verify_and_repair(false).await.unwrap();
+        assert!(!report.is_healthy());
+        assert!(!report.is_usable());
+
+        let report = store.verify_and_repair(true).await.unwrap();
+        assert!(report.is_usable());
+
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+    }
 }
 
 #[cfg(all(test, target_arch = "wasm32"))]
@@ -5,6 +5,7 @@ use thiserror::Error;
 
 #[cfg(feature = "e2e-encryption")]
 mod crypto_store;
+mod rotate;
 mod safe_encode;
 mod state_store;
 
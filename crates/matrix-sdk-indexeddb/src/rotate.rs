@@ -0,0 +1,288 @@
+//! Rotation of the keys of the store cipher of IndexedDB databases.
+//!
+//! The store cipher is saved in a different database than the data it
+//! encrypts, and IndexedDB transactions can't span several databases. So the
+//! new store cipher is first saved next to the current one, then the data of
+//! each database is rotated in a single transaction that also writes a marker,
+//! and finally the new store cipher replaces the current one. If this is
+//! interrupted, it can be resumed with [`resume_rotation()`].
+
+use base64::Engine;
+use gloo_utils::format::JsValueSerdeExt;
+use indexed_db_futures::{prelude::*, web_sys::DomException};
+use matrix_sdk_store_encryption::{EncryptedValue, Error as EncryptionError, StoreCipher};
+use wasm_bindgen::JsValue;
+use web_sys::IdbTransactionMode;
+
+use crate::safe_encode::{KEY_SEPARATOR, STANDARD_NO_PAD};
+
+/// The key of the marker that is written in a database once its data was
+/// rotated to the next store cipher.
+pub(crate) const ROTATION_MARKER: &str = "rotated_store_cipher";
+
+/// Where a store cipher is saved.
+pub(crate) struct CipherLocation<'a> {
+    pub db: &'a IdbDatabase,
+    pub store: &'static str,
+    /// The key of the current store cipher.
+    pub key: &'static str,
+    /// The key of the store cipher that is being rotated to.
+    pub next_key: &'static str,
+}
+
+/// A database with data encrypted by a store cipher.
+pub(crate) struct EncryptedDatabase<'a> {
+    pub db: &'a IdbDatabase,
+    /// The object stores whose keys are not hashed.
+    pub plain_key_stores: &'static [&'static str],
+    /// The object stores whose values are hashed keys.
+    pub hashed_value_stores: &'static [&'static str],
+    /// The object store where the rotation marker is written.
+    pub marker_store: &'static str,
+}
+
+/// Replace the store cipher at the given location with one with new keys,
+/// re-encrypt all the encrypted values and re-hash all the hashed keys of the
+/// given databases.
+///
+/// Returns `None` if there is no store cipher at the given location.
+pub(crate) async fn rotate_store_cipher<E>(
+    location: &CipherLocation<'_>,
+    databases: &[EncryptedDatabase<'_>],
+    passphrase: &str,
+) -> Result<Option<StoreCipher>, E>
+where
+    E: From<DomException> + From<serde_json::Error> + From<EncryptionError>,
+{
+    let (Some(export), _) = get_exports::<E>(location).await? else {
+        return Ok(None);
+    };
+
+    // The markers of a previous rotation that was finished.
+    for database in databases {
+        remove_marker::<E>(database).await?;
+    }
+
+    let old_cipher = StoreCipher::import(passphrase, &export)?;
+    let new_cipher = old_cipher.rotate_encryption_key()?;
+    #[cfg(not(test))]
+    let export = new_cipher.export(passphrase)?;
+    #[cfg(test)]
+    let export = new_cipher._insecure_export_fast_for_testing(passphrase)?;
+
+    let tx =
+        location.db.transaction_on_one_with_mode(location.store, IdbTransactionMode::Readwrite)?;
+    tx.object_store(location.store)?
+        .put_key_val(&JsValue::from_str(location.next_key), &JsValue::from_serde(&export)?)?;
+    tx.await.into_result()?;
+
+    finish_rotation::<E>(location, databases, &old_cipher, &new_cipher, &export).await?;
+
+    Ok(Some(new_cipher))
+}
+
+/// Whether there is a store cipher at the given location.
+pub(crate) async fn has_store_cipher<E>(location: &CipherLocation<'_>) -> Result<bool, E>
+where
+    E: From<DomException> + From<serde_json::Error>,
+{
+    Ok(get_exports::<E>(location).await?.0.is_some())
+}
+
+/// Whether a rotation of the store cipher at the given location was
+/// interrupted.
+pub(crate) async fn has_pending_rotation<E>(location: &CipherLocation<'_>) -> Result<bool, E>
+where
+    E: From<DomException> + From<serde_json::Error>,
+{
+    Ok(get_exports::<E>(location).await?.1.is_some())
+}
+
+/// Finish a rotation of the store cipher at the given location that was
+/// interrupted.
+///
+/// Returns the new store cipher, or `None` if no rotation was pending.
+pub(crate) async fn resume_rotation<E>(
+    location: &CipherLocation<'_>,
+    databases: &[EncryptedDatabase<'_>],
+    passphrase: &str,
+) -> Result<Option<StoreCipher>, E>
+where
+    E: From<DomException> + From<serde_json::Error> + From<EncryptionError>,
+{
+    let (Some(export), Some(next_export)) = get_exports::<E>(location).await? else {
+        return Ok(None);
+    };
+
+    let old_cipher = StoreCipher::import(passphrase, &export)?;
+    let new_cipher = StoreCipher::import(passphrase, &next_export)?;
+    finish_rotation::<E>(location, databases, &old_cipher, &new_cipher, &next_export).await?;
+
+    Ok(Some(new_cipher))
+}
+
+/// Get the exports of the current and next store ciphers at the given
+/// location.
+async fn get_exports<E>(
+    location: &CipherLocation<'_>,
+) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>), E>
+where
+    E: From<DomException> + From<serde_json::Error>,
+{
+    let tx =
+        location.db.transaction_on_one_with_mode(location.store, IdbTransactionMode::Readonly)?;
+    let store = tx.object_store(location.store)?;
+
+    let export: Option<Vec<u8>> =
+        store.get(&JsValue::from_str(location.key))?.await?.map(|v| v.into_serde()).transpose()?;
+    let next_export: Option<Vec<u8>> = store
+        .get(&JsValue::from_str(location.next_key))?
+        .await?
+        .map(|v| v.into_serde())
+        .transpose()?;
+
+    Ok((export, next_export))
+}
+
+async fn finish_rotation<E>(
+    location: &CipherLocation<'_>,
+    databases: &[EncryptedDatabase<'_>],
+    old_cipher: &StoreCipher,
+    new_cipher: &StoreCipher,
+    export: &[u8],
+) -> Result<(), E>
+where
+    E: From<DomException> + From<serde_json::Error> + From<EncryptionError>,
+{
+    for database in databases {
+        rotate_database::<E>(database, old_cipher, new_cipher).await?;
+    }
+
+    let tx =
+        location.db.transaction_on_one_with_mode(location.store, IdbTransactionMode::Readwrite)?;
+    let store = tx.object_store(location.store)?;
+    store.put_key_val(&JsValue::from_str(location.key), &JsValue::from_serde(&export)?)?;
+    store.delete(&JsValue::from_str(location.next_key))?;
+    tx.await.into_result()?;
+
+    for database in databases {
+        remove_marker::<E>(database).await?;
+    }
+
+    Ok(())
+}
+
+/// Rewrite all the entries of the given database with the new store cipher,
+/// in a single transaction, unless it has the rotation marker.
+async fn rotate_database<E>(
+    database: &EncryptedDatabase<'_>,
+    old_cipher: &StoreCipher,
+    new_cipher: &StoreCipher,
+) -> Result<(), E>
+where
+    E: From<DomException> + From<serde_json::Error> + From<EncryptionError>,
+{
+    let names: Vec<String> = database.db.object_store_names().collect();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let tx = database.db.transaction_on_multi_with_mode(&names, IdbTransactionMode::Readwrite)?;
+
+    let marker = JsValue::from_str(ROTATION_MARKER);
+    if tx.object_store(database.marker_store)?.get(&marker)?.await?.is_some() {
+        return Ok(());
+    }
+
+    // Compute all the new entries before writing anything, so an error doesn't
+    // leave the database half rotated.
+    let mut stores = Vec::new();
+    for name in names {
+        let store = tx.object_store(name)?;
+        let keys = store.get_all_keys()?.await?;
+        let values = store.get_all()?.await?;
+
+        let rehash_keys = !database.plain_key_stores.contains(&name);
+        let rehash_values = database.hashed_value_stores.contains(&name);
+        let mut entries = Vec::new();
+
+        for (key, value) in keys.iter().zip(values.iter()) {
+            let key = match key.as_string() {
+                Some(key) if rehash_keys => rehash_key(&key, new_cipher).into(),
+                _ => key,
+            };
+            let value = match value.as_string() {
+                Some(value) if rehash_values => rehash_key(&value, new_cipher).into(),
+                _ => reencrypt_value::<E>(value, old_cipher, new_cipher)?,
+            };
+
+            entries.push((key, value));
+        }
+
+        stores.push((store, entries));
+    }
+
+    for (store, entries) in stores {
+        store.clear()?;
+
+        for (key, value) in entries {
+            store.put_key_val(&key, &value)?;
+        }
+    }
+
+    tx.object_store(database.marker_store)?.put_key_val(&marker, &JsValue::TRUE)?;
+    tx.await.into_result()?;
+
+    Ok(())
+}
+
+async fn remove_marker<E>(database: &EncryptedDatabase<'_>) -> Result<(), E>
+where
+    E: From<DomException>,
+{
+    let tx = database
+        .db
+        .transaction_on_one_with_mode(database.marker_store, IdbTransactionMode::Readwrite)?;
+    tx.object_store(database.marker_store)?.delete(&JsValue::from_str(ROTATION_MARKER))?;
+    tx.await.into_result()?;
+
+    Ok(())
+}
+
+/// Hash again the hashed segments of the given key with the newest key of the
+/// store cipher.
+fn rehash_key(key: &str, new_cipher: &StoreCipher) -> String {
+    key.split(KEY_SEPARATOR)
+        .map(|segment| match STANDARD_NO_PAD.decode(segment) {
+            Ok(hash) if hash.len() == 32 => STANDARD_NO_PAD.encode(new_cipher.rehash_key(&hash)),
+            _ => segment.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(KEY_SEPARATOR)
+}
+
+/// Encrypt the given value again with the new store cipher, if it is
+/// encrypted.
+fn reencrypt_value<E>(
+    value: JsValue,
+    old_cipher: &StoreCipher,
+    new_cipher: &StoreCipher,
+) -> Result<JsValue, E>
+where
+    E: From<serde_json::Error> + From<EncryptionError>,
+{
+    // The state store saves the encrypted value as an object.
+    if let Ok(encrypted) = value.into_serde::<EncryptedValue>() {
+        let encrypted = old_cipher.reencrypt_value_data(encrypted, new_cipher)?;
+        return Ok(JsValue::from_serde(&encrypted)?);
+    }
+
+    // The crypto store saves the JSON of the encrypted value as bytes.
+    if let Some(encrypted) = value
+        .into_serde::<Vec<u8>>()
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<EncryptedValue>(&bytes).ok())
+    {
+        let encrypted = old_cipher.reencrypt_value_data(encrypted, new_cipher)?;
+        return Ok(JsValue::from_serde(&serde_json::to_vec(&encrypted)?)?);
+    }
+
+    Ok(value)
+}
@@ -26,7 +26,7 @@ pub const RANGE_END: &str = "\u{001E}";
 /// (though super unlikely)
 pub const ESCAPED: &str = "\u{001E}\u{001D}";
 
-const STANDARD_NO_PAD: GeneralPurpose =
+pub const STANDARD_NO_PAD: GeneralPurpose =
     GeneralPurpose::new(&alphabet::STANDARD, general_purpose::NO_PAD);
 
 /// Encode value as String/JsValue/IdbKeyRange for the JS APIs in a
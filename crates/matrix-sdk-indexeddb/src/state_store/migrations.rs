@@ -20,7 +20,7 @@ use std::{
 use gloo_utils::format::JsValueSerdeExt;
 use indexed_db_futures::{prelude::*, request::OpenDbRequest, IdbDatabase, IdbVersionChangeEvent};
 use js_sys::Date as JsDate;
-use matrix_sdk_base::{RoomInfo, StateStoreDataKey};
+use matrix_sdk_base::{store::StoreError, RoomInfo, StateStoreDataKey};
 use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
     events::{
@@ -40,7 +40,7 @@ use super::{
 };
 use crate::IndexeddbStateStoreError;
 
-const CURRENT_DB_VERSION: u32 = 6;
+pub(super) const CURRENT_DB_VERSION: u32 = 8;
 const CURRENT_META_DB_VERSION: u32 = 2;
 
 /// Sometimes Migrations can't proceed without having to drop existing
@@ -129,6 +129,36 @@ pub async fn upgrade_meta_db(
     Ok((meta_db, store_cipher))
 }
 
+/// Export the store cipher of the meta database again with a new passphrase.
+pub async fn change_meta_db_passphrase(
+    meta_db: &IdbDatabase,
+    old_passphrase: &str,
+    new_passphrase: &str,
+) -> Result<()> {
+    let tx: IdbTransaction<'_> = meta_db
+        .transaction_on_one_with_mode(keys::INTERNAL_STATE, IdbTransactionMode::Readwrite)?;
+    let ob = tx.object_store(keys::INTERNAL_STATE)?;
+
+    let Some(StoreKeyWrapper(inner)) =
+        ob.get(&JsValue::from_str(keys::STORE_KEY))?.await?.map(|v| v.into_serde()).transpose()?
+    else {
+        return Err(StoreError::UnencryptedStore.into());
+    };
+
+    let cipher = StoreCipher::import(old_passphrase, &inner)?;
+    #[cfg(not(test))]
+    let export = cipher.export(new_passphrase)?;
+    #[cfg(test)]
+    let export = cipher._insecure_export_fast_for_testing(new_passphrase)?;
+    ob.put_key_val(
+        &JsValue::from_str(keys::STORE_KEY),
+        &JsValue::from_serde(&StoreKeyWrapper(export))?,
+    )?;
+
+    tx.await.into_result()?;
+    Ok(())
+}
+
 // Helper struct for upgrading the inner DB.
 #[derive(Debug, Clone, Default)]
 pub struct OngoingMigration {
@@ -219,6 +249,12 @@ pub async fn upgrade_inner_db(
             if old_version < 6 {
                 migration.merge(migrate_to_v6(&pre_db, store_cipher).await?);
             }
+            if old_version < 7 {
+                migration.merge(migrate_to_v7());
+            }
+            if old_version < 8 {
+                migration.merge(migrate_to_v8());
+            }
         }
 
         pre_db.close();
@@ -597,6 +633,28 @@ async fn migrate_to_v6(
     })
 }
 
+/// Add the stores of the event cache.
+fn migrate_to_v7() -> OngoingMigration {
+    OngoingMigration {
+        drop_stores: Default::default(),
+        create_stores: HashSet::from_iter([
+            keys::EVENT_CHUNKS,
+            keys::EVENT_CHUNK_RANGES,
+            keys::EVENTS,
+        ]),
+        data: Default::default(),
+    }
+}
+
+/// Add the store of the media metadata.
+fn migrate_to_v8() -> OngoingMigration {
+    OngoingMigration {
+        drop_stores: Default::default(),
+        create_stores: HashSet::from_iter([keys::MEDIA_METADATA]),
+        data: Default::default(),
+    }
+}
+
 #[cfg(all(test, target_arch = "wasm32"))]
 mod tests {
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
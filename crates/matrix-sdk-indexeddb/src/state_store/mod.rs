@@ -13,7 +13,8 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeSet, HashSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::RangeInclusive,
     sync::Arc,
 };
 
@@ -23,8 +24,15 @@ use gloo_utils::format::JsValueSerdeExt;
 use indexed_db_futures::prelude::*;
 use matrix_sdk_base::{
     deserialized_responses::RawMemberEvent,
-    media::{MediaRequest, UniqueKey},
-    store::{StateChanges, StateStore, StoreError},
+    event_cache::EventChunk,
+    media::{
+        select_for_trimming, should_update_media_last_access, MediaCacheStats, MediaRequest,
+        MediaRetentionPolicy, UniqueKey,
+    },
+    store::{
+        integrity::compute_display_names, IntegrityIssue, IntegrityReport, StateChanges,
+        StateStore, StoreError,
+    },
     MinimalStateEvent, RoomInfo, RoomMemberships, StateStoreDataKey, StateStoreDataValue,
 };
 use matrix_sdk_store_encryption::{Error as EncryptionError, StoreCipher};
@@ -40,9 +48,13 @@ use ruma::{
         GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType, SyncStateEvent,
     },
     serde::Raw,
-    CanonicalJsonObject, EventId, MxcUri, OwnedEventId, OwnedUserId, RoomId, RoomVersionId, UserId,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId, OwnedUserId,
+    RoomId, RoomVersionId, UserId,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
 };
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::{debug, warn};
 use wasm_bindgen::JsValue;
 use web_sys::IdbKeyRange;
@@ -50,8 +62,16 @@ use web_sys::IdbKeyRange;
 mod migrations;
 
 pub use self::migrations::MigrationConflictStrategy;
-use self::migrations::{upgrade_inner_db, upgrade_meta_db};
-use crate::safe_encode::SafeEncode;
+use self::migrations::{
+    change_meta_db_passphrase, upgrade_inner_db, upgrade_meta_db, CURRENT_DB_VERSION,
+};
+use crate::{
+    rotate::{
+        has_pending_rotation, resume_rotation, rotate_store_cipher, CipherLocation,
+        EncryptedDatabase,
+    },
+    safe_encode::{SafeEncode, KEY_SEPARATOR},
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum IndexeddbStateStoreError {
@@ -65,6 +85,8 @@ pub enum IndexeddbStateStoreError {
     StoreError(#[from] StoreError),
     #[error("Can't migrate {name} from {old_version} to {new_version} without deleting data. See MigrationConflictStrategy for ways to configure.")]
     MigrationConflict { name: String, old_version: u32, new_version: u32 },
+    #[error("the store is used by other handles")]
+    InUse,
 }
 
 impl From<indexed_db_futures::web_sys::DomException> for IndexeddbStateStoreError {
@@ -111,6 +133,11 @@ mod keys {
     pub const ROOM_EVENT_RECEIPTS: &str = "room_event_receipts";
 
     pub const MEDIA: &str = "media";
+    pub const MEDIA_METADATA: &str = "media_metadata";
+
+    pub const EVENT_CHUNKS: &str = "event_chunks";
+    pub const EVENT_CHUNK_RANGES: &str = "event_chunk_ranges";
+    pub const EVENTS: &str = "events";
 
     pub const CUSTOM: &str = "custom";
     pub const KV: &str = "kv";
@@ -131,6 +158,10 @@ mod keys {
         ROOM_USER_RECEIPTS,
         ROOM_EVENT_RECEIPTS,
         MEDIA,
+        MEDIA_METADATA,
+        EVENT_CHUNKS,
+        EVENT_CHUNK_RANGES,
+        EVENTS,
         CUSTOM,
         KV,
     ];
@@ -138,6 +169,7 @@ mod keys {
     // static keys
 
     pub const STORE_KEY: &str = "store_key";
+    pub const NEXT_STORE_KEY: &str = "next_store_key";
 }
 
 pub use keys::ALL_STORES;
@@ -233,7 +265,16 @@ impl IndexeddbStateStoreBuilder {
         let inner =
             upgrade_inner_db(&name, store_cipher.as_deref(), migration_strategy, &meta).await?;
 
-        Ok(IndexeddbStateStore { name, inner, meta, store_cipher })
+        let mut store = IndexeddbStateStore { name, inner, meta, store_cipher };
+
+        if let Some(passphrase) = &self.passphrase {
+            // Finish a rotation of the store cipher that was interrupted.
+            if let Some(store_cipher) = store.rotate_store_cipher(passphrase, true).await? {
+                store.store_cipher = Some(Arc::new(store_cipher));
+            }
+        }
+
+        Ok(store)
     }
 }
 
@@ -268,6 +309,92 @@ impl IndexeddbStateStore {
         self.meta.version() as u32
     }
 
+    /// Change the passphrase that is used to encrypt the store cipher of this
+    /// store.
+    ///
+    /// The data of the store is not re-encrypted, only the store cipher is
+    /// exported again with the new passphrase.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        change_meta_db_passphrase(&self.meta, old_passphrase, new_passphrase).await
+    }
+
+    /// Rotate the keys that are used to encrypt the data of this store.
+    ///
+    /// All the values of the store are re-encrypted with a new key and all the
+    /// hashed keys are hashed again with a new key, in a single transaction.
+    /// This includes the data of the crypto store with the same name that
+    /// shares the store cipher of this store, as opened by
+    /// [`make_store_config()`], in a second transaction. The new store cipher
+    /// is encrypted with the given passphrase. If this is interrupted, it is
+    /// finished the next time the store is opened.
+    ///
+    /// Returns the store using the new keys. This fails if the store cipher is
+    /// used by other handles, like the crypto store that shares it, since they
+    /// would keep using the old keys, so this should be called before the
+    /// stores are given to a client.
+    ///
+    /// [`make_store_config()`]: crate::make_store_config
+    pub async fn rotate_encryption_key(self, passphrase: &str) -> Result<Self> {
+        let Some(store_cipher) = &self.store_cipher else {
+            return Err(StoreError::UnencryptedStore.into());
+        };
+
+        if Arc::strong_count(store_cipher) > 1 {
+            return Err(IndexeddbStateStoreError::InUse);
+        }
+
+        let Some(store_cipher) = self.rotate_store_cipher(passphrase, false).await? else {
+            return Err(StoreError::UnencryptedStore.into());
+        };
+
+        Ok(Self { store_cipher: Some(Arc::new(store_cipher)), ..self })
+    }
+
+    /// Rotate the store cipher of this store and of the crypto store that
+    /// shares it, or finish a rotation that was interrupted if `resume` is
+    /// true.
+    async fn rotate_store_cipher(
+        &self,
+        passphrase: &str,
+        resume: bool,
+    ) -> Result<Option<StoreCipher>> {
+        let location = CipherLocation {
+            db: &self.meta,
+            store: keys::INTERNAL_STATE,
+            key: keys::STORE_KEY,
+            next_key: keys::NEXT_STORE_KEY,
+        };
+
+        if resume && !has_pending_rotation::<IndexeddbStateStoreError>(&location).await? {
+            return Ok(None);
+        }
+
+        #[cfg(feature = "e2e-encryption")]
+        let crypto_store = crate::IndexeddbCryptoStore::open_with_shared_store_cipher(&self.name)
+            .await
+            .map_err(StoreError::backend)?;
+
+        #[allow(unused_mut)]
+        let mut databases = vec![EncryptedDatabase {
+            db: &self.inner,
+            plain_key_stores: &[keys::CUSTOM],
+            hashed_value_stores: &[],
+            marker_store: keys::KV,
+        }];
+        #[cfg(feature = "e2e-encryption")]
+        databases.extend(crypto_store.as_ref().map(|store| store.encrypted_database()));
+
+        if resume {
+            resume_rotation(&location, &databases, passphrase).await
+        } else {
+            rotate_store_cipher(&location, &databases, passphrase).await
+        }
+    }
+
     /// Whether this database has any migration backups
     pub async fn has_backups(&self) -> Result<bool> {
         Ok(self
@@ -290,6 +417,207 @@ impl IndexeddbStateStore {
             .and_then(|c| c.value().as_string()))
     }
 
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks the schema version of the database, that the values can be
+    /// decrypted and deserialized, that the members, profiles and state events
+    /// belong to rooms with a room info, and that the display name maps match
+    /// the members. See [`IntegrityIssue`] for what the repair does for each
+    /// problem.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        if self.version() != CURRENT_DB_VERSION {
+            let issue = IntegrityIssue::SchemaVersion {
+                found: Some(self.version() as usize),
+                expected: CURRENT_DB_VERSION as usize,
+            };
+            return Ok(IntegrityReport {
+                issues: vec![issue],
+                repaired: false,
+            });
+        }
+
+        let mode = if repair {
+            IdbTransactionMode::Readwrite
+        } else {
+            IdbTransactionMode::Readonly
+        };
+        let tx = self
+            .inner
+            .transaction_on_multi_with_mode(keys::ALL_STORES, mode)?;
+        let mut issues = Vec::new();
+        let mut room_ids = Vec::new();
+        let mut joined_room_ids = Vec::new();
+
+        for &store_name in keys::ALL_STORES {
+            let store = tx.object_store(store_name)?;
+            let mut broken_keys = Vec::new();
+
+            if let Some(cursor) = store.open_cursor()?.await? {
+                while let Some(key) = cursor.key() {
+                    let value = cursor.value();
+
+                    let is_valid = if store_name == keys::ROOM_INFOS
+                        || store_name == keys::STRIPPED_ROOM_INFOS
+                    {
+                        match self.deserialize_event::<RoomInfo>(&value) {
+                            Ok(room_info) => {
+                                // Display names are only tracked for the
+                                // members of non-stripped rooms.
+                                if store_name == keys::ROOM_INFOS {
+                                    joined_room_ids.push(room_info.room_id().to_owned());
+                                }
+                                room_ids.push(room_info.room_id().to_owned());
+                                true
+                            }
+                            Err(_) => false,
+                        }
+                    } else {
+                        self.deserialize_event::<IgnoredAny>(&value).is_ok()
+                    };
+
+                    if !is_valid {
+                        broken_keys.push(key);
+                    }
+
+                    cursor.continue_cursor()?.await?;
+                }
+            }
+
+            if broken_keys.is_empty() {
+                continue;
+            }
+
+            issues.push(IntegrityIssue::CorruptedValues {
+                table: store_name.to_owned(),
+                count: broken_keys.len(),
+            });
+
+            if repair {
+                for key in &broken_keys {
+                    store.delete(key)?;
+                }
+
+                if store_name == keys::ROOM_INFOS || store_name == keys::STRIPPED_ROOM_INFOS {
+                    // Sync again from scratch to restore the removed rooms.
+                    tx.object_store(keys::KV)?
+                        .delete(&self.encode_kv_data_key(StateStoreDataKey::SyncToken))?;
+                }
+            }
+        }
+
+        for store_name in [
+            keys::PROFILES,
+            keys::DISPLAY_NAMES,
+            keys::USER_IDS,
+            keys::ROOM_STATE,
+            keys::STRIPPED_USER_IDS,
+            keys::STRIPPED_ROOM_STATE,
+        ] {
+            let store = tx.object_store(store_name)?;
+            let known_prefixes: HashSet<String> = room_ids
+                .iter()
+                .filter_map(|room_id| self.encode_key(store_name, room_id).as_string())
+                .collect();
+            let mut orphans = HashSet::new();
+
+            for key in store.get_all_keys()?.await?.iter() {
+                let key_string = key.as_string();
+                let prefix = key_string
+                    .as_deref()
+                    .and_then(|key| key.split(KEY_SEPARATOR).next());
+
+                if prefix.map_or(true, |prefix| !known_prefixes.contains(prefix)) {
+                    orphans.insert(prefix.map(ToOwned::to_owned));
+
+                    if repair {
+                        store.delete(&key)?;
+                    }
+                }
+            }
+
+            if !orphans.is_empty() {
+                issues.push(IntegrityIssue::OrphanedRoomData {
+                    table: store_name.to_owned(),
+                    rooms: orphans.len(),
+                });
+            }
+        }
+
+        tx.await.into_result()?;
+
+        // The display names can only be computed reliably once the broken
+        // values are gone.
+        if repair || issues.is_empty() {
+            let mut inconsistent_rooms = 0;
+
+            for room_id in joined_room_ids {
+                let expected: BTreeMap<String, Option<BTreeSet<OwnedUserId>>> =
+                    compute_display_names(self, &room_id)
+                        .await?
+                        .into_iter()
+                        .filter_map(|(name, user_ids)| {
+                            let key = self.encode_key(keys::DISPLAY_NAMES, (&room_id, &name));
+                            Some((key.as_string()?, Some(user_ids)))
+                        })
+                        .collect();
+
+                let tx = self
+                    .inner
+                    .transaction_on_one_with_mode(keys::DISPLAY_NAMES, mode)?;
+                let store = tx.object_store(keys::DISPLAY_NAMES)?;
+                let range = self.encode_to_range(keys::DISPLAY_NAMES, &room_id)?;
+                let mut stored = BTreeMap::new();
+
+                if let Some(cursor) = store.open_cursor_with_range(&range)?.await? {
+                    while let Some(key) = cursor.key() {
+                        let user_ids: Option<BTreeSet<OwnedUserId>> =
+                            self.deserialize_event(&cursor.value()).ok();
+
+                        // Empty sets are kept when the last user with a
+                        // display name changes it.
+                        if user_ids
+                            .as_ref()
+                            .map_or(true, |user_ids| !user_ids.is_empty())
+                        {
+                            stored.insert(key.as_string().unwrap_or_default(), user_ids);
+                        }
+
+                        cursor.continue_cursor()?.await?;
+                    }
+                }
+
+                if stored != expected {
+                    inconsistent_rooms += 1;
+
+                    if repair {
+                        for key in store.get_all_keys_with_key(&range)?.await?.iter() {
+                            store.delete(&key)?;
+                        }
+                        for (key, user_ids) in expected {
+                            store.put_key_val(
+                                &JsValue::from(key),
+                                &self.serialize_event(&user_ids)?,
+                            )?;
+                        }
+                    }
+                }
+
+                tx.await.into_result()?;
+            }
+
+            if inconsistent_rooms > 0 {
+                issues.push(IntegrityIssue::InconsistentDisplayNames {
+                    rooms: inconsistent_rooms,
+                });
+            }
+        }
+
+        Ok(IntegrityReport {
+            issues,
+            repaired: repair,
+        })
+    }
+
     fn serialize_event(&self, event: &impl Serialize) -> Result<JsValue> {
         serialize_event(self.store_cipher.as_deref(), event)
     }
@@ -370,6 +698,35 @@ impl IndexeddbStateStore {
             .transpose()
     }
 
+    /// Get the key, size and time of last access of all the media content.
+    ///
+    /// Media content that was added before the size and time of last access
+    /// were tracked is considered accessed now.
+    async fn media_entries(
+        &self,
+        media: &IdbObjectStore<'_>,
+        media_metadata: &IdbObjectStore<'_>,
+    ) -> Result<Vec<(JsValue, usize, MilliSecondsSinceUnixEpoch)>> {
+        let mut entries = Vec::new();
+
+        for key in media.get_all_keys()?.await?.iter() {
+            let (size, last_access) = match media_metadata.get(&key)?.await? {
+                Some(metadata) => self.deserialize_event(&metadata)?,
+                None => {
+                    let Some(data) = media.get(&key)?.await? else { continue };
+                    let data: Vec<u8> = self.deserialize_event(&data)?;
+                    let metadata = (data.len(), MilliSecondsSinceUnixEpoch::now());
+                    media_metadata.put_key_val(&key, &self.serialize_event(&metadata)?)?;
+                    metadata
+                }
+            };
+
+            entries.push((key, size, last_access));
+        }
+
+        Ok(entries)
+    }
+
     fn encode_kv_data_key(&self, key: StateStoreDataKey<'_>) -> JsValue {
         // Use the key (prefix) for the table name as well, to keep encoded
         // keys compatible for the sync token and filters, which were in
@@ -532,6 +889,10 @@ impl_state_store! {
             stores.extend([keys::ROOM_EVENT_RECEIPTS, keys::ROOM_USER_RECEIPTS])
         }
 
+        if !changes.event_cache.is_empty() {
+            stores.extend([keys::EVENT_CHUNKS, keys::EVENT_CHUNK_RANGES, keys::EVENTS]);
+        }
+
         if stores.is_empty() {
             // nothing to do, quit early
             return Ok(());
@@ -802,6 +1163,88 @@ impl_state_store! {
             }
         }
 
+        if !changes.event_cache.is_empty() {
+            let chunks = tx.object_store(keys::EVENT_CHUNKS)?;
+            let ranges = tx.object_store(keys::EVENT_CHUNK_RANGES)?;
+            let events = tx.object_store(keys::EVENTS)?;
+
+            for (room_id, cache_changes) in &changes.event_cache {
+                let range_key = self.encode_key(keys::EVENT_CHUNK_RANGES, room_id);
+
+                let mut range = if cache_changes.reset {
+                    for (store, store_name) in [(&chunks, keys::EVENT_CHUNKS), (&events, keys::EVENTS)] {
+                        let key_range = self.encode_to_range(store_name, room_id)?;
+                        for key in store.get_all_keys_with_key(&key_range)?.await?.iter() {
+                            store.delete(&key)?;
+                        }
+                    }
+
+                    None
+                } else {
+                    ranges
+                        .get(&range_key)?
+                        .await?
+                        .map(|f| self.deserialize_event::<(i64, i64)>(&f))
+                        .transpose()?
+                };
+
+                for chunk_id in &cache_changes.removed_chunks {
+                    let chunk_key =
+                        self.encode_key(keys::EVENT_CHUNKS, (room_id, chunk_id.to_string()));
+
+                    if let Some(old_chunk) = chunks.get(&chunk_key)?.await? {
+                        let old_chunk = self.deserialize_event::<EventChunk>(&old_chunk)?;
+                        for event_id in old_chunk.event_ids() {
+                            events.delete(&self.encode_key(keys::EVENTS, (room_id, &event_id)))?;
+                        }
+                    }
+
+                    chunks.delete(&chunk_key)?;
+                }
+
+                // The removed chunks are the oldest ones, so the range now
+                // starts after them.
+                if let Some((mut start, end)) = range {
+                    while cache_changes.removed_chunks.contains(&start) {
+                        start += 1;
+                    }
+                    range = (start <= end).then_some((start, end));
+                }
+
+                for (chunk_id, chunk) in &cache_changes.chunks {
+                    let chunk_key =
+                        self.encode_key(keys::EVENT_CHUNKS, (room_id, chunk_id.to_string()));
+
+                    // Forget the events of the previous version of the chunk.
+                    if let Some(old_chunk) = chunks.get(&chunk_key)?.await? {
+                        let old_chunk = self.deserialize_event::<EventChunk>(&old_chunk)?;
+                        for event_id in old_chunk.event_ids() {
+                            events.delete(&self.encode_key(keys::EVENTS, (room_id, &event_id)))?;
+                        }
+                    }
+
+                    for event_id in chunk.event_ids() {
+                        events.put_key_val(
+                            &self.encode_key(keys::EVENTS, (room_id, &event_id)),
+                            &self.serialize_event(chunk_id)?,
+                        )?;
+                    }
+
+                    chunks.put_key_val(&chunk_key, &self.serialize_event(chunk)?)?;
+
+                    range = Some(match range {
+                        Some((start, end)) => (start.min(*chunk_id), end.max(*chunk_id)),
+                        None => (*chunk_id, *chunk_id),
+                    });
+                }
+
+                match range {
+                    Some(range) => ranges.put_key_val(&range_key, &self.serialize_event(&range)?)?,
+                    None => ranges.delete(&range_key)?,
+                }
+            }
+        }
+
         tx.await.into_result().map_err(|e| e.into())
     }
 
@@ -1014,9 +1457,14 @@ impl_state_store! {
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
         let key = self
             .encode_key(keys::MEDIA, (request.source.unique_key(), request.format.unique_key()));
-        let tx =
-            self.inner.transaction_on_one_with_mode(keys::MEDIA, IdbTransactionMode::Readwrite)?;
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
 
+        let metadata = (data.len(), MilliSecondsSinceUnixEpoch::now());
+        tx.object_store(keys::MEDIA_METADATA)?
+            .put_key_val(&key, &self.serialize_event(&metadata)?)?;
         tx.object_store(keys::MEDIA)?.put_key_val(&key, &self.serialize_event(&data)?)?;
 
         tx.await.into_result().map_err(|e| e.into())
@@ -1025,13 +1473,86 @@ impl_state_store! {
     async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
         let key = self
             .encode_key(keys::MEDIA, (request.source.unique_key(), request.format.unique_key()));
-        self.inner
-            .transaction_on_one_with_mode(keys::MEDIA, IdbTransactionMode::Readonly)?
-            .object_store(keys::MEDIA)?
-            .get(&key)?
-            .await?
-            .map(|f| self.deserialize_event(&f))
-            .transpose()
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+
+        let Some(data) = tx.object_store(keys::MEDIA)?.get(&key)?.await? else {
+            return Ok(None);
+        };
+        let data: Vec<u8> = self.deserialize_event(&data)?;
+
+        let media_metadata = tx.object_store(keys::MEDIA_METADATA)?;
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let update_last_access = match media_metadata.get(&key)?.await? {
+            Some(metadata) => {
+                let (_, last_access): (usize, MilliSecondsSinceUnixEpoch) =
+                    self.deserialize_event(&metadata)?;
+                should_update_media_last_access(last_access, now)
+            }
+            None => true,
+        };
+
+        if update_last_access {
+            media_metadata.put_key_val(&key, &self.serialize_event(&(data.len(), now))?)?;
+        }
+
+        tx.await.into_result().map_err(IndexeddbStateStoreError::from)?;
+        Ok(Some(data))
+    }
+
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<()> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+        let media = tx.object_store(keys::MEDIA)?;
+        let media_metadata = tx.object_store(keys::MEDIA_METADATA)?;
+
+        let entries = self.media_entries(&media, &media_metadata).await?;
+        for key in policy.select_for_removal(entries, MilliSecondsSinceUnixEpoch::now()) {
+            media.delete(&key)?;
+            media_metadata.delete(&key)?;
+        }
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+        let media = tx.object_store(keys::MEDIA)?;
+        let media_metadata = tx.object_store(keys::MEDIA_METADATA)?;
+
+        let entries = self.media_entries(&media, &media_metadata).await?;
+        let (keys, total_size) = select_for_trimming(entries, max_size);
+        for key in keys {
+            media.delete(&key)?;
+            media_metadata.delete(&key)?;
+        }
+
+        tx.await.into_result().map_err(IndexeddbStateStoreError::from)?;
+        Ok(total_size)
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
+        let media = tx.object_store(keys::MEDIA)?;
+        let media_metadata = tx.object_store(keys::MEDIA_METADATA)?;
+
+        let entries = self.media_entries(&media, &media_metadata).await?;
+        tx.await.into_result().map_err(IndexeddbStateStoreError::from)?;
+
+        Ok(MediaCacheStats {
+            count: entries.len(),
+            total_size: entries.iter().map(|(_, size, _)| size).sum(),
+        })
     }
 
     async fn get_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -1070,29 +1591,90 @@ impl_state_store! {
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
         let key = self
             .encode_key(keys::MEDIA, (request.source.unique_key(), request.format.unique_key()));
-        let tx =
-            self.inner.transaction_on_one_with_mode(keys::MEDIA, IdbTransactionMode::Readwrite)?;
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
 
         tx.object_store(keys::MEDIA)?.delete(&key)?;
+        tx.object_store(keys::MEDIA_METADATA)?.delete(&key)?;
 
         tx.await.into_result().map_err(|e| e.into())
     }
 
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
         let range = self.encode_to_range(keys::MEDIA, uri)?;
-        let tx =
-            self.inner.transaction_on_one_with_mode(keys::MEDIA, IdbTransactionMode::Readwrite)?;
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::MEDIA, keys::MEDIA_METADATA],
+            IdbTransactionMode::Readwrite,
+        )?;
         let store = tx.object_store(keys::MEDIA)?;
+        let metadata_store = tx.object_store(keys::MEDIA_METADATA)?;
 
         for k in store.get_all_keys_with_key(&range)?.await?.iter() {
             store.delete(&k)?;
+            metadata_store.delete(&k)?;
         }
 
         tx.await.into_result().map_err(|e| e.into())
     }
 
+    async fn get_event_chunk_range(&self, room_id: &RoomId) -> Result<Option<RangeInclusive<i64>>> {
+        Ok(self
+            .inner
+            .transaction_on_one_with_mode(keys::EVENT_CHUNK_RANGES, IdbTransactionMode::Readonly)?
+            .object_store(keys::EVENT_CHUNK_RANGES)?
+            .get(&self.encode_key(keys::EVENT_CHUNK_RANGES, room_id))?
+            .await?
+            .map(|f| self.deserialize_event::<(i64, i64)>(&f))
+            .transpose()?
+            .map(|(start, end)| start..=end))
+    }
+
+    async fn get_event_chunk(&self, room_id: &RoomId, chunk_id: i64) -> Result<Option<EventChunk>> {
+        self.inner
+            .transaction_on_one_with_mode(keys::EVENT_CHUNKS, IdbTransactionMode::Readonly)?
+            .object_store(keys::EVENT_CHUNKS)?
+            .get(&self.encode_key(keys::EVENT_CHUNKS, (room_id, chunk_id.to_string())))?
+            .await?
+            .map(|f| self.deserialize_event(&f))
+            .transpose()
+    }
+
+    async fn get_event_chunk_id(&self, room_id: &RoomId, event_id: &EventId) -> Result<Option<i64>> {
+        self.inner
+            .transaction_on_one_with_mode(keys::EVENTS, IdbTransactionMode::Readonly)?
+            .object_store(keys::EVENTS)?
+            .get(&self.encode_key(keys::EVENTS, (room_id, event_id)))?
+            .await?
+            .map(|f| self.deserialize_event(&f))
+            .transpose()
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>> {
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(keys::EVENTS, IdbTransactionMode::Readonly)?;
+        let store = tx.object_store(keys::EVENTS)?;
+        let mut chunk_ids = BTreeMap::new();
+
+        for event_id in event_ids {
+            if let Some(chunk_id) =
+                store.get(&self.encode_key(keys::EVENTS, (room_id, event_id)))?.await?
+            {
+                chunk_ids.insert(event_id.clone(), self.deserialize_event(&chunk_id)?);
+            }
+        }
+
+        Ok(chunk_ids)
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
-        let direct_stores = [keys::ROOM_INFOS, keys::STRIPPED_ROOM_INFOS];
+        let direct_stores = [keys::ROOM_INFOS, keys::STRIPPED_ROOM_INFOS, keys::EVENT_CHUNK_RANGES];
 
         let prefixed_stores = [
             keys::PROFILES,
@@ -1104,6 +1686,8 @@ impl_state_store! {
             keys::ROOM_USER_RECEIPTS,
             keys::STRIPPED_ROOM_STATE,
             keys::STRIPPED_USER_IDS,
+            keys::EVENT_CHUNKS,
+            keys::EVENTS,
         ];
 
         let all_stores = {
@@ -1131,6 +1715,19 @@ impl_state_store! {
         tx.await.into_result().map_err(|e| e.into())
     }
 
+    async fn clear(&self) -> Result<()> {
+        // The store cipher lives in the meta database, so it is kept.
+        let tx = self
+            .inner
+            .transaction_on_multi_with_mode(keys::ALL_STORES, IdbTransactionMode::Readwrite)?;
+
+        for &store_name in keys::ALL_STORES {
+            tx.object_store(store_name)?.clear()?;
+        }
+
+        tx.await.into_result().map_err(|e| e.into())
+    }
+
     async fn get_user_ids(&self, room_id: &RoomId, memberships: RoomMemberships) -> Result<Vec<OwnedUserId>> {
         let ids = self.get_user_ids_inner(room_id, memberships, true).await?;
         if !ids.is_empty() {
@@ -1190,10 +1787,16 @@ mod encrypted_tests {
     #[cfg(target_arch = "wasm32")]
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
-    use matrix_sdk_base::statestore_integration_tests;
+    use matrix_sdk_base::{
+        statestore_integration_tests, StateStore, StateStoreDataKey, StateStoreDataValue,
+    };
+    use matrix_sdk_crypto::store::{Changes, CryptoStore, RoomSettings};
+    use matrix_sdk_test::async_test;
+    use ruma::room_id;
     use uuid::Uuid;
 
     use super::{IndexeddbStateStore, Result};
+    use crate::IndexeddbCryptoStore;
 
     async fn get_store() -> Result<IndexeddbStateStore> {
         let db_name = format!("test-state-encrypted-{}", Uuid::new_v4().as_hyphenated());
@@ -1202,4 +1805,49 @@ mod encrypted_tests {
     }
 
     statestore_integration_tests!(with_media_tests);
+
+    #[async_test]
+    async fn rotate_encryption_key() {
+        let name = format!("test-state-rotate-{}", Uuid::new_v4().as_hyphenated());
+        let builder =
+            || IndexeddbStateStore::builder().name(name.clone()).passphrase("password".to_owned());
+        let room_id = room_id!("!test:localhost");
+        let settings = RoomSettings { only_allow_trusted_devices: true, ..RoomSettings::default() };
+
+        let store = builder().build().await.unwrap();
+        store
+            .set_kv_data(StateStoreDataKey::SyncToken, StateStoreDataValue::SyncToken("t".into()))
+            .await
+            .unwrap();
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+
+        let crypto_store =
+            IndexeddbCryptoStore::open_with_store_cipher(&name, store.store_cipher.clone())
+                .await
+                .unwrap();
+        let mut changes = Changes::default();
+        changes.room_settings.insert(room_id.to_owned(), settings.clone());
+        crypto_store.save_changes(changes).await.unwrap();
+
+        // The store can't be rotated while the crypto store uses its store
+        // cipher.
+        assert!(store.rotate_encryption_key("password").await.is_err());
+        drop(crypto_store);
+
+        let store = builder().build().await.unwrap();
+        let store = store.rotate_encryption_key("password").await.unwrap();
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        drop(store);
+
+        let store = builder().build().await.unwrap();
+        let sync_token = store.get_kv_data(StateStoreDataKey::SyncToken).await.unwrap();
+        assert_eq!(sync_token.and_then(|v| v.into_sync_token()).as_deref(), Some("t"));
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+
+        let crypto_store =
+            IndexeddbCryptoStore::open_with_store_cipher(&name, store.store_cipher.clone())
+                .await
+                .unwrap();
+        assert_eq!(crypto_store.get_room_settings(room_id).await.unwrap(), Some(settings));
+    }
 }
@@ -0,0 +1,213 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use matrix_sdk_common::AsyncTraitDeps;
+
+/// A minimal transactional key-value database.
+///
+/// The entries are grouped in tables, that a backend can map to separate trees,
+/// column families, or simply use as a prefix of the keys. The keys and values
+/// are opaque bytes, they are already hashed and encrypted when the store uses
+/// a passphrase.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait KvBackend: AsyncTraitDeps {
+    /// The error type used by this backend.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Get the value of the given key in the given table.
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Get all the entries of the given table whose key starts with the given
+    /// prefix, ordered by key.
+    ///
+    /// An empty prefix returns all the entries of the table.
+    async fn scan_prefix(
+        &self,
+        table: &str,
+        prefix: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error>;
+
+    /// Apply all the operations of the given transaction atomically, in order.
+    async fn commit(&self, transaction: KvTransaction) -> Result<(), Self::Error>;
+
+    /// Set the value of the given key in the given table, only if its current
+    /// value is `expected`, or if it doesn't exist when `expected` is `None`.
+    ///
+    /// The comparison and the write must be atomic, including between
+    /// processes that share the same database, since it is used to implement
+    /// the locks of the crypto store.
+    ///
+    /// Returns whether the value was set.
+    async fn compare_and_swap(
+        &self,
+        table: &str,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        value: Vec<u8>,
+    ) -> Result<bool, Self::Error>;
+
+    /// Set the value of the given key in the given table.
+    async fn put(&self, table: &str, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut transaction = KvTransaction::new();
+        transaction.put(table, key, value);
+        self.commit(transaction).await
+    }
+
+    /// Remove the given key from the given table.
+    async fn delete(&self, table: &str, key: Vec<u8>) -> Result<(), Self::Error> {
+        let mut transaction = KvTransaction::new();
+        transaction.delete(table, key);
+        self.commit(transaction).await
+    }
+}
+
+/// A write operation in a [`KvTransaction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvOperation {
+    /// Set the value of a key.
+    Put {
+        /// The table of the entry.
+        table: String,
+        /// The key of the entry.
+        key: Vec<u8>,
+        /// The new value of the entry.
+        value: Vec<u8>,
+    },
+    /// Remove a key.
+    Delete {
+        /// The table of the entry.
+        table: String,
+        /// The key of the entry.
+        key: Vec<u8>,
+    },
+}
+
+/// A list of write operations that must be applied atomically by a
+/// [`KvBackend`].
+#[derive(Debug, Clone, Default)]
+pub struct KvTransaction {
+    operations: Vec<KvOperation>,
+}
+
+impl KvTransaction {
+    /// Create a new empty `KvTransaction`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of the given key in the given table.
+    pub fn put(&mut self, table: &str, key: Vec<u8>, value: Vec<u8>) {
+        self.operations.push(KvOperation::Put { table: table.to_owned(), key, value });
+    }
+
+    /// Remove the given key from the given table.
+    pub fn delete(&mut self, table: &str, key: Vec<u8>) {
+        self.operations.push(KvOperation::Delete { table: table.to_owned(), key });
+    }
+
+    /// Whether this transaction doesn't contain any operation.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// The operations of this transaction, in the order they must be applied.
+    pub fn into_operations(self) -> Vec<KvOperation> {
+        self.operations
+    }
+}
+
+/// A [`KvBackend`] that keeps all the data in memory.
+///
+/// The clones of a `MemoryKvBackend` share the same data.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKvBackend {
+    tables: Arc<RwLock<HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>>>,
+}
+
+impl MemoryKvBackend {
+    /// Create a new empty `MemoryKvBackend`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl KvBackend for MemoryKvBackend {
+    type Error = Infallible;
+
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.tables.read().unwrap().get(table).and_then(|t| t.get(key)).cloned())
+    }
+
+    async fn scan_prefix(
+        &self,
+        table: &str,
+        prefix: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Self::Error> {
+        let tables = self.tables.read().unwrap();
+        let Some(table) = tables.get(table) else { return Ok(Vec::new()) };
+
+        Ok(table
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn commit(&self, transaction: KvTransaction) -> Result<(), Self::Error> {
+        let mut tables = self.tables.write().unwrap();
+
+        for operation in transaction.into_operations() {
+            match operation {
+                KvOperation::Put { table, key, value } => {
+                    tables.entry(table).or_default().insert(key, value);
+                }
+                KvOperation::Delete { table, key } => {
+                    if let Some(table) = tables.get_mut(&table) {
+                        table.remove(&key);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn compare_and_swap(
+        &self,
+        table: &str,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        value: Vec<u8>,
+    ) -> Result<bool, Self::Error> {
+        let mut tables = self.tables.write().unwrap();
+        let table = tables.entry(table.to_owned()).or_default();
+
+        if table.get(&key).map(Vec::as_slice) != expected {
+            return Ok(false);
+        }
+
+        table.insert(key, value);
+        Ok(true)
+    }
+}
@@ -0,0 +1,829 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use matrix_sdk_base::store::{
+    encode_key::{EncodeKey, ENCODE_SEPARATOR},
+    IntegrityIssue, IntegrityReport,
+};
+use matrix_sdk_crypto::{
+    olm::{
+        IdentityKeys, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
+        PickledInboundGroupSession, PrivateCrossSigningIdentity, Session,
+    },
+    store::{
+        caches::SessionStore, BackupKeys, Changes, CryptoStore, CryptoStoreError, Result,
+        RoomKeyCounts, RoomSettings,
+    },
+    types::events::room_key_withheld::RoomKeyWithheldEvent,
+    GossipRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities, SecretInfo,
+    TrackedUser,
+};
+use matrix_sdk_store_encryption::StoreCipher;
+use ruma::{
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
+use tokio::sync::Mutex;
+
+use crate::{get_or_create_store_cipher, KvBackend, KvTransaction, OpenStoreError};
+
+// Table names that are used to derive a separate key for each table. This
+// ensure that user ids encoded for different tables won't end up as the same
+// byte sequence. This prevents corelation attacks on our table metadata.
+const ACCOUNT_TABLE: &str = "crypto-account";
+const CUSTOM_TABLE: &str = "crypto-custom";
+const DEVICE_TABLE: &str = "crypto-devices";
+const DIRECT_WITHHELD_INFO_TABLE: &str = "crypto-direct-withheld-info";
+const IDENTITIES_TABLE: &str = "crypto-identities";
+const INBOUND_GROUP_TABLE: &str = "crypto-inbound-group-sessions";
+const LEASES_TABLE: &str = "crypto-leases";
+const OLM_HASHES_TABLE: &str = "crypto-olm-hashes";
+const OUTBOUND_GROUP_TABLE: &str = "crypto-outbound-group-sessions";
+const OUTGOING_SECRET_REQUESTS_TABLE: &str = "crypto-outgoing-secret-requests";
+const PRIVATE_IDENTITY_TABLE: &str = "crypto-private-identity";
+const ROOM_SETTINGS_TABLE: &str = "crypto-room-settings";
+const SECRET_REQUEST_BY_INFO_TABLE: &str = "crypto-secret-request-by-info";
+const SESSIONS_TABLE: &str = "crypto-sessions";
+const TRACKED_USERS_TABLE: &str = "crypto-tracked-users";
+const UNSENT_SECRET_REQUESTS_TABLE: &str = "crypto-unsent-secret-requests";
+
+#[derive(Clone, Debug)]
+struct AccountInfo {
+    user_id: OwnedUserId,
+    device_id: OwnedDeviceId,
+    identity_keys: Arc<IdentityKeys>,
+}
+
+/// A [`CryptoStore`] that persists its data in a [`KvBackend`].
+#[derive(Clone)]
+pub struct KvCryptoStore<B> {
+    backend: B,
+    store_cipher: Option<Arc<StoreCipher>>,
+    account_info: Arc<RwLock<Option<AccountInfo>>>,
+    session_cache: SessionStore,
+}
+
+impl<B: std::fmt::Debug> std::fmt::Debug for KvCryptoStore<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvCryptoStore")
+            .field("backend", &self.backend)
+            .field("encrypted", &self.store_cipher.is_some())
+            .finish()
+    }
+}
+
+impl<B: KvBackend> KvCryptoStore<B> {
+    /// Open a `KvCryptoStore` that persists its data in the given backend.
+    ///
+    /// If a passphrase is given, it is used to encrypt the data of the store.
+    /// The store cipher is shared with a [`KvStateStore`] opened on the same
+    /// backend, so they must use the same passphrase.
+    ///
+    /// [`KvStateStore`]: crate::KvStateStore
+    pub async fn open(backend: B, passphrase: Option<&str>) -> Result<Self, OpenStoreError> {
+        let store_cipher = match passphrase {
+            Some(passphrase) => {
+                Some(get_or_create_store_cipher(&backend, passphrase).await?.into())
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            backend,
+            store_cipher,
+            account_info: RwLock::new(None).into(),
+            session_cache: SessionStore::new(),
+        })
+    }
+
+    /// The backend of this store.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks that the values can be decrypted and deserialized, and
+    /// that the Olm sessions are stored with an account. See
+    /// [`IntegrityIssue`] for what the repair does for each problem.
+    ///
+    /// This store doesn't have a schema version yet, so it isn't checked. This
+    /// should be called before the store is given to a client, since the
+    /// values that are cached in memory are not updated.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        let mut issues = Vec::new();
+        let mut transaction = KvTransaction::new();
+
+        for table in [
+            ACCOUNT_TABLE,
+            CUSTOM_TABLE,
+            DEVICE_TABLE,
+            DIRECT_WITHHELD_INFO_TABLE,
+            IDENTITIES_TABLE,
+            INBOUND_GROUP_TABLE,
+            OUTBOUND_GROUP_TABLE,
+            OUTGOING_SECRET_REQUESTS_TABLE,
+            PRIVATE_IDENTITY_TABLE,
+            ROOM_SETTINGS_TABLE,
+            SESSIONS_TABLE,
+            TRACKED_USERS_TABLE,
+            UNSENT_SECRET_REQUESTS_TABLE,
+        ] {
+            let mut count = 0;
+
+            for (key, value) in self.scan(table).await? {
+                if self.deserialize_value::<IgnoredAny>(&value).is_err() {
+                    transaction.delete(table, key);
+                    count += 1;
+                }
+            }
+
+            if count > 0 {
+                issues.push(IntegrityIssue::CorruptedValues { table: table.to_owned(), count });
+            }
+        }
+
+        let has_account = self
+            .get(ACCOUNT_TABLE, &"account".encode())
+            .await?
+            .map_or(false, |value| self.deserialize_value::<IgnoredAny>(&value).is_ok());
+
+        if !has_account {
+            let sessions = self.scan(SESSIONS_TABLE).await?;
+
+            if !sessions.is_empty() {
+                issues.push(IntegrityIssue::SessionsWithoutAccount { count: sessions.len() });
+
+                for (key, _) in sessions {
+                    transaction.delete(SESSIONS_TABLE, key);
+                }
+            }
+        }
+
+        if repair {
+            self.commit(transaction).await?;
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
+    /// All the entries of the given table.
+    async fn scan(&self, table: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.backend.scan_prefix(table, &[]).await.map_err(CryptoStoreError::backend)
+    }
+
+    fn get_account_info(&self) -> Option<AccountInfo> {
+        self.account_info.read().unwrap().clone()
+    }
+
+    fn serialize_value(&self, value: &impl Serialize) -> Result<Vec<u8>> {
+        if let Some(key) = &self.store_cipher {
+            key.encrypt_value(value).map_err(CryptoStoreError::backend)
+        } else {
+            Ok(serde_json::to_vec(value)?)
+        }
+    }
+
+    fn deserialize_value<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T> {
+        if let Some(key) = &self.store_cipher {
+            key.decrypt_value(value).map_err(CryptoStoreError::backend)
+        } else {
+            Ok(serde_json::from_slice(value)?)
+        }
+    }
+
+    fn encode_key<T: EncodeKey>(&self, table_name: &str, key: T) -> Vec<u8> {
+        if let Some(store_cipher) = &self.store_cipher {
+            key.encode_secure(table_name, store_cipher).to_vec()
+        } else {
+            key.encode()
+        }
+    }
+
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.backend.get(table, key).await.map_err(CryptoStoreError::backend)
+    }
+
+    async fn get_value<T: DeserializeOwned>(&self, table: &str, key: &[u8]) -> Result<Option<T>> {
+        self.get(table, key).await?.map(|v| self.deserialize_value(&v)).transpose()
+    }
+
+    async fn scan_values<T: DeserializeOwned>(&self, table: &str, prefix: &[u8]) -> Result<Vec<T>> {
+        self.backend
+            .scan_prefix(table, prefix)
+            .await
+            .map_err(CryptoStoreError::backend)?
+            .iter()
+            .map(|(_, v)| self.deserialize_value(v))
+            .collect()
+    }
+
+    async fn commit(&self, transaction: KvTransaction) -> Result<()> {
+        if transaction.is_empty() {
+            return Ok(());
+        }
+
+        self.backend.commit(transaction).await.map_err(CryptoStoreError::backend)
+    }
+
+    async fn inbound_group_session_pickles(&self) -> Result<Vec<PickledInboundGroupSession>> {
+        self.scan_values(INBOUND_GROUP_TABLE, &[]).await
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        let pickles = self
+            .backend
+            .scan_prefix(INBOUND_GROUP_TABLE, &[])
+            .await
+            .map_err(CryptoStoreError::backend)?;
+
+        let mut transaction = KvTransaction::new();
+
+        for (key, pickle) in pickles {
+            let mut pickle: PickledInboundGroupSession = self.deserialize_value(&pickle)?;
+            pickle.backed_up = false;
+            transaction.put(INBOUND_GROUP_TABLE, key, self.serialize_value(&pickle)?);
+        }
+
+        self.commit(transaction).await
+    }
+
+    async fn load_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        self.get_value(OUTBOUND_GROUP_TABLE, &self.encode_key(OUTBOUND_GROUP_TABLE, room_id))
+            .await?
+            .map(|p| {
+                Ok(OutboundGroupSession::from_pickle(
+                    account_info.device_id,
+                    account_info.identity_keys,
+                    p,
+                )?)
+            })
+            .transpose()
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        if let Some(account) = changes.account {
+            let account_info = AccountInfo {
+                user_id: account.user_id.clone(),
+                device_id: account.device_id.clone(),
+                identity_keys: account.identity_keys.clone(),
+            };
+
+            *self.account_info.write().unwrap() = Some(account_info);
+            transaction.put(
+                ACCOUNT_TABLE,
+                "account".encode(),
+                self.serialize_value(&account.pickle().await)?,
+            );
+        }
+
+        if let Some(i) = changes.private_identity {
+            transaction.put(
+                PRIVATE_IDENTITY_TABLE,
+                "identity".encode(),
+                self.serialize_value(&i.pickle().await)?,
+            );
+        }
+
+        if let Some(r) = &changes.recovery_key {
+            transaction.put(ACCOUNT_TABLE, "recovery_key_v1".encode(), self.serialize_value(r)?);
+        }
+
+        if let Some(b) = &changes.backup_version {
+            transaction.put(ACCOUNT_TABLE, "backup_version_v1".encode(), self.serialize_value(b)?);
+        }
+
+        for session in changes.sessions {
+            let key = self.encode_key(SESSIONS_TABLE, &session);
+            let pickle = session.pickle().await;
+
+            self.session_cache.add(session).await;
+            transaction.put(SESSIONS_TABLE, key, self.serialize_value(&pickle)?);
+        }
+
+        for session in changes.inbound_group_sessions {
+            let key = self.encode_key(INBOUND_GROUP_TABLE, &session);
+            let pickle = session.pickle().await;
+
+            transaction.put(INBOUND_GROUP_TABLE, key, self.serialize_value(&pickle)?);
+        }
+
+        for session in changes.outbound_group_sessions {
+            let key = self.encode_key(OUTBOUND_GROUP_TABLE, &session);
+            let pickle = session.pickle().await;
+
+            transaction.put(OUTBOUND_GROUP_TABLE, key, self.serialize_value(&pickle)?);
+        }
+
+        let device_changes = changes.devices;
+
+        for device in device_changes.new.iter().chain(&device_changes.changed) {
+            let key = self.encode_key(DEVICE_TABLE, device);
+            transaction.put(DEVICE_TABLE, key, self.serialize_value(&device)?);
+        }
+
+        for device in &device_changes.deleted {
+            transaction.delete(DEVICE_TABLE, self.encode_key(DEVICE_TABLE, device));
+        }
+
+        let identity_changes = changes.identities;
+
+        for identity in identity_changes.changed.iter().chain(&identity_changes.new) {
+            transaction.put(
+                IDENTITIES_TABLE,
+                self.encode_key(IDENTITIES_TABLE, identity.user_id()),
+                self.serialize_value(&identity)?,
+            );
+        }
+
+        for hash in &changes.message_hashes {
+            transaction.put(OLM_HASHES_TABLE, serde_json::to_vec(hash)?, vec![0]);
+        }
+
+        for key_request in &changes.key_requests {
+            let key_request_id = key_request.request_id.encode();
+
+            transaction.put(
+                SECRET_REQUEST_BY_INFO_TABLE,
+                self.encode_key(SECRET_REQUEST_BY_INFO_TABLE, &key_request.info),
+                key_request_id.clone(),
+            );
+
+            let (table, other_table) = if key_request.sent_out {
+                (OUTGOING_SECRET_REQUESTS_TABLE, UNSENT_SECRET_REQUESTS_TABLE)
+            } else {
+                (UNSENT_SECRET_REQUESTS_TABLE, OUTGOING_SECRET_REQUESTS_TABLE)
+            };
+
+            transaction.delete(other_table, key_request_id.clone());
+            transaction.put(table, key_request_id, self.serialize_value(&key_request)?);
+        }
+
+        for (room_id, data) in &changes.withheld_session_info {
+            for (session_id, event) in data {
+                let key = self.encode_key(DIRECT_WITHHELD_INFO_TABLE, (session_id, room_id));
+                transaction.put(DIRECT_WITHHELD_INFO_TABLE, key, self.serialize_value(&event)?);
+            }
+        }
+
+        for (room_id, settings) in &changes.room_settings {
+            let key = self.encode_key(ROOM_SETTINGS_TABLE, room_id);
+            transaction.put(ROOM_SETTINGS_TABLE, key, self.serialize_value(&settings)?);
+        }
+
+        self.commit(transaction).await
+    }
+
+    async fn get_outgoing_key_request_helper(&self, id: &[u8]) -> Result<Option<GossipRequest>> {
+        if let Some(request) = self.get_value(OUTGOING_SECRET_REQUESTS_TABLE, id).await? {
+            return Ok(Some(request));
+        }
+
+        self.get_value(UNSENT_SECRET_REQUESTS_TABLE, id).await
+    }
+
+    /// Save a batch of tracked users.
+    ///
+    /// # Arguments
+    ///
+    /// * `tracked_users` - A list of tuples. The first element of the tuple is
+    /// the user ID, the second element is if the user should be considered to
+    /// be dirty.
+    pub async fn save_tracked_users(&self, tracked_users: &[(&UserId, bool)]) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        for (user_id, dirty) in tracked_users {
+            let user = TrackedUser { user_id: (*user_id).into(), dirty: *dirty };
+            transaction.put(
+                TRACKED_USERS_TABLE,
+                self.encode_key(TRACKED_USERS_TABLE, user.user_id.as_str()),
+                self.serialize_value(&user)?,
+            );
+        }
+
+        self.commit(transaction).await
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl<B: KvBackend> CryptoStore for KvCryptoStore<B> {
+    type Error = CryptoStoreError;
+
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        let Some(pickle) = self.get_value(ACCOUNT_TABLE, &"account".encode()).await? else {
+            return Ok(None);
+        };
+
+        let account = ReadOnlyAccount::from_pickle(pickle)?;
+
+        let account_info = AccountInfo {
+            user_id: account.user_id.clone(),
+            device_id: account.device_id.clone(),
+            identity_keys: account.identity_keys.clone(),
+        };
+
+        *self.account_info.write().unwrap() = Some(account_info);
+
+        Ok(Some(account))
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        self.save_changes(Changes { account: Some(account), ..Default::default() }).await
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        if let Some(pickle) = self.get_value(PRIVATE_IDENTITY_TABLE, &"identity".encode()).await? {
+            Ok(Some(
+                PrivateCrossSigningIdentity::from_pickle(pickle)
+                    .await
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        self.save_changes(changes).await
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        if self.session_cache.get(sender_key).is_none() {
+            let sessions = self
+                .scan_values(SESSIONS_TABLE, &self.encode_key(SESSIONS_TABLE, sender_key))
+                .await?
+                .into_iter()
+                .map(|p| {
+                    Session::from_pickle(
+                        account_info.user_id.clone(),
+                        account_info.device_id.clone(),
+                        account_info.identity_keys.clone(),
+                        p,
+                    )
+                })
+                .collect();
+
+            self.session_cache.set_for_sender(sender_key, sessions);
+        }
+
+        Ok(self.session_cache.get(sender_key))
+    }
+
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        Ok(self
+            .scan_values(SESSIONS_TABLE, &[])
+            .await?
+            .into_iter()
+            .map(|p| {
+                Session::from_pickle(
+                    account_info.user_id.clone(),
+                    account_info.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    p,
+                )
+            })
+            .collect())
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        let key = self.encode_key(INBOUND_GROUP_TABLE, (room_id, session_id));
+
+        self.get_value(INBOUND_GROUP_TABLE, &key)
+            .await?
+            .map(|p| InboundGroupSession::from_pickle(p).map_err(CryptoStoreError::from))
+            .transpose()
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        Ok(self
+            .inbound_group_session_pickles()
+            .await?
+            .into_iter()
+            .filter_map(|p| InboundGroupSession::from_pickle(p).ok())
+            .collect())
+    }
+
+    async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts> {
+        let pickles = self.inbound_group_session_pickles().await?;
+
+        let total = pickles.len();
+        let backed_up = pickles.into_iter().filter(|p| p.backed_up).count();
+
+        Ok(RoomKeyCounts { total, backed_up })
+    }
+
+    async fn inbound_group_sessions_for_backup(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.inbound_group_session_pickles()
+            .await?
+            .into_iter()
+            .filter(|p| !p.backed_up)
+            .take(limit)
+            .map(|p| InboundGroupSession::from_pickle(p).map_err(CryptoStoreError::from))
+            .collect()
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        self.reset_backup_state().await
+    }
+
+    async fn load_backup_keys(&self) -> Result<BackupKeys> {
+        let backup_version = self.get_value(ACCOUNT_TABLE, &"backup_version_v1".encode()).await?;
+        let recovery_key = self.get_value(ACCOUNT_TABLE, &"recovery_key_v1".encode()).await?;
+
+        Ok(BackupKeys { backup_version, recovery_key })
+    }
+
+    async fn get_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        self.load_outbound_group_session(room_id).await
+    }
+
+    async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
+        self.scan_values(TRACKED_USERS_TABLE, &[]).await
+    }
+
+    async fn save_tracked_users(&self, users: &[(&UserId, bool)]) -> Result<()> {
+        self.save_tracked_users(users).await
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        self.get_value(DEVICE_TABLE, &self.encode_key(DEVICE_TABLE, (user_id, device_id))).await
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<OwnedDeviceId, ReadOnlyDevice>> {
+        let devices: Vec<ReadOnlyDevice> =
+            self.scan_values(DEVICE_TABLE, &self.encode_key(DEVICE_TABLE, user_id)).await?;
+
+        Ok(devices.into_iter().map(|d| (d.device_id().to_owned(), d)).collect())
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<ReadOnlyUserIdentities>> {
+        self.get_value(IDENTITIES_TABLE, &self.encode_key(IDENTITIES_TABLE, user_id)).await
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        Ok(self.get(OLM_HASHES_TABLE, &serde_json::to_vec(message_hash)?).await?.is_some())
+    }
+
+    async fn get_outgoing_secret_requests(
+        &self,
+        request_id: &TransactionId,
+    ) -> Result<Option<GossipRequest>> {
+        self.get_outgoing_key_request_helper(&request_id.encode()).await
+    }
+
+    async fn get_secret_request_by_info(
+        &self,
+        key_info: &SecretInfo,
+    ) -> Result<Option<GossipRequest>> {
+        let key = self.encode_key(SECRET_REQUEST_BY_INFO_TABLE, key_info);
+
+        if let Some(id) = self.get(SECRET_REQUEST_BY_INFO_TABLE, &key).await? {
+            self.get_outgoing_key_request_helper(&id).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>> {
+        self.scan_values(UNSENT_SECRET_REQUESTS_TABLE, &[]).await
+    }
+
+    async fn delete_outgoing_secret_requests(&self, request_id: &TransactionId) -> Result<()> {
+        let request_id = request_id.encode();
+        let mut transaction = KvTransaction::new();
+
+        for table in [OUTGOING_SECRET_REQUESTS_TABLE, UNSENT_SECRET_REQUESTS_TABLE] {
+            if let Some(request) = self.get_value::<GossipRequest>(table, &request_id).await? {
+                transaction.delete(
+                    SECRET_REQUEST_BY_INFO_TABLE,
+                    self.encode_key(SECRET_REQUEST_BY_INFO_TABLE, &request.info),
+                );
+            }
+
+            transaction.delete(table, request_id.clone());
+        }
+
+        self.commit(transaction).await
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldEvent>> {
+        let key = self.encode_key(DIRECT_WITHHELD_INFO_TABLE, (session_id, room_id.as_str()));
+        self.get_value(DIRECT_WITHHELD_INFO_TABLE, &key).await
+    }
+
+    async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
+        self.get_value(ROOM_SETTINGS_TABLE, &self.encode_key(ROOM_SETTINGS_TABLE, room_id)).await
+    }
+
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get_value(CUSTOM_TABLE, &self.encode_key(CUSTOM_TABLE, key)).await
+    }
+
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.backend
+            .put(CUSTOM_TABLE, self.encode_key(CUSTOM_TABLE, key), self.serialize_value(&value)?)
+            .await
+            .map_err(CryptoStoreError::backend)
+    }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        let room_prefix = self.encode_key(INBOUND_GROUP_TABLE, room_id);
+        let sessions = self
+            .backend
+            .scan_prefix(INBOUND_GROUP_TABLE, &room_prefix)
+            .await
+            .map_err(CryptoStoreError::backend)?;
+        for (key, _) in sessions {
+            transaction.delete(INBOUND_GROUP_TABLE, key);
+        }
+
+        // The withheld info is keyed by session ID first, so we look for the
+        // keys that end with the room ID.
+        let room_suffix = self.encode_key(DIRECT_WITHHELD_INFO_TABLE, room_id);
+        for (key, _) in self.scan(DIRECT_WITHHELD_INFO_TABLE).await? {
+            if key.len() > room_suffix.len()
+                && key.ends_with(&room_suffix)
+                && key[key.len() - room_suffix.len() - 1] == ENCODE_SEPARATOR
+            {
+                transaction.delete(DIRECT_WITHHELD_INFO_TABLE, key);
+            }
+        }
+
+        transaction.delete(OUTBOUND_GROUP_TABLE, self.encode_key(OUTBOUND_GROUP_TABLE, room_id));
+        transaction.delete(ROOM_SETTINGS_TABLE, self.encode_key(ROOM_SETTINGS_TABLE, room_id));
+
+        self.commit(transaction).await
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        for table in [
+            ACCOUNT_TABLE,
+            CUSTOM_TABLE,
+            DEVICE_TABLE,
+            DIRECT_WITHHELD_INFO_TABLE,
+            IDENTITIES_TABLE,
+            INBOUND_GROUP_TABLE,
+            OLM_HASHES_TABLE,
+            OUTBOUND_GROUP_TABLE,
+            OUTGOING_SECRET_REQUESTS_TABLE,
+            PRIVATE_IDENTITY_TABLE,
+            ROOM_SETTINGS_TABLE,
+            SECRET_REQUEST_BY_INFO_TABLE,
+            SESSIONS_TABLE,
+            TRACKED_USERS_TABLE,
+            UNSENT_SECRET_REQUESTS_TABLE,
+        ] {
+            for (key, _) in self.scan(table).await? {
+                transaction.delete(table, key);
+            }
+        }
+
+        self.commit(transaction).await?;
+
+        *self.account_info.write().unwrap() = None;
+        self.session_cache.clear();
+
+        Ok(())
+    }
+
+    /// The lease is updated with [`KvBackend::compare_and_swap()`], so it is
+    /// atomic as long as the backend's implementation is.
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool> {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let expiration = now + u64::from(lease_duration_ms);
+        let key = self.encode_key(LEASES_TABLE, key);
+
+        let current = self.get(LEASES_TABLE, &key).await?;
+        if let Some(current) = &current {
+            let (current_holder, current_expiration) =
+                self.deserialize_value::<(String, u64)>(current)?;
+
+            if current_holder != holder && current_expiration > now {
+                return Ok(false);
+            }
+        }
+
+        // If another holder updated the lease since it was read, it has the
+        // lock.
+        self.backend
+            .compare_and_swap(
+                LEASES_TABLE,
+                key,
+                current.as_deref(),
+                self.serialize_value(&(holder, expiration))?,
+            )
+            .await
+            .map_err(CryptoStoreError::backend)
+    }
+
+    async fn clear_caches(&self) {
+        self.session_cache.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use matrix_sdk_crypto::cryptostore_integration_tests;
+    use once_cell::sync::Lazy;
+
+    use super::KvCryptoStore;
+    use crate::MemoryKvBackend;
+
+    static BACKENDS: Lazy<Mutex<HashMap<String, MemoryKvBackend>>> = Lazy::new(Default::default);
+
+    async fn get_store(name: &str, passphrase: Option<&str>) -> KvCryptoStore<MemoryKvBackend> {
+        let backend = BACKENDS.lock().unwrap().entry(name.to_owned()).or_default().clone();
+
+        KvCryptoStore::open(backend, passphrase).await.expect("Can't create a KV crypto store")
+    }
+
+    cryptostore_integration_tests!();
+}
+
+#[cfg(test)]
+mod encrypted_tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use matrix_sdk_crypto::cryptostore_integration_tests;
+    use once_cell::sync::Lazy;
+
+    use super::KvCryptoStore;
+    use crate::MemoryKvBackend;
+
+    static BACKENDS: Lazy<Mutex<HashMap<String, MemoryKvBackend>>> = Lazy::new(Default::default);
+
+    async fn get_store(name: &str, passphrase: Option<&str>) -> KvCryptoStore<MemoryKvBackend> {
+        let backend = BACKENDS.lock().unwrap().entry(name.to_owned()).or_default().clone();
+        let pass = passphrase.unwrap_or("default_test_password");
+
+        KvCryptoStore::open(backend, Some(pass))
+            .await
+            .expect("Can't create a passphrase protected store")
+    }
+
+    cryptostore_integration_tests!();
+}
@@ -0,0 +1,119 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![doc = include_str!("../README.md")]
+#![cfg_attr(docsrs, feature(doc_auto_cfg))]
+#![warn(missing_docs, missing_debug_implementations)]
+#![cfg_attr(
+    not(any(feature = "state-store", feature = "crypto-store")),
+    allow(dead_code, unused_imports)
+)]
+
+use matrix_sdk_base::store::StoreConfig;
+use matrix_sdk_store_encryption::{Error as StoreEncryptionError, StoreCipher};
+
+mod backend;
+#[cfg(feature = "crypto-store")]
+mod crypto_store;
+#[cfg(feature = "state-store")]
+mod state_store;
+
+pub use self::backend::{KvBackend, KvOperation, KvTransaction, MemoryKvBackend};
+#[cfg(feature = "crypto-store")]
+pub use self::crypto_store::KvCryptoStore;
+#[cfg(feature = "state-store")]
+pub use self::state_store::KvStateStore;
+
+/// The table holding the metadata of the stores, that is never encrypted.
+const META_TABLE: &str = "meta";
+
+/// The key of the store cipher in the [`META_TABLE`].
+const STORE_CIPHER_KEY: &[u8] = b"store_cipher";
+
+/// All the errors that can occur when opening a key-value store.
+#[derive(Debug, thiserror::Error)]
+pub enum OpenStoreError {
+    /// An error occurred in the key-value backend.
+    #[error(transparent)]
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+
+    /// The store cipher failed to be created, imported or exported.
+    ///
+    /// This happens notably if the wrong passphrase is used to open the store.
+    #[error(transparent)]
+    Encryption(#[from] StoreEncryptionError),
+}
+
+impl OpenStoreError {
+    fn backend<E>(error: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self::Backend(Box::new(error))
+    }
+}
+
+/// Get the store cipher saved in the given backend, or create and save a new
+/// one.
+async fn get_or_create_store_cipher<B: KvBackend>(
+    backend: &B,
+    passphrase: &str,
+) -> Result<StoreCipher, OpenStoreError> {
+    let encrypted =
+        backend.get(META_TABLE, STORE_CIPHER_KEY).await.map_err(OpenStoreError::backend)?;
+
+    let cipher = if let Some(encrypted) = encrypted {
+        StoreCipher::import(passphrase, &encrypted)?
+    } else {
+        let cipher = StoreCipher::new()?;
+        #[cfg(not(test))]
+        let export = cipher.export(passphrase)?;
+        #[cfg(test)]
+        let export = cipher._insecure_export_fast_for_testing(passphrase)?;
+        backend
+            .put(META_TABLE, STORE_CIPHER_KEY.to_vec(), export)
+            .await
+            .map_err(OpenStoreError::backend)?;
+        cipher
+    };
+
+    Ok(cipher)
+}
+
+/// Create a [`StoreConfig`] with an opened [`KvStateStore`] that uses the
+/// given backend and passphrase.
+///
+/// If the `crypto-store` Cargo feature is enabled, a [`KvCryptoStore`] that
+/// uses the same backend and passphrase is also opened.
+#[cfg(feature = "state-store")]
+pub async fn make_store_config<B>(
+    backend: B,
+    passphrase: Option<&str>,
+) -> Result<StoreConfig, OpenStoreError>
+where
+    B: KvBackend + Clone + 'static,
+{
+    #[cfg(feature = "crypto-store")]
+    {
+        let state_store = KvStateStore::open(backend.clone(), passphrase).await?;
+        let crypto_store = KvCryptoStore::open(backend, passphrase).await?;
+        Ok(StoreConfig::new().state_store(state_store).crypto_store(crypto_store))
+    }
+
+    #[cfg(not(feature = "crypto-store"))]
+    {
+        let state_store = KvStateStore::open(backend, passphrase).await?;
+        Ok(StoreConfig::new().state_store(state_store))
+    }
+}
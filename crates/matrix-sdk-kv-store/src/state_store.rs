@@ -0,0 +1,1375 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use matrix_sdk_base::{
+    deserialized_responses::RawMemberEvent,
+    event_cache::{EventCacheChanges, EventChunk},
+    media::{
+        select_for_trimming, should_update_media_last_access, MediaCacheStats, MediaRequest,
+        MediaRetentionPolicy, UniqueKey,
+    },
+    store::{
+        encode_key::{EncodeKey, EncodeUnchecked, ENCODE_SEPARATOR},
+        integrity::compute_display_names,
+        IntegrityIssue, IntegrityReport, Result as StoreResult, StateChanges, StateStore,
+        StoreError,
+    },
+    MinimalStateEvent, RoomInfo, RoomMemberships, StateStoreDataKey, StateStoreDataValue,
+};
+use matrix_sdk_common::instant::Instant;
+use matrix_sdk_store_encryption::StoreCipher;
+use ruma::{
+    canonical_json::redact,
+    events::{
+        presence::PresenceEvent,
+        receipt::{Receipt, ReceiptThread, ReceiptType},
+        room::member::{
+            MembershipState, RoomMemberEventContent, StrippedRoomMemberEvent, SyncRoomMemberEvent,
+        },
+        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnySyncStateEvent,
+        GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType, SyncStateEvent,
+    },
+    serde::Raw,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId, OwnedUserId,
+    RoomId, RoomVersionId, UserId,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use tracing::{debug, info, warn};
+
+use crate::{get_or_create_store_cipher, KvBackend, KvTransaction, OpenStoreError};
+
+/// The names of the tables used by the state store.
+///
+/// They are also used to hash the keys when the store is encrypted.
+mod tables {
+    pub const ACCOUNT_DATA: &str = "state-account-data";
+    pub const CUSTOM: &str = "state-custom";
+    pub const DISPLAY_NAME: &str = "state-display-name";
+    pub const EVENT: &str = "state-event";
+    pub const EVENT_CHUNK: &str = "state-event-chunk";
+    pub const EVENT_CHUNK_RANGE: &str = "state-event-chunk-range";
+    pub const KV: &str = "state-kv";
+    pub const MEDIA: &str = "state-media";
+    pub const MEDIA_METADATA: &str = "state-media-metadata";
+    pub const PRESENCE: &str = "state-presence";
+    pub const PROFILE: &str = "state-profile";
+    pub const ROOM_ACCOUNT_DATA: &str = "state-room-account-data";
+    pub const ROOM_EVENT_RECEIPT: &str = "state-room-event-receipt";
+    pub const ROOM_INFO: &str = "state-room-info";
+    pub const ROOM_STATE: &str = "state-room-state";
+    pub const ROOM_USER_RECEIPT: &str = "state-room-user-receipt";
+    pub const STRIPPED_ROOM_INFO: &str = "state-stripped-room-info";
+    pub const STRIPPED_ROOM_STATE: &str = "state-stripped-room-state";
+    pub const STRIPPED_USER_ID: &str = "state-stripped-user-ids";
+    pub const USER_ID: &str = "state-user-ids";
+}
+
+type Result<A, E = StoreError> = std::result::Result<A, E>;
+
+/// A [`StateStore`] that persists its data in a [`KvBackend`].
+#[derive(Clone)]
+pub struct KvStateStore<B> {
+    backend: B,
+    store_cipher: Option<Arc<StoreCipher>>,
+}
+
+impl<B: std::fmt::Debug> std::fmt::Debug for KvStateStore<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvStateStore")
+            .field("backend", &self.backend)
+            .field("encrypted", &self.store_cipher.is_some())
+            .finish()
+    }
+}
+
+impl<B: KvBackend> KvStateStore<B> {
+    /// Open a `KvStateStore` that persists its data in the given backend.
+    ///
+    /// If a passphrase is given, it is used to encrypt the data of the store.
+    /// The store cipher is shared with a [`KvCryptoStore`] opened on the same
+    /// backend, so they must use the same passphrase.
+    ///
+    /// [`KvCryptoStore`]: crate::KvCryptoStore
+    pub async fn open(backend: B, passphrase: Option<&str>) -> Result<Self, OpenStoreError> {
+        let store_cipher = match passphrase {
+            Some(passphrase) => {
+                Some(get_or_create_store_cipher(&backend, passphrase).await?.into())
+            }
+            None => None,
+        };
+
+        Ok(Self { backend, store_cipher })
+    }
+
+    /// The backend of this store.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks that the values can be decrypted and deserialized, that
+    /// the members, profiles and state events belong to rooms with a room
+    /// info, and that the display name maps match the members. See
+    /// [`IntegrityIssue`] for what the repair does for each problem.
+    ///
+    /// This store doesn't have a schema version yet, so it isn't checked.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        let mut issues = Vec::new();
+        let mut transaction = KvTransaction::new();
+
+        for table in [
+            tables::KV,
+            tables::ACCOUNT_DATA,
+            tables::PROFILE,
+            tables::DISPLAY_NAME,
+            tables::USER_ID,
+            tables::ROOM_STATE,
+            tables::ROOM_ACCOUNT_DATA,
+            tables::STRIPPED_USER_ID,
+            tables::STRIPPED_ROOM_STATE,
+            tables::PRESENCE,
+            tables::ROOM_USER_RECEIPT,
+            tables::ROOM_EVENT_RECEIPT,
+            tables::MEDIA,
+            tables::MEDIA_METADATA,
+            tables::EVENT_CHUNK,
+            tables::EVENT_CHUNK_RANGE,
+            tables::EVENT,
+            tables::CUSTOM,
+        ] {
+            let mut count = 0;
+
+            for (key, value) in self.scan(table, &[]).await? {
+                if self.deserialize_value::<IgnoredAny>(&value).is_err() {
+                    transaction.delete(table, key);
+                    count += 1;
+                }
+            }
+
+            if count > 0 {
+                issues.push(IntegrityIssue::CorruptedValues { table: table.to_owned(), count });
+            }
+        }
+
+        let mut room_ids = Vec::new();
+        let mut joined_room_ids = Vec::new();
+
+        for table in [tables::ROOM_INFO, tables::STRIPPED_ROOM_INFO] {
+            let mut count = 0;
+
+            for (key, value) in self.scan(table, &[]).await? {
+                match self.deserialize_value::<RoomInfo>(&value) {
+                    Ok(room_info) => {
+                        // Display names are only tracked for the members of
+                        // non-stripped rooms.
+                        if table == tables::ROOM_INFO {
+                            joined_room_ids.push(room_info.room_id().to_owned());
+                        }
+                        room_ids.push(room_info.room_id().to_owned());
+                    }
+                    Err(_) => {
+                        transaction.delete(table, key);
+                        count += 1;
+                    }
+                }
+            }
+
+            if count > 0 {
+                issues.push(IntegrityIssue::CorruptedValues { table: table.to_owned(), count });
+                // Sync again from scratch to restore the removed rooms.
+                transaction
+                    .delete(tables::KV, self.encode_kv_data_key(StateStoreDataKey::SyncToken));
+            }
+        }
+
+        for table in [
+            tables::PROFILE,
+            tables::DISPLAY_NAME,
+            tables::USER_ID,
+            tables::ROOM_STATE,
+            tables::STRIPPED_USER_ID,
+            tables::STRIPPED_ROOM_STATE,
+        ] {
+            let known_prefixes: HashSet<Vec<u8>> =
+                room_ids.iter().map(|room_id| self.encode_key(table, room_id)).collect();
+            let mut orphans = HashSet::new();
+
+            for (key, _) in self.scan(table, &[]).await? {
+                let prefix = self.room_key_prefix(&key).map(<[u8]>::to_vec);
+
+                if prefix.as_ref().map_or(true, |prefix| !known_prefixes.contains(prefix)) {
+                    orphans.insert(prefix);
+                    transaction.delete(table, key);
+                }
+            }
+
+            if !orphans.is_empty() {
+                issues.push(IntegrityIssue::OrphanedRoomData {
+                    table: table.to_owned(),
+                    rooms: orphans.len(),
+                });
+            }
+        }
+
+        if repair {
+            self.commit(transaction).await?;
+        }
+
+        // The display names can only be computed reliably once the broken
+        // values are gone.
+        if repair || issues.is_empty() {
+            let mut transaction = KvTransaction::new();
+            let mut inconsistent_rooms = 0;
+
+            for room_id in joined_room_ids {
+                let expected: BTreeMap<Vec<u8>, Option<BTreeSet<OwnedUserId>>> =
+                    compute_display_names(self, &room_id)
+                        .await?
+                        .into_iter()
+                        .map(|(name, user_ids)| {
+                            (
+                                self.encode_key(tables::DISPLAY_NAME, (&room_id, name)),
+                                Some(user_ids),
+                            )
+                        })
+                        .collect();
+
+                let prefix = self.encode_key(tables::DISPLAY_NAME, &room_id);
+                let entries = self.scan(tables::DISPLAY_NAME, &prefix).await?;
+                let mut stored = BTreeMap::new();
+                for (key, value) in &entries {
+                    let user_ids: Option<BTreeSet<OwnedUserId>> =
+                        self.deserialize_value(value).ok();
+
+                    // Empty sets are kept when the last user with a display
+                    // name changes it.
+                    if user_ids.as_ref().map_or(true, |user_ids| !user_ids.is_empty()) {
+                        stored.insert(key.clone(), user_ids);
+                    }
+                }
+
+                if stored == expected {
+                    continue;
+                }
+
+                inconsistent_rooms += 1;
+
+                for (key, _) in entries {
+                    transaction.delete(tables::DISPLAY_NAME, key);
+                }
+                for (key, user_ids) in expected {
+                    transaction.put(tables::DISPLAY_NAME, key, self.serialize_value(&user_ids)?);
+                }
+            }
+
+            if inconsistent_rooms > 0 {
+                issues.push(IntegrityIssue::InconsistentDisplayNames { rooms: inconsistent_rooms });
+            }
+
+            if repair {
+                self.commit(transaction).await?;
+            }
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
+    /// The part of the given key that is encoded from the room ID, including
+    /// the separator.
+    fn room_key_prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let end = if self.store_cipher.is_some() {
+            // Hashed keys have a fixed length.
+            32
+        } else {
+            key.iter().position(|&byte| byte == ENCODE_SEPARATOR)?
+        };
+
+        key.get(..=end)
+    }
+
+    fn serialize_value(&self, value: &impl Serialize) -> Result<Vec<u8>> {
+        if let Some(key) = &self.store_cipher {
+            Ok(key.encrypt_value(value)?)
+        } else {
+            Ok(serde_json::to_vec(value)?)
+        }
+    }
+
+    fn deserialize_value<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T> {
+        if let Some(key) = &self.store_cipher {
+            Ok(key.decrypt_value(value)?)
+        } else {
+            Ok(serde_json::from_slice(value)?)
+        }
+    }
+
+    fn encode_key<T: EncodeKey>(&self, table_name: &str, key: T) -> Vec<u8> {
+        if let Some(store_cipher) = &self.store_cipher {
+            key.encode_secure(table_name, store_cipher).to_vec()
+        } else {
+            key.encode()
+        }
+    }
+
+    fn encode_kv_data_key(&self, key: StateStoreDataKey<'_>) -> Vec<u8> {
+        match key {
+            StateStoreDataKey::SyncToken => StateStoreDataKey::SYNC_TOKEN.encode(),
+            StateStoreDataKey::Filter(filter_name) => {
+                self.encode_key(tables::KV, (StateStoreDataKey::FILTER, filter_name))
+            }
+            StateStoreDataKey::UserAvatarUrl(user_id) => {
+                self.encode_key(tables::KV, (StateStoreDataKey::USER_AVATAR_URL, user_id))
+            }
+        }
+    }
+
+    async fn get(&self, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.backend.get(table, key).await.map_err(StoreError::backend)
+    }
+
+    async fn get_value<T: DeserializeOwned>(&self, table: &str, key: &[u8]) -> Result<Option<T>> {
+        self.get(table, key).await?.map(|v| self.deserialize_value(&v)).transpose()
+    }
+
+    async fn scan(&self, table: &str, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.backend.scan_prefix(table, prefix).await.map_err(StoreError::backend)
+    }
+
+    async fn scan_values<T: DeserializeOwned>(&self, table: &str, prefix: &[u8]) -> Result<Vec<T>> {
+        self.scan(table, prefix).await?.iter().map(|(_, v)| self.deserialize_value(v)).collect()
+    }
+
+    /// Add the removal of all the entries of `table` whose key starts with
+    /// `prefix` to the given transaction.
+    async fn delete_prefix(
+        &self,
+        transaction: &mut KvTransaction,
+        table: &str,
+        prefix: &[u8],
+    ) -> Result<()> {
+        for (key, _) in self.scan(table, prefix).await? {
+            transaction.delete(table, key);
+        }
+
+        Ok(())
+    }
+
+    async fn commit(&self, transaction: KvTransaction) -> Result<()> {
+        if transaction.is_empty() {
+            return Ok(());
+        }
+
+        self.backend.commit(transaction).await.map_err(StoreError::backend)
+    }
+
+    async fn get_kv_data(&self, key: StateStoreDataKey<'_>) -> Result<Option<StateStoreDataValue>> {
+        let encoded_key = self.encode_kv_data_key(key);
+
+        let value = self.get_value::<String>(tables::KV, &encoded_key).await?;
+
+        let value = match key {
+            StateStoreDataKey::SyncToken => value.map(StateStoreDataValue::SyncToken),
+            StateStoreDataKey::Filter(_) => value.map(StateStoreDataValue::Filter),
+            StateStoreDataKey::UserAvatarUrl(_) => value.map(StateStoreDataValue::UserAvatarUrl),
+        };
+
+        Ok(value)
+    }
+
+    async fn set_kv_data(
+        &self,
+        key: StateStoreDataKey<'_>,
+        value: StateStoreDataValue,
+    ) -> Result<()> {
+        let encoded_key = self.encode_kv_data_key(key);
+
+        let value = match key {
+            StateStoreDataKey::SyncToken => {
+                value.into_sync_token().expect("Session data not a sync token")
+            }
+            StateStoreDataKey::Filter(_) => value.into_filter().expect("Session data not a filter"),
+            StateStoreDataKey::UserAvatarUrl(_) => {
+                value.into_user_avatar_url().expect("Session data not an user avatar url")
+            }
+        };
+
+        self.backend
+            .put(tables::KV, encoded_key, self.serialize_value(&value)?)
+            .await
+            .map_err(StoreError::backend)
+    }
+
+    async fn remove_kv_data(&self, key: StateStoreDataKey<'_>) -> Result<()> {
+        let encoded_key = self.encode_kv_data_key(key);
+        self.backend.delete(tables::KV, encoded_key).await.map_err(StoreError::backend)
+    }
+
+    async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        let now = Instant::now();
+
+        // room state & memberships
+        let mut transaction = KvTransaction::new();
+
+        for (room_id, ambiguity_maps) in &changes.ambiguity_maps {
+            for (display_name, map) in ambiguity_maps {
+                transaction.put(
+                    tables::DISPLAY_NAME,
+                    self.encode_key(tables::DISPLAY_NAME, (room_id, display_name)),
+                    self.serialize_value(&map)?,
+                );
+            }
+        }
+
+        for (room, events) in &changes.room_account_data {
+            for (event_type, event) in events {
+                transaction.put(
+                    tables::ROOM_ACCOUNT_DATA,
+                    self.encode_key(tables::ROOM_ACCOUNT_DATA, (room, event_type)),
+                    self.serialize_value(&event)?,
+                );
+            }
+        }
+
+        for (room, event_types) in &changes.state {
+            let profile_changes = changes.profiles.get(room);
+
+            for (event_type, events) in event_types {
+                for (state_key, raw_event) in events {
+                    transaction.put(
+                        tables::ROOM_STATE,
+                        self.encode_key(tables::ROOM_STATE, (room, event_type, state_key)),
+                        self.serialize_value(&raw_event)?,
+                    );
+                    transaction.delete(
+                        tables::STRIPPED_ROOM_STATE,
+                        self.encode_key(tables::STRIPPED_ROOM_STATE, (room, event_type, state_key)),
+                    );
+
+                    if *event_type == StateEventType::RoomMember {
+                        let event = match raw_event.deserialize_as::<SyncRoomMemberEvent>() {
+                            Ok(ev) => ev,
+                            Err(e) => {
+                                let event_id: Option<String> =
+                                    raw_event.get_field("event_id").ok().flatten();
+                                debug!(event_id, "Failed to deserialize member event: {e}");
+                                continue;
+                            }
+                        };
+
+                        let key = (room, state_key);
+
+                        transaction.delete(
+                            tables::STRIPPED_USER_ID,
+                            self.encode_key(tables::STRIPPED_USER_ID, key),
+                        );
+                        transaction.put(
+                            tables::USER_ID,
+                            self.encode_key(tables::USER_ID, key),
+                            self.serialize_value(&RoomMember::from(&event))?,
+                        );
+
+                        if let Some(profile) =
+                            profile_changes.and_then(|p| p.get(event.state_key()))
+                        {
+                            transaction.put(
+                                tables::PROFILE,
+                                self.encode_key(tables::PROFILE, key),
+                                self.serialize_value(&profile)?,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        for (room_id, room_info) in &changes.room_infos {
+            transaction.put(
+                tables::ROOM_INFO,
+                self.encode_key(tables::ROOM_INFO, room_id),
+                self.serialize_value(room_info)?,
+            );
+            transaction.delete(
+                tables::STRIPPED_ROOM_INFO,
+                self.encode_key(tables::STRIPPED_ROOM_INFO, room_id),
+            );
+        }
+
+        for (room_id, info) in &changes.stripped_room_infos {
+            transaction.put(
+                tables::STRIPPED_ROOM_INFO,
+                self.encode_key(tables::STRIPPED_ROOM_INFO, room_id),
+                self.serialize_value(&info)?,
+            );
+            transaction.delete(tables::ROOM_INFO, self.encode_key(tables::ROOM_INFO, room_id));
+        }
+
+        for (room, event_types) in &changes.stripped_state {
+            for (event_type, events) in event_types {
+                for (state_key, raw_event) in events {
+                    transaction.put(
+                        tables::STRIPPED_ROOM_STATE,
+                        self.encode_key(
+                            tables::STRIPPED_ROOM_STATE,
+                            (room, event_type.to_string(), state_key),
+                        ),
+                        self.serialize_value(&raw_event)?,
+                    );
+
+                    if *event_type == StateEventType::RoomMember {
+                        let event = match raw_event.deserialize_as::<StrippedRoomMemberEvent>() {
+                            Ok(ev) => ev,
+                            Err(e) => {
+                                let event_id: Option<String> =
+                                    raw_event.get_field("event_id").ok().flatten();
+                                debug!(
+                                    event_id,
+                                    "Failed to deserialize stripped member event: {e}"
+                                );
+                                continue;
+                            }
+                        };
+
+                        transaction.put(
+                            tables::STRIPPED_USER_ID,
+                            self.encode_key(tables::STRIPPED_USER_ID, (room, state_key)),
+                            self.serialize_value(&RoomMember::from(&event))?,
+                        );
+                    }
+                }
+            }
+        }
+
+        self.commit(transaction).await?;
+
+        // redactions, applied on the state that was just saved
+        let mut transaction = KvTransaction::new();
+
+        for (room_id, redactions) in &changes.redactions {
+            let key_prefix = self.encode_key(tables::ROOM_STATE, room_id);
+            let mut room_version = None;
+
+            for (key, evt) in self.scan(tables::ROOM_STATE, &key_prefix).await? {
+                let raw_evt = self.deserialize_value::<Raw<AnySyncStateEvent>>(&evt)?;
+                let Ok(Some(event_id)) = raw_evt.get_field::<OwnedEventId>("event_id") else {
+                    continue;
+                };
+                let Some(redaction) = redactions.get(&event_id) else { continue };
+
+                if room_version.is_none() {
+                    room_version = Some(self.room_version(room_id).await);
+                }
+
+                let redacted = redact(
+                    raw_evt.deserialize_as::<CanonicalJsonObject>()?,
+                    room_version.as_ref().expect("room version was just set"),
+                    Some(redaction.try_into()?),
+                )
+                .map_err(StoreError::Redaction)?;
+
+                transaction.put(tables::ROOM_STATE, key, self.serialize_value(&redacted)?);
+            }
+        }
+
+        self.commit(transaction).await?;
+
+        // receipts, presence, user state & event cache
+        let mut transaction = KvTransaction::new();
+
+        // The user receipts that were already saved in this transaction, that
+        // the backend can't return yet.
+        let mut saved_user_receipts: HashMap<Vec<u8>, OwnedEventId> = HashMap::new();
+
+        for (room, content) in &changes.receipts {
+            for (event_id, receipts) in &content.0 {
+                for (receipt_type, receipts) in receipts {
+                    for (user_id, receipt) in receipts {
+                        // Add the receipt to the room user receipts
+                        let key = match receipt.thread.as_str() {
+                            Some(thread_id) => self.encode_key(
+                                tables::ROOM_USER_RECEIPT,
+                                (room, receipt_type, thread_id, user_id),
+                            ),
+                            None => self.encode_key(
+                                tables::ROOM_USER_RECEIPT,
+                                (room, receipt_type, user_id),
+                            ),
+                        };
+
+                        let old_event = match saved_user_receipts.get(&key) {
+                            Some(old_event) => Some(old_event.clone()),
+                            None => self
+                                .get_value::<(OwnedEventId, Receipt)>(
+                                    tables::ROOM_USER_RECEIPT,
+                                    &key,
+                                )
+                                .await?
+                                .map(|(old_event, _)| old_event),
+                        };
+
+                        transaction.put(
+                            tables::ROOM_USER_RECEIPT,
+                            key.clone(),
+                            self.serialize_value(&(event_id, receipt))?,
+                        );
+                        saved_user_receipts.insert(key, event_id.clone());
+
+                        // Remove the old receipt from the room event receipts
+                        if let Some(old_event) = old_event {
+                            let key = match receipt.thread.as_str() {
+                                Some(thread_id) => self.encode_key(
+                                    tables::ROOM_EVENT_RECEIPT,
+                                    (room, receipt_type, thread_id, old_event, user_id),
+                                ),
+                                None => self.encode_key(
+                                    tables::ROOM_EVENT_RECEIPT,
+                                    (room, receipt_type, old_event, user_id),
+                                ),
+                            };
+                            transaction.delete(tables::ROOM_EVENT_RECEIPT, key);
+                        }
+
+                        // Add the receipt to the room event receipts
+                        let key = match receipt.thread.as_str() {
+                            Some(thread_id) => self.encode_key(
+                                tables::ROOM_EVENT_RECEIPT,
+                                (room, receipt_type, thread_id, event_id, user_id),
+                            ),
+                            None => self.encode_key(
+                                tables::ROOM_EVENT_RECEIPT,
+                                (room, receipt_type, event_id, user_id),
+                            ),
+                        };
+                        transaction.put(
+                            tables::ROOM_EVENT_RECEIPT,
+                            key,
+                            self.serialize_value(&(user_id, receipt))?,
+                        );
+                    }
+                }
+            }
+        }
+
+        for (sender, event) in &changes.presence {
+            transaction.put(
+                tables::PRESENCE,
+                self.encode_key(tables::PRESENCE, sender),
+                self.serialize_value(&event)?,
+            );
+        }
+
+        if let Some(s) = &changes.sync_token {
+            transaction.put(
+                tables::KV,
+                self.encode_kv_data_key(StateStoreDataKey::SyncToken),
+                self.serialize_value(s)?,
+            );
+        }
+
+        for (event_type, event) in &changes.account_data {
+            transaction.put(
+                tables::ACCOUNT_DATA,
+                self.encode_key(tables::ACCOUNT_DATA, event_type),
+                self.serialize_value(&event)?,
+            );
+        }
+
+        for (room_id, cache_changes) in &changes.event_cache {
+            self.save_event_cache_changes(&mut transaction, room_id, cache_changes).await?;
+        }
+
+        self.commit(transaction).await?;
+
+        info!("Saved changes in {:?}", now.elapsed());
+
+        Ok(())
+    }
+
+    /// Get the version of the given room, or version 9 if it is unknown.
+    async fn room_version(&self, room_id: &RoomId) -> RoomVersionId {
+        self.get_value::<RoomInfo>(tables::ROOM_INFO, &self.encode_key(tables::ROOM_INFO, room_id))
+            .await
+            .ok()
+            .flatten()
+            .and_then(|info| info.room_version().cloned())
+            .unwrap_or_else(|| {
+                warn!(?room_id, "Unable to find the room version, assume version 9");
+                RoomVersionId::V9
+            })
+    }
+
+    async fn save_event_cache_changes(
+        &self,
+        transaction: &mut KvTransaction,
+        room_id: &RoomId,
+        cache_changes: &EventCacheChanges,
+    ) -> Result<()> {
+        let range_key = self.encode_key(tables::EVENT_CHUNK_RANGE, room_id);
+
+        let mut range = if cache_changes.reset {
+            self.delete_prefix(
+                transaction,
+                tables::EVENT_CHUNK,
+                &self.encode_key(tables::EVENT_CHUNK, room_id),
+            )
+            .await?;
+            self.delete_prefix(
+                transaction,
+                tables::EVENT,
+                &self.encode_key(tables::EVENT, room_id),
+            )
+            .await?;
+
+            None
+        } else {
+            self.get_value::<(i64, i64)>(tables::EVENT_CHUNK_RANGE, &range_key).await?
+        };
+
+        for chunk_id in &cache_changes.removed_chunks {
+            let key = self.encode_key(tables::EVENT_CHUNK, (room_id, chunk_id.to_string()));
+
+            if let Some(old_chunk) = self.get_value::<EventChunk>(tables::EVENT_CHUNK, &key).await?
+            {
+                for event_id in old_chunk.event_ids() {
+                    transaction.delete(
+                        tables::EVENT,
+                        self.encode_key(tables::EVENT, (room_id, &event_id)),
+                    );
+                }
+            }
+
+            transaction.delete(tables::EVENT_CHUNK, key);
+        }
+
+        // The removed chunks are the oldest ones, so the range now starts after
+        // them.
+        if let Some((mut start, end)) = range {
+            while cache_changes.removed_chunks.contains(&start) {
+                start += 1;
+            }
+            range = (start <= end).then_some((start, end));
+        }
+
+        for (chunk_id, chunk) in &cache_changes.chunks {
+            let key = self.encode_key(tables::EVENT_CHUNK, (room_id, chunk_id.to_string()));
+
+            // Remove the events that were previously in this chunk, the new
+            // version of the chunk might not contain them anymore.
+            if let Some(old_chunk) = self.get_value::<EventChunk>(tables::EVENT_CHUNK, &key).await?
+            {
+                for event_id in old_chunk.event_ids() {
+                    transaction.delete(
+                        tables::EVENT,
+                        self.encode_key(tables::EVENT, (room_id, &event_id)),
+                    );
+                }
+            }
+
+            for event_id in chunk.event_ids() {
+                transaction.put(
+                    tables::EVENT,
+                    self.encode_key(tables::EVENT, (room_id, &event_id)),
+                    self.serialize_value(chunk_id)?,
+                );
+            }
+
+            transaction.put(tables::EVENT_CHUNK, key, self.serialize_value(chunk)?);
+
+            range = Some(match range {
+                Some((start, end)) => (start.min(*chunk_id), end.max(*chunk_id)),
+                None => (*chunk_id, *chunk_id),
+            });
+        }
+
+        if let Some(range) = range {
+            transaction.put(tables::EVENT_CHUNK_RANGE, range_key, self.serialize_value(&range)?);
+        } else {
+            transaction.delete(tables::EVENT_CHUNK_RANGE, range_key);
+        }
+
+        Ok(())
+    }
+
+    async fn get_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> Result<Option<RawMemberEvent>> {
+        let stripped_key = self.encode_key(
+            tables::STRIPPED_ROOM_STATE,
+            (room_id, StateEventType::RoomMember, state_key),
+        );
+
+        if let Some(e) = self.get_value(tables::STRIPPED_ROOM_STATE, &stripped_key).await? {
+            return Ok(Some(RawMemberEvent::Stripped(e)));
+        }
+
+        let key =
+            self.encode_key(tables::ROOM_STATE, (room_id, StateEventType::RoomMember, state_key));
+
+        Ok(self.get_value(tables::ROOM_STATE, &key).await?.map(RawMemberEvent::Sync))
+    }
+
+    /// Get the user IDs for the given room with the given memberships and
+    /// stripped state.
+    async fn get_user_ids(
+        &self,
+        room_id: &RoomId,
+        memberships: RoomMemberships,
+        stripped: bool,
+    ) -> Result<Vec<OwnedUserId>> {
+        let table = if stripped { tables::STRIPPED_USER_ID } else { tables::USER_ID };
+        let members: Vec<RoomMember> =
+            self.scan_values(table, &self.encode_key(table, room_id)).await?;
+
+        Ok(members
+            .into_iter()
+            .filter(|member| memberships.matches(&member.membership))
+            .map(|member| member.user_id)
+            .collect())
+    }
+
+    async fn get_user_room_receipt_event(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        thread: ReceiptThread,
+        user_id: &UserId,
+    ) -> Result<Option<(OwnedEventId, Receipt)>> {
+        let key = match thread.as_str() {
+            Some(thread_id) => self
+                .encode_key(tables::ROOM_USER_RECEIPT, (room_id, receipt_type, thread_id, user_id)),
+            None => self.encode_key(tables::ROOM_USER_RECEIPT, (room_id, receipt_type, user_id)),
+        };
+
+        self.get_value(tables::ROOM_USER_RECEIPT, &key).await
+    }
+
+    async fn get_event_room_receipt_events(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        thread: ReceiptThread,
+        event_id: &EventId,
+    ) -> Result<Vec<(OwnedUserId, Receipt)>> {
+        let key = match thread.as_str() {
+            Some(thread_id) => self.encode_key(
+                tables::ROOM_EVENT_RECEIPT,
+                (room_id, receipt_type, thread_id, event_id),
+            ),
+            None => self.encode_key(tables::ROOM_EVENT_RECEIPT, (room_id, receipt_type, event_id)),
+        };
+
+        self.scan_values(tables::ROOM_EVENT_RECEIPT, &key).await
+    }
+
+    fn encode_media_key(&self, request: &MediaRequest) -> Vec<u8> {
+        self.encode_key(tables::MEDIA, (request.source.unique_key(), request.format.unique_key()))
+    }
+
+    async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
+        let key = self.encode_media_key(request);
+
+        let mut transaction = KvTransaction::new();
+        transaction.put(
+            tables::MEDIA_METADATA,
+            key.clone(),
+            self.serialize_value(&(data.len(), MilliSecondsSinceUnixEpoch::now()))?,
+        );
+        transaction.put(tables::MEDIA, key, self.serialize_value(&data)?);
+
+        self.commit(transaction).await
+    }
+
+    async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
+        let key = self.encode_media_key(request);
+
+        let Some(data) = self.get_value::<Vec<u8>>(tables::MEDIA, &key).await? else {
+            return Ok(None);
+        };
+
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let update_last_access = match self
+            .get_value::<(usize, MilliSecondsSinceUnixEpoch)>(tables::MEDIA_METADATA, &key)
+            .await?
+        {
+            Some((_, last_access)) => should_update_media_last_access(last_access, now),
+            None => true,
+        };
+
+        if update_last_access {
+            self.backend
+                .put(tables::MEDIA_METADATA, key, self.serialize_value(&(data.len(), now))?)
+                .await
+                .map_err(StoreError::backend)?;
+        }
+
+        Ok(Some(data))
+    }
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
+        let key = self.encode_media_key(request);
+
+        let mut transaction = KvTransaction::new();
+        transaction.delete(tables::MEDIA, key.clone());
+        transaction.delete(tables::MEDIA_METADATA, key);
+
+        self.commit(transaction).await
+    }
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        for (key, _) in self.scan(tables::MEDIA, &self.encode_key(tables::MEDIA, uri)).await? {
+            transaction.delete(tables::MEDIA_METADATA, key.clone());
+            transaction.delete(tables::MEDIA, key);
+        }
+
+        self.commit(transaction).await
+    }
+
+    /// Get the key, size and time of last access of all the media content.
+    ///
+    /// The metadata is always written with the media content, so the content
+    /// itself doesn't need to be read.
+    async fn media_entries(&self) -> Result<Vec<(Vec<u8>, usize, MilliSecondsSinceUnixEpoch)>> {
+        self.scan(tables::MEDIA_METADATA, &[])
+            .await?
+            .into_iter()
+            .map(|(key, metadata)| {
+                let (size, last_access) = self.deserialize_value(&metadata)?;
+                Ok((key, size, last_access))
+            })
+            .collect()
+    }
+
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<()> {
+        let entries = self.media_entries().await?;
+
+        let mut transaction = KvTransaction::new();
+        for key in policy.select_for_removal(entries, MilliSecondsSinceUnixEpoch::now()) {
+            transaction.delete(tables::MEDIA_METADATA, key.clone());
+            transaction.delete(tables::MEDIA, key);
+        }
+
+        self.commit(transaction).await
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize> {
+        let (keys, total_size) = select_for_trimming(self.media_entries().await?, max_size);
+
+        let mut transaction = KvTransaction::new();
+        for key in keys {
+            transaction.delete(tables::MEDIA_METADATA, key.clone());
+            transaction.delete(tables::MEDIA, key);
+        }
+        self.commit(transaction).await?;
+
+        Ok(total_size)
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        let entries = self.media_entries().await?;
+
+        Ok(MediaCacheStats {
+            count: entries.len(),
+            total_size: entries.iter().map(|(_, size, _)| size).sum(),
+        })
+    }
+
+    async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let key = self.encode_key(tables::CUSTOM, EncodeUnchecked::from(key));
+
+        let old = self.get_value(tables::CUSTOM, &key).await?;
+        self.backend
+            .put(tables::CUSTOM, key, self.serialize_value(&value)?)
+            .await
+            .map_err(StoreError::backend)?;
+
+        Ok(old)
+    }
+
+    async fn remove_custom_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = self.encode_key(tables::CUSTOM, EncodeUnchecked::from(key));
+
+        let old = self.get_value(tables::CUSTOM, &key).await?;
+        if old.is_some() {
+            self.backend.delete(tables::CUSTOM, key).await.map_err(StoreError::backend)?;
+        }
+
+        Ok(old)
+    }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        transaction.delete(tables::ROOM_INFO, self.encode_key(tables::ROOM_INFO, room_id));
+        transaction.delete(
+            tables::STRIPPED_ROOM_INFO,
+            self.encode_key(tables::STRIPPED_ROOM_INFO, room_id),
+        );
+
+        for table in [
+            tables::PROFILE,
+            tables::DISPLAY_NAME,
+            tables::USER_ID,
+            tables::STRIPPED_USER_ID,
+            tables::ROOM_STATE,
+            tables::STRIPPED_ROOM_STATE,
+            tables::ROOM_ACCOUNT_DATA,
+            tables::ROOM_USER_RECEIPT,
+            tables::ROOM_EVENT_RECEIPT,
+        ] {
+            self.delete_prefix(&mut transaction, table, &self.encode_key(table, room_id)).await?;
+        }
+
+        self.save_event_cache_changes(
+            &mut transaction,
+            room_id,
+            &EventCacheChanges { reset: true, ..Default::default() },
+        )
+        .await?;
+
+        self.commit(transaction).await
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut transaction = KvTransaction::new();
+
+        for table in [
+            tables::ACCOUNT_DATA,
+            tables::CUSTOM,
+            tables::DISPLAY_NAME,
+            tables::EVENT,
+            tables::EVENT_CHUNK,
+            tables::EVENT_CHUNK_RANGE,
+            tables::KV,
+            tables::MEDIA,
+            tables::MEDIA_METADATA,
+            tables::PRESENCE,
+            tables::PROFILE,
+            tables::ROOM_ACCOUNT_DATA,
+            tables::ROOM_EVENT_RECEIPT,
+            tables::ROOM_INFO,
+            tables::ROOM_STATE,
+            tables::ROOM_USER_RECEIPT,
+            tables::STRIPPED_ROOM_INFO,
+            tables::STRIPPED_ROOM_STATE,
+            tables::STRIPPED_USER_ID,
+            tables::USER_ID,
+        ] {
+            self.delete_prefix(&mut transaction, table, &[]).await?;
+        }
+
+        self.commit(transaction).await
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl<B: KvBackend> StateStore for KvStateStore<B> {
+    type Error = StoreError;
+
+    async fn get_kv_data(
+        &self,
+        key: StateStoreDataKey<'_>,
+    ) -> StoreResult<Option<StateStoreDataValue>> {
+        self.get_kv_data(key).await
+    }
+
+    async fn set_kv_data(
+        &self,
+        key: StateStoreDataKey<'_>,
+        value: StateStoreDataValue,
+    ) -> StoreResult<()> {
+        self.set_kv_data(key, value).await
+    }
+
+    async fn remove_kv_data(&self, key: StateStoreDataKey<'_>) -> StoreResult<()> {
+        self.remove_kv_data(key).await
+    }
+
+    async fn save_changes(&self, changes: &StateChanges) -> StoreResult<()> {
+        self.save_changes(changes).await
+    }
+
+    async fn get_presence_event(
+        &self,
+        user_id: &UserId,
+    ) -> StoreResult<Option<Raw<PresenceEvent>>> {
+        self.get_value(tables::PRESENCE, &self.encode_key(tables::PRESENCE, user_id)).await
+    }
+
+    async fn get_state_event(
+        &self,
+        room_id: &RoomId,
+        event_type: StateEventType,
+        state_key: &str,
+    ) -> StoreResult<Option<Raw<AnySyncStateEvent>>> {
+        let key = self.encode_key(tables::ROOM_STATE, (room_id, event_type.to_string(), state_key));
+        self.get_value(tables::ROOM_STATE, &key).await
+    }
+
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: StateEventType,
+    ) -> StoreResult<Vec<Raw<AnySyncStateEvent>>> {
+        let key = self.encode_key(tables::ROOM_STATE, (room_id, event_type.to_string()));
+        self.scan_values(tables::ROOM_STATE, &key).await
+    }
+
+    async fn get_profile(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> StoreResult<Option<MinimalStateEvent<RoomMemberEventContent>>> {
+        self.get_value(tables::PROFILE, &self.encode_key(tables::PROFILE, (room_id, user_id))).await
+    }
+
+    async fn get_member_event(
+        &self,
+        room_id: &RoomId,
+        state_key: &UserId,
+    ) -> StoreResult<Option<RawMemberEvent>> {
+        self.get_member_event(room_id, state_key).await
+    }
+
+    async fn get_user_ids(
+        &self,
+        room_id: &RoomId,
+        memberships: RoomMemberships,
+    ) -> StoreResult<Vec<OwnedUserId>> {
+        let v = self.get_user_ids(room_id, memberships, true).await?;
+        if !v.is_empty() {
+            return Ok(v);
+        }
+        self.get_user_ids(room_id, memberships, false).await
+    }
+
+    async fn get_invited_user_ids(&self, room_id: &RoomId) -> StoreResult<Vec<OwnedUserId>> {
+        StateStore::get_user_ids(self, room_id, RoomMemberships::INVITE).await
+    }
+
+    async fn get_joined_user_ids(&self, room_id: &RoomId) -> StoreResult<Vec<OwnedUserId>> {
+        StateStore::get_user_ids(self, room_id, RoomMemberships::JOIN).await
+    }
+
+    async fn get_room_infos(&self) -> StoreResult<Vec<RoomInfo>> {
+        self.scan_values(tables::ROOM_INFO, &[]).await
+    }
+
+    async fn get_stripped_room_infos(&self) -> StoreResult<Vec<RoomInfo>> {
+        self.scan_values(tables::STRIPPED_ROOM_INFO, &[]).await
+    }
+
+    async fn get_users_with_display_name(
+        &self,
+        room_id: &RoomId,
+        display_name: &str,
+    ) -> StoreResult<BTreeSet<OwnedUserId>> {
+        let key = self.encode_key(tables::DISPLAY_NAME, (room_id, display_name));
+        Ok(self.get_value(tables::DISPLAY_NAME, &key).await?.unwrap_or_default())
+    }
+
+    async fn get_account_data_event(
+        &self,
+        event_type: GlobalAccountDataEventType,
+    ) -> StoreResult<Option<Raw<AnyGlobalAccountDataEvent>>> {
+        self.get_value(tables::ACCOUNT_DATA, &self.encode_key(tables::ACCOUNT_DATA, event_type))
+            .await
+    }
+
+    async fn get_room_account_data_event(
+        &self,
+        room_id: &RoomId,
+        event_type: RoomAccountDataEventType,
+    ) -> StoreResult<Option<Raw<AnyRoomAccountDataEvent>>> {
+        let key = self.encode_key(tables::ROOM_ACCOUNT_DATA, (room_id, event_type));
+        self.get_value(tables::ROOM_ACCOUNT_DATA, &key).await
+    }
+
+    async fn get_user_room_receipt_event(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        thread: ReceiptThread,
+        user_id: &UserId,
+    ) -> StoreResult<Option<(OwnedEventId, Receipt)>> {
+        self.get_user_room_receipt_event(room_id, receipt_type, thread, user_id).await
+    }
+
+    async fn get_event_room_receipt_events(
+        &self,
+        room_id: &RoomId,
+        receipt_type: ReceiptType,
+        thread: ReceiptThread,
+        event_id: &EventId,
+    ) -> StoreResult<Vec<(OwnedUserId, Receipt)>> {
+        self.get_event_room_receipt_events(room_id, receipt_type, thread, event_id).await
+    }
+
+    async fn get_custom_value(&self, key: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        let key = self.encode_key(tables::CUSTOM, EncodeUnchecked::from(key));
+        self.get_value(tables::CUSTOM, &key).await
+    }
+
+    async fn set_custom_value(&self, key: &[u8], value: Vec<u8>) -> StoreResult<Option<Vec<u8>>> {
+        self.set_custom_value(key, value).await
+    }
+
+    async fn remove_custom_value(&self, key: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        self.remove_custom_value(key).await
+    }
+
+    async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> StoreResult<()> {
+        self.add_media_content(request, data).await
+    }
+
+    async fn get_media_content(&self, request: &MediaRequest) -> StoreResult<Option<Vec<u8>>> {
+        self.get_media_content(request).await
+    }
+
+    async fn remove_media_content(&self, request: &MediaRequest) -> StoreResult<()> {
+        self.remove_media_content(request).await
+    }
+
+    async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> StoreResult<()> {
+        self.remove_media_content_for_uri(uri).await
+    }
+
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> StoreResult<()> {
+        self.clean_media_cache(policy).await
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> StoreResult<usize> {
+        self.trim_media_cache(max_size).await
+    }
+
+    async fn media_cache_stats(&self) -> StoreResult<MediaCacheStats> {
+        self.media_cache_stats().await
+    }
+
+    async fn get_event_chunk_range(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<Option<RangeInclusive<i64>>> {
+        let key = self.encode_key(tables::EVENT_CHUNK_RANGE, room_id);
+        Ok(self
+            .get_value::<(i64, i64)>(tables::EVENT_CHUNK_RANGE, &key)
+            .await?
+            .map(|(start, end)| start..=end))
+    }
+
+    async fn get_event_chunk(
+        &self,
+        room_id: &RoomId,
+        chunk_id: i64,
+    ) -> StoreResult<Option<EventChunk>> {
+        let key = self.encode_key(tables::EVENT_CHUNK, (room_id, chunk_id.to_string()));
+        self.get_value(tables::EVENT_CHUNK, &key).await
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> StoreResult<Option<i64>> {
+        self.get_value(tables::EVENT, &self.encode_key(tables::EVENT, (room_id, event_id))).await
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> StoreResult<BTreeMap<OwnedEventId, i64>> {
+        let mut chunk_ids = BTreeMap::new();
+
+        for event_id in event_ids {
+            let key = self.encode_key(tables::EVENT, (room_id, event_id));
+            if let Some(chunk_id) = self.get_value(tables::EVENT, &key).await? {
+                chunk_ids.insert(event_id.clone(), chunk_id);
+            }
+        }
+
+        Ok(chunk_ids)
+    }
+
+    async fn remove_room(&self, room_id: &RoomId) -> StoreResult<()> {
+        self.remove_room(room_id).await
+    }
+
+    async fn clear(&self) -> StoreResult<()> {
+        self.clear().await
+    }
+}
+
+/// A room member.
+#[derive(Debug, Serialize, Deserialize)]
+struct RoomMember {
+    user_id: OwnedUserId,
+    membership: MembershipState,
+}
+
+impl From<&SyncStateEvent<RoomMemberEventContent>> for RoomMember {
+    fn from(event: &SyncStateEvent<RoomMemberEventContent>) -> Self {
+        Self { user_id: event.state_key().clone(), membership: event.membership().clone() }
+    }
+}
+
+impl From<&StrippedRoomMemberEvent> for RoomMember {
+    fn from(event: &StrippedRoomMemberEvent) -> Self {
+        Self { user_id: event.state_key.clone(), membership: event.content.membership.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_base::statestore_integration_tests;
+
+    use super::{KvStateStore, StateStore, StoreError, StoreResult};
+    use crate::MemoryKvBackend;
+
+    async fn get_store() -> StoreResult<impl StateStore> {
+        KvStateStore::open(MemoryKvBackend::new(), None).await.map_err(StoreError::backend)
+    }
+
+    statestore_integration_tests!(with_media_tests);
+}
+
+#[cfg(test)]
+mod encrypted_tests {
+    use matrix_sdk_base::statestore_integration_tests;
+
+    use super::{KvStateStore, StateStore, StoreError, StoreResult};
+    use crate::MemoryKvBackend;
+
+    async fn get_store() -> StoreResult<impl StateStore> {
+        KvStateStore::open(MemoryKvBackend::new(), Some("secret"))
+            .await
+            .map_err(StoreError::backend)
+    }
+
+    statestore_integration_tests!(with_media_tests);
+}
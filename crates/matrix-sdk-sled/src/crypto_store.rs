@@ -13,13 +13,16 @@
 // limitations under the License.
 
 use std::{
-    borrow::Cow,
     collections::HashMap,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
 use async_trait::async_trait;
+use matrix_sdk_base::store::{
+    encode_key::{EncodeKey, ENCODE_SEPARATOR},
+    IntegrityIssue, IntegrityReport,
+};
 use matrix_sdk_crypto::{
     olm::{
         IdentityKeys, InboundGroupSession, OutboundGroupSession, PickledInboundGroupSession,
@@ -29,16 +32,18 @@ use matrix_sdk_crypto::{
         caches::SessionStore, BackupKeys, Changes, CryptoStore, CryptoStoreError, Result,
         RoomKeyCounts, RoomSettings,
     },
-    types::{
-        events::{room_key_request::SupportedKeyInfo, room_key_withheld::RoomKeyWithheldEvent},
-        EventEncryptionAlgorithm,
-    },
+    types::events::room_key_withheld::RoomKeyWithheldEvent,
     GossipRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities, SecretInfo,
     TrackedUser,
 };
 use matrix_sdk_store_encryption::StoreCipher;
-use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId};
-use serde::{de::DeserializeOwned, Serialize};
+use ruma::{
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
     Batch, Config, Db, IVec, Transactional, Tree,
@@ -47,7 +52,7 @@ use tokio::sync::Mutex;
 use tracing::debug;
 
 use super::OpenStoreError;
-use crate::encode_key::{EncodeKey, ENCODE_SEPARATOR};
+use crate::rotate::{rotate_store_cipher, RotateError};
 
 const DATABASE_VERSION: u8 = 7;
 
@@ -65,96 +70,6 @@ const DIRECT_WITHHELD_INFO_TABLE: &str = "crypto-store-direct-withheld-info";
 const NO_OLM_SENT_TABLE: &str = "crypto-store-no-olm-sent";
 const ROOM_SETTINGS_TABLE: &str = "crypto-store-secret-room-settings";
 
-impl EncodeKey for InboundGroupSession {
-    fn encode(&self) -> Vec<u8> {
-        (self.room_id(), self.session_id()).encode()
-    }
-
-    fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
-        (self.room_id(), self.session_id()).encode_secure(table_name, store_cipher)
-    }
-}
-
-impl EncodeKey for OutboundGroupSession {
-    fn encode(&self) -> Vec<u8> {
-        self.room_id().encode()
-    }
-    fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
-        self.room_id().encode_secure(table_name, store_cipher)
-    }
-}
-
-impl EncodeKey for Session {
-    fn encode(&self) -> Vec<u8> {
-        let sender_key = self.sender_key().to_base64();
-        let session_id = self.session_id();
-
-        [sender_key.as_bytes(), &[ENCODE_SEPARATOR], session_id.as_bytes(), &[ENCODE_SEPARATOR]]
-            .concat()
-    }
-
-    fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
-        let sender_key =
-            store_cipher.hash_key(table_name, self.sender_key().to_base64().as_bytes());
-        let session_id = store_cipher.hash_key(table_name, self.session_id().as_bytes());
-
-        [sender_key.as_slice(), &[ENCODE_SEPARATOR], session_id.as_slice(), &[ENCODE_SEPARATOR]]
-            .concat()
-    }
-}
-
-impl EncodeKey for SecretInfo {
-    fn encode(&self) -> Vec<u8> {
-        match self {
-            SecretInfo::KeyRequest(k) => k.encode(),
-            SecretInfo::SecretRequest(s) => s.encode(),
-        }
-    }
-    fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
-        match self {
-            SecretInfo::KeyRequest(k) => k.encode_secure(table_name, store_cipher),
-            SecretInfo::SecretRequest(s) => s.encode_secure(table_name, store_cipher),
-        }
-    }
-}
-
-impl EncodeKey for EventEncryptionAlgorithm {
-    fn encode_as_bytes(&self) -> Cow<'_, [u8]> {
-        let s: &str = self.as_ref();
-        s.as_bytes().into()
-    }
-}
-
-impl EncodeKey for SupportedKeyInfo {
-    fn encode(&self) -> Vec<u8> {
-        (self.room_id(), &self.algorithm(), self.session_id()).encode()
-    }
-    fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
-        let room_id = store_cipher.hash_key(table_name, self.room_id().as_bytes());
-        let algorithm = store_cipher.hash_key(table_name, self.algorithm().as_ref().as_bytes());
-        let session_id = store_cipher.hash_key(table_name, self.session_id().as_bytes());
-
-        [
-            room_id.as_slice(),
-            &[ENCODE_SEPARATOR],
-            algorithm.as_slice(),
-            &[ENCODE_SEPARATOR],
-            session_id.as_slice(),
-            &[ENCODE_SEPARATOR],
-        ]
-        .concat()
-    }
-}
-
-impl EncodeKey for ReadOnlyDevice {
-    fn encode(&self) -> Vec<u8> {
-        (self.user_id(), self.device_id()).encode()
-    }
-    fn encode_secure(&self, table_name: &str, store_cipher: &StoreCipher) -> Vec<u8> {
-        (self.user_id(), self.device_id()).encode_secure(table_name, store_cipher)
-    }
-}
-
 #[derive(Clone, Debug)]
 pub struct AccountInfo {
     user_id: OwnedUserId,
@@ -195,6 +110,8 @@ pub struct SledCryptoStore {
     direct_withheld_info: Tree,
     no_olm_sent: Tree,
     room_settings: Tree,
+
+    leases: Tree,
 }
 
 impl std::fmt::Debug for SledCryptoStore {
@@ -235,6 +152,140 @@ impl SledCryptoStore {
         SledCryptoStore::open_helper(db, None, store_cipher).await
     }
 
+    /// Change the passphrase that is used to encrypt the store cipher of this
+    /// store.
+    ///
+    /// The data of the store is not re-encrypted, only the store cipher is
+    /// exported again with the new passphrase.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        let key = self
+            .inner
+            .get("store_cipher".encode())
+            .map_err(CryptoStoreError::backend)?
+            .ok_or(CryptoStoreError::UnpicklingError)?;
+
+        let cipher = StoreCipher::import(old_passphrase, &key)
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+        #[cfg(not(test))]
+        let export = cipher.export(new_passphrase);
+        #[cfg(test)]
+        let export = cipher._insecure_export_fast_for_testing(new_passphrase);
+        self.inner
+            .insert("store_cipher".encode(), export.map_err(CryptoStoreError::backend)?)
+            .map_err(CryptoStoreError::backend)?;
+        self.inner.flush_async().await.map_err(CryptoStoreError::backend)?;
+
+        Ok(())
+    }
+
+    /// Rotate the keys that are used to encrypt the data of this store.
+    ///
+    /// All the values of the database are re-encrypted with a new key and all
+    /// the hashed keys are hashed again with a new key, in a single
+    /// transaction. The new store cipher is encrypted with the given
+    /// passphrase.
+    ///
+    /// Returns the store using the new keys. This fails if other clones of
+    /// this store, or the state store it was opened from, exist, since they
+    /// would keep using the old keys, so this should be called before the
+    /// store is given to a client.
+    pub async fn rotate_encryption_key(self, passphrase: &str) -> Result<Self> {
+        let Some(store_cipher) = &self.store_cipher else {
+            return Err(CryptoStoreError::backend(RotateError::Unencrypted));
+        };
+
+        if Arc::strong_count(store_cipher) > 1 {
+            return Err(CryptoStoreError::backend(RotateError::InUse));
+        }
+
+        let store_cipher =
+            rotate_store_cipher(&self.inner, passphrase).map_err(CryptoStoreError::backend)?;
+
+        Ok(Self { store_cipher: Some(Arc::new(store_cipher)), ..self })
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks the schema version of the database, that the values can be
+    /// decrypted and deserialized, and that the Olm sessions are stored with
+    /// an account. See [`IntegrityIssue`] for what the repair does for each
+    /// problem.
+    ///
+    /// This should be called before the store is given to a client, since the
+    /// values that are cached in memory are not updated.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        let version = self
+            .inner
+            .get("store_version")
+            .map_err(CryptoStoreError::backend)?
+            .and_then(|v| v.first().copied());
+
+        if version != Some(DATABASE_VERSION) {
+            let issue = IntegrityIssue::SchemaVersion {
+                found: version.map(Into::into),
+                expected: DATABASE_VERSION.into(),
+            };
+            return Ok(IntegrityReport { issues: vec![issue], repaired: false });
+        }
+
+        let mut issues = Vec::new();
+
+        for (name, tree) in [
+            ("account", &self.account),
+            ("private_identity", &self.private_identity),
+            ("session", &self.sessions),
+            ("inbound_group_sessions", &self.inbound_group_sessions),
+            ("outbound_group_sessions", &self.outbound_group_sessions),
+            ("outgoing_secret_requests", &self.outgoing_secret_requests),
+            ("unsent_secret_requests", &self.unsent_secret_requests),
+            ("devices", &self.devices),
+            ("identities", &self.identities),
+            ("tracked_users", &self.tracked_users),
+            ("direct_withheld_info", &self.direct_withheld_info),
+            ("room_settings", &self.room_settings),
+        ] {
+            let mut batch = Batch::default();
+            let mut count = 0;
+
+            for entry in tree.iter() {
+                let (key, value) = entry.map_err(CryptoStoreError::backend)?;
+                if self.deserialize_value::<IgnoredAny>(&value).is_err() {
+                    batch.remove(key);
+                    count += 1;
+                }
+            }
+
+            if count > 0 {
+                issues.push(IntegrityIssue::CorruptedValues { table: name.to_owned(), count });
+
+                if repair {
+                    tree.apply_batch(batch).map_err(CryptoStoreError::backend)?;
+                }
+            }
+        }
+
+        let has_account =
+            self.account.contains_key("account".encode()).map_err(CryptoStoreError::backend)?;
+
+        if !has_account && !self.sessions.is_empty() {
+            issues.push(IntegrityIssue::SessionsWithoutAccount { count: self.sessions.len() });
+
+            if repair {
+                self.sessions.clear().map_err(CryptoStoreError::backend)?;
+            }
+        }
+
+        if repair {
+            self.inner.flush_async().await.map_err(CryptoStoreError::backend)?;
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
     fn get_account_info(&self) -> Option<AccountInfo> {
         self.account_info.read().unwrap().clone()
     }
@@ -408,6 +459,7 @@ impl SledCryptoStore {
 
         let direct_withheld_info = db.open_tree("direct_withheld_info")?;
         let no_olm_sent = db.open_tree("no_olm_sent")?;
+        let leases = db.open_tree("leases")?;
 
         let database = Self {
             account_info: RwLock::new(None).into(),
@@ -430,6 +482,7 @@ impl SledCryptoStore {
             direct_withheld_info,
             no_olm_sent,
             room_settings,
+            leases,
         };
 
         database.upgrade().await?;
@@ -831,6 +884,23 @@ impl CryptoStore for SledCryptoStore {
         Ok(self.session_cache.get(sender_key))
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        let account_info = self.get_account_info().ok_or(CryptoStoreError::AccountUnset)?;
+
+        self.sessions
+            .iter()
+            .map(|s| self.deserialize_value(&s.map_err(CryptoStoreError::backend)?.1))
+            .map(|p| {
+                Ok(Session::from_pickle(
+                    account_info.user_id.clone(),
+                    account_info.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    p?,
+                ))
+            })
+            .collect()
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -1106,6 +1176,130 @@ impl CryptoStore for SledCryptoStore {
         self.inner.insert(key, value).map_err(CryptoStoreError::backend)?;
         Ok(())
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let mut inbound_group_sessions = Batch::default();
+        for key in self
+            .inbound_group_sessions
+            .scan_prefix(self.encode_key(INBOUND_GROUP_TABLE_NAME, room_id))
+            .keys()
+        {
+            inbound_group_sessions.remove(key.map_err(CryptoStoreError::backend)?);
+        }
+
+        // The withheld info is keyed by session ID first, so we look for the
+        // keys that end with the room ID.
+        let room_suffix = self.encode_key(DIRECT_WITHHELD_INFO_TABLE, room_id);
+        let mut direct_withheld_info = Batch::default();
+        for key in self.direct_withheld_info.iter().keys() {
+            let key = key.map_err(CryptoStoreError::backend)?;
+            if key.len() > room_suffix.len()
+                && key.ends_with(&room_suffix)
+                && key[key.len() - room_suffix.len() - 1] == ENCODE_SEPARATOR
+            {
+                direct_withheld_info.remove(key);
+            }
+        }
+
+        let outbound_key = self.encode_key(OUTBOUND_GROUP_TABLE_NAME, room_id);
+        let settings_key = self.encode_key(ROOM_SETTINGS_TABLE, room_id);
+
+        (
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.direct_withheld_info,
+            &self.room_settings,
+        )
+            .transaction(|(inbound, outbound, withheld, settings)| {
+                inbound.apply_batch(&inbound_group_sessions)?;
+                withheld.apply_batch(&direct_withheld_info)?;
+                outbound.remove(outbound_key.as_slice())?;
+                settings.remove(settings_key.as_slice())?;
+
+                Ok::<_, ConflictableTransactionError<CryptoStoreError>>(())
+            })
+            .map_err(CryptoStoreError::backend)?;
+
+        self.inner.flush_async().await.map_err(CryptoStoreError::backend)?;
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        for tree in [
+            &self.account,
+            &self.private_identity,
+            &self.olm_hashes,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.outgoing_secret_requests,
+            &self.unsent_secret_requests,
+            &self.secret_requests_by_info,
+            &self.devices,
+            &self.identities,
+            &self.tracked_users,
+            &self.direct_withheld_info,
+            &self.no_olm_sent,
+            &self.room_settings,
+        ] {
+            tree.clear().map_err(CryptoStoreError::backend)?;
+        }
+
+        // The custom values are stored in the default tree, with the data
+        // needed to open the store.
+        let store_cipher_key = "store_cipher".encode();
+        for key in self.inner.iter().keys() {
+            let key = key.map_err(CryptoStoreError::backend)?;
+            if &*key != store_cipher_key.as_slice() && &*key != b"store_version".as_slice() {
+                self.inner.remove(key).map_err(CryptoStoreError::backend)?;
+            }
+        }
+
+        self.inner.flush_async().await.map_err(CryptoStoreError::backend)?;
+
+        *self.account_info.write().unwrap() = None;
+        self.session_cache.clear();
+
+        Ok(())
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool> {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let expiration = now + u64::from(lease_duration_ms);
+
+        let acquired = self
+            .leases
+            .transaction(|leases| {
+                if let Some(lease) = leases.get(key)? {
+                    let (current_holder, current_expiration): (String, u64) =
+                        serde_json::from_slice(&lease)
+                            .map_err(ConflictableTransactionError::Abort)?;
+
+                    if current_holder != holder && current_expiration > now {
+                        return Ok(false);
+                    }
+                }
+
+                let lease = serde_json::to_vec(&(holder, expiration))
+                    .map_err(ConflictableTransactionError::Abort)?;
+                leases.insert(key, lease)?;
+
+                Ok(true)
+            })
+            .map_err(CryptoStoreError::backend)?;
+
+        Ok(acquired)
+    }
+
+    async fn clear_caches(&self) {
+        self.session_cache.clear();
+    }
 }
 
 #[cfg(test)]
@@ -1131,8 +1325,13 @@ mod tests {
 
 #[cfg(test)]
 mod encrypted_tests {
-    use matrix_sdk_crypto::cryptostore_integration_tests;
+    use matrix_sdk_crypto::{
+        cryptostore_integration_tests,
+        store::{Changes, CryptoStore, RoomSettings},
+    };
+    use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
+    use ruma::room_id;
     use tempfile::{tempdir, TempDir};
 
     use super::SledCryptoStore;
@@ -1148,4 +1347,26 @@ mod encrypted_tests {
             .expect("Can't create a passphrase protected store")
     }
     cryptostore_integration_tests!();
+
+    #[async_test]
+    async fn rotate_encryption_key() {
+        let room_id = room_id!("!test:localhost");
+        let settings = RoomSettings { only_allow_trusted_devices: true, ..RoomSettings::default() };
+
+        let store = get_store("rotate_encryption_key", Some("password")).await;
+        let mut changes = Changes::default();
+        changes.room_settings.insert(room_id.to_owned(), settings.clone());
+        store.save_changes(changes).await.unwrap();
+
+        // The store can't be rotated while it is cloned.
+        let clone = store.clone();
+        assert!(store.rotate_encryption_key("password").await.is_err());
+
+        let store = clone.rotate_encryption_key("password").await.unwrap();
+        assert_eq!(store.get_room_settings(room_id).await.unwrap().as_ref(), Some(&settings));
+        drop(store);
+
+        let store = get_store("rotate_encryption_key", Some("password")).await;
+        assert_eq!(store.get_room_settings(room_id).await.unwrap(), Some(settings));
+    }
 }
@@ -9,7 +9,8 @@ use thiserror::Error;
 
 #[cfg(feature = "crypto-store")]
 mod crypto_store;
-mod encode_key;
+#[cfg(any(feature = "state-store", feature = "crypto-store"))]
+mod rotate;
 #[cfg(feature = "state-store")]
 mod state_store;
 
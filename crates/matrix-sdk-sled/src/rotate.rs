@@ -0,0 +1,214 @@
+//! Rotation of the keys of the store cipher of a sled database.
+
+use matrix_sdk_base::{
+    store::encode_key::{EncodeKey, ENCODE_SEPARATOR},
+    StateStoreDataKey,
+};
+use matrix_sdk_store_encryption::{EncryptedValue, Error as KeyEncryptionError, StoreCipher};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Batch, Db, Transactional, Tree,
+};
+use thiserror::Error;
+
+/// The name of the default tree of a sled database.
+const DEFAULT_TREE: &str = "__sled__default";
+
+/// The length of a hashed key segment, including the separator.
+const HASHED_SEGMENT_LEN: usize = 33;
+
+/// How the keys of a tree are encoded when the store is encrypted.
+#[derive(Clone, Copy, Debug)]
+enum KeySchema {
+    /// The keys are never hashed.
+    Plain,
+
+    /// Every component of the keys is hashed, except for the given keys that
+    /// are always encoded with [`EncodeKey::encode()`].
+    Hashed { plain_keys: &'static [&'static str] },
+}
+
+/// A tree where every component of the keys is hashed.
+const HASHED: KeySchema = KeySchema::Hashed { plain_keys: &[] };
+
+/// The key schema of every tree of the state store and the crypto store.
+///
+/// Rotating the store cipher fails if the database has a tree that is not
+/// listed here, so new trees must be added with the schema of their keys.
+const TREE_KEY_SCHEMAS: &[(&str, KeySchema)] = &[
+    (DEFAULT_TREE, KeySchema::Plain),
+    // State store.
+    ("account-data", HASHED),
+    ("custom", HASHED),
+    ("display-name", HASHED),
+    ("event", HASHED),
+    ("event-chunk", HASHED),
+    ("event-chunk-range", HASHED),
+    ("kv", KeySchema::Hashed { plain_keys: &[StateStoreDataKey::SYNC_TOKEN] }),
+    ("media", HASHED),
+    ("media-metadata", HASHED),
+    ("presence", HASHED),
+    ("profile", HASHED),
+    ("room", HASHED),
+    ("room-account-data", HASHED),
+    ("room-event-receipt", HASHED),
+    ("room-info", HASHED),
+    ("room-state", HASHED),
+    ("room-user-receipt", HASHED),
+    ("stripped-room-info", HASHED),
+    ("stripped-room-state", HASHED),
+    ("stripped-user-ids", HASHED),
+    ("user-ids", HASHED),
+    // Crypto store.
+    ("account", KeySchema::Plain),
+    ("devices", HASHED),
+    ("direct_withheld_info", HASHED),
+    ("identities", HASHED),
+    ("inbound_group_sessions", HASHED),
+    ("leases", KeySchema::Plain),
+    ("no_olm_sent", HASHED),
+    ("olm_hashes", KeySchema::Plain),
+    ("outbound_group_sessions", HASHED),
+    ("outgoing_secret_requests", KeySchema::Plain),
+    ("private_identity", KeySchema::Plain),
+    ("room_settings", HASHED),
+    ("secret_requests_by_info", HASHED),
+    ("session", HASHED),
+    ("tracked_users", HASHED),
+    ("unsent_secret_requests", KeySchema::Plain),
+];
+
+/// An error that can occur when rotating the store cipher.
+#[derive(Debug, Error)]
+pub(crate) enum RotateError {
+    #[error(transparent)]
+    Sled(#[from] sled::Error),
+    #[error(transparent)]
+    Encryption(#[from] KeyEncryptionError),
+    #[error("the store is not encrypted")]
+    Unencrypted,
+    #[error("the store is used by other handles")]
+    InUse,
+    #[error("the key schema of the tree `{0}` is unknown")]
+    UnknownTree(String),
+    #[error("a key of the tree `{0}` is not hashed")]
+    UnexpectedKey(String),
+}
+
+impl From<TransactionError<KeyEncryptionError>> for RotateError {
+    fn from(e: TransactionError<KeyEncryptionError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e.into(),
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+/// Replace the store cipher saved in the database with one with new keys,
+/// re-encrypt all the encrypted values and re-hash all the hashed keys of all
+/// the trees of the database, according to [`TREE_KEY_SCHEMAS`].
+///
+/// Everything happens in a single transaction, and the new store cipher is
+/// returned.
+pub(crate) fn rotate_store_cipher(db: &Db, passphrase: &str) -> Result<StoreCipher, RotateError> {
+    let encrypted = db.get("store_cipher".encode())?.ok_or(RotateError::Unencrypted)?;
+    let old_cipher = StoreCipher::import(passphrase, &encrypted)?;
+    let new_cipher = old_cipher.rotate_encryption_key()?;
+    #[cfg(not(test))]
+    let export = new_cipher.export(passphrase)?;
+    #[cfg(test)]
+    let export = new_cipher._insecure_export_fast_for_testing(passphrase)?;
+
+    let mut trees = Vec::new();
+    let mut batches = Vec::new();
+
+    for name in db.tree_names() {
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let schema = TREE_KEY_SCHEMAS
+            .iter()
+            .find_map(|(tree_name, schema)| (*tree_name == name).then_some(*schema))
+            .ok_or_else(|| RotateError::UnknownTree(name.clone()))?;
+
+        let tree = db.open_tree(&name)?;
+        let mut removed = Vec::new();
+        let mut batch = Batch::default();
+
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+
+            let new_key = match schema {
+                KeySchema::Plain => None,
+                KeySchema::Hashed { plain_keys } => {
+                    if plain_keys.iter().any(|plain_key| *key == *plain_key.encode()) {
+                        None
+                    } else {
+                        let new_key = rehash_key(&key, &new_cipher)
+                            .ok_or_else(|| RotateError::UnexpectedKey(name.clone()))?;
+                        Some(new_key)
+                    }
+                }
+            };
+            let new_value = match serde_json::from_slice::<EncryptedValue>(&value) {
+                Ok(value) => {
+                    let value = old_cipher.reencrypt_value_data(value, &new_cipher)?;
+                    Some(serde_json::to_vec(&value).map_err(KeyEncryptionError::Json)?)
+                }
+                Err(_) => None,
+            };
+
+            match (new_key, new_value) {
+                (Some(new_key), new_value) => {
+                    removed.push(key);
+                    batch.insert(new_key, new_value.map_or(value, Into::into));
+                }
+                (None, Some(new_value)) => batch.insert(key, new_value),
+                (None, None) => {}
+            }
+        }
+
+        if name == DEFAULT_TREE {
+            batch.insert("store_cipher".encode(), export.as_slice());
+        }
+
+        // Remove the old keys before inserting the new ones, so a new key can't
+        // be removed if it is equal to an old one.
+        let mut removals = Batch::default();
+        for key in removed {
+            removals.remove(key);
+        }
+
+        trees.push(tree);
+        batches.push((removals, batch));
+    }
+
+    trees.as_slice().transaction(|trees| {
+        for (tree, (removals, batch)) in trees.iter().zip(&batches) {
+            tree.apply_batch(removals)?;
+            tree.apply_batch(batch)?;
+        }
+
+        Ok::<_, ConflictableTransactionError<KeyEncryptionError>>(())
+    })?;
+
+    db.flush()?;
+
+    Ok(new_cipher)
+}
+
+/// Hash again the given key with the newest key of the store cipher.
+///
+/// Returns `None` if the key is not made of hashed segments.
+fn rehash_key(key: &[u8], new_cipher: &StoreCipher) -> Option<Vec<u8>> {
+    let is_hashed = !key.is_empty()
+        && key.len() % HASHED_SEGMENT_LEN == 0
+        && key.chunks(HASHED_SEGMENT_LEN).all(|segment| segment.last() == Some(&ENCODE_SEPARATOR));
+
+    is_hashed.then(|| {
+        key.chunks(HASHED_SEGMENT_LEN)
+            .flat_map(|segment| {
+                let hash = new_cipher.rehash_key(&segment[..HASHED_SEGMENT_LEN - 1]);
+                [hash.as_slice(), &[ENCODE_SEPARATOR]].concat()
+            })
+            .collect()
+    })
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use matrix_sdk_base::{
-    store::{Result as StoreResult, StoreError},
+    store::{encode_key::EncodeKey, Result as StoreResult, StoreError},
     RoomInfo, StateStoreDataKey,
 };
 use ruma::{
@@ -28,9 +28,8 @@ use sled::{transaction::TransactionError, Batch, Transactional, Tree};
 use tracing::debug;
 
 use super::{keys, Result, RoomMember, SledStateStore, SledStoreError};
-use crate::encode_key::EncodeKey;
 
-const DATABASE_VERSION: u8 = 7;
+pub(super) const DATABASE_VERSION: u8 = 7;
 
 const VERSION_KEY: &str = "state-store-version";
 
@@ -107,7 +106,7 @@ impl SledStateStore {
     /// Get the version of the database.
     ///
     /// Returns `0` for a new database.
-    fn db_version(&self) -> Result<u8> {
+    pub(super) fn db_version(&self) -> Result<u8> {
         Ok(self
             .inner
             .get(VERSION_KEY)?
@@ -396,8 +395,8 @@ pub const V1_DB_STORES: &[&str] = &[
 mod test {
     use assert_matches::assert_matches;
     use matrix_sdk_base::{
-        deserialized_responses::RawMemberEvent, RoomInfo, RoomMemberships, RoomState,
-        StateStoreDataKey,
+        deserialized_responses::RawMemberEvent, store::encode_key::EncodeKey, RoomInfo,
+        RoomMemberships, RoomState, StateStoreDataKey,
     };
     use matrix_sdk_test::{async_test, test_json};
     use ruma::{
@@ -413,10 +412,7 @@ mod test {
     use tempfile::TempDir;
 
     use super::{old_keys, MigrationConflictStrategy};
-    use crate::{
-        encode_key::EncodeKey,
-        state_store::{keys, Result, SledStateStore, SledStoreError},
-    };
+    use crate::state_store::{keys, Result, SledStateStore, SledStoreError};
 
     #[async_test]
     pub async fn migrating_v1_to_2_plain() -> Result<()> {
@@ -13,7 +13,8 @@
 // limitations under the License.
 
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::RangeInclusive,
     path::PathBuf,
     sync::Arc,
     time::{Instant, SystemTime, UNIX_EPOCH},
@@ -24,8 +25,17 @@ use futures_core::stream::Stream;
 use futures_util::stream::{self, TryStreamExt};
 use matrix_sdk_base::{
     deserialized_responses::RawMemberEvent,
-    media::{MediaRequest, UniqueKey},
-    store::{Result as StoreResult, StateChanges, StateStore, StoreError},
+    event_cache::{EventCacheChanges, EventChunk},
+    media::{
+        select_for_trimming, should_update_media_last_access, MediaCacheStats, MediaRequest,
+        MediaRetentionPolicy, UniqueKey,
+    },
+    store::{
+        encode_key::{EncodeKey, EncodeUnchecked, ENCODE_SEPARATOR},
+        integrity::compute_display_names,
+        IntegrityIssue, IntegrityReport, Result as StoreResult, StateChanges, StateStore,
+        StoreError,
+    },
     MinimalStateEvent, RoomInfo, RoomMemberships, StateStoreDataKey, StateStoreDataValue,
 };
 use matrix_sdk_store_encryption::{Error as KeyEncryptionError, StoreCipher};
@@ -41,12 +51,18 @@ use ruma::{
         GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType, SyncStateEvent,
     },
     serde::Raw,
-    CanonicalJsonObject, EventId, IdParseError, MxcUri, OwnedEventId, OwnedUserId, RoomId,
-    RoomVersionId, UserId,
+    CanonicalJsonObject, EventId, IdParseError, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId,
+    OwnedUserId, RoomId, RoomVersionId, UserId,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
 };
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sled::{
-    transaction::{ConflictableTransactionError, TransactionError},
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
     Config, Db, Transactional, Tree,
 };
 use tokio::task::spawn_blocking;
@@ -57,7 +73,7 @@ mod migrations;
 pub use self::migrations::MigrationConflictStrategy;
 #[cfg(feature = "crypto-store")]
 use super::OpenStoreError;
-use crate::encode_key::{EncodeKey, EncodeUnchecked};
+use crate::rotate::{rotate_store_cipher, RotateError};
 #[cfg(feature = "crypto-store")]
 pub use crate::SledCryptoStore;
 
@@ -112,8 +128,12 @@ mod keys {
     pub const ACCOUNT_DATA: &str = "account-data";
     pub const CUSTOM: &str = "custom";
     pub const DISPLAY_NAME: &str = "display-name";
+    pub const EVENT: &str = "event";
+    pub const EVENT_CHUNK: &str = "event-chunk";
+    pub const EVENT_CHUNK_RANGE: &str = "event-chunk-range";
     pub const USER_ID: &str = "user-ids";
     pub const MEDIA: &str = "media";
+    pub const MEDIA_METADATA: &str = "media-metadata";
     pub const PRESENCE: &str = "presence";
     pub const PROFILE: &str = "profile";
     pub const ROOM_ACCOUNT_DATA: &str = "room-account-data";
@@ -291,6 +311,10 @@ pub struct SledStateStore {
     room_user_receipts: Tree,
     room_event_receipts: Tree,
     media: Tree,
+    media_metadata: Tree,
+    event_chunks: Tree,
+    event_chunk_ranges: Tree,
+    events: Tree,
     custom: Tree,
 }
 
@@ -330,6 +354,11 @@ impl SledStateStore {
         let room_event_receipts = db.open_tree(keys::ROOM_EVENT_RECEIPT)?;
 
         let media = db.open_tree(keys::MEDIA)?;
+        let media_metadata = db.open_tree(keys::MEDIA_METADATA)?;
+
+        let event_chunks = db.open_tree(keys::EVENT_CHUNK)?;
+        let event_chunk_ranges = db.open_tree(keys::EVENT_CHUNK_RANGE)?;
+        let events = db.open_tree(keys::EVENT)?;
 
         let custom = db.open_tree(keys::CUSTOM)?;
 
@@ -352,6 +381,10 @@ impl SledStateStore {
             room_user_receipts,
             room_event_receipts,
             media,
+            media_metadata,
+            event_chunks,
+            event_chunk_ranges,
+            events,
             custom,
         })
     }
@@ -361,6 +394,286 @@ impl SledStateStore {
         SledStateStoreBuilder::new()
     }
 
+    /// Change the passphrase that is used to encrypt the store cipher of this
+    /// store.
+    ///
+    /// The data of the store is not re-encrypted, only the store cipher is
+    /// exported again with the new passphrase.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store is not encrypted or if the old passphrase
+    /// is wrong.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        let Some(inner) = self.inner.get("store_cipher".encode())? else {
+            return Err(StoreError::UnencryptedStore.into());
+        };
+
+        let cipher = StoreCipher::import(old_passphrase, &inner)?;
+        #[cfg(not(test))]
+        let export = cipher.export(new_passphrase)?;
+        #[cfg(test)]
+        let export = cipher._insecure_export_fast_for_testing(new_passphrase)?;
+        self.inner.insert("store_cipher".encode(), export)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Rotate the keys that are used to encrypt the data of this store.
+    ///
+    /// All the values of the database are re-encrypted with a new key and all
+    /// the hashed keys are hashed again with a new key, in a single
+    /// transaction. This includes the data of the crypto store that uses the
+    /// same database. The new store cipher is encrypted with the given
+    /// passphrase.
+    ///
+    /// Returns the store using the new keys. This fails if other clones of
+    /// this store, or a crypto store opened with
+    /// [`SledStateStore::open_crypto_store()`], exist, since they would keep
+    /// using the old keys, so this should be called before the store is given
+    /// to a client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store is not encrypted, if it is used by other
+    /// handles, if the passphrase is wrong or if the database has a tree whose
+    /// key encoding is unknown.
+    pub async fn rotate_encryption_key(self, passphrase: &str) -> Result<Self> {
+        let Some(store_cipher) = &self.store_cipher else {
+            return Err(StoreError::UnencryptedStore.into());
+        };
+
+        if Arc::strong_count(store_cipher) > 1 {
+            return Err(StoreError::backend(RotateError::InUse).into());
+        }
+
+        let store_cipher = rotate_store_cipher(&self.inner, passphrase).map_err(|e| match e {
+            RotateError::Encryption(e) => SledStoreError::Encryption(e),
+            e => StoreError::backend(e).into(),
+        })?;
+
+        Ok(Self { store_cipher: Some(Arc::new(store_cipher)), ..self })
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks the schema version of the database, that the values can be
+    /// decrypted and deserialized, that the members, profiles and state events
+    /// belong to rooms with a room info, and that the display name maps match
+    /// the members. See [`IntegrityIssue`] for what the repair does for each
+    /// problem.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport> {
+        let version = self.db_version()?;
+        if version != migrations::DATABASE_VERSION {
+            let issue = IntegrityIssue::SchemaVersion {
+                found: (version != 0).then_some(version.into()),
+                expected: migrations::DATABASE_VERSION.into(),
+            };
+            return Ok(IntegrityReport { issues: vec![issue], repaired: false });
+        }
+
+        let mut issues = Vec::new();
+
+        for (name, tree) in [
+            (keys::KV, &self.kv),
+            (keys::ACCOUNT_DATA, &self.account_data),
+            (keys::PROFILE, &self.profiles),
+            (keys::DISPLAY_NAME, &self.display_names),
+            (keys::USER_ID, &self.user_ids),
+            (keys::ROOM_STATE, &self.room_state),
+            (keys::ROOM_ACCOUNT_DATA, &self.room_account_data),
+            (keys::STRIPPED_USER_ID, &self.stripped_user_ids),
+            (keys::STRIPPED_ROOM_STATE, &self.stripped_room_state),
+            (keys::PRESENCE, &self.presence),
+            (keys::ROOM_USER_RECEIPT, &self.room_user_receipts),
+            (keys::ROOM_EVENT_RECEIPT, &self.room_event_receipts),
+            (keys::MEDIA, &self.media),
+            (keys::MEDIA_METADATA, &self.media_metadata),
+            (keys::EVENT_CHUNK, &self.event_chunks),
+            (keys::EVENT_CHUNK_RANGE, &self.event_chunk_ranges),
+            (keys::EVENT, &self.events),
+            (keys::CUSTOM, &self.custom),
+        ] {
+            let count = self.check_tree(tree, repair, |value| {
+                self.deserialize_value::<IgnoredAny>(value).is_ok()
+            })?;
+            if count > 0 {
+                issues.push(IntegrityIssue::CorruptedValues { table: name.to_owned(), count });
+            }
+        }
+
+        let mut room_ids = Vec::new();
+        let mut joined_room_ids = Vec::new();
+
+        for (name, tree) in [
+            (keys::ROOM_INFO, &self.room_info),
+            (keys::STRIPPED_ROOM_INFO, &self.stripped_room_infos),
+        ] {
+            let count = self.check_tree(tree, repair, |value| {
+                match self.deserialize_value::<RoomInfo>(value) {
+                    Ok(room_info) => {
+                        // Display names are only tracked for the members of
+                        // non-stripped rooms.
+                        if name == keys::ROOM_INFO {
+                            joined_room_ids.push(room_info.room_id().to_owned());
+                        }
+                        room_ids.push(room_info.room_id().to_owned());
+                        true
+                    }
+                    Err(_) => false,
+                }
+            })?;
+
+            if count > 0 {
+                issues.push(IntegrityIssue::CorruptedValues { table: name.to_owned(), count });
+
+                if repair {
+                    // Sync again from scratch to restore the removed rooms.
+                    self.kv.remove(self.encode_kv_data_key(StateStoreDataKey::SyncToken))?;
+                }
+            }
+        }
+
+        for (name, tree) in [
+            (keys::PROFILE, &self.profiles),
+            (keys::DISPLAY_NAME, &self.display_names),
+            (keys::USER_ID, &self.user_ids),
+            (keys::ROOM_STATE, &self.room_state),
+            (keys::STRIPPED_USER_ID, &self.stripped_user_ids),
+            (keys::STRIPPED_ROOM_STATE, &self.stripped_room_state),
+        ] {
+            let known_prefixes: HashSet<Vec<u8>> =
+                room_ids.iter().map(|room_id| self.encode_key(name, room_id)).collect();
+            let mut orphans = HashSet::new();
+            let mut batch = sled::Batch::default();
+
+            for key in tree.iter().keys() {
+                let key = key?;
+                let prefix = self.room_key_prefix(&key).map(<[u8]>::to_vec);
+
+                if prefix.as_ref().map_or(true, |prefix| !known_prefixes.contains(prefix)) {
+                    orphans.insert(prefix);
+                    batch.remove(key);
+                }
+            }
+
+            if !orphans.is_empty() {
+                issues.push(IntegrityIssue::OrphanedRoomData {
+                    table: name.to_owned(),
+                    rooms: orphans.len(),
+                });
+
+                if repair {
+                    tree.apply_batch(batch)?;
+                }
+            }
+        }
+
+        // The display names can only be computed reliably once the broken
+        // values are gone.
+        if repair || issues.is_empty() {
+            let mut inconsistent_rooms = 0;
+
+            for room_id in joined_room_ids {
+                let expected: BTreeMap<Vec<u8>, Option<BTreeSet<OwnedUserId>>> =
+                    compute_display_names(self, &room_id)
+                        .await?
+                        .into_iter()
+                        .map(|(name, user_ids)| {
+                            (self.encode_key(keys::DISPLAY_NAME, (&room_id, name)), Some(user_ids))
+                        })
+                        .collect();
+
+                let prefix = self.encode_key(keys::DISPLAY_NAME, &room_id);
+                let mut stored = BTreeMap::new();
+                for entry in self.display_names.scan_prefix(&prefix) {
+                    let (key, value) = entry?;
+                    let user_ids: Option<BTreeSet<OwnedUserId>> =
+                        self.deserialize_value(&value).ok();
+
+                    // Empty sets are kept when the last user with a display
+                    // name changes it.
+                    if user_ids.as_ref().map_or(true, |user_ids| !user_ids.is_empty()) {
+                        stored.insert(key.to_vec(), user_ids);
+                    }
+                }
+
+                if stored == expected {
+                    continue;
+                }
+
+                inconsistent_rooms += 1;
+
+                if repair {
+                    let mut batch = sled::Batch::default();
+                    for key in self.display_names.scan_prefix(&prefix).keys() {
+                        batch.remove(key?);
+                    }
+                    for (key, user_ids) in expected {
+                        batch.insert(key, self.serialize_value(&user_ids)?);
+                    }
+                    self.display_names.apply_batch(batch)?;
+                }
+            }
+
+            if inconsistent_rooms > 0 {
+                issues.push(IntegrityIssue::InconsistentDisplayNames { rooms: inconsistent_rooms });
+            }
+        }
+
+        if repair {
+            self.inner.flush_async().await?;
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
+    /// Find the values of the given tree that are rejected by `is_valid`, and
+    /// remove them if `repair` is true.
+    ///
+    /// Returns the number of broken values.
+    fn check_tree(
+        &self,
+        tree: &Tree,
+        repair: bool,
+        mut is_valid: impl FnMut(&[u8]) -> bool,
+    ) -> Result<usize> {
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            if !is_valid(&value) {
+                batch.remove(key);
+                count += 1;
+            }
+        }
+
+        if repair && count > 0 {
+            tree.apply_batch(batch)?;
+        }
+
+        Ok(count)
+    }
+
+    /// The part of the given key that is encoded from the room ID, including
+    /// the separator.
+    fn room_key_prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        let end = if self.store_cipher.is_some() {
+            // Hashed keys have a fixed length.
+            32
+        } else {
+            key.iter().position(|&byte| byte == ENCODE_SEPARATOR)?
+        };
+
+        key.get(..=end)
+    }
+
     /// Open a `SledCryptoStore` that uses the same database as this store.
     ///
     /// The given passphrase will be used to encrypt private data.
@@ -455,7 +768,13 @@ impl SledStateStore {
     pub async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
         let now = Instant::now();
 
-        // room state & memberships
+        let event_cache_batches = changes
+            .event_cache
+            .iter()
+            .map(|(room_id, cache_changes)| self.event_cache_batch(room_id, cache_changes))
+            .collect::<Result<Vec<_>>>()?;
+
+        // room state, memberships & event cache
         let ret: Result<(), TransactionError<SledStoreError>> = (
             &self.profiles,
             &self.display_names,
@@ -466,6 +785,9 @@ impl SledStateStore {
             &self.stripped_user_ids,
             &self.stripped_room_infos,
             &self.stripped_room_state,
+            &self.event_chunks,
+            &self.event_chunk_ranges,
+            &self.events,
         )
             .transaction(
                 |(
@@ -478,6 +800,9 @@ impl SledStateStore {
                     stripped_user_ids,
                     stripped_rooms,
                     stripped_state,
+                    event_chunks,
+                    event_chunk_ranges,
+                    events,
                 )| {
                     for (room_id, ambiguity_maps) in &changes.ambiguity_maps {
                         for (display_name, map) in ambiguity_maps {
@@ -615,6 +940,10 @@ impl SledStateStore {
                         }
                     }
 
+                    for batch in &event_cache_batches {
+                        batch.apply(event_chunks, event_chunk_ranges, events)?;
+                    }
+
                     Ok(())
                 },
             );
@@ -775,6 +1104,89 @@ impl SledStateStore {
         Ok(())
     }
 
+    /// Prepare the changes to the event cache of the given room, to apply them
+    /// in a transaction.
+    fn event_cache_batch(
+        &self,
+        room_id: &RoomId,
+        cache_changes: &EventCacheChanges,
+    ) -> Result<EventCacheBatch> {
+        let range_key = self.encode_key(keys::EVENT_CHUNK_RANGE, room_id);
+        let mut chunks_batch = sled::Batch::default();
+        let mut events_batch = sled::Batch::default();
+
+        let mut range = if cache_changes.reset {
+            for key in
+                self.event_chunks.scan_prefix(self.encode_key(keys::EVENT_CHUNK, room_id)).keys()
+            {
+                chunks_batch.remove(key?);
+            }
+            for key in self.events.scan_prefix(self.encode_key(keys::EVENT, room_id)).keys() {
+                events_batch.remove(key?);
+            }
+
+            None
+        } else {
+            self.event_chunk_ranges
+                .get(&range_key)?
+                .map(|v| self.deserialize_value::<(i64, i64)>(&v))
+                .transpose()?
+        };
+
+        for chunk_id in &cache_changes.removed_chunks {
+            let key = self.encode_key(keys::EVENT_CHUNK, (room_id, chunk_id.to_string()));
+
+            if let Some(old_chunk) = self.event_chunks.get(&key)? {
+                let old_chunk: EventChunk = self.deserialize_value(&old_chunk)?;
+                for event_id in old_chunk.event_ids() {
+                    events_batch.remove(self.encode_key(keys::EVENT, (room_id, &event_id)));
+                }
+            }
+
+            chunks_batch.remove(key);
+        }
+
+        // The removed chunks are the oldest ones, so the range now starts after
+        // them.
+        if let Some((mut start, end)) = range {
+            while cache_changes.removed_chunks.contains(&start) {
+                start += 1;
+            }
+            range = (start <= end).then_some((start, end));
+        }
+
+        for (chunk_id, chunk) in &cache_changes.chunks {
+            let key = self.encode_key(keys::EVENT_CHUNK, (room_id, chunk_id.to_string()));
+
+            // Remove the events that were previously in this chunk, the new
+            // version of the chunk might not contain them anymore.
+            if let Some(old_chunk) = self.event_chunks.get(&key)? {
+                let old_chunk: EventChunk = self.deserialize_value(&old_chunk)?;
+                for event_id in old_chunk.event_ids() {
+                    events_batch.remove(self.encode_key(keys::EVENT, (room_id, &event_id)));
+                }
+            }
+
+            for event_id in chunk.event_ids() {
+                events_batch.insert(
+                    self.encode_key(keys::EVENT, (room_id, &event_id)),
+                    self.serialize_value(chunk_id)?,
+                );
+            }
+
+            chunks_batch.insert(key, self.serialize_value(chunk)?);
+
+            range = Some(match range {
+                Some((start, end)) => (start.min(*chunk_id), end.max(*chunk_id)),
+                None => (*chunk_id, *chunk_id),
+            });
+        }
+
+        let range = range.map(|range| self.serialize_value(&range)).transpose()?;
+
+        Ok(EventCacheBatch { range_key, range, chunks: chunks_batch, events: events_batch })
+    }
+
     pub async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<Raw<PresenceEvent>>> {
         let db = self.clone();
         let key = self.encode_key(keys::PRESENCE, user_id);
@@ -992,13 +1404,14 @@ impl SledStateStore {
     }
 
     async fn add_media_content(&self, request: &MediaRequest, data: Vec<u8>) -> Result<()> {
-        self.media.insert(
-            self.encode_key(
-                keys::MEDIA,
-                (request.source.unique_key(), request.format.unique_key()),
-            ),
-            self.serialize_value(&data)?,
+        let key = self
+            .encode_key(keys::MEDIA, (request.source.unique_key(), request.format.unique_key()));
+
+        self.media_metadata.insert(
+            key.as_slice(),
+            self.serialize_value(&(data.len(), MilliSecondsSinceUnixEpoch::now()))?,
         )?;
+        self.media.insert(key, self.serialize_value(&data)?)?;
 
         self.inner.flush_async().await?;
 
@@ -1011,7 +1424,110 @@ impl SledStateStore {
             .encode_key(keys::MEDIA, (request.source.unique_key(), request.format.unique_key()));
 
         spawn_blocking(move || {
-            db.media.get(key)?.map(move |m| db.deserialize_value(&m)).transpose()
+            let Some(data) = db.media.get(&key)? else { return Ok(None) };
+            let data: Vec<u8> = db.deserialize_value(&data)?;
+
+            let now = MilliSecondsSinceUnixEpoch::now();
+            let update_last_access = match db.media_metadata.get(&key)? {
+                Some(metadata) => {
+                    let (_, last_access): (usize, MilliSecondsSinceUnixEpoch) =
+                        db.deserialize_value(&metadata)?;
+                    should_update_media_last_access(last_access, now)
+                }
+                None => true,
+            };
+
+            if update_last_access {
+                db.media_metadata.insert(key, db.serialize_value(&(data.len(), now))?)?;
+            }
+
+            Ok(Some(data))
+        })
+        .await?
+    }
+
+    /// Get the key, size and time of last access of all the media content.
+    ///
+    /// Media content that was added before the size and time of last access
+    /// were tracked is considered accessed now.
+    fn media_entries(&self) -> Result<Vec<(sled::IVec, usize, MilliSecondsSinceUnixEpoch)>> {
+        let mut entries = Vec::new();
+
+        for key in self.media.iter().keys() {
+            let key = key?;
+
+            let (size, last_access) = match self.media_metadata.get(&key)? {
+                Some(metadata) => self.deserialize_value(&metadata)?,
+                None => {
+                    let Some(data) = self.media.get(&key)? else { continue };
+                    let data: Vec<u8> = self.deserialize_value(&data)?;
+                    let metadata = (data.len(), MilliSecondsSinceUnixEpoch::now());
+                    self.media_metadata.insert(&key, self.serialize_value(&metadata)?)?;
+                    metadata
+                }
+            };
+
+            entries.push((key, size, last_access));
+        }
+
+        Ok(entries)
+    }
+
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<()> {
+        let db = self.clone();
+
+        spawn_blocking(move || {
+            let entries = db.media_entries()?;
+
+            let mut batch = sled::Batch::default();
+            for key in policy.select_for_removal(entries, MilliSecondsSinceUnixEpoch::now()) {
+                batch.remove(key);
+            }
+
+            db.media.apply_batch(batch.clone())?;
+            db.media_metadata.apply_batch(batch)?;
+
+            Ok::<_, SledStoreError>(())
+        })
+        .await??;
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize> {
+        let db = self.clone();
+
+        let total_size = spawn_blocking(move || {
+            let (keys, total_size) = select_for_trimming(db.media_entries()?, max_size);
+
+            let mut batch = sled::Batch::default();
+            for key in keys {
+                batch.remove(key);
+            }
+
+            db.media.apply_batch(batch.clone())?;
+            db.media_metadata.apply_batch(batch)?;
+
+            Ok::<_, SledStoreError>(total_size)
+        })
+        .await??;
+
+        self.inner.flush_async().await?;
+
+        Ok(total_size)
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        let db = self.clone();
+
+        spawn_blocking(move || {
+            let entries = db.media_entries()?;
+            Ok(MediaCacheStats {
+                count: entries.len(),
+                total_size: entries.iter().map(|(_, size, _)| size).sum(),
+            })
         })
         .await?
     }
@@ -1047,12 +1563,11 @@ impl SledStateStore {
     }
 
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
-        self.media.remove(
-            self.encode_key(
-                keys::MEDIA,
-                (request.source.unique_key(), request.format.unique_key()),
-            ),
-        )?;
+        let key = self
+            .encode_key(keys::MEDIA, (request.source.unique_key(), request.format.unique_key()));
+
+        self.media.remove(key.as_slice())?;
+        self.media_metadata.remove(key)?;
 
         Ok(())
     }
@@ -1065,7 +1580,69 @@ impl SledStateStore {
             batch.remove(key?);
         }
 
-        Ok(self.media.apply_batch(batch)?)
+        self.media.apply_batch(batch.clone())?;
+        Ok(self.media_metadata.apply_batch(batch)?)
+    }
+
+    async fn get_event_chunk_range(&self, room_id: &RoomId) -> Result<Option<RangeInclusive<i64>>> {
+        let db = self.clone();
+        let key = self.encode_key(keys::EVENT_CHUNK_RANGE, room_id);
+
+        spawn_blocking(move || {
+            Ok(db
+                .event_chunk_ranges
+                .get(key)?
+                .map(|r| db.deserialize_value::<(i64, i64)>(&r))
+                .transpose()?
+                .map(|(start, end)| start..=end))
+        })
+        .await?
+    }
+
+    async fn get_event_chunk(&self, room_id: &RoomId, chunk_id: i64) -> Result<Option<EventChunk>> {
+        let db = self.clone();
+        let key = self.encode_key(keys::EVENT_CHUNK, (room_id, chunk_id.to_string()));
+
+        spawn_blocking(move || {
+            db.event_chunks.get(key)?.map(|c| db.deserialize_value(&c)).transpose()
+        })
+        .await?
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<i64>> {
+        let db = self.clone();
+        let key = self.encode_key(keys::EVENT, (room_id, event_id));
+
+        spawn_blocking(move || db.events.get(key)?.map(|id| db.deserialize_value(&id)).transpose())
+            .await?
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>> {
+        let db = self.clone();
+        let room_id = room_id.to_owned();
+        let event_ids = event_ids.to_vec();
+
+        spawn_blocking(move || {
+            let mut chunk_ids = BTreeMap::new();
+
+            for event_id in event_ids {
+                let key = db.encode_key(keys::EVENT, (&room_id, &event_id));
+                if let Some(chunk_id) = db.events.get(key)? {
+                    chunk_ids.insert(event_id, db.deserialize_value(&chunk_id)?);
+                }
+            }
+
+            Ok(chunk_ids)
+        })
+        .await?
     }
 
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
@@ -1185,10 +1762,81 @@ impl SledStateStore {
             );
         ret?;
 
+        let event_cache_batch = self
+            .event_cache_batch(room_id, &EventCacheChanges { reset: true, ..Default::default() })?;
+        let ret: Result<(), TransactionError<SledStoreError>> =
+            (&self.event_chunks, &self.event_chunk_ranges, &self.events).transaction(
+                |(event_chunks, event_chunk_ranges, events)| {
+                    event_cache_batch.apply(event_chunks, event_chunk_ranges, events)
+                },
+            );
+        ret?;
+
         self.inner.flush_async().await?;
 
         Ok(())
     }
+
+    async fn clear(&self) -> Result<()> {
+        // The store cipher and the version live in the default tree, which we
+        // keep so the store can be opened again.
+        for tree in [
+            &self.kv,
+            &self.account_data,
+            &self.profiles,
+            &self.display_names,
+            &self.user_ids,
+            &self.room_info,
+            &self.room_state,
+            &self.room_account_data,
+            &self.stripped_user_ids,
+            &self.stripped_room_infos,
+            &self.stripped_room_state,
+            &self.presence,
+            &self.room_user_receipts,
+            &self.room_event_receipts,
+            &self.media,
+            &self.media_metadata,
+            &self.event_chunks,
+            &self.event_chunk_ranges,
+            &self.events,
+            &self.custom,
+        ] {
+            tree.clear()?;
+        }
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+}
+
+/// Changes to the event cache of a room, ready to be applied in a transaction.
+struct EventCacheBatch {
+    range_key: Vec<u8>,
+    range: Option<Vec<u8>>,
+    chunks: sled::Batch,
+    events: sled::Batch,
+}
+
+impl EventCacheBatch {
+    fn apply(
+        &self,
+        event_chunks: &TransactionalTree,
+        event_chunk_ranges: &TransactionalTree,
+        events: &TransactionalTree,
+    ) -> ConflictableTransactionResult<(), SledStoreError> {
+        event_chunks.apply_batch(&self.chunks)?;
+        events.apply_batch(&self.events)?;
+
+        if let Some(range) = &self.range {
+            event_chunk_ranges.insert(self.range_key.as_slice(), range.as_slice())?;
+        } else {
+            event_chunk_ranges.remove(self.range_key.as_slice())?;
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -1371,9 +2019,56 @@ impl StateStore for SledStateStore {
         self.remove_media_content_for_uri(uri).await.map_err(Into::into)
     }
 
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> StoreResult<()> {
+        self.clean_media_cache(policy).await.map_err(Into::into)
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> StoreResult<usize> {
+        self.trim_media_cache(max_size).await.map_err(Into::into)
+    }
+
+    async fn media_cache_stats(&self) -> StoreResult<MediaCacheStats> {
+        self.media_cache_stats().await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk_range(
+        &self,
+        room_id: &RoomId,
+    ) -> StoreResult<Option<RangeInclusive<i64>>> {
+        self.get_event_chunk_range(room_id).await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk(
+        &self,
+        room_id: &RoomId,
+        chunk_id: i64,
+    ) -> StoreResult<Option<EventChunk>> {
+        self.get_event_chunk(room_id, chunk_id).await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> StoreResult<Option<i64>> {
+        self.get_event_chunk_id(room_id, event_id).await.map_err(Into::into)
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> StoreResult<BTreeMap<OwnedEventId, i64>> {
+        self.get_event_chunk_ids(room_id, event_ids).await.map_err(Into::into)
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> StoreResult<()> {
         self.remove_room(room_id).await.map_err(Into::into)
     }
+
+    async fn clear(&self) -> StoreResult<()> {
+        self.clear().await.map_err(Into::into)
+    }
 }
 
 /// A room member.
@@ -1411,12 +2106,82 @@ mod tests {
 #[cfg(test)]
 mod encrypted_tests {
     use matrix_sdk_base::statestore_integration_tests;
+    use matrix_sdk_test::async_test;
+    use tempfile::TempDir;
+
+    use matrix_sdk_base::{StateStoreDataKey, StateStoreDataValue};
 
-    use super::{SledStateStoreBuilder, StateStore, StoreResult};
+    use super::{
+        keys, EncodeUnchecked, SledStateStore, SledStateStoreBuilder, StateStore, StoreResult,
+    };
 
     async fn get_store() -> StoreResult<impl StateStore> {
         SledStateStoreBuilder::build_encrypted().map_err(Into::into)
     }
 
     statestore_integration_tests!(with_media_tests);
+
+    #[async_test]
+    async fn rotate_encryption_key() {
+        let folder = TempDir::new().unwrap();
+        let builder = || {
+            SledStateStore::builder()
+                .path(folder.path().to_path_buf())
+                .passphrase("password".to_owned())
+        };
+
+        let store = builder().build().unwrap();
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+        store
+            .set_kv_data(
+                StateStoreDataKey::SyncToken,
+                StateStoreDataValue::SyncToken("token".to_owned()),
+            )
+            .await
+            .unwrap();
+        store
+            .set_kv_data(
+                StateStoreDataKey::Filter("filter"),
+                StateStoreDataValue::Filter("filter_id".to_owned()),
+            )
+            .await
+            .unwrap();
+        let hashed_key = store.encode_key(keys::CUSTOM, EncodeUnchecked::from(b"key"));
+
+        // The store can't be rotated while it is cloned.
+        let clone = store.clone();
+        assert!(store.rotate_encryption_key("password").await.is_err());
+
+        let store = clone.rotate_encryption_key("password").await.unwrap();
+        assert_ne!(store.encode_key(keys::CUSTOM, EncodeUnchecked::from(b"key")), hashed_key);
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        drop(store);
+
+        let store = builder().build().unwrap();
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        let sync_token = store.get_kv_data(StateStoreDataKey::SyncToken).await.unwrap();
+        assert_eq!(sync_token.and_then(|v| v.into_sync_token()).as_deref(), Some("token"));
+        let filter = store.get_kv_data(StateStoreDataKey::Filter("filter")).await.unwrap();
+        assert_eq!(filter.and_then(|v| v.into_filter()).as_deref(), Some("filter_id"));
+    }
+
+    #[async_test]
+    async fn rotate_encryption_key_unknown_tree() {
+        let folder = TempDir::new().unwrap();
+        let builder = || {
+            SledStateStore::builder()
+                .path(folder.path().to_path_buf())
+                .passphrase("password".to_owned())
+        };
+
+        let store = builder().build().unwrap();
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+
+        // The keys of a tree without a known schema are not guessed.
+        store.inner.open_tree("unknown").unwrap().insert(b"key", b"value").unwrap();
+        assert!(store.rotate_encryption_key("password").await.is_err());
+
+        let store = builder().build().unwrap();
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    }
 }
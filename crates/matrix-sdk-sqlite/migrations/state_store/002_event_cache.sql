@@ -0,0 +1,19 @@
+-- chunks of the event cache of rooms
+CREATE TABLE "event_chunk" (
+    "room_id" BLOB NOT NULL,
+    "chunk_id" INTEGER NOT NULL,
+    "data" BLOB NOT NULL,
+
+    PRIMARY KEY ("room_id", "chunk_id")
+);
+
+-- the chunk that each cached event is in
+CREATE TABLE "event" (
+    "room_id" BLOB NOT NULL,
+    "event_id" BLOB NOT NULL,
+    "chunk_id" INTEGER NOT NULL,
+
+    PRIMARY KEY ("room_id", "event_id")
+);
+CREATE INDEX "event_room_id_chunk_id"
+    ON "event" ("room_id", "chunk_id");
@@ -0,0 +1,9 @@
+-- size and time of last access of the cached media, for the media retention policy
+ALTER TABLE "media" ADD COLUMN "size" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "media" ADD COLUMN "last_access" INTEGER NOT NULL DEFAULT 0;
+
+UPDATE "media"
+    SET "size" = length("data"), "last_access" = CAST(strftime('%s', 'now') AS INTEGER) * 1000;
+
+-- to remove the least recently accessed media first without reading the data
+CREATE INDEX "media_last_access" ON "media" ("last_access", "size");
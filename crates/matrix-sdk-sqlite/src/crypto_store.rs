@@ -21,7 +21,8 @@ use std::{
 };
 
 use async_trait::async_trait;
-use deadpool_sqlite::{Object as SqliteConn, Pool as SqlitePool, Runtime};
+use deadpool_sqlite::{Object as SqliteConn, Pool as SqlitePool};
+use matrix_sdk_base::store::{IntegrityIssue, IntegrityReport};
 use matrix_sdk_crypto::{
     olm::{
         IdentityKeys, InboundGroupSession, OutboundGroupSession, PickledInboundGroupSession,
@@ -29,23 +30,59 @@ use matrix_sdk_crypto::{
     },
     store::{caches::SessionStore, BackupKeys, Changes, CryptoStore, RoomKeyCounts, RoomSettings},
     types::events::room_key_withheld::RoomKeyWithheldEvent,
-    GossipRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities, SecretInfo,
-    TrackedUser,
+    CryptoStoreError, GossipRequest, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyUserIdentities,
+    SecretInfo, TrackedUser,
 };
 use matrix_sdk_store_encryption::StoreCipher;
-use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId};
+use ruma::{
+    DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, TransactionId, UserId,
+};
 use rusqlite::OptionalExtension;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{fs, sync::Mutex};
-use tracing::{debug, error, instrument, warn};
+use tracing::{debug, instrument, warn};
 
 use crate::{
+    change_store_cipher_passphrase, check_encrypted_column, check_schema_version, db_size,
     error::{Error, Result},
-    get_or_create_store_cipher,
+    get_or_create_store_cipher, load_schema_version, optimize,
+    options::AbortOnDrop,
+    rotate_store_cipher,
     utils::{Key, SqliteConnectionExt as _, SqliteObjectExt, SqliteObjectStoreExt as _},
-    OpenStoreError,
+    vacuum, EncryptedColumn, HashedColumns, OpenStoreError, SqliteStoreOptions,
 };
 
+/// The columns that contain values encrypted with the store cipher.
+const ENCRYPTED_COLUMNS: &[EncryptedColumn] = &[
+    EncryptedColumn {
+        table: "kv",
+        column: "value",
+        filter: Some("key NOT IN ('version', 'cipher')"),
+    },
+    EncryptedColumn::data("session"),
+    EncryptedColumn::data("inbound_group_session"),
+    EncryptedColumn::data("outbound_group_session"),
+    EncryptedColumn::data("device"),
+    EncryptedColumn::data("identity"),
+    EncryptedColumn::data("tracked_user"),
+    EncryptedColumn::data("key_requests"),
+    EncryptedColumn::data("room_settings"),
+    EncryptedColumn::data("direct_withheld_info"),
+];
+
+/// The columns that contain keys hashed with the store cipher.
+const HASHED_COLUMNS: &[HashedColumns] = &[
+    HashedColumns { table: "session", columns: &["session_id", "sender_key"] },
+    HashedColumns { table: "inbound_group_session", columns: &["session_id", "room_id"] },
+    HashedColumns { table: "outbound_group_session", columns: &["room_id"] },
+    HashedColumns { table: "device", columns: &["user_id", "device_id"] },
+    HashedColumns { table: "identity", columns: &["user_id"] },
+    HashedColumns { table: "tracked_user", columns: &["user_id"] },
+    HashedColumns { table: "key_requests", columns: &["request_id"] },
+    HashedColumns { table: "room_settings", columns: &["room_id"] },
+    HashedColumns { table: "direct_withheld_info", columns: &["session_id", "room_id"] },
+];
+
 #[derive(Clone, Debug)]
 pub struct AccountInfo {
     user_id: OwnedUserId,
@@ -59,6 +96,9 @@ pub struct SqliteCryptoStore {
     store_cipher: Option<Arc<StoreCipher>>,
     path: Option<PathBuf>,
     pool: SqlitePool,
+    /// The task that checkpoints the write-ahead log, aborted when the last
+    /// clone of the store is dropped.
+    _wal_checkpoint_task: Option<Arc<AbortOnDrop>>,
 
     // DB values cached in memory
     account_info: Arc<RwLock<Option<AccountInfo>>>,
@@ -82,13 +122,33 @@ impl SqliteCryptoStore {
     pub async fn open(
         path: impl AsRef<Path>,
         passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_with_options(path, SqliteStoreOptions::new().passphrase(passphrase)).await
+    }
+
+    /// Open the sqlite-based crypto store at the given path with the given
+    /// options.
+    pub async fn open_with_options(
+        path: impl AsRef<Path>,
+        options: SqliteStoreOptions,
     ) -> Result<Self, OpenStoreError> {
         let path = path.as_ref();
         fs::create_dir_all(path).await.map_err(OpenStoreError::CreateDir)?;
-        let cfg = deadpool_sqlite::Config::new(path.join("matrix-sdk-crypto.sqlite3"));
-        let pool = cfg.create_pool(Runtime::Tokio1)?;
+        let pool = options.create_pool(path.join("matrix-sdk-crypto.sqlite3"))?;
 
-        Self::open_with_pool(pool, passphrase).await
+        let mut store = Self::open_helper(
+            pool.clone(),
+            options.passphrase.as_deref(),
+            options.should_run_migrations(),
+        )
+        .await?;
+
+        let conn = pool.get().await?;
+        options.apply_journal_mode(&conn).await.map_err(OpenStoreError::Configure)?;
+
+        store._wal_checkpoint_task = options.spawn_wal_checkpoint(pool);
+
+        Ok(store)
     }
 
     /// Create a sqlite-based crypto store using the given sqlite database pool.
@@ -96,9 +156,19 @@ impl SqliteCryptoStore {
     pub async fn open_with_pool(
         pool: SqlitePool,
         passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_helper(pool, passphrase, true).await
+    }
+
+    async fn open_helper(
+        pool: SqlitePool,
+        passphrase: Option<&str>,
+        run_migrations: bool,
     ) -> Result<Self, OpenStoreError> {
         let conn = pool.get().await?;
-        run_migrations(&conn).await.map_err(OpenStoreError::Migration)?;
+        if run_migrations {
+            self::run_migrations(&conn).await?;
+        }
         let store_cipher = match passphrase {
             Some(p) => Some(Arc::new(get_or_create_store_cipher(p, &conn).await?)),
             None => None,
@@ -108,11 +178,140 @@ impl SqliteCryptoStore {
             store_cipher,
             path: None,
             pool,
+            _wal_checkpoint_task: None,
             account_info: Arc::new(RwLock::new(None)),
             session_cache: SessionStore::new(),
         })
     }
 
+    /// Change the passphrase that is used to encrypt the store cipher of this
+    /// store.
+    ///
+    /// The data of the store is not re-encrypted, see
+    /// [`SqliteCryptoStore::rotate_encryption_key()`] for that.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), CryptoStoreError> {
+        let conn = self.acquire().await?;
+        Ok(change_store_cipher_passphrase(&conn, old_passphrase, new_passphrase).await?)
+    }
+
+    /// Rotate the keys that are used to encrypt the data of this store.
+    ///
+    /// All the values of the store are re-encrypted with a new key and all the
+    /// hashed keys are hashed again with a new key, in a single transaction.
+    /// The new store cipher is encrypted with the given passphrase.
+    ///
+    /// Returns the store using the new keys. This fails if other clones of
+    /// this store exist, since they would keep using the old keys, so this
+    /// should be called before the store is given to a client.
+    pub async fn rotate_encryption_key(self, passphrase: &str) -> Result<Self, CryptoStoreError> {
+        if self.store_cipher.as_ref().map_or(false, |cipher| Arc::strong_count(cipher) > 1) {
+            return Err(Error::InUse.into());
+        }
+
+        let conn = self.acquire().await?;
+        let store_cipher =
+            rotate_store_cipher(&conn, passphrase, ENCRYPTED_COLUMNS, HASHED_COLUMNS).await?;
+
+        Ok(Self { store_cipher: Some(Arc::new(store_cipher)), ..self })
+    }
+
+    /// Rebuild the database to reclaim the space of the deleted data, and
+    /// shrink the database file.
+    ///
+    /// This needs as much free disk space as the size of the database, and
+    /// blocks the other connections until it is done, so it should only be
+    /// called occasionally, for example after a lot of data was removed.
+    pub async fn vacuum(&self) -> Result<(), CryptoStoreError> {
+        let conn = self.acquire().await?;
+        Ok(vacuum(&conn).await?)
+    }
+
+    /// Let SQLite run the optimizations that it thinks are useful, like
+    /// updating the statistics used by the query planner.
+    ///
+    /// This is cheap, and can be called periodically, for example before
+    /// closing the store.
+    pub async fn optimize(&self) -> Result<(), CryptoStoreError> {
+        let conn = self.acquire().await?;
+        Ok(optimize(&conn).await?)
+    }
+
+    /// Get the size of the database, in bytes.
+    ///
+    /// The size of the write-ahead log, which is not yet merged into the
+    /// database, is not included.
+    pub async fn db_size(&self) -> Result<u64, CryptoStoreError> {
+        let conn = self.acquire().await?;
+        Ok(db_size(&conn).await?)
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks the schema version of the database, that the values can be
+    /// decrypted with the current store cipher and that the Olm sessions are
+    /// stored with an account. See [`IntegrityIssue`] for what the repair does
+    /// for each problem.
+    ///
+    /// This should be called before the store is given to a client, since the
+    /// values that are cached in memory are not updated. A store that fails to
+    /// open can be checked by opening it with
+    /// [`SqliteStoreOptions::run_migrations()`] set to `false`.
+    pub async fn verify_and_repair(
+        &self,
+        repair: bool,
+    ) -> Result<IntegrityReport, CryptoStoreError> {
+        let conn = self.acquire().await?;
+
+        if let Some(issue) = check_schema_version(&conn, DATABASE_VERSION).await? {
+            return Ok(IntegrityReport { issues: vec![issue], repaired: false });
+        }
+
+        let store_cipher = self.store_cipher.clone();
+        let issues = conn
+            .with_transaction(move |txn| -> Result<_> {
+                let mut issues = Vec::new();
+
+                if let Some(cipher) = &store_cipher {
+                    for column in ENCRYPTED_COLUMNS {
+                        let count = check_encrypted_column(txn, column, cipher, repair)?;
+                        if count > 0 {
+                            issues.push(IntegrityIssue::CorruptedValues {
+                                table: column.table.to_owned(),
+                                count,
+                            });
+                        }
+                    }
+                }
+
+                let has_account = txn
+                    .query_row("SELECT 1 FROM kv WHERE key = 'account'", (), |_| Ok(()))
+                    .optional()?
+                    .is_some();
+
+                if !has_account {
+                    let count: usize =
+                        txn.query_row("SELECT COUNT(*) FROM session", (), |row| row.get(0))?;
+
+                    if count > 0 {
+                        issues.push(IntegrityIssue::SessionsWithoutAccount { count });
+
+                        if repair {
+                            txn.execute("DELETE FROM session", ())?;
+                        }
+                    }
+                }
+
+                Ok(issues)
+            })
+            .await?;
+
+        Ok(IntegrityReport { issues, repaired: repair })
+    }
+
     fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
         if let Some(key) = &self.store_cipher {
             let encrypted = key.encrypt_value_data(value)?;
@@ -190,37 +389,24 @@ impl SqliteCryptoStore {
     }
 }
 
-const DATABASE_VERSION: u8 = 6;
+const DATABASE_VERSION: u8 = 7;
 
-async fn run_migrations(conn: &SqliteConn) -> rusqlite::Result<()> {
-    let kv_exists = conn
-        .query_row(
-            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'kv'",
-            (),
-            |row| row.get::<_, u32>(0),
-        )
-        .await?
-        > 0;
-
-    let version = if kv_exists {
-        match conn.get_kv("version").await?.as_deref() {
-            Some([v]) => *v,
-            Some(_) => {
-                error!("version database field has multiple bytes");
-                return Ok(());
-            }
-            None => {
-                error!("version database field is missing");
-                return Ok(());
-            }
-        }
-    } else {
-        0
-    };
+async fn run_migrations(conn: &SqliteConn) -> Result<(), OpenStoreError> {
+    let version = load_schema_version(conn, DATABASE_VERSION).await?;
+
+    if version < DATABASE_VERSION {
+        migrate(conn, version).await.map_err(OpenStoreError::Migration)?;
+    }
+
+    Ok(())
+}
 
+/// Migrate the schema of the database from the given version to
+/// `DATABASE_VERSION`.
+async fn migrate(conn: &SqliteConn, version: u8) -> rusqlite::Result<()> {
     if version == 0 {
         debug!("Creating database");
-    } else if version < DATABASE_VERSION {
+    } else {
         debug!(version, new_version = DATABASE_VERSION, "Upgrading database");
     }
 
@@ -273,6 +459,13 @@ async fn run_migrations(conn: &SqliteConn) -> rusqlite::Result<()> {
         .await?;
     }
 
+    if version < 7 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!("../migrations/crypto_store/007_lease_locks.sql"))
+        })
+        .await?;
+    }
+
     conn.set_kv("version", vec![DATABASE_VERSION]).await?;
 
     Ok(())
@@ -446,6 +639,14 @@ trait SqliteObjectCryptoStoreExt: SqliteObjectExt {
             .await?)
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare("SELECT data FROM session", |mut stmt| {
+                stmt.query(())?.mapped(|row| row.get(0)).collect()
+            })
+            .await?)
+    }
+
     async fn get_inbound_group_session(
         &self,
         session_id: Key,
@@ -849,6 +1050,26 @@ impl CryptoStore for SqliteCryptoStore {
         Ok(self.session_cache.get(sender_key))
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        let account_info = self.get_account_info().ok_or(Error::AccountUnset)?;
+
+        self.acquire()
+            .await?
+            .get_all_sessions()
+            .await?
+            .into_iter()
+            .map(|bytes| {
+                let pickle = self.deserialize_value(&bytes)?;
+                Ok(Session::from_pickle(
+                    account_info.user_id.clone(),
+                    account_info.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    pickle,
+                ))
+            })
+            .collect()
+    }
+
     #[instrument(skip(self))]
     async fn get_inbound_group_session(
         &self,
@@ -1127,6 +1348,91 @@ impl CryptoStore for SqliteCryptoStore {
         self.acquire().await?.set_kv(key, serialized).await?;
         Ok(())
     }
+
+    async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
+        let inbound_room_id = self.encode_key("inbound_group_session", room_id.as_bytes());
+        let outbound_room_id = self.encode_key("outbound_group_session", room_id.as_bytes());
+        let withheld_room_id = self.encode_key("direct_withheld_info", room_id.as_bytes());
+        let settings_room_id = self.encode_key("room_settings", room_id.as_bytes());
+
+        self.acquire()
+            .await?
+            .with_transaction(move |txn| -> Result<()> {
+                txn.execute(
+                    "DELETE FROM inbound_group_session WHERE room_id = ?",
+                    (inbound_room_id,),
+                )?;
+                txn.execute(
+                    "DELETE FROM outbound_group_session WHERE room_id = ?",
+                    (outbound_room_id,),
+                )?;
+                txn.execute(
+                    "DELETE FROM direct_withheld_info WHERE room_id = ?",
+                    (withheld_room_id,),
+                )?;
+                txn.execute("DELETE FROM room_settings WHERE room_id = ?", (settings_room_id,))?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.acquire()
+            .await?
+            .with_transaction(|txn| {
+                txn.execute_batch(
+                    "DELETE FROM kv WHERE key NOT IN ('version', 'cipher');
+                    DELETE FROM session;
+                    DELETE FROM inbound_group_session;
+                    DELETE FROM outbound_group_session;
+                    DELETE FROM device;
+                    DELETE FROM identity;
+                    DELETE FROM tracked_user;
+                    DELETE FROM olm_hash;
+                    DELETE FROM key_requests;
+                    DELETE FROM room_settings;
+                    DELETE FROM direct_withheld_info;",
+                )
+            })
+            .await?;
+
+        *self.account_info.write().unwrap() = None;
+        self.session_cache.clear();
+
+        Ok(())
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<bool> {
+        let key = key.to_owned();
+        let holder = holder.to_owned();
+        let now: i64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let expiration = now + i64::from(lease_duration_ms);
+
+        let num_touched = self
+            .acquire()
+            .await?
+            .execute(
+                "INSERT INTO lease_locks (key, holder, expiration)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (key)
+                DO UPDATE SET holder = ?2, expiration = ?3
+                WHERE holder = ?2 OR expiration <= ?4",
+                (key, holder, expiration, now),
+            )
+            .await?;
+
+        Ok(num_touched == 1)
+    }
+
+    async fn clear_caches(&self) {
+        self.session_cache.clear();
+    }
 }
 
 #[cfg(test)]
@@ -1152,11 +1458,15 @@ mod tests {
 
 #[cfg(test)]
 mod encrypted_tests {
-    use matrix_sdk_crypto::cryptostore_integration_tests;
+    use matrix_sdk_base::store::IntegrityIssue;
+    use matrix_sdk_crypto::{cryptostore_integration_tests, store::CryptoStore, OlmMachine};
+    use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
+    use ruma::{device_id, user_id};
     use tempfile::{tempdir, TempDir};
 
-    use super::SqliteCryptoStore;
+    use super::{SqliteConnectionExt, SqliteCryptoStore};
+    use crate::utils::SqliteObjectExt;
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
 
@@ -1170,4 +1480,80 @@ mod encrypted_tests {
     }
 
     cryptostore_integration_tests!();
+
+    #[async_test]
+    async fn change_passphrase_and_rotate_encryption_key() {
+        let path = TMP_DIR.path().join("change_passphrase_and_rotate_encryption_key");
+
+        let store = SqliteCryptoStore::open(&path, Some("old_password")).await.unwrap();
+        store.set_custom_value("key", b"value".to_vec()).await.unwrap();
+        store.change_passphrase("old_password", "new_password").await.unwrap();
+
+        let store = store.rotate_encryption_key("new_password").await.unwrap();
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        drop(store);
+
+        SqliteCryptoStore::open(&path, Some("old_password")).await.unwrap_err();
+
+        let store = SqliteCryptoStore::open(&path, Some("new_password")).await.unwrap();
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[async_test]
+    async fn crypto_store_generation() {
+        let path = TMP_DIR.path().join("crypto_store_generation");
+        let user_id = user_id!("@alice:localhost");
+        let device_id = device_id!("ALICEDEVICE");
+
+        // Two stores using the same database, like two processes would.
+        let store = SqliteCryptoStore::open(&path, Some("password")).await.unwrap();
+        let main = OlmMachine::with_store(user_id, device_id, store).await.unwrap();
+        let store = SqliteCryptoStore::open(&path, Some("password")).await.unwrap();
+        let extension = OlmMachine::with_store(user_id, device_id, store).await.unwrap();
+
+        // The caches are always reloaded the first time.
+        assert!(main.maintain_crypto_store_generation().await.unwrap());
+        assert!(!main.maintain_crypto_store_generation().await.unwrap());
+
+        // The extension used the store, so the caches of the main process are
+        // outdated.
+        assert!(extension.maintain_crypto_store_generation().await.unwrap());
+        assert!(main.maintain_crypto_store_generation().await.unwrap());
+        assert!(!main.maintain_crypto_store_generation().await.unwrap());
+    }
+
+    #[async_test]
+    async fn verify_and_repair() {
+        let path = TMP_DIR.path().join("verify_and_repair");
+
+        let store = SqliteCryptoStore::open(&path, Some("password")).await.unwrap();
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+
+        store
+            .acquire()
+            .await
+            .unwrap()
+            .with_transaction(|txn| txn.set_session(b"session_id", b"sender_key", b"not encrypted"))
+            .await
+            .unwrap();
+
+        let report = store.verify_and_repair(false).await.unwrap();
+        assert!(!report.is_usable());
+        assert_eq!(
+            report.issues,
+            [
+                IntegrityIssue::CorruptedValues { table: "session".to_owned(), count: 1 },
+                IntegrityIssue::SessionsWithoutAccount { count: 1 },
+            ]
+        );
+
+        let report = store.verify_and_repair(true).await.unwrap();
+        assert!(report.is_usable());
+        assert_eq!(
+            report.issues,
+            [IntegrityIssue::CorruptedValues { table: "session".to_owned(), count: 1 }]
+        );
+
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+    }
 }
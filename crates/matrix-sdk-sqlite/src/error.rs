@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use deadpool_sqlite::{CreatePoolError, PoolError};
+use deadpool_sqlite::{BuildError, CreatePoolError, PoolError};
 #[cfg(feature = "state-store")]
 use matrix_sdk_base::store::StoreError as StateStoreError;
 #[cfg(feature = "crypto-store")]
@@ -32,10 +32,28 @@ pub enum OpenStoreError {
     #[error(transparent)]
     CreatePool(#[from] CreatePoolError),
 
+    /// Failed to build the DB pool with the given options.
+    #[error(transparent)]
+    BuildPool(#[from] BuildError),
+
+    /// Failed to apply the options to the DB.
+    #[error("Failed to apply the options to the database")]
+    Configure(#[source] rusqlite::Error),
+
     /// Failed to apply migrations.
     #[error("Failed to run migrations")]
     Migration(#[source] rusqlite::Error),
 
+    /// The version of the schema of the DB is newer than the one of this
+    /// store, or it couldn't be read.
+    #[error("Unsupported database schema version: found {found:?}, expected {expected}")]
+    SchemaVersion {
+        /// The version of the schema of the DB, if it could be read.
+        found: Option<u8>,
+        /// The version of the schema of this store.
+        expected: u8,
+    },
+
     /// Failed to get a DB connection from the pool.
     #[error(transparent)]
     Pool(#[from] PoolError),
@@ -75,6 +93,10 @@ pub enum Error {
     Unpickle,
     #[error("Redaction failed: {0}")]
     Redaction(#[source] ruma::canonical_json::RedactionError),
+    #[error("the store is not encrypted")]
+    Unencrypted,
+    #[error("the store is used by other handles")]
+    InUse,
 }
 
 macro_rules! impl_from {
@@ -107,6 +129,7 @@ impl From<Error> for StateStoreError {
             Error::Json(e) => StateStoreError::Json(e),
             Error::Encryption(e) => StateStoreError::Encryption(e),
             Error::Redaction(e) => StateStoreError::Redaction(e),
+            Error::Unencrypted => StateStoreError::UnencryptedStore,
             e => StateStoreError::backend(e),
         }
     }
@@ -19,22 +19,31 @@
 use std::path::Path;
 
 use deadpool_sqlite::Object as SqliteConn;
-use matrix_sdk_base::store::StoreConfig;
+use matrix_sdk_base::store::{IntegrityIssue, StoreConfig};
 use matrix_sdk_store_encryption::StoreCipher;
+use rusqlite::Transaction;
 
 #[cfg(feature = "crypto-store")]
 mod crypto_store;
 mod error;
+mod options;
 #[cfg(feature = "state-store")]
 mod state_store;
 mod utils;
 
 #[cfg(feature = "crypto-store")]
 pub use self::crypto_store::SqliteCryptoStore;
-pub use self::error::OpenStoreError;
 #[cfg(feature = "state-store")]
 pub use self::state_store::SqliteStateStore;
-use self::utils::SqliteObjectStoreExt;
+pub use self::{
+    error::OpenStoreError,
+    options::{JournalMode, SqliteStoreOptions},
+};
+use self::{
+    error::{Error, Result},
+    options::checkpoint_wal,
+    utils::{SqliteConnectionExt, SqliteObjectExt, SqliteObjectStoreExt},
+};
 
 async fn get_or_create_store_cipher(
     passphrase: &str,
@@ -46,10 +55,7 @@ async fn get_or_create_store_cipher(
         StoreCipher::import(passphrase, &encrypted)?
     } else {
         let cipher = StoreCipher::new()?;
-        #[cfg(not(test))]
-        let export = cipher.export(passphrase);
-        #[cfg(test)]
-        let export = cipher._insecure_export_fast_for_testing(passphrase);
+        let export = export_store_cipher(&cipher, passphrase);
         conn.set_kv("cipher", export?).await.map_err(OpenStoreError::SaveCipher)?;
         cipher
     };
@@ -57,6 +63,261 @@ async fn get_or_create_store_cipher(
     Ok(cipher)
 }
 
+fn export_store_cipher(
+    cipher: &StoreCipher,
+    passphrase: &str,
+) -> Result<Vec<u8>, matrix_sdk_store_encryption::Error> {
+    #[cfg(not(test))]
+    let export = cipher.export(passphrase);
+    #[cfg(test)]
+    let export = cipher._insecure_export_fast_for_testing(passphrase);
+    export
+}
+
+/// Get the version of the schema of the database, or 0 if it was never
+/// created.
+///
+/// Returns an error if the version is newer than the `expected` one, or if it
+/// can't be read, since the migrations can't be run in that case.
+async fn load_schema_version(conn: &SqliteConn, expected: u8) -> Result<u8, OpenStoreError> {
+    let kv_exists = conn
+        .query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'kv'",
+            (),
+            |row| row.get::<_, u32>(0),
+        )
+        .await
+        .map_err(OpenStoreError::Migration)?
+        > 0;
+
+    if !kv_exists {
+        return Ok(0);
+    }
+
+    match conn.get_kv("version").await.map_err(OpenStoreError::Migration)?.as_deref() {
+        Some([version]) if *version <= expected => Ok(*version),
+        Some([version]) => Err(OpenStoreError::SchemaVersion { found: Some(*version), expected }),
+        _ => Err(OpenStoreError::SchemaVersion { found: None, expected }),
+    }
+}
+
+/// Encrypt the store cipher saved in the database with a new passphrase.
+async fn change_store_cipher_passphrase(
+    conn: &SqliteConn,
+    old_passphrase: &str,
+    new_passphrase: &str,
+) -> Result<()> {
+    let encrypted = conn.get_kv("cipher").await?.ok_or(Error::Unencrypted)?;
+    let cipher = StoreCipher::import(old_passphrase, &encrypted)?;
+
+    conn.set_kv("cipher", export_store_cipher(&cipher, new_passphrase)?).await?;
+
+    Ok(())
+}
+
+/// Rebuild the database to reclaim the space of the deleted data.
+async fn vacuum(conn: &SqliteConn) -> Result<()> {
+    conn.execute_batch("VACUUM;").await?;
+    // With a write-ahead log, the rebuilt database is only written to the
+    // database file once the log is checkpointed.
+    checkpoint_wal(conn).await?;
+
+    Ok(())
+}
+
+/// Let SQLite run the optimizations that it thinks are useful, like updating
+/// the statistics used by the query planner.
+async fn optimize(conn: &SqliteConn) -> Result<()> {
+    conn.execute_batch("PRAGMA optimize;").await?;
+    Ok(())
+}
+
+/// Get the size of the database, in bytes.
+///
+/// The size of the write-ahead log is not included.
+async fn db_size(conn: &SqliteConn) -> Result<u64> {
+    Ok(conn
+        .query_row(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            (),
+            |row| row.get(0),
+        )
+        .await?)
+}
+
+/// A column of a table that contains values encrypted with the store cipher.
+struct EncryptedColumn {
+    table: &'static str,
+    column: &'static str,
+    /// A condition to only select the rows with encrypted values.
+    filter: Option<&'static str>,
+}
+
+impl EncryptedColumn {
+    const fn data(table: &'static str) -> Self {
+        Self { table, column: "data", filter: None }
+    }
+}
+
+/// The columns of a table that contain keys hashed with the store cipher.
+struct HashedColumns {
+    table: &'static str,
+    columns: &'static [&'static str],
+}
+
+/// Replace the store cipher saved in the database with one with new keys,
+/// re-encrypt all the values in the given encrypted columns, and re-hash all
+/// the keys in the given hashed columns.
+///
+/// Everything happens in a single transaction, and the new store cipher is
+/// returned.
+async fn rotate_store_cipher(
+    conn: &SqliteConn,
+    passphrase: &str,
+    encrypted_columns: &'static [EncryptedColumn],
+    hashed_columns: &'static [HashedColumns],
+) -> Result<StoreCipher> {
+    let encrypted = conn.get_kv("cipher").await?.ok_or(Error::Unencrypted)?;
+    let old_cipher = StoreCipher::import(passphrase, &encrypted)?;
+    let new_cipher = old_cipher.rotate_encryption_key()?;
+    let export = export_store_cipher(&new_cipher, passphrase)?;
+
+    conn.with_transaction(move |txn| {
+        for column in encrypted_columns {
+            reencrypt_column(txn, column, &old_cipher, &new_cipher)?;
+        }
+
+        for columns in hashed_columns {
+            rehash_columns(txn, columns, &new_cipher)?;
+        }
+
+        txn.set_kv("cipher", &export)?;
+
+        Ok(new_cipher)
+    })
+    .await
+}
+
+fn reencrypt_column(
+    txn: &Transaction<'_>,
+    column: &EncryptedColumn,
+    old_cipher: &StoreCipher,
+    new_cipher: &StoreCipher,
+) -> Result<()> {
+    let EncryptedColumn { table, column, filter } = column;
+    let filter = filter.unwrap_or("1");
+
+    let mut select =
+        txn.prepare(&format!("SELECT rowid, \"{column}\" FROM \"{table}\" WHERE {filter}"))?;
+    let rows = select
+        .query_map((), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut update =
+        txn.prepare(&format!("UPDATE \"{table}\" SET \"{column}\" = ? WHERE rowid = ?"))?;
+
+    for (rowid, value) in rows {
+        let encrypted = rmp_serde::from_slice(&value)?;
+        let encrypted = old_cipher.reencrypt_value_data(encrypted, new_cipher)?;
+        update.execute((rmp_serde::to_vec_named(&encrypted)?, rowid))?;
+    }
+
+    Ok(())
+}
+
+fn rehash_columns(
+    txn: &Transaction<'_>,
+    columns: &HashedColumns,
+    new_cipher: &StoreCipher,
+) -> Result<()> {
+    let HashedColumns { table, columns } = columns;
+    let selection = columns.iter().map(|column| format!("\"{column}\"")).collect::<Vec<_>>();
+    let assignments = columns.iter().map(|column| format!("\"{column}\" = ?")).collect::<Vec<_>>();
+
+    let mut select =
+        txn.prepare(&format!("SELECT rowid, {} FROM \"{table}\"", selection.join(", ")))?;
+    let rows = select
+        .query_map((), |row| {
+            let keys = (1..=columns.len())
+                .map(|idx| row.get::<_, Option<Vec<u8>>>(idx))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok((row.get::<_, i64>(0)?, keys))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut update =
+        txn.prepare(&format!("UPDATE \"{table}\" SET {} WHERE rowid = ?", assignments.join(", ")))?;
+
+    for (rowid, keys) in rows {
+        let mut params: Vec<rusqlite::types::Value> = keys
+            .into_iter()
+            .map(|key| key.map(|key| new_cipher.rehash_key(&key).to_vec()).into())
+            .collect();
+        params.push(rowid.into());
+
+        update.execute(rusqlite::params_from_iter(params))?;
+    }
+
+    Ok(())
+}
+
+/// Check that the schema version saved in the database is the expected one.
+async fn check_schema_version(conn: &SqliteConn, expected: u8) -> Result<Option<IntegrityIssue>> {
+    let found = match conn.get_kv("version").await?.as_deref() {
+        Some([version]) if *version == expected => return Ok(None),
+        Some([version]) => Some((*version).into()),
+        _ => None,
+    };
+
+    Ok(Some(IntegrityIssue::SchemaVersion { found, expected: expected.into() }))
+}
+
+/// Find the rows of the given column whose values can't be decrypted with the
+/// given store cipher, and remove them if `repair` is true.
+///
+/// Returns the number of broken rows.
+fn check_encrypted_column(
+    txn: &Transaction<'_>,
+    column: &EncryptedColumn,
+    cipher: &StoreCipher,
+    repair: bool,
+) -> Result<usize> {
+    let EncryptedColumn { table, column, filter } = column;
+    let filter = filter.unwrap_or("1");
+
+    let mut select =
+        txn.prepare(&format!("SELECT rowid, \"{column}\" FROM \"{table}\" WHERE {filter}"))?;
+    let broken_rows = select
+        .query_map((), |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+        .filter_map(|row| match row {
+            Ok((rowid, value)) => {
+                let decrypted = rmp_serde::from_slice(&value)
+                    .ok()
+                    .and_then(|encrypted| cipher.decrypt_value_data(encrypted).ok());
+                decrypted.is_none().then_some(Ok(rowid))
+            }
+            Err(error) => Some(Err(error)),
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if repair {
+        delete_rows(txn, table, &broken_rows)?;
+    }
+
+    Ok(broken_rows.len())
+}
+
+/// Remove the rows with the given IDs from the given table.
+fn delete_rows(txn: &Transaction<'_>, table: &str, rowids: &[i64]) -> rusqlite::Result<()> {
+    let mut delete = txn.prepare(&format!("DELETE FROM \"{table}\" WHERE rowid = ?"))?;
+
+    for rowid in rowids {
+        delete.execute((rowid,))?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 #[ctor::ctor]
 fn init_logging() {
@@ -89,3 +350,103 @@ pub async fn make_store_config(
         Ok(config)
     }
 }
+
+#[cfg(all(test, feature = "state-store", feature = "crypto-store"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use matrix_sdk_base::{
+        store::{StateChanges, StateStore, StoreArchive, StoreConfig},
+        RoomInfo, RoomState,
+    };
+    use matrix_sdk_crypto::{
+        store::{Changes, CryptoStore, RoomSettings},
+        ReadOnlyAccount,
+    };
+    use matrix_sdk_sled::SledStateStore;
+    use matrix_sdk_test::async_test;
+    use ruma::{device_id, room_id, user_id};
+    use tempfile::tempdir;
+
+    use crate::{SqliteCryptoStore, SqliteStateStore};
+
+    #[async_test]
+    async fn archive_round_trip_from_sled() {
+        let room_id = room_id!("!room:localhost");
+        let alice = ReadOnlyAccount::new(user_id!("@alice:localhost"), device_id!("ALICE"));
+        let bob = ReadOnlyAccount::new(user_id!("@bob:localhost"), device_id!("BOB"));
+
+        // Bob is not tracked, so his session can't be found from his devices.
+        let (session, _) = alice.create_session_for(&bob).await;
+        let (outbound, inbound) = alice.create_group_session_pair_with_defaults(room_id).await;
+        let settings = RoomSettings { only_allow_trusted_devices: true, ..Default::default() };
+
+        let sled_dir = tempdir().unwrap();
+        let sled_state_store = SledStateStore::builder()
+            .path(sled_dir.path().to_owned())
+            .passphrase("sled".to_owned())
+            .build()
+            .unwrap();
+        let sled_crypto_store = sled_state_store.open_crypto_store().await.unwrap();
+
+        let mut changes = StateChanges::default();
+        changes.add_room(RoomInfo::new(room_id, RoomState::Joined));
+        sled_state_store.save_changes(&changes).await.unwrap();
+
+        sled_crypto_store.save_account(alice.clone()).await.unwrap();
+        sled_crypto_store
+            .save_changes(Changes {
+                sessions: vec![session.clone()],
+                inbound_group_sessions: vec![inbound.clone()],
+                outbound_group_sessions: vec![outbound.clone()],
+                room_settings: HashMap::from([(room_id.to_owned(), settings.clone())]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let sled_config =
+            StoreConfig::new().state_store(sled_state_store).crypto_store(sled_crypto_store);
+        let bytes = StoreArchive::export(&sled_config).await.unwrap().encrypt("archive").unwrap();
+        let archive = StoreArchive::decrypt(&bytes, "archive").unwrap();
+
+        let sqlite_dir = tempdir().unwrap();
+        let sqlite_state_store =
+            SqliteStateStore::open(sqlite_dir.path(), Some("sqlite")).await.unwrap();
+        let sqlite_crypto_store =
+            SqliteCryptoStore::open(sqlite_dir.path(), Some("sqlite")).await.unwrap();
+        let sqlite_config = StoreConfig::new()
+            .state_store(sqlite_state_store.clone())
+            .crypto_store(sqlite_crypto_store.clone());
+        archive.import(&sqlite_config).await.unwrap();
+
+        let room_infos = sqlite_state_store.get_room_infos().await.unwrap();
+        assert_eq!(room_infos.len(), 1);
+        assert_eq!(room_infos[0].room_id(), room_id);
+
+        let account = sqlite_crypto_store.load_account().await.unwrap().unwrap();
+        assert_eq!(account.identity_keys().curve25519, alice.identity_keys().curve25519);
+
+        let sessions = sqlite_crypto_store
+            .get_sessions(&bob.identity_keys().curve25519.to_base64())
+            .await
+            .unwrap()
+            .unwrap();
+        let sessions = sessions.lock().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id(), session.session_id());
+
+        let inbound_from_store = sqlite_crypto_store
+            .get_inbound_group_session(room_id, inbound.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inbound_from_store.session_id(), inbound.session_id());
+
+        let outbound_from_store =
+            sqlite_crypto_store.get_outbound_group_session(room_id).await.unwrap().unwrap();
+        assert_eq!(outbound_from_store.session_id(), outbound.session_id());
+
+        assert_eq!(sqlite_crypto_store.get_room_settings(room_id).await.unwrap(), Some(settings));
+    }
+}
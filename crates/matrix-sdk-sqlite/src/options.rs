@@ -0,0 +1,271 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use deadpool_sqlite::{
+    Hook, HookError, HookErrorCause, Manager, Pool as SqlitePool, PoolConfig, Runtime,
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::{utils::SqliteObjectExt, OpenStoreError};
+
+/// The journal mode of a SQLite database.
+///
+/// See the [SQLite documentation] for the details of each mode.
+///
+/// [SQLite documentation]: https://www.sqlite.org/pragma.html#pragma_journal_mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JournalMode {
+    /// Use a write-ahead log.
+    ///
+    /// This allows readers and a writer to access the database at the same
+    /// time. This is the default.
+    #[default]
+    Wal,
+    /// Delete the rollback journal at the end of each transaction.
+    Delete,
+    /// Truncate the rollback journal at the end of each transaction.
+    Truncate,
+    /// Keep the rollback journal, but overwrite its header at the end of each
+    /// transaction.
+    Persist,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Wal => "wal",
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+        }
+    }
+}
+
+/// Options to open a [`SqliteStateStore`] or a [`SqliteCryptoStore`].
+///
+/// [`SqliteStateStore`]: crate::SqliteStateStore
+/// [`SqliteCryptoStore`]: crate::SqliteCryptoStore
+#[derive(Clone, Default)]
+pub struct SqliteStoreOptions {
+    pub(crate) passphrase: Option<String>,
+    pool_size: Option<usize>,
+    journal_mode: JournalMode,
+    busy_timeout: Option<Duration>,
+    cache_size: Option<u32>,
+    wal_checkpoint_interval: Option<Duration>,
+    skip_migrations: bool,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SqliteStoreOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStoreOptions")
+            .field("pool_size", &self.pool_size)
+            .field("journal_mode", &self.journal_mode)
+            .field("busy_timeout", &self.busy_timeout)
+            .field("cache_size", &self.cache_size)
+            .field("wal_checkpoint_interval", &self.wal_checkpoint_interval)
+            .field("skip_migrations", &self.skip_migrations)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SqliteStoreOptions {
+    /// Create the default `SqliteStoreOptions`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the passphrase that is used to encrypt the private data of the
+    /// store.
+    pub fn passphrase(mut self, passphrase: Option<&str>) -> Self {
+        self.passphrase = passphrase.map(ToOwned::to_owned);
+        self
+    }
+
+    /// Set the maximum number of connections to the database.
+    ///
+    /// Defaults to four times the number of CPUs.
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = Some(size);
+        self
+    }
+
+    /// Set the journal mode of the database.
+    ///
+    /// Defaults to [`JournalMode::Wal`].
+    pub fn journal_mode(mut self, mode: JournalMode) -> Self {
+        self.journal_mode = mode;
+        self
+    }
+
+    /// Set how long a connection waits for the database to be unlocked by
+    /// other connections, before failing with a "database is locked" error.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the maximum size of the page cache of each connection, in KiB.
+    ///
+    /// Defaults to the SQLite default, 2000 KiB.
+    pub fn cache_size(mut self, size: u32) -> Self {
+        self.cache_size = Some(size);
+        self
+    }
+
+    /// Checkpoint the write-ahead log in the background, with the given
+    /// interval.
+    ///
+    /// The content of the write-ahead log is moved into the database, and the
+    /// log is truncated, so it doesn't grow indefinitely in long-running
+    /// processes. This has no effect if the journal mode is not
+    /// [`JournalMode::Wal`].
+    ///
+    /// Disabled by default, in which case SQLite checkpoints the log when it
+    /// reaches 1000 pages, without truncating it.
+    pub fn wal_checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.wal_checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Set whether the migrations of the schema of the database are run when
+    /// the store is opened.
+    ///
+    /// Opening a store fails if its migrations fail, or if its schema is newer
+    /// than the one of this version of the store. A store opened without
+    /// running the migrations can still be checked with `verify_and_repair`,
+    /// to find out why, but it should not be used otherwise.
+    ///
+    /// Defaults to `true`.
+    pub fn run_migrations(mut self, run: bool) -> Self {
+        self.skip_migrations = !run;
+        self
+    }
+
+    /// Whether the migrations should be run when the store is opened.
+    pub(crate) fn should_run_migrations(&self) -> bool {
+        !self.skip_migrations
+    }
+
+    /// The pragmas to run on each new connection.
+    fn connection_pragmas(&self) -> String {
+        let mut pragmas = format!("PRAGMA journal_mode = {};", self.journal_mode.as_str());
+
+        if let Some(timeout) = self.busy_timeout {
+            pragmas.push_str(&format!("PRAGMA busy_timeout = {};", timeout.as_millis()));
+        }
+
+        // A negative value is a size in KiB, a positive value is a number of
+        // pages.
+        if let Some(size) = self.cache_size {
+            pragmas.push_str(&format!("PRAGMA cache_size = -{size};"));
+        }
+
+        pragmas
+    }
+
+    /// Create a pool of connections to the database at the given path, that
+    /// are configured with these options.
+    pub(crate) fn create_pool(&self, path: PathBuf) -> Result<SqlitePool, OpenStoreError> {
+        let config = deadpool_sqlite::Config::new(path);
+        let manager = Manager::from_config(&config, Runtime::Tokio1);
+
+        let mut builder = SqlitePool::builder(manager).runtime(Runtime::Tokio1);
+        if let Some(size) = self.pool_size {
+            builder = builder.config(PoolConfig::new(size));
+        }
+
+        let pragmas: Arc<str> = self.connection_pragmas().into();
+        let hook = Hook::async_fn(move |conn, _| {
+            let pragmas = pragmas.clone();
+            Box::pin(async move {
+                conn.interact(move |conn| conn.execute_batch(&pragmas))
+                    .await
+                    .map_err(|e| HookError::Abort(HookErrorCause::Message(e.to_string())))?
+                    .map_err(|e| HookError::Abort(HookErrorCause::Backend(e)))
+            })
+        });
+
+        Ok(builder.post_create(hook).build()?)
+    }
+
+    /// Set the journal mode of the database again, since the migrations of a
+    /// new database enable the write-ahead log.
+    pub(crate) async fn apply_journal_mode(
+        &self,
+        conn: &deadpool_sqlite::Object,
+    ) -> rusqlite::Result<()> {
+        if self.journal_mode != JournalMode::Wal {
+            conn.execute_batch(format!("PRAGMA journal_mode = {};", self.journal_mode.as_str()))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Spawn the task that checkpoints the write-ahead log of the database,
+    /// if it is enabled.
+    pub(crate) fn spawn_wal_checkpoint(&self, pool: SqlitePool) -> Option<Arc<AbortOnDrop>> {
+        let interval = self.wal_checkpoint_interval?;
+
+        if self.journal_mode != JournalMode::Wal {
+            return None;
+        }
+
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // The first tick completes immediately.
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                let result = match pool.get().await {
+                    Ok(conn) => checkpoint_wal(&conn).await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+
+                match result {
+                    Ok(()) => debug!("Checkpointed the write-ahead log"),
+                    Err(error) => warn!(%error, "Failed to checkpoint the write-ahead log"),
+                }
+            }
+        });
+
+        Some(Arc::new(AbortOnDrop(task)))
+    }
+}
+
+/// Move the content of the write-ahead log into the database, and truncate the
+/// log.
+pub(crate) async fn checkpoint_wal(conn: &deadpool_sqlite::Object) -> rusqlite::Result<()> {
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);").await
+}
+
+/// A handle to a background task, that aborts it when dropped.
+#[derive(Debug)]
+pub(crate) struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -1,16 +1,22 @@
 use std::{
     borrow::Cow,
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet, HashSet},
     fmt, iter,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use async_trait::async_trait;
-use deadpool_sqlite::{Object as SqliteConn, Pool as SqlitePool, Runtime};
+use deadpool_sqlite::{Object as SqliteConn, Pool as SqlitePool};
 use matrix_sdk_base::{
     deserialized_responses::RawMemberEvent,
-    media::{MediaRequest, UniqueKey},
+    event_cache::EventChunk,
+    media::{
+        should_update_media_last_access, MediaCacheStats, MediaRequest, MediaRetentionPolicy,
+        UniqueKey,
+    },
+    store::{integrity::compute_display_names, IntegrityIssue, IntegrityReport, StoreError},
     RoomInfo, RoomMemberships, RoomState, StateChanges, StateStore, StateStoreDataKey,
     StateStoreDataValue,
 };
@@ -25,18 +31,24 @@ use ruma::{
         GlobalAccountDataEventType, RoomAccountDataEventType, StateEventType,
     },
     serde::Raw,
-    CanonicalJsonObject, EventId, OwnedEventId, OwnedUserId, RoomId, RoomVersionId, UserId,
+    CanonicalJsonObject, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, RoomId,
+    RoomVersionId, UserId,
 };
 use rusqlite::{OptionalExtension, Transaction};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::fs;
-use tracing::{debug, error, warn};
+use tracing::{debug, warn};
 
 use crate::{
+    change_store_cipher_passphrase, check_encrypted_column, check_schema_version, db_size,
+    delete_rows,
     error::{Error, Result},
-    get_or_create_store_cipher,
+    get_or_create_store_cipher, load_schema_version, optimize,
+    options::AbortOnDrop,
+    rotate_store_cipher,
     utils::{chain, Key, SqliteObjectExt},
-    OpenStoreError, SqliteObjectStoreExt,
+    vacuum, EncryptedColumn, HashedColumns, OpenStoreError, SqliteObjectStoreExt,
+    SqliteStoreOptions,
 };
 
 mod keys {
@@ -51,14 +63,56 @@ mod keys {
     pub const RECEIPT: &str = "receipt";
     pub const DISPLAY_NAME: &str = "display_name";
     pub const MEDIA: &str = "media";
+    pub const EVENT_CHUNK: &str = "event_chunk";
+    pub const EVENT: &str = "event";
 }
 
+/// The columns that contain values encrypted with the store cipher.
+const ENCRYPTED_COLUMNS: &[EncryptedColumn] = &[
+    EncryptedColumn { table: keys::KV_BLOB, column: "value", filter: None },
+    EncryptedColumn::data(keys::ROOM_INFO),
+    EncryptedColumn::data(keys::STATE_EVENT),
+    EncryptedColumn::data(keys::GLOBAL_ACCOUNT_DATA),
+    EncryptedColumn::data(keys::ROOM_ACCOUNT_DATA),
+    EncryptedColumn::data(keys::MEMBER),
+    EncryptedColumn::data(keys::PROFILE),
+    EncryptedColumn::data(keys::RECEIPT),
+    EncryptedColumn::data(keys::DISPLAY_NAME),
+    EncryptedColumn::data(keys::MEDIA),
+    EncryptedColumn::data(keys::EVENT_CHUNK),
+];
+
+/// The columns that contain keys hashed with the store cipher.
+const HASHED_COLUMNS: &[HashedColumns] = &[
+    HashedColumns { table: keys::KV_BLOB, columns: &["key"] },
+    HashedColumns { table: keys::ROOM_INFO, columns: &["room_id"] },
+    HashedColumns {
+        table: keys::STATE_EVENT,
+        columns: &["room_id", "event_type", "state_key", "event_id"],
+    },
+    HashedColumns { table: keys::GLOBAL_ACCOUNT_DATA, columns: &["event_type"] },
+    HashedColumns { table: keys::ROOM_ACCOUNT_DATA, columns: &["room_id", "event_type"] },
+    HashedColumns { table: keys::MEMBER, columns: &["room_id", "user_id", "membership"] },
+    HashedColumns { table: keys::PROFILE, columns: &["room_id", "user_id"] },
+    HashedColumns {
+        table: keys::RECEIPT,
+        columns: &["room_id", "user_id", "receipt_type", "thread", "event_id"],
+    },
+    HashedColumns { table: keys::DISPLAY_NAME, columns: &["room_id", "name"] },
+    HashedColumns { table: keys::MEDIA, columns: &["uri", "format"] },
+    HashedColumns { table: keys::EVENT_CHUNK, columns: &["room_id"] },
+    HashedColumns { table: keys::EVENT, columns: &["room_id", "event_id"] },
+];
+
 /// A sqlite based cryptostore.
 #[derive(Clone)]
 pub struct SqliteStateStore {
     store_cipher: Option<Arc<StoreCipher>>,
     path: Option<PathBuf>,
     pool: SqlitePool,
+    /// The task that checkpoints the write-ahead log, aborted when the last
+    /// clone of the store is dropped.
+    _wal_checkpoint_task: Option<Arc<AbortOnDrop>>,
 }
 
 impl fmt::Debug for SqliteStateStore {
@@ -77,13 +131,33 @@ impl SqliteStateStore {
     pub async fn open(
         path: impl AsRef<Path>,
         passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_with_options(path, SqliteStoreOptions::new().passphrase(passphrase)).await
+    }
+
+    /// Open the sqlite-based state store at the given path with the given
+    /// options.
+    pub async fn open_with_options(
+        path: impl AsRef<Path>,
+        options: SqliteStoreOptions,
     ) -> Result<Self, OpenStoreError> {
         let path = path.as_ref();
         fs::create_dir_all(path).await.map_err(OpenStoreError::CreateDir)?;
-        let cfg = deadpool_sqlite::Config::new(path.join("matrix-sdk-state.sqlite3"));
-        let pool = cfg.create_pool(Runtime::Tokio1)?;
+        let pool = options.create_pool(path.join("matrix-sdk-state.sqlite3"))?;
+
+        let mut store = Self::open_helper(
+            pool.clone(),
+            options.passphrase.as_deref(),
+            options.should_run_migrations(),
+        )
+        .await?;
+
+        let conn = pool.get().await?;
+        options.apply_journal_mode(&conn).await.map_err(OpenStoreError::Configure)?;
 
-        Self::open_with_pool(pool, passphrase).await
+        store._wal_checkpoint_task = options.spawn_wal_checkpoint(pool);
+
+        Ok(store)
     }
 
     /// Create a sqlite-based state store using the given sqlite database pool.
@@ -91,15 +165,274 @@ impl SqliteStateStore {
     pub async fn open_with_pool(
         pool: SqlitePool,
         passphrase: Option<&str>,
+    ) -> Result<Self, OpenStoreError> {
+        Self::open_helper(pool, passphrase, true).await
+    }
+
+    async fn open_helper(
+        pool: SqlitePool,
+        passphrase: Option<&str>,
+        run_migrations: bool,
     ) -> Result<Self, OpenStoreError> {
         let conn = pool.get().await?;
-        run_migrations(&conn).await.map_err(OpenStoreError::Migration)?;
+        if run_migrations {
+            self::run_migrations(&conn).await?;
+        }
         let store_cipher = match passphrase {
             Some(p) => Some(Arc::new(get_or_create_store_cipher(p, &conn).await?)),
             None => None,
         };
 
-        Ok(Self { store_cipher, path: None, pool })
+        Ok(Self { store_cipher, path: None, pool, _wal_checkpoint_task: None })
+    }
+
+    /// Change the passphrase that is used to encrypt the store cipher of this
+    /// store.
+    ///
+    /// The data of the store is not re-encrypted, see
+    /// [`SqliteStateStore::rotate_encryption_key()`] for that.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), StoreError> {
+        let conn = self.acquire().await?;
+        Ok(change_store_cipher_passphrase(&conn, old_passphrase, new_passphrase).await?)
+    }
+
+    /// Rotate the keys that are used to encrypt the data of this store.
+    ///
+    /// All the values of the store are re-encrypted with a new key and all the
+    /// hashed keys are hashed again with a new key, in a single transaction.
+    /// The new store cipher is encrypted with the given passphrase.
+    ///
+    /// Returns the store using the new keys. This fails if other clones of
+    /// this store exist, since they would keep using the old keys, so this
+    /// should be called before the store is given to a client.
+    pub async fn rotate_encryption_key(self, passphrase: &str) -> Result<Self, StoreError> {
+        if self.store_cipher.as_ref().map_or(false, |cipher| Arc::strong_count(cipher) > 1) {
+            return Err(Error::InUse.into());
+        }
+
+        let conn = self.acquire().await?;
+        let store_cipher =
+            rotate_store_cipher(&conn, passphrase, ENCRYPTED_COLUMNS, HASHED_COLUMNS).await?;
+
+        Ok(Self { store_cipher: Some(Arc::new(store_cipher)), ..self })
+    }
+
+    /// Rebuild the database to reclaim the space of the deleted data, and
+    /// shrink the database file.
+    ///
+    /// This needs as much free disk space as the size of the database, and
+    /// blocks the other connections until it is done, so it should only be
+    /// called occasionally, for example after a lot of data was removed.
+    pub async fn vacuum(&self) -> Result<(), StoreError> {
+        let conn = self.acquire().await?;
+        Ok(vacuum(&conn).await?)
+    }
+
+    /// Let SQLite run the optimizations that it thinks are useful, like
+    /// updating the statistics used by the query planner.
+    ///
+    /// This is cheap, and can be called periodically, for example before
+    /// closing the store.
+    pub async fn optimize(&self) -> Result<(), StoreError> {
+        let conn = self.acquire().await?;
+        Ok(optimize(&conn).await?)
+    }
+
+    /// Get the size of the database, in bytes.
+    ///
+    /// The size of the write-ahead log, which is not yet merged into the
+    /// database, is not included.
+    pub async fn db_size(&self) -> Result<u64, StoreError> {
+        let conn = self.acquire().await?;
+        Ok(db_size(&conn).await?)
+    }
+
+    /// Check the integrity of this store, and repair it if `repair` is true.
+    ///
+    /// This checks the schema version of the database, that the values can be
+    /// decrypted with the current store cipher, that the members, profiles and
+    /// state events belong to rooms with a room info, and that the display
+    /// name maps match the members. See [`IntegrityIssue`] for what the repair
+    /// does for each problem.
+    ///
+    /// A store that fails to open can be checked by opening it with
+    /// [`SqliteStoreOptions::run_migrations()`] set to `false`.
+    pub async fn verify_and_repair(&self, repair: bool) -> Result<IntegrityReport, StoreError> {
+        let conn = self.acquire().await?;
+
+        if let Some(issue) = check_schema_version(&conn, DATABASE_VERSION).await? {
+            return Ok(IntegrityReport { issues: vec![issue], repaired: false });
+        }
+
+        let this = self.clone();
+        let (mut issues, joined_room_ids) = conn
+            .with_transaction(move |txn| -> Result<_> {
+                let mut issues = Vec::new();
+
+                // The room infos are checked below, since they also need to
+                // be deserialized.
+                if let Some(cipher) = &this.store_cipher {
+                    for column in ENCRYPTED_COLUMNS.iter().filter(|c| c.table != keys::ROOM_INFO) {
+                        let count = check_encrypted_column(txn, column, cipher, repair)?;
+                        if count > 0 {
+                            issues.push(IntegrityIssue::CorruptedValues {
+                                table: column.table.to_owned(),
+                                count,
+                            });
+                        }
+                    }
+                }
+
+                let mut room_ids = Vec::new();
+                let mut joined_room_ids = Vec::new();
+                let mut broken_rows = Vec::new();
+                let rows = txn
+                    .prepare("SELECT rowid, data, stripped FROM room_info")?
+                    .query_map((), |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, Vec<u8>>(1)?,
+                            row.get::<_, bool>(2)?,
+                        ))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                for (rowid, data, stripped) in rows {
+                    match this.deserialize_json::<RoomInfo>(&data) {
+                        Ok(room_info) => {
+                            let room_id = room_info.room_id().to_owned();
+                            // Display names are only tracked for the members of
+                            // non-stripped rooms.
+                            if !stripped {
+                                joined_room_ids.push(room_id.clone());
+                            }
+                            room_ids.push(room_id);
+                        }
+                        Err(_) => broken_rows.push(rowid),
+                    }
+                }
+
+                if !broken_rows.is_empty() {
+                    issues.push(IntegrityIssue::CorruptedValues {
+                        table: keys::ROOM_INFO.to_owned(),
+                        count: broken_rows.len(),
+                    });
+
+                    if repair {
+                        delete_rows(txn, keys::ROOM_INFO, &broken_rows)?;
+                        // Sync again from scratch to restore the removed rooms.
+                        let sync_token =
+                            this.encode_state_store_data_key(StateStoreDataKey::SyncToken);
+                        txn.execute("DELETE FROM kv_blob WHERE key = ?", (sync_token,))?;
+                    }
+                }
+
+                for table in [keys::STATE_EVENT, keys::MEMBER, keys::PROFILE, keys::DISPLAY_NAME] {
+                    let known_room_ids: HashSet<Vec<u8>> = room_ids
+                        .iter()
+                        .map(|room_id| this.encode_key(table, room_id).to_vec())
+                        .collect();
+                    let orphans = txn
+                        .prepare(&format!("SELECT DISTINCT room_id FROM \"{table}\""))?
+                        .query_map((), |row| row.get::<_, Vec<u8>>(0))?
+                        .filter(|room_id| {
+                            room_id
+                                .as_ref()
+                                .map_or(true, |room_id| !known_room_ids.contains(room_id))
+                        })
+                        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                    if !orphans.is_empty() {
+                        issues.push(IntegrityIssue::OrphanedRoomData {
+                            table: table.to_owned(),
+                            rooms: orphans.len(),
+                        });
+
+                        if repair {
+                            let mut delete =
+                                txn.prepare(&format!("DELETE FROM \"{table}\" WHERE room_id = ?"))?;
+                            for room_id in orphans {
+                                delete.execute((room_id,))?;
+                            }
+                        }
+                    }
+                }
+
+                Ok((issues, joined_room_ids))
+            })
+            .await?;
+
+        // The display names can only be computed reliably once the broken
+        // values are gone.
+        if repair || issues.is_empty() {
+            let mut inconsistent_rooms = 0;
+
+            for room_id in joined_room_ids {
+                let expected: BTreeMap<Vec<u8>, Option<BTreeSet<OwnedUserId>>> =
+                    compute_display_names(self, &room_id)
+                        .await?
+                        .into_iter()
+                        .map(|(name, user_ids)| {
+                            (self.encode_key(keys::DISPLAY_NAME, name).to_vec(), Some(user_ids))
+                        })
+                        .collect();
+
+                let encoded_room_id = self.encode_key(keys::DISPLAY_NAME, &room_id);
+                let stored: BTreeMap<Vec<u8>, Option<BTreeSet<OwnedUserId>>> = conn
+                    .prepare(
+                        "SELECT name, data FROM display_name WHERE room_id = ?",
+                        move |mut stmt| {
+                            stmt.query_map((encoded_room_id,), |row| {
+                                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+                            })?
+                            .collect::<rusqlite::Result<Vec<_>>>()
+                        },
+                    )
+                    .await
+                    .map_err(Error::from)?
+                    .into_iter()
+                    .map(|(name, data)| (name, self.deserialize_json(&data).ok()))
+                    // Empty sets are kept when the last user with a display
+                    // name changes it.
+                    .filter(|(_, user_ids): &(_, Option<BTreeSet<OwnedUserId>>)| {
+                        user_ids.as_ref().map_or(true, |user_ids| !user_ids.is_empty())
+                    })
+                    .collect();
+
+                if stored == expected {
+                    continue;
+                }
+
+                inconsistent_rooms += 1;
+
+                if repair {
+                    let room_id = self.encode_key(keys::DISPLAY_NAME, &room_id);
+                    let display_names = expected
+                        .into_iter()
+                        .map(|(name, user_ids)| Ok((name, self.serialize_json(&user_ids)?)))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    conn.with_transaction(move |txn| -> Result<()> {
+                        txn.remove_room_display_names(&room_id)?;
+                        for (name, data) in display_names {
+                            txn.set_display_name(&room_id, &name, &data)?;
+                        }
+                        Ok(())
+                    })
+                    .await?;
+                }
+            }
+
+            if inconsistent_rooms > 0 {
+                issues.push(IntegrityIssue::InconsistentDisplayNames { rooms: inconsistent_rooms });
+            }
+        }
+
+        Ok(IntegrityReport { issues, repaired: repair })
     }
 
     fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
@@ -195,37 +528,24 @@ impl SqliteStateStore {
     }
 }
 
-const DATABASE_VERSION: u8 = 1;
+const DATABASE_VERSION: u8 = 3;
 
-async fn run_migrations(conn: &SqliteConn) -> rusqlite::Result<()> {
-    let kv_exists = conn
-        .query_row(
-            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'kv'",
-            (),
-            |row| row.get::<_, u32>(0),
-        )
-        .await?
-        > 0;
-
-    let version = if kv_exists {
-        match conn.get_kv("version").await?.as_deref() {
-            Some([v]) => *v,
-            Some(_) => {
-                error!("version database field has multiple bytes");
-                return Ok(());
-            }
-            None => {
-                error!("version database field is missing");
-                return Ok(());
-            }
-        }
-    } else {
-        0
-    };
+async fn run_migrations(conn: &SqliteConn) -> Result<(), OpenStoreError> {
+    let version = load_schema_version(conn, DATABASE_VERSION).await?;
+
+    if version < DATABASE_VERSION {
+        migrate(conn, version).await.map_err(OpenStoreError::Migration)?;
+    }
+
+    Ok(())
+}
 
+/// Migrate the schema of the database from the given version to
+/// `DATABASE_VERSION`.
+async fn migrate(conn: &SqliteConn, version: u8) -> rusqlite::Result<()> {
     if version == 0 {
         debug!("Creating database");
-    } else if version < DATABASE_VERSION {
+    } else {
         debug!(version, new_version = DATABASE_VERSION, "Upgrading database");
     }
 
@@ -239,6 +559,20 @@ async fn run_migrations(conn: &SqliteConn) -> rusqlite::Result<()> {
         .await?;
     }
 
+    if version < 2 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!("../migrations/state_store/002_event_cache.sql"))
+        })
+        .await?;
+    }
+
+    if version < 3 {
+        conn.with_transaction(|txn| {
+            txn.execute_batch(include_str!("../migrations/state_store/003_media_cache.sql"))
+        })
+        .await?;
+    }
+
     conn.set_kv("version", vec![DATABASE_VERSION]).await?;
 
     Ok(())
@@ -308,6 +642,13 @@ trait SqliteConnectionStateStoreExt {
     fn set_display_name(&self, room_id: &[u8], name: &[u8], data: &[u8]) -> rusqlite::Result<()>;
     fn remove_display_name(&self, room_id: &[u8], name: &[u8]) -> rusqlite::Result<()>;
     fn remove_room_display_names(&self, room_id: &[u8]) -> rusqlite::Result<()>;
+
+    fn set_event_chunk(&self, room_id: &[u8], chunk_id: i64, data: &[u8]) -> rusqlite::Result<()>;
+    fn remove_event_chunk(&self, room_id: &[u8], chunk_id: i64) -> rusqlite::Result<()>;
+    fn set_event(&self, room_id: &[u8], event_id: &[u8], chunk_id: i64) -> rusqlite::Result<()>;
+    fn remove_chunk_events(&self, room_id: &[u8], chunk_id: i64) -> rusqlite::Result<()>;
+    fn remove_room_event_chunks(&self, room_id: &[u8]) -> rusqlite::Result<()>;
+    fn remove_room_events(&self, room_id: &[u8]) -> rusqlite::Result<()>;
 }
 
 impl SqliteConnectionStateStoreExt for rusqlite::Connection {
@@ -521,6 +862,48 @@ impl SqliteConnectionStateStoreExt for rusqlite::Connection {
         self.prepare("DELETE FROM display_name WHERE room_id = ?")?.execute((room_id,))?;
         Ok(())
     }
+
+    fn set_event_chunk(&self, room_id: &[u8], chunk_id: i64, data: &[u8]) -> rusqlite::Result<()> {
+        self.prepare_cached(
+            "INSERT OR REPLACE
+             INTO event_chunk (room_id, chunk_id, data)
+             VALUES (?, ?, ?)",
+        )?
+        .execute((room_id, chunk_id, data))?;
+        Ok(())
+    }
+
+    fn remove_event_chunk(&self, room_id: &[u8], chunk_id: i64) -> rusqlite::Result<()> {
+        self.prepare_cached("DELETE FROM event_chunk WHERE room_id = ? AND chunk_id = ?")?
+            .execute((room_id, chunk_id))?;
+        Ok(())
+    }
+
+    fn set_event(&self, room_id: &[u8], event_id: &[u8], chunk_id: i64) -> rusqlite::Result<()> {
+        self.prepare_cached(
+            "INSERT OR REPLACE
+             INTO event (room_id, event_id, chunk_id)
+             VALUES (?, ?, ?)",
+        )?
+        .execute((room_id, event_id, chunk_id))?;
+        Ok(())
+    }
+
+    fn remove_chunk_events(&self, room_id: &[u8], chunk_id: i64) -> rusqlite::Result<()> {
+        self.prepare_cached("DELETE FROM event WHERE room_id = ? AND chunk_id = ?")?
+            .execute((room_id, chunk_id))?;
+        Ok(())
+    }
+
+    fn remove_room_event_chunks(&self, room_id: &[u8]) -> rusqlite::Result<()> {
+        self.prepare("DELETE FROM event_chunk WHERE room_id = ?")?.execute((room_id,))?;
+        Ok(())
+    }
+
+    fn remove_room_events(&self, room_id: &[u8]) -> rusqlite::Result<()> {
+        self.prepare("DELETE FROM event WHERE room_id = ?")?.execute((room_id,))?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -700,21 +1083,38 @@ trait SqliteObjectStateStoreExt: SqliteObjectExt {
             .await?)
     }
 
-    async fn set_media(&self, uri: Key, format: Key, data: Vec<u8>) -> Result<()> {
+    async fn set_media(
+        &self,
+        uri: Key,
+        format: Key,
+        data: Vec<u8>,
+        size: i64,
+        last_access: i64,
+    ) -> Result<()> {
+        self.execute(
+            "INSERT OR REPLACE INTO media (uri, format, data, size, last_access)
+             VALUES (?, ?, ?, ?, ?)",
+            (uri, format, data, size, last_access),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn set_media_last_access(&self, uri: Key, format: Key, last_access: i64) -> Result<()> {
         self.execute(
-            "INSERT OR REPLACE INTO media (uri, format, data) VALUES (?, ?, ?)",
-            (uri, format, data),
+            "UPDATE media SET last_access = ? WHERE uri = ? AND format = ?",
+            (last_access, uri, format),
         )
         .await?;
         Ok(())
     }
 
-    async fn get_media(&self, uri: Key, format: Key) -> Result<Option<Vec<u8>>> {
+    async fn get_media(&self, uri: Key, format: Key) -> Result<Option<(Vec<u8>, i64)>> {
         Ok(self
             .query_row(
-                "SELECT data FROM media WHERE uri = ? AND format = ?",
+                "SELECT data, last_access FROM media WHERE uri = ? AND format = ?",
                 (uri, format),
-                |row| row.get(0),
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
             .await
             .optional()?)
@@ -729,6 +1129,125 @@ trait SqliteObjectStateStoreExt: SqliteObjectExt {
         self.execute("DELETE FROM media WHERE uri = ?", (uri,)).await?;
         Ok(())
     }
+
+    async fn get_media_entries(&self) -> Result<Vec<((Vec<u8>, Vec<u8>), i64, i64)>> {
+        Ok(self
+            .prepare("SELECT uri, format, size, last_access FROM media", |mut stmt| {
+                stmt.query(())?
+                    .mapped(|row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?, row.get(3)?)))
+                    .collect()
+            })
+            .await?)
+    }
+
+    async fn remove_medias(&self, keys: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.with_transaction(move |txn| {
+            let mut stmt = txn.prepare("DELETE FROM media WHERE uri = ? AND format = ?")?;
+            for (uri, format) in keys {
+                stmt.execute((uri, format))?;
+            }
+            Ok::<_, rusqlite::Error>(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Remove the least recently accessed medias until their total size is at
+    /// most `max_size`, and return the total size of the medias that are left.
+    async fn trim_medias(&self, max_size: i64) -> Result<i64> {
+        Ok(self
+            .with_transaction(move |txn| {
+                let mut total_size: i64 =
+                    txn.query_row("SELECT COALESCE(SUM(size), 0) FROM media", (), |row| {
+                        row.get(0)
+                    })?;
+
+                if total_size > max_size {
+                    let mut keys = Vec::new();
+                    let mut stmt = txn
+                        .prepare("SELECT uri, format, size FROM media ORDER BY last_access ASC")?;
+                    let mut rows = stmt.query(())?;
+                    while total_size > max_size {
+                        let Some(row) = rows.next()? else { break };
+                        keys.push((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?));
+                        total_size -= row.get::<_, i64>(2)?;
+                    }
+                    drop(rows);
+
+                    let mut stmt = txn.prepare("DELETE FROM media WHERE uri = ? AND format = ?")?;
+                    for (uri, format) in keys {
+                        stmt.execute((uri, format))?;
+                    }
+                }
+
+                Ok::<_, rusqlite::Error>(total_size)
+            })
+            .await?)
+    }
+
+    async fn get_media_stats(&self) -> Result<(i64, i64)> {
+        Ok(self
+            .query_row("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM media", (), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .await?)
+    }
+
+    async fn get_event_chunk_range(&self, room_id: Key) -> Result<Option<(i64, i64)>> {
+        Ok(self
+            .query_row(
+                "SELECT MIN(chunk_id), MAX(chunk_id) FROM event_chunk WHERE room_id = ?",
+                (room_id,),
+                |row| Ok(row.get::<_, Option<i64>>(0)?.zip(row.get::<_, Option<i64>>(1)?)),
+            )
+            .await?)
+    }
+
+    async fn get_event_chunk(&self, room_id: Key, chunk_id: i64) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .query_row(
+                "SELECT data FROM event_chunk WHERE room_id = ? AND chunk_id = ?",
+                (room_id, chunk_id),
+                |row| row.get(0),
+            )
+            .await
+            .optional()?)
+    }
+
+    async fn get_event_chunk_id(&self, room_id: Key, event_id: Key) -> Result<Option<i64>> {
+        Ok(self
+            .query_row(
+                "SELECT chunk_id FROM event WHERE room_id = ? AND event_id = ?",
+                (room_id, event_id),
+                |row| row.get(0),
+            )
+            .await
+            .optional()?)
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: Key,
+        event_ids: Vec<Key>,
+    ) -> Result<Vec<(Vec<u8>, i64)>> {
+        if event_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let sql_params = vec!["?"; event_ids.len()].join(", ");
+        let sql = format!(
+            "SELECT event_id, chunk_id FROM event WHERE room_id = ? AND event_id IN ({sql_params})"
+        );
+        let params = iter::once(room_id).chain(event_ids);
+
+        Ok(self
+            .prepare(sql, move |mut stmt| {
+                stmt.query(rusqlite::params_from_iter(params))?
+                    .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                    .collect()
+            })
+            .await?)
+    }
 }
 
 #[async_trait]
@@ -806,6 +1325,7 @@ impl StateStore for SqliteStateStore {
                     stripped_room_infos,
                     ambiguity_maps,
                     notifications: _,
+                    event_cache,
                 } = changes;
 
                 if let Some(sync_token) = sync_token {
@@ -1059,6 +1579,32 @@ impl StateStore for SqliteStateStore {
                     }
                 }
 
+                for (room_id, cache_changes) in event_cache {
+                    let chunk_room_id = this.encode_key(keys::EVENT_CHUNK, &room_id);
+                    let event_room_id = this.encode_key(keys::EVENT, &room_id);
+
+                    if cache_changes.reset {
+                        txn.remove_room_event_chunks(&chunk_room_id)?;
+                        txn.remove_room_events(&event_room_id)?;
+                    }
+
+                    for chunk_id in cache_changes.removed_chunks {
+                        txn.remove_chunk_events(&event_room_id, chunk_id)?;
+                        txn.remove_event_chunk(&chunk_room_id, chunk_id)?;
+                    }
+
+                    for (chunk_id, chunk) in cache_changes.chunks {
+                        txn.remove_chunk_events(&event_room_id, chunk_id)?;
+                        for event_id in chunk.event_ids() {
+                            let event_id = this.encode_key(keys::EVENT, event_id);
+                            txn.set_event(&event_room_id, &event_id, chunk_id)?;
+                        }
+
+                        let data = this.serialize_json(&chunk)?;
+                        txn.set_event_chunk(&chunk_room_id, chunk_id, &data)?;
+                    }
+                }
+
                 Ok::<_, Error>(())
             })
             .await?;
@@ -1313,15 +1859,25 @@ impl StateStore for SqliteStateStore {
     async fn add_media_content(&self, request: &MediaRequest, content: Vec<u8>) -> Result<()> {
         let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
         let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+        let size = content.len().try_into().unwrap_or(i64::MAX);
         let data = self.encode_value(content)?;
-        self.acquire().await?.set_media(uri, format, data).await
+        self.acquire().await?.set_media(uri, format, data, size, now_millis()).await
     }
 
     async fn get_media_content(&self, request: &MediaRequest) -> Result<Option<Vec<u8>>> {
         let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
         let format = self.encode_key(keys::MEDIA, request.format.unique_key());
-        let data = self.acquire().await?.get_media(uri, format).await?;
-        data.map(|v| self.decode_value(&v).map(Into::into)).transpose()
+        let conn = self.acquire().await?;
+        let Some((data, last_access)) = conn.get_media(uri.clone(), format.clone()).await? else {
+            return Ok(None);
+        };
+
+        let last_access = MilliSecondsSinceUnixEpoch(last_access.try_into().unwrap_or_default());
+        if should_update_media_last_access(last_access, MilliSecondsSinceUnixEpoch::now()) {
+            conn.set_media_last_access(uri, format, now_millis()).await?;
+        }
+
+        Ok(Some(self.decode_value(&data)?.into()))
     }
 
     async fn remove_media_content(&self, request: &MediaRequest) -> Result<()> {
@@ -1335,6 +1891,84 @@ impl StateStore for SqliteStateStore {
         self.acquire().await?.remove_uri_medias(uri).await
     }
 
+    async fn clean_media_cache(&self, policy: MediaRetentionPolicy) -> Result<()> {
+        let conn = self.acquire().await?;
+        let entries =
+            conn.get_media_entries().await?.into_iter().map(|(key, size, last_access)| {
+                let last_access =
+                    MilliSecondsSinceUnixEpoch(last_access.try_into().unwrap_or_default());
+                (key, size.try_into().unwrap_or(usize::MAX), last_access)
+            });
+
+        let keys = policy.select_for_removal(entries, MilliSecondsSinceUnixEpoch::now());
+        if !keys.is_empty() {
+            conn.remove_medias(keys).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn trim_media_cache(&self, max_size: usize) -> Result<usize> {
+        let max_size = max_size.try_into().unwrap_or(i64::MAX);
+        let total_size = self.acquire().await?.trim_medias(max_size).await?;
+        Ok(total_size.try_into().unwrap_or_default())
+    }
+
+    async fn media_cache_stats(&self) -> Result<MediaCacheStats> {
+        let (count, total_size) = self.acquire().await?.get_media_stats().await?;
+        Ok(MediaCacheStats {
+            count: count.try_into().unwrap_or_default(),
+            total_size: total_size.try_into().unwrap_or_default(),
+        })
+    }
+
+    async fn get_event_chunk_range(&self, room_id: &RoomId) -> Result<Option<RangeInclusive<i64>>> {
+        let room_id = self.encode_key(keys::EVENT_CHUNK, room_id);
+        let range = self.acquire().await?.get_event_chunk_range(room_id).await?;
+        Ok(range.map(|(start, end)| start..=end))
+    }
+
+    async fn get_event_chunk(&self, room_id: &RoomId, chunk_id: i64) -> Result<Option<EventChunk>> {
+        let room_id = self.encode_key(keys::EVENT_CHUNK, room_id);
+        self.acquire()
+            .await?
+            .get_event_chunk(room_id, chunk_id)
+            .await?
+            .map(|data| self.deserialize_json(&data))
+            .transpose()
+    }
+
+    async fn get_event_chunk_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<i64>> {
+        let room_id = self.encode_key(keys::EVENT, room_id);
+        let event_id = self.encode_key(keys::EVENT, event_id);
+        self.acquire().await?.get_event_chunk_id(room_id, event_id).await
+    }
+
+    async fn get_event_chunk_ids(
+        &self,
+        room_id: &RoomId,
+        event_ids: &[OwnedEventId],
+    ) -> Result<BTreeMap<OwnedEventId, i64>> {
+        let room_id = self.encode_key(keys::EVENT, room_id);
+        // The keys might be hashed, so we need to map them back to the IDs.
+        let mut event_keys: BTreeMap<_, _> = event_ids
+            .iter()
+            .map(|event_id| (self.encode_key(keys::EVENT, event_id).to_vec(), event_id))
+            .collect();
+        let params = event_keys.keys().cloned().map(Key::Plain).collect();
+
+        let chunk_ids = self.acquire().await?.get_event_chunk_ids(room_id, params).await?;
+
+        Ok(chunk_ids
+            .into_iter()
+            .filter_map(|(key, chunk_id)| Some((event_keys.remove(&key)?.clone(), chunk_id)))
+            .collect())
+    }
+
     async fn remove_room(&self, room_id: &RoomId) -> Result<()> {
         let this = self.clone();
         let room_id = room_id.to_owned();
@@ -1363,10 +1997,41 @@ impl StateStore for SqliteStateStore {
                 let display_name_room_id = this.encode_key(keys::DISPLAY_NAME, &room_id);
                 txn.remove_room_display_names(&display_name_room_id)?;
 
+                let event_chunk_room_id = this.encode_key(keys::EVENT_CHUNK, &room_id);
+                txn.remove_room_event_chunks(&event_chunk_room_id)?;
+
+                let event_room_id = this.encode_key(keys::EVENT, &room_id);
+                txn.remove_room_events(&event_room_id)?;
+
                 Ok(())
             })
             .await
     }
+
+    async fn clear(&self) -> Result<()> {
+        self.acquire()
+            .await?
+            .with_transaction(|txn| {
+                txn.execute_batch(
+                    "DELETE FROM kv WHERE key NOT IN ('version', 'cipher');
+                    DELETE FROM kv_blob;
+                    DELETE FROM room_info;
+                    DELETE FROM state_event;
+                    DELETE FROM global_account_data;
+                    DELETE FROM room_account_data;
+                    DELETE FROM member;
+                    DELETE FROM profile;
+                    DELETE FROM receipt;
+                    DELETE FROM display_name;
+                    DELETE FROM media;
+                    DELETE FROM event_chunk;
+                    DELETE FROM event;",
+                )
+            })
+            .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1376,15 +2041,29 @@ struct ReceiptData {
     user_id: OwnedUserId,
 }
 
+/// The current time, in milliseconds since the Unix epoch.
+fn now_millis() -> i64 {
+    MilliSecondsSinceUnixEpoch::now().get().into()
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::{
+        sync::atomic::{AtomicU32, Ordering::SeqCst},
+        time::Duration,
+    };
 
-    use matrix_sdk_base::{statestore_integration_tests, StateStore, StoreError};
+    use matrix_sdk_base::{
+        media::{MediaFormat, MediaRequest},
+        statestore_integration_tests, StateStore, StoreError,
+    };
+    use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
+    use ruma::{events::room::MediaSource, mxc_uri};
     use tempfile::{tempdir, TempDir};
 
     use super::SqliteStateStore;
+    use crate::{JournalMode, SqliteStoreOptions};
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
     static NUM: AtomicU32 = AtomicU32::new(0);
@@ -1397,17 +2076,72 @@ mod tests {
     }
 
     statestore_integration_tests!(with_media_tests);
+
+    async fn check_vacuum(options: SqliteStoreOptions) {
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+        let store = SqliteStateStore::open_with_options(&path, options).await.unwrap();
+
+        let initial_size = store.db_size().await.unwrap();
+
+        let request = MediaRequest {
+            source: MediaSource::Plain(mxc_uri!("mxc://localhost/media").to_owned()),
+            format: MediaFormat::File,
+        };
+        store.add_media_content(&request, vec![0; 1024 * 1024]).await.unwrap();
+
+        let size_with_media = store.db_size().await.unwrap();
+        assert!(size_with_media > initial_size);
+
+        // The deleted data still takes space until the database is vacuumed.
+        store.remove_media_content(&request).await.unwrap();
+        assert_eq!(store.db_size().await.unwrap(), size_with_media);
+
+        store.vacuum().await.unwrap();
+        store.optimize().await.unwrap();
+        assert!(store.db_size().await.unwrap() < size_with_media);
+    }
+
+    #[async_test]
+    async fn vacuum_with_wal() {
+        check_vacuum(
+            SqliteStoreOptions::new()
+                .busy_timeout(Duration::from_secs(1))
+                .wal_checkpoint_interval(Duration::from_millis(10)),
+        )
+        .await;
+    }
+
+    #[async_test]
+    async fn vacuum_without_wal() {
+        check_vacuum(
+            SqliteStoreOptions::new()
+                .journal_mode(JournalMode::Delete)
+                .pool_size(2)
+                .cache_size(512),
+        )
+        .await;
+    }
 }
 
 #[cfg(test)]
 mod encrypted_tests {
-    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::{
+        collections::BTreeSet,
+        sync::atomic::{AtomicU32, Ordering::SeqCst},
+    };
 
-    use matrix_sdk_base::{statestore_integration_tests, StateStore, StoreError};
+    use assert_matches::assert_matches;
+    use matrix_sdk_base::{
+        statestore_integration_tests, store::IntegrityIssue, RoomInfo, RoomState, StateChanges,
+        StateStore, StateStoreDataKey, StateStoreDataValue, StoreError,
+    };
+    use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
+    use ruma::{room_id, user_id, OwnedUserId};
     use tempfile::{tempdir, TempDir};
 
-    use super::SqliteStateStore;
+    use super::{keys, SqliteConnectionStateStoreExt, SqliteStateStore, DATABASE_VERSION};
+    use crate::{utils::SqliteObjectExt, OpenStoreError, SqliteObjectStoreExt, SqliteStoreOptions};
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
     static NUM: AtomicU32 = AtomicU32::new(0);
@@ -1422,4 +2156,163 @@ mod encrypted_tests {
     }
 
     statestore_integration_tests!(with_media_tests);
+
+    #[async_test]
+    async fn change_passphrase() {
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+
+        let store = SqliteStateStore::open(&path, Some("old_password")).await.unwrap();
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+        store.change_passphrase("old_password", "new_password").await.unwrap();
+        drop(store);
+
+        SqliteStateStore::open(&path, Some("old_password")).await.unwrap_err();
+
+        let store = SqliteStateStore::open(&path, Some("new_password")).await.unwrap();
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[async_test]
+    async fn rotate_encryption_key() {
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+
+        let store = SqliteStateStore::open(&path, Some("password")).await.unwrap();
+        store
+            .set_kv_data(
+                StateStoreDataKey::SyncToken,
+                StateStoreDataValue::SyncToken("sync_token".to_owned()),
+            )
+            .await
+            .unwrap();
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+        let hashed_key = store.encode_custom_key(b"key").to_vec();
+
+        // The store can't be rotated while it is cloned.
+        let clone = store.clone();
+        assert!(store.rotate_encryption_key("password").await.is_err());
+
+        let store = clone.rotate_encryption_key("password").await.unwrap();
+        assert_ne!(store.encode_custom_key(b"key").to_vec(), hashed_key);
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        drop(store);
+
+        let store = SqliteStateStore::open(&path, Some("password")).await.unwrap();
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        assert_matches!(
+            store.get_kv_data(StateStoreDataKey::SyncToken).await.unwrap(),
+            Some(StateStoreDataValue::SyncToken(token)) if token == "sync_token"
+        );
+    }
+
+    #[async_test]
+    async fn verify_and_repair() {
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+        let room_id = room_id!("!room:localhost");
+        let unknown_room_id = room_id!("!unknown:localhost");
+
+        let store = SqliteStateStore::open(&path, Some("password")).await.unwrap();
+        let mut changes = StateChanges::default();
+        changes.add_room(RoomInfo::new(room_id, RoomState::Joined));
+        store.save_changes(&changes).await.unwrap();
+
+        // A value that can't be decrypted, a member of a room without a room
+        // info, and a display name without a member.
+        let display_name_room_id = store.encode_key(keys::DISPLAY_NAME, room_id);
+        let display_name = store.encode_key(keys::DISPLAY_NAME, "alice");
+        let user_ids =
+            store.serialize_json(&BTreeSet::from([user_id!("@alice:localhost")])).unwrap();
+        let member_room_id = store.encode_key(keys::MEMBER, unknown_room_id);
+        let member_user_id = store.encode_key(keys::MEMBER, "@alice:localhost");
+        store
+            .acquire()
+            .await
+            .unwrap()
+            .with_transaction(move |txn| {
+                txn.set_kv_blob(b"broken", b"not encrypted")?;
+                txn.set_member(&member_room_id, &member_user_id, b"join", false, b"not encrypted")?;
+                txn.set_display_name(&display_name_room_id, &display_name, &user_ids)
+            })
+            .await
+            .unwrap();
+
+        let report = store.verify_and_repair(false).await.unwrap();
+        assert!(!report.is_usable());
+        assert_eq!(
+            report.issues,
+            [
+                IntegrityIssue::CorruptedValues { table: "kv_blob".to_owned(), count: 1 },
+                IntegrityIssue::CorruptedValues { table: "member".to_owned(), count: 1 },
+                IntegrityIssue::OrphanedRoomData { table: "member".to_owned(), rooms: 1 },
+            ]
+        );
+
+        let report = store.verify_and_repair(true).await.unwrap();
+        assert!(report.is_usable());
+        assert_eq!(
+            report.issues,
+            [
+                IntegrityIssue::CorruptedValues { table: "kv_blob".to_owned(), count: 1 },
+                IntegrityIssue::CorruptedValues { table: "member".to_owned(), count: 1 },
+                IntegrityIssue::InconsistentDisplayNames { rooms: 1 },
+            ]
+        );
+
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+        assert!(store.get_room_infos().await.unwrap().iter().any(|r| r.room_id() == room_id));
+        assert!(store.get_users_with_display_name(room_id, "alice").await.unwrap().is_empty());
+
+        // An empty set is kept when the last user with a display name changes
+        // it, it is not an issue.
+        let display_name_room_id = store.encode_key(keys::DISPLAY_NAME, room_id);
+        let display_name = store.encode_key(keys::DISPLAY_NAME, "bob");
+        let user_ids = store.serialize_json(&BTreeSet::<OwnedUserId>::new()).unwrap();
+        store
+            .acquire()
+            .await
+            .unwrap()
+            .with_transaction(move |txn| {
+                txn.set_display_name(&display_name_room_id, &display_name, &user_ids)
+            })
+            .await
+            .unwrap();
+
+        assert!(store.verify_and_repair(false).await.unwrap().is_healthy());
+    }
+
+    #[async_test]
+    async fn verify_unsupported_schema_version() {
+        let path = TMP_DIR.path().join(NUM.fetch_add(1, SeqCst).to_string());
+
+        let store = SqliteStateStore::open(&path, Some("password")).await.unwrap();
+        store.acquire().await.unwrap().set_kv("version", vec![DATABASE_VERSION + 1]).await.unwrap();
+        drop(store);
+
+        // The store is rejected instead of being downgraded.
+        let error = SqliteStateStore::open(&path, Some("password")).await.unwrap_err();
+        assert_matches!(
+            error,
+            OpenStoreError::SchemaVersion { found: Some(version), expected: DATABASE_VERSION }
+                if version == DATABASE_VERSION + 1
+        );
+
+        // It can still be opened without migrations to be checked.
+        let options = SqliteStoreOptions::new().passphrase(Some("password")).run_migrations(false);
+        let store = SqliteStateStore::open_with_options(&path, options).await.unwrap();
+        let report = store.verify_and_repair(true).await.unwrap();
+        assert!(!report.is_usable());
+        assert_eq!(
+            report.issues,
+            [IntegrityIssue::SchemaVersion {
+                found: Some((DATABASE_VERSION + 1).into()),
+                expected: DATABASE_VERSION.into(),
+            }]
+        );
+        drop(store);
+
+        // Nothing was written by the check.
+        assert_matches!(
+            SqliteStateStore::open(&path, Some("password")).await,
+            Err(OpenStoreError::SchemaVersion { .. })
+        );
+    }
 }
@@ -35,6 +35,8 @@ const VERSION: u8 = 1;
 const KDF_SALT_SIZE: usize = 32;
 const XNONCE_SIZE: usize = 24;
 const KDF_ROUNDS: u32 = 200_000;
+/// The context used to derive the keys that hash the hashed keys again.
+const REHASH_KEY_CONTEXT: &str = "matrix-sdk-store-encryption rehash key";
 
 type MacKeySeed = [u8; 32];
 
@@ -172,10 +174,14 @@ impl StoreCipher {
 
         let nonce = Keys::get_nonce()?;
 
-        let mut keys = [0u8; 64];
+        let mut keys = Vec::with_capacity(64 + 32 * self.inner.rehash_key_seeds.len());
 
-        keys[0..32].copy_from_slice(self.inner.encryption_key.as_ref());
-        keys[32..64].copy_from_slice(self.inner.mac_key_seed.as_ref());
+        keys.extend_from_slice(self.inner.encryption_key.as_ref());
+        keys.extend_from_slice(self.inner.mac_key_seed.as_ref());
+
+        for seed in &self.inner.rehash_key_seeds {
+            keys.extend_from_slice(seed.as_ref());
+        }
 
         let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), keys.as_ref())?;
 
@@ -217,7 +223,9 @@ impl StoreCipher {
             }
         };
 
-        if decrypted.len() != 64 {
+        // The export contains the encryption key and the MAC key seed, followed
+        // by the seed of every rehash key.
+        if decrypted.len() < 64 || decrypted.len() % 32 != 0 {
             decrypted.zeroize();
 
             Err(Error::Length(64, decrypted.len()))
@@ -228,7 +236,16 @@ impl StoreCipher {
             encryption_key.copy_from_slice(&decrypted[0..32]);
             mac_key_seed.copy_from_slice(&decrypted[32..64]);
 
-            let keys = Keys { encryption_key, mac_key_seed };
+            let rehash_key_seeds = decrypted[64..]
+                .chunks_exact(32)
+                .map(|chunk| {
+                    let mut seed = Box::new([0u8; 32]);
+                    seed.copy_from_slice(chunk);
+                    seed
+                })
+                .collect();
+
+            let keys = Keys { encryption_key, mac_key_seed, rehash_key_seeds };
 
             decrypted.zeroize();
 
@@ -326,6 +343,111 @@ impl StoreCipher {
         Self::import_helper(key, encrypted)
     }
 
+    /// Create a new store cipher with a new random key to encrypt values and a
+    /// new random key to hash keys.
+    ///
+    /// This can be used to rotate the keys of a key/value store. Hashing a key
+    /// is a one-way transformation, so the new store cipher can't hash the
+    /// original keys from scratch. Instead, it hashes the output of this store
+    /// cipher again with its new random key. This way, the hashed keys that
+    /// are in the store can be re-hashed without knowing the original keys,
+    /// and the keys of this store cipher alone can't be used anymore to check
+    /// if a given key is in the store.
+    ///
+    /// All the values that were encrypted with this store cipher must then be
+    /// re-encrypted with the new one, for example with
+    /// [`StoreCipher::reencrypt_value_data()`], all the hashed keys must be
+    /// re-hashed with [`StoreCipher::rehash_key()`] on the new store cipher,
+    /// and the new store cipher must be exported to replace the export of this
+    /// one.
+    ///
+    /// The original key to hash keys can't be replaced, so every rotation adds
+    /// a 32 bytes seed to the store cipher and its export, and one more keyed
+    /// hash to every call to [`StoreCipher::hash_key()`]. This is meant for
+    /// rare rotations, for example after a suspected leak of the passphrase,
+    /// not to be done periodically. To get a store cipher without rehash keys,
+    /// the data must be copied to a new store that uses a new store cipher
+    /// created with [`StoreCipher::new()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # let example = || {
+    /// use matrix_sdk_store_encryption::StoreCipher;
+    /// use serde_json::{json, value::Value};
+    ///
+    /// let store_cipher = StoreCipher::new()?;
+    /// let encrypted = store_cipher.encrypt_value_typed(&json!({ "some": "data" }))?;
+    ///
+    /// let hashed_key = store_cipher.hash_key("table", b"key");
+    ///
+    /// let new_store_cipher = store_cipher.rotate_encryption_key()?;
+    /// let encrypted = store_cipher.reencrypt_value_data(encrypted, &new_store_cipher)?;
+    /// let hashed_key = new_store_cipher.rehash_key(&hashed_key);
+    ///
+    /// let decrypted: Value = new_store_cipher.decrypt_value_typed(encrypted)?;
+    /// assert_eq!(hashed_key, new_store_cipher.hash_key("table", b"key"));
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub fn rotate_encryption_key(&self) -> Result<Self, Error> {
+        let mut rng = thread_rng();
+
+        let mut encryption_key = Box::new([0u8; 32]);
+        encryption_key.try_fill(&mut rng)?;
+
+        let mut rehash_key_seed = Box::new([0u8; 32]);
+        rehash_key_seed.try_fill(&mut rng)?;
+
+        let mac_key_seed = Box::new(*self.inner.mac_key_seed());
+        let mut rehash_key_seeds: Vec<_> =
+            self.inner.rehash_key_seeds.iter().map(|seed| Box::new(**seed)).collect();
+        rehash_key_seeds.push(rehash_key_seed);
+
+        Ok(Self { inner: Keys { encryption_key, mac_key_seed, rehash_key_seeds } })
+    }
+
+    /// Hash again a key that was hashed by the store cipher that this one was
+    /// rotated from.
+    ///
+    /// The result is the same as hashing the original key with
+    /// [`StoreCipher::hash_key()`] on this store cipher, with the same table
+    /// name.
+    ///
+    /// # Arguments
+    ///
+    /// * `hashed_key` - The key hashed by the previous store cipher, the one
+    /// [`StoreCipher::rotate_encryption_key()`] was called on to create this
+    /// one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this store cipher wasn't created with
+    /// [`StoreCipher::rotate_encryption_key()`].
+    pub fn rehash_key(&self, hashed_key: &[u8]) -> [u8; 32] {
+        let seed = self.inner.rehash_key_seeds.last().expect("The store cipher was never rotated");
+
+        Keys::get_rehash_key(seed).mac(hashed_key).into()
+    }
+
+    /// Decrypt a value that was encrypted with this store cipher and encrypt
+    /// it again with the given store cipher.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The EncryptedValue that was encrypted with this store
+    /// cipher.
+    ///
+    /// * `new_cipher` - The store cipher that should be used to encrypt the
+    /// value, usually created with [`StoreCipher::rotate_encryption_key()`].
+    pub fn reencrypt_value_data(
+        &self,
+        value: EncryptedValue,
+        new_cipher: &StoreCipher,
+    ) -> Result<EncryptedValue, Error> {
+        let data = self.decrypt_value_data(value)?;
+        new_cipher.encrypt_value_data(data)
+    }
+
     /// Hash a key before it is inserted into the key/value store.
     ///
     /// This prevents the key names from leaking to parties which do not have
@@ -365,7 +487,10 @@ impl StoreCipher {
     pub fn hash_key(&self, table_name: &str, key: &[u8]) -> [u8; 32] {
         let mac_key = self.inner.get_mac_key_for_table(table_name);
 
-        mac_key.mac(key).into()
+        // Hash the key again for every time the store cipher was rotated.
+        self.inner.rehash_key_seeds.iter().fold(mac_key.mac(key).into(), |hashed_key, seed| {
+            Keys::get_rehash_key(seed).mac(&hashed_key).into()
+        })
     }
 
     /// Encrypt a value before it is inserted into the key/value store.
@@ -627,6 +752,9 @@ pub struct EncryptedValue {
 struct Keys {
     encryption_key: Box<[u8; 32]>,
     mac_key_seed: Box<MacKeySeed>,
+    /// The seeds of the keys used to hash the hashed keys again, one for every
+    /// time the store cipher was rotated, from the oldest to the newest.
+    rehash_key_seeds: Vec<Box<MacKeySeed>>,
 }
 
 impl Keys {
@@ -639,7 +767,7 @@ impl Keys {
         encryption_key.try_fill(&mut rng)?;
         mac_key_seed.try_fill(&mut rng)?;
 
-        Ok(Self { encryption_key, mac_key_seed })
+        Ok(Self { encryption_key, mac_key_seed, rehash_key_seeds: Vec::new() })
     }
 
     fn encryption_key(&self) -> &ChachaKey {
@@ -661,6 +789,17 @@ impl Keys {
         key
     }
 
+    fn get_rehash_key(seed: &MacKeySeed) -> MacKey {
+        let mut key = MacKey(Box::new([0u8; 32]));
+        let mut output = derive_key(REHASH_KEY_CONTEXT, seed);
+
+        key.0.copy_from_slice(&output);
+
+        output.zeroize();
+
+        key
+    }
+
     fn get_nonce() -> Result<[u8; XNONCE_SIZE], RandomError> {
         let mut nonce = [0u8; XNONCE_SIZE];
         let mut rng = thread_rng();
@@ -845,4 +984,53 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn rotating_encryption_key() -> Result<(), Error> {
+        let value = json!({
+            "some": "data"
+        });
+
+        let store_cipher = StoreCipher::new()?;
+        let encrypted = store_cipher.encrypt_value_typed(&value)?;
+
+        let new_store_cipher = store_cipher.rotate_encryption_key()?;
+
+        assert_ne!(store_cipher.inner.encryption_key, new_store_cipher.inner.encryption_key);
+
+        let hashed_key = store_cipher.hash_key("table", b"key");
+        assert_ne!(hashed_key, new_store_cipher.hash_key("table", b"key"));
+        assert_eq!(
+            new_store_cipher.rehash_key(&hashed_key),
+            new_store_cipher.hash_key("table", b"key")
+        );
+
+        // The keys are hashed again after every rotation, and the rehash keys
+        // are exported.
+        let newest_store_cipher = new_store_cipher.rotate_encryption_key()?;
+        let hashed_key = new_store_cipher.hash_key("table", b"key");
+        assert_eq!(
+            newest_store_cipher.rehash_key(&hashed_key),
+            newest_store_cipher.hash_key("table", b"key")
+        );
+
+        let export = newest_store_cipher._insecure_export_fast_for_testing("passphrase")?;
+        let imported = StoreCipher::import("passphrase", &export)?;
+        assert_eq!(
+            imported.hash_key("table", b"key"),
+            newest_store_cipher.hash_key("table", b"key")
+        );
+
+        let encrypted = store_cipher.reencrypt_value_data(encrypted, &new_store_cipher)?;
+
+        let decrypted: Value = new_store_cipher.decrypt_value_typed(encrypted)?;
+        assert_eq!(value, decrypted);
+
+        let encrypted = store_cipher.encrypt_value_typed(&value)?;
+        new_store_cipher
+            .decrypt_value_typed::<Value>(encrypted)
+            .expect_err("The new store cipher can't decrypt values of the old one");
+
+        Ok(())
+    }
 }
@@ -17,7 +17,7 @@
 use matrix_sdk_base::{
     media::{MediaFormat, MediaRequest},
     store::StateStoreExt,
-    StateStoreDataKey, StateStoreDataValue,
+    StateChanges, StateStoreDataKey, StateStoreDataValue,
 };
 use mime::Mime;
 use ruma::{
@@ -48,7 +48,10 @@ use ruma::{
 use serde::Deserialize;
 use tracing::error;
 
-use crate::{config::RequestConfig, Client, Error, HttpError, Result};
+use crate::{
+    config::RequestConfig, notification_settings::NotificationSettings, Client, Error, HttpError,
+    Result, ServerFeature,
+};
 
 /// A high-level API to manage the client owner's account.
 ///
@@ -321,6 +324,8 @@ impl Account {
         new_password: &str,
         auth_data: Option<AuthData>,
     ) -> Result<change_password::v3::Response> {
+        self.client.ensure_server_supports(ServerFeature::ChangePassword).await?;
+
         let request = assign!(change_password::v3::Request::new(new_password.to_owned()), {
             auth: auth_data,
         });
@@ -827,14 +832,34 @@ impl Account {
         Ok(ignored_user_list)
     }
 
-    /// Get the current push rules.
+    /// Get the notification settings of the account.
     ///
-    /// If no push rules event was found, or it fails to deserialize, a ruleset
-    /// with the server-default push rules is returned.
+    /// The returned [`NotificationSettings`] is kept up to date with the push
+    /// rules received during sync.
     ///
-    /// Panics if called when the client is not logged in.
-    pub(crate) async fn push_rules(&self) -> Result<Ruleset> {
-        Ok(self
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::{notification_settings::RoomNotificationMode, Client};
+    /// # use ruma::room_id;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let settings = client.account().notification_settings().await?;
+    ///
+    /// let room_id = room_id!("!test:localhost");
+    /// settings.set_room_notification_mode(room_id, RoomNotificationMode::Mute).await?;
+    /// settings.add_keyword("matrix".to_owned()).await?;
+    /// # anyhow::Ok(()) });
+    /// ```
+    pub async fn notification_settings(&self) -> Result<NotificationSettings> {
+        let user_id = self.client.user_id().ok_or(Error::AuthenticationRequired)?;
+
+        // Get the ruleset of the server, the rules that it doesn't support are
+        // added by `NotificationSettings`.
+        let ruleset = self
             .account_data::<PushRulesEventContent>()
             .await?
             .and_then(|r| match r.deserialize() {
@@ -844,11 +869,20 @@ impl Account {
                     None
                 }
             })
-            .unwrap_or_else(|| {
-                Ruleset::server_default(
-                    self.client.user_id().expect("The client should be logged in"),
-                )
-            }))
+            .unwrap_or_else(|| Ruleset::server_default(user_id));
+
+        Ok(NotificationSettings::new(self.client.clone(), ruleset))
+    }
+
+    /// Get the current push rules.
+    ///
+    /// If no push rules event was found, or it fails to deserialize, a ruleset
+    /// with the server-default push rules is returned.
+    ///
+    /// The server-default rules for intentional mentions are added if they
+    /// are missing.
+    pub(crate) async fn push_rules(&self) -> Result<Ruleset> {
+        Ok(self.client.base_client().get_push_rules(&StateChanges::default()).await?)
     }
 }
 
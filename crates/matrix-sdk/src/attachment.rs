@@ -16,6 +16,8 @@
 
 #[cfg(feature = "image-proc")]
 use std::io::{BufRead, Cursor, Seek};
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[cfg(feature = "image-proc")]
@@ -173,6 +175,17 @@ pub struct Thumbnail {
     pub info: Option<BaseThumbnailInfo>,
 }
 
+/// The data of an attachment to upload.
+#[derive(Debug)]
+pub(crate) enum AttachmentData {
+    /// The raw bytes of the attachment.
+    Bytes(Vec<u8>),
+    /// The path of a file containing the attachment, that is streamed to the
+    /// server without buffering it in memory.
+    #[cfg(not(target_arch = "wasm32"))]
+    File(PathBuf),
+}
+
 /// Configuration for sending an attachment.
 #[derive(Debug)]
 pub struct AttachmentConfig {
@@ -13,9 +13,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fmt, sync::Arc};
+use std::{
+    fmt,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 
-use matrix_sdk_base::{store::StoreConfig, BaseClient};
+use matrix_sdk_base::{media::MediaRetentionPolicy, store::StoreConfig, BaseClient};
 use ruma::{
     api::{client::discovery::discover_homeserver, error::FromHttpResponseError, MatrixVersion},
     OwnedServerName, ServerName,
@@ -25,7 +29,7 @@ use tokio::sync::{broadcast, Mutex, OnceCell, RwLock};
 use tracing::{debug, field::debug, instrument, Span};
 use url::Url;
 
-use super::{Client, ClientInner};
+use super::{server_info::DEFAULT_SERVER_INFO_TTL, Client, ClientInner};
 use crate::{
     config::RequestConfig,
     error::RumaApiError,
@@ -81,6 +85,8 @@ pub struct ClientBuilder {
     respect_login_well_known: bool,
     appservice_mode: bool,
     server_versions: Option<Box<[MatrixVersion]>>,
+    server_info_ttl: Duration,
+    media_retention_policy: MediaRetentionPolicy,
     handle_refresh_tokens: bool,
 }
 
@@ -94,6 +100,8 @@ impl ClientBuilder {
             respect_login_well_known: true,
             appservice_mode: false,
             server_versions: None,
+            server_info_ttl: DEFAULT_SERVER_INFO_TTL,
+            media_retention_policy: MediaRetentionPolicy::default(),
             handle_refresh_tokens: false,
         }
     }
@@ -268,6 +276,22 @@ impl ClientBuilder {
         self
     }
 
+    /// All outgoing http requests that assert the identity of the user will
+    /// also have a GET query key-value appended with the `device_id` from the
+    /// `Session`, so the requests are made as this device of the user. This
+    /// is called [device masquerading] in MSC3202.
+    ///
+    /// This has no effect if [`assert_identity()`][Self::assert_identity] is
+    /// not set.
+    ///
+    /// [device masquerading]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    #[doc(hidden)]
+    #[cfg(feature = "appservice")]
+    pub fn assert_device_identity(mut self) -> Self {
+        self.request_config.assert_device_identity = true;
+        self
+    }
+
     /// Specify the Matrix versions supported by the homeserver manually, rather
     /// than `build()` doing it using a `get_supported_versions` request.
     ///
@@ -277,6 +301,26 @@ impl ClientBuilder {
         self
     }
 
+    /// Set how long the information returned by [`Client::server_info()`] is
+    /// cached before it is fetched again.
+    ///
+    /// Defaults to one hour.
+    pub fn server_info_ttl(mut self, ttl: Duration) -> Self {
+        self.server_info_ttl = ttl;
+        self
+    }
+
+    /// Set the policy of the media cache.
+    ///
+    /// Defaults to [`MediaRetentionPolicy::default()`]. See
+    /// [`Media::clean_cache()`] for more details.
+    ///
+    /// [`Media::clean_cache()`]: crate::Media::clean_cache
+    pub fn media_retention_policy(mut self, policy: MediaRetentionPolicy) -> Self {
+        self.media_retention_policy = policy;
+        self
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     fn http_settings(&mut self) -> &mut HttpSettings {
         self.http_cfg.get_or_insert_with(Default::default).settings()
@@ -407,9 +451,16 @@ impl ClientBuilder {
             authentication_issuer,
             #[cfg(feature = "experimental-sliding-sync")]
             sliding_sync_proxy,
+            #[cfg(feature = "experimental-sliding-sync")]
+            sliding_syncs: Default::default(),
             http_client,
             base_client,
             server_versions: OnceCell::new_with(self.server_versions),
+            server_info: Default::default(),
+            server_info_fetch_lock: Default::default(),
+            server_info_ttl: self.server_info_ttl,
+            media_retention_policy: StdMutex::new(self.media_retention_policy),
+            media_cache_size: Default::default(),
             #[cfg(feature = "e2e-encryption")]
             group_session_locks: Default::default(),
             #[cfg(feature = "e2e-encryption")]
@@ -421,6 +472,8 @@ impl ClientBuilder {
             notification_handlers: Default::default(),
             sync_gap_broadcast_txs: Default::default(),
             appservice_mode: self.appservice_mode,
+            #[cfg(feature = "appservice")]
+            transaction_lock: Default::default(),
             respect_login_well_known: self.respect_login_well_known,
             sync_beat: event_listener::Event::new(),
             handle_refresh_tokens: self.handle_refresh_tokens,
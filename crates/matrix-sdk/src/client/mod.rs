@@ -20,6 +20,7 @@ use std::{
     future::Future,
     pin::Pin,
     sync::{Arc, Mutex as StdMutex},
+    time::Duration,
 };
 
 use dashmap::DashMap;
@@ -27,12 +28,10 @@ use eyeball::{unique::Observable, Subscriber};
 use futures_core::Stream;
 use futures_util::StreamExt;
 use matrix_sdk_base::{
-    store::DynStateStore, BaseClient, RoomState, SendOutsideWasm, Session, SessionMeta,
-    SessionTokens, SyncOutsideWasm,
+    media::MediaRetentionPolicy, store::DynStateStore, BaseClient, RoomState, SendOutsideWasm,
+    Session, SessionMeta, SessionTokens, SyncOutsideWasm,
 };
 use matrix_sdk_common::instant::Instant;
-#[cfg(feature = "appservice")]
-use ruma::TransactionId;
 use ruma::{
     api::{
         client::{
@@ -65,6 +64,8 @@ use ruma::{
     DeviceId, OwnedDeviceId, OwnedRoomId, OwnedServerName, RoomAliasId, RoomId, RoomOrAliasId,
     ServerName, UInt, UserId,
 };
+#[cfg(feature = "appservice")]
+use ruma::{MilliSecondsSinceUnixEpoch, TransactionId};
 use serde::de::DeserializeOwned;
 use tokio::sync::{broadcast, Mutex, OnceCell, RwLock, RwLockReadGuard};
 use tracing::{debug, error, info, instrument, trace, Instrument, Span};
@@ -72,26 +73,34 @@ use url::Url;
 
 #[cfg(feature = "e2e-encryption")]
 use crate::encryption::Encryption;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::http_client::ByteStream;
 use crate::{
-    config::RequestConfig,
+    config::{RequestConfig, SyncFilterProfile},
     error::{HttpError, HttpResult},
     event_handler::{
         EventHandler, EventHandlerDropGuard, EventHandlerHandle, EventHandlerStore, SyncEvent,
     },
     http_client::HttpClient,
     room,
-    sync::SyncResponse,
+    sync::{SyncHandle, SyncResponse},
     Account, Error, Media, RefreshTokenError, Result, RumaApiError,
 };
 
 mod builder;
 mod login_builder;
+mod server_info;
+#[cfg(feature = "appservice")]
+mod transaction_ids;
 
 #[cfg(feature = "sso-login")]
 pub use self::login_builder::SsoLoginBuilder;
+#[cfg(feature = "appservice")]
+use self::transaction_ids::TransactionIds;
 pub use self::{
     builder::{ClientBuildError, ClientBuilder},
     login_builder::LoginBuilder,
+    server_info::{ServerFeature, ServerInfo},
 };
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -143,12 +152,27 @@ pub(crate) struct ClientInner {
     /// The sliding sync proxy that is trusted by the homeserver.
     #[cfg(feature = "experimental-sliding-sync")]
     sliding_sync_proxy: Option<RwLock<Url>>,
+    /// The sliding syncs that were built with this client and are still
+    /// alive.
+    #[cfg(feature = "experimental-sliding-sync")]
+    pub(crate) sliding_syncs: StdMutex<Vec<crate::sliding_sync::WeakSlidingSync>>,
     /// The underlying HTTP client.
     http_client: HttpClient,
     /// User session data.
     base_client: BaseClient,
     /// The Matrix versions the server supports (well-known ones only)
     server_versions: OnceCell<Box<[MatrixVersion]>>,
+    /// What the server supports, see [`Client::server_info`].
+    server_info: StdMutex<server_info::ServerInfoCache>,
+    /// Lock making sure the server info is only fetched once at a time.
+    server_info_fetch_lock: Mutex<()>,
+    /// How long the cached `server_info` is used before being refreshed.
+    server_info_ttl: Duration,
+    /// The policy of the media cache, see [`Media::clean_cache`].
+    pub(crate) media_retention_policy: StdMutex<MediaRetentionPolicy>,
+    /// The total size of the media cache, if it is known, to only trim the
+    /// cache when it grows over the maximum size of the policy.
+    pub(crate) media_cache_size: StdMutex<Option<usize>>,
     /// Locks making sure we only have one group session sharing request in
     /// flight per room.
     #[cfg(feature = "e2e-encryption")]
@@ -169,6 +193,10 @@ pub(crate) struct ClientInner {
     /// This is low-level functionality. For an high-level API check the
     /// `matrix_sdk_appservice` crate.
     appservice_mode: bool,
+    /// Lock making sure we're only processing one appservice transaction at
+    /// a time.
+    #[cfg(feature = "appservice")]
+    pub(crate) transaction_lock: Mutex<()>,
     /// Whether the client should update its homeserver URL with the discovery
     /// information present in the login response.
     respect_login_well_known: bool,
@@ -267,11 +295,18 @@ impl Client {
     /// # Arguments
     ///
     /// * `transaction_id` - The id of the transaction, used to guard against
-    ///   the same transaction being sent twice. This guarding currently isn't
-    ///   implemented.
+    ///   the same transaction being sent twice. The ids of the last 1000
+    ///   transactions processed in the last 24 hours are remembered, and a
+    ///   transaction is only remembered once it was processed successfully.
     /// * `sync_response` - The sync response converted from a transaction
     ///   received from the homeserver.
     ///
+    /// If the `e2e-encryption` feature is enabled, the to-device events, the
+    /// device list changes and the one-time key counts of the sync response
+    /// are handled by the crypto machine of this client, and its outgoing
+    /// requests, like key uploads, are sent afterwards if the client has a
+    /// device.
+    ///
     /// [transaction]: https://matrix.org/docs/spec/application_service/r0.1.2#put-matrix-app-v1-transactions-txnid
     #[cfg(feature = "appservice")]
     pub async fn receive_transaction(
@@ -279,29 +314,39 @@ impl Client {
         transaction_id: &TransactionId,
         sync_response: sync_events::v3::Response,
     ) -> Result<()> {
-        const TXN_ID_KEY: &[u8] = b"appservice.txn_id";
-
-        let store = self.store();
-        let store_tokens = store.get_custom_value(TXN_ID_KEY).await?;
-        let mut txn_id_bytes = transaction_id.as_bytes().to_vec();
-        if let Some(mut store_tokens) = store_tokens {
-            // The data is separated by a NULL byte.
-            let mut store_tokens_split = store_tokens.split(|x| *x == b'\0');
-            if store_tokens_split.any(|x| x == transaction_id.as_bytes()) {
-                // We already encountered this transaction id before, so we exit early instead
-                // of processing further.
-                //
-                // Spec: https://spec.matrix.org/v1.3/application-service-api/#pushing-events
-                return Ok(());
-            }
-            store_tokens.push(b'\0');
-            store_tokens.append(&mut txn_id_bytes);
-            self.store().set_custom_value(TXN_ID_KEY, store_tokens).await?;
-        } else {
-            self.store().set_custom_value(TXN_ID_KEY, txn_id_bytes).await?;
+        let _guard = self.inner.transaction_lock.lock().await;
+
+        let transaction_ids = TransactionIds::new(self.store());
+        if transaction_ids.contains(transaction_id, MilliSecondsSinceUnixEpoch::now()).await? {
+            // We already encountered this transaction id before, so we exit
+            // early instead of processing further.
+            //
+            // Spec: https://spec.matrix.org/v1.3/application-service-api/#pushing-events
+            debug!(%transaction_id, "Skipping transaction that was already processed");
+            return Ok(());
         }
+
         self.process_sync(sync_response).await?;
 
+        // The transaction might have contained to-device events, device list
+        // changes or one-time key counts for the device of this client, send
+        // out the requests of the crypto machine like a sync would. A client
+        // that asserts the identity of a user without asserting a device
+        // doesn't have a device to send them for.
+        #[cfg(feature = "e2e-encryption")]
+        {
+            let config = self.request_config();
+            if !config.assert_identity || config.assert_device_identity {
+                if let Err(e) = self.send_outgoing_requests().await {
+                    error!(error = ?e, "Error while sending outgoing E2EE requests");
+                }
+            }
+        }
+
+        // Only remember the transaction once it was fully processed, so it is
+        // processed again if the homeserver retries it after a failure.
+        transaction_ids.insert(transaction_id, MilliSecondsSinceUnixEpoch::now()).await?;
+
         Ok(())
     }
 
@@ -1425,7 +1470,7 @@ impl Client {
                     None,
                     self.homeserver().await.to_string(),
                     self.access_token().as_deref(),
-                    self.user_id(),
+                    self.session_meta(),
                     self.server_versions().await?,
                 )
                 .await;
@@ -1579,6 +1624,25 @@ impl Client {
         }
     }
 
+    /// Get or upload the sync filter described by the given profile.
+    ///
+    /// This is the same as [`get_or_upload_filter()`][Self::get_or_upload_filter]
+    /// with the definition of the profile, except that a modified profile
+    /// is uploaded again instead of reusing the filter of the previous
+    /// definition.
+    ///
+    /// Returns the filter ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The profile describing the filter.
+    pub async fn get_or_upload_filter_profile(
+        &self,
+        profile: &SyncFilterProfile,
+    ) -> Result<String> {
+        self.get_or_upload_filter(&profile.store_key(), profile.definition().clone()).await
+    }
+
     /// Join a room by `RoomId`.
     ///
     /// Returns a `join_room_by_id::Response` consisting of the
@@ -1695,6 +1759,10 @@ impl Client {
     /// # });
     /// ```
     pub async fn create_room(&self, request: create_room::v3::Request) -> Result<room::Joined> {
+        if let Some(room_version) = &request.room_version {
+            self.ensure_server_supports(ServerFeature::RoomVersion(room_version.clone())).await?;
+        }
+
         let invite = request.invite.clone();
         let is_direct_room = request.is_direct;
         let response = self.send(request, None).await?;
@@ -1907,7 +1975,7 @@ impl Client {
                 config,
                 homeserver,
                 self.access_token().as_deref(),
-                self.user_id(),
+                self.session_meta(),
                 self.server_versions().await?,
             )
             .await;
@@ -1926,6 +1994,67 @@ impl Client {
         response
     }
 
+    /// Send the given request and receive the body of the response as a
+    /// stream of bytes.
+    ///
+    /// The `headers` are added to the request. Contrary to [`Client::send()`],
+    /// the request is not retried.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) async fn send_streaming<Request>(
+        &self,
+        request: Request,
+        headers: http::HeaderMap,
+        config: Option<RequestConfig>,
+    ) -> HttpResult<http::Response<ByteStream>>
+    where
+        Request: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.inner
+            .http_client
+            .send_streaming(
+                request,
+                headers,
+                config,
+                self.homeserver().await.to_string(),
+                self.access_token().as_deref(),
+                self.session_meta(),
+                self.server_versions().await?,
+            )
+            .await
+    }
+
+    /// Send the given request with the given stream of bytes as its body.
+    ///
+    /// The `headers` are added to the request. Contrary to [`Client::send()`],
+    /// the request is not retried.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) async fn send_with_body_stream<Request>(
+        &self,
+        request: Request,
+        body: ByteStream,
+        headers: http::HeaderMap,
+        config: Option<RequestConfig>,
+    ) -> HttpResult<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.inner
+            .http_client
+            .send_with_body_stream(
+                request,
+                body,
+                headers,
+                config,
+                self.homeserver().await.to_string(),
+                self.access_token().as_deref(),
+                self.session_meta(),
+                self.server_versions().await?,
+            )
+            .await
+    }
+
     async fn request_server_versions(&self) -> HttpResult<Box<[MatrixVersion]>> {
         let server_versions: Box<[MatrixVersion]> = self
             .inner
@@ -2166,6 +2295,22 @@ impl Client {
             error!(error = ?e, "Error while sending outgoing E2EE requests");
         }
 
+        let mut sync_settings = sync_settings;
+        self.resolve_filter_profile(&mut sync_settings).await?;
+
+        let response = self.send_sync_request(sync_settings).await?;
+        self.handle_sync_request_response(response).await
+    }
+
+    /// Send a `/sync` request built from the given settings, without
+    /// processing the response.
+    ///
+    /// Dropping the returned future before it completes doesn't leave any
+    /// trace in the store, which makes it safe to race against other futures.
+    pub(crate) async fn send_sync_request(
+        &self,
+        sync_settings: crate::config::SyncSettings,
+    ) -> HttpResult<sync_events::v3::Response> {
         let request = assign!(sync_events::v3::Request::new(), {
             filter: sync_settings.filter.map(|f| *f),
             since: sync_settings.token,
@@ -2178,7 +2323,17 @@ impl Client {
             request_config.timeout += timeout;
         }
 
-        let response = self.send(request, Some(request_config)).await?;
+        self.send(request, Some(request_config)).await
+    }
+
+    /// Process a response received by [`Client::send_sync_request`].
+    ///
+    /// This persists the new state, including the `next_batch` token, runs
+    /// the event handlers and sends out any outgoing E2EE requests.
+    pub(crate) async fn handle_sync_request_response(
+        &self,
+        response: sync_events::v3::Response,
+    ) -> Result<SyncResponse> {
         let next_batch = response.next_batch.clone();
         let response = self.process_sync(response).await?;
 
@@ -2490,6 +2645,60 @@ impl Client {
         }
     }
 
+    /// Start a sync loop in the background and return a handle to control it.
+    ///
+    /// Contrary to [`Client::sync`] and friends, the returned [`SyncHandle`]
+    /// allows the loop to be paused, resumed and stopped, and exposes the
+    /// state of the loop as a stream. This is useful for apps that need to
+    /// suspend syncing, e.g. when a mobile app is sent to the background.
+    ///
+    /// Errors don't end the loop, it instead retries with an exponential
+    /// backoff. The only exception is an `M_UNKNOWN_TOKEN` error, after which
+    /// the loop stops since retrying can't succeed.
+    ///
+    /// # Arguments
+    ///
+    /// * `sync_settings` - Settings for the sync call. *Note* that those
+    ///   settings will be only used for the first sync call. See the argument
+    ///   docs for [`Client::sync_once`] for more info.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// use futures::StreamExt;
+    /// use matrix_sdk::{config::SyncSettings, sync::SyncState, Client};
+    ///
+    /// let client = Client::new(homeserver).await?;
+    ///
+    /// let sync_handle = client.start_sync(SyncSettings::default());
+    /// let mut states = sync_handle.state_stream();
+    ///
+    /// while let Some(state) = states.next().await {
+    ///     if state == SyncState::Running {
+    ///         break;
+    ///     }
+    /// }
+    ///
+    /// // The app is about to be suspended.
+    /// sync_handle.pause();
+    ///
+    /// // The app is back in the foreground.
+    /// sync_handle.resume();
+    ///
+    /// // Wait for the loop to finish, the store is consistent afterwards.
+    /// sync_handle.stop().await;
+    /// # anyhow::Ok(()) });
+    /// ```
+    ///
+    /// [`SyncHandle`]: crate::sync::SyncHandle
+    pub fn start_sync(&self, sync_settings: crate::config::SyncSettings) -> SyncHandle {
+        SyncHandle::start(self.clone(), sync_settings)
+    }
+
     /// Get the current, if any, sync token of the client.
     /// This will be None if the client didn't sync at least once.
     pub(crate) async fn sync_token(&self) -> Option<String> {
@@ -2508,6 +2717,32 @@ impl Client {
         self.send(request, None).await
     }
 
+    /// Remove all the data of the account from the local stores.
+    ///
+    /// This removes the rooms, the account data, the sync token, the media
+    /// cache, the custom values, including the caches of sliding sync, and the
+    /// end-to-end encryption data, including the identity and the keys of this
+    /// device.
+    ///
+    /// This is meant to be called after a [`logout`](Self::logout) or the
+    /// deactivation of the account.
+    ///
+    /// The sync loops and the sliding syncs of the client must be stopped
+    /// before calling this, otherwise they would save new data in the stores.
+    /// The state of the client that is kept in memory, like the rooms or the
+    /// end-to-end encryption keys, is not reset, so the client must not be
+    /// used anymore afterwards, a new client must be created to log in again.
+    pub async fn wipe_local_data(&self) -> Result<()> {
+        self.store().clear().await?;
+
+        #[cfg(feature = "e2e-encryption")]
+        if let Some(machine) = self.olm_machine() {
+            machine.store().clear().await?;
+        }
+
+        Ok(())
+    }
+
     /// Subscribes a new receiver to client UnknownToken errors
     pub fn subscribe_to_unknown_token_errors(&self) -> broadcast::Receiver<UnknownToken> {
         let broadcast = &self.inner.unknown_token_error_sender;
@@ -0,0 +1,407 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use matrix_sdk_common::instant::Instant;
+use ruma::{
+    api::{
+        client::discovery::{
+            discover_homeserver, get_capabilities::Capabilities, get_supported_versions,
+        },
+        MatrixVersion,
+    },
+    RoomVersionId,
+};
+use tracing::{debug, warn};
+
+use super::Client;
+use crate::{Error, HttpResult, Result};
+
+/// The default duration after which the cached [`ServerInfo`] is refreshed.
+pub(crate) const DEFAULT_SERVER_INFO_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A feature that might or might not be supported by the homeserver.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerFeature {
+    /// A version of the Matrix specification.
+    Version(MatrixVersion),
+    /// An unstable feature advertised in the `/versions` response.
+    UnstableFeature(String),
+    /// A room version that rooms can be created with.
+    RoomVersion(RoomVersionId),
+    /// Threads, stable since Matrix 1.4 ([MSC3440]).
+    ///
+    /// [MSC3440]: https://github.com/matrix-org/matrix-spec-proposals/pull/3440
+    Threads,
+    /// Sliding sync through a proxy advertised in the well-known
+    /// configuration ([MSC3575]).
+    ///
+    /// [MSC3575]: https://github.com/matrix-org/matrix-spec-proposals/pull/3575
+    SlidingSync,
+    /// Authentication through an OpenID Connect provider ([MSC2965]).
+    ///
+    /// [MSC2965]: https://github.com/matrix-org/matrix-spec-proposals/pull/2965
+    Oidc,
+    /// Changing the password of the account.
+    ChangePassword,
+    /// Changing the display name of the account.
+    SetDisplayName,
+    /// Changing the avatar of the account.
+    SetAvatarUrl,
+}
+
+impl fmt::Display for ServerFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(version) => write!(f, "Matrix version {version:?}"),
+            Self::UnstableFeature(feature) => write!(f, "unstable feature {feature}"),
+            Self::RoomVersion(version) => write!(f, "room version {version}"),
+            Self::Threads => f.write_str("threads"),
+            Self::SlidingSync => f.write_str("sliding sync"),
+            Self::Oidc => f.write_str("OpenID Connect authentication"),
+            Self::ChangePassword => f.write_str("changing the password"),
+            Self::SetDisplayName => f.write_str("changing the display name"),
+            Self::SetAvatarUrl => f.write_str("changing the avatar"),
+        }
+    }
+}
+
+/// What the homeserver supports, as advertised by its `/versions`,
+/// `/capabilities` and well-known endpoints.
+///
+/// Get it with [`Client::server_info()`].
+#[derive(Clone, Debug)]
+pub struct ServerInfo {
+    /// The Matrix versions supported by the server, only the ones known to
+    /// this SDK are included.
+    pub versions: Box<[MatrixVersion]>,
+    /// The unstable features advertised by the server, with whether they are
+    /// enabled.
+    pub unstable_features: BTreeMap<String, bool>,
+    /// The capabilities of the server.
+    ///
+    /// This is `None` if the capabilities couldn't be fetched, e.g. because
+    /// the client isn't logged in.
+    pub capabilities: Option<Capabilities>,
+    /// The well-known client configuration of the server, if any.
+    pub well_known: Option<discover_homeserver::Response>,
+    fetched_at: Instant,
+}
+
+impl ServerInfo {
+    /// Whether the server supports the given version of the Matrix
+    /// specification.
+    pub fn supports_version(&self, version: MatrixVersion) -> bool {
+        self.versions.contains(&version)
+    }
+
+    /// Whether the server advertises the given unstable feature as enabled.
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.unstable_features.get(feature).copied().unwrap_or(false)
+    }
+
+    /// Whether rooms can be created with the given room version.
+    ///
+    /// Returns `false` if the capabilities of the server are unknown.
+    pub fn supports_room_version(&self, room_version: &RoomVersionId) -> bool {
+        self.capabilities
+            .as_ref()
+            .map_or(false, |c| c.room_versions.available.contains_key(room_version))
+    }
+
+    /// Whether the server supports threads.
+    pub fn supports_threads(&self) -> bool {
+        self.versions.iter().any(|v| *v >= MatrixVersion::V1_4)
+            || self.supports_unstable_feature("org.matrix.msc3440.stable")
+    }
+
+    /// Whether a sliding sync proxy is advertised by the server.
+    pub fn supports_sliding_sync(&self) -> bool {
+        #[cfg(feature = "experimental-sliding-sync")]
+        if self.well_known.as_ref().map_or(false, |w| w.sliding_sync_proxy.is_some()) {
+            return true;
+        }
+
+        self.supports_unstable_feature("org.matrix.msc3575")
+    }
+
+    /// Whether the server delegates authentication to an OpenID Connect
+    /// provider.
+    pub fn supports_oidc(&self) -> bool {
+        self.well_known.as_ref().map_or(false, |w| w.authentication.is_some())
+    }
+
+    /// Whether the server supports the given feature.
+    ///
+    /// Features that depend on the capabilities of the server are considered
+    /// unsupported if the capabilities are unknown, use
+    /// [`ServerInfo::is_unsupported()`] to only get a negative answer when the
+    /// server is known to not support the feature.
+    pub fn supports(&self, feature: &ServerFeature) -> bool {
+        self.check(feature).unwrap_or(false)
+    }
+
+    /// Whether the server is known to not support the given feature.
+    ///
+    /// Contrary to [`ServerInfo::supports()`], this returns `false` if the
+    /// answer depends on capabilities of the server that are unknown.
+    pub fn is_unsupported(&self, feature: &ServerFeature) -> bool {
+        !self.check(feature).unwrap_or(true)
+    }
+
+    /// Whether the server supports the given feature, or `None` if it depends
+    /// on unknown capabilities.
+    fn check(&self, feature: &ServerFeature) -> Option<bool> {
+        let supported = match feature {
+            ServerFeature::Version(version) => self.supports_version(*version),
+            ServerFeature::UnstableFeature(feature) => self.supports_unstable_feature(feature),
+            ServerFeature::RoomVersion(version) => {
+                self.capabilities.as_ref()?.room_versions.available.contains_key(version)
+            }
+            ServerFeature::Threads => self.supports_threads(),
+            ServerFeature::SlidingSync => self.supports_sliding_sync(),
+            ServerFeature::Oidc => self.supports_oidc(),
+            ServerFeature::ChangePassword => self.capabilities.as_ref()?.change_password.enabled,
+            ServerFeature::SetDisplayName => self.capabilities.as_ref()?.set_displayname.enabled,
+            ServerFeature::SetAvatarUrl => self.capabilities.as_ref()?.set_avatar_url.enabled,
+        };
+
+        Some(supported)
+    }
+
+    /// How long ago this information was fetched from the server.
+    pub fn age(&self) -> Duration {
+        self.fetched_at.elapsed()
+    }
+}
+
+/// The cached [`ServerInfo`], and when fetching it failed last.
+#[derive(Debug, Default)]
+pub(crate) struct ServerInfoCache {
+    server_info: Option<Arc<ServerInfo>>,
+    failed_at: Option<Instant>,
+}
+
+impl Client {
+    /// Get what the homeserver supports.
+    ///
+    /// The information is fetched the first time this is called and cached,
+    /// it is refreshed the next time this is called after it became older
+    /// than the duration configured with
+    /// [`ClientBuilder::server_info_ttl()`][crate::ClientBuilder::server_info_ttl].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// let client = Client::new(homeserver).await?;
+    ///
+    /// let server_info = client.server_info().await?;
+    ///
+    /// if server_info.supports_threads() {
+    ///     // Show the threads list
+    /// }
+    /// # anyhow::Ok(()) });
+    /// ```
+    pub async fn server_info(&self) -> HttpResult<Arc<ServerInfo>> {
+        if let Some(server_info) = self.fresh_server_info() {
+            return Ok(server_info);
+        }
+
+        let _guard = self.inner.server_info_fetch_lock.lock().await;
+
+        // It might have been fetched while we were waiting for the lock.
+        if let Some(server_info) = self.fresh_server_info() {
+            return Ok(server_info);
+        }
+
+        self.fetch_and_cache_server_info().await
+    }
+
+    /// Fetch what the homeserver supports again, regardless of the age of the
+    /// cached information.
+    pub async fn refresh_server_info(&self) -> HttpResult<Arc<ServerInfo>> {
+        let _guard = self.inner.server_info_fetch_lock.lock().await;
+        self.fetch_and_cache_server_info().await
+    }
+
+    /// Check that the homeserver supports the given feature.
+    ///
+    /// Returns [`Error::UnsupportedByServer`] if the homeserver is known to
+    /// not support it. If what the homeserver supports couldn't be fetched,
+    /// the feature is assumed to be supported and the request that needs it
+    /// will fail if it isn't. The failure is remembered for as long as the
+    /// server info would have been cached, so it is not fetched again for
+    /// every check.
+    ///
+    /// This doesn't wait for a fetch of the server info that is already in
+    /// progress, the outdated information is used if there is any.
+    pub async fn ensure_server_supports(&self, feature: ServerFeature) -> Result<()> {
+        match self.server_info_for_check().await {
+            Some(server_info) if server_info.is_unsupported(&feature) => {
+                Err(Error::UnsupportedByServer(feature))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the cached server info if it isn't outdated.
+    fn fresh_server_info(&self) -> Option<Arc<ServerInfo>> {
+        let cache = self.inner.server_info.lock().unwrap();
+        cache.server_info.as_ref().filter(|info| info.age() < self.inner.server_info_ttl).cloned()
+    }
+
+    /// Get the server info to check whether a feature is supported.
+    ///
+    /// It is only fetched if it isn't already being fetched, and if fetching
+    /// it didn't fail recently.
+    async fn server_info_for_check(&self) -> Option<Arc<ServerInfo>> {
+        let outdated = {
+            let cache = self.inner.server_info.lock().unwrap();
+            let ttl = self.inner.server_info_ttl;
+
+            match &cache.server_info {
+                Some(server_info) if server_info.age() < ttl => return Some(server_info.clone()),
+                _ if cache.failed_at.map_or(false, |failed_at| failed_at.elapsed() < ttl) => {
+                    return cache.server_info.clone();
+                }
+                _ => cache.server_info.clone(),
+            }
+        };
+
+        let Ok(_guard) = self.inner.server_info_fetch_lock.try_lock() else {
+            return outdated;
+        };
+
+        match self.fetch_and_cache_server_info().await {
+            Ok(server_info) => Some(server_info),
+            Err(error) => {
+                warn!("Couldn't fetch the server info, assuming features are supported: {error}");
+                outdated
+            }
+        }
+    }
+
+    /// Fetch the server info and cache the result.
+    ///
+    /// Must be called with the `server_info_fetch_lock` held.
+    async fn fetch_and_cache_server_info(&self) -> HttpResult<Arc<ServerInfo>> {
+        let result = self.fetch_server_info().await.map(Arc::new);
+
+        let mut cache = self.inner.server_info.lock().unwrap();
+        match &result {
+            Ok(server_info) => {
+                cache.server_info = Some(server_info.clone());
+                cache.failed_at = None;
+            }
+            Err(_) => cache.failed_at = Some(Instant::now()),
+        }
+
+        result
+    }
+
+    async fn fetch_server_info(&self) -> HttpResult<ServerInfo> {
+        debug!("Fetching the server info");
+
+        let homeserver = self.homeserver().await;
+
+        let response = self
+            .inner
+            .http_client
+            .send(
+                get_supported_versions::Request::new(),
+                None,
+                homeserver.to_string(),
+                None,
+                None,
+                &[MatrixVersion::V1_0],
+            )
+            .await;
+
+        // Respect the versions set with `ClientBuilder::server_versions()`, the
+        // unstable features are only fetched for information in that case.
+        let (versions, unstable_features) = match self.inner.server_versions.get() {
+            Some(versions) => {
+                let unstable_features = match response {
+                    Ok(response) => response.unstable_features,
+                    Err(error) => {
+                        debug!("Couldn't fetch the unstable features: {error}");
+                        BTreeMap::new()
+                    }
+                };
+                (versions.clone(), unstable_features)
+            }
+            None => {
+                let response = response?;
+                (response.known_versions().collect(), response.unstable_features)
+            }
+        };
+
+        let capabilities = if self.logged_in() {
+            match self.get_capabilities().await {
+                Ok(capabilities) => Some(capabilities),
+                Err(error) => {
+                    warn!("Couldn't fetch the server capabilities: {error}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // The well-known configuration lives on the server name, which is the
+        // one of our user ID if we know it.
+        let well_known_server = match self.user_id() {
+            Some(user_id) => format!("https://{}", user_id.server_name()),
+            None => {
+                let mut url = homeserver.clone();
+                url.set_path("");
+                url.to_string()
+            }
+        };
+        let well_known = match self
+            .inner
+            .http_client
+            .send(
+                discover_homeserver::Request::new(),
+                None,
+                well_known_server,
+                None,
+                None,
+                &[MatrixVersion::V1_0],
+            )
+            .await
+        {
+            Ok(well_known) => Some(well_known),
+            Err(error) => {
+                debug!("Couldn't fetch the well-known configuration: {error}");
+                None
+            }
+        };
+
+        Ok(ServerInfo {
+            versions,
+            unstable_features,
+            capabilities,
+            well_known,
+            fetched_at: Instant::now(),
+        })
+    }
+}
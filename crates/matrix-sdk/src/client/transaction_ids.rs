@@ -0,0 +1,329 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deduplication of the transactions pushed by the homeserver to an
+//! application service.
+
+use std::time::Duration;
+
+use matrix_sdk_base::store::DynStateStore;
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedTransactionId, TransactionId};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// The maximum number of transaction IDs that are remembered.
+const MAX_TRANSACTION_IDS: usize = 1000;
+
+/// How long a transaction ID is remembered.
+const TRANSACTION_ID_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The key of the NUL-separated list of transaction IDs used by earlier
+/// versions of the SDK.
+const LEGACY_TXN_IDS_KEY: &[u8] = b"appservice.txn_id";
+
+/// The key of the [`IndexBounds`].
+const TXN_ID_INDEX_KEY: &[u8] = b"appservice.txn_id_index";
+
+/// The prefix of the key of every position of the index.
+const TXN_ID_INDEX_PREFIX: &[u8] = b"appservice.txn_id_index.";
+
+/// The prefix of the key of every remembered transaction ID.
+const TXN_ID_PREFIX: &[u8] = b"appservice.txn_id.";
+
+/// The positions of the oldest transaction ID in the index, and of the next
+/// one that will be added.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IndexBounds {
+    first: u64,
+    next: u64,
+}
+
+/// A remembered transaction ID.
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionIdEntry {
+    /// The position of the last occurrence of the transaction ID in the index.
+    position: u64,
+    /// When the transaction was processed.
+    processed_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// A bounded window of the transaction IDs that were already processed.
+///
+/// Every transaction ID is stored under its own key so that looking one up
+/// doesn't need to load the whole window. The order in which they were
+/// processed is kept in an append-only index, one key per position, so adding
+/// a transaction ID only writes a few small values.
+///
+/// The store has no transactions, so the bounds of the index are saved before
+/// the new position is written: if the process stops in between, the position
+/// is missing and skipped when it is evicted, and the transaction ID is not
+/// remembered, like if it had not been processed.
+#[derive(Debug)]
+pub(crate) struct TransactionIds<'a> {
+    store: &'a DynStateStore,
+}
+
+impl<'a> TransactionIds<'a> {
+    pub(crate) fn new(store: &'a DynStateStore) -> Self {
+        Self { store }
+    }
+
+    /// Whether the given transaction ID was already processed and hasn't
+    /// expired yet.
+    pub(crate) async fn contains(
+        &self,
+        txn_id: &TransactionId,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> Result<bool> {
+        self.migrate_legacy_ids(now).await?;
+
+        let Some(entry) = self.load_entry(txn_id).await? else {
+            return Ok(false);
+        };
+
+        Ok(!is_expired(entry.processed_at, now))
+    }
+
+    /// Remember that the given transaction ID was processed, and forget the
+    /// transaction IDs that fall out of the window.
+    pub(crate) async fn insert(
+        &self,
+        txn_id: &TransactionId,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let mut bounds = self.load_bounds().await?;
+        let position = bounds.next;
+        bounds.next += 1;
+
+        self.evict(&mut bounds, position, now).await?;
+        self.save_bounds(&bounds).await?;
+
+        self.push(txn_id, position, now).await
+    }
+
+    async fn load_bounds(&self) -> Result<IndexBounds> {
+        Ok(match self.store.get_custom_value(TXN_ID_INDEX_KEY).await? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => IndexBounds::default(),
+        })
+    }
+
+    async fn save_bounds(&self, bounds: &IndexBounds) -> Result<()> {
+        self.store.set_custom_value(TXN_ID_INDEX_KEY, serde_json::to_vec(bounds)?).await?;
+        Ok(())
+    }
+
+    async fn load_entry(&self, txn_id: &TransactionId) -> Result<Option<TransactionIdEntry>> {
+        Ok(match self.store.get_custom_value(&txn_id_key(txn_id)).await? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        })
+    }
+
+    /// Write the given transaction ID at the given position of the index.
+    async fn push(
+        &self,
+        txn_id: &TransactionId,
+        position: u64,
+        processed_at: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        self.store
+            .set_custom_value(&index_key(position), serde_json::to_vec(&(txn_id, processed_at))?)
+            .await?;
+
+        let entry = TransactionIdEntry { position, processed_at };
+        self.store.set_custom_value(&txn_id_key(txn_id), serde_json::to_vec(&entry)?).await?;
+
+        Ok(())
+    }
+
+    /// Evict the transaction IDs before the given position of the index that
+    /// are expired or over the limit.
+    async fn evict(
+        &self,
+        bounds: &mut IndexBounds,
+        until: u64,
+        now: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        while bounds.first < until {
+            let key = index_key(bounds.first);
+            let over_limit = bounds.next - bounds.first > MAX_TRANSACTION_IDS as u64;
+
+            if let Some(value) = self.store.get_custom_value(&key).await? {
+                let (txn_id, processed_at): (OwnedTransactionId, MilliSecondsSinceUnixEpoch) =
+                    serde_json::from_slice(&value)?;
+
+                if !over_limit && !is_expired(processed_at, now) {
+                    break;
+                }
+
+                // A transaction ID that was received again after being evicted
+                // is in the index twice, only its last occurrence owns the
+                // stored entry.
+                let entry = self.load_entry(&txn_id).await?;
+                if entry.map_or(false, |entry| entry.position == bounds.first) {
+                    self.store.remove_custom_value(&txn_id_key(&txn_id)).await?;
+                }
+
+                self.store.remove_custom_value(&key).await?;
+            }
+
+            bounds.first += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Move the transaction IDs stored by earlier versions of the SDK to the
+    /// window.
+    ///
+    /// They are considered processed now, since the time at which they were
+    /// processed wasn't stored.
+    async fn migrate_legacy_ids(&self, now: MilliSecondsSinceUnixEpoch) -> Result<()> {
+        let Some(legacy_ids) = self.store.get_custom_value(LEGACY_TXN_IDS_KEY).await? else {
+            return Ok(());
+        };
+
+        let mut bounds = self.load_bounds().await?;
+        // The IDs are separated by a NUL byte, oldest first.
+        let legacy_ids: Vec<_> = legacy_ids
+            .split(|b| *b == b'\0')
+            .filter(|id| !id.is_empty())
+            .filter_map(|id| std::str::from_utf8(id).ok())
+            .collect();
+        let skip = legacy_ids.len().saturating_sub(MAX_TRANSACTION_IDS);
+
+        // If the migration is interrupted, it is done again at the same
+        // positions, since the bounds are only saved at the end.
+        for txn_id in legacy_ids.into_iter().skip(skip) {
+            self.push(txn_id.into(), bounds.next, now).await?;
+            bounds.next += 1;
+        }
+
+        let until = bounds.next;
+        self.evict(&mut bounds, until, now).await?;
+        self.save_bounds(&bounds).await?;
+        self.store.remove_custom_value(LEGACY_TXN_IDS_KEY).await?;
+
+        Ok(())
+    }
+}
+
+fn txn_id_key(txn_id: &TransactionId) -> Vec<u8> {
+    [TXN_ID_PREFIX, txn_id.as_bytes()].concat()
+}
+
+fn index_key(position: u64) -> Vec<u8> {
+    [TXN_ID_INDEX_PREFIX, position.to_string().as_bytes()].concat()
+}
+
+fn is_expired(processed_at: MilliSecondsSinceUnixEpoch, now: MilliSecondsSinceUnixEpoch) -> bool {
+    let age = u64::from(now.get()).saturating_sub(u64::from(processed_at.get()));
+    u128::from(age) > TRANSACTION_ID_LIFETIME.as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk_test::async_test;
+    use ruma::{MilliSecondsSinceUnixEpoch, OwnedTransactionId, TransactionId, UInt};
+
+    use super::{
+        index_key, IndexBounds, TransactionIds, LEGACY_TXN_IDS_KEY, MAX_TRANSACTION_IDS,
+        TXN_ID_INDEX_KEY,
+    };
+    use crate::test_utils::logged_in_client;
+
+    fn ts(millis: u64) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(UInt::new(millis).unwrap())
+    }
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    #[async_test]
+    async fn transaction_ids_expire() {
+        let client = logged_in_client(None).await;
+        let txn_ids = TransactionIds::new(client.store());
+        let txn_id: &TransactionId = "txn1".into();
+
+        assert!(!txn_ids.contains(txn_id, ts(0)).await.unwrap());
+        txn_ids.insert(txn_id, ts(0)).await.unwrap();
+        assert!(txn_ids.contains(txn_id, ts(DAY)).await.unwrap());
+        assert!(!txn_ids.contains(txn_id, ts(DAY + 1)).await.unwrap());
+
+        // Expired IDs are removed from the store on the next insertion.
+        txn_ids.insert("txn2".into(), ts(DAY + 1)).await.unwrap();
+        assert!(client
+            .store()
+            .get_custom_value(b"appservice.txn_id.txn1")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_test]
+    async fn transaction_ids_are_bounded() {
+        let client = logged_in_client(None).await;
+        let txn_ids = TransactionIds::new(client.store());
+
+        for i in 0..=MAX_TRANSACTION_IDS {
+            let txn_id: OwnedTransactionId = format!("txn{i}").into();
+            txn_ids.insert(&txn_id, ts(0)).await.unwrap();
+        }
+
+        assert!(!txn_ids.contains("txn0".into(), ts(0)).await.unwrap());
+        assert!(txn_ids.contains("txn1".into(), ts(0)).await.unwrap());
+        let last: OwnedTransactionId = format!("txn{MAX_TRANSACTION_IDS}").into();
+        assert!(txn_ids.contains(&last, ts(0)).await.unwrap());
+    }
+
+    #[async_test]
+    async fn legacy_transaction_ids_are_migrated() {
+        let client = logged_in_client(None).await;
+        let store = client.store();
+        store.set_custom_value(LEGACY_TXN_IDS_KEY, b"txn1\0txn2".to_vec()).await.unwrap();
+
+        let txn_ids = TransactionIds::new(store);
+        let now = ts(1_000_000);
+        assert!(txn_ids.contains("txn1".into(), now).await.unwrap());
+        assert!(txn_ids.contains("txn2".into(), now).await.unwrap());
+        assert!(!txn_ids.contains("txn3".into(), now).await.unwrap());
+
+        assert!(store.get_custom_value(LEGACY_TXN_IDS_KEY).await.unwrap().is_none());
+        assert!(store.get_custom_value(TXN_ID_INDEX_KEY).await.unwrap().is_some());
+    }
+
+    #[async_test]
+    async fn interrupted_insertion_is_skipped() {
+        let client = logged_in_client(None).await;
+        let txn_ids = TransactionIds::new(client.store());
+
+        // The bounds were saved, but not the first position.
+        txn_ids.save_bounds(&IndexBounds { first: 0, next: 1 }).await.unwrap();
+
+        // The missing position is skipped.
+        txn_ids.insert("txn1".into(), ts(0)).await.unwrap();
+        assert!(txn_ids.contains("txn1".into(), ts(0)).await.unwrap());
+        let bounds = txn_ids.load_bounds().await.unwrap();
+        assert_eq!((bounds.first, bounds.next), (1, 2));
+
+        txn_ids.insert("txn2".into(), ts(DAY + 1)).await.unwrap();
+        assert!(!txn_ids.contains("txn1".into(), ts(DAY + 1)).await.unwrap());
+        assert!(txn_ids.contains("txn2".into(), ts(DAY + 1)).await.unwrap());
+
+        let bounds = txn_ids.load_bounds().await.unwrap();
+        assert_eq!((bounds.first, bounds.next), (2, 3));
+        assert!(client.store().get_custom_value(&index_key(1)).await.unwrap().is_none());
+    }
+}
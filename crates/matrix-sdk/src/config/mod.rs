@@ -16,7 +16,9 @@
 
 mod request;
 mod sync;
+mod sync_filter;
 
 pub use matrix_sdk_base::store::StoreConfig;
 pub use request::RequestConfig;
 pub use sync::SyncSettings;
+pub use sync_filter::SyncFilterProfile;
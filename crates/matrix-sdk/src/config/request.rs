@@ -46,12 +46,20 @@ pub struct RequestConfig {
     pub(crate) retry_timeout: Option<Duration>,
     pub(crate) force_auth: bool,
     pub(crate) assert_identity: bool,
+    pub(crate) assert_device_identity: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
 impl Debug for RequestConfig {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { timeout, retry_limit, retry_timeout, force_auth, assert_identity } = self;
+        let Self {
+            timeout,
+            retry_limit,
+            retry_timeout,
+            force_auth,
+            assert_identity,
+            assert_device_identity,
+        } = self;
 
         let mut res = fmt.debug_struct("RequestConfig");
         res.field("timeout", timeout)
@@ -64,6 +72,9 @@ impl Debug for RequestConfig {
         if *assert_identity {
             res.field("assert_identity", &true);
         }
+        if *assert_device_identity {
+            res.field("assert_device_identity", &true);
+        }
 
         res.finish()
     }
@@ -77,6 +88,7 @@ impl Default for RequestConfig {
             retry_timeout: Default::default(),
             force_auth: false,
             assert_identity: false,
+            assert_device_identity: false,
         }
     }
 }
@@ -17,6 +17,8 @@ use std::{fmt, time::Duration};
 use matrix_sdk_common::debug::DebugStructExt;
 use ruma::{api::client::sync::sync_events, presence::PresenceState};
 
+use super::SyncFilterProfile;
+
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Settings for a sync call.
@@ -24,6 +26,7 @@ const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 pub struct SyncSettings {
     // Filter is pretty big at 1000 bytes, box it to reduce stack size
     pub(crate) filter: Option<Box<sync_events::v3::Filter>>,
+    pub(crate) filter_profile: Option<SyncFilterProfile>,
     pub(crate) timeout: Option<Duration>,
     pub(crate) token: Option<String>,
     pub(crate) full_state: bool,
@@ -39,9 +42,10 @@ impl Default for SyncSettings {
 #[cfg(not(tarpaulin_include))]
 impl fmt::Debug for SyncSettings {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let Self { filter, timeout, token: _, full_state, set_presence } = self;
+        let Self { filter, filter_profile, timeout, token: _, full_state, set_presence } = self;
         f.debug_struct("SyncSettings")
             .maybe_field("filter", filter)
+            .maybe_field("filter_profile", filter_profile)
             .maybe_field("timeout", timeout)
             .field("full_state", full_state)
             .field("set_presence", set_presence)
@@ -55,6 +59,7 @@ impl SyncSettings {
     pub fn new() -> Self {
         Self {
             filter: None,
+            filter_profile: None,
             timeout: Some(DEFAULT_SYNC_TIMEOUT),
             token: None,
             full_state: false,
@@ -98,6 +103,24 @@ impl SyncSettings {
         self
     }
 
+    /// Set the sync filter with a [`SyncFilterProfile`].
+    ///
+    /// The filter is uploaded the first time it is used and its ID is
+    /// remembered in the state store, see
+    /// [`Client::get_or_upload_filter_profile()`]. This is ignored if a filter
+    /// is also set with [`filter()`](Self::filter).
+    ///
+    /// # Arguments
+    ///
+    /// * `profile` - The filter profile that should be used for the sync call.
+    ///
+    /// [`Client::get_or_upload_filter_profile()`]: crate::Client::get_or_upload_filter_profile
+    #[must_use]
+    pub fn filter_profile(mut self, profile: SyncFilterProfile) -> Self {
+        self.filter_profile = Some(profile);
+        self
+    }
+
     /// Should the server return the full state from the start of the timeline.
     ///
     /// This does nothing if no sync token is set.
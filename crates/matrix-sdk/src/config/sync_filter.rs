@@ -0,0 +1,184 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::{
+    api::client::filter::{FilterDefinition, LazyLoadOptions},
+    UInt,
+};
+
+/// A named sync filter, built with sensible defaults for a client.
+///
+/// Contrary to a raw [`FilterDefinition`], a profile enables [lazy-loading] of
+/// room members by default, which makes syncs much lighter in large rooms: the
+/// server only sends the member events of the senders of the events it
+/// returns.
+///
+/// Set it with [`SyncSettings::filter_profile()`]. The SDK uploads the filter
+/// the first time it's used and remembers the filter ID in the state store. A
+/// modified profile is uploaded again.
+///
+/// # Examples
+///
+/// ```
+/// use matrix_sdk::config::{SyncFilterProfile, SyncSettings};
+///
+/// let profile = SyncFilterProfile::new("app")
+///     .timeline_limit(20)
+///     .deny_timeline_types(["m.call.*"]);
+///
+/// let sync_settings = SyncSettings::new().filter_profile(profile);
+/// ```
+///
+/// [lazy-loading]: https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
+/// [`SyncSettings::filter_profile()`]: super::SyncSettings::filter_profile
+#[derive(Clone, Debug)]
+pub struct SyncFilterProfile {
+    name: String,
+    definition: FilterDefinition,
+}
+
+impl Default for SyncFilterProfile {
+    fn default() -> Self {
+        Self::new("default")
+    }
+}
+
+impl SyncFilterProfile {
+    /// Create a new profile with the given name.
+    ///
+    /// The name is used to remember the ID of the uploaded filter.
+    pub fn new(name: impl Into<String>) -> Self {
+        let mut definition = FilterDefinition::default();
+        definition.room.state.lazy_load_options =
+            LazyLoadOptions::Enabled { include_redundant_members: false };
+
+        Self { name: name.into(), definition }
+    }
+
+    /// Whether to enable lazy-loading of room members.
+    ///
+    /// Defaults to `true`.
+    pub fn lazy_load_members(mut self, enabled: bool) -> Self {
+        self.definition.room.state.lazy_load_options = if enabled {
+            LazyLoadOptions::Enabled { include_redundant_members: false }
+        } else {
+            LazyLoadOptions::Disabled
+        };
+        self
+    }
+
+    /// Set the maximum number of events to return per room in the timeline.
+    pub fn timeline_limit(mut self, limit: u32) -> Self {
+        self.definition.room.timeline.limit = Some(UInt::from(limit));
+        self
+    }
+
+    /// Only return timeline events of the given types.
+    ///
+    /// Types can end with a `*` wildcard, e.g. `m.room.*`.
+    pub fn allow_timeline_types<T: Into<String>>(
+        mut self,
+        types: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.definition.room.timeline.types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never return timeline events of the given types.
+    ///
+    /// Types can end with a `*` wildcard, e.g. `m.room.*`.
+    pub fn deny_timeline_types<T: Into<String>>(
+        mut self,
+        types: impl IntoIterator<Item = T>,
+    ) -> Self {
+        self.definition.room.timeline.not_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Never return state events of the given types.
+    ///
+    /// Types can end with a `*` wildcard, e.g. `m.room.*`.
+    pub fn deny_state_types<T: Into<String>>(mut self, types: impl IntoIterator<Item = T>) -> Self {
+        self.definition.room.state.not_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether to include the rooms that the user has left.
+    ///
+    /// Defaults to `false`.
+    pub fn include_leave(mut self, include: bool) -> Self {
+        self.definition.room.include_leave = include;
+        self
+    }
+
+    /// The name of this profile.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The filter definition built by this profile.
+    pub fn definition(&self) -> &FilterDefinition {
+        &self.definition
+    }
+
+    /// The key under which the ID of the uploaded filter is stored.
+    ///
+    /// It contains a fingerprint of the definition, so a modified profile
+    /// doesn't reuse the filter uploaded for a previous version of it.
+    pub(crate) fn store_key(&self) -> String {
+        format!("{}.{:016x}", self.name, self.fingerprint())
+    }
+
+    /// A 64-bit FNV-1a hash of the JSON serialization of the definition.
+    ///
+    /// We can't use the hashers from the standard library since their output
+    /// isn't guaranteed to be stable across Rust releases.
+    fn fingerprint(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        let json = serde_json::to_vec(&self.definition)
+            .expect("serializing a filter definition can't fail");
+
+        json.iter().fold(OFFSET_BASIS, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(PRIME))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::api::client::filter::LazyLoadOptions;
+
+    use super::SyncFilterProfile;
+
+    #[test]
+    fn lazy_loading_is_enabled_by_default() {
+        let profile = SyncFilterProfile::new("test");
+
+        assert!(matches!(
+            profile.definition().room.state.lazy_load_options,
+            LazyLoadOptions::Enabled { include_redundant_members: false }
+        ));
+    }
+
+    #[test]
+    fn store_key_changes_with_definition() {
+        let profile = SyncFilterProfile::new("test");
+        let same_profile = SyncFilterProfile::new("test");
+        let other_profile = SyncFilterProfile::new("test").timeline_limit(10);
+
+        assert!(profile.store_key().starts_with("test."));
+        assert_eq!(profile.store_key(), same_profile.store_key());
+        assert_ne!(profile.store_key(), other_profile.store_key());
+    }
+}
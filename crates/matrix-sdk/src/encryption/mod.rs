@@ -24,8 +24,19 @@ use std::{
     iter,
     path::PathBuf,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+};
 
+#[cfg(not(target_arch = "wasm32"))]
+use bytes::BytesMut;
 use futures_util::stream::{self, StreamExt};
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::TryStreamExt;
+#[cfg(not(target_arch = "wasm32"))]
+use matrix_sdk_base::crypto::AttachmentChunkEncryptor;
 pub use matrix_sdk_base::crypto::{
     olm::{
         SessionCreationError as MegolmSessionCreationError,
@@ -54,7 +65,7 @@ use tracing::{debug, instrument, trace, warn};
 
 pub use crate::error::RoomKeyImportError;
 use crate::{
-    attachment::{AttachmentInfo, Thumbnail},
+    attachment::{AttachmentData, AttachmentInfo, Thumbnail},
     encryption::{
         identities::{Device, UserDevices},
         verification::{SasVerification, Verification, VerificationRequest},
@@ -160,13 +171,52 @@ impl Client {
         Ok(file)
     }
 
-    /// Encrypt and upload the file to be read from `reader` and construct an
-    /// attachment message with `body`, `content_type`, `info` and `thumbnail`.
+    /// Encrypt and upload the file at the given path, without buffering it in
+    /// memory.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn prepare_encrypted_file_from_path(
+        &self,
+        content_type: &mime::Mime,
+        path: &Path,
+    ) -> Result<ruma::events::room::EncryptedFile> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        let encryptor = Arc::new(StdMutex::new(AttachmentChunkEncryptor::new()));
+        let data = crate::media::file_to_stream(file).map_ok({
+            let encryptor = encryptor.clone();
+            move |chunk| {
+                let mut chunk = BytesMut::from(&chunk[..]);
+                encryptor.lock().unwrap().encrypt_chunk(&mut chunk);
+                chunk.freeze()
+            }
+        });
+
+        let response =
+            self.media().upload_stream(content_type, Box::pin(data), size, |_| {}).await?;
+
+        let file: ruma::events::room::EncryptedFile = {
+            let keys = std::mem::take(&mut *encryptor.lock().unwrap()).finish();
+            ruma::events::room::EncryptedFileInit {
+                url: response.content_uri,
+                key: keys.key,
+                iv: keys.iv,
+                hashes: keys.hashes,
+                v: keys.version,
+            }
+            .into()
+        };
+
+        Ok(file)
+    }
+
+    /// Encrypt and upload the attachment `data` and construct an attachment
+    /// message with `body`, `content_type`, `info` and `thumbnail`.
     pub(crate) async fn prepare_encrypted_attachment_message(
         &self,
         body: &str,
         content_type: &mime::Mime,
-        data: Vec<u8>,
+        data: AttachmentData,
         info: Option<AttachmentInfo>,
         thumbnail: Option<Thumbnail>,
     ) -> Result<ruma::events::room::message::MessageType> {
@@ -187,8 +237,15 @@ impl Client {
             (None, None)
         };
 
-        let mut cursor = Cursor::new(data);
-        let file = self.prepare_encrypted_file(content_type, &mut cursor).await?;
+        let file = match data {
+            AttachmentData::Bytes(data) => {
+                self.prepare_encrypted_file(content_type, &mut Cursor::new(data)).await?
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            AttachmentData::File(path) => {
+                self.prepare_encrypted_file_from_path(content_type, &path).await?
+            }
+        };
 
         use std::io::Cursor;
 
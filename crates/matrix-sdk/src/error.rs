@@ -36,6 +36,8 @@ use serde_json::Error as JsonError;
 use thiserror::Error;
 use url::ParseError as UrlParseError;
 
+use crate::{client::ServerFeature, notification_settings::NotificationSettingsError};
+
 /// Result type of the matrix-sdk.
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -108,6 +110,11 @@ pub enum HttpError {
     /// An error occurred while refreshing the access token.
     #[error(transparent)]
     RefreshToken(#[from] RefreshTokenError),
+
+    /// An IO error occurred while streaming the body of a request or a
+    /// response.
+    #[error(transparent)]
+    Io(#[from] IoError),
 }
 
 #[rustfmt::skip] // stop rustfmt breaking the `<code>` in docs across multiple lines
@@ -250,6 +257,15 @@ pub enum Error {
     #[error(transparent)]
     Timeline(#[from] crate::room::timeline::Error),
 
+    /// An error occurred when changing the notification settings.
+    #[error(transparent)]
+    NotificationSettings(#[from] NotificationSettingsError),
+
+    /// The homeserver doesn't support a feature that is required for this
+    /// action.
+    #[error("the homeserver doesn't support {0}")]
+    UnsupportedByServer(ServerFeature),
+
     /// The client is in inconsistent state. This happens when we set a room to
     /// a specific type, but then cannot get it in this type.
     #[error("The internal client state is inconsistent.")]
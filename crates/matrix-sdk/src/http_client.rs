@@ -21,10 +21,22 @@ use std::{
     },
     time::Duration,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    pin::Pin,
+};
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use bytesize::ByteSize;
+#[cfg(not(target_arch = "wasm32"))]
+use futures_core::Stream;
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::{stream, StreamExt, TryStreamExt};
+use matrix_sdk_base::SessionMeta;
+#[cfg(not(target_arch = "wasm32"))]
+use matrix_sdk_common::timeout::timeout;
 use matrix_sdk_common::AsyncTraitDeps;
 use ruma::{
     api::{
@@ -32,7 +44,7 @@ use ruma::{
         AuthScheme, IncomingResponse, MatrixVersion, OutgoingRequest, OutgoingRequestAppserviceExt,
         SendAccessToken,
     },
-    UserId,
+    DeviceId,
 };
 use tracing::{debug, field::debug, instrument, trace};
 
@@ -40,6 +52,26 @@ use crate::{config::RequestConfig, error::HttpError};
 
 pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// The query parameter used to assert the device of an application service
+/// user, as defined in [MSC3202].
+///
+/// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+const DEVICE_ID_QUERY_PARAM: &str = "org.matrix.msc3202.device_id";
+
+/// A stream of bytes, used for the bodies of requests and responses that
+/// shouldn't be buffered entirely in memory.
+#[cfg(not(target_arch = "wasm32"))]
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, IoError>> + Send + Sync>>;
+
+/// Progress of sending or receiving a payload.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransmissionProgress {
+    /// How many bytes were already transferred.
+    pub current: u64,
+    /// How many bytes there are in total, if it is known.
+    pub total: Option<u64>,
+}
+
 /// Abstraction around the http layer. The allows implementors to use different
 /// http libraries.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -99,6 +131,47 @@ pub trait HttpSend: AsyncTraitDeps {
         request: http::Request<Bytes>,
         timeout: Duration,
     ) -> Result<http::Response<Bytes>, HttpError>;
+
+    /// Send a request and receive the body of the response as a stream of
+    /// bytes.
+    ///
+    /// This is used by the client to download media without buffering it in
+    /// memory. The `timeout` only applies to receiving the head of the
+    /// response, the client makes sure that the body doesn't stall for longer
+    /// than that.
+    ///
+    /// The default implementation buffers the response with
+    /// [`send_request()`](Self::send_request) and should be overridden to
+    /// support streaming.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_request_streaming(
+        &self,
+        request: http::Request<Bytes>,
+        timeout: Duration,
+    ) -> Result<http::Response<ByteStream>, HttpError> {
+        let response = self.send_request(request, timeout).await?;
+        Ok(response.map(bytes_to_stream))
+    }
+
+    /// Send a request with a body that is a stream of bytes.
+    ///
+    /// This is used by the client to upload media without buffering it in
+    /// memory.
+    ///
+    /// The default implementation buffers the body of the request and sends it
+    /// with [`send_request()`](Self::send_request), it should be overridden to
+    /// support streaming.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_request_with_body_stream(
+        &self,
+        request: http::Request<ByteStream>,
+        timeout: Duration,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let (parts, body) = request.into_parts();
+        let body = collect_stream(body).await?;
+
+        self.send_request(http::Request::from_parts(parts, body), timeout).await
+    }
 }
 
 #[derive(Debug)]
@@ -124,7 +197,7 @@ impl HttpClient {
         config: RequestConfig,
         homeserver: String,
         access_token: Option<&str>,
-        user_id: Option<&UserId>,
+        session_meta: Option<&SessionMeta>,
         server_versions: &[MatrixVersion],
     ) -> Result<http::Request<Bytes>, IntoHttpError>
     where
@@ -133,15 +206,21 @@ impl HttpClient {
         trace!(request_type = type_name::<R>(), "Serializing request");
 
         // We can't assert the identity without a user_id.
-        let request = if let Some((access_token, user_id)) =
-            access_token.filter(|_| config.assert_identity).zip(user_id)
+        let request = if let Some((access_token, session_meta)) =
+            access_token.filter(|_| config.assert_identity).zip(session_meta)
         {
-            request.try_into_http_request_with_user_id::<BytesMut>(
+            let mut request = request.try_into_http_request_with_user_id::<BytesMut>(
                 &homeserver,
                 SendAccessToken::Always(access_token),
-                user_id,
+                &session_meta.user_id,
                 server_versions,
-            )?
+            )?;
+
+            if config.assert_device_identity {
+                assert_device_identity(&mut request, &session_meta.device_id)?;
+            }
+
+            request
         } else {
             let send_access_token = match access_token {
                 Some(access_token) => {
@@ -263,7 +342,7 @@ impl HttpClient {
     }
 
     #[instrument(
-        skip(self, access_token, config, request, user_id),
+        skip(self, access_token, config, request, session_meta),
         fields(
             config,
             path,
@@ -281,7 +360,7 @@ impl HttpClient {
         config: Option<RequestConfig>,
         homeserver: String,
         access_token: Option<&str>,
-        user_id: Option<&UserId>,
+        session_meta: Option<&SessionMeta>,
         server_versions: &[MatrixVersion],
     ) -> Result<R::IncomingResponse, HttpError>
     where
@@ -306,7 +385,7 @@ impl HttpClient {
             // The user ID is only used if we're an app-service. Only log the user_id if
             // it's `Some` and if assert_identity is set.
             if config.assert_identity {
-                span.record("user_id", user_id.map(debug));
+                span.record("user_id", session_meta.map(|s| debug(&s.user_id)));
             }
 
             let auth_scheme = R::METADATA.authentication;
@@ -319,7 +398,7 @@ impl HttpClient {
                 config,
                 homeserver,
                 access_token,
-                user_id,
+                session_meta,
                 server_versions,
             )?;
 
@@ -361,6 +440,154 @@ impl HttpClient {
             }
         }
     }
+
+    /// Send the given request and receive the body of the response as a
+    /// stream.
+    ///
+    /// The `headers` are added to the serialized request. Contrary to
+    /// [`HttpClient::send()`], the request is never retried.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(
+        skip(self, access_token, config, request, headers, session_meta),
+        fields(path, request_id, status)
+    )]
+    pub async fn send_streaming<R>(
+        &self,
+        request: R,
+        headers: http::HeaderMap,
+        config: Option<RequestConfig>,
+        homeserver: String,
+        access_token: Option<&str>,
+        session_meta: Option<&SessionMeta>,
+        server_versions: &[MatrixVersion],
+    ) -> Result<http::Response<ByteStream>, HttpError>
+    where
+        R: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<R::EndpointError>>,
+    {
+        let config = config.unwrap_or(self.request_config);
+
+        let mut request = self.serialize_client_request(
+            request,
+            config,
+            homeserver,
+            access_token,
+            session_meta,
+            server_versions,
+        )?;
+        request.headers_mut().extend(headers);
+
+        debug!("Sending request");
+        let response = self.inner.send_request_streaming(request, config.timeout).await?;
+        tracing::Span::current().record("status", response.status().as_u16());
+
+        // The body can take much longer than the timeout to be received, as
+        // long as it doesn't stall.
+        let response = response.map(|body| with_idle_timeout(body, config.timeout));
+
+        if response.status().as_u16() < 400 {
+            return Ok(response);
+        }
+
+        // Buffer the body to deserialize the error.
+        let (parts, body) = response.into_parts();
+        let body = collect_stream(body).await?;
+
+        match R::IncomingResponse::try_from_http_response(http::Response::from_parts(parts, body)) {
+            Err(e) => {
+                debug!("Error while sending request: {e:?}");
+                Err(e.into())
+            }
+            Ok(_) => unreachable!("responses with an error status code are deserialized as errors"),
+        }
+    }
+
+    /// Send the given request with the given stream as its body.
+    ///
+    /// The body of the serialized request is replaced by `body`, and the
+    /// `headers` are added to it. Contrary to [`HttpClient::send()`], the
+    /// request is never retried.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(
+        skip(self, access_token, config, request, body, headers, session_meta),
+        fields(path, request_id, status)
+    )]
+    pub async fn send_with_body_stream<R>(
+        &self,
+        request: R,
+        body: ByteStream,
+        headers: http::HeaderMap,
+        config: Option<RequestConfig>,
+        homeserver: String,
+        access_token: Option<&str>,
+        session_meta: Option<&SessionMeta>,
+        server_versions: &[MatrixVersion],
+    ) -> Result<R::IncomingResponse, HttpError>
+    where
+        R: OutgoingRequest + Debug,
+        HttpError: From<FromHttpResponseError<R::EndpointError>>,
+    {
+        let config = config.unwrap_or(self.request_config);
+
+        let mut request = self
+            .serialize_client_request(
+                request,
+                config,
+                homeserver,
+                access_token,
+                session_meta,
+                server_versions,
+            )?
+            .map(|_| body);
+        request.headers_mut().extend(headers);
+
+        debug!("Sending request");
+        let response = self.inner.send_request_with_body_stream(request, config.timeout).await?;
+        tracing::Span::current().record("status", response.status().as_u16());
+
+        R::IncomingResponse::try_from_http_response(response).map_err(|e| {
+            debug!("Error while sending request: {e:?}");
+            e.into()
+        })
+    }
+
+    /// Serialize the given request, which must be a client request, and
+    /// record its ID and path in the current span.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn serialize_client_request<R>(
+        &self,
+        request: R,
+        config: RequestConfig,
+        homeserver: String,
+        access_token: Option<&str>,
+        session_meta: Option<&SessionMeta>,
+        server_versions: &[MatrixVersion],
+    ) -> Result<http::Request<Bytes>, HttpError>
+    where
+        R: OutgoingRequest + Debug,
+    {
+        let auth_scheme = R::METADATA.authentication;
+        if !matches!(auth_scheme, AuthScheme::AccessToken | AuthScheme::None) {
+            return Err(HttpError::NotClientRequest);
+        }
+
+        let request = self.serialize_request(
+            request,
+            config,
+            homeserver,
+            access_token,
+            session_meta,
+            server_versions,
+        )?;
+
+        tracing::Span::current()
+            .record("request_id", self.get_request_id())
+            .record("path", request.uri().path());
+
+        Ok(request)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -417,6 +644,28 @@ impl HttpSettings {
     }
 }
 
+/// Append the query parameter that asserts the given device of the user to
+/// the URI of the request.
+fn assert_device_identity(
+    request: &mut http::Request<BytesMut>,
+    device_id: &DeviceId,
+) -> Result<(), IntoHttpError> {
+    let device_id: String = url::form_urlencoded::byte_serialize(device_id.as_bytes()).collect();
+    let uri = request.uri();
+    let separator = if uri.query().is_some() { '&' } else { '?' };
+    let path_and_query = match uri.path_and_query() {
+        Some(path_and_query) => path_and_query.as_str(),
+        None => "/",
+    };
+    let path_and_query = format!("{path_and_query}{separator}{DEVICE_ID_QUERY_PARAM}={device_id}");
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.try_into().map_err(http::Error::from)?);
+    *request.uri_mut() = http::Uri::from_parts(parts).map_err(http::Error::from)?;
+
+    Ok(())
+}
+
 // Clones all request parts except the extensions which can't be cloned.
 // See also https://github.com/hyperium/http/issues/395
 #[cfg(not(target_arch = "wasm32"))]
@@ -429,9 +678,45 @@ fn clone_request(request: &http::Request<Bytes>) -> http::Request<Bytes> {
     builder.body(request.body().clone()).unwrap()
 }
 
-async fn response_to_http_response(
-    mut response: reqwest::Response,
-) -> Result<http::Response<Bytes>, reqwest::Error> {
+/// Turn the given bytes into a stream with a single item.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn bytes_to_stream(bytes: Bytes) -> ByteStream {
+    Box::pin(stream::once(async move { Ok(bytes) }))
+}
+
+/// Make the given stream fail if no bytes are received for the given
+/// duration.
+#[cfg(not(target_arch = "wasm32"))]
+fn with_idle_timeout(stream: ByteStream, duration: Duration) -> ByteStream {
+    Box::pin(stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+
+        match timeout(stream.next(), duration).await {
+            Ok(Some(chunk)) => Some((chunk, Some(stream))),
+            Ok(None) => None,
+            Err(_) => Some((
+                Err(IoError::new(IoErrorKind::TimedOut, "no data received before the timeout")),
+                None,
+            )),
+        }
+    }))
+}
+
+/// Collect all the bytes of the given stream.
+#[cfg(not(target_arch = "wasm32"))]
+async fn collect_stream(mut stream: ByteStream) -> Result<Bytes, IoError> {
+    let mut bytes = BytesMut::new();
+
+    while let Some(chunk) = stream.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+
+    Ok(bytes.freeze())
+}
+
+/// Create a builder for a http response with the status and the headers of the
+/// given response.
+fn response_builder(response: &mut reqwest::Response) -> http::response::Builder {
     let status = response.status();
 
     let mut http_builder = http::Response::builder().status(status);
@@ -443,6 +728,13 @@ async fn response_to_http_response(
         }
     }
 
+    http_builder
+}
+
+async fn response_to_http_response(
+    mut response: reqwest::Response,
+) -> Result<http::Response<Bytes>, reqwest::Error> {
+    let http_builder = response_builder(&mut response);
     let body = response.bytes().await?;
 
     Ok(http_builder.body(body).expect("Can't construct a response using the given body"))
@@ -469,4 +761,49 @@ impl HttpSend for reqwest::Client {
 
         Ok(response_to_http_response(response).await?)
     }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_request_streaming(
+        &self,
+        request: http::Request<Bytes>,
+        timeout: Duration,
+    ) -> Result<http::Response<ByteStream>, HttpError> {
+        let request = reqwest::Request::try_from(request)?;
+
+        // Setting the timeout of the request would also apply it to receiving
+        // the body, which can take much longer.
+        let response =
+            matrix_sdk_common::timeout::timeout(Box::pin(self.execute(request)), timeout);
+        let mut response = match response.await {
+            Ok(response) => response?,
+            Err(_) => {
+                return Err(IoError::new(
+                    IoErrorKind::TimedOut,
+                    "no response received before the timeout",
+                )
+                .into())
+            }
+        };
+        let http_builder = response_builder(&mut response);
+        let body = response.bytes_stream().map_err(|e| IoError::new(IoErrorKind::Other, e));
+
+        Ok(http_builder
+            .body(Box::pin(body) as ByteStream)
+            .expect("Can't construct a response using the given body"))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_request_with_body_stream(
+        &self,
+        request: http::Request<ByteStream>,
+        timeout: Duration,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let request = request.map(reqwest::Body::wrap_stream);
+        let mut request = reqwest::Request::try_from(request)?;
+        *request.timeout_mut() = Some(timeout);
+
+        let response = self.execute(request).await?;
+
+        Ok(response_to_http_response(response).await?)
+    }
 }
@@ -35,6 +35,7 @@ mod error;
 pub mod event_handler;
 mod http_client;
 pub mod media;
+pub mod notification_settings;
 pub mod room;
 pub mod sync;
 
@@ -49,17 +50,24 @@ mod events;
 pub use account::Account;
 #[cfg(feature = "sso-login")]
 pub use client::SsoLoginBuilder;
-pub use client::{Client, ClientBuildError, ClientBuilder, LoginBuilder, LoopCtrl, UnknownToken};
+pub use client::{
+    Client, ClientBuildError, ClientBuilder, LoginBuilder, LoopCtrl, ServerFeature, ServerInfo,
+    UnknownToken,
+};
 #[cfg(feature = "image-proc")]
 pub use error::ImageError;
 pub use error::{Error, HttpError, HttpResult, RefreshTokenError, Result, RumaApiError};
-pub use http_client::HttpSend;
+#[cfg(not(target_arch = "wasm32"))]
+pub use http_client::ByteStream;
+pub use http_client::{HttpSend, TransmissionProgress};
 pub use media::Media;
+pub use notification_settings::NotificationSettings;
 pub use ruma::{IdParseError, OwnedServerName, ServerName};
 #[cfg(feature = "experimental-sliding-sync")]
 pub use sliding_sync::{
-    RoomListEntry, SlidingSync, SlidingSyncBuilder, SlidingSyncList, SlidingSyncListBuilder,
-    SlidingSyncMode, SlidingSyncRoom, SlidingSyncState, UpdateSummary,
+    RoomListEntry, RoomListFilter, RoomListFilterController, RoomListItem, RoomListService,
+    RoomListServiceState, RoomListSort, SlidingSync, SlidingSyncBuilder, SlidingSyncList,
+    SlidingSyncListBuilder, SlidingSyncMode, SlidingSyncRoom, SlidingSyncState, UpdateSummary,
 };
 
 #[cfg(any(test, feature = "testing"))]
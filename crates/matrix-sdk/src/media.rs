@@ -17,10 +17,31 @@
 
 #[cfg(feature = "e2e-encryption")]
 use std::io::Read;
-#[cfg(not(target_arch = "wasm32"))]
-use std::path::Path;
 use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    fmt,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
+#[cfg(not(target_arch = "wasm32"))]
+use bytes::Bytes;
+#[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
+use bytes::BytesMut;
+#[cfg(not(target_arch = "wasm32"))]
+use futures_core::{ready, Stream};
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::{stream, StreamExt, TryStreamExt};
+#[cfg(not(target_arch = "wasm32"))]
+use http::{
+    header::{CONTENT_LENGTH, RANGE},
+    HeaderMap, HeaderValue, StatusCode,
+};
+#[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
+use matrix_sdk_base::crypto::AttachmentChunkDecryptor;
 pub use matrix_sdk_base::media::*;
 use mime::Mime;
 #[cfg(not(target_arch = "wasm32"))]
@@ -29,22 +50,30 @@ use ruma::{
     api::client::media::{create_content, get_content, get_content_thumbnail},
     assign,
     events::room::MediaSource,
-    MxcUri,
+    MxcUri, OwnedMxcUri,
 };
 #[cfg(not(target_arch = "wasm32"))]
 use tempfile::{Builder as TempFileBuilder, NamedTempFile, TempDir};
 #[cfg(not(target_arch = "wasm32"))]
-use tokio::{fs::File as TokioFile, io::AsyncWriteExt};
+use tokio::{
+    fs::{File as TokioFile, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+};
 
 use crate::{
-    attachment::{AttachmentInfo, Thumbnail},
+    attachment::{AttachmentData, AttachmentInfo, Thumbnail},
     Client, Result,
 };
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{http_client::ByteStream, TransmissionProgress};
 
 /// A conservative upload speed of 1Mbps
 const DEFAULT_UPLOAD_SPEED: u64 = 125_000;
 /// 5 min minimal upload request timeout, used to clamp the request timeout.
 const MIN_UPLOAD_REQUEST_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+/// The size of the chunks read from a file when uploading it.
+#[cfg(not(target_arch = "wasm32"))]
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
 
 /// A high-level API to interact with the media API.
 #[derive(Debug, Clone)]
@@ -74,6 +103,129 @@ impl MediaFileHandle {
     }
 }
 
+/// A stream of the content of a media file, received from the server.
+///
+/// If the content is encrypted and encryption is enabled, it is decrypted on
+/// the fly. The integrity of encrypted content can only be checked once all of
+/// it was received, so the stream ends with an error if the check fails.
+///
+/// The content can be consumed either as a [`Stream`] of [`Bytes`], or with
+/// the [`AsyncRead`] implementation.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct MediaStream {
+    inner: ByteStream,
+    #[cfg(feature = "e2e-encryption")]
+    decryptor: Option<AttachmentChunkDecryptor>,
+    progress: TransmissionProgress,
+    progress_callback: Option<Box<dyn Fn(TransmissionProgress) + Send + Sync>>,
+    /// The bytes that were received but not read yet, for the `AsyncRead`
+    /// implementation.
+    read_buffer: Bytes,
+    finished: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl MediaStream {
+    /// Call the given function every time a chunk of the content is received.
+    pub fn with_progress(
+        mut self,
+        callback: impl Fn(TransmissionProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// The progress of the download.
+    ///
+    /// When a download is resumed, this includes the content that was
+    /// received before.
+    pub fn progress(&self) -> TransmissionProgress {
+        self.progress
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl fmt::Debug for MediaStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MediaStream")
+            .field("progress", &self.progress)
+            .field("finished", &self.finished)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Stream for MediaStream {
+    type Item = Result<Bytes, IoError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if this.finished {
+            return Poll::Ready(None);
+        }
+
+        match ready!(this.inner.as_mut().poll_next(cx)) {
+            Some(Ok(chunk)) => {
+                this.progress.current += chunk.len() as u64;
+
+                if let Some(callback) = &this.progress_callback {
+                    callback(this.progress);
+                }
+
+                #[cfg(feature = "e2e-encryption")]
+                let chunk = match &mut this.decryptor {
+                    Some(decryptor) => {
+                        let mut chunk = BytesMut::from(chunk.as_ref());
+                        decryptor.decrypt_chunk(&mut chunk);
+                        chunk.freeze()
+                    }
+                    None => chunk,
+                };
+
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Some(Err(error)) => {
+                this.finished = true;
+                Poll::Ready(Some(Err(error)))
+            }
+            None => {
+                this.finished = true;
+
+                #[cfg(feature = "e2e-encryption")]
+                if let Some(decryptor) = this.decryptor.take() {
+                    if let Err(error) = decryptor.finish() {
+                        return Poll::Ready(Some(Err(IoError::new(IoErrorKind::Other, error))));
+                    }
+                }
+
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl AsyncRead for MediaStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        while self.read_buffer.is_empty() {
+            match ready!(self.as_mut().poll_next(cx)) {
+                Some(chunk) => self.read_buffer = chunk?,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = buf.remaining().min(self.read_buffer.len());
+        buf.put_slice(&self.read_buffer.split_to(len));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
 impl Media {
     pub(crate) fn new(client: Client) -> Self {
         Self { client }
@@ -125,6 +277,104 @@ impl Media {
         Ok(self.client.send(request, Some(request_config)).await?)
     }
 
+    /// Upload some media to the server from a stream of bytes, without
+    /// buffering it in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `data` - The stream of the raw bytes of the media.
+    ///
+    /// * `size` - The total size of the media, in bytes. It must match the
+    /// number of bytes in the stream.
+    ///
+    /// * `progress` - A function called every time a chunk of the media is
+    /// sent.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn upload_stream(
+        &self,
+        content_type: &Mime,
+        data: ByteStream,
+        size: u64,
+        progress: impl Fn(TransmissionProgress) + Send + Sync + 'static,
+    ) -> Result<create_content::v3::Response> {
+        let timeout = std::cmp::max(
+            Duration::from_secs(size / DEFAULT_UPLOAD_SPEED),
+            MIN_UPLOAD_REQUEST_TIMEOUT,
+        );
+
+        let request = assign!(create_content::v3::Request::new(Vec::new()), {
+            content_type: Some(content_type.essence_str().to_owned()),
+        });
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+
+        let mut current = 0;
+        let body = data.inspect_ok(move |chunk| {
+            current += chunk.len() as u64;
+            progress(TransmissionProgress { current, total: Some(size) });
+        });
+
+        let request_config = self.client.request_config().timeout(timeout);
+        Ok(self
+            .client
+            .send_with_body_stream(request, Box::pin(body), headers, Some(request_config))
+            .await?)
+    }
+
+    /// Upload the file at the given path to the server, without buffering it
+    /// in memory.
+    ///
+    /// This is a convenience method that calls the
+    /// [`upload_stream`](#method.upload_stream) method.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `path` - The path of the file to upload.
+    ///
+    /// * `progress` - A function called every time a chunk of the file is
+    /// sent.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # use mime;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// let response = client
+    ///     .media()
+    ///     .upload_file(&mime::IMAGE_JPEG, Path::new("/home/example/my-cat.jpg"), |progress| {
+    ///         println!("Sent {} bytes", progress.current);
+    ///     })
+    ///     .await?;
+    ///
+    /// println!("Cat URI: {}", response.content_uri);
+    /// # anyhow::Ok(()) });
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn upload_file(
+        &self,
+        content_type: &Mime,
+        path: &Path,
+        progress: impl Fn(TransmissionProgress) + Send + Sync + 'static,
+    ) -> Result<create_content::v3::Response> {
+        let file = TokioFile::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        self.upload_stream(content_type, file_to_stream(file), size, progress).await
+    }
+
     /// Gets a media file by copying it to a temporary location on disk.
     ///
     /// The file won't be encrypted even if it is encrypted on the server.
@@ -140,6 +390,9 @@ impl Media {
     ///   temporary file's extension.
     ///
     /// * `use_cache` - If we should use the media cache for this request.
+    /// Content that is not in the cache is written to the file as it is
+    /// received, and only kept in memory to be added to the cache if the
+    /// [`MediaRetentionPolicy`] allows it.
     #[cfg(not(target_arch = "wasm32"))]
     pub async fn get_media_file(
         &self,
@@ -148,8 +401,56 @@ impl Media {
         content_type: &Mime,
         use_cache: bool,
     ) -> Result<MediaFileHandle> {
-        let data = self.get_media_content(request, use_cache).await?;
+        let (temp_file, temp_dir) = Self::create_temp_file(body, content_type)?;
+        let mut file = TokioFile::from_std(temp_file.reopen()?);
+
+        let cached =
+            if use_cache { self.client.store().get_media_content(request).await? } else { None };
 
+        if let Some(data) = cached {
+            file.write_all(&data).await?;
+        } else {
+            let mut stream = self.media_stream(request, 0).await?;
+            let policy = self.media_retention_policy();
+            let exceeds_max_file_size =
+                |size: u64| policy.exceeds_max_file_size(size.try_into().unwrap_or(usize::MAX));
+
+            // The content is only kept in memory until it is too big to be
+            // cached.
+            let mut content = (use_cache
+                && !stream.progress().total.map_or(false, exceeds_max_file_size))
+            .then(Vec::new);
+
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+
+                if let Some(buffer) = &mut content {
+                    if exceeds_max_file_size((buffer.len() + chunk.len()) as u64) {
+                        content = None;
+                    } else {
+                        buffer.extend_from_slice(&chunk);
+                    }
+                }
+            }
+
+            file.flush().await?;
+
+            if let Some(content) = content {
+                self.add_media_content_to_cache(request, content, policy).await?;
+            }
+        }
+
+        Ok(MediaFileHandle { file: temp_file, _directory: temp_dir })
+    }
+
+    /// Create a temporary file with a name based on the given body and
+    /// content type.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn create_temp_file(
+        body: Option<String>,
+        content_type: &Mime,
+    ) -> Result<(NamedTempFile, Option<TempDir>)> {
         let inferred_extension = mime2ext::mime2ext(content_type);
 
         let body_path = body.as_ref().map(Path::new);
@@ -196,9 +497,183 @@ impl Media {
             _ => (TempFileBuilder::new().tempfile()?, None),
         };
 
-        TokioFile::from_std(temp_file.reopen()?).write_all(&data).await?;
+        Ok((temp_file, temp_dir))
+    }
 
-        Ok(MediaFileHandle { file: temp_file, _directory: temp_dir })
+    /// Get a media file's content as a stream, without buffering it in memory.
+    ///
+    /// If the content is encrypted and encryption is enabled, the content will
+    /// be decrypted as it is received.
+    ///
+    /// The media cache is not used by this method.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the content.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{
+    /// #     media::{MediaFormat, MediaRequest},
+    /// #     ruma::{events::room::MediaSource, mxc_uri},
+    /// #     Client,
+    /// # };
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// use futures_util::TryStreamExt;
+    ///
+    /// let request = MediaRequest {
+    ///     source: MediaSource::Plain(mxc_uri!("mxc://example.org/video").to_owned()),
+    ///     format: MediaFormat::File,
+    /// };
+    ///
+    /// let mut stream = client
+    ///     .media()
+    ///     .get_media_content_stream(&request)
+    ///     .await?
+    ///     .with_progress(|progress| println!("Received {} bytes", progress.current));
+    ///
+    /// while let Some(_chunk) = stream.try_next().await? {
+    ///     // Do something with the chunk.
+    /// }
+    /// # anyhow::Ok(()) });
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn get_media_content_stream(&self, request: &MediaRequest) -> Result<MediaStream> {
+        self.media_stream(request, 0).await
+    }
+
+    /// Download a media file's content to the given path, without buffering it
+    /// in memory.
+    ///
+    /// If the file already exists and the content is not encrypted, the
+    /// download is resumed from the end of the file, if the server supports
+    /// it. Otherwise the file is overwritten.
+    ///
+    /// If the content is encrypted and encryption is enabled, the content will
+    /// be decrypted. If the decrypted content fails the integrity check, the
+    /// file is removed.
+    ///
+    /// The media cache is not used by this method.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The `MediaRequest` of the content.
+    ///
+    /// * `path` - The path of the file where the content should be written.
+    ///
+    /// * `progress` - A function called every time a chunk of the content is
+    /// received.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn download_to_file(
+        &self,
+        request: &MediaRequest,
+        path: &Path,
+        progress: impl Fn(TransmissionProgress) + Send + Sync + 'static,
+    ) -> Result<()> {
+        let is_encrypted = matches!(request.source, MediaSource::Encrypted(_));
+
+        let offset = if is_encrypted {
+            0
+        } else {
+            match tokio::fs::metadata(path).await {
+                Ok(metadata) => metadata.len(),
+                Err(error) if error.kind() == IoErrorKind::NotFound => 0,
+                Err(error) => return Err(error.into()),
+            }
+        };
+
+        let stream = self.media_stream(request, offset).await?.with_progress(progress);
+
+        // The server might not support range requests.
+        let resumed = offset > 0 && stream.progress().current == offset;
+        let mut file = if resumed {
+            OpenOptions::new().append(true).open(path).await?
+        } else {
+            TokioFile::create(path).await?
+        };
+
+        if let Err(error) = write_stream_to_file(stream, &mut file).await {
+            if is_encrypted {
+                drop(file);
+                tokio::fs::remove_file(path).await?;
+            }
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Request a media file's content from the server, starting at the given
+    /// offset.
+    ///
+    /// The offset is ignored for encrypted content, and if the server doesn't
+    /// support range requests. The progress of the returned stream starts at
+    /// the offset where the content actually starts.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn media_stream(&self, request: &MediaRequest, offset: u64) -> Result<MediaStream> {
+        let mut headers = HeaderMap::new();
+        // The timeout doesn't apply to the whole download, only to the time
+        // waiting for the response and between chunks of the content.
+        let request_config = self.client.request_config();
+
+        let response = match &request.source {
+            MediaSource::Encrypted(file) => {
+                let request = get_content::v3::Request::from_url(&file.url)?;
+                self.client.send_streaming(request, headers, Some(request_config)).await?
+            }
+            MediaSource::Plain(uri) => {
+                if offset > 0 {
+                    headers.insert(
+                        RANGE,
+                        HeaderValue::from_str(&format!("bytes={offset}-"))
+                            .expect("range header should be valid"),
+                    );
+                }
+
+                if let MediaFormat::Thumbnail(size) = &request.format {
+                    let request =
+                        get_content_thumbnail::v3::Request::from_url(uri, size.width, size.height)?;
+                    self.client.send_streaming(request, headers, Some(request_config)).await?
+                } else {
+                    let request = get_content::v3::Request::from_url(uri)?;
+                    self.client.send_streaming(request, headers, Some(request_config)).await?
+                }
+            }
+        };
+
+        let offset = if response.status() == StatusCode::PARTIAL_CONTENT { offset } else { 0 };
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+
+        #[cfg(feature = "e2e-encryption")]
+        let decryptor = match &request.source {
+            MediaSource::Encrypted(file) => {
+                Some(AttachmentChunkDecryptor::new(file.as_ref().clone().into())?)
+            }
+            MediaSource::Plain(_) => None,
+        };
+
+        Ok(MediaStream {
+            inner: response.into_body(),
+            #[cfg(feature = "e2e-encryption")]
+            decryptor,
+            progress: TransmissionProgress {
+                current: offset,
+                total: content_length.map(|length| offset + length),
+            },
+            progress_callback: None,
+            read_buffer: Bytes::new(),
+            finished: false,
+        })
     }
 
     /// Get a media file's content.
@@ -257,12 +732,84 @@ impl Media {
         };
 
         if use_cache {
-            self.client.store().add_media_content(request, content.clone()).await?;
+            let policy = self.media_retention_policy();
+
+            if !policy.exceeds_max_file_size(content.len()) {
+                self.add_media_content_to_cache(request, content.clone(), policy).await?;
+            }
         }
 
         Ok(content)
     }
 
+    /// Add the given media content to the cache, and trim the cache if it
+    /// grows over the maximum cache size of the policy.
+    async fn add_media_content_to_cache(
+        &self,
+        request: &MediaRequest,
+        content: Vec<u8>,
+        policy: MediaRetentionPolicy,
+    ) -> Result<()> {
+        let size = content.len();
+        self.client.store().add_media_content(request, content).await?;
+
+        let Some(max_cache_size) = policy.max_cache_size else {
+            return Ok(());
+        };
+
+        // Keep track of the total size of the cache, so the store only needs
+        // to look at all the media content when the cache is too big.
+        let cache_size = self
+            .client
+            .inner
+            .media_cache_size
+            .lock()
+            .unwrap()
+            .map(|cache_size| cache_size.saturating_add(size))
+            .filter(|cache_size| *cache_size <= max_cache_size);
+
+        let cache_size = match cache_size {
+            Some(cache_size) => cache_size,
+            None => self.client.store().trim_media_cache(max_cache_size).await?,
+        };
+        *self.client.inner.media_cache_size.lock().unwrap() = Some(cache_size);
+
+        Ok(())
+    }
+
+    /// Get the policy of the media cache.
+    pub fn media_retention_policy(&self) -> MediaRetentionPolicy {
+        *self.client.inner.media_retention_policy.lock().unwrap()
+    }
+
+    /// Set the policy of the media cache.
+    ///
+    /// The new policy is only enforced on the media content already in the
+    /// cache after a call to [`Media::clean_cache()`].
+    pub fn set_media_retention_policy(&self, policy: MediaRetentionPolicy) {
+        *self.client.inner.media_retention_policy.lock().unwrap() = policy;
+    }
+
+    /// Get statistics about the media cache.
+    pub async fn cache_stats(&self) -> Result<MediaCacheStats> {
+        Ok(self.client.store().media_cache_stats().await?)
+    }
+
+    /// Remove the media content that doesn't respect the
+    /// [`MediaRetentionPolicy`] from the media cache.
+    ///
+    /// The media content that is too big is not cached, and when the policy
+    /// has a maximum cache size, the least recently accessed media content is
+    /// removed when the cache grows over it. This method should be called
+    /// regularly, for example when the application starts, for the rest of the
+    /// policy to be enforced.
+    pub async fn clean_cache(&self) -> Result<()> {
+        let policy = self.media_retention_policy();
+        self.client.store().clean_media_cache(policy).await?;
+        *self.client.inner.media_cache_size.lock().unwrap() = None;
+        Ok(())
+    }
+
     /// Remove a media file's content from the store.
     ///
     /// # Arguments
@@ -386,13 +933,28 @@ impl Media {
         Ok(())
     }
 
-    /// Upload the file bytes in `data` and construct an attachment
-    /// message with `body`, `content_type`, `info` and `thumbnail`.
+    /// Upload the given attachment data and get its MXC URI.
+    pub(crate) async fn upload_attachment_data(
+        &self,
+        content_type: &Mime,
+        data: AttachmentData,
+    ) -> Result<OwnedMxcUri> {
+        let response = match data {
+            AttachmentData::Bytes(data) => self.upload(content_type, data).await?,
+            #[cfg(not(target_arch = "wasm32"))]
+            AttachmentData::File(path) => self.upload_file(content_type, &path, |_| {}).await?,
+        };
+
+        Ok(response.content_uri)
+    }
+
+    /// Upload the attachment `data` and construct an attachment message with
+    /// `body`, `content_type`, `info` and `thumbnail`.
     pub(crate) async fn prepare_attachment_message(
         &self,
         body: &str,
         content_type: &Mime,
-        data: Vec<u8>,
+        data: AttachmentData,
         info: Option<AttachmentInfo>,
         thumbnail: Option<Thumbnail>,
     ) -> Result<ruma::events::room::message::MessageType> {
@@ -411,9 +973,7 @@ impl Media {
             (None, None)
         };
 
-        let response = self.upload(content_type, data).await?;
-
-        let url = response.content_uri;
+        let url = self.upload_attachment_data(content_type, data).await?;
 
         use ruma::events::room::{self, message};
         Ok(match content_type.type_() {
@@ -466,3 +1026,37 @@ impl Media {
         })
     }
 }
+
+/// Read the given file in chunks, as a stream of bytes.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn file_to_stream(file: TokioFile) -> ByteStream {
+    Box::pin(stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+        let read = file.read(&mut chunk).await?;
+
+        if read == 0 {
+            Ok(None)
+        } else {
+            chunk.truncate(read);
+            Ok(Some((Bytes::from(chunk), file)))
+        }
+    }))
+}
+
+/// Write all the content of the given stream to the given file.
+///
+/// Returns the number of bytes that were written.
+#[cfg(not(target_arch = "wasm32"))]
+async fn write_stream_to_file(mut stream: MediaStream, file: &mut TokioFile) -> Result<u64> {
+    let mut written = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+
+    Ok(written)
+}
@@ -0,0 +1,348 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! High-level API to manage the notification settings of the account.
+//!
+//! See [`NotificationSettings`] for details.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, RwLock as StdRwLock},
+};
+
+use eyeball::shared::Observable as SharedObservable;
+use futures_core::Stream;
+use ruma::{
+    api::client::push::{delete_pushrule, set_pushrule, set_pushrule_enabled, RuleScope},
+    events::push_rules::PushRulesEvent,
+    push::{
+        Action, NewConditionalPushRule, NewPatternedPushRule, NewPushRule, NewSimplePushRule,
+        PushCondition, RuleKind, Ruleset, Tweak,
+    },
+    RoomId,
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::{event_handler::EventHandlerDropGuard, Client, Result};
+
+/// The notification mode of a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomNotificationMode {
+    /// Notify for all messages.
+    AllMessages,
+    /// Only notify for mentions and keywords.
+    MentionsAndKeywordsOnly,
+    /// Never notify.
+    Mute,
+}
+
+/// Errors that can occur when changing the notification settings.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum NotificationSettingsError {
+    /// The push rule doesn't exist in the ruleset.
+    #[error("the {0} push rule `{1}` was not found")]
+    RuleNotFound(RuleKind, String),
+
+    /// The push rule was added locally because the server doesn't support it
+    /// yet, so it can't be changed on the server.
+    #[error("the {0} push rule `{1}` is not supported by the server")]
+    RuleNotOnServer(RuleKind, String),
+}
+
+/// A high-level API to manage the notification settings of the account.
+///
+/// The settings are stored in the `m.push_rules` global account data of the
+/// account. This type keeps a local copy of the ruleset that is updated right
+/// away when a setting is changed, and replaced by the ruleset received from
+/// the server during sync, which is always the source of truth.
+///
+/// The server-default rules for intentional mentions are added to the local
+/// ruleset if the server doesn't support them yet. They can't be changed.
+///
+/// Get it with [`Account::notification_settings()`](crate::Account::notification_settings).
+#[derive(Clone)]
+pub struct NotificationSettings {
+    client: Client,
+    rules: SharedObservable<Ruleset>,
+    /// The IDs of the override rules that only exist in the local ruleset.
+    local_rule_ids: Arc<StdRwLock<Vec<String>>>,
+    _push_rules_handler: Arc<EventHandlerDropGuard>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for NotificationSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NotificationSettings").finish_non_exhaustive()
+    }
+}
+
+impl NotificationSettings {
+    /// Create a new `NotificationSettings` with the ruleset of the server.
+    pub(crate) fn new(client: Client, mut ruleset: Ruleset) -> Self {
+        let local_rule_ids = client.base_client().add_missing_mention_rules(&mut ruleset);
+        let rules = SharedObservable::new(ruleset);
+        let local_rule_ids = Arc::new(StdRwLock::new(local_rule_ids));
+
+        let handler = client.add_event_handler({
+            let rules = rules.clone();
+            let local_rule_ids = local_rule_ids.clone();
+            move |ev: PushRulesEvent, client: Client| {
+                let rules = rules.clone();
+                let local_rule_ids = local_rule_ids.clone();
+                async move {
+                    debug!("Received new push rules");
+                    let mut ruleset = ev.content.global;
+                    *local_rule_ids.write().unwrap() =
+                        client.base_client().add_missing_mention_rules(&mut ruleset);
+                    rules.set(ruleset);
+                }
+            }
+        });
+        let guard = client.event_handler_drop_guard(handler);
+
+        Self { client, rules, local_rule_ids, _push_rules_handler: Arc::new(guard) }
+    }
+
+    /// Get the current push ruleset.
+    pub fn ruleset(&self) -> Ruleset {
+        self.rules.get()
+    }
+
+    /// Get a stream of the push ruleset, updated every time it changes.
+    pub fn subscribe_to_changes(&self) -> impl Stream<Item = Ruleset> {
+        self.rules.subscribe()
+    }
+
+    /// Get the notification mode that the user set for the given room.
+    ///
+    /// Returns `None` if the room uses the default notification settings.
+    pub fn room_notification_mode(&self, room_id: &RoomId) -> Option<RoomNotificationMode> {
+        let rules = self.rules.get();
+
+        if let Some(rule) = rules.get(RuleKind::Override, room_id) {
+            if rule.enabled() && !rule.actions().iter().any(|a| matches!(a, Action::Notify)) {
+                return Some(RoomNotificationMode::Mute);
+            }
+        }
+
+        let rule = rules.get(RuleKind::Room, room_id)?;
+        if !rule.enabled() {
+            None
+        } else if rule.actions().iter().any(|a| matches!(a, Action::Notify)) {
+            Some(RoomNotificationMode::AllMessages)
+        } else {
+            Some(RoomNotificationMode::MentionsAndKeywordsOnly)
+        }
+    }
+
+    /// Set the notification mode of the given room.
+    ///
+    /// This replaces the rules that were previously set for this room. The new
+    /// rule is set before the previous ones are deleted, so the room keeps its
+    /// previous notification mode if a request fails.
+    pub async fn set_room_notification_mode(
+        &self,
+        room_id: &RoomId,
+        mode: RoomNotificationMode,
+    ) -> Result<()> {
+        // A rule of the same kind is replaced by the server, only the rule of
+        // the other kind needs to be deleted.
+        let stale_kind = match mode {
+            RoomNotificationMode::Mute => RuleKind::Room,
+            RoomNotificationMode::AllMessages | RoomNotificationMode::MentionsAndKeywordsOnly => {
+                RuleKind::Override
+            }
+        };
+
+        let rule = match mode {
+            RoomNotificationMode::AllMessages => NewPushRule::Room(NewSimplePushRule::new(
+                room_id.to_owned(),
+                vec![Action::Notify, Action::SetTweak(Tweak::Sound("default".into()))],
+            )),
+            RoomNotificationMode::MentionsAndKeywordsOnly => {
+                NewPushRule::Room(NewSimplePushRule::new(room_id.to_owned(), vec![]))
+            }
+            RoomNotificationMode::Mute => NewPushRule::Override(NewConditionalPushRule::new(
+                room_id.to_string(),
+                vec![PushCondition::EventMatch {
+                    key: "room_id".to_owned(),
+                    pattern: room_id.to_string(),
+                }],
+                vec![],
+            )),
+        };
+
+        self.insert_rule(rule).await?;
+        self.delete_room_rule(stale_kind, room_id).await
+    }
+
+    /// Delete the rules that were set for the given room, so it uses the
+    /// default notification settings again.
+    pub async fn delete_user_defined_room_rules(&self, room_id: &RoomId) -> Result<()> {
+        for kind in [RuleKind::Override, RuleKind::Room] {
+            self.delete_room_rule(kind, room_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete the rule of the given kind that was set for the given room, if
+    /// any.
+    async fn delete_room_rule(&self, kind: RuleKind, room_id: &RoomId) -> Result<()> {
+        if self.rules.get().get(kind.clone(), room_id).is_some() {
+            self.delete_rule(kind, room_id.as_str()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the keywords that trigger a notification.
+    pub fn keywords(&self) -> BTreeSet<String> {
+        self.rules
+            .get()
+            .content
+            .iter()
+            .filter(|rule| !rule.default)
+            .map(|rule| rule.pattern.clone())
+            .collect()
+    }
+
+    /// Notify when a message contains the given keyword.
+    ///
+    /// Does nothing if the keyword is already set.
+    pub async fn add_keyword(&self, keyword: String) -> Result<()> {
+        if self.keywords().contains(&keyword) {
+            return Ok(());
+        }
+
+        let rule = NewPushRule::Content(NewPatternedPushRule::new(
+            keyword.clone(),
+            keyword,
+            vec![Action::Notify, Action::SetTweak(Tweak::Sound("default".into()))],
+        ));
+
+        self.insert_rule(rule).await
+    }
+
+    /// Stop notifying when a message contains the given keyword.
+    pub async fn remove_keyword(&self, keyword: &str) -> Result<()> {
+        let rule_ids: Vec<_> = self
+            .rules
+            .get()
+            .content
+            .iter()
+            .filter(|rule| !rule.default && rule.pattern == keyword)
+            .map(|rule| rule.rule_id.clone())
+            .collect();
+
+        for rule_id in rule_ids {
+            self.delete_rule(RuleKind::Content, &rule_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the given push rule is enabled.
+    ///
+    /// This is mostly useful for the server-default rules, like
+    /// [`PredefinedOverrideRuleId::IsUserMention`].
+    ///
+    /// [`PredefinedOverrideRuleId::IsUserMention`]: ruma::push::PredefinedOverrideRuleId::IsUserMention
+    pub fn is_push_rule_enabled(&self, kind: RuleKind, rule_id: &str) -> Result<bool> {
+        self.rules
+            .get()
+            .get(kind.clone(), rule_id)
+            .map(|rule| rule.enabled())
+            .ok_or_else(|| NotificationSettingsError::RuleNotFound(kind, rule_id.to_owned()).into())
+    }
+
+    /// Enable or disable the given push rule.
+    ///
+    /// Returns [`NotificationSettingsError::RuleNotOnServer`] if the rule was
+    /// only added locally because the server doesn't support it.
+    pub async fn set_push_rule_enabled(
+        &self,
+        kind: RuleKind,
+        rule_id: &str,
+        enabled: bool,
+    ) -> Result<()> {
+        if self.is_push_rule_enabled(kind.clone(), rule_id)? == enabled {
+            return Ok(());
+        }
+
+        if kind == RuleKind::Override
+            && self.local_rule_ids.read().unwrap().iter().any(|id| id == rule_id)
+        {
+            return Err(NotificationSettingsError::RuleNotOnServer(kind, rule_id.to_owned()).into());
+        }
+
+        let request = set_pushrule_enabled::v3::Request::new(
+            RuleScope::Global,
+            kind.clone(),
+            rule_id.to_owned(),
+            enabled,
+        );
+        self.client.send(request, None).await?;
+
+        self.update_local_rules(|rules| {
+            rules.set_enabled(kind, rule_id, enabled).map_err(|e| e.to_string())
+        });
+
+        Ok(())
+    }
+
+    /// Create a new rule on the server and in the local ruleset.
+    ///
+    /// Like the server, the local ruleset puts new rules before the other
+    /// user-defined rules of the same kind, so they have the highest priority.
+    async fn insert_rule(&self, rule: NewPushRule) -> Result<()> {
+        let request = set_pushrule::v3::Request::new(RuleScope::Global, rule.clone());
+        self.client.send(request, None).await?;
+
+        self.update_local_rules(|rules| rules.insert(rule, None, None).map_err(|e| e.to_string()));
+
+        Ok(())
+    }
+
+    /// Delete a rule on the server and in the local ruleset.
+    async fn delete_rule(&self, kind: RuleKind, rule_id: &str) -> Result<()> {
+        let request =
+            delete_pushrule::v3::Request::new(RuleScope::Global, kind.clone(), rule_id.to_owned());
+        self.client.send(request, None).await?;
+
+        self.update_local_rules(|rules| rules.remove(kind, rule_id).map_err(|e| e.to_string()));
+
+        Ok(())
+    }
+
+    /// Apply a change that succeeded on the server to the local ruleset.
+    ///
+    /// If the change can't be applied, the local ruleset is out of date and
+    /// will be fixed by the next sync.
+    fn update_local_rules(&self, f: impl FnOnce(&mut Ruleset) -> std::result::Result<(), String>) {
+        let mut rules = self.rules.get();
+
+        match f(&mut rules) {
+            Ok(()) => {
+                self.rules.set(rules);
+            }
+            Err(error) => {
+                warn!("Couldn't update the local push rules: {error}");
+            }
+        }
+    }
+}
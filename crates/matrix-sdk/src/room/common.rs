@@ -1,7 +1,7 @@
 use std::{borrow::Borrow, collections::BTreeMap, ops::Deref, sync::Arc};
 
 use matrix_sdk_base::{
-    deserialized_responses::{MembersResponse, TimelineEvent},
+    deserialized_responses::{MembersResponse, SyncTimelineEvent, TimelineEvent},
     store::StateStoreExt,
     RoomMemberships, StateChanges,
 };
@@ -14,8 +14,9 @@ use ruma::{
     api::{
         client::{
             config::set_global_account_data,
+            context::get_context,
             error::ErrorKind,
-            filter::RoomEventFilter,
+            filter::{LazyLoadOptions, RoomEventFilter},
             membership::{get_member_events, join_room_by_id, leave_room},
             message::get_message_events,
             room::get_room_event,
@@ -203,6 +204,14 @@ impl Common {
     /// ```
     pub async fn messages(&self, options: MessagesOptions) -> Result<Messages> {
         let room_id = self.inner.room_id();
+
+        // Only unfiltered back-paginations can extend the event cache.
+        let cache_from = options.from.clone().filter(|_| {
+            let mut filter = options.filter.clone();
+            filter.lazy_load_options = LazyLoadOptions::Disabled;
+            matches!(options.dir, Direction::Backward) && options.to.is_none() && filter.is_empty()
+        });
+
         let request = options.into_request(room_id);
         let http_response = self.client.send(request, None).await?;
 
@@ -247,9 +256,61 @@ impl Common {
             }
         }
 
+        // With lazy-loading, the state contains the member events of the
+        // senders of the events in the chunk.
+        if !response.state.is_empty() {
+            self.client.base_client().receive_lazy_loaded_members(room_id, &response.state).await?;
+        }
+
+        if let Some(from) = cache_from {
+            let events: Vec<SyncTimelineEvent> =
+                response.chunk.iter().cloned().map(Into::into).collect();
+            self.client
+                .base_client()
+                .receive_back_paginated_events(room_id, &from, &events, response.end.clone())
+                .await?;
+        }
+
         Ok(response)
     }
 
+    /// Fetch the member events needed to display the given event, e.g. the one
+    /// of its sender, using [lazy-loading].
+    ///
+    /// This is much cheaper than fetching the full member list with
+    /// [`sync_members()`](#method.sync_members) in large rooms. The member
+    /// events describe the membership at the time of the event, so they are
+    /// only available with
+    /// [`get_historical_member()`](BaseRoom::get_historical_member) for
+    /// members whose current state is not known. Nothing is fetched if the
+    /// full member list is already known.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The ID of the event whose members should be fetched.
+    ///
+    /// [lazy-loading]: https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
+    pub async fn load_members_for_event(&self, event_id: &EventId) -> Result<()> {
+        if self.are_members_synced() {
+            return Ok(());
+        }
+
+        let room_id = self.inner.room_id();
+        let request = assign!(get_context::v3::Request::new(room_id.to_owned(), event_id.to_owned()), {
+            limit: uint!(0),
+            filter: assign!(RoomEventFilter::default(), {
+                // We want the member events even if the server thinks it sent
+                // them already, since we might not have persisted them.
+                lazy_load_options: LazyLoadOptions::Enabled { include_redundant_members: true },
+            }),
+        });
+        let response = self.client.send(request, None).await?;
+
+        self.client.base_client().receive_lazy_loaded_members(room_id, &response.state).await?;
+
+        Ok(())
+    }
+
     /// Register a handler for events of a specific type, within this room.
     ///
     /// This method works the same way as [`Client::add_event_handler`], except
@@ -1,5 +1,7 @@
 #[cfg(feature = "image-proc")]
 use std::io::Cursor;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
 #[cfg(feature = "e2e-encryption")]
 use std::sync::Arc;
 use std::{borrow::Borrow, ops::Deref};
@@ -35,7 +37,8 @@ use ruma::{
         EmptyStateKey, MessageLikeEventContent, StateEventContent,
     },
     serde::Raw,
-    EventId, Int, MxcUri, OwnedEventId, OwnedTransactionId, TransactionId, UserId,
+    EventId, Int, MilliSecondsSinceUnixEpoch, MxcUri, OwnedEventId, OwnedTransactionId,
+    TransactionId, UserId,
 };
 use serde_json::Value;
 #[cfg(feature = "e2e-encryption")]
@@ -43,17 +46,17 @@ use tokio::sync::Mutex;
 use tracing::{debug, instrument};
 
 use super::Left;
-use crate::{
-    attachment::AttachmentConfig,
-    error::{Error, HttpResult},
-    room::Common,
-    BaseRoom, Client, Result, RoomState,
-};
 #[cfg(feature = "image-proc")]
 use crate::{
     attachment::{generate_image_thumbnail, Thumbnail},
     error::ImageError,
 };
+use crate::{
+    attachment::{AttachmentConfig, AttachmentData},
+    error::{Error, HttpResult},
+    room::Common,
+    BaseRoom, Client, Result, RoomState,
+};
 
 const TYPING_NOTICE_TIMEOUT: Duration = Duration::from_secs(4);
 const TYPING_NOTICE_RESEND_TIMEOUT: Duration = Duration::from_secs(3);
@@ -601,6 +604,55 @@ impl Joined {
         content: Value,
         event_type: &str,
         txn_id: Option<&TransactionId>,
+    ) -> Result<send_message_event::v3::Response> {
+        self.send_raw_impl(content, event_type, txn_id, None).await
+    }
+
+    /// Send a room message to this room with the given timestamp.
+    ///
+    /// This is the same as [`Joined::send()`], but the `origin_server_ts` of
+    /// the event is set to `timestamp` instead of the time at which the
+    /// homeserver received it. This is called [timestamp massaging], and is
+    /// only allowed for application services, for example to bridge messages
+    /// with their original timestamp. It doesn't change the position of the
+    /// event in the timeline.
+    ///
+    /// [timestamp massaging]: https://spec.matrix.org/v1.6/application-service-api/#timestamp-massaging
+    #[cfg(feature = "appservice")]
+    pub async fn send_with_timestamp(
+        &self,
+        content: impl MessageLikeEventContent,
+        txn_id: Option<&TransactionId>,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> Result<send_message_event::v3::Response> {
+        let event_type = content.event_type().to_string();
+        let content = serde_json::to_value(&content)?;
+
+        self.send_raw_with_timestamp(content, &event_type, txn_id, timestamp).await
+    }
+
+    /// Send a room message to this room from a json `Value` with the given
+    /// timestamp.
+    ///
+    /// This is the same as [`Joined::send_raw()`], with the timestamp
+    /// massaging of [`Joined::send_with_timestamp()`].
+    #[cfg(feature = "appservice")]
+    pub async fn send_raw_with_timestamp(
+        &self,
+        content: Value,
+        event_type: &str,
+        txn_id: Option<&TransactionId>,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> Result<send_message_event::v3::Response> {
+        self.send_raw_impl(content, event_type, txn_id, Some(timestamp)).await
+    }
+
+    async fn send_raw_impl(
+        &self,
+        content: Value,
+        event_type: &str,
+        txn_id: Option<&TransactionId>,
+        timestamp: Option<MilliSecondsSinceUnixEpoch>,
     ) -> Result<send_message_event::v3::Response> {
         let txn_id: OwnedTransactionId = txn_id.map_or_else(TransactionId::new, ToOwned::to_owned);
 
@@ -652,12 +704,13 @@ impl Joined {
             (Raw::new(&content)?.cast(), event_type)
         };
 
-        let request = send_message_event::v3::Request::new_raw(
+        let mut request = send_message_event::v3::Request::new_raw(
             self.inner.room_id().to_owned(),
             txn_id,
             event_type.into(),
             content,
         );
+        request.timestamp = timestamp;
 
         let response = self.client.send(request, None).await?;
         Ok(response)
@@ -721,6 +774,7 @@ impl Joined {
         config: AttachmentConfig,
     ) -> Result<send_message_event::v3::Response> {
         if config.thumbnail.is_some() {
+            let data = AttachmentData::Bytes(data);
             self.prepare_and_send_attachment(body, content_type, data, config).await
         } else {
             #[cfg(not(feature = "image-proc"))]
@@ -778,10 +832,69 @@ impl Joined {
                 thumbnail_size: None,
             };
 
+            let data = AttachmentData::Bytes(data);
             self.prepare_and_send_attachment(body, content_type, data, config).await
         }
     }
 
+    /// Send the file at the given path as an attachment to this room, without
+    /// buffering it in memory.
+    ///
+    /// This works like [`send_attachment()`](#method.send_attachment), but
+    /// the file is streamed to the server while it is read, and encrypted on
+    /// the fly if the room is encrypted.
+    ///
+    /// Thumbnails are never generated from the file, even if
+    /// [`AttachmentConfig::generate_thumbnail()`] was used, because that would
+    /// require loading the whole file in memory. A thumbnail can be provided
+    /// with [`AttachmentConfig::with_thumbnail()`].
+    ///
+    /// # Arguments
+    /// * `body` - A textual representation of the media that is going to be
+    /// uploaded. Usually the file name.
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `path` - The path of the file to upload.
+    ///
+    /// * `config` - Metadata and configuration for the attachment.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use matrix_sdk::{Client, ruma::room_id, attachment::AttachmentConfig};
+    /// # use url::Url;
+    /// # use mime;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080")?;
+    /// # let mut client = Client::new(homeserver).await?;
+    /// # let room_id = room_id!("!test:localhost");
+    /// if let Some(room) = client.get_joined_room(&room_id) {
+    ///     room.send_attachment_file(
+    ///         "My favorite cat",
+    ///         &mime::IMAGE_JPEG,
+    ///         Path::new("/home/example/my-cat.jpg"),
+    ///         AttachmentConfig::new(),
+    ///     ).await?;
+    /// }
+    /// # anyhow::Ok(()) });
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    #[instrument(skip_all)]
+    pub async fn send_attachment_file(
+        &self,
+        body: &str,
+        content_type: &Mime,
+        path: &Path,
+        config: AttachmentConfig,
+    ) -> Result<send_message_event::v3::Response> {
+        let data = AttachmentData::File(path.to_owned());
+        self.prepare_and_send_attachment(body, content_type, data, config).await
+    }
+
     /// Prepare and send an attachment to this room.
     ///
     /// This will upload the given data that the reader produces using the
@@ -808,7 +921,7 @@ impl Joined {
         &self,
         body: &str,
         content_type: &Mime,
-        data: Vec<u8>,
+        data: AttachmentData,
         config: AttachmentConfig,
     ) -> Result<send_message_event::v3::Response> {
         #[cfg(feature = "e2e-encryption")]
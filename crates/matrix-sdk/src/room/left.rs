@@ -1,6 +1,7 @@
 use std::ops::Deref;
 
-use ruma::api::client::membership::forget_room;
+use ruma::{api::client::membership::forget_room, OwnedMxcUri};
+use serde::Deserialize;
 
 use super::Joined;
 use crate::{room::Common, BaseRoom, Client, Result, RoomState};
@@ -38,14 +39,82 @@ impl Left {
 
     /// Forget this room.
     ///
-    /// This communicates to the homeserver that it should forget the room.
+    /// This communicates to the homeserver that it should forget the room, and
+    /// removes the state of the room from the local store.
+    ///
+    /// Use [`Left::forget_with_settings()`] to also remove the media and the
+    /// encryption keys of the room.
     pub async fn forget(&self) -> Result<()> {
+        self.forget_with_settings(ForgetRoomSettings::new()).await
+    }
+
+    /// Forget this room, and remove the data of the room from the local stores
+    /// according to the given settings.
+    ///
+    /// This communicates to the homeserver that it should forget the room, and
+    /// removes the state of the room from the local store and from the sliding
+    /// syncs of the client, and their caches.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The data to remove, in addition to the state of the room.
+    pub async fn forget_with_settings(&self, settings: ForgetRoomSettings) -> Result<()> {
         let request = forget_room::v3::Request::new(self.inner.room_id().to_owned());
         let _response = self.client.send(request, None).await?;
-        self.client.store().remove_room(self.inner.room_id()).await?;
+
+        let store = self.client.store();
+
+        // The media of the events must be collected before the event cache is
+        // removed with the room.
+        if settings.purge_media {
+            for uri in self.media_uris().await? {
+                store.remove_media_content_for_uri(&uri).await?;
+            }
+        }
+
+        store.remove_room(self.inner.room_id()).await?;
+
+        #[cfg(feature = "experimental-sliding-sync")]
+        self.client.forget_room_in_sliding_syncs(self.inner.room_id()).await?;
+
+        #[cfg(feature = "e2e-encryption")]
+        if settings.purge_room_keys {
+            if let Some(machine) = self.client.olm_machine() {
+                machine.remove_room_data(self.inner.room_id()).await?;
+            }
+        }
 
         Ok(())
     }
+
+    /// Get the URIs of the media of this room that might be in the media cache.
+    ///
+    /// These are the avatar of the room and the media files and thumbnails of
+    /// the events in the event cache.
+    async fn media_uris(&self) -> Result<Vec<OwnedMxcUri>> {
+        let store = self.client.store();
+        let room_id = self.inner.room_id();
+
+        let mut uris: Vec<_> = self.inner.avatar_url().into_iter().collect();
+
+        let Some(range) = store.get_event_chunk_range(room_id).await? else {
+            return Ok(uris);
+        };
+
+        for chunk_id in range {
+            let Some(chunk) = store.get_event_chunk(room_id, chunk_id).await? else { continue };
+
+            for event in chunk.events {
+                // Events without media, or that can't be deserialized, are
+                // ignored.
+                if let Ok(Some(content)) = event.event.get_field::<MediaContent>("content") {
+                    uris.extend(content.into_uris());
+                }
+            }
+        }
+
+        Ok(uris)
+    }
 }
 
 impl Deref for Left {
@@ -55,3 +124,71 @@ impl Deref for Left {
         &self.inner
     }
 }
+
+/// Settings for [`Left::forget_with_settings()`].
+///
+/// By default, only the state of the room is removed from the local store.
+#[derive(Debug, Clone, Default)]
+pub struct ForgetRoomSettings {
+    purge_media: bool,
+    #[cfg_attr(not(feature = "e2e-encryption"), allow(dead_code))]
+    purge_room_keys: bool,
+}
+
+impl ForgetRoomSettings {
+    /// Create the default `ForgetRoomSettings`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to remove the media of the room from the media cache.
+    ///
+    /// This removes the avatar of the room and the media files and thumbnails
+    /// of the events in the event cache.
+    pub fn purge_media(mut self, purge: bool) -> Self {
+        self.purge_media = purge;
+        self
+    }
+
+    /// Whether to remove the end-to-end encryption data of the room.
+    ///
+    /// This removes the room keys of the room, so its encrypted events can't
+    /// be decrypted anymore unless the keys are received again, for example
+    /// from a key backup.
+    ///
+    /// This has no effect if the `e2e-encryption` feature is disabled.
+    pub fn purge_room_keys(mut self, purge: bool) -> Self {
+        self.purge_room_keys = purge;
+        self
+    }
+}
+
+/// The fields of the content of an event that can reference media.
+#[derive(Deserialize)]
+struct MediaContent {
+    url: Option<OwnedMxcUri>,
+    file: Option<MediaFile>,
+    info: Option<MediaInfo>,
+}
+
+#[derive(Deserialize)]
+struct MediaFile {
+    url: OwnedMxcUri,
+}
+
+#[derive(Deserialize)]
+struct MediaInfo {
+    thumbnail_url: Option<OwnedMxcUri>,
+    thumbnail_file: Option<MediaFile>,
+}
+
+impl MediaContent {
+    fn into_uris(self) -> impl Iterator<Item = OwnedMxcUri> {
+        let (thumbnail_url, thumbnail_file) =
+            self.info.map(|info| (info.thumbnail_url, info.thumbnail_file)).unwrap_or_default();
+
+        [self.url, self.file.map(|f| f.url), thumbnail_url, thumbnail_file.map(|f| f.url)]
+            .into_iter()
+            .flatten()
+    }
+}
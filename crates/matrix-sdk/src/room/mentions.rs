@@ -0,0 +1,140 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+
+use ruma::{
+    events::{room::message::RoomMessageEventContent, Mentions},
+    OwnedUserId, UserId,
+};
+
+/// A builder for the intentional mentions ([MSC3952]) of a message.
+///
+/// Contrary to mentions detected in the body of a message, intentional
+/// mentions are listed in the `m.mentions` property of the message, so
+/// receiving clients don't need to guess who was mentioned. Once a message has
+/// an `m.mentions` property, its body isn't used to look for mentions anymore,
+/// so every user that should be notified must be listed, even if it is empty.
+///
+/// # Examples
+///
+/// ```
+/// use matrix_sdk::{
+///     room::MentionsBuilder,
+///     ruma::{events::room::message::RoomMessageEventContent, user_id},
+/// };
+///
+/// let mut content = RoomMessageEventContent::text_plain("Hello @alice and everyone!");
+/// MentionsBuilder::new()
+///     .user(user_id!("@alice:localhost"))
+///     .room()
+///     .apply_to(&mut content);
+/// ```
+///
+/// [MSC3952]: https://github.com/matrix-org/matrix-spec-proposals/pull/3952
+#[derive(Clone, Debug, Default)]
+pub struct MentionsBuilder {
+    user_ids: BTreeSet<OwnedUserId>,
+    room: bool,
+}
+
+impl MentionsBuilder {
+    /// Create a new builder that doesn't mention anyone.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mention the given user.
+    pub fn user(mut self, user_id: &UserId) -> Self {
+        self.user_ids.insert(user_id.to_owned());
+        self
+    }
+
+    /// Mention the given users.
+    pub fn users<'a>(mut self, user_ids: impl IntoIterator<Item = &'a UserId>) -> Self {
+        self.user_ids.extend(user_ids.into_iter().map(ToOwned::to_owned));
+        self
+    }
+
+    /// Mention the whole room, like `@room` in the body of a message.
+    ///
+    /// Only users with the power level required for room notifications can
+    /// notify the whole room, it is ignored for the other users.
+    pub fn room(mut self) -> Self {
+        self.room = true;
+        self
+    }
+
+    /// Mention the sender of the event that the message replies to.
+    ///
+    /// [`Timeline::send()`] does this automatically for replies that have an
+    /// `m.mentions` property.
+    ///
+    /// [`Timeline::send()`]: crate::room::timeline::Timeline::send
+    pub fn reply_to(self, replied_to_sender: &UserId) -> Self {
+        self.user(replied_to_sender)
+    }
+
+    /// Build the mentions.
+    pub fn build(self) -> Mentions {
+        let mut mentions = Mentions::with_user_ids(self.user_ids);
+        mentions.room = self.room;
+        mentions
+    }
+
+    /// Set the mentions as the `m.mentions` property of the given message.
+    ///
+    /// This replaces the mentions that might already have been set.
+    pub fn apply_to(self, content: &mut RoomMessageEventContent) {
+        content.mentions = Some(self.build());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{events::room::message::RoomMessageEventContent, owned_user_id, user_id};
+
+    use super::MentionsBuilder;
+
+    #[test]
+    fn build_mentions() {
+        let mentions = MentionsBuilder::new().build();
+        assert!(mentions.user_ids.is_empty());
+        assert!(!mentions.room);
+
+        let mentions = MentionsBuilder::new()
+            .user(user_id!("@alice:localhost"))
+            .users([user_id!("@bob:localhost"), user_id!("@alice:localhost")])
+            .room()
+            .build();
+        assert_eq!(
+            mentions.user_ids.into_iter().collect::<Vec<_>>(),
+            [owned_user_id!("@alice:localhost"), owned_user_id!("@bob:localhost")]
+        );
+        assert!(mentions.room);
+    }
+
+    #[test]
+    fn apply_mentions() {
+        let mut content = RoomMessageEventContent::text_plain("Hello!");
+        MentionsBuilder::new().reply_to(user_id!("@alice:localhost")).apply_to(&mut content);
+
+        let mentions = content.mentions.unwrap();
+        assert_eq!(
+            mentions.user_ids.into_iter().collect::<Vec<_>>(),
+            [owned_user_id!("@alice:localhost")]
+        );
+        assert!(!mentions.room);
+    }
+}
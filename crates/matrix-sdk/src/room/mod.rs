@@ -9,6 +9,7 @@ mod invited;
 mod joined;
 mod left;
 mod member;
+mod mentions;
 #[cfg(feature = "experimental-timeline")]
 pub mod timeline;
 
@@ -16,8 +17,9 @@ pub use self::{
     common::{Common, Messages, MessagesOptions},
     invited::Invited,
     joined::{Joined, Receipts},
-    left::Left,
+    left::{ForgetRoomSettings, Left},
     member::RoomMember,
+    mentions::MentionsBuilder,
 };
 
 /// An enum that abstracts over the different states a room can be in.
@@ -15,7 +15,10 @@
 use std::sync::Arc;
 
 use imbl::Vector;
-use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncTimelineEvent};
+use matrix_sdk_base::{
+    deserialized_responses::{EncryptionInfo, SyncTimelineEvent},
+    event_cache::EventChunk,
+};
 use ruma::{
     events::receipt::{ReceiptThread, ReceiptType, SyncReceiptEvent},
     push::Action,
@@ -26,7 +29,7 @@ use tracing::error;
 #[cfg(feature = "e2e-encryption")]
 use super::to_device::{handle_forwarded_room_key_event, handle_room_key_event};
 use super::{inner::TimelineInner, Timeline, TimelineEventHandlerHandles};
-use crate::room;
+use crate::{room, Result};
 
 /// Builder that allows creating and configuring various parts of a
 /// [`Timeline`].
@@ -70,7 +73,26 @@ impl TimelineBuilder {
 
     /// Create a [`Timeline`] with the options set on this builder.
     pub(crate) async fn build(self) -> Timeline {
-        let Self { room, prev_token, events, track_read_marker_and_receipts } = self;
+        let Self { room, mut prev_token, mut events, track_read_marker_and_receipts } = self;
+
+        // Without initial events, start with the latest events of the cache.
+        let mut cached_chunk = None;
+        if events.is_empty() && prev_token.is_none() {
+            match latest_cached_chunk(&room).await {
+                Ok(Some(chunk)) => {
+                    cached_chunk = Some(chunk.id);
+                    // Used to paginate with the network if the cache is
+                    // cleared.
+                    prev_token = chunk.prev_batch;
+                    events = chunk.events.into_iter().collect();
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to load the latest events from the event cache: {e}");
+                }
+            }
+        }
+
         let has_events = !events.is_empty();
 
         let mut inner =
@@ -180,6 +202,7 @@ impl TimelineBuilder {
             inner,
             start_token: Mutex::new(prev_token),
             _end_token: Mutex::new(None),
+            cached_chunk: Mutex::new(cached_chunk),
             event_handler_handles: Arc::new(TimelineEventHandlerHandles { client, handles }),
         };
 
@@ -198,3 +221,12 @@ impl TimelineBuilder {
         timeline
     }
 }
+
+/// Load the newest chunk of the event cache of the given room.
+async fn latest_cached_chunk(room: &room::Common) -> Result<Option<EventChunk>> {
+    let store = room.client.store();
+    let Some(range) = store.get_event_chunk_range(room.room_id()).await? else {
+        return Ok(None);
+    };
+    Ok(store.get_event_chunk(room.room_id(), *range.end()).await?)
+}
@@ -22,7 +22,7 @@ use imbl::Vector;
 use indexmap::{IndexMap, IndexSet};
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_base::crypto::OlmMachine;
-use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncTimelineEvent, TimelineEvent};
+use matrix_sdk_base::deserialized_responses::{EncryptionInfo, SyncTimelineEvent};
 #[cfg(feature = "e2e-encryption")]
 use ruma::RoomId;
 use ruma::{
@@ -270,11 +270,11 @@ impl<P: RoomDataProvider> TimelineInner<P> {
     #[instrument(skip_all)]
     pub(super) async fn handle_back_paginated_event(
         &self,
-        event: TimelineEvent,
+        event: SyncTimelineEvent,
     ) -> HandleEventResult {
         let mut state = self.state.lock().await;
         handle_remote_event(
-            event.event.cast(),
+            event.event,
             event.encryption_info,
             event.push_actions,
             TimelineItemPosition::Start,
@@ -746,12 +746,24 @@ impl RoomDataProvider for room::Common {
                 display_name_ambiguous: member.name_ambiguous(),
                 avatar_url: member.avatar_url().map(ToOwned::to_owned),
             }),
-            Ok(None) if self.are_members_synced() => Some(Profile {
-                display_name: None,
-                display_name_ambiguous: false,
-                avatar_url: None,
-            }),
-            Ok(None) => None,
+            // The member event from the room history is only used if the
+            // current one is unknown.
+            Ok(None) => match self.get_historical_member(user_id) {
+                Some(member) => {
+                    let content = member.as_original().map(|ev| &ev.content);
+                    Some(Profile {
+                        display_name: content.and_then(|c| c.displayname.clone()),
+                        display_name_ambiguous: false,
+                        avatar_url: content.and_then(|c| c.avatar_url.clone()),
+                    })
+                }
+                None if self.are_members_synced() => Some(Profile {
+                    display_name: None,
+                    display_name_ambiguous: false,
+                    avatar_url: None,
+                }),
+                None => None,
+            },
             Err(e) => {
                 error!(%user_id, "Failed to getch room member information: {e}");
                 None
@@ -25,18 +25,22 @@ use imbl::Vector;
 use mime::Mime;
 use pin_project_lite::pin_project;
 use ruma::{
-    api::client::receipt::create_receipt::v3::ReceiptType,
+    api::client::{
+        filter::{LazyLoadOptions, RoomEventFilter},
+        receipt::create_receipt::v3::ReceiptType,
+    },
     assign,
     events::{
         receipt::{Receipt, ReceiptThread},
-        room::message::sanitize::HtmlSanitizerMode,
+        relation::Thread,
+        room::message::{sanitize::HtmlSanitizerMode, Relation, RoomMessageEventContent},
         AnyMessageLikeEventContent,
     },
-    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, TransactionId, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, TransactionId, UserId,
 };
 use thiserror::Error;
 use tokio::sync::Mutex;
-use tracing::{error, instrument, warn};
+use tracing::{debug, error, instrument, warn};
 
 use super::{Joined, Receipts};
 use crate::{
@@ -84,6 +88,9 @@ pub struct Timeline {
     inner: Arc<TimelineInner<room::Common>>,
     start_token: Mutex<Option<String>>,
     _end_token: Mutex<Option<String>>,
+    /// The ID of the oldest event cache chunk loaded into the timeline, as
+    /// long as older chunks can still be loaded from the cache.
+    cached_chunk: Mutex<Option<i64>>,
     event_handler_handles: Arc<TimelineEventHandlerHandles>,
 }
 
@@ -104,14 +111,44 @@ impl Timeline {
 
         *start_lock = None;
         *end_lock = None;
+        *self.cached_chunk.lock().await = None;
 
         self.inner.clear().await;
     }
 
     /// Add more events to the start of the timeline.
+    ///
+    /// As long as the event cache contains older events than the ones in the
+    /// timeline, a single chunk of cached events is added and no request is
+    /// sent to the homeserver.
     #[instrument(skip_all, fields(initial_pagination_size, room_id = ?self.room().room_id()))]
     pub async fn paginate_backwards(&self, mut opts: PaginationOptions<'_>) -> Result<()> {
         let mut start_lock = self.start_token.lock().await;
+
+        {
+            let mut cached_chunk = self.cached_chunk.lock().await;
+            if let Some(chunk_id) = *cached_chunk {
+                match self.paginate_backwards_from_cache(chunk_id).await? {
+                    CachePagination::Chunk(chunk_id, prev_batch) => {
+                        *cached_chunk = Some(chunk_id);
+                        *start_lock = prev_batch;
+                        return Ok(());
+                    }
+                    CachePagination::StartOfRoom => {
+                        *cached_chunk = None;
+                        self.inner.add_loading_indicator().await;
+                        self.inner.remove_loading_indicator(false).await;
+                        return Ok(());
+                    }
+                    CachePagination::Exhausted => {
+                        // Continue with the network from the token of the
+                        // oldest chunk that was added to the timeline.
+                        *cached_chunk = None;
+                    }
+                }
+            }
+        }
+
         if start_lock.is_none()
             && self.inner.items().await.front().map_or(false, |item| item.is_timeline_start())
         {
@@ -130,6 +167,11 @@ impl Timeline {
                 .messages(assign!(MessagesOptions::backward(), {
                     from,
                     limit: limit.into(),
+                    filter: assign!(RoomEventFilter::default(), {
+                        lazy_load_options: LazyLoadOptions::Enabled {
+                            include_redundant_members: false,
+                        },
+                    }),
                 }))
                 .await?;
 
@@ -141,7 +183,7 @@ impl Timeline {
                 outcome.items_updated = 0;
 
                 for room_ev in messages.chunk {
-                    let res = self.inner.handle_back_paginated_event(room_ev).await;
+                    let res = self.inner.handle_back_paginated_event(room_ev.into()).await;
                     outcome.items_added = outcome.items_added.checked_add(res.item_added as u16)?;
                     outcome.items_updated = outcome.items_updated.checked_add(res.items_updated)?;
                 }
@@ -173,6 +215,45 @@ impl Timeline {
         Ok(())
     }
 
+    /// Add the event cache chunk preceding the one with the given ID to the
+    /// start of the timeline.
+    async fn paginate_backwards_from_cache(&self, chunk_id: i64) -> Result<CachePagination> {
+        let room_id = self.room().room_id();
+        let store = self.room().client.store();
+
+        let Some(range) = store.get_event_chunk_range(room_id).await? else {
+            // The cache was cleared.
+            return Ok(CachePagination::Exhausted);
+        };
+
+        if !range.contains(&chunk_id) {
+            return Ok(CachePagination::Exhausted);
+        }
+
+        if chunk_id == *range.start() {
+            // Continue with the network from where the cache stops.
+            let chunk = store.get_event_chunk(room_id, chunk_id).await?;
+            return Ok(match chunk.and_then(|chunk| chunk.prev_batch) {
+                Some(_) => CachePagination::Exhausted,
+                None => CachePagination::StartOfRoom,
+            });
+        }
+
+        let Some(chunk) = store.get_event_chunk(room_id, chunk_id - 1).await? else {
+            return Ok(CachePagination::Exhausted);
+        };
+
+        debug!(chunk_id = chunk.id, "Adding {} events from the event cache", chunk.events.len());
+
+        self.inner.add_loading_indicator().await;
+        for event in chunk.events.into_iter().rev() {
+            self.inner.handle_back_paginated_event(event).await;
+        }
+        self.inner.remove_loading_indicator(true).await;
+
+        Ok(CachePagination::Chunk(chunk.id, chunk.prev_batch))
+    }
+
     /// Retry decryption of previously un-decryptable events given a list of
     /// session IDs whose keys have been imported.
     ///
@@ -258,6 +339,11 @@ impl Timeline {
     /// If sending the message fails, the local echo item will change its
     /// `send_state` to [`EventSendState::SendingFailed`].
     ///
+    /// If the message is a reply and has intentional mentions, e.g. set with
+    /// [`MentionsBuilder`](crate::room::MentionsBuilder), the sender of the
+    /// replied-to event is added to them. It is only added to the message that
+    /// is sent, not to the local echo.
+    ///
     /// # Arguments
     ///
     /// * `content` - The content of the message event.
@@ -277,10 +363,20 @@ impl Timeline {
     /// [`MessageLikeUnsigned`]: ruma::events::MessageLikeUnsigned
     /// [`SyncMessageLikeEvent`]: ruma::events::SyncMessageLikeEvent
     #[instrument(skip(self, content), fields(room_id = ?self.room().room_id()))]
-    pub async fn send(&self, content: AnyMessageLikeEventContent, txn_id: Option<&TransactionId>) {
+    pub async fn send(
+        &self,
+        mut content: AnyMessageLikeEventContent,
+        txn_id: Option<&TransactionId>,
+    ) {
         let txn_id = txn_id.map_or_else(TransactionId::new, ToOwned::to_owned);
         self.inner.handle_local_event(txn_id.clone(), content.clone()).await;
 
+        // The replied-to event might need to be fetched from the server, so
+        // only do it once the local echo is shown.
+        if let AnyMessageLikeEventContent::RoomMessage(message) = &mut content {
+            self.add_reply_mention(message).await;
+        }
+
         // If this room isn't actually in joined state, we'll get a server error.
         // Not ideal, but works for now.
         let room = Joined { inner: self.room().clone() };
@@ -294,6 +390,39 @@ impl Timeline {
         self.inner.update_event_send_state(&txn_id, send_state).await;
     }
 
+    /// Mention the sender of the replied-to event, if the message is a reply
+    /// with intentional mentions.
+    async fn add_reply_mention(&self, content: &mut RoomMessageEventContent) {
+        let Some(mentions) = &mut content.mentions else { return };
+        let in_reply_to = match &content.relates_to {
+            Some(Relation::Reply { in_reply_to }) => in_reply_to,
+            Some(Relation::Thread(Thread {
+                in_reply_to: Some(in_reply_to),
+                is_falling_back: false,
+                ..
+            })) => in_reply_to,
+            _ => return,
+        };
+
+        let items = self.items().await;
+        let sender = match rfind_event_by_id(&items, &in_reply_to.event_id) {
+            Some((_, item)) => Some(item.sender().to_owned()),
+            None => match self.room().event(&in_reply_to.event_id).await {
+                Ok(event) => event.event.get_field::<OwnedUserId>("sender").ok().flatten(),
+                Err(e) => {
+                    warn!("Failed to fetch the replied-to event: {e}");
+                    None
+                }
+            },
+        };
+
+        if let Some(sender) = sender {
+            if &*sender != self.room().own_user_id() {
+                mentions.user_ids.insert(sender);
+            }
+        }
+    }
+
     /// Sends an attachment to the room. It does not currently support local
     /// echoes
     ///
@@ -356,8 +485,9 @@ impl Timeline {
 
     /// Fetch all member events for the room this timeline is displaying.
     ///
-    /// If the full member list is not known, sender profiles are currently
-    /// likely not going to be available. This will be fixed in the future.
+    /// If the full member list is not known, sender profiles might not be
+    /// available. In large rooms, it is cheaper to only fetch the profiles
+    /// that are missing with [`Self::fetch_sender_profile`].
     ///
     /// If fetching the members fails, any affected timeline items will have
     /// the `sender_profile` set to [`TimelineDetails::Error`].
@@ -374,6 +504,28 @@ impl Timeline {
         }
     }
 
+    /// Fetch the profile of the sender of the given event, if it is not known
+    /// yet.
+    ///
+    /// Only the member events needed to display the event are fetched, see
+    /// [`Common::load_members_for_event()`](super::Common::load_members_for_event).
+    /// This is meant to be called when rendering a timeline item whose
+    /// `sender_profile` is [`TimelineDetails::Unavailable`].
+    ///
+    /// If fetching the member events fails, the timeline items that don't have
+    /// a sender profile yet will have it set to [`TimelineDetails::Error`].
+    #[instrument(skip(self), fields(room_id = ?self.room().room_id()))]
+    pub async fn fetch_sender_profile(&self, event_id: &EventId) {
+        match self.room().load_members_for_event(event_id).await {
+            Ok(()) => {
+                self.inner.update_sender_profiles().await;
+            }
+            Err(e) => {
+                self.inner.set_sender_profiles_error(Arc::new(e)).await;
+            }
+        }
+    }
+
     /// Get the latest read receipt for the given user.
     ///
     /// Contrary to [`Common::user_receipt()`](super::Common::user_receipt) that
@@ -634,6 +786,20 @@ pub enum Error {
     FailedSendingAttachment,
 }
 
+/// The result of a back-pagination from the event cache.
+enum CachePagination {
+    /// The chunk with the given ID was added to the timeline, the events
+    /// before it can be fetched from the network with the given token.
+    Chunk(i64, Option<String>),
+    /// The start of the cache was reached, or the cache was cleared.
+    ///
+    /// Pagination continues with the network from the token of the oldest
+    /// chunk that was added to the timeline.
+    Exhausted,
+    /// The start of the cache is the start of the room.
+    StartOfRoom,
+}
+
 /// Result of comparing events position in the timeline.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum RelativePosition {
@@ -333,3 +333,51 @@ async fn reply() {
     let replied_to_event = assert_matches!(&in_reply_to.event, TimelineDetails::Ready(msg) => msg);
     assert_eq!(replied_to_event.sender(), *ALICE);
 }
+
+#[async_test]
+async fn intentional_mentions() {
+    let timeline = TestTimeline::new();
+
+    // The body doesn't contain the name of the user, but the event mentions
+    // them intentionally.
+    timeline
+        .handle_back_paginated_custom_event(json!({
+            "content": {
+                "body": "Can you have a look?",
+                "msgtype": "m.text",
+                "m.mentions": {
+                    "user_ids": [*ALICE],
+                },
+            },
+            "event_id": "$mention",
+            "origin_server_ts": 152037280,
+            "room_id": "!my_room:server.name",
+            "sender": *BOB,
+            "type": "m.room.message",
+        }))
+        .await;
+
+    // The body contains the name of the user, but the event has an empty
+    // `m.mentions` so the legacy mention rules don't apply.
+    timeline
+        .handle_back_paginated_custom_event(json!({
+            "content": {
+                "body": "Alice wrote this",
+                "msgtype": "m.text",
+                "m.mentions": {},
+            },
+            "event_id": "$no_mention",
+            "origin_server_ts": 152037220,
+            "room_id": "!my_room:server.name",
+            "sender": *BOB,
+            "type": "m.room.message",
+        }))
+        .await;
+
+    let timeline_items = timeline.inner.items().await;
+    assert_eq!(timeline_items.len(), 3);
+    let no_mention = timeline_items[1].as_event().unwrap();
+    assert!(!no_mention.is_highlighted());
+    let mention = timeline_items[2].as_event().unwrap();
+    assert!(mention.is_highlighted());
+}
@@ -13,7 +13,7 @@ use ruma::{
     events::TimelineEventType,
     OwnedRoomId,
 };
-use tokio::sync::{mpsc::channel, RwLock as AsyncRwLock};
+use tokio::sync::{broadcast, mpsc::channel, RwLock as AsyncRwLock};
 use url::Url;
 
 use super::{
@@ -232,7 +232,7 @@ impl SlidingSyncBuilder {
         let rooms = StdRwLock::new(rooms_found);
         let lists = StdRwLock::new(lists);
 
-        Ok(SlidingSync::new(SlidingSyncInner {
+        let sliding_sync = SlidingSync::new(SlidingSyncInner {
             homeserver: self.homeserver,
             client,
             storage_key: self.storage_key,
@@ -256,6 +256,17 @@ impl SlidingSyncBuilder {
                 internal_channel_sender,
                 AsyncRwLock::new(internal_channel_receiver),
             ),
-        }))
+
+            rooms_updates: broadcast::channel(16).0,
+        });
+
+        // Keep track of the sliding sync, so that the client can remove the
+        // rooms it forgets from it.
+        let mut sliding_syncs = sliding_sync.inner.client.inner.sliding_syncs.lock().unwrap();
+        sliding_syncs.retain(|sliding_sync| sliding_sync.upgrade().is_some());
+        sliding_syncs.push(sliding_sync.downgrade());
+        drop(sliding_syncs);
+
+        Ok(sliding_sync)
     }
 }
@@ -1,8 +1,8 @@
 use matrix_sdk_base::sync::SyncResponse;
-use ruma::api::client::sync::sync_events::v4;
+use ruma::{api::client::sync::sync_events::v4, RoomId};
 use tracing::{debug, instrument};
 
-use super::{SlidingSync, SlidingSyncBuilder};
+use super::{SlidingSync, SlidingSyncBuilder, WeakSlidingSync};
 use crate::{Client, Result};
 
 impl Client {
@@ -11,6 +11,25 @@ impl Client {
         SlidingSync::builder(self.clone())
     }
 
+    /// Remove a room from the sliding syncs of this client that are alive,
+    /// and from their caches.
+    pub(crate) async fn forget_room_in_sliding_syncs(&self, room_id: &RoomId) -> Result<()> {
+        let sliding_syncs: Vec<_> = self
+            .inner
+            .sliding_syncs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(WeakSlidingSync::upgrade)
+            .collect();
+
+        for sliding_sync in sliding_syncs {
+            sliding_sync.forget_room(room_id).await?;
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self, response))]
     pub(crate) async fn process_sliding_sync(
         &self,
@@ -20,9 +20,11 @@ use futures_core::Stream;
 use imbl::Vector;
 pub(super) use request_generator::*;
 pub use room_list_entry::RoomListEntry;
-use ruma::{api::client::sync::sync_events::v4, assign, events::StateEventType, OwnedRoomId};
+use ruma::{
+    api::client::sync::sync_events::v4, assign, events::StateEventType, OwnedRoomId, RoomId,
+};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
 use tracing::{instrument, warn};
 
 use super::{Error, SlidingSyncInternalMessage};
@@ -153,6 +155,18 @@ impl SlidingSyncList {
         ObservableVector::subscribe(&self.inner.room_list.read().unwrap())
     }
 
+    /// Get the current room list and a stream of its updates.
+    ///
+    /// Unlike calling [`Self::room_list`] and [`Self::room_list_stream`] one
+    /// after the other, no update can be missed between the two.
+    pub(super) fn room_list_with_stream(
+        &self,
+    ) -> (Vector<RoomListEntry>, impl Stream<Item = VectorDiff<RoomListEntry>>) {
+        let room_list = self.inner.room_list.read().unwrap();
+
+        (room_list.iter().cloned().collect(), ObservableVector::subscribe(&room_list))
+    }
+
     /// Get the maximum number of rooms. See [`Self::maximum_number_of_rooms`]
     /// to learn more.
     pub fn maximum_number_of_rooms(&self) -> Option<u32> {
@@ -174,6 +188,21 @@ impl SlidingSyncList {
             .and_then(|room_list_entry| room_list_entry.as_room_id().map(ToOwned::to_owned))
     }
 
+    /// Replace the entries of the given room by empty entries.
+    pub(super) fn forget_room(&self, room_id: &RoomId) {
+        let mut room_list = self.inner.room_list.write().unwrap();
+        let indices: Vec<_> = room_list
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.as_room_id() == Some(room_id))
+            .map(|(index, _)| index)
+            .collect();
+
+        for index in indices {
+            room_list.set(index, RoomListEntry::Empty);
+        }
+    }
+
     /// Calculate the next request and return it.
     pub(super) fn next_request(&mut self) -> Result<v4::SyncRequestList, Error> {
         self.inner.next_request()
@@ -213,13 +242,17 @@ impl SlidingSyncList {
     pub(super) fn reset(&self) -> Result<(), Error> {
         self.inner.reset();
 
-        // When a list is reset, the sync loop must be “restarted”.
-        self.inner
+        // When a list is reset, the sync loop must be “restarted”. The message
+        // is not awaited, so the ranges can be modified from any context. If
+        // the channel is full, the sync loop is already going to restart.
+        match self
+            .inner
             .sliding_sync_internal_channel_sender
-            .blocking_send(SlidingSyncInternalMessage::ContinueSyncLoop)
-            .map_err(|_| Error::InternalChannelIsBroken)?;
-
-        Ok(())
+            .try_send(SlidingSyncInternalMessage::ContinueSyncLoop)
+        {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err(Error::InternalChannelIsBroken),
+        }
     }
 }
 
@@ -890,6 +923,7 @@ mod tests {
         sync::{Arc, Mutex},
     };
 
+    use assert_matches::assert_matches;
     use futures::StreamExt;
     use imbl::vector;
     use ruma::{api::client::sync::sync_events::v4::SlidingOp, room_id, uint};
@@ -1029,6 +1063,24 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_sliding_sync_list_set_range_does_not_block() {
+        let (sender, receiver) = channel(1);
+
+        let list = SlidingSyncList::builder("foo")
+            .sync_mode(SlidingSyncMode::Selective)
+            .ranges(vec![0..=1])
+            .build(sender);
+
+        // The channel is full after the first call, but the sync loop is
+        // going to restart anyway.
+        list.set_range(2..=3).unwrap();
+        list.set_range(4..=5).unwrap();
+
+        drop(receiver);
+        assert_matches!(list.set_range(6..=7), Err(Error::InternalChannelIsBroken));
+    }
+
     #[test]
     fn test_sliding_sync_list_reset_ranges() {
         let (sender, _receiver) = channel(1);
@@ -21,6 +21,8 @@ mod client;
 mod error;
 mod list;
 mod room;
+mod room_list_filter;
+mod room_list_service;
 
 use std::{
     collections::BTreeMap,
@@ -28,7 +30,7 @@ use std::{
     mem,
     sync::{
         atomic::{AtomicU8, Ordering},
-        Arc, Mutex, RwLock as StdRwLock,
+        Arc, Mutex, RwLock as StdRwLock, Weak,
     },
     time::Duration,
 };
@@ -42,6 +44,8 @@ use futures_core::stream::Stream;
 pub use list::*;
 use matrix_sdk_base::sync::SyncResponse;
 pub use room::*;
+pub use room_list_filter::{RoomListFilter, RoomListFilterController, RoomListItem, RoomListSort};
+pub use room_list_service::*;
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -55,6 +59,7 @@ use serde::{Deserialize, Serialize};
 use tokio::{
     select, spawn,
     sync::{
+        broadcast,
         mpsc::{Receiver, Sender},
         Mutex as AsyncMutex, RwLock as AsyncRwLock,
     },
@@ -122,6 +127,27 @@ pub(super) struct SlidingSyncInner {
 
     internal_channel:
         (Sender<SlidingSyncInternalMessage>, AsyncRwLock<Receiver<SlidingSyncInternalMessage>>),
+
+    /// The rooms updated by the responses, see
+    /// [`SlidingSync::subscribe_to_rooms_updates`].
+    rooms_updates: broadcast::Sender<RoomsUpdate>,
+}
+
+/// A weak reference to a [`SlidingSync`].
+#[derive(Clone, Debug)]
+pub(crate) struct WeakSlidingSync {
+    inner: Weak<SlidingSyncInner>,
+    response_handling_lock: Weak<AsyncMutex<()>>,
+}
+
+impl WeakSlidingSync {
+    /// Get the [`SlidingSync`], if it is still alive.
+    pub(crate) fn upgrade(&self) -> Option<SlidingSync> {
+        Some(SlidingSync {
+            inner: self.inner.upgrade()?,
+            response_handling_lock: self.response_handling_lock.upgrade()?,
+        })
+    }
 }
 
 impl SlidingSync {
@@ -133,6 +159,39 @@ impl SlidingSync {
         cache::store_sliding_sync_state(self).await
     }
 
+    /// Create a weak reference to this sliding sync, that doesn't keep it
+    /// alive.
+    pub(crate) fn downgrade(&self) -> WeakSlidingSync {
+        WeakSlidingSync {
+            inner: Arc::downgrade(&self.inner),
+            response_handling_lock: Arc::downgrade(&self.response_handling_lock),
+        }
+    }
+
+    /// Remove a room from the rooms, the lists and the subscriptions of this
+    /// sliding sync, and from its cache.
+    ///
+    /// The room will be added again if the server sends it.
+    pub(crate) async fn forget_room(&self, room_id: &RoomId) -> Result<()> {
+        // The `response_handling_lock` is not taken, because this can be
+        // called by an event handler while a response is handled.
+        self.inner.subscriptions.write().unwrap().remove(room_id);
+
+        {
+            // The rooms of the entries of the lists are expected to exist when
+            // the lists are cached, so they are updated together.
+            let mut rooms = self.inner.rooms.write().unwrap();
+
+            for list in self.inner.lists.read().unwrap().values() {
+                list.forget_room(room_id);
+            }
+
+            rooms.remove(room_id);
+        }
+
+        self.cache_to_storage().await
+    }
+
     /// Create a new [`SlidingSyncBuilder`].
     pub fn builder(client: Client) -> SlidingSyncBuilder {
         SlidingSyncBuilder::new(client)
@@ -188,6 +247,13 @@ impl SlidingSync {
         }
     }
 
+    /// Subscribe to the rooms updated by the responses.
+    ///
+    /// The updates are sent once the lists are updated.
+    pub(super) fn subscribe_to_rooms_updates(&self) -> broadcast::Receiver<RoomsUpdate> {
+        self.inner.rooms_updates.subscribe()
+    }
+
     /// Lookup a specific room
     pub fn get_room(&self, room_id: &RoomId) -> Option<SlidingSyncRoom> {
         self.inner.rooms.read().unwrap().get(room_id).cloned()
@@ -308,6 +374,11 @@ impl SlidingSync {
             Observable::set(&mut position_lock.delta_token, sliding_sync_response.delta_token);
         }
 
+        // The account data of rooms can be received without any other update.
+        let account_data_rooms: Vec<OwnedRoomId> =
+            sliding_sync_response.extensions.account_data.rooms.keys().cloned().collect();
+        let global_account_data = !sliding_sync_response.extensions.account_data.global.is_empty();
+
         let update_summary = {
             // Update the rooms.
             let updated_rooms = {
@@ -383,6 +454,18 @@ impl SlidingSync {
             UpdateSummary { lists: updated_lists, rooms: updated_rooms }
         };
 
+        let mut rooms = update_summary.rooms.clone();
+        for room_id in account_data_rooms {
+            if !rooms.contains(&room_id) {
+                rooms.push(room_id);
+            }
+        }
+
+        if !rooms.is_empty() || global_account_data {
+            // It's fine if nobody is listening.
+            let _ = self.inner.rooms_updates.send(RoomsUpdate { rooms, global_account_data });
+        }
+
         Ok(update_summary)
     }
 
@@ -700,6 +783,17 @@ impl From<&SlidingSync> for FrozenSlidingSync {
     }
 }
 
+/// The rooms updated by a response, see
+/// [`SlidingSync::subscribe_to_rooms_updates`].
+#[derive(Debug, Clone)]
+pub(super) struct RoomsUpdate {
+    /// The rooms whose data, state or account data was updated.
+    pub(super) rooms: Vec<OwnedRoomId>,
+    /// Whether the global account data was updated, which can change the
+    /// data of any room, like whether it is a direct message.
+    pub(super) global_account_data: bool,
+}
+
 /// A summary of the updates received after a sync (like in
 /// [`SlidingSync::stream`]).
 #[derive(Debug, Clone)]
@@ -713,7 +807,11 @@ pub struct UpdateSummary {
 #[cfg(test)]
 mod test {
     use assert_matches::assert_matches;
-    use ruma::api::client::sync::sync_events::v4::{E2EEConfig, ToDeviceConfig};
+    use imbl::vector;
+    use ruma::{
+        api::client::sync::sync_events::v4::{E2EEConfig, ToDeviceConfig},
+        room_id,
+    };
     use wiremock::MockServer;
 
     use super::*;
@@ -758,4 +856,65 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn forget_room() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let room_id = room_id!("!forgotten:localhost");
+
+        let sync = client
+            .sliding_sync()
+            .await
+            .storage_key(Some("forget_room".to_owned()))
+            .add_list(SlidingSyncList::builder("all_rooms"))
+            .build()
+            .await?;
+
+        // Fill the list and the rooms as if the server had sent the room.
+        {
+            let mut rooms = sync.inner.rooms.write().unwrap();
+            rooms.insert(
+                room_id.to_owned(),
+                SlidingSyncRoom::new(
+                    client.clone(),
+                    room_id.to_owned(),
+                    v4::SlidingSyncRoom::new(),
+                    Vec::new(),
+                ),
+            );
+
+            let mut lists = sync.inner.lists.write().unwrap();
+            lists
+                .get_mut("all_rooms")
+                .unwrap()
+                .set_from_cold(Some(1), vector![RoomListEntry::Filled(room_id.to_owned())]);
+        }
+        sync.cache_to_storage().await?;
+
+        client.forget_room_in_sliding_syncs(room_id).await?;
+
+        assert!(sync.get_room(room_id).is_none());
+        assert_eq!(
+            sync.inner.lists.read().unwrap()["all_rooms"].room_list_with_stream().0,
+            vector![RoomListEntry::Empty]
+        );
+
+        // The room is not restored from the cache either.
+        let sync = client
+            .sliding_sync()
+            .await
+            .storage_key(Some("forget_room".to_owned()))
+            .add_list(SlidingSyncList::builder("all_rooms"))
+            .build()
+            .await?;
+
+        assert!(sync.get_room(room_id).is_none());
+        assert_eq!(
+            sync.inner.lists.read().unwrap()["all_rooms"].room_list_with_stream().0,
+            vector![RoomListEntry::Empty]
+        );
+
+        Ok(())
+    }
 }
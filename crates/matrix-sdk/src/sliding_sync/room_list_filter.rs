@@ -0,0 +1,919 @@
+//! Client-side filtering and sorting of the entries of a
+//! [`RoomListService`][super::RoomListService].
+//!
+//! Sliding Sync lists are ordered by the server, and can only be filtered with
+//! [server-side filters][ruma::api::client::sync::sync_events::v4::SyncRequestListFilters],
+//! which needs a new request every time they change. The types of this module
+//! filter and sort the entries that are already loaded instead, so a change is
+//! visible instantly.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Not,
+    sync::{Arc, RwLock as StdRwLock},
+};
+
+use async_stream::stream;
+use eyeball::unique::Observable;
+use eyeball_im::VectorDiff;
+use futures_core::stream::Stream;
+use futures_util::{pin_mut, FutureExt, StreamExt};
+use imbl::Vector;
+use ruma::{
+    events::{tag::TagName, StateEventType},
+    OwnedRoomId, RoomId,
+};
+use serde_json::Value as JsonValue;
+use tokio::{
+    select,
+    sync::broadcast::error::{RecvError, TryRecvError},
+};
+use tracing::warn;
+
+use super::{RoomListEntry, RoomsUpdate, SlidingSync, SlidingSyncList};
+
+/// The information about a room that is used to filter and sort the room
+/// list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RoomListItem {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+    /// The name of the room, as calculated by the server if any.
+    pub name: Option<String>,
+    /// Whether the room is a direct message.
+    pub is_dm: bool,
+    /// Whether the room has the `m.favourite` tag.
+    pub is_favourite: bool,
+    /// The number of unread notifications.
+    pub notification_count: u64,
+    /// The number of unread notifications with the highlight flag set.
+    pub highlight_count: u64,
+    /// The spaces the room is a child of, according to its `m.space.parent`
+    /// state events and the `m.space.child` state events of the spaces of
+    /// the list.
+    pub spaces: BTreeSet<OwnedRoomId>,
+    /// The children of the room if it is a space, according to its
+    /// `m.space.child` state events.
+    pub children: BTreeSet<OwnedRoomId>,
+}
+
+impl RoomListItem {
+    /// Load the information about the given room from the Sliding Sync room
+    /// and the store.
+    async fn load(sliding_sync: &SlidingSync, room_id: &RoomId) -> Self {
+        let mut item = Self {
+            room_id: room_id.to_owned(),
+            name: None,
+            is_dm: false,
+            is_favourite: false,
+            notification_count: 0,
+            highlight_count: 0,
+            spaces: BTreeSet::new(),
+            children: BTreeSet::new(),
+        };
+        item.reload(sliding_sync, PartialReload::AllButSpaces).await;
+
+        let client = &sliding_sync.inner.client;
+
+        if client.get_room(room_id).is_some() {
+            match client.store().get_state_events(room_id, StateEventType::SpaceParent).await {
+                Ok(events) => {
+                    item.spaces = events
+                        .iter()
+                        .filter_map(|event| event.get_field("state_key").ok().flatten())
+                        .collect();
+                }
+                Err(error) => warn!(%room_id, %error, "Failed to load the spaces of the room"),
+            }
+
+            match client.store().get_state_events(room_id, StateEventType::SpaceChild).await {
+                Ok(events) => {
+                    item.children = events
+                        .iter()
+                        // A child is removed by sending an event without `via`.
+                        .filter(|event| {
+                            let content = event.get_field::<JsonValue>("content").ok().flatten();
+                            let via =
+                                content.as_ref().and_then(|content| content["via"].as_array());
+
+                            via.map_or(false, |via| !via.is_empty())
+                        })
+                        .filter_map(|event| event.get_field("state_key").ok().flatten())
+                        .collect();
+                }
+                Err(error) => warn!(%room_id, %error, "Failed to load the children of the room"),
+            }
+        }
+
+        item
+    }
+
+    /// Reload the given fields of this item.
+    async fn reload(&mut self, sliding_sync: &SlidingSync, fields: PartialReload) {
+        let client = &sliding_sync.inner.client;
+        let room_id = &*self.room_id;
+        let sliding_sync_room = sliding_sync.get_room(room_id);
+        let room = client.get_room(room_id);
+
+        self.is_dm = match (sliding_sync_room.as_ref().and_then(|room| room.is_dm()), &room) {
+            (Some(is_dm), _) => is_dm,
+            (None, Some(room)) => room.is_direct().await.unwrap_or(false),
+            (None, None) => false,
+        };
+
+        if fields == PartialReload::DirectMessage {
+            return;
+        }
+
+        self.name = sliding_sync_room
+            .as_ref()
+            .and_then(|room| room.name())
+            .or_else(|| room.as_ref().and_then(|room| room.name()));
+
+        (self.notification_count, self.highlight_count) = sliding_sync_room
+            .map(|room| {
+                let count = room.unread_notifications();
+
+                (
+                    count.notification_count.map_or(0, u64::from),
+                    count.highlight_count.map_or(0, u64::from),
+                )
+            })
+            .unwrap_or_default();
+
+        self.is_favourite = false;
+
+        if let Some(room) = &room {
+            match room.tags().await {
+                Ok(tags) => {
+                    self.is_favourite =
+                        tags.map_or(false, |tags| tags.contains_key(&TagName::Favorite));
+                }
+                Err(error) => warn!(%room_id, %error, "Failed to load the tags of the room"),
+            }
+        }
+    }
+}
+
+/// The fields of the [`RoomListItem`]s that are reloaded when any room might
+/// have changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PartialReload {
+    /// Whether the rooms are direct messages, which depends on the global
+    /// account data.
+    DirectMessage,
+    /// All the fields except the spaces, which would need to load the state
+    /// events of every room.
+    AllButSpaces,
+}
+
+/// A filter of the room list.
+///
+/// Filters can be combined with [`Self::and`], [`Self::or`] and `!`.
+#[derive(Clone, Debug, Default)]
+pub enum RoomListFilter {
+    /// Match all the rooms.
+    #[default]
+    All,
+    /// Match the rooms with unread notifications.
+    Unread,
+    /// Match the direct messages.
+    DirectMessage,
+    /// Match the rooms with the `m.favourite` tag.
+    Favourite,
+    /// Match the rooms that are a child of the given space.
+    InSpace(OwnedRoomId),
+    /// Match the rooms whose name contains all the characters of the given
+    /// pattern in the same order, ignoring the case and whitespaces.
+    ///
+    /// The room ID is used for the rooms without a name.
+    FuzzyName(String),
+    /// Match the rooms matched by all the filters.
+    And(Vec<RoomListFilter>),
+    /// Match the rooms matched by any of the filters.
+    Or(Vec<RoomListFilter>),
+    /// Match the rooms not matched by the filter.
+    Not(Box<RoomListFilter>),
+}
+
+impl RoomListFilter {
+    /// Match the rooms matched by both this filter and the other one.
+    pub fn and(self, other: RoomListFilter) -> Self {
+        match self {
+            Self::And(mut filters) => {
+                filters.push(other);
+                Self::And(filters)
+            }
+            filter => Self::And(vec![filter, other]),
+        }
+    }
+
+    /// Match the rooms matched by this filter or the other one.
+    pub fn or(self, other: RoomListFilter) -> Self {
+        match self {
+            Self::Or(mut filters) => {
+                filters.push(other);
+                Self::Or(filters)
+            }
+            filter => Self::Or(vec![filter, other]),
+        }
+    }
+
+    /// Whether the given room is matched by this filter.
+    pub fn matches(&self, item: &RoomListItem) -> bool {
+        match self {
+            Self::All => true,
+            Self::Unread => item.notification_count > 0 || item.highlight_count > 0,
+            Self::DirectMessage => item.is_dm,
+            Self::Favourite => item.is_favourite,
+            Self::InSpace(space_id) => item.spaces.contains(space_id),
+            Self::FuzzyName(pattern) => {
+                fuzzy_match(pattern, item.name.as_deref().unwrap_or(item.room_id.as_str()))
+            }
+            Self::And(filters) => filters.iter().all(|filter| filter.matches(item)),
+            Self::Or(filters) => filters.iter().any(|filter| filter.matches(item)),
+            Self::Not(filter) => filter.matches(item).not(),
+        }
+    }
+}
+
+impl Not for RoomListFilter {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+}
+
+/// Whether all the characters of `pattern` are in `haystack`, in the same
+/// order, ignoring the case and whitespaces.
+fn fuzzy_match(pattern: &str, haystack: &str) -> bool {
+    let haystack = haystack.to_lowercase();
+    let mut haystack = haystack.chars();
+
+    pattern
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .all(|c| haystack.by_ref().any(|h| h == c))
+}
+
+/// A sort of the room list.
+///
+/// Sorts are applied in order, and the rooms that are equal for all of them
+/// keep the order of the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomListSort {
+    /// The rooms with unread notifications first.
+    Unread,
+    /// The rooms with the `m.favourite` tag first.
+    Favourite,
+    /// By name, ignoring the case. The rooms without a name are last.
+    Name,
+}
+
+impl RoomListSort {
+    fn compare(&self, left: &RoomListItem, right: &RoomListItem) -> Ordering {
+        match self {
+            Self::Unread => {
+                let is_unread =
+                    |item: &RoomListItem| (item.highlight_count > 0, item.notification_count > 0);
+
+                is_unread(right).cmp(&is_unread(left))
+            }
+            Self::Favourite => right.is_favourite.cmp(&left.is_favourite),
+            Self::Name => match (&left.name, &right.name) {
+                (Some(left), Some(right)) => left.to_lowercase().cmp(&right.to_lowercase()),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct FilterSettings {
+    pub(super) filter: RoomListFilter,
+    pub(super) sorts: Vec<RoomListSort>,
+}
+
+/// A handle to change the filter and the sorts of a filtered room list, see
+/// [`RoomListService::filtered_entries`][super::RoomListService::filtered_entries].
+///
+/// It is OK to clone this type as much as you need: cloning it is cheap.
+#[derive(Clone, Debug)]
+pub struct RoomListFilterController {
+    pub(super) settings: Arc<StdRwLock<Observable<FilterSettings>>>,
+}
+
+impl RoomListFilterController {
+    pub(super) fn new(settings: FilterSettings) -> Self {
+        Self { settings: Arc::new(StdRwLock::new(Observable::new(settings))) }
+    }
+
+    /// Replace the filter.
+    pub fn set_filter(&self, filter: RoomListFilter) {
+        Observable::update(&mut self.settings.write().unwrap(), |settings| {
+            settings.filter = filter;
+        });
+    }
+
+    /// Replace the sorts.
+    pub fn set_sorts(&self, sorts: Vec<RoomListSort>) {
+        Observable::update(&mut self.settings.write().unwrap(), |settings| {
+            settings.sorts = sorts;
+        });
+    }
+}
+
+/// The entries of a list, filtered and sorted.
+#[derive(Debug)]
+struct FilteredEntries {
+    sliding_sync: SlidingSync,
+    /// The entries of the list, in the order of the server.
+    source: Vector<RoomListEntry>,
+    /// The information about the rooms of `source`.
+    items: HashMap<OwnedRoomId, RoomListItem>,
+    /// The spaces of the rooms of `source`, according to their own
+    /// `m.space.parent` state events.
+    space_parents: HashMap<OwnedRoomId, BTreeSet<OwnedRoomId>>,
+    /// The filtered and sorted entries.
+    entries: Vector<RoomListEntry>,
+}
+
+impl FilteredEntries {
+    /// Apply an update of the list, and return the rooms that were updated.
+    fn apply_source_diff(&mut self, diff: VectorDiff<RoomListEntry>) -> HashSet<OwnedRoomId> {
+        let mut updated = Vec::new();
+
+        match diff {
+            VectorDiff::Append { values } => {
+                updated.extend(values.iter().cloned());
+                self.source.append(values);
+            }
+            VectorDiff::Clear => self.source.clear(),
+            VectorDiff::PushFront { value } => {
+                updated.push(value.clone());
+                self.source.push_front(value);
+            }
+            VectorDiff::PushBack { value } => {
+                updated.push(value.clone());
+                self.source.push_back(value);
+            }
+            VectorDiff::PopFront => {
+                self.source.pop_front();
+            }
+            VectorDiff::PopBack => {
+                self.source.pop_back();
+            }
+            VectorDiff::Insert { index, value } => {
+                updated.push(value.clone());
+                self.source.insert(index, value);
+            }
+            VectorDiff::Set { index, value } => {
+                updated.push(value.clone());
+                self.source.set(index, value);
+            }
+            VectorDiff::Remove { index } => {
+                self.source.remove(index);
+            }
+            VectorDiff::Reset { values } => {
+                updated.extend(values.iter().cloned());
+                self.source = values;
+            }
+        }
+
+        updated.iter().filter_map(|entry| entry.as_room_id().map(ToOwned::to_owned)).collect()
+    }
+
+    /// Get the rooms of the list that were updated by a response.
+    ///
+    /// If the global account data was updated, the fields of all the rooms
+    /// that depend on it are added to `partial_reload`.
+    fn rooms_in_update(
+        &self,
+        update: RoomsUpdate,
+        partial_reload: &mut Option<PartialReload>,
+    ) -> HashSet<OwnedRoomId> {
+        if update.global_account_data {
+            partial_reload.get_or_insert(PartialReload::DirectMessage);
+        }
+
+        update.rooms.into_iter().filter(|room_id| self.items.contains_key(room_id)).collect()
+    }
+
+    /// The IDs of the rooms of the list.
+    fn room_ids(&self) -> HashSet<OwnedRoomId> {
+        self.source.iter().filter_map(|entry| entry.as_room_id().map(ToOwned::to_owned)).collect()
+    }
+
+    /// Load the information about the given rooms, and forget about the rooms
+    /// that are no longer in the list.
+    async fn load_items(&mut self, room_ids: &HashSet<OwnedRoomId>) {
+        for room_id in room_ids {
+            let item = RoomListItem::load(&self.sliding_sync, room_id).await;
+            self.space_parents.insert(room_id.clone(), item.spaces.clone());
+            self.items.insert(room_id.clone(), item);
+        }
+
+        let in_source: HashSet<&RoomId> =
+            self.source.iter().filter_map(RoomListEntry::as_room_id).collect();
+        self.items.retain(|room_id, _| in_source.contains(&**room_id));
+        self.space_parents.retain(|room_id, _| in_source.contains(&**room_id));
+
+        self.link_spaces();
+    }
+
+    /// Reload the given fields of all the rooms, except the `skipped` ones,
+    /// and return the rooms that changed.
+    async fn reload_items(
+        &mut self,
+        fields: PartialReload,
+        skipped: &HashSet<OwnedRoomId>,
+    ) -> HashSet<OwnedRoomId> {
+        let mut changed = HashSet::new();
+
+        for (room_id, item) in &mut self.items {
+            if skipped.contains(room_id) {
+                continue;
+            }
+
+            let previous = item.clone();
+            item.reload(&self.sliding_sync, fields).await;
+
+            if *item != previous {
+                changed.insert(room_id.clone());
+            }
+        }
+
+        changed
+    }
+
+    /// Add the spaces of the list to the spaces of their children, since a
+    /// child doesn't necessarily have an `m.space.parent` state event.
+    fn link_spaces(&mut self) {
+        let mut spaces_of_children: HashMap<OwnedRoomId, BTreeSet<OwnedRoomId>> = HashMap::new();
+
+        for item in self.items.values() {
+            for child in &item.children {
+                spaces_of_children.entry(child.clone()).or_default().insert(item.room_id.clone());
+            }
+        }
+
+        for (room_id, item) in &mut self.items {
+            item.spaces = self.space_parents.get(room_id).cloned().unwrap_or_default();
+            item.spaces.extend(spaces_of_children.remove(room_id).unwrap_or_default());
+        }
+    }
+
+    /// Compute the filtered and sorted entries.
+    fn compute(&self, settings: &FilterSettings) -> Vec<RoomListEntry> {
+        let mut seen = HashSet::new();
+        let mut entries: Vec<_> = self
+            .source
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                let room_id = entry.as_room_id()?;
+
+                // Ignore the duplicates, the server may send a room twice while
+                // it is moved.
+                if !seen.insert(room_id) {
+                    return None;
+                }
+
+                let item = self.items.get(room_id)?;
+
+                settings.filter.matches(item).then_some((index, entry, item))
+            })
+            .collect();
+
+        // The entries are already in the order of the server.
+        if !settings.sorts.is_empty() {
+            entries.sort_by(|(left_index, _, left), (right_index, _, right)| {
+                settings
+                    .sorts
+                    .iter()
+                    .map(|sort| sort.compare(left, right))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+                    .then(left_index.cmp(right_index))
+            });
+        }
+
+        entries.into_iter().map(|(_, entry, _)| entry.clone()).collect()
+    }
+
+    /// Replace the filtered entries by the given ones, and return the diffs
+    /// to go from the former to the latter.
+    ///
+    /// The entries of the `updated` rooms are set again even if they didn't
+    /// move, so the consumers know they changed.
+    fn update(
+        &mut self,
+        target: Vec<RoomListEntry>,
+        updated: &HashSet<OwnedRoomId>,
+    ) -> Vec<VectorDiff<RoomListEntry>> {
+        let mut diffs = Vec::new();
+
+        // Keep the longest sequence of entries that are already in the order
+        // of the target, so as few entries as possible are moved.
+        let kept = {
+            let target_indices: HashMap<&RoomId, usize> = target
+                .iter()
+                .enumerate()
+                .filter_map(|(index, entry)| Some((entry.as_room_id()?, index)))
+                .collect();
+            let indices: Vec<_> = self
+                .entries
+                .iter()
+                .map(|entry| {
+                    entry.as_room_id().and_then(|room_id| target_indices.get(room_id).copied())
+                })
+                .collect();
+
+            longest_increasing_subsequence(&indices)
+        };
+
+        // Remove the other entries, from the end so the indices of the next
+        // ones don't change.
+        for index in (0..self.entries.len()).rev() {
+            if !kept[index] {
+                self.entries.remove(index);
+                diffs.push(VectorDiff::Remove { index });
+            }
+        }
+
+        // Now that the entries are a subsequence of the target, insert the
+        // missing entries at their index.
+        for (index, entry) in target.into_iter().enumerate() {
+            let room_id = entry.as_room_id();
+
+            if self.entries.get(index).map(RoomListEntry::as_room_id) == Some(room_id) {
+                let is_updated = room_id.map_or(false, |room_id| updated.contains(room_id));
+
+                if is_updated || !same_entry(&self.entries[index], &entry) {
+                    self.entries.set(index, entry.clone());
+                    diffs.push(VectorDiff::Set { index, value: entry });
+                }
+
+                continue;
+            }
+
+            self.entries.insert(index, entry.clone());
+            diffs.push(VectorDiff::Insert { index, value: entry });
+        }
+
+        diffs
+    }
+}
+
+/// Find the longest strictly increasing subsequence of `values`, ignoring the
+/// `None`s, and return whether each value is part of it.
+fn longest_increasing_subsequence(values: &[Option<usize>]) -> Vec<bool> {
+    // The positions of the last values of the best subsequence of each length.
+    let mut tails: Vec<usize> = Vec::new();
+    // The position of the value before each value in its subsequence.
+    let mut previous = vec![None; values.len()];
+
+    for (position, value) in values.iter().enumerate() {
+        let Some(value) = value else { continue };
+        let length = tails.partition_point(|tail| values[*tail] < Some(*value));
+
+        previous[position] = length.checked_sub(1).map(|length| tails[length]);
+
+        if length == tails.len() {
+            tails.push(position);
+        } else {
+            tails[length] = position;
+        }
+    }
+
+    let mut in_subsequence = vec![false; values.len()];
+    let mut position = tails.last().copied();
+
+    while let Some(current) = position {
+        in_subsequence[current] = true;
+        position = previous[current];
+    }
+
+    in_subsequence
+}
+
+fn same_entry(left: &RoomListEntry, right: &RoomListEntry) -> bool {
+    match (left, right) {
+        (RoomListEntry::Empty, RoomListEntry::Empty) => true,
+        (RoomListEntry::Filled(left), RoomListEntry::Filled(right))
+        | (RoomListEntry::Invalidated(left), RoomListEntry::Invalidated(right)) => left == right,
+        _ => false,
+    }
+}
+
+/// Filter and sort the entries of the given list with the settings of a
+/// [`RoomListFilterController`].
+///
+/// Returns the initial entries and a stream of their updates.
+pub(super) async fn filtered_entries(
+    sliding_sync: SlidingSync,
+    list: SlidingSyncList,
+    settings: Arc<StdRwLock<Observable<FilterSettings>>>,
+) -> (Vector<RoomListEntry>, impl Stream<Item = VectorDiff<RoomListEntry>>) {
+    let settings_stream = Observable::subscribe(&settings.read().unwrap());
+    let mut rooms_updates = sliding_sync.subscribe_to_rooms_updates();
+    let (source, source_stream) = list.room_list_with_stream();
+
+    let mut filtered = FilteredEntries {
+        sliding_sync,
+        source,
+        items: HashMap::new(),
+        space_parents: HashMap::new(),
+        entries: Vector::new(),
+    };
+    let room_ids = filtered.room_ids();
+    filtered.load_items(&room_ids).await;
+
+    let mut current_settings = (**settings.read().unwrap()).clone();
+    filtered.entries = filtered.compute(&current_settings).into_iter().collect();
+    let entries = filtered.entries.clone();
+
+    let stream = stream! {
+        pin_mut!(source_stream);
+        pin_mut!(settings_stream);
+
+        loop {
+            // The fields to reload for all the rooms, when the rooms that
+            // changed are unknown.
+            let mut partial_reload = None;
+
+            let mut updated = select! {
+                Some(diff) = source_stream.next() => filtered.apply_source_diff(diff),
+                Some(settings) = settings_stream.next() => {
+                    current_settings = settings;
+
+                    HashSet::new()
+                }
+                update = rooms_updates.recv() => match update {
+                    Ok(update) => filtered.rooms_in_update(update, &mut partial_reload),
+                    // Some updates were missed. Loading the spaces of every
+                    // room is too expensive, they are reloaded with the next
+                    // update of each room.
+                    Err(RecvError::Lagged(_)) => {
+                        partial_reload = Some(PartialReload::AllButSpaces);
+
+                        HashSet::new()
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                else => break,
+            };
+
+            // Apply all the pending updates at once, so the entries are only
+            // computed once per response.
+            loop {
+                if let Some(Some(diff)) = source_stream.next().now_or_never() {
+                    updated.extend(filtered.apply_source_diff(diff));
+                    continue;
+                }
+
+                match rooms_updates.try_recv() {
+                    Ok(update) => {
+                        updated.extend(filtered.rooms_in_update(update, &mut partial_reload));
+                    }
+                    Err(TryRecvError::Lagged(_)) => {
+                        partial_reload = Some(PartialReload::AllButSpaces);
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+
+            filtered.load_items(&updated).await;
+
+            if let Some(fields) = partial_reload {
+                let changed = filtered.reload_items(fields, &updated).await;
+                updated.extend(changed);
+            }
+
+            let target = filtered.compute(&current_settings);
+
+            for diff in filtered.update(target, &updated) {
+                yield diff;
+            }
+        }
+    };
+
+    (entries, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use imbl::vector;
+    use ruma::{room_id, OwnedRoomId};
+    use wiremock::MockServer;
+
+    use super::*;
+    use crate::test_utils::logged_in_client;
+
+    fn item(room_id: &str, name: Option<&str>) -> RoomListItem {
+        RoomListItem {
+            room_id: OwnedRoomId::try_from(room_id).unwrap(),
+            name: name.map(ToOwned::to_owned),
+            is_dm: false,
+            is_favourite: false,
+            notification_count: 0,
+            highlight_count: 0,
+            spaces: BTreeSet::new(),
+            children: BTreeSet::new(),
+        }
+    }
+
+    fn filled(room_id: &str) -> RoomListEntry {
+        RoomListEntry::Filled(OwnedRoomId::try_from(room_id).unwrap())
+    }
+
+    fn apply(entries: &mut Vector<RoomListEntry>, diffs: Vec<VectorDiff<RoomListEntry>>) {
+        for diff in diffs {
+            match diff {
+                VectorDiff::Insert { index, value } => entries.insert(index, value),
+                VectorDiff::Set { index, value } => {
+                    entries.set(index, value);
+                }
+                VectorDiff::Remove { index } => {
+                    entries.remove(index);
+                }
+                diff => panic!("unexpected diff {diff:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzzy_match() {
+        assert!(fuzzy_match("", "Matrix"));
+        assert!(fuzzy_match("mtx", "Matrix"));
+        assert!(fuzzy_match("MAT rix", "matrix"));
+        assert!(!fuzzy_match("xm", "Matrix"));
+        assert!(!fuzzy_match("matrixx", "Matrix"));
+    }
+
+    #[test]
+    fn test_room_list_filter_matches() {
+        let mut dm = item("!dm:bar.org", Some("Alice"));
+        dm.is_dm = true;
+        dm.notification_count = 2;
+
+        let mut favourite = item("!fav:bar.org", Some("Matrix HQ"));
+        favourite.is_favourite = true;
+        favourite.spaces.insert(room_id!("!space:bar.org").to_owned());
+
+        assert!(RoomListFilter::All.matches(&dm));
+        assert!(RoomListFilter::Unread.matches(&dm));
+        assert!(!RoomListFilter::Unread.matches(&favourite));
+        assert!(RoomListFilter::DirectMessage.matches(&dm));
+        assert!(RoomListFilter::Favourite.matches(&favourite));
+        assert!(RoomListFilter::InSpace(room_id!("!space:bar.org").to_owned()).matches(&favourite));
+        assert!(!RoomListFilter::InSpace(room_id!("!space:bar.org").to_owned()).matches(&dm));
+        assert!(RoomListFilter::FuzzyName("mhq".to_owned()).matches(&favourite));
+
+        let filter = RoomListFilter::DirectMessage.or(RoomListFilter::Favourite);
+        assert!(filter.matches(&dm));
+        assert!(filter.matches(&favourite));
+
+        let filter = RoomListFilter::DirectMessage.and(!RoomListFilter::Unread);
+        assert!(!filter.matches(&dm));
+        assert!(!filter.matches(&favourite));
+    }
+
+    #[test]
+    fn test_longest_increasing_subsequence() {
+        assert!(longest_increasing_subsequence(&[]).is_empty());
+        assert_eq!(
+            longest_increasing_subsequence(&[Some(2), Some(0), Some(1)]),
+            vec![false, true, true]
+        );
+        assert_eq!(
+            longest_increasing_subsequence(&[Some(0), None, Some(3), Some(1), Some(2)]),
+            vec![true, false, false, true, true]
+        );
+        assert_eq!(longest_increasing_subsequence(&[Some(1), Some(0)]), vec![false, true]);
+    }
+
+    #[tokio::test]
+    async fn test_filtered_entries_spaces() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let sliding_sync = client.sliding_sync().await.build().await.unwrap();
+        let space_id = room_id!("!space:bar.org");
+
+        let mut space = item("!space:bar.org", Some("Space"));
+        space.children.insert(room_id!("!a:bar.org").to_owned());
+        let mut b = item("!b:bar.org", Some("Bob"));
+        b.spaces.insert(space_id.to_owned());
+
+        let mut filtered = FilteredEntries {
+            sliding_sync,
+            source: vector![filled("!space:bar.org"), filled("!a:bar.org"), filled("!b:bar.org")],
+            items: HashMap::from([
+                (space_id.to_owned(), space),
+                (room_id!("!a:bar.org").to_owned(), item("!a:bar.org", Some("Alice"))),
+                (room_id!("!b:bar.org").to_owned(), b.clone()),
+            ]),
+            space_parents: HashMap::from([(room_id!("!b:bar.org").to_owned(), b.spaces)]),
+            entries: Vector::new(),
+        };
+        filtered.link_spaces();
+
+        // The child of the space and the room with a parent are both in it.
+        let settings = FilterSettings {
+            filter: RoomListFilter::InSpace(space_id.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(filtered.compute(&settings), vec![filled("!a:bar.org"), filled("!b:bar.org")]);
+
+        // Only the rooms of the list are updated.
+        let mut partial_reload = None;
+        let update = RoomsUpdate {
+            rooms: vec![room_id!("!a:bar.org").to_owned(), room_id!("!c:bar.org").to_owned()],
+            global_account_data: false,
+        };
+        assert_eq!(
+            filtered.rooms_in_update(update, &mut partial_reload),
+            HashSet::from([room_id!("!a:bar.org").to_owned()])
+        );
+        assert_eq!(partial_reload, None);
+
+        // The global account data only changes whether the rooms are direct
+        // messages.
+        let update = RoomsUpdate { rooms: Vec::new(), global_account_data: true };
+        assert!(filtered.rooms_in_update(update, &mut partial_reload).is_empty());
+        assert_eq!(partial_reload, Some(PartialReload::DirectMessage));
+
+        // Only the rooms whose direct message state changed are updated, and
+        // their spaces are kept.
+        let changed = filtered.reload_items(PartialReload::DirectMessage, &HashSet::new()).await;
+        assert!(changed.is_empty());
+        assert_eq!(filtered.compute(&settings), vec![filled("!a:bar.org"), filled("!b:bar.org")]);
+    }
+
+    #[tokio::test]
+    async fn test_filtered_entries_diffs() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let sliding_sync = client.sliding_sync().await.build().await.unwrap();
+
+        let mut favourite = item("!c:bar.org", Some("Charlie"));
+        favourite.is_favourite = true;
+
+        let mut filtered = FilteredEntries {
+            sliding_sync,
+            source: vector![filled("!a:bar.org"), filled("!b:bar.org"), filled("!c:bar.org")],
+            items: HashMap::from([
+                (room_id!("!a:bar.org").to_owned(), item("!a:bar.org", Some("Alice"))),
+                (room_id!("!b:bar.org").to_owned(), item("!b:bar.org", Some("Bob"))),
+                (room_id!("!c:bar.org").to_owned(), favourite),
+            ]),
+            space_parents: HashMap::new(),
+            entries: Vector::new(),
+        };
+        let mut entries = Vector::new();
+
+        let mut settings = FilterSettings::default();
+        let target = filtered.compute(&settings);
+        apply(&mut entries, filtered.update(target.clone(), &HashSet::new()));
+        assert_eq!(entries, target.into_iter().collect::<Vector<_>>());
+        assert_eq!(entries, filtered.source);
+
+        // Move the favourite first.
+        settings.sorts = vec![RoomListSort::Favourite];
+        let target = filtered.compute(&settings);
+        assert_eq!(target, vec![filled("!c:bar.org"), filled("!a:bar.org"), filled("!b:bar.org")]);
+        apply(&mut entries, filtered.update(target.clone(), &HashSet::new()));
+        assert_eq!(entries, target.into_iter().collect::<Vector<_>>());
+
+        // Filter some rooms out.
+        settings.filter = RoomListFilter::FuzzyName("b".to_owned()).or(RoomListFilter::Favourite);
+        let target = filtered.compute(&settings);
+        assert_eq!(target, vec![filled("!c:bar.org"), filled("!b:bar.org")]);
+        apply(&mut entries, filtered.update(target.clone(), &HashSet::new()));
+        assert_eq!(entries, target.into_iter().collect::<Vector<_>>());
+
+        // An update of the list is translated.
+        let updated = filtered.apply_source_diff(VectorDiff::Remove { index: 2 });
+        assert!(updated.is_empty());
+        filtered.items.remove(room_id!("!c:bar.org"));
+        let target = filtered.compute(&settings);
+        let diffs = filtered.update(target, &updated);
+        assert_eq!(diffs.len(), 1);
+        apply(&mut entries, diffs);
+        assert_eq!(entries, vector![filled("!b:bar.org")]);
+
+        // An updated room is set again.
+        let updated =
+            filtered.apply_source_diff(VectorDiff::Set { index: 1, value: filled("!b:bar.org") });
+        let target = filtered.compute(&settings);
+        let diffs = filtered.update(target, &updated);
+        assert_eq!(diffs.len(), 1);
+        assert!(matches!(&diffs[0], VectorDiff::Set { index: 0, .. }));
+    }
+}
@@ -0,0 +1,538 @@
+//! A room list built on top of Sliding Sync.
+//!
+//! See [`RoomListService`] to learn more.
+
+use std::{
+    ops::RangeInclusive,
+    sync::{Arc, RwLock as StdRwLock},
+};
+
+use async_stream::stream;
+use eyeball::unique::Observable;
+use eyeball_im::VectorDiff;
+use futures_core::stream::Stream;
+use futures_util::{pin_mut, StreamExt};
+use imbl::Vector;
+use ruma::{events::StateEventType, RoomId};
+use tracing::debug;
+
+use super::{
+    room_list_filter::{self, FilterSettings},
+    Bound, Error, RoomListEntry, RoomListFilter, RoomListFilterController, RoomListSort,
+    SlidingSync, SlidingSyncList, SlidingSyncMode, SlidingSyncRoom, SlidingSyncState,
+};
+use crate::{Client, Result};
+
+/// The name of the list containing all the rooms.
+pub const ALL_ROOMS_LIST_NAME: &str = "all_rooms";
+
+/// The name of the list containing the rooms visible to the user.
+pub const VISIBLE_ROOMS_LIST_NAME: &str = "visible_rooms";
+
+/// The storage key of the Sliding Sync instance of the [`RoomListService`].
+const STORAGE_KEY: &str = "room-list-service";
+
+/// The number of rooms loaded by each request of the
+/// [`ALL_ROOMS_LIST_NAME`] list, including the first one, which is meant to
+/// fill the first screen.
+const ALL_ROOMS_BATCH_SIZE: u32 = 20;
+
+/// The number of timeline events loaded for the rooms of the
+/// [`VISIBLE_ROOMS_LIST_NAME`] list.
+const VISIBLE_ROOMS_TIMELINE_LIMIT: Bound = 20;
+
+/// The state of a [`RoomListService`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum RoomListServiceState {
+    /// Nothing has been synced yet.
+    #[default]
+    Init,
+    /// The first rooms, enough to fill a screen, have been loaded. The visible
+    /// rooms are loaded from now on.
+    FirstRooms,
+    /// The rest of the rooms are being loaded in the background.
+    AllRooms,
+    /// All the rooms have been loaded, only updates are received.
+    CarryOn,
+    /// The last sync has failed. The next successful sync leaves this state.
+    Error,
+    /// The sync has stopped.
+    Terminated,
+}
+
+/// A room list built on top of [`SlidingSync`], with the lists that every
+/// client needs.
+///
+/// It manages two lists:
+///
+/// - [`ALL_ROOMS_LIST_NAME`], in [`SlidingSyncMode::Growing`] mode, which
+///   loads all the rooms in the background, with only the latest event of
+///   their timeline. Its first request only loads enough rooms to fill the
+///   first screen, so they can be shown quickly.
+/// - [`VISIBLE_ROOMS_LIST_NAME`], in [`SlidingSyncMode::Selective`] mode, which
+///   loads more timeline events for the rooms the user is looking at, see
+///   [`Self::set_visible_range`]. It is only added once the first rooms have
+///   been loaded, to keep the first request small.
+///
+/// The progress of the sync is described by the [`RoomListServiceState`],
+/// which is updated after every response of the server while
+/// [`Self::sync`] is polled.
+///
+/// It is OK to clone this type as much as you need: cloning it is cheap.
+#[derive(Clone, Debug)]
+pub struct RoomListService {
+    sliding_sync: SlidingSync,
+    state: Arc<StdRwLock<Observable<RoomListServiceState>>>,
+    /// The range set with [`Self::set_visible_range`] before the
+    /// [`VISIBLE_ROOMS_LIST_NAME`] list was added.
+    pending_visible_range: Arc<StdRwLock<Option<RangeInclusive<Bound>>>>,
+}
+
+impl RoomListService {
+    /// Create a new `RoomListService`.
+    ///
+    /// The sliding sync proxy of the client is used if there is one. The state
+    /// of the rooms is cached and restored from the store of the client.
+    pub async fn new(client: Client) -> Result<Self> {
+        let mut builder = client
+            .sliding_sync()
+            .await
+            .storage_key(Some(STORAGE_KEY.to_owned()))
+            .with_common_extensions()
+            .add_list(
+                SlidingSyncList::builder(ALL_ROOMS_LIST_NAME)
+                    .sync_mode(SlidingSyncMode::Growing)
+                    .full_sync_batch_size(ALL_ROOMS_BATCH_SIZE)
+                    .timeline_limit(1)
+                    .required_state(vec![
+                        (StateEventType::RoomEncryption, "".to_owned()),
+                        (StateEventType::RoomTombstone, "".to_owned()),
+                        (StateEventType::SpaceParent, "*".to_owned()),
+                        (StateEventType::SpaceChild, "*".to_owned()),
+                    ]),
+            );
+
+        if let Some(proxy) = client.sliding_sync_proxy().await {
+            builder = builder.homeserver(proxy);
+        }
+
+        Ok(Self {
+            sliding_sync: builder.build().await?,
+            state: Default::default(),
+            pending_visible_range: Default::default(),
+        })
+    }
+
+    /// Get the underlying [`SlidingSync`] instance, e.g. to subscribe to
+    /// rooms.
+    pub fn sliding_sync(&self) -> &SlidingSync {
+        &self.sliding_sync
+    }
+
+    /// Get the current state.
+    pub fn state(&self) -> RoomListServiceState {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Get a stream of the state.
+    pub fn state_stream(&self) -> impl Stream<Item = RoomListServiceState> {
+        Observable::subscribe(&self.state.read().unwrap())
+    }
+
+    /// Run the sync.
+    ///
+    /// The stream yields an item after every response of the server, and ends
+    /// when the sync stops, in which case the state becomes
+    /// [`RoomListServiceState::Terminated`]. An error doesn't stop the sync,
+    /// but the state is [`RoomListServiceState::Error`] until the next
+    /// successful response.
+    pub fn sync(&self) -> impl Stream<Item = Result<()>> + '_ {
+        stream! {
+            let sync = self.sliding_sync.stream();
+            pin_mut!(sync);
+
+            while let Some(update_summary) = sync.next().await {
+                match update_summary {
+                    Ok(_) => {
+                        yield self.advance();
+                    }
+
+                    Err(error) => {
+                        self.set_state(RoomListServiceState::Error);
+
+                        yield Err(error);
+                    }
+                }
+            }
+
+            self.set_state(RoomListServiceState::Terminated);
+        }
+    }
+
+    /// Get all the room entries, and a stream of their updates.
+    ///
+    /// The entries are in the order of the [`ALL_ROOMS_LIST_NAME`] list, and
+    /// are [`RoomListEntry::Empty`] until their room is loaded.
+    pub fn entries(
+        &self,
+    ) -> (Vector<RoomListEntry>, impl Stream<Item = VectorDiff<RoomListEntry>>) {
+        self.sliding_sync
+            .on_list(ALL_ROOMS_LIST_NAME, |list| list.room_list_with_stream())
+            .expect("the list of all the rooms must exist")
+    }
+
+    /// Get the room entries filtered and sorted on the client side, and a
+    /// stream of their updates.
+    ///
+    /// Only the entries of the loaded rooms are kept, in the order of the
+    /// [`ALL_ROOMS_LIST_NAME`] list unless `sorts` are given. The filter and
+    /// the sorts can be changed with the returned [`RoomListFilterController`],
+    /// which updates the stream without sending a new request.
+    ///
+    /// The entries are also updated when the data of their room, like its
+    /// name, its notifications or its tags, is updated by a response.
+    pub async fn filtered_entries(
+        &self,
+        filter: RoomListFilter,
+        sorts: Vec<RoomListSort>,
+    ) -> (
+        Vector<RoomListEntry>,
+        impl Stream<Item = VectorDiff<RoomListEntry>>,
+        RoomListFilterController,
+    ) {
+        let list = self
+            .sliding_sync
+            .on_list(ALL_ROOMS_LIST_NAME, |list| list.clone())
+            .expect("the list of all the rooms must exist");
+
+        let controller = RoomListFilterController::new(FilterSettings { filter, sorts });
+        let (entries, entries_stream) = room_list_filter::filtered_entries(
+            self.sliding_sync.clone(),
+            list,
+            controller.settings.clone(),
+        )
+        .await;
+
+        (entries, entries_stream, controller)
+    }
+
+    /// Get the room with the given ID, if it has been loaded.
+    pub fn room(&self, room_id: &RoomId) -> Option<SlidingSyncRoom> {
+        self.sliding_sync.get_room(room_id)
+    }
+
+    /// Set the range of the entries that are visible to the user, for example
+    /// when the room list is scrolled.
+    ///
+    /// More timeline events are loaded for these rooms. If the first rooms
+    /// haven't been loaded yet, the range is used once they are.
+    pub fn set_visible_range(&self, range: RangeInclusive<Bound>) -> Result<(), Error> {
+        if *range.start() > *range.end() {
+            return Err(Error::InvalidRange { start: *range.start(), end: *range.end() });
+        }
+
+        let list = {
+            // Hold the lock so the list can't be added in the meantime.
+            let mut pending_visible_range = self.pending_visible_range.write().unwrap();
+
+            match self.sliding_sync.on_list(VISIBLE_ROOMS_LIST_NAME, |list| list.clone()) {
+                Some(list) => list,
+                None => {
+                    *pending_visible_range = Some(range);
+
+                    return Ok(());
+                }
+            }
+        };
+
+        // The lock is released before the sync loop is restarted, since the
+        // sync loop needs it to handle the responses.
+        list.set_range(range)
+    }
+
+    /// Move to the next state after a successful response.
+    fn advance(&self) -> Result<()> {
+        let next_state = {
+            let mut pending_visible_range = self.pending_visible_range.write().unwrap();
+
+            if self.sliding_sync.on_list(VISIBLE_ROOMS_LIST_NAME, |_| ()).is_none() {
+                let visible_rooms = SlidingSyncList::builder(VISIBLE_ROOMS_LIST_NAME)
+                    .sync_mode(SlidingSyncMode::Selective)
+                    .timeline_limit(VISIBLE_ROOMS_TIMELINE_LIMIT)
+                    .ranges(pending_visible_range.take().into_iter().collect());
+
+                self.sliding_sync.add_list(visible_rooms)?;
+
+                RoomListServiceState::FirstRooms
+            } else if self.sliding_sync.on_list(ALL_ROOMS_LIST_NAME, |list| list.state())
+                == Some(SlidingSyncState::FullyLoaded)
+            {
+                RoomListServiceState::CarryOn
+            } else {
+                RoomListServiceState::AllRooms
+            }
+        };
+
+        self.set_state(next_state);
+
+        Ok(())
+    }
+
+    fn set_state(&self, state: RoomListServiceState) {
+        let mut lock = self.state.write().unwrap();
+
+        if **lock != state {
+            debug!(from = ?**lock, to = ?state, "Room list service state changed");
+
+            Observable::set(&mut lock, state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use futures_util::{pin_mut, StreamExt};
+    use imbl::vector;
+    use ruma::room_id;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::test_utils::logged_in_client;
+
+    async fn mock_sync(
+        server: &MockServer,
+        request: serde_json::Value,
+        response: serde_json::Value,
+    ) -> wiremock::MockGuard {
+        Mock::given(method("POST"))
+            .and(path("/_matrix/client/unstable/org.matrix.msc3575/sync"))
+            .and(body_partial_json(request))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .mount_as_scoped(server)
+            .await
+    }
+
+    #[tokio::test]
+    async fn room_list_service_states() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let room_list = RoomListService::new(client).await?;
+        assert_eq!(room_list.state(), RoomListServiceState::Init);
+
+        // The visible list doesn't exist yet, the range is kept for later.
+        room_list.set_visible_range(0..=9)?;
+        assert!(room_list.sliding_sync().on_list(VISIBLE_ROOMS_LIST_NAME, |_| ()).is_none());
+
+        let (entries, mut entries_stream) = room_list.entries();
+        assert!(entries.is_empty());
+
+        let sync = room_list.sync();
+        pin_mut!(sync);
+
+        // The first request only loads the first rooms.
+        {
+            let _guard = mock_sync(
+                &server,
+                json!({
+                    "lists": {
+                        "all_rooms": { "ranges": [[0, 19]], "timeline_limit": 1 },
+                    },
+                }),
+                json!({
+                    "pos": "0",
+                    "lists": {
+                        "all_rooms": {
+                            "count": 100,
+                            "ops": [{
+                                "op": "SYNC",
+                                "range": [0, 1],
+                                "room_ids": ["!r0:bar.org", "!r1:bar.org"],
+                            }],
+                        },
+                    },
+                    "rooms": {},
+                }),
+            )
+            .await;
+
+            sync.next().await.unwrap()?;
+        }
+
+        assert_eq!(room_list.state(), RoomListServiceState::FirstRooms);
+        assert!(entries_stream.next().await.is_some());
+
+        let (entries, _) = room_list.entries();
+        assert_eq!(entries.len(), 100);
+        assert_eq!(entries[0], RoomListEntry::Filled(room_id!("!r0:bar.org").to_owned()));
+        assert_eq!(entries[1], RoomListEntry::Filled(room_id!("!r1:bar.org").to_owned()));
+
+        // The visible list is added with the pending range.
+        {
+            let _guard = mock_sync(
+                &server,
+                json!({
+                    "lists": {
+                        "visible_rooms": { "ranges": [[0, 9]], "timeline_limit": 20 },
+                    },
+                }),
+                json!({
+                    "pos": "1",
+                    "lists": {
+                        "all_rooms": { "count": 100 },
+                        "visible_rooms": { "count": 100 },
+                    },
+                    "rooms": {},
+                }),
+            )
+            .await;
+
+            sync.next().await.unwrap()?;
+        }
+
+        assert_eq!(room_list.state(), RoomListServiceState::AllRooms);
+
+        room_list.set_visible_range(10..=19)?;
+
+        // Once all the rooms are loaded, only updates are received.
+        {
+            let _guard = mock_sync(
+                &server,
+                json!({
+                    "lists": {
+                        "visible_rooms": { "ranges": [[10, 19]] },
+                    },
+                }),
+                json!({
+                    "pos": "2",
+                    "lists": {
+                        "all_rooms": { "count": 40 },
+                        "visible_rooms": { "count": 40 },
+                    },
+                    "rooms": {},
+                }),
+            )
+            .await;
+
+            sync.next().await.unwrap()?;
+        }
+
+        assert_eq!(room_list.state(), RoomListServiceState::CarryOn);
+
+        // Without any mock, the request fails.
+        assert_matches!(sync.next().await, Some(Err(_)));
+        assert_eq!(room_list.state(), RoomListServiceState::Error);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn room_list_service_invalid_visible_range() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let room_list = RoomListService::new(client).await?;
+
+        assert_eq!(
+            room_list.set_visible_range(9..=0),
+            Err(Error::InvalidRange { start: 9, end: 0 })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn room_list_service_filtered_entries() -> Result<()> {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let room_list = RoomListService::new(client).await?;
+        let sync = room_list.sync();
+        pin_mut!(sync);
+
+        {
+            let _guard = mock_sync(
+                &server,
+                json!({}),
+                json!({
+                    "pos": "0",
+                    "lists": {
+                        "all_rooms": {
+                            "count": 3,
+                            "ops": [{
+                                "op": "SYNC",
+                                "range": [0, 2],
+                                "room_ids": ["!r0:bar.org", "!r1:bar.org", "!r2:bar.org"],
+                            }],
+                        },
+                    },
+                    "rooms": {
+                        "!r0:bar.org": { "name": "Alice" },
+                        "!r1:bar.org": { "name": "Bob" },
+                        "!r2:bar.org": { "name": "Alicia" },
+                    },
+                }),
+            )
+            .await;
+
+            sync.next().await.unwrap()?;
+        }
+
+        let (entries, entries_stream, controller) = room_list
+            .filtered_entries(RoomListFilter::FuzzyName("ali".to_owned()), Vec::new())
+            .await;
+        pin_mut!(entries_stream);
+
+        assert_eq!(
+            entries,
+            vector![
+                RoomListEntry::Filled(room_id!("!r0:bar.org").to_owned()),
+                RoomListEntry::Filled(room_id!("!r2:bar.org").to_owned()),
+            ]
+        );
+
+        // Changing the filter doesn't need a new request.
+        controller.set_filter(RoomListFilter::FuzzyName("bob".to_owned()));
+
+        assert_matches!(entries_stream.next().await, Some(VectorDiff::Remove { index: 1 }));
+        assert_matches!(entries_stream.next().await, Some(VectorDiff::Remove { index: 0 }));
+        assert_matches!(
+            entries_stream.next().await,
+            Some(VectorDiff::Insert { index: 0, value: RoomListEntry::Filled(room_id) }) => {
+                assert_eq!(room_id, "!r1:bar.org");
+            }
+        );
+
+        // Sort by name.
+        controller.set_filter(RoomListFilter::All);
+        controller.set_sorts(vec![RoomListSort::Name]);
+
+        let mut entries = vector![RoomListEntry::Filled(room_id!("!r1:bar.org").to_owned())];
+
+        while entries.len() < 3 {
+            match entries_stream.next().await.unwrap() {
+                VectorDiff::Insert { index, value } => entries.insert(index, value),
+                VectorDiff::Remove { index } => {
+                    entries.remove(index);
+                }
+                diff => panic!("unexpected diff {diff:?}"),
+            }
+        }
+
+        assert_eq!(
+            entries,
+            vector![
+                RoomListEntry::Filled(room_id!("!r0:bar.org").to_owned()),
+                RoomListEntry::Filled(room_id!("!r2:bar.org").to_owned()),
+                RoomListEntry::Filled(room_id!("!r1:bar.org").to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
+}
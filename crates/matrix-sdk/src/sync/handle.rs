@@ -0,0 +1,309 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A controllable sync loop, see [`Client::start_sync`].
+
+use std::{
+    fmt,
+    sync::{Arc, RwLock as StdRwLock},
+    time::Duration,
+};
+
+use eyeball::{shared::Observable as SharedObservable, Subscriber};
+use futures_core::Stream;
+use futures_util::{
+    future::{select, Either},
+    pin_mut,
+};
+#[cfg(target_arch = "wasm32")]
+use matrix_sdk_common::executor::JoinHandle;
+use matrix_sdk_common::{executor::spawn, instant::Instant};
+use ruma::api::client::error::ErrorKind;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{config::SyncSettings, Client};
+
+/// The delay before retrying after the first failed sync request.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The maximum delay between two retries of a failing sync request.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The state of a sync loop started with [`Client::start_sync`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncState {
+    /// The sync loop hasn't sent its first request yet.
+    Idle,
+    /// The sync loop is fetching what happened since the last persisted sync
+    /// token, either because it just started or because it was resumed.
+    CatchingUp,
+    /// The sync loop is up to date and waiting for new events from the
+    /// server.
+    Running,
+    /// The sync loop is paused, see [`SyncHandle::pause`].
+    Paused,
+    /// The last sync request failed.
+    Error {
+        /// A description of the error.
+        message: String,
+        /// The number of consecutive failed sync requests.
+        attempts: u32,
+        /// How long the loop waits before retrying, `None` if the error isn't
+        /// recoverable and the loop stopped.
+        retry_in: Option<Duration>,
+    },
+    /// The sync loop has been stopped, see [`SyncHandle::stop`].
+    Stopped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SyncCommand {
+    Run,
+    Pause,
+    Stop,
+}
+
+struct SyncHandleInner {
+    state: SharedObservable<SyncState>,
+    command: SharedObservable<SyncCommand>,
+    /// The `next_batch` token of the last response that has been fully
+    /// processed and persisted.
+    last_next_batch: StdRwLock<Option<String>>,
+}
+
+/// A handle to a sync loop running in the background.
+///
+/// Created with [`Client::start_sync`]. Dropping the handle stops the sync
+/// loop once the response that is currently being processed, if any, has been
+/// persisted.
+pub struct SyncHandle {
+    inner: Arc<SyncHandleInner>,
+    task: Option<JoinHandle<()>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for SyncHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncHandle")
+            .field("state", &self.state())
+            .field("last_next_batch", &self.last_next_batch())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SyncHandle {
+    pub(crate) fn start(client: Client, sync_settings: SyncSettings) -> Self {
+        let inner = Arc::new(SyncHandleInner {
+            state: SharedObservable::new(SyncState::Idle),
+            command: SharedObservable::new(SyncCommand::Run),
+            last_next_batch: StdRwLock::new(None),
+        });
+
+        let task = spawn(sync_loop(client, sync_settings, inner.clone()));
+
+        Self { inner, task: Some(task) }
+    }
+
+    /// Get the current state of the sync loop.
+    pub fn state(&self) -> SyncState {
+        self.inner.state.get()
+    }
+
+    /// Get a stream of the states of the sync loop.
+    pub fn state_stream(&self) -> impl Stream<Item = SyncState> {
+        self.inner.state.subscribe()
+    }
+
+    /// Get the `next_batch` token of the last sync response that has been
+    /// persisted in the store by this sync loop.
+    ///
+    /// Returns `None` if no response has been processed yet.
+    pub fn last_next_batch(&self) -> Option<String> {
+        self.inner.last_next_batch.read().unwrap().clone()
+    }
+
+    /// Pause the sync loop.
+    ///
+    /// A sync request that is in flight is cancelled, but a response that is
+    /// being processed is always persisted completely. Does nothing if the
+    /// loop is already paused or stopped.
+    pub fn pause(&self) {
+        self.send_command(SyncCommand::Pause);
+    }
+
+    /// Resume a sync loop paused with [`SyncHandle::pause`].
+    ///
+    /// The loop continues from the last persisted sync token.
+    pub fn resume(&self) {
+        self.send_command(SyncCommand::Run);
+    }
+
+    /// Stop the sync loop and wait for it to finish.
+    ///
+    /// Once this returns, the sync loop doesn't touch the store anymore and
+    /// the last persisted sync token is available through
+    /// [`SyncHandle::last_next_batch`].
+    pub async fn stop(mut self) -> Option<String> {
+        self.inner.command.set(SyncCommand::Stop);
+
+        if let Some(task) = self.task.take() {
+            if task.await.is_err() {
+                error!("The sync loop task panicked");
+            }
+        }
+
+        self.last_next_batch()
+    }
+
+    fn send_command(&self, command: SyncCommand) {
+        // A stopped loop can't be restarted.
+        if self.inner.command.get() != SyncCommand::Stop {
+            self.inner.command.set(command);
+        }
+    }
+}
+
+impl Drop for SyncHandle {
+    fn drop(&mut self) {
+        self.inner.command.set(SyncCommand::Stop);
+
+        // The loop must be able to persist the response it is processing, so
+        // the task is detached rather than cancelled. Dropping a tokio handle
+        // detaches the task, but dropping the handle cancels it on WASM.
+        if let Some(task) = self.task.take() {
+            #[cfg(target_arch = "wasm32")]
+            task.forget();
+            #[cfg(not(target_arch = "wasm32"))]
+            drop(task);
+        }
+    }
+}
+
+/// Wait until a command other than [`SyncCommand::Run`] is received.
+async fn interrupted(commands: &mut Subscriber<SyncCommand>) {
+    while let Some(command) = commands.next().await {
+        if command != SyncCommand::Run {
+            return;
+        }
+    }
+}
+
+async fn sync_loop(client: Client, mut sync_settings: SyncSettings, inner: Arc<SyncHandleInner>) {
+    let mut commands = inner.command.subscribe();
+    let mut catching_up = true;
+    let mut attempts = 0;
+    let mut last_sync_time: Option<Instant> = None;
+
+    if sync_settings.token.is_none() {
+        sync_settings.token = client.sync_token().await;
+    }
+
+    loop {
+        match commands.get() {
+            SyncCommand::Stop => break,
+            SyncCommand::Pause => {
+                debug!("Sync loop paused");
+                inner.state.set(SyncState::Paused);
+
+                while let Some(command) = commands.next().await {
+                    if command != SyncCommand::Pause {
+                        break;
+                    }
+                }
+
+                catching_up = true;
+                continue;
+            }
+            SyncCommand::Run => {}
+        }
+
+        inner.state.set(if catching_up { SyncState::CatchingUp } else { SyncState::Running });
+
+        #[cfg(feature = "e2e-encryption")]
+        if let Err(e) = client.send_outgoing_requests().await {
+            error!(error = ?e, "Error while sending outgoing E2EE requests");
+        }
+
+        // Only the request itself may be interrupted, the response is always
+        // processed completely so the store stays consistent.
+        let response = match client.resolve_filter_profile(&mut sync_settings).await {
+            Ok(()) => {
+                let request = client.send_sync_request(sync_settings.clone());
+                let interrupted = interrupted(&mut commands);
+                pin_mut!(request, interrupted);
+
+                match select(request, interrupted).await {
+                    Either::Left((response, _)) => response.map_err(Into::into),
+                    Either::Right(_) => {
+                        trace!("Sync request interrupted");
+                        continue;
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        let result = match response {
+            Ok(response) => client.handle_sync_request_response(response).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(response) => {
+                trace!(next_batch = response.next_batch, "Sync response persisted");
+
+                sync_settings.token = Some(response.next_batch.clone());
+                *inner.last_next_batch.write().unwrap() = Some(response.next_batch);
+
+                catching_up = false;
+                attempts = 0;
+
+                Client::delay_sync(&mut last_sync_time).await;
+            }
+            Err(e) => {
+                attempts += 1;
+
+                if let Some(ErrorKind::UnknownToken { .. }) = e.client_api_error_kind() {
+                    warn!("The access token is invalid, stopping the sync loop");
+                    inner.state.set(SyncState::Error {
+                        message: e.to_string(),
+                        attempts,
+                        retry_in: None,
+                    });
+                    return;
+                }
+
+                let retry_in = INITIAL_BACKOFF
+                    .checked_mul(2u32.saturating_pow(attempts - 1))
+                    .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+
+                info!(attempts, ?retry_in, "Sync request failed: {e}");
+                inner.state.set(SyncState::Error {
+                    message: e.to_string(),
+                    attempts,
+                    retry_in: Some(retry_in),
+                });
+
+                let sleep = Client::sleep(retry_in);
+                let interrupted = interrupted(&mut commands);
+                pin_mut!(sleep, interrupted);
+                select(sleep, interrupted).await;
+            }
+        }
+    }
+
+    debug!("Sync loop stopped");
+    inner.state.set(SyncState::Stopped);
+}
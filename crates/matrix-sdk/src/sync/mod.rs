@@ -37,6 +37,10 @@ use tracing::{debug, error, warn};
 
 use crate::{event_handler::HandlerKind, Client, Result};
 
+mod handle;
+
+pub use self::handle::{SyncHandle, SyncState};
+
 /// The processed response of a `/sync` request.
 #[derive(Clone, Default)]
 pub struct SyncResponse {
@@ -222,18 +226,44 @@ impl Client {
         Ok(())
     }
 
-    async fn sleep() {
+    pub(crate) async fn sleep(duration: Duration) {
         #[cfg(target_arch = "wasm32")]
-        gloo_timers::future::TimeoutFuture::new(1_000).await;
+        gloo_timers::future::TimeoutFuture::new(
+            u32::try_from(duration.as_millis()).expect("Overlong duration"),
+        )
+        .await;
 
         #[cfg(not(target_arch = "wasm32"))]
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Replace the filter profile of the given settings, if any, by the ID of
+    /// the uploaded filter.
+    pub(crate) async fn resolve_filter_profile(
+        &self,
+        sync_settings: &mut crate::config::SyncSettings,
+    ) -> Result<()> {
+        if let Some(profile) = &sync_settings.filter_profile {
+            if sync_settings.filter.is_none() {
+                let filter_id = self.get_or_upload_filter_profile(profile).await?;
+                sync_settings.filter = Some(Box::new(sync_events::v3::Filter::FilterId(filter_id)));
+            }
+
+            sync_settings.filter_profile = None;
+        }
+
+        Ok(())
     }
 
     pub(crate) async fn sync_loop_helper(
         &self,
         sync_settings: &mut crate::config::SyncSettings,
     ) -> Result<SyncResponse> {
+        if let Err(e) = self.resolve_filter_profile(sync_settings).await {
+            error!("Couldn't upload the sync filter: {e}");
+            return Err(e);
+        }
+
         let response = self.sync_once(sync_settings.clone()).await;
 
         match response {
@@ -256,7 +286,7 @@ impl Client {
         // the sync timeout.
         if let Some(t) = last_sync_time {
             if now - *t <= Duration::from_secs(1) {
-                Self::sleep().await;
+                Self::sleep(Duration::from_secs(1)).await;
             }
         }
 
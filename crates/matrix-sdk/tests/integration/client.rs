@@ -1,36 +1,49 @@
-use std::{collections::BTreeMap, str::FromStr, time::Duration};
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use assert_matches::assert_matches;
+use futures::StreamExt;
 use matrix_sdk::{
+    bytes::Bytes,
     config::SyncSettings,
-    media::{MediaFormat, MediaRequest, MediaThumbnailSize},
-    RumaApiError, Session,
+    media::{MediaCacheStats, MediaFormat, MediaRequest, MediaRetentionPolicy, MediaThumbnailSize},
+    sync::SyncState,
+    Error, RumaApiError, ServerFeature, Session, TransmissionProgress,
 };
 use matrix_sdk_test::{async_test, test_json};
 use ruma::{
-    api::client::{
-        self as client_api,
-        account::register::{v3::Request as RegistrationRequest, RegistrationKind},
-        directory::{
-            get_public_rooms,
-            get_public_rooms_filtered::{self, v3::Request as PublicRoomsFilterRequest},
+    api::{
+        client::{
+            self as client_api,
+            account::register::{v3::Request as RegistrationRequest, RegistrationKind},
+            directory::{
+                get_public_rooms,
+                get_public_rooms_filtered::{self, v3::Request as PublicRoomsFilterRequest},
+            },
+            media::get_content_thumbnail::v3::Method,
+            room::create_room::v3::Request as CreateRoomRequest,
+            session::get_login_types::v3::LoginType,
+            uiaa,
         },
-        media::get_content_thumbnail::v3::Method,
-        session::get_login_types::v3::LoginType,
-        uiaa,
+        MatrixVersion,
     },
     assign, device_id,
     directory::Filter,
     events::room::{message::ImageMessageEventContent, ImageInfo, MediaSource},
-    mxc_uri, room_id, uint, user_id,
+    mxc_uri, room_id, uint, user_id, RoomVersionId,
 };
 use serde_json::{from_value as from_json_value, json, to_value as to_json_value};
 use url::Url;
 use wiremock::{
-    matchers::{header, method, path, path_regex},
+    matchers::{header, method, path, path_regex, query_param},
     Mock, ResponseTemplate,
 };
 
-use crate::{logged_in_client, mock_sync, no_retry_test_client};
+use crate::{logged_in_client, mock_sync, no_retry_test_client, synced_client};
 
 #[async_test]
 async fn login() {
@@ -260,6 +273,172 @@ async fn sync() {
     assert_ne!(response.next_batch, "");
 }
 
+#[async_test]
+async fn sync_handle() {
+    let (client, server) = logged_in_client().await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+    // Keep the follow-up request pending so the loop stays in the running state.
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/sync"))
+        .and(query_param("since", "s526_47314_0_7_1_1_1_11444_1"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(&*test_json::SYNC)
+                .set_delay(Duration::from_secs(60)),
+        )
+        .mount(&server)
+        .await;
+
+    let sync_handle = client.start_sync(SyncSettings::new().timeout(Duration::from_millis(3000)));
+    let mut states = sync_handle.state_stream();
+
+    while let Some(state) = states.next().await {
+        match state {
+            SyncState::Running => break,
+            SyncState::Idle | SyncState::CatchingUp => {}
+            state => panic!("unexpected sync state: {state:?}"),
+        }
+    }
+    assert_eq!(sync_handle.last_next_batch().as_deref(), Some("s526_47314_0_7_1_1_1_11444_1"));
+
+    sync_handle.pause();
+    while let Some(state) = states.next().await {
+        if state == SyncState::Paused {
+            break;
+        }
+    }
+    assert_eq!(sync_handle.state(), SyncState::Paused);
+
+    // Stopping must not wait for the pending request.
+    let next_batch = sync_handle.stop().await;
+    assert_eq!(next_batch.as_deref(), Some("s526_47314_0_7_1_1_1_11444_1"));
+}
+
+#[async_test]
+async fn server_info() {
+    let (client, server) = no_retry_test_client().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["r0.6.0", "v1.1", "v1.4"],
+            "unstable_features": {
+                "org.matrix.msc3575": true,
+                "org.matrix.e2e_cross_signing": false,
+            },
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/.well-known/matrix/client"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "m.homeserver": {
+                "base_url": server.uri(),
+            },
+        })))
+        .mount(&server)
+        .await;
+
+    let server_info = client.server_info().await.unwrap();
+
+    assert!(server_info.supports_version(MatrixVersion::V1_1));
+    assert!(server_info.supports_threads());
+    assert!(server_info.supports_sliding_sync());
+    assert!(!server_info.supports_oidc());
+    assert!(!server_info.supports_unstable_feature("org.matrix.e2e_cross_signing"));
+    assert!(server_info.well_known.is_some());
+
+    // The second call uses the cached value.
+    let server_info = client.server_info().await.unwrap();
+    assert!(server_info.supports(&ServerFeature::Threads));
+}
+
+#[async_test]
+async fn create_room_with_unsupported_room_version() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::VERSIONS))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/.*/capabilities"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "capabilities": {
+                "m.room_versions": {
+                    "default": "9",
+                    "available": {
+                        "9": "stable",
+                        "10": "stable",
+                    },
+                },
+            },
+        })))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/.*/createRoom"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_ID))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let request = assign!(CreateRoomRequest::new(), { room_version: Some(RoomVersionId::V1) });
+    let error = client.create_room(request).await.unwrap_err();
+
+    assert_matches!(
+        error,
+        Error::UnsupportedByServer(ServerFeature::RoomVersion(version)) if version == RoomVersionId::V1
+    );
+}
+
+#[async_test]
+async fn create_room_with_unknown_capabilities() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::VERSIONS))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/.*/capabilities"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "errcode": "M_UNKNOWN",
+            "error": "Internal server error",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/.*/createRoom"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_ID))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    // The room version can't be checked, so the request is sent anyway.
+    let request = assign!(CreateRoomRequest::new(), { room_version: Some(RoomVersionId::V9) });
+    client.create_room(request).await.unwrap();
+
+    // The unknown capabilities are cached, they are not fetched again.
+    let request = assign!(CreateRoomRequest::new(), { room_version: Some(RoomVersionId::V9) });
+    client.create_room(request).await.unwrap();
+
+    let server_info = client.server_info().await.unwrap();
+    assert!(server_info.capabilities.is_none());
+    assert!(!server_info.supports_room_version(&RoomVersionId::V9));
+    assert!(!server_info.is_unsupported(&ServerFeature::RoomVersion(RoomVersionId::V9)));
+}
+
 #[async_test]
 async fn devices() {
     let (client, server) = logged_in_client().await;
@@ -493,6 +672,20 @@ async fn left_rooms() {
     assert!(client.get_left_room(&test_json::DEFAULT_SYNC_ROOM_ID).is_some())
 }
 
+#[async_test]
+async fn wipe_local_data() {
+    let (client, _server) = synced_client().await;
+    let store = client.store();
+
+    store.set_custom_value(b"custom", b"value".to_vec()).await.unwrap();
+    assert!(!store.get_room_infos().await.unwrap().is_empty());
+
+    client.wipe_local_data().await.unwrap();
+
+    assert!(store.get_room_infos().await.unwrap().is_empty());
+    assert!(store.get_custom_value(b"custom").await.unwrap().is_none());
+}
+
 #[async_test]
 async fn get_media_content() {
     let (client, server) = logged_in_client().await;
@@ -511,6 +704,74 @@ async fn get_media_content() {
     client.media().get_media_content(&request, false).await.unwrap();
 }
 
+#[async_test]
+async fn media_cache_policy() {
+    let (client, server) = logged_in_client().await;
+    let media = client.media();
+
+    let request = MediaRequest {
+        source: MediaSource::Plain(mxc_uri!("mxc://localhost/textfile").to_owned()),
+        format: MediaFormat::File,
+    };
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/media/r0/download/localhost/textfile"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Some very interesting text."))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    // The content is too big to be cached.
+    media.set_media_retention_policy(MediaRetentionPolicy::new().with_max_file_size(Some(10)));
+    media.get_media_content(&request, true).await.unwrap();
+    assert_eq!(media.cache_stats().await.unwrap(), MediaCacheStats::default());
+
+    // The content is cached and not requested again.
+    media.set_media_retention_policy(MediaRetentionPolicy::default());
+    media.get_media_content(&request, true).await.unwrap();
+    media.get_media_content(&request, true).await.unwrap();
+    assert_eq!(media.cache_stats().await.unwrap(), MediaCacheStats { count: 1, total_size: 27 });
+
+    // The expired content is removed.
+    media.set_media_retention_policy(
+        MediaRetentionPolicy::new().with_last_access_expiry(Some(Duration::ZERO)),
+    );
+    media.clean_cache().await.unwrap();
+    assert_eq!(media.cache_stats().await.unwrap(), MediaCacheStats::default());
+}
+
+#[async_test]
+async fn media_cache_max_size() {
+    let (client, server) = logged_in_client().await;
+    let media = client.media();
+    media.set_media_retention_policy(MediaRetentionPolicy::new().with_max_cache_size(Some(60)));
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/media/r0/download/localhost/"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Some very interesting text."))
+        .mount(&server)
+        .await;
+
+    for (count, file) in [(1, "first"), (2, "second")] {
+        let request = MediaRequest {
+            source: MediaSource::Plain(format!("mxc://localhost/{file}").into()),
+            format: MediaFormat::File,
+        };
+        media.get_media_content(&request, true).await.unwrap();
+        assert_eq!(media.cache_stats().await.unwrap().count, count);
+    }
+
+    // The cache is trimmed when it grows over the maximum size.
+    let request = MediaRequest {
+        source: MediaSource::Plain(mxc_uri!("mxc://localhost/third").to_owned()),
+        format: MediaFormat::File,
+    };
+    media.get_media_content(&request, true).await.unwrap();
+    let stats = media.cache_stats().await.unwrap();
+    assert_eq!(stats.count, 2);
+    assert!(stats.total_size <= 60);
+}
+
 #[async_test]
 async fn get_media_file() {
     let (client, server) = logged_in_client().await;
@@ -556,6 +817,101 @@ async fn get_media_file() {
         .unwrap();
 }
 
+#[async_test]
+async fn get_media_content_stream() {
+    let (client, server) = logged_in_client().await;
+
+    let request = MediaRequest {
+        source: MediaSource::Plain(mxc_uri!("mxc://localhost/textfile").to_owned()),
+        format: MediaFormat::File,
+    };
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/media/r0/download/localhost/textfile"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("Some very interesting text."))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let progress_clone = progress.clone();
+    let stream = client
+        .media()
+        .get_media_content_stream(&request)
+        .await
+        .unwrap()
+        .with_progress(move |p| progress_clone.lock().unwrap().push(p));
+
+    let chunks: Vec<_> = stream.map(Result::unwrap).collect().await;
+    assert_eq!(chunks.concat(), b"Some very interesting text.");
+
+    let last_progress = *progress.lock().unwrap().last().unwrap();
+    assert_eq!(last_progress, TransmissionProgress { current: 27, total: Some(27) });
+
+    // The content was not cached.
+    assert_eq!(client.media().cache_stats().await.unwrap(), MediaCacheStats::default());
+}
+
+#[async_test]
+async fn download_to_file_resume() {
+    let (client, server) = logged_in_client().await;
+
+    let request = MediaRequest {
+        source: MediaSource::Plain(mxc_uri!("mxc://localhost/textfile").to_owned()),
+        format: MediaFormat::File,
+    };
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/media/r0/download/localhost/textfile"))
+        .and(header("range", "bytes=10-"))
+        .respond_with(ResponseTemplate::new(206).set_body_string("interesting text."))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("textfile");
+    std::fs::write(&file_path, "Some very ").unwrap();
+
+    client.media().download_to_file(&request, &file_path, |_| {}).await.unwrap();
+
+    assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "Some very interesting text.");
+}
+
+#[async_test]
+async fn upload_stream() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/media/r0/upload"))
+        .and(header("content-type", "text/plain"))
+        .and(header("content-length", "11"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+          "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let data = futures::stream::iter([
+        Ok::<_, std::io::Error>(Bytes::from_static(b"Hello ")),
+        Ok(Bytes::from_static(b"world")),
+    ]);
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let progress_clone = progress.clone();
+    let response = client
+        .media()
+        .upload_stream(&mime::TEXT_PLAIN, Box::pin(data), 11, move |p| {
+            progress_clone.lock().unwrap().push(p.current)
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(response.content_uri, "mxc://example.com/AQwafuaFswefuhsfAFAgsw");
+    assert_eq!(*progress.lock().unwrap(), [6, 11]);
+}
+
 #[async_test]
 async fn whoami() {
     let (client, server) = logged_in_client().await;
@@ -14,6 +14,7 @@ use wiremock::{
 };
 
 mod client;
+mod notification_settings;
 mod refresh_token;
 mod room;
 
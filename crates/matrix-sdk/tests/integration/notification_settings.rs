@@ -0,0 +1,171 @@
+use assert_matches::assert_matches;
+use matrix_sdk::{
+    config::SyncSettings,
+    notification_settings::{NotificationSettingsError, RoomNotificationMode},
+    Error,
+};
+use matrix_sdk_test::async_test;
+use ruma::{
+    push::{PredefinedOverrideRuleId, RuleKind, Ruleset},
+    room_id, user_id,
+};
+use serde_json::json;
+use wiremock::{
+    matchers::{method, path, path_regex},
+    Mock, ResponseTemplate,
+};
+
+use crate::{logged_in_client, mock_sync};
+
+#[async_test]
+async fn room_notification_mode() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!test:localhost");
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/pushrules/global/(override|room)/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path_regex(r"^/_matrix/client/r0/pushrules/global/override/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let settings = client.account().notification_settings().await.unwrap();
+    assert_eq!(settings.room_notification_mode(room_id), None);
+
+    settings.set_room_notification_mode(room_id, RoomNotificationMode::Mute).await.unwrap();
+    assert_eq!(settings.room_notification_mode(room_id), Some(RoomNotificationMode::Mute));
+
+    // Changing the mode replaces the previous rule.
+    settings
+        .set_room_notification_mode(room_id, RoomNotificationMode::MentionsAndKeywordsOnly)
+        .await
+        .unwrap();
+    assert_eq!(
+        settings.room_notification_mode(room_id),
+        Some(RoomNotificationMode::MentionsAndKeywordsOnly)
+    );
+    assert!(settings.ruleset().get(RuleKind::Override, room_id).is_none());
+}
+
+#[async_test]
+async fn room_notification_mode_failure_keeps_previous_rule() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!test:localhost");
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/pushrules/global/override/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/pushrules/global/room/.*"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(json!({
+            "errcode": "M_UNKNOWN",
+            "error": "Internal server error",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path_regex(r"^/_matrix/client/r0/pushrules/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let settings = client.account().notification_settings().await.unwrap();
+    settings.set_room_notification_mode(room_id, RoomNotificationMode::Mute).await.unwrap();
+
+    // The new rule couldn't be set, so the previous one must not be deleted.
+    settings
+        .set_room_notification_mode(room_id, RoomNotificationMode::AllMessages)
+        .await
+        .unwrap_err();
+    assert_eq!(settings.room_notification_mode(room_id), Some(RoomNotificationMode::Mute));
+}
+
+#[async_test]
+async fn keywords() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("PUT"))
+        .and(path("/_matrix/client/r0/pushrules/global/content/matrix"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/_matrix/client/r0/pushrules/global/content/matrix"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let settings = client.account().notification_settings().await.unwrap();
+    assert!(settings.keywords().is_empty());
+
+    settings.add_keyword("matrix".to_owned()).await.unwrap();
+    // Adding the same keyword again doesn't send a request.
+    settings.add_keyword("matrix".to_owned()).await.unwrap();
+    assert_eq!(settings.keywords().into_iter().collect::<Vec<_>>(), ["matrix"]);
+
+    settings.remove_keyword("matrix").await.unwrap();
+    assert!(settings.keywords().is_empty());
+}
+
+#[async_test]
+async fn local_mention_rules() {
+    let (client, server) = logged_in_client().await;
+    let is_user_mention = PredefinedOverrideRuleId::IsUserMention.as_str();
+
+    // The server doesn't support intentional mentions.
+    let mut ruleset = Ruleset::server_default(user_id!("@example:localhost"));
+    ruleset.override_.retain(|rule| {
+        rule.rule_id != is_user_mention
+            && rule.rule_id != PredefinedOverrideRuleId::IsRoomMention.as_str()
+    });
+    mock_sync(
+        &server,
+        json!({
+            "next_batch": "s1",
+            "account_data": {
+                "events": [{
+                    "type": "m.push_rules",
+                    "content": { "global": ruleset },
+                }],
+            },
+        }),
+        None,
+    )
+    .await;
+    client.sync_once(SyncSettings::default()).await.unwrap();
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/pushrules/global/override/.*/enabled"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let settings = client.account().notification_settings().await.unwrap();
+    assert!(settings.is_push_rule_enabled(RuleKind::Override, is_user_mention).unwrap());
+
+    // The rule only exists locally, so it can't be changed on the server.
+    let error = settings
+        .set_push_rule_enabled(RuleKind::Override, is_user_mention, false)
+        .await
+        .unwrap_err();
+    assert_matches!(
+        error,
+        Error::NotificationSettings(NotificationSettingsError::RuleNotOnServer(kind, rule_id))
+            if kind == RuleKind::Override && rule_id == is_user_mention
+    );
+    assert!(settings.is_push_rule_enabled(RuleKind::Override, is_user_mention).unwrap());
+}
@@ -18,7 +18,7 @@ use ruma::{
 };
 use serde_json::json;
 use wiremock::{
-    matchers::{body_json, body_partial_json, header, method, path, path_regex},
+    matchers::{body_bytes, body_json, body_partial_json, header, method, path, path_regex},
     Mock, ResponseTemplate,
 };
 
@@ -275,6 +275,38 @@ async fn room_message_send() {
     assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
 }
 
+#[cfg(feature = "appservice")]
+#[async_test]
+async fn room_message_send_with_timestamp() {
+    use ruma::MilliSecondsSinceUnixEpoch;
+    use wiremock::matchers::query_param;
+
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/.*"))
+        .and(query_param("ts", "152037280"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EVENT_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+    mock_encryption_state(&server, false).await;
+
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let _response = client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+
+    let content = RoomMessageEventContent::text_plain("Hello world");
+    let timestamp = MilliSecondsSinceUnixEpoch(uint!(152037280));
+    let response = room.send_with_timestamp(content, None, timestamp).await.unwrap();
+
+    assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+}
+
 #[async_test]
 async fn room_attachment_send() {
     let (client, server) = logged_in_client().await;
@@ -323,6 +355,58 @@ async fn room_attachment_send() {
     assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
 }
 
+#[async_test]
+async fn room_attachment_send_file() {
+    let (client, server) = logged_in_client().await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/.*"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_partial_json(json!({
+            "url": "mxc://example.com/AQwafuaFswefuhsfAFAgsw",
+            "info": {
+                "mimetype": "image/jpeg",
+            }
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EVENT_ID))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/media/r0/upload"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(header("content-type", "image/jpeg"))
+        .and(header("content-length", "11"))
+        .and(body_bytes(b"Hello world".to_vec()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+          "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    mock_sync(&server, &*test_json::SYNC, None).await;
+    mock_encryption_state(&server, false).await;
+
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let _response = client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_joined_room(&test_json::DEFAULT_SYNC_ROOM_ID).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("image.jpg");
+    std::fs::write(&file_path, "Hello world").unwrap();
+
+    let response = room
+        .send_attachment_file("image", &mime::IMAGE_JPEG, &file_path, AttachmentConfig::new())
+        .await
+        .unwrap();
+
+    assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+}
+
 #[async_test]
 async fn room_attachment_send_info() {
     let (client, server) = logged_in_client().await;
@@ -1,7 +1,12 @@
 use std::time::Duration;
 
-use matrix_sdk::config::SyncSettings;
-use matrix_sdk_test::{async_test, test_json};
+use matrix_sdk::{
+    config::SyncSettings,
+    media::{MediaFormat, MediaRequest},
+    room::ForgetRoomSettings,
+};
+use matrix_sdk_test::{async_test, test_json, EventBuilder, LeftRoomBuilder, TimelineTestEvent};
+use ruma::{events::room::MediaSource, mxc_uri, room_id};
 use serde_json::json;
 use wiremock::{
     matchers::{header, method, path_regex},
@@ -10,6 +15,23 @@ use wiremock::{
 
 use crate::{logged_in_client, mock_sync};
 
+/// An export of a room key of `!DovneieKSTkdHKpIXy:morpheus.localhost`,
+/// encrypted with the passphrase `1234`.
+#[cfg(feature = "e2e-encryption")]
+const ROOM_KEY_EXPORT: &[u8] = b"\
+    -----BEGIN MEGOLM SESSION DATA-----\n\
+    ASKcWoiAVUM97482UAi83Avce62hSLce7i5JhsqoF6xeAAAACqt2Cg3nyJPRWTTMXxXH7TXnkfdlmBXbQtq5\
+    bpHo3LRijcq2Gc6TXilESCmJN14pIsfKRJrWjZ0squ/XsoTFytuVLWwkNaW3QF6obeg2IoVtJXLMPdw3b2vO\
+    vgwGY3OMP0XafH13j1vcb6YLzvgLkZQLnYvd47hv3yK/9GmKS9tokuaQ7dCVYckYcIOS09EDTs70YdxUd5WG\
+    rQynATCLFP1p/NAGv70r9MK7Cy/mNpjD0r4qC7UEDIoi1kOWzHgnLo19wtvwsb8Fg8ATxcs3Wmtj8hIUYpDx\
+    ia4sM10zbytUuaPUAfCDf42IyxdmOnGe1CueXhgI71y+RW0s0argNqUt7jB70JT0o9CyX6UBGRaqLk2MPY9T\
+    hUu5J8X3UgIa6rcbWigzohzWm9rdbEHFrSWqjpfQYMaAKQQgETrjSy4XTrp2RhC2oNqG/hylI4ab+F4X6fpH\
+    DYP1NqNMP5g36xNu7LhDnrUB5qsPjYOmWORxGLfudpF3oLYCSlr3DgHqEIB6HjQblLZ3KQuPBse3zxyROTnS\
+    AhdPH4a/z1wioFtKNVph3hecsiKEdqnz4Y2coSIdhz58mJ9JWNQoFAENE5CSsoEZAGvafYZVpW4C75YY2zq1\
+    wIeiFi1dT43/jLAUGkslsi1VvnyfUu8qO404RxYO3XHoGLMFoFLOO+lZ+VGci2Vz10AhxJhEBHxRKxw4k2uB\
+    HztoSJUr/2Y\n\
+    -----END MEGOLM SESSION DATA-----";
+
 #[async_test]
 async fn forget_room() {
     let (client, server) = logged_in_client().await;
@@ -32,6 +54,84 @@ async fn forget_room() {
     room.forget().await.unwrap();
 }
 
+#[async_test]
+async fn forget_room_with_settings() {
+    let (client, server) = logged_in_client().await;
+    let room_id = room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost");
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/forget$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::EMPTY))
+        .mount(&server)
+        .await;
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_left_room(LeftRoomBuilder::new(room_id).add_timeline_event(
+        TimelineTestEvent::Custom(json!({
+            "content": {
+                "body": "image.jpg",
+                "msgtype": "m.image",
+                "url": "mxc://localhost/image",
+            },
+            "event_id": "$image",
+            "origin_server_ts": 151800140,
+            "sender": "@example:localhost",
+            "type": "m.room.message",
+        })),
+    ));
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let _response = client.sync_once(sync_settings).await.unwrap();
+
+    let room = client.get_left_room(room_id).unwrap();
+
+    // The media of the event of the room, and a media that isn't used in the
+    // room.
+    let room_media = MediaRequest {
+        source: MediaSource::Plain(mxc_uri!("mxc://localhost/image").to_owned()),
+        format: MediaFormat::File,
+    };
+    let other_media = MediaRequest {
+        source: MediaSource::Plain(mxc_uri!("mxc://localhost/other").to_owned()),
+        format: MediaFormat::File,
+    };
+    client.store().add_media_content(&room_media, b"image".to_vec()).await.unwrap();
+    client.store().add_media_content(&other_media, b"other".to_vec()).await.unwrap();
+
+    // A room key of the room.
+    #[cfg(feature = "e2e-encryption")]
+    let room_keys = {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("room-keys.txt");
+        std::fs::write(&path, ROOM_KEY_EXPORT).unwrap();
+
+        let result = client.encryption().import_room_keys(path.clone(), "1234").await.unwrap();
+        assert_eq!(result.imported_count, 1);
+        (dir, path)
+    };
+
+    room.forget_with_settings(ForgetRoomSettings::new().purge_media(true).purge_room_keys(true))
+        .await
+        .unwrap();
+
+    let room_infos = client.store().get_room_infos().await.unwrap();
+    assert!(room_infos.iter().all(|info| info.room_id() != room.room_id()));
+
+    assert!(client.store().get_media_content(&room_media).await.unwrap().is_none());
+    assert!(client.store().get_media_content(&other_media).await.unwrap().is_some());
+
+    // The room key is imported again, since it was removed.
+    #[cfg(feature = "e2e-encryption")]
+    {
+        let (_dir, path) = room_keys;
+        let result = client.encryption().import_room_keys(path, "1234").await.unwrap();
+        assert_eq!(result.imported_count, 1);
+    }
+}
+
 #[async_test]
 async fn rejoin_room() {
     let (client, server) = logged_in_client().await;
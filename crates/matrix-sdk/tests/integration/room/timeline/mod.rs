@@ -7,9 +7,13 @@ use eyeball_im::VectorDiff;
 use futures_util::StreamExt;
 use matrix_sdk::{
     config::SyncSettings,
-    room::timeline::{
-        AnyOtherFullStateEventContent, Error as TimelineError, EventSendState, PaginationOptions,
-        TimelineDetails, TimelineItem, TimelineItemContent, VirtualTimelineItem,
+    room::{
+        timeline::{
+            AnyOtherFullStateEventContent, Error as TimelineError, EventSendState,
+            PaginationOptions, TimelineDetails, TimelineItem, TimelineItemContent,
+            VirtualTimelineItem,
+        },
+        MentionsBuilder,
     },
     ruma::MilliSecondsSinceUnixEpoch,
     Error,
@@ -22,14 +26,14 @@ use matrix_sdk_test::{
 use ruma::{
     event_id,
     events::{
-        room::message::{MessageType, RoomMessageEventContent},
+        room::message::{InReplyTo, MessageType, Relation, RoomMessageEventContent},
         FullStateEventContent,
     },
-    room_id, uint, user_id, TransactionId,
+    owned_event_id, room_id, uint, user_id, TransactionId,
 };
 use serde_json::json;
 use wiremock::{
-    matchers::{header, method, path_regex},
+    matchers::{body_partial_json, header, method, path_regex, query_param},
     Mock, ResponseTemplate,
 };
 
@@ -248,6 +252,76 @@ async fn echo() {
     assert_eq!(item.timestamp(), MilliSecondsSinceUnixEpoch(uint!(152038280)));
 }
 
+#[async_test]
+async fn reply_mention_added_after_local_echo() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(JoinedRoomBuilder::new(room_id));
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    let room = client.get_room(room_id).unwrap();
+    let timeline = Arc::new(room.timeline().await);
+    let (_, mut timeline_stream) = timeline.subscribe().await;
+
+    mock_encryption_state(&server, false).await;
+
+    // The replied-to event is not in the timeline, so it is fetched.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/event/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "content": {
+                "body": "Hi!",
+                "msgtype": "m.text",
+            },
+            "event_id": "$replied_to",
+            "origin_server_ts": 152037280,
+            "room_id": room_id,
+            "sender": "@bob:example.org",
+            "type": "m.room.message",
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/.*"))
+        .and(body_partial_json(json!({
+            "m.mentions": { "user_ids": ["@bob:example.org"] },
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$reply" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut content = RoomMessageEventContent::text_plain("Hello, Bob!");
+    content.relates_to =
+        Some(Relation::Reply { in_reply_to: InReplyTo::new(owned_event_id!("$replied_to")) });
+    MentionsBuilder::new().apply_to(&mut content);
+
+    let timeline = timeline.clone();
+    #[allow(unknown_lints, clippy::redundant_async_block)] // false positive
+    let send_hdl = spawn(async move { timeline.send(content.into(), None).await });
+
+    let _day_divider = assert_matches!(timeline_stream.next().await, Some(VectorDiff::PushBack { value }) => value);
+    let local_echo = assert_matches!(timeline_stream.next().await, Some(VectorDiff::PushBack { value }) => value);
+    let item = local_echo.as_event().unwrap();
+    assert_matches!(item.send_state(), Some(EventSendState::NotSentYet));
+
+    send_hdl.await.unwrap();
+
+    let sent_confirmation = assert_matches!(
+        timeline_stream.next().await,
+        Some(VectorDiff::Set { index: 1, value }) => value
+    );
+    let item = sent_confirmation.as_event().unwrap();
+    assert_matches!(item.send_state(), Some(EventSendState::Sent { .. }));
+}
+
 #[async_test]
 async fn back_pagination() {
     let room_id = room_id!("!a98sd12bjh:example.org");
@@ -361,6 +435,69 @@ async fn back_pagination() {
     assert_matches!(loading.as_virtual().unwrap(), VirtualTimelineItem::TimelineStart);
 }
 
+#[async_test]
+async fn back_pagination_from_cache() {
+    let room_id = room_id!("!a98sd12bjh:example.org");
+    let (client, server) = logged_in_client().await;
+    let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+    let mut ev_builder = EventBuilder::new();
+    ev_builder.add_joined_room(
+        JoinedRoomBuilder::new(room_id)
+            .set_timeline_prev_batch("prev_batch_1".to_owned())
+            .add_timeline_event(TimelineTestEvent::Custom(json!({
+                "content": {
+                    "body": "hello from the cache",
+                    "msgtype": "m.text",
+                },
+                "event_id": "$cached_event",
+                "origin_server_ts": 152037280,
+                "sender": "@example:localhost",
+                "type": "m.room.message",
+            }))),
+    );
+
+    mock_sync(&server, ev_builder.build_json_sync_response(), None).await;
+    let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+    server.reset().await;
+
+    // The timeline starts with the cached events.
+    let room = client.get_room(room_id).unwrap();
+    let timeline = room.timeline().await;
+    let (items, _) = timeline.subscribe().await;
+    assert_eq!(items.len(), 2);
+    assert_matches!(items[0].as_virtual().unwrap(), VirtualTimelineItem::DayDivider(_));
+    let msg = assert_matches!(
+        items[1].as_event().unwrap().content(),
+        TimelineItemContent::Message(msg) => msg
+    );
+    assert_eq!(msg.body(), "hello from the cache");
+
+    // Back-pagination continues from where the cache stops and extends it.
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/messages$"))
+        .and(query_param("from", "prev_batch_1"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(&*test_json::ROOM_MESSAGES_BATCH_1))
+        .expect(1)
+        .named("messages_batch_1")
+        .mount(&server)
+        .await;
+
+    timeline.paginate_backwards(PaginationOptions::single_request(10)).await.unwrap();
+    server.reset().await;
+
+    let items_count = timeline.subscribe().await.0.len();
+
+    // A new timeline loads the older events from the cache, without any
+    // request to the server.
+    let timeline = room.timeline().await;
+    assert_eq!(timeline.subscribe().await.0.len(), 2);
+
+    timeline.paginate_backwards(PaginationOptions::single_request(10)).await.unwrap();
+    assert_eq!(timeline.subscribe().await.0.len(), items_count);
+}
+
 #[async_test]
 async fn reaction() {
     let room_id = room_id!("!a98sd12bjh:example.org");
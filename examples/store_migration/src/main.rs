@@ -0,0 +1,194 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use matrix_sdk_base::store::{StoreArchive, StoreConfig};
+
+// The passphrases are never passed as arguments, so they don't end up in the
+// shell history or the process list. They are read from these environment
+// variables, or from the standard input if they are not set.
+
+/// The environment variable containing the passphrase of the store to export.
+const FROM_PASSPHRASE_VAR: &str = "STORE_FROM_PASSPHRASE";
+/// The environment variable containing the passphrase of the store to import
+/// into.
+const TO_PASSPHRASE_VAR: &str = "STORE_TO_PASSPHRASE";
+/// The environment variable containing the passphrase of the archive.
+const ARCHIVE_PASSPHRASE_VAR: &str = "STORE_ARCHIVE_PASSPHRASE";
+
+/// Export, import or migrate the content of stores
+#[derive(Parser)]
+struct Cli {
+    #[clap(subcommand)]
+    cmd: StoreCommand,
+}
+
+#[derive(Subcommand)]
+enum StoreCommand {
+    /// Export the content of a store to an encrypted archive
+    ///
+    /// The passphrase of the archive is read from
+    /// `STORE_ARCHIVE_PASSPHRASE` or the standard input.
+    Export {
+        /// The store to export, as `sled:<path>` or `sqlite:<path>`
+        #[clap(long)]
+        from: StoreLocation,
+        /// Whether the store to export is encrypted, its passphrase is read
+        /// from `STORE_FROM_PASSPHRASE` or the standard input
+        #[clap(long)]
+        from_encrypted: bool,
+        /// The path of the archive to write
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Import an encrypted archive into a store
+    ///
+    /// The passphrase of the archive is read from
+    /// `STORE_ARCHIVE_PASSPHRASE` or the standard input.
+    Import {
+        /// The path of the archive to read
+        #[clap(long)]
+        input: PathBuf,
+        /// The store to import into, as `sled:<path>` or `sqlite:<path>`
+        #[clap(long)]
+        to: StoreLocation,
+        /// Whether the store to import into is encrypted, its passphrase is
+        /// read from `STORE_TO_PASSPHRASE` or the standard input
+        #[clap(long)]
+        to_encrypted: bool,
+    },
+    /// Copy the content of a store into another store
+    Migrate {
+        /// The store to export, as `sled:<path>` or `sqlite:<path>`
+        #[clap(long)]
+        from: StoreLocation,
+        /// Whether the store to export is encrypted, its passphrase is read
+        /// from `STORE_FROM_PASSPHRASE` or the standard input
+        #[clap(long)]
+        from_encrypted: bool,
+        /// The store to import into, as `sled:<path>` or `sqlite:<path>`
+        #[clap(long)]
+        to: StoreLocation,
+        /// Whether the store to import into is encrypted, its passphrase is
+        /// read from `STORE_TO_PASSPHRASE` or the standard input
+        #[clap(long)]
+        to_encrypted: bool,
+    },
+}
+
+/// The backend and the path of a store.
+#[derive(Clone)]
+enum StoreLocation {
+    Sled(PathBuf),
+    Sqlite(PathBuf),
+}
+
+impl FromStr for StoreLocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("sled", path)) => Ok(Self::Sled(path.into())),
+            Some(("sqlite", path)) => Ok(Self::Sqlite(path.into())),
+            _ => Err(format!("invalid store `{s}`, expected `sled:<path>` or `sqlite:<path>`")),
+        }
+    }
+}
+
+impl StoreLocation {
+    async fn open(&self, passphrase: Option<&str>) -> Result<StoreConfig> {
+        let config = match self {
+            Self::Sled(path) => matrix_sdk_sled::make_store_config(path, passphrase).await?,
+            Self::Sqlite(path) => matrix_sdk_sqlite::make_store_config(path, passphrase).await?,
+        };
+
+        Ok(config)
+    }
+}
+
+impl StoreCommand {
+    async fn run(self) -> Result<()> {
+        match self {
+            Self::Export { from, from_encrypted, output } => {
+                let from_passphrase = store_passphrase(from_encrypted, FROM_PASSPHRASE_VAR)?;
+                let archive_passphrase = read_passphrase(ARCHIVE_PASSPHRASE_VAR)?;
+
+                let archive = export(&from, from_passphrase.as_deref()).await?;
+                fs::write(output, archive.encrypt(&archive_passphrase)?)?;
+            }
+            Self::Import { input, to, to_encrypted } => {
+                let archive_passphrase = read_passphrase(ARCHIVE_PASSPHRASE_VAR)?;
+                let to_passphrase = store_passphrase(to_encrypted, TO_PASSPHRASE_VAR)?;
+
+                let archive = StoreArchive::decrypt(&fs::read(input)?, &archive_passphrase)?;
+                import(archive, &to, to_passphrase.as_deref()).await?;
+            }
+            Self::Migrate { from, from_encrypted, to, to_encrypted } => {
+                let from_passphrase = store_passphrase(from_encrypted, FROM_PASSPHRASE_VAR)?;
+                let to_passphrase = store_passphrase(to_encrypted, TO_PASSPHRASE_VAR)?;
+
+                let archive = export(&from, from_passphrase.as_deref()).await?;
+                import(archive, &to, to_passphrase.as_deref()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the passphrase of a store, if it is encrypted.
+fn store_passphrase(encrypted: bool, var: &str) -> Result<Option<String>> {
+    encrypted.then(|| read_passphrase(var)).transpose()
+}
+
+/// Read a passphrase from the given environment variable, or from a line of
+/// the standard input if it is not set.
+fn read_passphrase(var: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(var) {
+        return Ok(passphrase);
+    }
+
+    eprint!("{var} is not set, enter the passphrase: ");
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    let passphrase = line.trim_end_matches(['\n', '\r']);
+
+    if passphrase.is_empty() {
+        bail!("no passphrase in {var} or the standard input");
+    }
+
+    Ok(passphrase.to_owned())
+}
+
+async fn export(location: &StoreLocation, passphrase: Option<&str>) -> Result<StoreArchive> {
+    let config = location.open(passphrase).await?;
+    let archive = StoreArchive::export(&config).await?;
+    println!("Exported {archive:?}");
+
+    Ok(archive)
+}
+
+async fn import(
+    archive: StoreArchive,
+    location: &StoreLocation,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let config = location.open(passphrase).await?;
+    let room_count = archive.room_count();
+    archive.import(&config).await?;
+    println!("Imported {room_count} rooms");
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    Cli::parse().cmd.run().await
+}
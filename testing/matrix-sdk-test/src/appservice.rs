@@ -20,6 +20,8 @@ pub fn value_with_room_id(value: &mut Value) {
 #[derive(Debug, Default)]
 pub struct TransactionBuilder {
     events: Vec<Raw<AnyTimelineEvent>>,
+    ephemeral: Vec<Value>,
+    to_device: Vec<Value>,
 }
 
 impl TransactionBuilder {
@@ -38,12 +40,41 @@ impl TransactionBuilder {
         self
     }
 
+    /// Add an ephemeral event, as defined in MSC2409.
+    ///
+    /// Typing notifications and receipts must have the `room_id` of their
+    /// room.
+    pub fn add_ephemeral_event(&mut self, event: Value) -> &mut Self {
+        self.ephemeral.push(event);
+        self
+    }
+
+    /// Add a to-device event, as defined in MSC2409.
+    ///
+    /// The event must have the `to_user_id` and `to_device_id` fields of its
+    /// recipient.
+    pub fn add_to_device_event(&mut self, event: Value) -> &mut Self {
+        self.to_device.push(event);
+        self
+    }
+
     /// Build the transaction as a serialized HTTP body
     pub fn build_transaction(&self) -> Vec<u8> {
-        serde_json::to_vec(&serde_json::json!({ "events": self.events })).unwrap()
+        let mut body = serde_json::json!({ "events": self.events });
+
+        if !self.ephemeral.is_empty() {
+            body["de.sorunome.msc2409.ephemeral"] = self.ephemeral.clone().into();
+        }
+        if !self.to_device.is_empty() {
+            body["de.sorunome.msc2409.to_device"] = self.to_device.clone().into();
+        }
+
+        serde_json::to_vec(&body).unwrap()
     }
 
     pub fn clear(&mut self) {
         self.events.clear();
+        self.ephemeral.clear();
+        self.to_device.clear();
     }
 }
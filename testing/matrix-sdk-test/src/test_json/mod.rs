@@ -13,6 +13,7 @@ pub mod messages;
 pub mod search_users;
 pub mod sync;
 pub mod sync_events;
+pub mod thirdparty;
 
 pub use api_responses::{
     DEVICES, GET_ALIAS, KEYS_QUERY, KEYS_QUERY_TWO_DEVICES_ONE_SIGNED, KEYS_UPLOAD, LOGIN,
@@ -0,0 +1,69 @@
+use once_cell::sync::Lazy;
+use serde_json::{json, Value as JsonValue};
+
+/// The metadata of a third party protocol.
+pub static PROTOCOL: Lazy<JsonValue> = Lazy::new(|| {
+    json!({
+        "field_types": {
+            "channel": {
+                "placeholder": "#foobar",
+                "regexp": "#[^\\s]+"
+            },
+            "network": {
+                "placeholder": "irc.example.org",
+                "regexp": "([a-z0-9]+\\.)*[a-z0-9]+"
+            },
+            "nickname": {
+                "placeholder": "username",
+                "regexp": "[^\\s#]+"
+            }
+        },
+        "icon": "mxc://example.org/aBcDeFgH",
+        "instances": [
+            {
+                "desc": "Freenode",
+                "fields": {
+                    "network": "freenode"
+                },
+                "icon": "mxc://example.org/JkLmNoPq",
+                "network_id": "freenode"
+            }
+        ],
+        "location_fields": [
+            "network",
+            "channel"
+        ],
+        "user_fields": [
+            "network",
+            "nickname"
+        ]
+    })
+});
+
+/// The third party locations of a portal room.
+pub static LOCATIONS: Lazy<JsonValue> = Lazy::new(|| {
+    json!([
+        {
+            "alias": "#freenode_#matrix:matrix.org",
+            "fields": {
+                "channel": "#matrix",
+                "network": "freenode"
+            },
+            "protocol": "irc"
+        }
+    ])
+});
+
+/// The third party users of a virtual user.
+pub static USERS: Lazy<JsonValue> = Lazy::new(|| {
+    json!([
+        {
+            "fields": {
+                "network": "freenode",
+                "nickname": "jim"
+            },
+            "protocol": "irc",
+            "userid": "@_appservice_jim:localhost"
+        }
+    ])
+});